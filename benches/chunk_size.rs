@@ -0,0 +1,155 @@
+//! Compares the throughput and the latency of a tcp tunnel on the loopback
+//! across the data chunk sizes of the server, see `Config::data_chunk_size`.
+//!
+//! The throughput is measured by a user uploading through the tunnel as fast as it can,
+//! the latency by the round trips of small messages echoed by the local server.
+//!
+//! ```sh
+//! cargo bench --bench chunk_size
+//! ```
+use std::{
+    net::{SocketAddr, TcpListener as StdTcpListener},
+    time::{Duration, Instant},
+};
+
+use async_shutdown::ShutdownManager;
+use castled::{
+    client::{
+        tunnel::{RemoteConfig, Tunnel},
+        Client,
+    },
+    server::{Config, Server},
+};
+use tokio::{
+    io::{AsyncReadExt as _, AsyncWriteExt as _},
+    net::{TcpListener, TcpStream},
+    time::sleep,
+};
+
+const UPLOAD_BYTES: usize = 256 * 1024 * 1024;
+const WRITE_SIZE: usize = 64 * 1024;
+const ROUND_TRIPS: usize = 2000;
+const MESSAGE_SIZE: usize = 64;
+
+fn free_port() -> u16 {
+    StdTcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+/// start a server and a tcp tunnel to the local server, returns the port of the tunnel.
+async fn start_tunnel(
+    data_chunk_size: Option<usize>,
+    local_addr: SocketAddr,
+    shutdown: ShutdownManager<i8>,
+) -> u16 {
+    let control_port = free_port();
+    let server = Server::new(
+        Config {
+            control_port,
+            vhttp_port: free_port(),
+            data_chunk_size,
+            ..Default::default()
+        },
+        shutdown.clone(),
+    );
+    tokio::spawn(async move {
+        let _ = server.run().await;
+    });
+    sleep(Duration::from_millis(50)).await;
+
+    let remote_port = free_port();
+    let client = Client::new(SocketAddr::from(([127, 0, 0, 1], control_port)))
+        .await
+        .unwrap();
+    client
+        .start_tunnel(
+            Tunnel::new("bench", local_addr, RemoteConfig::Tcp(remote_port)),
+            shutdown,
+        )
+        .await
+        .unwrap();
+    remote_port
+}
+
+/// the bytes per second uploaded by the user.
+async fn throughput(data_chunk_size: Option<usize>) -> f64 {
+    let local = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let local_addr = local.local_addr().unwrap();
+    let received = tokio::spawn(async move {
+        let (mut stream, _) = local.accept().await.unwrap();
+        let mut buf = vec![0; WRITE_SIZE];
+        let mut received = 0;
+        while received < UPLOAD_BYTES {
+            let n = stream.read(&mut buf).await.unwrap();
+            assert!(n > 0, "the tunnel closed early");
+            received += n;
+        }
+    });
+
+    let shutdown = ShutdownManager::new();
+    let port = start_tunnel(data_chunk_size, local_addr, shutdown.clone()).await;
+    let mut user = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    let buf = vec![7; WRITE_SIZE];
+    let start = Instant::now();
+    for _ in 0..UPLOAD_BYTES / WRITE_SIZE {
+        user.write_all(&buf).await.unwrap();
+    }
+    received.await.unwrap();
+    let elapsed = start.elapsed();
+    shutdown.trigger_shutdown(0).unwrap();
+    UPLOAD_BYTES as f64 / elapsed.as_secs_f64()
+}
+
+/// the mean round trip of a small message.
+async fn latency(data_chunk_size: Option<usize>) -> Duration {
+    let local = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let local_addr = local.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = local.accept().await.unwrap();
+        let mut buf = [0; MESSAGE_SIZE];
+        while stream.read_exact(&mut buf).await.is_ok() {
+            stream.write_all(&buf).await.unwrap();
+        }
+    });
+
+    let shutdown = ShutdownManager::new();
+    let port = start_tunnel(data_chunk_size, local_addr, shutdown.clone()).await;
+    let mut user = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    user.set_nodelay(true).unwrap();
+    let mut buf = [1; MESSAGE_SIZE];
+    let start = Instant::now();
+    for _ in 0..ROUND_TRIPS {
+        user.write_all(&buf).await.unwrap();
+        user.read_exact(&mut buf).await.unwrap();
+    }
+    let elapsed = start.elapsed();
+    shutdown.trigger_shutdown(0).unwrap();
+    elapsed / ROUND_TRIPS as u32
+}
+
+#[tokio::main]
+async fn main() {
+    println!(
+        "{:>10} {:>18} {:>18}",
+        "chunk", "throughput (MB/s)", "round trip (us)"
+    );
+    for chunk_size in [
+        None,
+        Some(1024),
+        Some(4096),
+        Some(16 * 1024),
+        Some(64 * 1024),
+    ] {
+        let throughput = throughput(chunk_size).await;
+        let latency = latency(chunk_size).await;
+        println!(
+            "{:>10} {:>18.1} {:>18.1}",
+            chunk_size.map_or("default".to_string(), |size| size.to_string()),
+            throughput / (1024.0 * 1024.0),
+            latency.as_secs_f64() * 1e6,
+        );
+    }
+}
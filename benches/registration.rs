@@ -0,0 +1,91 @@
+//! Measures the throughput of the registrations of tcp tunnels under a burst,
+//! the clients register at the same time and the server opens their entrypoints one by one.
+//!
+//! ```sh
+//! cargo bench --bench registration
+//! ```
+use std::{
+    net::{SocketAddr, TcpListener as StdTcpListener},
+    time::{Duration, Instant},
+};
+
+use async_shutdown::ShutdownManager;
+use castled::{
+    client::{
+        tunnel::{RemoteConfig, Tunnel},
+        Client,
+    },
+    server::{Config, Server},
+};
+use futures::future::join_all;
+
+const ROUNDS: usize = 3;
+
+fn free_port() -> u16 {
+    StdTcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+/// the time of registering the burst of tunnels at once, each of them on its own control stream.
+async fn burst(burst: usize) -> Duration {
+    let control_port = free_port();
+    let shutdown = ShutdownManager::new();
+    let server = Server::new(
+        Config {
+            control_port,
+            vhttp_port: free_port(),
+            max_pending_registrations: burst,
+            ..Default::default()
+        },
+        shutdown.clone(),
+    );
+    let ready = server.ready();
+    tokio::spawn(async move {
+        let _ = server.run().await;
+    });
+    assert!(ready.await, "the server failed to start");
+
+    let client = Client::new(SocketAddr::from(([127, 0, 0, 1], control_port)))
+        .await
+        .unwrap();
+    let local_addr = SocketAddr::from(([127, 0, 0, 1], free_port()));
+    let names: Vec<String> = (0..burst).map(|i| format!("bench-{}", i)).collect();
+    let tunnels = names.iter().map(|name| {
+        client.clone().start_tunnel(
+            Tunnel::new(name, local_addr, RemoteConfig::Tcp(0)),
+            shutdown.clone(),
+        )
+    });
+    let start = Instant::now();
+    for registered in join_all(tunnels).await {
+        registered.unwrap();
+    }
+    let elapsed = start.elapsed();
+    shutdown.trigger_shutdown(0).unwrap();
+    shutdown.wait_shutdown_complete().await;
+    elapsed
+}
+
+#[tokio::main]
+async fn main() {
+    println!(
+        "{:>8} {:>14} {:>22}",
+        "burst", "total (ms)", "registrations per sec"
+    );
+    for size in [1, 10, 100, 500] {
+        // the best of the rounds
+        let mut best = Duration::MAX;
+        for _ in 0..ROUNDS {
+            best = best.min(burst(size).await);
+        }
+        println!(
+            "{:>8} {:>14.1} {:>22.0}",
+            size,
+            best.as_secs_f64() * 1e3,
+            size as f64 / best.as_secs_f64(),
+        );
+    }
+}
@@ -0,0 +1,107 @@
+//! Compares the throughput of adding, looking up and removing the connections
+//! of the sharded map used by the connection registry of the server with a single map+lock.
+//!
+//! ```sh
+//! cargo bench --bench registry
+//! ```
+use std::{
+    collections::HashMap,
+    sync::{Arc, Barrier, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use dashmap::DashMap;
+
+const OPS_PER_THREAD: usize = 200_000;
+
+trait Registry: Send + Sync + 'static {
+    fn add(&self, id: Bytes, value: u64);
+    fn get(&self, id: &Bytes) -> Option<u64>;
+    fn remove(&self, id: &Bytes) -> Option<u64>;
+}
+
+impl Registry for DashMap<Bytes, u64> {
+    fn add(&self, id: Bytes, value: u64) {
+        self.insert(id, value);
+    }
+
+    fn get(&self, id: &Bytes) -> Option<u64> {
+        DashMap::get(self, id).map(|value| *value)
+    }
+
+    fn remove(&self, id: &Bytes) -> Option<u64> {
+        DashMap::remove(self, id).map(|(_, value)| value)
+    }
+}
+
+impl Registry for Mutex<HashMap<Bytes, u64>> {
+    fn add(&self, id: Bytes, value: u64) {
+        self.lock().unwrap().insert(id, value);
+    }
+
+    fn get(&self, id: &Bytes) -> Option<u64> {
+        self.lock().unwrap().get(id).copied()
+    }
+
+    fn remove(&self, id: &Bytes) -> Option<u64> {
+        self.lock().unwrap().remove(id)
+    }
+}
+
+/// each thread adds a connection, looks it up like a data stream message, then removes it.
+fn run(registry: Arc<impl Registry>, threads: usize) -> Duration {
+    let ids: Vec<Vec<Bytes>> = (0..threads)
+        .map(|t| {
+            (0..OPS_PER_THREAD)
+                .map(|i| Bytes::from(format!("{:08}-{:08}", t, i)))
+                .collect()
+        })
+        .collect();
+    let barrier = Arc::new(Barrier::new(threads + 1));
+    let handles: Vec<_> = ids
+        .into_iter()
+        .map(|ids| {
+            let registry = Arc::clone(&registry);
+            let barrier = Arc::clone(&barrier);
+            thread::spawn(move || {
+                barrier.wait();
+                for (i, id) in ids.iter().enumerate() {
+                    registry.add(id.clone(), i as u64);
+                    assert_eq!(registry.get(id), Some(i as u64));
+                    registry.remove(id);
+                }
+            })
+        })
+        .collect();
+
+    barrier.wait();
+    let start = Instant::now();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    start.elapsed()
+}
+
+fn main() {
+    let cores = thread::available_parallelism().map_or(1, usize::from);
+    let shard_amount = (cores * 4).next_power_of_two().max(4);
+    println!("cores: {}, shards: {}", cores, shard_amount);
+    println!(
+        "{:>8} {:>20} {:>20} {:>8}",
+        "threads", "mutex (ops/s)", "sharded (ops/s)", "speedup"
+    );
+    for threads in [1, 2, 4, 8, 16] {
+        let ops = (threads * OPS_PER_THREAD * 3) as f64;
+        let mutex = run(Arc::new(Mutex::new(HashMap::new())), threads);
+        let sharded = run(Arc::new(DashMap::with_shard_amount(shard_amount)), threads);
+        println!(
+            "{:>8} {:>20.0} {:>20.0} {:>7.2}x",
+            threads,
+            ops / mutex.as_secs_f64(),
+            ops / sharded.as_secs_f64(),
+            mutex.as_secs_f64() / sharded.as_secs_f64(),
+        );
+    }
+}
@@ -0,0 +1,123 @@
+//! Compares the throughput of a udp tunnel on the loopback across the buffer pools
+//! of the server, see `Config::udp_buffer_pool`.
+//!
+//! A user sends the datagrams in windows, each window is echoed back by the local server
+//! through the tunnel before the next one is sent, so the socket buffers don't drop them.
+//!
+//! ```sh
+//! cargo bench --bench udp_buffer_pool
+//! ```
+use std::{
+    net::{SocketAddr, UdpSocket as StdUdpSocket},
+    time::{Duration, Instant},
+};
+
+use async_shutdown::ShutdownManager;
+use castled::{
+    client::{
+        tunnel::{RemoteConfig, Tunnel},
+        Client,
+    },
+    server::{Config, Server},
+};
+use tokio::{net::UdpSocket, time::sleep};
+
+const DATAGRAMS: usize = 10_000;
+const WINDOW: usize = 32;
+const DATAGRAM_SIZE: usize = 1200;
+
+fn free_port() -> u16 {
+    StdUdpSocket::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+/// start a server and a udp tunnel to the local server, returns the port of the tunnel.
+async fn start_tunnel(
+    udp_buffer_pool: usize,
+    local_addr: SocketAddr,
+    shutdown: ShutdownManager<i8>,
+) -> u16 {
+    let control_port = free_port();
+    let server = Server::new(
+        Config {
+            control_port,
+            vhttp_port: free_port(),
+            udp_buffer_pool,
+            ..Default::default()
+        },
+        shutdown.clone(),
+    );
+    tokio::spawn(async move {
+        let _ = server.run().await;
+    });
+    sleep(Duration::from_millis(50)).await;
+
+    let remote_port = free_port();
+    let client = Client::new(SocketAddr::from(([127, 0, 0, 1], control_port)))
+        .await
+        .unwrap();
+    client
+        .start_tunnel(
+            Tunnel::new("bench", local_addr, RemoteConfig::Udp(remote_port)),
+            shutdown,
+        )
+        .await
+        .unwrap();
+    remote_port
+}
+
+/// the datagrams per second echoed through the tunnel, the lost ones are not counted.
+async fn throughput(udp_buffer_pool: usize) -> f64 {
+    let local = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let local_addr = local.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buf = vec![0; 64 * 1024];
+        while let Ok((n, peer)) = local.recv_from(&mut buf).await {
+            let _ = local.send_to(&buf[..n], peer).await;
+        }
+    });
+
+    let shutdown = ShutdownManager::new();
+    let port = start_tunnel(udp_buffer_pool, local_addr, shutdown.clone()).await;
+    let user = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    user.connect(("127.0.0.1", port)).await.unwrap();
+    let datagram = vec![7; DATAGRAM_SIZE];
+    let mut buf = vec![0; 64 * 1024];
+    let mut echoed = 0;
+    let start = Instant::now();
+    for _ in 0..DATAGRAMS / WINDOW {
+        for _ in 0..WINDOW {
+            user.send(&datagram).await.unwrap();
+        }
+        for _ in 0..WINDOW {
+            match tokio::time::timeout(Duration::from_millis(100), user.recv(&mut buf)).await {
+                Ok(Ok(_)) => echoed += 1,
+                // the rest of the window is lost
+                _ => break,
+            }
+        }
+    }
+    let elapsed = start.elapsed();
+    shutdown.trigger_shutdown(0).unwrap();
+    echoed as f64 / elapsed.as_secs_f64()
+}
+
+#[tokio::main]
+async fn main() {
+    println!("{:>10} {:>22}", "pool", "throughput (dgram/s)");
+    for pool in [0, 64 * 1024, 256 * 1024, 1024 * 1024] {
+        let throughput = throughput(pool).await;
+        println!(
+            "{:>10} {:>22.0}",
+            if pool == 0 {
+                "none".to_string()
+            } else {
+                pool.to_string()
+            },
+            throughput,
+        );
+    }
+}
@@ -94,7 +94,7 @@ async fn main() -> anyhow::Result<()> {
     // ### call the proxy
     let mut proxy_url = "crawler/proxy-request?requestTo=".to_owned();
     proxy_url.push_str(
-        &form_urlencoded::byte_serialize(format!("http://castled.tunnel.svc:6611/foo").as_bytes())
+        &form_urlencoded::byte_serialize("http://castled.tunnel.svc:6611/foo".as_bytes())
             .collect::<String>(),
     );
     let response2 = client
@@ -0,0 +1,114 @@
+//! The shared-secret authentication of the control channel.
+//!
+//! The server is given an auth token, the client sends it in the `authorization` metadata
+//! of every rpc as `Bearer <token>`, the rpcs without the matching token are rejected
+//! with `UNAUTHENTICATED` before they are handled, e.g. no port is bound for the tunnel.
+//! The admin api and the grpc reflection of the control port require the same token,
+//! the admin api answers the requests without it with `401 Unauthorized`.
+//!
+//! The tokens are compared by their SHA-256 digests in constant time,
+//! so neither their contents nor their lengths leak by the timing.
+use ring::{constant_time, digest};
+use tonic::{
+    metadata::{Ascii, MetadataMap, MetadataValue},
+    service::Interceptor,
+    Request, Status,
+};
+
+const AUTHORIZATION: &str = "authorization";
+const BEARER: &str = "Bearer ";
+
+/// RequireToken rejects the rpcs without the token, it lets all of them pass without one.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct RequireToken {
+    digest: Option<digest::Digest>,
+}
+
+impl RequireToken {
+    pub(crate) fn new(token: Option<&str>) -> Self {
+        Self {
+            digest: token.map(|token| digest::digest(&digest::SHA256, token.as_bytes())),
+        }
+    }
+
+    /// verify the value of the `authorization` metadata of an rpc or header of a http request.
+    pub(crate) fn verify(&self, authorization: Option<&str>) -> Result<(), Status> {
+        let Some(expected) = &self.digest else {
+            return Ok(());
+        };
+        let token = authorization
+            .and_then(|value| value.strip_prefix(BEARER))
+            .ok_or_else(|| Status::unauthenticated("missing the auth token"))?;
+        let actual = digest::digest(&digest::SHA256, token.as_bytes());
+        constant_time::verify_slices_are_equal(actual.as_ref(), expected.as_ref())
+            .map_err(|_| Status::unauthenticated("invalid auth token"))
+    }
+}
+
+impl Interceptor for RequireToken {
+    fn call(&mut self, req: Request<()>) -> Result<Request<()>, Status> {
+        self.verify(authorization(req.metadata()))?;
+        Ok(req)
+    }
+}
+
+/// the value of the `authorization` metadata of an rpc, None if it's missing or not ascii.
+pub(crate) fn authorization(metadata: &MetadataMap) -> Option<&str> {
+    metadata
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+}
+
+/// AttachToken sends the token with every rpc, it sends nothing without one.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct AttachToken {
+    value: Option<MetadataValue<Ascii>>,
+}
+
+impl AttachToken {
+    /// the token must be visible ascii, i.e. a valid header value.
+    pub(crate) fn new(token: &str) -> anyhow::Result<Self> {
+        let value = format!("{}{}", BEARER, token)
+            .parse()
+            .map_err(|_| anyhow::anyhow!("invalid auth token, expect visible ascii characters"))?;
+        Ok(Self { value: Some(value) })
+    }
+}
+
+impl Interceptor for AttachToken {
+    fn call(&mut self, mut req: Request<()>) -> Result<Request<()>, Status> {
+        if let Some(value) = &self.value {
+            req.metadata_mut().insert(AUTHORIZATION, value.clone());
+        }
+        Ok(req)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_require_token() {
+        let mut attached = AttachToken::new("secret").unwrap();
+        let req = attached.call(Request::new(())).unwrap();
+        assert_eq!(req.metadata().get(AUTHORIZATION).unwrap(), "Bearer secret");
+
+        let mut required = RequireToken::new(Some("secret"));
+        assert!(required.call(req).is_ok());
+        for token in ["", "secre", "secret2", "Secret"] {
+            let req = AttachToken::new(token)
+                .unwrap()
+                .call(Request::new(()))
+                .unwrap();
+            let status = required.call(req).unwrap_err();
+            assert_eq!(status.code(), tonic::Code::Unauthenticated, "{:?}", token);
+        }
+        let status = required.call(Request::new(())).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+
+        // no token is required
+        assert!(RequireToken::new(None).call(Request::new(())).is_ok());
+        assert!(AttachToken::new("bad\ntoken").is_err());
+    }
+}
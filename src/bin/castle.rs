@@ -1,30 +1,236 @@
+use anyhow::{anyhow, Context};
 use async_shutdown::ShutdownManager;
 use castled::{
     client::{
-        tunnel::{HttpRemoteConfig, RemoteConfig, Tunnel},
-        Client,
+        config::{TunnelConfig, TunnelsFile},
+        tunnel::{HeaderRules, HttpRemoteConfig, RemoteConfig, Tunnel},
+        Client, ClientTls, LocalTls,
     },
-    debug::setup_logging,
+    debug::{log_level, setup_logging},
+    profile,
+    signal::shutdown_signal,
+    PayloadKey, TcpKeepalive,
 };
-use clap::{Parser, Subcommand};
-use std::net::{SocketAddr, ToSocketAddrs};
-use tokio::{net::lookup_host, signal};
-use tracing::info;
+use clap::{ArgAction, CommandFactory, Parser, Subcommand};
+use clap_complete::Shell;
+use serde::Deserialize;
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, SocketAddr, SocketAddrV6},
+    path::PathBuf,
+    time::Duration,
+};
+use tokio::net::lookup_host;
+use tracing::{error, info};
 
 #[derive(Parser)]
 struct Args {
     #[command(subcommand)]
-    command: Commands,
+    command: Option<Commands>,
+
+    /// Register all the tunnels of the tunnels file instead of the one of the subcommand,
+    /// e.g. the file written by `--export`. They are registered independently,
+    /// the failing ones don't stop the rest. `--label`, `--payload-key` and the tcp keepalive
+    /// apply to all of them, the keepalive of a tunnel of the file takes precedence.
+    #[arg(
+        long,
+        conflicts_with_all = ["name", "export", "shadow", "wait_for_local", "loopback", "rate_limit", "probe_targets"]
+    )]
+    config: Option<PathBuf>,
+
+    /// The profile holding the default values of the flags, see `--profile-file`.
+    #[arg(long, global = true)]
+    profile: Option<String>,
+
+    /// The file of the profiles, defaults to `~/.castle/config.toml`.
+    #[arg(long, global = true)]
+    profile_file: Option<PathBuf>,
+
+    /// The control address of the server, can be repeated,
+    /// the rest addresses are the backups tried in order when the first one is unreachable.
+    ///
+    /// [default: 127.0.0.1:6610]
+    #[arg(long)]
+    server_addr: Vec<SocketAddr>,
+
+    /// The name of the tunnel, it shows up in the logs and the admin api of the server.
+    ///
+    /// [default: <type>-<local address>, e.g. tcp-127.0.0.1:8080]
+    #[arg(long, global = true)]
+    name: Option<String>,
+
+    /// Attach a label to the tunnel, can be repeated, e.g. `--label team=infra`.
+    #[arg(long = "label", global = true, value_parser = parse_label)]
+    labels: Vec<(String, String)>,
+
+    /// Enable the tcp keepalive on the local connections, the idle seconds before
+    /// the first keepalive probe.
+    #[arg(long, global = true)]
+    tcp_keepalive_idle: Option<u64>,
+
+    /// The seconds between the tcp keepalive probes, requires `--tcp-keepalive-idle`.
+    #[arg(long, global = true)]
+    tcp_keepalive_interval: Option<u64>,
+
+    /// The number of tcp keepalive probes before dropping the connection,
+    /// requires `--tcp-keepalive-idle`.
+    #[arg(long, global = true)]
+    tcp_keepalive_retries: Option<u32>,
+
+    /// Allow the server to probe the target besides the local endpoint, can be repeated,
+    /// e.g. `--probe-target 127.0.0.1:5432`.
+    #[arg(long = "probe-target", global = true)]
+    probe_targets: Vec<SocketAddr>,
+
+    /// Register the tunnel only after the local endpoint accepts a connection,
+    /// waiting at most the seconds, e.g. when the local server starts together with the client.
+    #[arg(
+        long,
+        global = true,
+        value_name = "SECS",
+        num_args = 0..=1,
+        default_missing_value = "30"
+    )]
+    wait_for_local: Option<u64>,
+
+    /// Keep reconnecting the tunnel until the server is back, backing off at most the seconds
+    /// between the retries, it gives up after a few retries without it.
+    #[arg(
+        long,
+        global = true,
+        value_name = "MAX_SECS",
+        num_args = 0..=1,
+        default_missing_value = "30"
+    )]
+    reconnect: Option<u64>,
+
+    /// Encrypt the payloads end-to-end with the key shared with the server,
+    /// 64 hex characters. The exported tunnel file never contains it.
+    #[arg(long, global = true)]
+    payload_key: Option<PayloadKey>,
 
-    #[arg(long, default_value = "127.0.0.1:6610")]
-    server_addr: SocketAddr,
+    /// Send the token required by the server, the exported tunnel file never contains it.
+    #[arg(long, global = true)]
+    auth_token: Option<String>,
+
+    /// Connect to the server with the tls, trusting only the pem certificates of the file,
+    /// e.g. the self-signed certificate of `castled --tls-cert`.
+    ///
+    /// [default: plaintext]
+    #[arg(long, global = true)]
+    tls_ca: Option<PathBuf>,
+
+    /// Verify the certificate of the server against the name instead of its ip, requires `--tls-ca`.
+    #[arg(long, global = true, requires = "tls_ca")]
+    tls_server_name: Option<String>,
+
+    /// Mirror the traffic to the shadow endpoint besides the local endpoint,
+    /// its responses are discarded, e.g. `--shadow 127.0.0.1:3001`. tcp and http only.
+    #[arg(long, global = true)]
+    shadow: Option<SocketAddr>,
+
+    /// Forward the traffic to the named pipe instead of the local port, e.g. `\\.\pipe\name`,
+    /// the port only describes the tunnel then. tcp and http only.
+    #[cfg(windows)]
+    #[arg(long, global = true)]
+    local_pipe: Option<String>,
+
+    /// Bind the listener of the tunnel to the loopback of the server, so it's only reachable
+    /// on the server, e.g. by a reverse proxy. http tunnels require `--remote-port` or a random port.
+    #[arg(long, global = true)]
+    loopback: bool,
+
+    /// Cap the bytes per second of the tunnel in each direction, the limit of the server
+    /// still applies if it's smaller.
+    #[arg(long, global = true)]
+    rate_limit: Option<u64>,
+
+    /// Write the tunnel to the file once it's registered, with the port or subdomain
+    /// assigned by the server, so it can be registered again the same way.
+    #[arg(long, global = true)]
+    export: Option<PathBuf>,
+
+    /// Decrease the log level, can be repeated: -q warn, -qq error.
+    /// It overrides `RUST_LOG` when provided.
+    #[arg(short, long, action = ArgAction::Count, global = true)]
+    quiet: u8,
+
+    /// Increase the log level, can be repeated: -v debug, -vv trace.
+    /// It overrides `RUST_LOG` when provided.
+    #[arg(short, long, action = ArgAction::Count, global = true)]
+    verbose: u8,
+}
+
+/// The keys of a profile, see [`castled::profile`].
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct Profile {
+    server_addr: Vec<SocketAddr>,
+    labels: BTreeMap<String, String>,
+    tcp_keepalive_idle: Option<u64>,
+    tcp_keepalive_interval: Option<u64>,
+    tcp_keepalive_retries: Option<u32>,
+    payload_key: Option<String>,
+    auth_token: Option<String>,
+    tls_ca: Option<PathBuf>,
+    tls_server_name: Option<String>,
+    reconnect: Option<u64>,
 }
 
+impl Args {
+    /// fill the flags not given on the command line with the profile.
+    fn resolve(&mut self) -> anyhow::Result<()> {
+        let profile: Profile =
+            profile::load(self.profile_file.as_deref(), self.profile.as_deref())?;
+
+        if self.server_addr.is_empty() {
+            self.server_addr = profile.server_addr;
+        }
+        if self.server_addr.is_empty() {
+            self.server_addr = vec![SocketAddr::from(([127, 0, 0, 1], 6610))];
+        }
+        // the labels of the command line override the same keys of the profile
+        let mut labels = profile.labels;
+        labels.extend(std::mem::take(&mut self.labels));
+        self.labels = labels.into_iter().collect();
+
+        if self.payload_key.is_none() {
+            self.payload_key = profile
+                .payload_key
+                .map(|key| key.parse())
+                .transpose()
+                .map_err(|err| anyhow!("invalid payload_key of the profile: {}", err))?;
+        }
+        self.auth_token = self.auth_token.take().or(profile.auth_token);
+        self.reconnect = self.reconnect.or(profile.reconnect);
+        self.tls_ca = self.tls_ca.take().or(profile.tls_ca);
+        self.tls_server_name = self.tls_server_name.take().or(profile.tls_server_name);
+        if self.tls_server_name.is_some() && self.tls_ca.is_none() {
+            return Err(anyhow!("tls_server_name of the profile requires tls_ca"));
+        }
+        self.tcp_keepalive_idle = self.tcp_keepalive_idle.or(profile.tcp_keepalive_idle);
+        self.tcp_keepalive_interval = self
+            .tcp_keepalive_interval
+            .or(profile.tcp_keepalive_interval);
+        self.tcp_keepalive_retries = self.tcp_keepalive_retries.or(profile.tcp_keepalive_retries);
+        if self.tcp_keepalive_idle.is_none()
+            && (self.tcp_keepalive_interval.is_some() || self.tcp_keepalive_retries.is_some())
+        {
+            return Err(anyhow!(
+                "--tcp-keepalive-interval and --tcp-keepalive-retries require --tcp-keepalive-idle"
+            ));
+        }
+        Ok(())
+    }
+}
+
+// parsed once, the size doesn't matter
 #[derive(Subcommand)]
+#[allow(clippy::large_enum_variant)]
 enum Commands {
     Tcp {
-        #[clap(index = 1)]
-        port: u16,
+        #[clap(index = 1, required_unless_present = "echo")]
+        port: Option<u16>,
         /// remote port the client will forward the traffic to the local port.
         #[arg(long, required = false, default_value_t = 0)]
         remote_port: u16,
@@ -34,10 +240,28 @@ enum Commands {
             help = "Local address to bind to, e.g localhost, 127.0.0.1"
         )]
         local_host: String,
+        /// Echo the bytes back instead of forwarding them to the local port,
+        /// e.g. to verify the tunnel works end-to-end without a local server.
+        #[arg(long)]
+        echo: bool,
+        /// Seconds to wait for the first byte of a connection, the silent ones are closed,
+        /// e.g. the probes of the scanners.
+        #[arg(long)]
+        first_byte_timeout: Option<u64>,
+        /// Only count the bytes of the user against `--first-byte-timeout`,
+        /// e.g. for the server-speaks-first protocols like ssh.
+        #[arg(long, requires = "first_byte_timeout")]
+        first_byte_from_user: bool,
+        /// The max bytes of a data message of the connections, e.g. a little under the path mtu,
+        /// the server may lower it to its own, at least 512.
+        #[arg(long)]
+        max_chunk_size: Option<usize>,
     },
     Http {
         #[clap(index = 1)]
         port: u16,
+        /// Serve the tunnel on its own port of the server instead of the shared vhttp port,
+        /// the requests are processed the same way
         #[arg(long)]
         remote_port: Option<u16>,
         #[arg(long)]
@@ -52,12 +276,91 @@ enum Commands {
         local_host: String,
         #[arg(long)]
         random_subdomain: bool,
+        /// Only route the requests under the path of the domain or subdomain to the tunnel,
+        /// e.g. `/api`, the tunnels of the same host are routed by the longest prefix.
+        #[arg(long)]
+        path_prefix: Option<String>,
+        /// Seconds to wait for the response of the local server,
+        /// the server answers `504 Gateway Timeout` after it, or aborts the unfinished body.
+        #[arg(long)]
+        http_timeout: Option<u64>,
+        /// Only forward the requests of the methods, e.g. `POST,PUT`,
+        /// the server answers `405 Method Not Allowed` for the others.
+        #[arg(long, value_delimiter = ',')]
+        allow_methods: Vec<String>,
+        /// Show the interstitial page of the server to the first-time visitors of the browsers.
+        #[arg(long)]
+        interstitial: bool,
+        /// Forward only one of the concurrent identical GETs, the rest are served
+        /// its response if the server caches it.
+        #[arg(long)]
+        coalesce: bool,
+        /// Rewrite the absolute urls of the local server to the public origin
+        /// in the redirects and the html pages.
+        #[arg(long)]
+        rewrite_local_origin: bool,
+        /// The max bytes of the request bodies,
+        /// the server answers `413 Payload Too Large` for the larger ones.
+        #[arg(long)]
+        max_request_body: Option<u64>,
+        /// The max bytes of the response bodies of the local server,
+        /// the larger ones are aborted.
+        #[arg(long)]
+        max_response_body: Option<u64>,
+        /// Forward the requests of the host to another local endpoint, `host=addr`,
+        /// e.g. `--upstream admin.example.com=127.0.0.1:3001`, can be repeated.
+        #[arg(long, value_parser = parse_upstream)]
+        upstream: Vec<(String, SocketAddr)>,
+        /// Append a header to the requests, `name:value`, e.g. `--add-header X-Forwarded-Host:example.com`,
+        /// the `Host` replaces the one of the request, can be repeated.
+        #[arg(long, value_parser = parse_header)]
+        add_header: Vec<(String, String)>,
+        /// Replace all the values of a header of the requests, `name:value`,
+        /// e.g. `--set-header Host:localhost:3000`, can be repeated.
+        #[arg(long, value_parser = parse_header)]
+        set_header: Vec<(String, String)>,
+        /// Drop a header of the requests, can be repeated.
+        #[arg(long)]
+        remove_header: Vec<String>,
+        /// Append a header to the responses of the local server, `name:value`, can be repeated.
+        #[arg(long, value_parser = parse_header)]
+        add_response_header: Vec<(String, String)>,
+        /// Replace all the values of a header of the responses, `name:value`, can be repeated.
+        #[arg(long, value_parser = parse_header)]
+        set_response_header: Vec<(String, String)>,
+        /// Drop a header of the responses, e.g. `Server`, can be repeated.
+        #[arg(long)]
+        remove_response_header: Vec<String>,
+        /// Dial the local server with the tls, trusting only the pem certificates of the file,
+        /// they are verified against the ip of the local server.
+        ///
+        /// [default: plaintext]
+        #[arg(long)]
+        local_tls_ca: Option<PathBuf>,
+        /// Present the pem certificate chain to the local server requiring the client certificates,
+        /// requires `--local-tls-key` and `--local-tls-ca`.
+        #[arg(long, requires_all = ["local_tls_key", "local_tls_ca"])]
+        local_tls_cert: Option<PathBuf>,
+        /// The pem private key of `--local-tls-cert`.
+        #[arg(long, requires = "local_tls_cert")]
+        local_tls_key: Option<PathBuf>,
     },
     Udp {
         #[clap(index = 1)]
         port: u16,
         #[arg(long, required = false, default_value_t = 0)]
         remote_port: u16,
+        /// The larger datagrams replied by the local endpoint are dropped, max 65507.
+        #[arg(long)]
+        max_datagram_size: Option<usize>,
+        /// Bind the sockets dialing the local endpoint to the local address,
+        /// e.g. to receive the replies on the interface of a multi-homed host.
+        #[arg(long)]
+        bind_addr: Option<IpAddr>,
+        /// Compress the datagrams between the server and the client, e.g. on a slow link,
+        /// only the ones getting smaller are sent compressed.
+        #[arg(long)]
+        compress: bool,
         #[arg(
             long,
             default_value = "127.0.0.1",
@@ -65,49 +368,149 @@ enum Commands {
         )]
         local_host: String,
     },
+    /// Print the completion script of the shell, e.g. `castle completions bash > /etc/bash_completion.d/castle`.
+    #[command(hide = true)]
+    Completions {
+        #[clap(index = 1)]
+        shell: Shell,
+    },
 }
 
-const DEFAULT_TCP_TUNNEL_NAME: &str = "castle-tcp";
-const DEFAULT_UDP_TUNNEL_NAME: &str = "castle-udp";
-const DEFAULT_HTTP_TUNNEL_NAME: &str = "castle-http";
-
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     // 6670 is the default tokio console server port of the client,
     // use `TOKIO_CONSOLE_BIND=127.0.0.1:6669` to change it.
-    setup_logging(6670);
-
-    let args = Args::parse();
+    let mut args = Args::parse();
+    if let Some(Commands::Completions { shell }) = args.command {
+        // before the logging, nothing else is printed to the stdout
+        clap_complete::generate(
+            shell,
+            &mut Args::command(),
+            "castle",
+            &mut std::io::stdout(),
+        );
+        return Ok(());
+    }
+    let command = args.command.take();
+    match (&command, &args.config) {
+        (None, None) => Args::command()
+            .error(
+                clap::error::ErrorKind::MissingSubcommand,
+                "a subcommand or --config is required",
+            )
+            .exit(),
+        (Some(_), Some(_)) => Args::command()
+            .error(
+                clap::error::ErrorKind::ArgumentConflict,
+                "--config can't be used with a subcommand",
+            )
+            .exit(),
+        _ => {}
+    }
+    setup_logging(6670, log_level(args.quiet, args.verbose));
+    args.resolve()?;
 
-    let client = Client::new(args.server_addr).await?;
+    let client = match &args.tls_ca {
+        Some(ca) => {
+            let tls = ClientTls::from_ca_file(ca)?;
+            let tls = match &args.tls_server_name {
+                Some(name) => tls.with_server_name(name)?,
+                None => tls,
+            };
+            Client::with_tls(std::mem::take(&mut args.server_addr), tls).await?
+        }
+        None => Client::with_servers(std::mem::take(&mut args.server_addr)).await?,
+    };
+    let client = match &args.auth_token {
+        Some(token) => client.with_auth_token(token)?,
+        None => client,
+    };
+    let client = match args.reconnect {
+        Some(max_backoff) => client.with_reconnect(Duration::from_secs(max_backoff)),
+        None => client,
+    };
+    info!("Server: {}", client.active_server());
+    let Some(command) = command else {
+        // checked above, either the subcommand or the tunnels file is given
+        let path = args.config.as_ref().unwrap();
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        let file = TunnelsFile::parse(&content)
+            .with_context(|| format!("invalid tunnels file {}", path.display()))?;
+        let code = run_tunnels(client, &file, &args).await?;
+        std::process::exit(code as i32)
+    };
     let tunnel;
     let shutdown: ShutdownManager<i8> = ShutdownManager::new();
     let wait_complete = shutdown.wait_shutdown_complete();
 
-    match args.command {
+    match command {
         Commands::Tcp {
             port,
             remote_port,
             local_host,
+            echo,
+            first_byte_timeout,
+            first_byte_from_user,
+            max_chunk_size,
         } => {
-            let local_endpoint = parse_socket_addr(&local_host, port).await?;
-            tunnel = Tunnel::new(
-                DEFAULT_TCP_TUNNEL_NAME,
-                local_endpoint,
-                RemoteConfig::Tcp(remote_port),
-            );
+            let mut local_endpoints =
+                parse_socket_addrs(&local_host, port.unwrap_or_default()).await?;
+            let local_endpoint = local_endpoints.remove(0);
+            let name = if echo {
+                to_str(args.name.take().unwrap_or_else(|| "tcp-echo".to_string()))
+            } else {
+                tunnel_name(args.name.take(), "tcp", local_endpoint)
+            };
+            let tcp_tunnel = Tunnel::new(name, local_endpoint, RemoteConfig::Tcp(remote_port))
+                .with_fallback_addrs(local_endpoints);
+            let tcp_tunnel = match first_byte_timeout {
+                Some(timeout) => tcp_tunnel.with_first_byte_timeout(Duration::from_secs(timeout)),
+                None => tcp_tunnel,
+            };
+            let tcp_tunnel = if first_byte_from_user {
+                tcp_tunnel.with_first_byte_from_user()
+            } else {
+                tcp_tunnel
+            };
+            let tcp_tunnel = match max_chunk_size {
+                Some(size) => tcp_tunnel.with_max_chunk_size(size),
+                None => tcp_tunnel,
+            };
+            tunnel = if echo {
+                tcp_tunnel.with_echo()
+            } else {
+                tcp_tunnel
+            };
         }
         Commands::Udp {
             port,
             remote_port,
             local_host,
+            max_datagram_size,
+            bind_addr,
+            compress,
         } => {
-            let local_endpoint = parse_socket_addr(&local_host, port).await?;
-            tunnel = Tunnel::new(
-                DEFAULT_UDP_TUNNEL_NAME,
+            // dialing a udp endpoint doesn't tell whether it's alive, the first one is taken.
+            let local_endpoint = parse_socket_addrs(&local_host, port).await?[0];
+            let udp_tunnel = Tunnel::new(
+                tunnel_name(args.name.take(), "udp", local_endpoint),
                 local_endpoint,
                 RemoteConfig::Udp(remote_port),
             );
+            let udp_tunnel = match max_datagram_size {
+                Some(size) => udp_tunnel.with_max_datagram_size(size),
+                None => udp_tunnel,
+            };
+            let udp_tunnel = match bind_addr {
+                Some(addr) => udp_tunnel.with_bind_addr(addr),
+                None => udp_tunnel,
+            };
+            tunnel = if compress {
+                udp_tunnel.with_compression()
+            } else {
+                udp_tunnel
+            };
         }
         Commands::Http {
             port,
@@ -115,37 +518,175 @@ async fn main() -> anyhow::Result<()> {
             domain,
             subdomain,
             random_subdomain,
+            path_prefix,
             remote_port,
+            http_timeout,
+            allow_methods,
+            interstitial,
+            coalesce,
+            rewrite_local_origin,
+            max_request_body,
+            max_response_body,
+            upstream,
+            add_header,
+            set_header,
+            remove_header,
+            add_response_header,
+            set_response_header,
+            remove_response_header,
+            local_tls_ca,
+            local_tls_cert,
+            local_tls_key,
         } => {
-            let local_endpoint = parse_socket_addr(&local_host, port).await?;
-            tunnel = Tunnel::new(
-                DEFAULT_HTTP_TUNNEL_NAME,
+            let mut local_endpoints = parse_socket_addrs(&local_host, port).await?;
+            let local_endpoint = local_endpoints.remove(0);
+            let http_tunnel = Tunnel::new(
+                tunnel_name(args.name.take(), "http", local_endpoint),
                 local_endpoint,
-                RemoteConfig::Http(if domain.is_some() {
-                    HttpRemoteConfig::Domain(to_str(domain.unwrap()))
-                } else if subdomain.is_some() {
-                    HttpRemoteConfig::Subdomain(to_str(subdomain.unwrap()))
+                RemoteConfig::Http(if let Some(domain) = domain {
+                    HttpRemoteConfig::Domain(to_str(domain))
+                } else if let Some(subdomain) = subdomain {
+                    HttpRemoteConfig::Subdomain(to_str(subdomain))
                 } else if random_subdomain {
                     HttpRemoteConfig::RandomSubdomain
-                } else if remote_port.is_some() {
-                    HttpRemoteConfig::Port(remote_port.unwrap())
+                } else if let Some(remote_port) = remote_port {
+                    HttpRemoteConfig::Port(remote_port)
                 } else {
                     HttpRemoteConfig::RandomPort
                 }),
-            );
+            )
+            .with_fallback_addrs(local_endpoints);
+            let http_tunnel = match path_prefix {
+                Some(path_prefix) => http_tunnel.with_path_prefix(path_prefix),
+                None => http_tunnel,
+            };
+            let http_tunnel = match http_timeout {
+                Some(timeout) => http_tunnel.with_http_timeout(Duration::from_secs(timeout)),
+                None => http_tunnel,
+            };
+            let http_tunnel = if interstitial {
+                http_tunnel.with_interstitial()
+            } else {
+                http_tunnel
+            };
+            let http_tunnel = if coalesce {
+                http_tunnel.with_coalesce()
+            } else {
+                http_tunnel
+            };
+            let http_tunnel = if rewrite_local_origin {
+                http_tunnel.with_rewrite_local_origin()
+            } else {
+                http_tunnel
+            };
+            let http_tunnel = match max_request_body {
+                Some(max) => http_tunnel.with_max_request_body(max),
+                None => http_tunnel,
+            };
+            let http_tunnel = match max_response_body {
+                Some(max) => http_tunnel.with_max_response_body(max),
+                None => http_tunnel,
+            };
+            let http_tunnel = match local_tls_ca {
+                Some(ca) => http_tunnel.with_local_tls(LocalTls {
+                    ca,
+                    identity: local_tls_cert.zip(local_tls_key),
+                })?,
+                None => http_tunnel,
+            };
+            let http_tunnel = upstream.iter().fold(http_tunnel, |tunnel, (host, addr)| {
+                tunnel.with_upstream(host, *addr)
+            });
+            let http_tunnel = http_tunnel
+                .with_request_headers(HeaderRules {
+                    add: add_header.into_iter().collect(),
+                    remove: remove_header,
+                    set: set_header.into_iter().collect(),
+                })
+                .with_response_headers(HeaderRules {
+                    add: add_response_header.into_iter().collect(),
+                    remove: remove_response_header,
+                    set: set_response_header.into_iter().collect(),
+                });
+            tunnel = if allow_methods.is_empty() {
+                http_tunnel
+            } else {
+                http_tunnel.with_allowed_methods(
+                    allow_methods
+                        .into_iter()
+                        .map(|method| method.trim().to_ascii_uppercase()),
+                )
+            };
         }
+        Commands::Completions { .. } => unreachable!("the completions are printed before"),
     }
 
+    let tunnel = match args.tcp_keepalive_idle {
+        Some(idle) => tunnel.with_tcp_keepalive(TcpKeepalive {
+            idle: Duration::from_secs(idle),
+            interval: args.tcp_keepalive_interval.map(Duration::from_secs),
+            retries: args.tcp_keepalive_retries,
+        }),
+        None => tunnel,
+    };
+
+    let tunnel = args
+        .labels
+        .into_iter()
+        .fold(tunnel, |tunnel, (key, value)| tunnel.with_label(key, value));
+    let tunnel = args
+        .probe_targets
+        .into_iter()
+        .fold(tunnel, Tunnel::with_probe_target);
+    #[cfg(windows)]
+    let tunnel = match args.local_pipe {
+        Some(name) => tunnel.with_local_pipe(name),
+        None => tunnel,
+    };
+    let tunnel = match args.shadow {
+        Some(shadow) => tunnel.with_shadow(shadow),
+        None => tunnel,
+    };
+    let tunnel = match args.wait_for_local {
+        Some(timeout) => tunnel.with_wait_for_local(Duration::from_secs(timeout)),
+        None => tunnel,
+    };
+    let tunnel = match args.payload_key {
+        Some(key) => tunnel.with_payload_key(key),
+        None => tunnel,
+    };
+    let tunnel = if args.loopback {
+        tunnel.with_loopback()
+    } else {
+        tunnel
+    };
+    let tunnel = match args.rate_limit {
+        Some(rate_limit) => tunnel.with_rate_limit(rate_limit),
+        None => tunnel,
+    };
+
+    let tunnel_config = TunnelConfig::from_tunnel(&tunnel);
     let entrypoint = client.start_tunnel(tunnel, shutdown.clone()).await?;
 
-    info!("Entrypoint: {:?}", entrypoint);
+    for entrypoint in &entrypoint {
+        info!("Entrypoint: {}", entrypoint);
+    }
+
+    if let Some(path) = &args.export {
+        let file = TunnelsFile {
+            tunnels: vec![tunnel_config.with_assigned(&entrypoint)],
+        };
+        std::fs::write(path, file.to_toml()?)
+            .with_context(|| format!("failed to export the tunnel to {}", path.display()))?;
+        info!("Exported the tunnel to {}", path.display());
+    }
 
     tokio::spawn(async move {
-        if let Err(e) = signal::ctrl_c().await {
+        let signal = shutdown_signal().await.unwrap_or_else(|e| {
             // Something really weird happened. So just panic
-            panic!("Failed to listen for the ctrl-c signal: {:?}", e);
-        }
-        info!("Received ctrl-c signal. Shutting down...");
+            panic!("Failed to listen for the shutdown signals: {:?}", e)
+        });
+        info!("Received {} signal. Shutting down...", signal);
         shutdown.trigger_shutdown(0).ok();
     });
 
@@ -153,24 +694,240 @@ async fn main() -> anyhow::Result<()> {
     std::process::exit(code as i32)
 }
 
+/// register the tunnels of the file, each of its own shutdown, so the failing ones
+/// don't stop the rest, returns the exit code once all of them stopped.
+async fn run_tunnels(client: Client, file: &TunnelsFile, args: &Args) -> anyhow::Result<i8> {
+    if file.tunnels.is_empty() {
+        return Err(anyhow!("no tunnel in the tunnels file"));
+    }
+    // the invalid definitions fail before any tunnel is registered
+    let mut tunnels = Vec::with_capacity(file.tunnels.len());
+    for (i, config) in file.tunnels.iter().enumerate() {
+        // the server replaces the tunnel of the same name and type of the client
+        if file.tunnels[..i]
+            .iter()
+            .any(|other| other.name == config.name && other.kind == config.kind)
+        {
+            return Err(anyhow!(
+                "duplicate tunnel {:?} in the tunnels file",
+                config.name
+            ));
+        }
+        let tunnel = config.to_tunnel()?;
+        let tunnel = match args.tcp_keepalive_idle {
+            Some(idle) if config.tcp_keepalive_idle.is_none() => {
+                tunnel.with_tcp_keepalive(TcpKeepalive {
+                    idle: Duration::from_secs(idle),
+                    interval: args.tcp_keepalive_interval.map(Duration::from_secs),
+                    retries: args.tcp_keepalive_retries,
+                })
+            }
+            _ => tunnel,
+        };
+        let tunnel = args
+            .labels
+            .iter()
+            .fold(tunnel, |tunnel, (key, value)| tunnel.with_label(key, value));
+        let tunnel = match &args.payload_key {
+            Some(key) => tunnel.with_payload_key(key.clone()),
+            None => tunnel,
+        };
+        tunnels.push(tunnel);
+    }
+
+    let mut started = Vec::with_capacity(tunnels.len());
+    for (config, tunnel) in file.tunnels.iter().zip(tunnels) {
+        let name = &config.name;
+        let shutdown = ShutdownManager::new();
+        let handle = client.clone().spawn_tunnel(tunnel, shutdown.clone());
+        started.push((name, handle, shutdown));
+    }
+    let registered =
+        futures::future::join_all(started.iter_mut().map(|(_, handle, _)| handle.registered()))
+            .await;
+    let mut failed = 0;
+    for ((name, _, _), registration) in started.iter().zip(registered) {
+        match registration {
+            Ok(registration) if registration.entrypoint.is_empty() => {
+                info!("Tunnel {} registered", name);
+            }
+            Ok(registration) => {
+                for entrypoint in &registration.entrypoint {
+                    info!("Tunnel {}: {}", name, entrypoint);
+                }
+            }
+            Err(err) => {
+                failed += 1;
+                error!("Tunnel {} failed: {:#}", name, err);
+            }
+        }
+    }
+    info!(
+        "{} of {} tunnels registered",
+        started.len() - failed,
+        started.len()
+    );
+
+    let shutdowns: Vec<_> = started
+        .into_iter()
+        .map(|(_, _, shutdown)| shutdown)
+        .collect();
+    let wait_complete = futures::future::join_all(
+        shutdowns
+            .iter()
+            .map(ShutdownManager::wait_shutdown_complete),
+    );
+    let triggers = shutdowns.clone();
+    tokio::spawn(async move {
+        let signal = shutdown_signal().await.unwrap_or_else(|e| {
+            // Something really weird happened. So just panic
+            panic!("Failed to listen for the shutdown signals: {:?}", e)
+        });
+        info!("Received {} signal. Shutting down...", signal);
+        for shutdown in triggers {
+            shutdown.trigger_shutdown(0).ok();
+        }
+    });
+    // the failure of any tunnel fails the process
+    Ok(wait_complete.await.into_iter().max().unwrap_or_default())
+}
+
+fn parse_label(label: &str) -> Result<(String, String), String> {
+    label
+        .split_once('=')
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .ok_or_else(|| format!("invalid label {:?}, expect key=value", label))
+}
+
+fn parse_header(header: &str) -> Result<(String, String), String> {
+    header
+        .split_once(':')
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .filter(|(name, _)| !name.is_empty())
+        .ok_or_else(|| format!("invalid header {:?}, expect name:value", header))
+}
+
+fn parse_upstream(upstream: &str) -> Result<(String, SocketAddr), String> {
+    upstream
+        .split_once('=')
+        .and_then(|(host, addr)| Some((host.to_string(), addr.parse().ok()?)))
+        .ok_or_else(|| format!("invalid upstream {:?}, expect host=ip:port", upstream))
+}
+
+/// the given name, or a name telling the tunnels of a client apart.
+fn tunnel_name<'a>(name: Option<String>, kind: &str, local_endpoint: SocketAddr) -> &'a str {
+    to_str(name.unwrap_or_else(|| format!("{}-{}", kind, local_endpoint)))
+}
+
 fn to_str<'a>(s: String) -> &'a str {
     Box::leak(s.into_boxed_str())
 }
 
-async fn parse_socket_addr(local_host: &str, port: u16) -> anyhow::Result<SocketAddr> {
-    let addr = format!("{}:{}", local_host, port);
-    let mut addrs = addr.to_socket_addrs()?;
-    if addrs.len() == 1 {
-        return Ok(addrs.next().unwrap());
+async fn parse_socket_addrs(local_host: &str, port: u16) -> anyhow::Result<Vec<SocketAddr>> {
+    if let Some(addr) = parse_ip_literal(local_host, port)? {
+        return Ok(vec![addr]);
+    }
+    let ips = lookup_host((local_host, port)).await?.collect::<Vec<_>>();
+    if ips.is_empty() {
+        return Err(anyhow::anyhow!("Invalid address"));
+    }
+    if ips.len() > 1 {
+        info!(port = port, ips = ?ips, "dns parsed, the rest are tried if the first fails");
     }
-    let ips = lookup_host(addr).await?.collect::<Vec<_>>();
-    if !ips.is_empty() {
-        info!(port = port, ips = ?ips, "dns parsed",);
+    Ok(ips)
+}
 
-        let mut ip = ips[0];
-        ip.set_port(port);
-        return Ok(ip);
+/// parse the ip of the local host, e.g. `::1`, `[::1]` or `fe80::1%eth0`, None for a hostname.
+fn parse_ip_literal(local_host: &str, port: u16) -> anyhow::Result<Option<SocketAddr>> {
+    let host = local_host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(local_host);
+    let (ip, scope) = match host.split_once('%') {
+        Some((ip, scope)) => (ip, Some(scope)),
+        None => (host, None),
+    };
+    let Ok(ip) = ip.parse::<IpAddr>() else {
+        return Ok(None);
+    };
+    match (ip, scope) {
+        (ip, None) => Ok(Some(SocketAddr::new(ip, port))),
+        (IpAddr::V6(ip), Some(scope)) => Ok(Some(
+            SocketAddrV6::new(ip, port, 0, scope_id(scope)?).into(),
+        )),
+        (IpAddr::V4(_), Some(_)) => Err(anyhow!(
+            "invalid local host {:?}, only the ipv6 addresses have the scope id",
+            local_host
+        )),
     }
+}
 
-    Err(anyhow::anyhow!("Invalid address"))
+/// the scope id is the index or the name of the interface, e.g. `2` or `eth0`.
+fn scope_id(scope: &str) -> anyhow::Result<u32> {
+    if let Ok(index) = scope.parse() {
+        return Ok(index);
+    }
+    interface_index(scope).ok_or_else(|| anyhow!("unknown interface {:?} of the scope id", scope))
+}
+
+#[cfg(unix)]
+fn interface_index(name: &str) -> Option<u32> {
+    let name = std::ffi::CString::new(name).ok()?;
+    // SAFETY: the name is a nul terminated string living through the call.
+    let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+    (index != 0).then_some(index)
+}
+
+/// only the indexes of the interfaces are supported.
+#[cfg(not(unix))]
+fn interface_index(_: &str) -> Option<u32> {
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::Ipv6Addr;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_parse_socket_addrs() {
+        let loopback = SocketAddr::from((Ipv6Addr::LOCALHOST, 8080));
+        assert_eq!(parse_socket_addrs("::1", 8080).await.unwrap(), [loopback]);
+        assert_eq!(parse_socket_addrs("[::1]", 8080).await.unwrap(), [loopback]);
+        assert_eq!(
+            parse_socket_addrs("127.0.0.1", 8080).await.unwrap(),
+            [SocketAddr::from(([127, 0, 0, 1], 8080))]
+        );
+
+        let link_local: Ipv6Addr = "fe80::1".parse().unwrap();
+        let addr = SocketAddr::V6(SocketAddrV6::new(link_local, 8080, 0, 2));
+        assert_eq!(parse_socket_addrs("fe80::1%2", 8080).await.unwrap(), [addr]);
+        assert_eq!(
+            parse_socket_addrs("[fe80::1%2]", 8080).await.unwrap(),
+            [addr]
+        );
+        #[cfg(target_os = "linux")]
+        {
+            let [SocketAddr::V6(addr)] = parse_socket_addrs("fe80::1%lo", 8080).await.unwrap()[..]
+            else {
+                panic!("expect an ipv6 address");
+            };
+            assert_eq!(addr.scope_id(), 1);
+        }
+
+        assert!(parse_socket_addrs("fe80::1%no-such-interface", 8080)
+            .await
+            .is_err());
+        assert!(parse_socket_addrs("127.0.0.1%2", 8080).await.is_err());
+
+        // all the addresses of the hostname are kept
+        let addrs = parse_socket_addrs("localhost", 8080).await.unwrap();
+        assert!(addrs.iter().all(|addr| addr.port() == 8080), "{:?}", addrs);
+        assert!(
+            addrs.iter().all(|addr| addr.ip().is_loopback()),
+            "{:?}",
+            addrs
+        );
+    }
 }
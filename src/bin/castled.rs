@@ -1,89 +1,685 @@
-use std::net::IpAddr;
+use std::{convert::Infallible, fmt, net::IpAddr, path::PathBuf, str::FromStr, time::Duration};
 
-use anyhow::Ok;
+use anyhow::{anyhow, Ok};
 use async_shutdown::ShutdownManager;
 use castled::{
-    debug::setup_logging,
-    server::{Config, EntrypointConfig, Server},
+    debug::{log_level, setup_logging},
+    profile,
+    server::{
+        Config, ControlTls, EntrypointConfig, Reloader, Server, SubdomainWords, TunnelWeight,
+    },
+    signal::shutdown_signal,
+    PayloadKey, TcpKeepalive,
 };
-use clap::Parser;
-use tokio::signal;
-use tracing::info;
+use clap::{ArgAction, CommandFactory, Parser, Subcommand};
+use clap_complete::Shell;
+use serde::Deserialize;
+use tracing::{error, info};
 
 #[derive(Parser, Debug, Default)]
 struct Args {
-    #[arg(long, default_value = "6610")]
-    control_port: u16,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// The profile holding the default values of the flags, see `--profile-file`.
+    /// The switches turn off the keys of the profile with `=false`, e.g. `--admin-api=false`.
+    #[arg(long)]
+    profile: Option<String>,
+
+    /// The file of the profiles, defaults to `~/.castle/config.toml`.
+    #[arg(long)]
+    profile_file: Option<PathBuf>,
+
+    /// The config file holding the keys of a profile at the top level, it's read instead
+    /// of the profiles, e.g. the several domains and the port range of the server.
+    /// The flags given on the command line override it, it's reloaded on `SIGHUP` too.
+    #[arg(long, conflicts_with_all = ["profile", "profile_file"])]
+    config: Option<PathBuf>,
+
+    /// [default: 6610]
+    #[arg(long)]
+    control_port: Option<u16>,
 
     /// the vhttp server port, it serves all the http requests through the vhttp port.
-    #[arg(long, default_value = "6611")]
-    vhttp_port: u16,
+    ///
+    /// [default: 6611]
+    #[arg(long)]
+    vhttp_port: Option<u16>,
+
+    /// The address the vhttp server listens on, e.g. 127.0.0.1 when it's fronted
+    /// by a local reverse proxy, see `--vhttp-behind-proxy-tls`.
+    ///
+    /// [default: 0.0.0.0]
+    #[arg(long)]
+    vhttp_bind_ip: Option<IpAddr>,
+
+    /// Keep running without the vhttp server if the vhttp port can't be bound,
+    /// only the tunnels of their own ports are served then.
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    vhttp_optional: Option<bool>,
 
     /// Domain names for the http server, it could be empty,
-    /// the client can't register with domain if it's empty.
+    /// the client can't register with a subdomain if it's empty.
     ///
     /// e.g. "tunnel.example.com", don't include the protocol.
     #[arg(long, required = false)]
     domain: Vec<String>,
 
     /// The IP addresses of the castle server.
+    ///
+    /// The http tunnels require a domain or an ip, unless `--disable-http` is given.
     #[arg(long, required = false)]
     ip: Vec<IpAddr>,
 
     /// If the vhttp server is behind a http proxy like nginx, set this to true.
-    #[arg(long, default_value = "false")]
-    vhttp_behind_proxy_tls: bool,
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    vhttp_behind_proxy_tls: Option<bool>,
+
+    /// The path the vhttp server is served under by the http proxy in front of it,
+    /// e.g. "/tunnels" of "https://site.com/tunnels/...", the proxy forwards the full paths.
+    #[arg(long)]
+    external_base_path: Option<String>,
 
     /// Minimum accepted port number.
-    #[clap(long, default_value_t = 1024)]
-    random_min_port: u16,
+    ///
+    /// [default: 1024]
+    #[clap(long)]
+    random_min_port: Option<u16>,
 
     /// Maximum accepted port number.
-    #[clap(long, default_value_t = 65535)]
-    random_max_port: u16,
+    ///
+    /// [default: 65535]
+    #[clap(long)]
+    random_max_port: Option<u16>,
 
     #[clap(long, required = false, default_value = "")]
     exclude_ports: String,
+
+    /// Let the os pick any free port for the tunnels asking for a random port,
+    /// ignoring --random-min-port and --random-max-port.
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    ephemeral_ports: Option<bool>,
+
+    /// Make the random subdomains of the words, e.g. `brave-otter-1234`,
+    /// the words are built in unless `subdomain_words` of the profile lists them.
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    word_subdomains: Option<bool>,
+
+    /// the `subdomain_words` of the profile, it's read by `resolve`.
+    #[arg(skip)]
+    subdomain_words: Option<SubdomainWords>,
+
+    /// Reject the registrations of the tcp tunnels.
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    disable_tcp: Option<bool>,
+
+    /// Reject the registrations of the udp tunnels.
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    disable_udp: Option<bool>,
+
+    /// Reject the registrations of the http tunnels.
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    disable_http: Option<bool>,
+
+    /// Serve the admin api on the control port, e.g. `GET /admin/tunnels?label=team=infra`.
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    admin_api: Option<bool>,
+
+    /// Serve the grpc server reflection on the control port, e.g. for `grpcurl list`.
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    enable_reflection: Option<bool>,
+
+    /// The max number of user connections waiting for the clients to set up
+    /// the data streams, the new connections wait when it's reached.
+    ///
+    /// [default: 1024]
+    #[arg(long)]
+    max_pending_connections: Option<usize>,
+
+    /// The max number of registrations waiting for the entrypoints and the open hooks,
+    /// the registrations beyond it are rejected as unavailable, the clients retry them later.
+    ///
+    /// [default: 1024]
+    #[arg(long)]
+    max_pending_registrations: Option<usize>,
+
+    /// The max bytes of each user connection sent by the client but not written to the user yet,
+    /// the server stops reading from the client when it's reached.
+    ///
+    /// [default: 1048576]
+    #[arg(long)]
+    max_in_flight_bytes: Option<usize>,
+
+    /// The max bytes of a message sent to the client for the tcp tunnels, the larger reads
+    /// of the user connections are split and the smaller ones coalesced until the user pauses.
+    /// The larger chunks cost less per byte, the smaller ones delay the other connections less.
+    ///
+    /// [default: what's read at once, at most 8192]
+    #[arg(long)]
+    data_chunk_size: Option<usize>,
+
+    /// The max seconds of the deadlines the users set on their requests to the http tunnels
+    /// with the `X-Tunnel-Deadline` header, e.g. `500m`, the longer ones are capped.
+    ///
+    /// [default: the header is ignored]
+    #[arg(long)]
+    max_request_deadline: Option<u64>,
+
+    /// The max bytes of the request bodies of the http tunnels, the larger ones get 413,
+    /// the smaller limit of a tunnel still applies.
+    ///
+    /// [default: unlimited]
+    #[arg(long)]
+    max_request_body: Option<u64>,
+
+    /// The max bytes of the response bodies of the http tunnels, the larger ones are aborted,
+    /// the smaller limit of a tunnel still applies.
+    ///
+    /// [default: unlimited]
+    #[arg(long)]
+    max_response_body: Option<u64>,
+
+    /// The max sessions of each udp tunnel, the least recently used one is evicted for a new peer.
+    ///
+    /// [default: unlimited]
+    #[arg(long)]
+    max_udp_sessions: Option<usize>,
+
+    /// The seconds a udp session lives without any datagram in either direction,
+    /// it's closed after it, the next datagram of the peer opens a new one.
+    ///
+    /// [default: until the session is evicted or the tunnel closes]
+    #[arg(long)]
+    udp_idle_timeout: Option<u64>,
+
+    /// The bytes of the buffer each udp tunnel reads the datagrams into, a new one is allocated
+    /// when it's used up, 0 reads each datagram into its own buffer.
+    ///
+    /// [default: 262144]
+    #[arg(long)]
+    udp_buffer_pool: Option<usize>,
+
+    /// The max bytes in flight of all the user connections, the new connections wait
+    /// and the datagrams of the new udp peers are dropped when it's reached.
+    ///
+    /// [default: unlimited]
+    #[arg(long)]
+    memory_budget: Option<usize>,
+
+    /// Cache the GET responses of the http tunnels up to the bytes,
+    /// as `Cache-Control` of the responses allows.
+    ///
+    /// [default: disabled]
+    #[arg(long)]
+    http_cache: Option<usize>,
+
+    /// The html file shown by the http tunnels with the interstitial enabled,
+    /// `{{host}}` and `{{continue_url}}` in it are replaced by the host and the continue link.
+    ///
+    /// [default: the built-in page]
+    #[arg(long)]
+    interstitial_page: Option<PathBuf>,
+
+    /// The max messages per second sent by each client to the control server,
+    /// the registrations exceeding it are rejected, the data streams are throttled.
+    ///
+    /// [default: unlimited]
+    #[arg(long)]
+    control_rate_limit: Option<u32>,
+
+    /// Encrypt the payloads of the data streams of the clients with the same key,
+    /// 64 hex characters, e.g. the output of `openssl rand -hex 32`.
+    /// The clients without the key still work.
+    #[arg(long)]
+    payload_key: Option<PayloadKey>,
+
+    /// Require the token from the clients, e.g. when the control port is public,
+    /// the clients without it are rejected before any port is bound for their tunnels.
+    /// The admin api and the grpc reflection require it too.
+    ///
+    /// [default: any client can register the tunnels]
+    #[arg(long)]
+    auth_token: Option<AuthToken>,
+
+    /// The max registrations per minute of each auth token, e.g. so a leaked token
+    /// can't churn the tunnels, the clients without a token share it.
+    ///
+    /// [default: unlimited]
+    #[arg(long)]
+    registration_rate_limit: Option<u32>,
+
+    /// Serve the control port with the tls of the pem certificate chain, requires `--tls-key`.
+    /// The clients connect with `castle --tls-ca`.
+    ///
+    /// [default: plaintext]
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// The pem private key of `--tls-cert`.
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// Pace the data streams of each direction at the bytes per second, the tunnels
+    /// with traffic queued share it by their weights. Set it a bit below the capacity of the link.
+    ///
+    /// [default: unlimited]
+    #[arg(long)]
+    bandwidth_limit: Option<u64>,
+
+    /// The weight of the tunnels with the label in `--bandwidth-limit`, `key=value:weight`,
+    /// e.g. `tier=paid:4`, can be repeated, the first matching one wins.
+    ///
+    /// [default: 1]
+    #[arg(long)]
+    tunnel_weight: Vec<TunnelWeight>,
+
+    /// Cap the bytes per second of each tunnel in each direction, the traffic over it
+    /// waits instead of being dropped. The tunnels may ask for a lower one.
+    ///
+    /// [default: unlimited]
+    #[arg(long)]
+    tunnel_rate_limit: Option<u64>,
+
+    /// Log the open and close of 1 in the number of the user connections of each tunnel.
+    ///
+    /// [default: 1]
+    #[arg(long)]
+    connection_log_sample: Option<u32>,
+
+    /// The max seconds the data of a client waits for the user connection to take it,
+    /// the stuck connections are closed after it, e.g. the users stop reading.
+    ///
+    /// [default: wait for as long as it takes]
+    #[arg(long)]
+    data_send_timeout: Option<u64>,
+
+    /// The max seconds each side of a closing tcp connection takes to shut down,
+    /// the connection is dropped after it, e.g. the client doesn't take the end of the stream.
+    ///
+    /// [default: wait for as long as it takes]
+    #[arg(long)]
+    close_timeout: Option<u64>,
+
+    /// the content of `--interstitial-page`, it's read by `resolve`.
+    #[arg(skip)]
+    interstitial_html: Option<String>,
+
+    /// Enable the tcp keepalive on the user connections, the idle seconds before
+    /// the first keepalive probe.
+    #[arg(long)]
+    tcp_keepalive_idle: Option<u64>,
+
+    /// The seconds between the tcp keepalive probes, requires `--tcp-keepalive-idle`.
+    #[arg(long)]
+    tcp_keepalive_interval: Option<u64>,
+
+    /// The number of tcp keepalive probes before dropping the connection,
+    /// requires `--tcp-keepalive-idle`.
+    #[arg(long)]
+    tcp_keepalive_retries: Option<u32>,
+
+    /// Decrease the log level, can be repeated: -q warn, -qq error.
+    /// It overrides `RUST_LOG` when provided.
+    #[arg(short, long, action = ArgAction::Count, global = true)]
+    quiet: u8,
+
+    /// Increase the log level, can be repeated: -v debug, -vv trace.
+    /// It overrides `RUST_LOG` when provided.
+    #[arg(short, long, action = ArgAction::Count, global = true)]
+    verbose: u8,
 }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    // 6669 is the default tokio console server port of the server,
-    // use `TOKIO_CONSOLE_BIND=127.0.0.1:6670` to change it.
-    setup_logging(6669);
+/// AuthToken is the `--auth-token`, it's redacted from the logged args.
+#[derive(Clone)]
+struct AuthToken(String);
 
-    let args = Args::parse();
-    info!("server args: {:?}", args);
+impl FromStr for AuthToken {
+    type Err = Infallible;
 
-    let shutdown = ShutdownManager::new();
-    let wait_complete = shutdown.wait_shutdown_complete();
+    fn from_str(token: &str) -> Result<Self, Self::Err> {
+        std::result::Result::Ok(Self(token.to_string()))
+    }
+}
 
-    let server = Server::new(
+impl fmt::Debug for AuthToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // never log the token
+        f.write_str("AuthToken(..)")
+    }
+}
+
+/// The keys of a profile, see [`castled::profile`].
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct Profile {
+    control_port: Option<u16>,
+    vhttp_port: Option<u16>,
+    vhttp_bind_ip: Option<IpAddr>,
+    vhttp_optional: bool,
+    domain: Vec<String>,
+    ip: Vec<IpAddr>,
+    vhttp_behind_proxy_tls: bool,
+    external_base_path: Option<String>,
+    random_min_port: Option<u16>,
+    random_max_port: Option<u16>,
+    exclude_ports: Vec<u16>,
+    ephemeral_ports: bool,
+    word_subdomains: bool,
+    subdomain_words: Option<SubdomainWords>,
+    disable_tcp: bool,
+    disable_udp: bool,
+    disable_http: bool,
+    admin_api: bool,
+    enable_reflection: bool,
+    max_pending_connections: Option<usize>,
+    max_pending_registrations: Option<usize>,
+    max_in_flight_bytes: Option<usize>,
+    data_chunk_size: Option<usize>,
+    max_request_deadline: Option<u64>,
+    max_request_body: Option<u64>,
+    max_response_body: Option<u64>,
+    max_udp_sessions: Option<usize>,
+    udp_idle_timeout: Option<u64>,
+    udp_buffer_pool: Option<usize>,
+    memory_budget: Option<usize>,
+    http_cache: Option<usize>,
+    interstitial_page: Option<PathBuf>,
+    control_rate_limit: Option<u32>,
+    payload_key: Option<String>,
+    auth_token: Option<String>,
+    registration_rate_limit: Option<u32>,
+    tls_cert: Option<PathBuf>,
+    tls_key: Option<PathBuf>,
+    bandwidth_limit: Option<u64>,
+    tunnel_weights: Vec<String>,
+    tunnel_rate_limit: Option<u64>,
+    connection_log_sample: Option<u32>,
+    data_send_timeout: Option<u64>,
+    close_timeout: Option<u64>,
+    tcp_keepalive_idle: Option<u64>,
+    tcp_keepalive_interval: Option<u64>,
+    tcp_keepalive_retries: Option<u32>,
+}
+
+impl Args {
+    /// fill the flags not given on the command line with the profile or the config file.
+    fn resolve(&mut self) -> anyhow::Result<()> {
+        let profile: Profile = match &self.config {
+            Some(path) => profile::load_file(path)?,
+            None => profile::load(self.profile_file.as_deref(), self.profile.as_deref())?,
+        };
+
+        self.control_port = self.control_port.or(profile.control_port);
+        self.vhttp_port = self.vhttp_port.or(profile.vhttp_port);
+        self.vhttp_bind_ip = self.vhttp_bind_ip.or(profile.vhttp_bind_ip);
+        self.vhttp_optional = self.vhttp_optional.or(Some(profile.vhttp_optional));
+        if self.domain.is_empty() {
+            self.domain = profile.domain;
+        }
+        if self.ip.is_empty() {
+            self.ip = profile.ip;
+        }
+        self.vhttp_behind_proxy_tls = self
+            .vhttp_behind_proxy_tls
+            .or(Some(profile.vhttp_behind_proxy_tls));
+        self.external_base_path = self
+            .external_base_path
+            .take()
+            .or(profile.external_base_path);
+        if let Some(base_path) = &self.external_base_path {
+            if !base_path.starts_with('/')
+                || base_path
+                    .chars()
+                    .any(|c| c.is_whitespace() || c.is_control() || matches!(c, '?' | '#'))
+            {
+                return Err(anyhow!(
+                    "invalid --external-base-path {:?}, it must start with '/' and have no query or fragment",
+                    base_path
+                ));
+            }
+        }
+        self.random_min_port = self.random_min_port.or(profile.random_min_port);
+        self.random_max_port = self.random_max_port.or(profile.random_max_port);
+        if self.exclude_ports.is_empty() {
+            self.exclude_ports = profile
+                .exclude_ports
+                .iter()
+                .map(u16::to_string)
+                .collect::<Vec<_>>()
+                .join(",");
+        }
+        self.ephemeral_ports = self.ephemeral_ports.or(Some(profile.ephemeral_ports));
+        self.word_subdomains = self.word_subdomains.or(Some(profile.word_subdomains));
+        if let Some(words) = &profile.subdomain_words {
+            words
+                .validate()
+                .map_err(|err| anyhow!("invalid subdomain_words of the profile: {}", err))?;
+        }
+        self.subdomain_words = profile.subdomain_words;
+        self.disable_tcp = self.disable_tcp.or(Some(profile.disable_tcp));
+        self.disable_udp = self.disable_udp.or(Some(profile.disable_udp));
+        self.disable_http = self.disable_http.or(Some(profile.disable_http));
+        self.admin_api = self.admin_api.or(Some(profile.admin_api));
+        self.enable_reflection = self.enable_reflection.or(Some(profile.enable_reflection));
+        self.max_pending_connections = self
+            .max_pending_connections
+            .or(profile.max_pending_connections);
+        self.max_pending_registrations = self
+            .max_pending_registrations
+            .or(profile.max_pending_registrations);
+        self.max_in_flight_bytes = self.max_in_flight_bytes.or(profile.max_in_flight_bytes);
+        self.data_chunk_size = self.data_chunk_size.or(profile.data_chunk_size);
+        self.max_request_deadline = self.max_request_deadline.or(profile.max_request_deadline);
+        self.max_request_body = self.max_request_body.or(profile.max_request_body);
+        self.max_response_body = self.max_response_body.or(profile.max_response_body);
+        self.max_udp_sessions = self.max_udp_sessions.or(profile.max_udp_sessions);
+        self.udp_idle_timeout = self.udp_idle_timeout.or(profile.udp_idle_timeout);
+        self.udp_buffer_pool = self.udp_buffer_pool.or(profile.udp_buffer_pool);
+        self.memory_budget = self.memory_budget.or(profile.memory_budget);
+        self.http_cache = self.http_cache.or(profile.http_cache);
+        self.interstitial_page = self.interstitial_page.take().or(profile.interstitial_page);
+        self.interstitial_html = self
+            .interstitial_page
+            .as_ref()
+            .map(|path| {
+                std::fs::read_to_string(path)
+                    .map_err(|err| anyhow!("failed to read {}: {}", path.display(), err))
+            })
+            .transpose()?;
+        self.control_rate_limit = self.control_rate_limit.or(profile.control_rate_limit);
+        if self.payload_key.is_none() {
+            self.payload_key = profile
+                .payload_key
+                .map(|key| key.parse())
+                .transpose()
+                .map_err(|err| anyhow!("invalid payload_key of the profile: {}", err))?;
+        }
+        self.auth_token = self.auth_token.take().or(profile.auth_token.map(AuthToken));
+        self.registration_rate_limit = self
+            .registration_rate_limit
+            .or(profile.registration_rate_limit);
+        if self.tls_cert.is_none() {
+            self.tls_cert = profile.tls_cert;
+            self.tls_key = profile.tls_key;
+        }
+        if self.tls_cert.is_some() != self.tls_key.is_some() {
+            return Err(anyhow!(
+                "tls_cert and tls_key of the profile require each other"
+            ));
+        }
+        self.bandwidth_limit = self.bandwidth_limit.or(profile.bandwidth_limit);
+        if self.tunnel_weight.is_empty() {
+            self.tunnel_weight = profile
+                .tunnel_weights
+                .iter()
+                .map(|rule| rule.parse())
+                .collect::<Result<_, _>>()
+                .map_err(|err| anyhow!("invalid tunnel_weights of the profile: {}", err))?;
+        }
+        self.tunnel_rate_limit = self.tunnel_rate_limit.or(profile.tunnel_rate_limit);
+        // the http tunnels are reached at the domains or the ips, the subdomains need a domain
+        if !self.disable_http.unwrap_or_default() {
+            if self.domain.is_empty() && self.ip.is_empty() {
+                return Err(anyhow!(
+                    "the http tunnels require a --domain or an --ip, or --disable-http"
+                ));
+            }
+            if self.word_subdomains.unwrap_or_default() && self.domain.is_empty() {
+                return Err(anyhow!("--word-subdomains requires a --domain"));
+            }
+        }
+        self.connection_log_sample = self.connection_log_sample.or(profile.connection_log_sample);
+        self.data_send_timeout = self.data_send_timeout.or(profile.data_send_timeout);
+        self.close_timeout = self.close_timeout.or(profile.close_timeout);
+        self.tcp_keepalive_idle = self.tcp_keepalive_idle.or(profile.tcp_keepalive_idle);
+        self.tcp_keepalive_interval = self
+            .tcp_keepalive_interval
+            .or(profile.tcp_keepalive_interval);
+        self.tcp_keepalive_retries = self.tcp_keepalive_retries.or(profile.tcp_keepalive_retries);
+        if self.tcp_keepalive_idle.is_none()
+            && (self.tcp_keepalive_interval.is_some() || self.tcp_keepalive_retries.is_some())
+        {
+            return Err(anyhow!(
+                "--tcp-keepalive-interval and --tcp-keepalive-retries require --tcp-keepalive-idle"
+            ));
+        }
+        Ok(())
+    }
+
+    fn to_config(&self) -> Config {
         Config {
-            control_port: args.control_port,
-            vhttp_port: args.vhttp_port,
+            control_port: self.control_port.unwrap_or(6610),
+            vhttp_port: self.vhttp_port.unwrap_or(6611),
+            vhttp_bind_ip: self.vhttp_bind_ip.unwrap_or(IpAddr::from([0, 0, 0, 0])),
+            vhttp_optional: self.vhttp_optional.unwrap_or_default(),
             entrypoint: EntrypointConfig {
-                domain: args.domain,
-                ip: args.ip,
-                vhttp_behind_proxy_tls: args.vhttp_behind_proxy_tls,
-                port_range: args.random_min_port..=args.random_max_port,
-                exclude_ports: args
+                domain: self.domain.clone(),
+                ip: self.ip.clone(),
+                vhttp_behind_proxy_tls: self.vhttp_behind_proxy_tls.unwrap_or_default(),
+                external_base_path: self
+                    .external_base_path
+                    .as_deref()
+                    .map(|base_path| base_path.trim_end_matches('/'))
+                    .filter(|base_path| !base_path.is_empty())
+                    .map(str::to_string),
+                port_range: self.random_min_port.unwrap_or(1024)
+                    ..=self.random_max_port.unwrap_or(65535),
+                exclude_ports: self
                     .exclude_ports
                     .split(',')
                     .filter(|s| !s.is_empty())
                     .map(|s| s.parse().unwrap())
                     .collect(),
+                ephemeral_ports: self.ephemeral_ports.unwrap_or_default(),
+                subdomain_words: if self.word_subdomains.unwrap_or_default() {
+                    Some(self.subdomain_words.clone().unwrap_or_default())
+                } else {
+                    None
+                },
             },
-        },
-        shutdown.clone(),
-    );
+            tcp_keepalive: self.tcp_keepalive_idle.map(|idle| TcpKeepalive {
+                idle: Duration::from_secs(idle),
+                interval: self.tcp_keepalive_interval.map(Duration::from_secs),
+                retries: self.tcp_keepalive_retries,
+            }),
+            max_pending_connections: self.max_pending_connections.unwrap_or(1024),
+            max_pending_registrations: self.max_pending_registrations.unwrap_or(1024),
+            max_in_flight_bytes: self.max_in_flight_bytes.unwrap_or(1024 * 1024),
+            data_chunk_size: self.data_chunk_size,
+            max_request_deadline: self.max_request_deadline.map(Duration::from_secs),
+            max_request_body: self.max_request_body,
+            max_response_body: self.max_response_body,
+            max_udp_sessions: self.max_udp_sessions,
+            udp_idle_timeout: self.udp_idle_timeout.map(Duration::from_secs),
+            udp_buffer_pool: self.udp_buffer_pool.unwrap_or(256 * 1024),
+            memory_budget: self.memory_budget,
+            http_cache: self.http_cache,
+            interstitial_page: self.interstitial_html.clone(),
+            control_rate_limit: self.control_rate_limit,
+            payload_key: self.payload_key.clone(),
+            auth_token: self.auth_token.as_ref().map(|token| token.0.clone()),
+            registration_rate_limit: self.registration_rate_limit,
+            control_tls: self
+                .tls_cert
+                .clone()
+                .zip(self.tls_key.clone())
+                .map(|(cert, key)| ControlTls { cert, key }),
+            bandwidth_limit: self.bandwidth_limit,
+            tunnel_weights: self.tunnel_weight.clone(),
+            tunnel_rate_limit: self.tunnel_rate_limit,
+            connection_log_sample: self.connection_log_sample,
+            data_send_timeout: self.data_send_timeout.map(Duration::from_secs),
+            close_timeout: self.close_timeout.map(Duration::from_secs),
+            disable_tcp: self.disable_tcp.unwrap_or_default(),
+            disable_udp: self.disable_udp.unwrap_or_default(),
+            disable_http: self.disable_http.unwrap_or_default(),
+            admin_api: self.admin_api.unwrap_or_default(),
+            grpc_reflection: self.enable_reflection.unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Print the completion script of the shell, e.g. `castled completions zsh > ~/.zfunc/_castled`.
+    #[command(hide = true)]
+    Completions {
+        #[clap(index = 1)]
+        shell: Shell,
+    },
+}
+
+/// reload the profile on `SIGHUP`, the flags of the command line still take precedence.
+#[cfg(unix)]
+async fn reload_on_sighup(reloader: Reloader) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = signal(SignalKind::hangup()).expect("Failed to listen for the SIGHUP signal");
+    while hangup.recv().await.is_some() {
+        info!("Received SIGHUP signal. Reloading the config...");
+        let mut args = Args::parse();
+        if let Err(err) = args.resolve() {
+            error!(?err, "failed to reload the config, keep the running one");
+            continue;
+        }
+        reloader.reload(args.to_config());
+    }
+}
+
+#[cfg(not(unix))]
+async fn reload_on_sighup(_: Reloader) {}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    // 6669 is the default tokio console server port of the server,
+    // use `TOKIO_CONSOLE_BIND=127.0.0.1:6670` to change it.
+    let mut args = Args::parse();
+    if let Some(Command::Completions { shell }) = args.command {
+        // before the logging, nothing else is printed to the stdout
+        clap_complete::generate(
+            shell,
+            &mut Args::command(),
+            "castled",
+            &mut std::io::stdout(),
+        );
+        return Ok(());
+    }
+    setup_logging(6669, log_level(args.quiet, args.verbose));
+    args.resolve()?;
+    info!("server args: {:?}", args);
+
+    let shutdown = ShutdownManager::new();
+    let wait_complete = shutdown.wait_shutdown_complete();
+
+    let server = Server::new(args.to_config(), shutdown.clone());
+    tokio::spawn(reload_on_sighup(server.reloader()));
 
     tokio::spawn(async move {
-        if let Err(e) = signal::ctrl_c().await {
+        let signal = shutdown_signal().await.unwrap_or_else(|e| {
             // Something really weird happened. So just panic
-            panic!("Failed to listen for the ctrl-c signal: {:?}", e);
-        }
-        info!("Received ctrl-c signal. Shutting down...");
+            panic!("Failed to listen for the shutdown signals: {:?}", e)
+        });
+        info!("Received {} signal. Shutting down...", signal);
         shutdown.trigger_shutdown(0).ok();
     });
 
@@ -93,3 +689,40 @@ async fn main() -> anyhow::Result<()> {
         code => std::process::exit(code as i32),
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_resolve_switches() {
+        let path = std::env::temp_dir().join(format!("castled-test-{}.toml", uuid::Uuid::new_v4()));
+        std::fs::write(&path, "admin_api = true\ndisable_udp = true\n").unwrap();
+        let config = path.to_str().unwrap();
+        let resolve = |flags: &[&str]| {
+            let mut args = Args::parse_from(
+                [&["castled", "--config", config, "--ip", "127.0.0.1"], flags].concat(),
+            );
+            args.resolve().unwrap();
+            args
+        };
+        // the http tunnels need a domain or an ip
+        assert!(Args::parse_from(["castled", "--config", config])
+            .resolve()
+            .is_err());
+        let mut args = Args::parse_from(["castled", "--config", config, "--disable-http"]);
+        assert!(args.resolve().is_ok());
+
+        let args = resolve(&[]);
+        assert_eq!((args.admin_api, args.disable_udp), (Some(true), Some(true)));
+        assert_eq!(args.disable_tcp, Some(false));
+        // the command line turns off the keys of the config
+        let args = resolve(&["--admin-api=false", "--disable-udp=false", "--disable-tcp"]);
+        assert_eq!(
+            (args.admin_api, args.disable_udp),
+            (Some(false), Some(false))
+        );
+        assert_eq!(args.disable_tcp, Some(true));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
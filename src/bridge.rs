@@ -1,10 +1,21 @@
+use std::{
+    future::poll_fn,
+    sync::{Arc, Mutex},
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
 use bytes::Bytes;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, Semaphore};
 use tokio_util::sync::CancellationToken;
 
+use crate::{constant, io::Remaining};
+
 /// IdDataSenderBridge is id with [`DataSenderBridge`].
 pub(crate) struct IdDataSenderBridge {
     pub id: Bytes,
+    /// the host of the http request, empty for the tcp and udp tunnels.
+    pub host: String,
     pub inner: DataSenderBridge,
 }
 
@@ -12,32 +23,65 @@ pub(crate) struct IdDataSenderBridge {
 ///
 /// the data server creates DataSenderBridge then sends it to control server,
 /// then the control server uses the `chan` to send data to data server.
+#[derive(Clone)]
 pub(crate) struct DataSenderBridge {
     /// BridgeChan is used for sending data to data server.
     chan: DataSender,
     /// when the server receives [`crate::protocol::pb::traffic_to_server::Action::Close`] action from [`crate::protocol::pb::tunnel_service_server::TunnelService::data`] streaming,
     /// the server will cancel the bridge.
     shutdown: CancellationToken,
+    /// shared with the [`DataReceiver`] of the data server.
+    in_flight: InFlight,
 }
 
 impl DataSenderBridge {
     /// new creates a new DataSenderBridge.
-    pub(crate) fn new(chan: DataSender, shutdown: CancellationToken) -> Self {
-        Self { chan, shutdown }
+    pub(crate) fn new(chan: DataSender, shutdown: CancellationToken, in_flight: InFlight) -> Self {
+        Self {
+            chan,
+            shutdown,
+            in_flight,
+        }
     }
 
-    /// send sends data to data server.
+    /// send sends data to data server,
+    /// it waits when the data server hasn't received the previous data of the in-flight cap.
     pub(crate) async fn send_data(
         &self,
         data: Vec<u8>,
     ) -> Result<(), mpsc::error::SendError<BridgeData>> {
+        if !self.in_flight.acquire(data.len()).await {
+            // the receiver is gone
+            return Err(mpsc::error::SendError(BridgeData::Data(data)));
+        }
+        // the bytes are given back to the budget when the receiver is dropped.
         self.chan.send(BridgeData::Data(data)).await
     }
 
+    /// send_data_within is [`DataSenderBridge::send_data`] giving up after the timeout,
+    /// e.g. the user connection is stuck and never drains the data, None waits as long as it takes.
+    pub(crate) async fn send_data_within(
+        &self,
+        data: Vec<u8>,
+        timeout: Option<Duration>,
+    ) -> Result<(), SendDataError> {
+        let Some(timeout) = timeout else {
+            return self
+                .send_data(data)
+                .await
+                .map_err(|_| SendDataError::Closed);
+        };
+        match tokio::time::timeout(timeout, self.send_data(data)).await {
+            Ok(result) => result.map_err(|_| SendDataError::Closed),
+            // the bytes taken by the abandoned data are given back with the receiver
+            Err(_) => Err(SendDataError::Timeout),
+        }
+    }
+
     /// send_sender sends sender to data server.
     pub(crate) async fn send_sender(
         &self,
-        sender: mpsc::Sender<Vec<u8>>,
+        sender: mpsc::Sender<Bytes>,
     ) -> Result<(), mpsc::error::SendError<BridgeData>> {
         self.chan.send(BridgeData::Sender(sender)).await
     }
@@ -48,6 +92,15 @@ impl DataSenderBridge {
     }
 }
 
+/// SendDataError is why the data isn't taken by the data server.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum SendDataError {
+    /// the data server is gone, e.g. the user connection is closed.
+    Closed,
+    /// the data server hasn't taken the data within the timeout.
+    Timeout,
+}
+
 /// DataSender is the sender holden by control server to send data to data server.
 ///
 /// this channel has two purposes:
@@ -60,6 +113,302 @@ pub(crate) type DataSender = mpsc::Sender<BridgeData>;
 /// BridgeData is the data of the `BridgeChan`.
 pub(crate) enum BridgeData {
     // Sender is used for sending [`BridgeData::Data`].
-    Sender(mpsc::Sender<Vec<u8>>),
+    Sender(mpsc::Sender<Bytes>),
     Data(Vec<u8>),
 }
+
+/// the max permits of a semaphore acquired at once.
+const MAX_PERMITS: usize = if Semaphore::MAX_PERMITS < u32::MAX as usize {
+    Semaphore::MAX_PERMITS
+} else {
+    u32::MAX as usize
+};
+
+/// MemoryBudget caps the bytes in flight of all the user connections of the server,
+/// it's shared by the [`InFlight`] caps of the bridges.
+///
+/// The usage is counted even if it's unlimited.
+#[derive(Debug, Clone)]
+pub(crate) struct MemoryBudget {
+    bytes: Arc<Semaphore>,
+    max: Option<usize>,
+}
+
+impl MemoryBudget {
+    /// None means unlimited.
+    pub(crate) fn new(max: Option<usize>) -> Self {
+        let max = max.map(|max| max.clamp(1, MAX_PERMITS));
+        Self {
+            bytes: Arc::new(Semaphore::new(max.unwrap_or(MAX_PERMITS))),
+            max,
+        }
+    }
+
+    pub(crate) fn max(&self) -> Option<usize> {
+        self.max
+    }
+
+    /// the bytes sent by the clients but not received by the data server yet.
+    pub(crate) fn used(&self) -> usize {
+        self.max.unwrap_or(MAX_PERMITS) - self.bytes.available_permits()
+    }
+
+    /// the budget is exhausted when it can't hold another buffer.
+    pub(crate) fn is_exhausted(&self) -> bool {
+        self.bytes.available_permits() < self.reserve()
+    }
+
+    /// wait until the budget isn't exhausted.
+    pub(crate) async fn wait_available(&self) {
+        // the budget is never closed
+        let _ = self.bytes.acquire_many(self.reserve() as u32).await;
+    }
+
+    fn reserve(&self) -> usize {
+        constant::DEFAULT_BUF_SIZE.min(self.max.unwrap_or(MAX_PERMITS))
+    }
+
+    fn permits(&self, len: usize) -> u32 {
+        len.min(self.max.unwrap_or(MAX_PERMITS)) as u32
+    }
+}
+
+/// InFlight caps the bytes sent through a bridge but not received by the data server yet.
+///
+/// The sender waits when the cap or the [`MemoryBudget`] is reached, then the control server
+/// stops reading the data stream of the client, so the data doesn't pile up in the channel.
+#[derive(Debug, Clone)]
+pub(crate) struct InFlight {
+    bytes: Arc<Semaphore>,
+    max: usize,
+    budget: MemoryBudget,
+    /// the bytes taken from the budget, they are given back when the receiver is dropped.
+    taken: Arc<Mutex<Taken>>,
+}
+
+#[derive(Debug, Default)]
+struct Taken {
+    bytes: usize,
+    closed: bool,
+}
+
+impl InFlight {
+    pub(crate) fn new(max: usize, budget: MemoryBudget) -> Self {
+        // a single data larger than the cap takes the whole cap.
+        let max = max.clamp(1, MAX_PERMITS);
+        Self {
+            bytes: Arc::new(Semaphore::new(max)),
+            max,
+            budget,
+            taken: Arc::default(),
+        }
+    }
+
+    fn permits(&self, len: usize) -> u32 {
+        len.min(self.max) as u32
+    }
+
+    /// returns false if the receiver is dropped.
+    async fn acquire(&self, len: usize) -> bool {
+        let permits = self.permits(len);
+        if permits == 0 {
+            return !self.bytes.is_closed();
+        }
+        match self.bytes.acquire_many(permits).await {
+            Ok(permit) => {
+                // given back by the receiver
+                permit.forget();
+            }
+            Err(_) => return false,
+        }
+
+        let budget = self.budget.permits(len);
+        if let Ok(permit) = self.budget.bytes.acquire_many(budget).await {
+            permit.forget();
+        }
+        let mut taken = self.taken.lock().unwrap();
+        if taken.closed {
+            self.budget.bytes.add_permits(budget as usize);
+            return false;
+        }
+        taken.bytes += budget as usize;
+        true
+    }
+
+    fn release(&self, len: usize) {
+        self.bytes.add_permits(self.permits(len) as usize);
+        let mut taken = self.taken.lock().unwrap();
+        // only the data sent by the bridge is taken from the budget
+        let budget = (self.budget.permits(len) as usize).min(taken.bytes);
+        taken.bytes -= budget;
+        self.budget.bytes.add_permits(budget);
+    }
+
+    /// give back all the bytes taken from the budget, including the data left in the channel.
+    fn close(&self) {
+        self.bytes.close();
+        let mut taken = self.taken.lock().unwrap();
+        taken.closed = true;
+        self.budget
+            .bytes
+            .add_permits(std::mem::take(&mut taken.bytes));
+    }
+}
+
+/// DataReceiver is the receiver of the [`DataSender`] held by the data server,
+/// receiving the data gives the bytes back to the [`InFlight`] cap.
+pub(crate) struct DataReceiver {
+    inner: mpsc::Receiver<BridgeData>,
+    in_flight: InFlight,
+    /// the rest of the data larger than the read buffer, see the `AsyncRead` of it.
+    pub(crate) remaining: Remaining,
+}
+
+impl DataReceiver {
+    pub(crate) fn new(inner: mpsc::Receiver<BridgeData>, in_flight: InFlight) -> Self {
+        Self {
+            inner,
+            in_flight,
+            remaining: Remaining::default(),
+        }
+    }
+
+    pub(crate) async fn recv(&mut self) -> Option<BridgeData> {
+        poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    pub(crate) fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Option<BridgeData>> {
+        let data = ready!(self.inner.poll_recv(cx));
+        if let Some(BridgeData::Data(data)) = &data {
+            self.in_flight.release(data.len());
+        }
+        Poll::Ready(data)
+    }
+}
+
+impl Drop for DataReceiver {
+    fn drop(&mut self) {
+        // wake up the sender waiting for the cap
+        self.in_flight.close();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use tokio::time::timeout;
+
+    use super::*;
+
+    fn bridge(max_in_flight: usize) -> (DataSenderBridge, DataReceiver) {
+        bridge_with_budget(max_in_flight, MemoryBudget::new(None))
+    }
+
+    fn bridge_with_budget(
+        max_in_flight: usize,
+        budget: MemoryBudget,
+    ) -> (DataSenderBridge, DataReceiver) {
+        let (chan, receiver) = mpsc::channel(1024);
+        let in_flight = InFlight::new(max_in_flight, budget);
+        (
+            DataSenderBridge::new(chan, CancellationToken::new(), in_flight.clone()),
+            DataReceiver::new(receiver, in_flight),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_in_flight_cap() {
+        let (sender, mut receiver) = bridge(10);
+        sender.send_data(vec![0; 6]).await.unwrap();
+
+        // the slow reader hasn't received the first data yet
+        let second = sender.send_data(vec![1; 6]);
+        tokio::pin!(second);
+        assert!(timeout(Duration::from_millis(50), &mut second)
+            .await
+            .is_err());
+
+        assert!(matches!(receiver.recv().await, Some(BridgeData::Data(data)) if data == [0; 6]));
+        second.await.unwrap();
+        assert!(matches!(receiver.recv().await, Some(BridgeData::Data(data)) if data == [1; 6]));
+
+        // a data larger than the cap is sent alone
+        sender.send_data(vec![2; 100]).await.unwrap();
+        assert_eq!(sender.in_flight.bytes.available_permits(), 0);
+        receiver.recv().await.unwrap();
+        assert_eq!(sender.in_flight.bytes.available_permits(), 10);
+
+        // the waiting sender fails when the reader is gone
+        sender.send_data(vec![3; 10]).await.unwrap();
+        let blocked = tokio::spawn({
+            let sender = sender.clone();
+            async move { sender.send_data(vec![4; 1]).await }
+        });
+        drop(receiver);
+        assert!(blocked.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_send_data_within() {
+        let (sender, receiver) = bridge(10);
+        let within = Some(Duration::from_millis(50));
+        sender.send_data_within(vec![0; 4], within).await.unwrap();
+
+        // the receiver never drains the data
+        assert_eq!(
+            sender.send_data_within(vec![1; 10], within).await,
+            Err(SendDataError::Timeout)
+        );
+        drop(receiver);
+        assert_eq!(
+            sender.send_data_within(vec![2; 1], within).await,
+            Err(SendDataError::Closed)
+        );
+        assert_eq!(
+            sender.send_data_within(vec![2; 1], None).await,
+            Err(SendDataError::Closed)
+        );
+
+        // the full channel times out as well
+        let (chan, mut receiver) = mpsc::channel(1);
+        let in_flight = InFlight::new(1024, MemoryBudget::new(None));
+        let sender = DataSenderBridge::new(chan, CancellationToken::new(), in_flight.clone());
+        sender.send_data_within(vec![0; 1], within).await.unwrap();
+        assert_eq!(
+            sender.send_data_within(vec![1; 1], within).await,
+            Err(SendDataError::Timeout)
+        );
+        assert!(matches!(receiver.recv().await, Some(BridgeData::Data(data)) if data == [0; 1]));
+    }
+
+    #[tokio::test]
+    async fn test_memory_budget() {
+        let budget = MemoryBudget::new(Some(16));
+        let (first, mut first_receiver) = bridge_with_budget(10, budget.clone());
+        let (second, second_receiver) = bridge_with_budget(10, budget.clone());
+
+        first.send_data(vec![0; 10]).await.unwrap();
+        second.send_data(vec![1; 6]).await.unwrap();
+        assert_eq!(budget.used(), 16);
+        assert!(budget.is_exhausted());
+
+        // the budget is shared, the second bridge waits for the first one
+        let blocked = second.send_data(vec![2; 1]);
+        tokio::pin!(blocked);
+        assert!(timeout(Duration::from_millis(50), &mut blocked)
+            .await
+            .is_err());
+        first_receiver.recv().await.unwrap();
+        blocked.await.unwrap();
+        assert_eq!(budget.used(), 7);
+
+        // the data left in the channel is given back with the receiver
+        drop(second_receiver);
+        assert_eq!(budget.used(), 0);
+        assert!(!budget.is_exhausted());
+        timeout(Duration::from_millis(50), budget.wait_available())
+            .await
+            .unwrap();
+    }
+}
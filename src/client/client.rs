@@ -1,34 +1,70 @@
 use anyhow::{Context as _, Result};
 use async_shutdown::{ShutdownManager, ShutdownSignal};
-use std::{net::SocketAddr, sync::Arc, time::Duration};
+use rand::Rng as _;
+use std::{
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 use tokio_stream::{wrappers::ReceiverStream, StreamExt};
-use tonic::{transport::Channel, Response, Status, Streaming};
-use tracing::{debug, error, info, instrument, span};
+use tonic::{
+    service::interceptor::InterceptedService, transport::Channel, Response, Status, Streaming,
+};
+use tracing::{debug, error, info, instrument, span, warn};
+use uuid::Uuid;
 
 use tokio::{
     io::{self, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
     select,
     sync::{mpsc, oneshot},
-    time::timeout,
+    time::{sleep, timeout},
 };
 
 use crate::socket::Dialer;
 use crate::{
+    auth::AttachToken,
+    compress::{self, PAYLOAD_COMPRESSION},
     constant,
+    crypto::{Direction, PayloadCipher, PayloadKey, PAYLOAD_CIPHER},
+    flow::{self, Replenisher},
     io::{StreamingReader, StreamingWriter, TrafficToServerWrapper},
     pb::{
-        self, control_command::Payload, traffic_to_server,
-        tunnel_service_client::TunnelServiceClient, ControlCommand, RegisterReq, TrafficToClient,
-        TrafficToServer,
+        self, close_payload::Reason, control_command::Payload, traffic_to_server,
+        tunnel_service_client::TunnelServiceClient, ControlCommand, InitPayload, RegisterReq,
+        TrafficToClient, TrafficToServer,
     },
+    protocol,
+    timing::{self, FirstRead, SetupTimer},
 };
 
-use super::tunnel::Tunnel;
+use super::{
+    handle::{Reporter, TunnelHandle},
+    probe,
+    shadow::TeeWriter,
+    tls::ClientTls,
+    tunnel::Tunnel,
+};
+
+/// the rpc client of the server sending the auth token with every rpc.
+type RpcClient = TunnelServiceClient<InterceptedService<Channel, AttachToken>>;
 
 /// Client represents a castle client that can register tunnels with the server.
 #[derive(Clone)]
 pub struct Client {
-    grpc_client: TunnelServiceClient<Channel>,
+    channel: Channel,
+    /// sent with every control and data stream, see [`Client::with_auth_token`].
+    auth: AttachToken,
+    /// the tls of the control connections, they are plaintext without it.
+    tls: Option<ClientTls>,
+    /// the control addresses of the servers in the order of preference.
+    servers: Vec<SocketAddr>,
+    /// the control address of the server the client is connected to.
+    active_server: SocketAddr,
+    /// the max backoff of reconnecting the tunnels, they give up after a few retries without it.
+    reconnect: Option<Duration>,
+    /// sent with the registrations, so the server replaces only the tunnels of this client
+    /// registered again, the clones share it.
+    instance_id: String,
 }
 
 impl Client {
@@ -40,12 +76,124 @@ impl Client {
     /// }
     /// ```
     pub async fn new(addr: SocketAddr) -> Result<Self> {
-        let grpc_client = new_rpc_client(addr).await?;
-        Ok(Self { grpc_client })
+        Self::with_servers(vec![addr]).await
+    }
+
+    /// Creates a new `Client` instance connected to the first reachable server,
+    /// the rest addresses are the backups, they are tried in order when the active server
+    /// is unreachable.
+    ///
+    /// ```
+    /// async fn run() {
+    ///     let client = castled::client::Client::with_servers(vec![
+    ///         "127.0.0.1:6100".parse().unwrap(),
+    ///         "127.0.0.1:6200".parse().unwrap(),
+    ///     ]).await.unwrap();
+    ///     println!("connected to {}", client.active_server());
+    /// }
+    /// ```
+    pub async fn with_servers(servers: Vec<SocketAddr>) -> Result<Self> {
+        Self::connect_servers(servers, None).await
+    }
+
+    /// Creates a new `Client` instance like [`Client::with_servers`], but connects to the servers
+    /// with the tls, the servers must serve the control port with the tls too.
+    ///
+    /// ```no_run
+    /// async fn run() {
+    ///     let tls = castled::client::ClientTls::from_ca_file("ca.pem").unwrap();
+    ///     let client = castled::client::Client::with_tls(vec!["127.0.0.1:6100".parse().unwrap()], tls)
+    ///         .await
+    ///         .unwrap();
+    /// }
+    /// ```
+    pub async fn with_tls(servers: Vec<SocketAddr>, tls: ClientTls) -> Result<Self> {
+        Self::connect_servers(servers, Some(tls)).await
     }
 
-    /// Registers a tunnel with the server and returns a future that represents the tunnel handler.
-    /// Also returns a receiver for receiving the assigned entrypoint from the server.
+    async fn connect_servers(servers: Vec<SocketAddr>, tls: Option<ClientTls>) -> Result<Self> {
+        let (active_server, channel) = connect_any(&servers, tls.as_ref()).await?;
+        Ok(Self {
+            channel,
+            auth: AttachToken::default(),
+            tls,
+            servers,
+            active_server,
+            reconnect: None,
+            instance_id: Uuid::new_v4().to_string(),
+        })
+    }
+
+    /// Send the token with every rpc, the server with an auth token rejects the clients without it.
+    ///
+    /// It fails if the token isn't visible ascii.
+    ///
+    /// ```
+    /// async fn run() {
+    ///     let client = castled::client::Client::new("127.0.0.1:6100".parse().unwrap())
+    ///         .await
+    ///         .unwrap()
+    ///         .with_auth_token("secret")
+    ///         .unwrap();
+    /// }
+    /// ```
+    pub fn with_auth_token(mut self, token: &str) -> Result<Self> {
+        self.auth = AttachToken::new(token)?;
+        Ok(self)
+    }
+
+    /// Keep reconnecting the tunnels until the servers are back, e.g. after a long outage,
+    /// the tunnels give up after a few retries by default.
+    ///
+    /// The backoff doubles after each retry up to the max, a random part of it is taken,
+    /// so the clients don't register again all at once after the server restarted.
+    /// The user connections in flight are lost, see [`TunnelHandle::state`] to follow it.
+    ///
+    /// ```
+    /// async fn run() {
+    ///     let client = castled::client::Client::new("127.0.0.1:6100".parse().unwrap())
+    ///         .await
+    ///         .unwrap()
+    ///         .with_reconnect(std::time::Duration::from_secs(30));
+    /// }
+    /// ```
+    pub fn with_reconnect(mut self, max_backoff: Duration) -> Self {
+        self.reconnect = Some(max_backoff.max(constant::CLIENT_RETRY_BACKOFF));
+        self
+    }
+
+    fn rpc_client(&self) -> RpcClient {
+        TunnelServiceClient::with_interceptor(self.channel.clone(), self.auth.clone())
+    }
+
+    /// Returns the control address of the server the client is connected to,
+    /// the tunnels of [`Client::spawn_tunnel`] fail over on their own,
+    /// see [`super::Registration::server`] for theirs.
+    pub fn active_server(&self) -> SocketAddr {
+        self.active_server
+    }
+
+    /// fail over to the next reachable server after the active one.
+    async fn failover(&mut self) -> Result<()> {
+        let active = self
+            .servers
+            .iter()
+            .position(|addr| *addr == self.active_server)
+            .unwrap_or_default();
+        // the active server is the last resort.
+        let candidates: Vec<SocketAddr> = self.servers[active + 1..]
+            .iter()
+            .chain(&self.servers[..=active])
+            .copied()
+            .collect();
+        let (active_server, channel) = connect_any(&candidates, self.tls.as_ref()).await?;
+        self.active_server = active_server;
+        self.channel = channel;
+        Ok(())
+    }
+
+    /// Registers a tunnel with the server and returns the assigned entrypoint once it's registered,
+    /// the tunnel keeps running in the background until the shutdown is triggered.
     ///
     /// # Example
     ///
@@ -82,35 +230,69 @@ impl Client {
         tunnel: Tunnel<'_>,
         shutdown: ShutdownManager<i8>,
     ) -> Result<Vec<String>> {
-        let (entrypoint_tx, entrypoint_rx) = oneshot::channel();
-        let pb_tunnel = tunnel.config.to_pb_tunnel(tunnel.name);
+        let registration = self
+            .spawn_tunnel(tunnel, shutdown)
+            .registered()
+            .await
+            .context("failed to start tunnel")?;
+        Ok(registration.entrypoint)
+    }
+
+    /// Starts the tunnel like [`Client::start_tunnel`], but returns its handle right away,
+    /// the handle tells what the server assigned to the tunnel, e.g. the random port,
+    /// once it's registered, see [`TunnelHandle`].
+    ///
+    /// ```no_run
+    /// use std::net::SocketAddr;
+    /// use castled::client::{Client, tunnel::{RemoteConfig, Tunnel}};
+    /// use async_shutdown::ShutdownManager;
+    ///
+    /// async fn run() {
+    ///     let client = Client::new("127.0.0.1:6100".parse().unwrap()).await.unwrap();
+    ///     let tunnel = Tunnel::new("my-tunnel", SocketAddr::from(([127, 0, 0, 1], 8971)), RemoteConfig::Tcp(0));
+    ///     let shutdown = ShutdownManager::new();
+    ///     let mut handle = client.spawn_tunnel(tunnel, shutdown.clone());
+    ///     let registration = handle.registered().await.unwrap();
+    ///     println!("remote port: {:?}", registration.remote_port);
+    ///     shutdown.wait_shutdown_complete().await;
+    /// }
+    /// ```
+    pub fn spawn_tunnel(self, tunnel: Tunnel<'_>, shutdown: ShutdownManager<i8>) -> TunnelHandle {
+        let (handle, reporter) = TunnelHandle::new();
+        let pb_tunnel = tunnel.to_pb_tunnel();
         let dialer = tunnel.dialer;
+        let probe_targets = tunnel.probe_targets;
+        let payload_key = tunnel.payload_key;
+        let wait_for_local = tunnel.wait_for_local;
 
+        let mut this = self;
         tokio::spawn(async move {
             let _delay = shutdown.delay_shutdown_token();
-            if let Err(err) = self
-                .handle_tunnel(
+            let result = async {
+                if let Some(timeout) = wait_for_local {
+                    wait_for_local_endpoint(shutdown.wait_shutdown_triggered(), &dialer, timeout)
+                        .await?;
+                }
+                this.handle_tunnel(
                     shutdown.wait_shutdown_triggered(),
                     pb_tunnel,
                     dialer,
-                    Some(move |entrypoint| {
-                        let _ = entrypoint_tx.send(entrypoint);
-                    }),
+                    probe_targets,
+                    payload_key,
+                    &reporter,
                 )
                 .await
-            {
+            };
+            if let Err(err) = result.await {
                 error!(?err, "failed to handle tunnel");
+                reporter.stopped(Some(err));
                 shutdown.trigger_shutdown_token(1)
             } else {
+                reporter.stopped(None);
                 shutdown.trigger_shutdown_token(0)
             }
         });
-
-        let entrypoint = entrypoint_rx
-            .await
-            .context("failed to start tunnel, check the log")?;
-
-        Ok(entrypoint)
+        handle
     }
 
     /// wait to receive first init command from the server.
@@ -119,30 +301,43 @@ impl Client {
         &self,
         shutdown: ShutdownSignal<i8>,
         control_stream: &mut Streaming<ControlCommand>,
-    ) -> Result<Vec<String>> {
+    ) -> Result<InitPayload> {
         select! {
             _ = shutdown => {
                 debug!("cancelling tcp tunnel");
                 return Err(Status::cancelled("tunnel registration cancelled").into());
             }
             result = control_stream.next() => {
-                let result = result.unwrap();
-                if result.is_err() {
-                    return Err(result.unwrap_err().into());
-                }
-                let command = result.unwrap();
+                let command = match result.unwrap() {
+                    Ok(command) => command,
+                    Err(status) => return Err(status.into()),
+                };
                 match command.payload {
                     Some(Payload::Init(init)) => {
+                        // the server predating them tells neither its version nor its start.
+                        let server_uptime = (init.server_started_at > 0).then(|| {
+                            let started_at = UNIX_EPOCH + Duration::from_secs(init.server_started_at);
+                            SystemTime::now().duration_since(started_at).unwrap_or_default()
+                        });
                         info!(
                             tunnel_id = init.tunnel_id,
                             entrypoint = ?init.assigned_entrypoint,
+                            data_stream_version = init.data_stream_version,
+                            server_version = init.server_version,
+                            ?server_uptime,
                             "tunnel registered successfully",
                         );
-                        return Ok(init.assigned_entrypoint);
+                        return Ok(init);
                     }
                     Some(Payload::Work(_)) => {
                         error!("unexpected work command");
                     }
+                    Some(Payload::Probe(_)) => {
+                        error!("unexpected probe command");
+                    }
+                    Some(Payload::Close(close)) => {
+                        return Err(close_status(close).into());
+                    }
                     None => {
                         error!("missing payload in init command");
                     }
@@ -154,8 +349,9 @@ impl Client {
 
     async fn register_tunnel(
         &self,
-        rpc_client: &mut TunnelServiceClient<Channel>,
+        rpc_client: &mut RpcClient,
         tunnel: pb::Tunnel,
+        encrypted: bool,
     ) -> Result<Response<Streaming<ControlCommand>>> {
         let span = span!(
             tracing::Level::INFO,
@@ -167,60 +363,161 @@ impl Client {
         rpc_client
             .register(RegisterReq {
                 tunnel: Some(tunnel),
+                data_stream_version: protocol::DATA_STREAM_VERSION,
+                instance_id: self.instance_id.clone(),
+                payload_cipher: if encrypted {
+                    PAYLOAD_CIPHER.to_string()
+                } else {
+                    String::new()
+                },
             })
             .await
             .map_err(|err| err.into())
     }
 
     /// Handles the tunnel registration process.
-    #[allow(dead_code)]
+    ///
+    /// The transient errors(see [`is_retriable`]) of the registration and the control stream
+    /// re-register the tunnel after a backoff, failing over to the next server,
+    /// the rest errors are returned immediately, e.g. an invalid tunnel.
+    /// The retries are unlimited with [`Client::with_reconnect`].
     async fn handle_tunnel(
-        &self,
+        &mut self,
         shutdown: ShutdownSignal<i8>,
         tunnel: pb::Tunnel,
         dial: Dialer,
-        hook: Option<impl FnOnce(Vec<String>) + Send + 'static>,
+        probe_targets: Vec<SocketAddr>,
+        payload_key: Option<PayloadKey>,
+        reporter: &Reporter,
     ) -> Result<()> {
-        let mut rpc_client = self.grpc_client.clone();
+        let dialer = Arc::new(dial);
+        let probe_targets = Arc::new(probe_targets);
+        let mut retries = 0;
+        loop {
+            let mut registered = false;
+            let err = match self
+                .run_tunnel(
+                    shutdown.clone(),
+                    tunnel.clone(),
+                    Arc::clone(&dialer),
+                    Arc::clone(&probe_targets),
+                    payload_key.as_ref(),
+                    reporter,
+                    &mut registered,
+                )
+                .await
+            {
+                Ok(()) => return Ok(()),
+                Err(err) => err,
+            };
+            if registered {
+                // the tunnel has been running, it's a new interruption
+                retries = 0;
+            }
+            let exhausted = self.reconnect.is_none() && retries >= constant::CLIENT_MAX_RETRIES;
+            if !is_retriable(&err) || exhausted {
+                return Err(err);
+            }
+            let backoff = retry_backoff(retries, self.reconnect);
+            retries = retries.saturating_add(1);
+            warn!(server = ?self.active_server, ?err, ?backoff, retries, "the tunnel is interrupted, retrying");
+            reporter.reconnecting(retries, backoff);
+            select! {
+                _ = shutdown.clone() => return Ok(()),
+                _ = sleep(backoff) => {}
+            }
+            match self.failover().await {
+                Ok(()) => {
+                    info!(server = ?self.active_server, "reconnected, registering the tunnel again")
+                }
+                // the registration fails with the stale client, it's retried again
+                Err(err) => warn!(?err, "failed to reconnect to the servers"),
+            }
+        }
+    }
+
+    /// register the tunnel on the active server and serve its control stream.
+    #[allow(clippy::too_many_arguments)]
+    async fn run_tunnel(
+        &self,
+        shutdown: ShutdownSignal<i8>,
+        tunnel: pb::Tunnel,
+        dialer: Arc<Dialer>,
+        probe_targets: Arc<Vec<SocketAddr>>,
+        payload_key: Option<&PayloadKey>,
+        reporter: &Reporter,
+        registered: &mut bool,
+    ) -> Result<()> {
+        let mut rpc_client = self.rpc_client();
         let response = timeout(
             Duration::from_secs(3),
-            self.register_tunnel(&mut rpc_client, tunnel),
+            self.register_tunnel(&mut rpc_client, tunnel, payload_key.is_some()),
         )
-        .await??;
+        .await
+        .map_err(anyhow::Error::from)
+        .and_then(|result| result)?;
 
         self.handle_control_stream(
-            shutdown.clone(),
+            shutdown,
             rpc_client,
             response.into_inner(),
-            dial,
-            hook,
+            dialer,
+            probe_targets,
+            payload_key,
+            reporter,
+            registered,
         )
         .await
     }
 
     /// Handles the control stream from the server.
-    #[instrument(skip(self, shutdown, rpc_client, control_stream, hook))]
+    #[allow(clippy::too_many_arguments)]
+    #[instrument(skip(
+        self,
+        shutdown,
+        rpc_client,
+        control_stream,
+        payload_key,
+        reporter,
+        registered
+    ))]
     async fn handle_control_stream(
         &self,
         shutdown: ShutdownSignal<i8>,
-        rpc_client: TunnelServiceClient<Channel>,
+        rpc_client: RpcClient,
         mut control_stream: Streaming<ControlCommand>,
-        dialer: Dialer,
-        mut hook: Option<impl FnOnce(Vec<String>) + Send + 'static>,
+        dialer: Arc<Dialer>,
+        probe_targets: Arc<Vec<SocketAddr>>,
+        payload_key: Option<&PayloadKey>,
+        reporter: &Reporter,
+        registered: &mut bool,
     ) -> Result<()> {
-        let entrypoint = self
+        let init = self
             .wait_until_registered(shutdown.clone(), &mut control_stream)
             .await?;
-        if let Some(hook) = hook.take() {
-            hook(entrypoint);
+        // the server may predate the negotiation, then it speaks version 1.
+        let data_stream_version = protocol::accept(init.data_stream_version)?;
+        // the server predating the encryption ignores the cipher, never fall back to plaintext.
+        if payload_key.is_some() && init.payload_cipher != PAYLOAD_CIPHER {
+            return Err(Status::failed_precondition(
+                "the server doesn't support the payload encryption",
+            )
+            .into());
         }
+        // the server predating the compression leaves the datagrams uncompressed.
+        let compressed = init.compression == PAYLOAD_COMPRESSION;
+        // the server predating the negotiation answers 0, the writes are sent as they are then.
+        let chunk_size = (init.max_chunk_size > 0).then_some(init.max_chunk_size as usize);
+        *registered = true;
+        // the re-registrations are published too, e.g. the random port may change.
+        reporter.registered(self.active_server, &init);
 
-        let dialer = Arc::new(dialer);
         loop {
             tokio::select! {
                 result = control_stream.next() => {
                     if result.is_none() {
-                        return Err(anyhow::anyhow!("control stream closed unexpectedly"));
+                        // e.g. the server is restarting
+                        return Err(Status::unavailable("control stream closed unexpectedly").into());
                     }
                     let command = result.unwrap()?;
                     match command.payload {
@@ -231,16 +528,36 @@ impl Client {
                             debug!("received work command, starting to forward traffic");
                             let rpc_client = rpc_client.clone();
                             let dialer = dialer.clone();
+                            let payload_key = payload_key.cloned();
                             tokio::spawn(async move {
                                 if let Err(err) = handle_work_traffic(
                                     rpc_client,
                                     &work.connection_id,
+                                    &work.host,
                                     dialer,
+                                    data_stream_version,
+                                    payload_key,
+                                    compressed,
+                                    chunk_size,
                                 ).await {
                                     error!(?err, "failed to handle work traffic");
                                 }
                             });
                         }
+                        Some(Payload::Probe(payload)) => {
+                            debug!(target = payload.target, "received probe command");
+                            let mut rpc_client = rpc_client.clone();
+                            let probe_targets = probe_targets.clone();
+                            tokio::spawn(async move {
+                                let report = probe::probe(&probe_targets, payload).await;
+                                if let Err(err) = rpc_client.report_probe(report).await {
+                                    warn!(?err, "failed to report probe");
+                                }
+                            });
+                        }
+                        Some(Payload::Close(close)) => {
+                            return Err(close_status(close).into());
+                        }
                         None => {
                             error!("missing payload in work command");
                             return Err(anyhow::anyhow!("missing payload in work command"));
@@ -256,25 +573,156 @@ impl Client {
     }
 }
 
-#[instrument]
-async fn new_rpc_client(control_addr: SocketAddr) -> Result<TunnelServiceClient<Channel>> {
+/// connect to the first reachable server of the addresses.
+async fn connect_any(
+    servers: &[SocketAddr],
+    tls: Option<&ClientTls>,
+) -> Result<(SocketAddr, Channel)> {
+    for addr in servers {
+        match connect(*addr, tls).await {
+            Ok(channel) => {
+                info!(server = ?addr, "connected to the server");
+                return Ok((*addr, channel));
+            }
+            Err(err) => {
+                warn!(server = ?addr, ?err, "failed to connect to the server");
+            }
+        }
+    }
+    Err(anyhow::anyhow!(
+        "none of the servers is reachable: {:?}",
+        servers
+    ))
+}
+
+/// the error of the tunnel closed by the server, the reason decides whether it's retried:
+/// the tunnel is registered again when the server shuts down, but not when it's evicted or revoked.
+fn close_status(close: pb::ClosePayload) -> Status {
+    let reason = close.reason();
+    warn!(
+        reason = reason.as_str_name(),
+        message = close.message,
+        "the server closed the tunnel"
+    );
+    let message = if close.message.is_empty() {
+        format!("the server closed the tunnel: {}", reason.as_str_name())
+    } else {
+        format!(
+            "the server closed the tunnel: {}: {}",
+            reason.as_str_name(),
+            close.message
+        )
+    };
+    match reason {
+        Reason::ServerShutdown | Reason::Unspecified => Status::unavailable(message),
+        Reason::Evicted => Status::cancelled(message),
+        Reason::Revoked => Status::permission_denied(message),
+    }
+}
+
+/// the backoff before the retry, it doubles after each retry.
+/// Reconnecting, it's capped at the max and a random half is cut off,
+/// so the clients don't register again all at once after the server restarted.
+fn retry_backoff(retries: u32, reconnect: Option<Duration>) -> Duration {
+    let backoff = constant::CLIENT_RETRY_BACKOFF.saturating_mul(2u32.saturating_pow(retries));
+    match reconnect {
+        Some(max) => backoff
+            .min(max)
+            .mul_f64(rand::thread_rng().gen_range(0.5..=1.0)),
+        None => backoff,
+    }
+}
+
+/// the tunnel fails because of the server or the network, not the tunnel itself,
+/// e.g. the server is restarting, it's worth registering the tunnel again.
+fn is_retriable(err: &anyhow::Error) -> bool {
+    if err.is::<tokio::time::error::Elapsed>() {
+        return true;
+    }
+    err.downcast_ref::<Status>().is_some_and(|status| {
+        matches!(
+            status.code(),
+            tonic::Code::Unavailable | tonic::Code::DeadlineExceeded | tonic::Code::Aborted
+        )
+    })
+}
+
+#[instrument(skip(tls))]
+async fn connect(control_addr: SocketAddr, tls: Option<&ClientTls>) -> Result<Channel> {
     debug!("connecting server");
 
-    TunnelServiceClient::connect(format!("http://{}", control_addr))
+    let Some(tls) = tls.cloned() else {
+        return Channel::from_shared(format!("http://{}", control_addr))
+            .context("invalid server address")?
+            .connect()
+            .await
+            .context("Failed to connect to the server");
+    };
+    Channel::from_shared(format!("https://{}", control_addr))
+        .context("invalid server address")?
+        // the channel reconnects with it too.
+        .connect_with_connector(tower::service_fn(move |_: tonic::transport::Uri| {
+            let tls = tls.clone();
+            async move { tls.connect(control_addr).await }
+        }))
         .await
         .context("Failed to connect to the server")
-        .map_err(Into::into)
+}
+
+/// dial the local endpoint every `WAIT_FOR_LOCAL_INTERVAL` until it accepts a connection,
+/// the connection is closed right away, e.g. the local server is still starting.
+///
+/// It fails once the timeout is exceeded, with the last error of dialing,
+/// or when the client shuts down meanwhile.
+async fn wait_for_local_endpoint(
+    shutdown: ShutdownSignal<i8>,
+    dialer: &Dialer,
+    timeout: Duration,
+) -> Result<()> {
+    let deadline = tokio::time::Instant::now() + timeout;
+    loop {
+        let err = match tokio::time::timeout_at(deadline, dialer.dial()).await {
+            Ok(Ok(_)) => {
+                info!(local_endpoint = %dialer.addr(), "the local endpoint is ready");
+                return Ok(());
+            }
+            Ok(Err(err)) => err.to_string(),
+            Err(_) => "timed out".to_string(),
+        };
+        if tokio::time::Instant::now() >= deadline {
+            return Err(anyhow::anyhow!(
+                "the local endpoint {} isn't ready after {:?}: {}",
+                dialer.addr(),
+                timeout,
+                err
+            ));
+        }
+        debug!(local_endpoint = %dialer.addr(), err, "waiting for the local endpoint");
+        select! {
+            _ = shutdown.clone() => {
+                return Err(Status::cancelled("waiting for the local endpoint cancelled").into());
+            }
+            _ = sleep(constant::WAIT_FOR_LOCAL_INTERVAL) => {}
+        }
+    }
 }
 
 /// Handles the work traffic from the server to the local endpoint.
-#[instrument(skip(rpc_client))]
+#[allow(clippy::too_many_arguments)]
+#[instrument(skip(rpc_client, payload_key))]
 async fn handle_work_traffic(
-    mut rpc_client: TunnelServiceClient<Channel>,
+    mut rpc_client: RpcClient,
     connection_id: &str,
+    host: &str,
     dialer: Arc<Dialer>,
+    data_stream_version: u32,
+    payload_key: Option<PayloadKey>,
+    compressed: bool,
+    chunk_size: Option<usize>,
 ) -> Result<()> {
     // write response to the streaming_tx
     // rpc_client sends the data from reading the streaming_rx
+    let mut timer = SetupTimer::start();
     let (streaming_tx, streaming_rx) = mpsc::channel::<TrafficToServer>(64);
     let streaming_to_server = ReceiverStream::new(streaming_rx);
 
@@ -283,12 +731,14 @@ async fn handle_work_traffic(
         .await
         .unwrap()
         .into_inner();
+    let data_stream = timer.lap();
 
     let connection_id = connection_id.to_string();
     let streaming_tx_for_first_msg = streaming_tx.clone();
     let start_message = TrafficToServer {
         connection_id: connection_id.clone(),
         action: traffic_to_server::Action::Start as i32,
+        version: data_stream_version,
         ..Default::default()
     };
 
@@ -296,8 +746,28 @@ async fn handle_work_traffic(
     // then forward_traffic_to_local can read the data from transfer_rx
     let (transfer_tx, transfer_rx) = mpsc::channel::<TrafficToClient>(64);
 
+    let mut opener = payload_key
+        .as_ref()
+        .map(|key| PayloadCipher::new(key, &connection_id, Direction::ToClient));
+    let sealer = payload_key
+        .as_ref()
+        .map(|key| PayloadCipher::new(key, &connection_id, Direction::ToServer));
+
+    // the credits of the client granted by the server, and the messages of the server to grant back.
+    let (credits, granter, mut replenisher) = if protocol::flow_controlled(data_stream_version) {
+        let (credits, granter) = flow::credits(protocol::INITIAL_CREDITS);
+        let replenisher = Replenisher::new(protocol::INITIAL_CREDITS);
+        (Some(credits), Some(granter), Some(replenisher))
+    } else {
+        (None, None, None)
+    };
+    let credit_connection_id = connection_id.clone();
+
     let (local_conn_established_tx, local_conn_established_rx) = mpsc::channel::<()>(1);
     let mut local_conn_established_rx = Some(local_conn_established_rx);
+    // dropped once the local transfer finishes, the grants are no longer needed by then,
+    // so the data stream is released instead of waiting for the server to end it.
+    let (transferred_tx, mut transferred_rx) = oneshot::channel::<()>();
     tokio::spawn(async move {
         if local_conn_established_rx
             .take()
@@ -318,11 +788,61 @@ async fn handle_work_traffic(
             .unwrap();
 
         loop {
-            let result = streaming_response.next().await;
+            let result = select! {
+                result = streaming_response.next() => result,
+                _ = &mut transferred_rx => {
+                    debug!("the local transfer finished, release the data streaming");
+                    return;
+                }
+            };
 
             match result {
-                Some(Ok(traffic)) => {
-                    transfer_tx.send(traffic).await.unwrap();
+                Some(Ok(mut traffic)) => {
+                    if traffic.credit > 0 {
+                        // the grant carries no data, it isn't sealed either.
+                        if let Some(granter) = &granter {
+                            granter.grant(traffic.credit);
+                        }
+                        continue;
+                    }
+                    if let Some(opener) = &mut opener {
+                        // the payload is opened in place, it's copied out of the received message.
+                        match opener.open(traffic.data.to_vec()) {
+                            Ok(data) => traffic.data = data.into(),
+                            Err(status) => {
+                                // closing the transfer closes the local connection.
+                                error!(?status, "invalid payload from the server");
+                                return;
+                            }
+                        }
+                    }
+                    if compressed {
+                        match compress::decompress(&traffic.data) {
+                            Ok(data) => traffic.data = data.into(),
+                            Err(status) => {
+                                // only the datagram is lost
+                                warn!(?status, "invalid compressed datagram from the server");
+                                continue;
+                            }
+                        }
+                    }
+                    if transfer_tx.send(traffic).await.is_err() {
+                        // the local transfer is gone
+                        return;
+                    }
+                    // the message is queued for the local connection, the queue is bounded,
+                    // so a slow local endpoint stops the grants.
+                    if let Some(credit) = replenisher.as_mut().and_then(Replenisher::consume) {
+                        let grant = TrafficToServer {
+                            connection_id: credit_connection_id.clone(),
+                            action: traffic_to_server::Action::Credit as i32,
+                            credit,
+                            ..Default::default()
+                        };
+                        if streaming_tx_for_first_msg.send(grant).await.is_err() {
+                            return;
+                        }
+                    }
                 }
                 Some(Err(status)) => {
                     error!("received error status: {:?}", status);
@@ -337,22 +857,46 @@ async fn handle_work_traffic(
         }
     });
 
-    let wrapper = TrafficToServerWrapper::new(connection_id.clone());
-    let writer = StreamingWriter::new(streaming_tx.clone(), wrapper);
+    let wrapper = TrafficToServerWrapper::new(connection_id.clone(), sealer, compressed);
+    let writer = StreamingWriter::new(streaming_tx.clone(), wrapper)
+        .with_credits(credits)
+        .with_chunk_size(chunk_size);
 
+    let host = host.to_string();
     tokio::spawn(async move {
-        match dialer.dial().await {
+        let _transferred = transferred_tx;
+        match dialer.dial_host(&host).await {
             Ok((local_r, local_w)) => {
+                let dial = timer.lap();
+                let timed_connection_id = connection_id.clone();
+                let local_r = FirstRead::new(local_r, move || {
+                    let first_byte = timer.lap();
+                    debug!(
+                        target: timing::TARGET,
+                        connection_id = timed_connection_id,
+                        ?data_stream,
+                        ?dial,
+                        ?first_byte,
+                        total = ?timer.total(),
+                        "local connection set up"
+                    );
+                });
                 local_conn_established_tx.send(()).await.unwrap();
-                if let Err(err) =
-                    transfer(local_r, local_w, StreamingReader::new(transfer_rx), writer).await
+                if let Err(err) = transfer(
+                    local_r,
+                    TeeWriter::new(local_w, &dialer),
+                    dialer.read_buf_size(),
+                    StreamingReader::new(transfer_rx),
+                    writer,
+                )
+                .await
                 {
                     debug!("failed to forward traffic to local: {:?}", err);
                 }
             }
             Err(err) => {
                 error!(
-                    local_endpoint = ?dialer.addr(),
+                    local_endpoint = ?dialer.upstream(&host),
                     ?err,
                     "failed to connect to local endpoint, so let's notify the server to close the user connection",
                 );
@@ -384,13 +928,15 @@ async fn handle_work_traffic(
 async fn transfer(
     local_r: impl AsyncRead + Unpin,
     mut local_w: impl AsyncWrite + Unpin,
+    local_buf_size: usize,
     remote_r: impl AsyncRead + Unpin,
     mut remote_w: impl AsyncWrite + Unpin,
 ) -> Result<()> {
     let remote_to_me_to_local = async {
-        // read from remote, write to local
+        // read from remote, write to local,
+        // a message is read at once if it fits, so a datagram is written as a whole.
         match io::copy_buf(
-            &mut BufReader::with_capacity(constant::DEFAULT_BUF_SIZE, remote_r),
+            &mut BufReader::with_capacity(local_buf_size.max(constant::DEFAULT_BUF_SIZE), remote_r),
             &mut local_w,
         )
         .await
@@ -408,7 +954,7 @@ async fn transfer(
     let local_to_me_to_remote = async {
         // read from local, write to remote
         match io::copy_buf(
-            &mut BufReader::with_capacity(constant::DEFAULT_BUF_SIZE, local_r),
+            &mut BufReader::with_capacity(local_buf_size, local_r),
             &mut remote_w,
         )
         .await
@@ -418,7 +964,10 @@ async fn transfer(
                 let _ = remote_w.shutdown().await;
             }
             Err(err) => {
-                error!(err = ?err, "failed to copy from local to remote")
+                error!(err = ?err, "failed to copy from local to remote");
+                // the remote doesn't wait for the rest, e.g. the local tls rejects the handshake
+                // only after it's done on this side
+                let _ = remote_w.shutdown().await;
             }
         }
     };
@@ -427,3 +976,24 @@ async fn transfer(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_retry_backoff() {
+        assert_eq!(retry_backoff(0, None), constant::CLIENT_RETRY_BACKOFF);
+        assert_eq!(retry_backoff(2, None), constant::CLIENT_RETRY_BACKOFF * 4);
+        let max = Duration::from_secs(5);
+        for retries in [0, 3, 10, 100] {
+            let backoff = retry_backoff(retries, Some(max));
+            let expected = (constant::CLIENT_RETRY_BACKOFF * 2u32.saturating_pow(retries)).min(max);
+            assert!(
+                backoff <= expected && backoff >= expected / 2,
+                "{:?}",
+                backoff
+            );
+        }
+    }
+}
@@ -0,0 +1,592 @@
+//! The tunnels file, it declares the tunnels of a client, e.g.
+//!
+//! ```toml
+//! [[tunnel]]
+//! name = "db"
+//! type = "tcp"
+//! local_addr = "127.0.0.1:5432"
+//! remote_port = 40001
+//!
+//! [[tunnel]]
+//! name = "web"
+//! type = "http"
+//! local_addr = "127.0.0.1:3000"
+//! subdomain = "k3x9a2bd"
+//! labels = { team = "infra" }
+//! ```
+//!
+//! `castle --export <path>` writes the running tunnels to it, with the ports
+//! and subdomains assigned by the server, so the same tunnels can be registered again,
+//! `castle --config <path>` registers all the tunnels of it.
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+
+use super::tunnel::{HeaderRules, HttpRemoteConfig, RemoteConfig, Tunnel};
+use super::LocalTls;
+use crate::{socket::MAX_DATAGRAM_SIZE, TcpKeepalive};
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TunnelsFile {
+    #[serde(rename = "tunnel", default)]
+    pub tunnels: Vec<TunnelConfig>,
+}
+
+impl TunnelsFile {
+    pub fn parse(content: &str) -> anyhow::Result<Self> {
+        Ok(toml::from_str(content)?)
+    }
+
+    pub fn to_toml(&self) -> anyhow::Result<String> {
+        Ok(toml::to_string(self)?)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TunnelKind {
+    Tcp,
+    Udp,
+    Http,
+}
+
+/// The definition of a tunnel, the fields mirror the flags of `castle`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TunnelConfig {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub kind: TunnelKind,
+    pub local_addr: SocketAddr,
+    /// the named pipe the traffic is forwarded to instead of `local_addr`, windows only.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub local_pipe: Option<String>,
+    /// the local endpoint is dialed with the tls trusting the pem certificates, tcp and http only.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub local_tls_ca: Option<PathBuf>,
+    /// the pem certificate chain presented to the local endpoint, requires `local_tls_key`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub local_tls_cert: Option<PathBuf>,
+    /// the pem private key of `local_tls_cert`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub local_tls_key: Option<PathBuf>,
+    /// 0 lets the server assign a random port.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub remote_port: u16,
+    /// http only.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub domain: Option<String>,
+    /// http only.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subdomain: Option<String>,
+    /// http only.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub random_subdomain: bool,
+    /// http only, the requests under the path of the domain or subdomain are routed to the tunnel.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path_prefix: Option<String>,
+    /// http only, in seconds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub http_timeout: Option<u64>,
+    /// http only, empty allows all the methods.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allowed_methods: Vec<String>,
+    /// http only.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub interstitial: bool,
+    /// http only.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub coalesce: bool,
+    /// http only.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub rewrite_local_origin: bool,
+    /// http only, in bytes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_request_body: Option<u64>,
+    /// http only, in bytes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_response_body: Option<u64>,
+    /// http only, e.g. `request_headers = { set = { Host = "localhost:3000" } }`.
+    #[serde(default, skip_serializing_if = "HeaderRules::is_empty")]
+    pub request_headers: HeaderRules,
+    /// http only.
+    #[serde(default, skip_serializing_if = "HeaderRules::is_empty")]
+    pub response_headers: HeaderRules,
+    /// http only, the local endpoints of the hosts of the requests instead of `local_addr`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub upstreams: BTreeMap<String, SocketAddr>,
+    /// tcp only, in seconds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub first_byte_timeout: Option<u64>,
+    /// tcp only, only the bytes of the user count against `first_byte_timeout`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub first_byte_from_user: bool,
+    /// tcp only, in bytes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_chunk_size: Option<usize>,
+    /// udp only.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_datagram_size: Option<usize>,
+    /// udp only, the local address the sockets dialing `local_addr` are bound to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bind_addr: Option<IpAddr>,
+    /// udp only, the datagrams are compressed.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub compress: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tcp_keepalive_idle: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tcp_keepalive_interval: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tcp_keepalive_retries: Option<u32>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+    /// the targets allowed to probe besides the local endpoint.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub probe_targets: Vec<SocketAddr>,
+    /// the endpoint receiving a copy of the traffic, tcp and http only.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shadow: Option<SocketAddr>,
+    /// the bytes are echoed back instead of forwarded to `local_addr`, tcp only.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub echo: bool,
+    /// the seconds to wait for the local endpoint before registering the tunnel.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wait_for_local: Option<u64>,
+    /// the listener is bound to the loopback of the server.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub loopback: bool,
+    /// the bytes per second of each direction.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rate_limit: Option<u64>,
+}
+
+fn is_zero(port: &u16) -> bool {
+    *port == 0
+}
+
+impl TunnelConfig {
+    /// Describe the tunnel, it's used before the tunnel is started,
+    /// see [`TunnelConfig::with_assigned`] to pin what the server assigns.
+    pub fn from_tunnel(tunnel: &Tunnel<'_>) -> Self {
+        let local_addr = tunnel.dialer.addr();
+        let options = tunnel.dialer.options();
+        let mut config = Self {
+            name: tunnel.name.to_string(),
+            kind: TunnelKind::Tcp,
+            local_addr,
+            local_pipe: options.local_pipe.clone(),
+            local_tls_ca: tunnel.local_tls.as_ref().map(|tls| tls.ca.clone()),
+            local_tls_cert: tunnel
+                .local_tls
+                .as_ref()
+                .and_then(|tls| tls.identity.as_ref())
+                .map(|(cert, _)| cert.clone()),
+            local_tls_key: tunnel
+                .local_tls
+                .as_ref()
+                .and_then(|tls| tls.identity.as_ref())
+                .map(|(_, key)| key.clone()),
+            remote_port: 0,
+            domain: None,
+            subdomain: None,
+            random_subdomain: false,
+            path_prefix: tunnel.path_prefix.clone(),
+            http_timeout: tunnel.http_timeout.map(|timeout| timeout.as_secs().max(1)),
+            allowed_methods: tunnel.allowed_methods.clone(),
+            interstitial: tunnel.interstitial,
+            coalesce: tunnel.coalesce,
+            rewrite_local_origin: tunnel.rewrite_local_origin,
+            max_request_body: tunnel.max_request_body,
+            max_response_body: tunnel.max_response_body,
+            request_headers: tunnel.request_headers.clone(),
+            response_headers: tunnel.response_headers.clone(),
+            upstreams: tunnel
+                .dialer
+                .upstreams()
+                .iter()
+                .map(|(host, upstream)| (host.clone(), *upstream))
+                .collect(),
+            first_byte_timeout: tunnel
+                .first_byte_timeout
+                .map(|timeout| timeout.as_secs().max(1)),
+            first_byte_from_user: tunnel.first_byte_from_user,
+            max_chunk_size: tunnel.max_chunk_size,
+            max_datagram_size: None,
+            bind_addr: options.bind_addr,
+            compress: tunnel.compressed,
+            tcp_keepalive_idle: options
+                .tcp_keepalive
+                .map(|keepalive| keepalive.idle.as_secs()),
+            tcp_keepalive_interval: options
+                .tcp_keepalive
+                .and_then(|keepalive| keepalive.interval)
+                .map(|interval| interval.as_secs()),
+            tcp_keepalive_retries: options
+                .tcp_keepalive
+                .and_then(|keepalive| keepalive.retries),
+            labels: tunnel.labels.clone().into_iter().collect(),
+            probe_targets: tunnel
+                .probe_targets
+                .iter()
+                // the local endpoint and the upstreams are always allowed
+                .filter(|target| {
+                    **target != local_addr
+                        && !tunnel.dialer.upstreams().values().any(|u| u == *target)
+                })
+                .copied()
+                .collect(),
+            shadow: tunnel.dialer.shadow(),
+            echo: tunnel.echo,
+            wait_for_local: tunnel
+                .wait_for_local
+                .map(|timeout| timeout.as_secs().max(1)),
+            loopback: tunnel.loopback,
+            rate_limit: tunnel.rate_limit,
+        };
+        match &tunnel.config {
+            RemoteConfig::Tcp(port) => config.remote_port = *port,
+            RemoteConfig::Udp(port) => {
+                config.kind = TunnelKind::Udp;
+                config.remote_port = *port;
+                config.max_datagram_size = options
+                    .max_datagram_size
+                    .filter(|size| *size != MAX_DATAGRAM_SIZE);
+            }
+            RemoteConfig::Http(http) => {
+                config.kind = TunnelKind::Http;
+                match http {
+                    HttpRemoteConfig::Domain(domain) => config.domain = Some(domain.to_string()),
+                    HttpRemoteConfig::Subdomain(subdomain) => {
+                        config.subdomain = Some(subdomain.to_string())
+                    }
+                    HttpRemoteConfig::RandomSubdomain => config.random_subdomain = true,
+                    HttpRemoteConfig::Port(port) => config.remote_port = *port,
+                    HttpRemoteConfig::RandomPort => {}
+                }
+            }
+        }
+        config
+    }
+
+    /// Build the tunnel of the definition, the reverse of [`TunnelConfig::from_tunnel`].
+    ///
+    /// It fails if the fields contradict each other, e.g. an http tunnel of both
+    /// a domain and a subdomain, the fields of the other types of tunnels are ignored.
+    pub fn to_tunnel(&self) -> anyhow::Result<Tunnel<'_>> {
+        let config = match self.kind {
+            TunnelKind::Tcp => RemoteConfig::Tcp(self.remote_port),
+            TunnelKind::Udp => RemoteConfig::Udp(self.remote_port),
+            TunnelKind::Http => RemoteConfig::Http(
+                match (&self.domain, &self.subdomain, self.random_subdomain) {
+                    (Some(domain), None, false) => HttpRemoteConfig::Domain(domain),
+                    (None, Some(subdomain), false) => HttpRemoteConfig::Subdomain(subdomain),
+                    (None, None, true) => HttpRemoteConfig::RandomSubdomain,
+                    (None, None, false) if self.remote_port > 0 => {
+                        HttpRemoteConfig::Port(self.remote_port)
+                    }
+                    (None, None, false) => HttpRemoteConfig::RandomPort,
+                    _ => {
+                        return Err(anyhow!(
+                        "tunnel {:?}: domain, subdomain and random_subdomain exclude each other",
+                        self.name
+                    ))
+                    }
+                },
+            ),
+        };
+        let mut tunnel = Tunnel::new(&self.name, self.local_addr, config);
+        if let Some(name) = &self.local_pipe {
+            #[cfg(windows)]
+            {
+                tunnel = tunnel.with_local_pipe(name);
+            }
+            #[cfg(not(windows))]
+            return Err(anyhow!(
+                "tunnel {:?}: local_pipe {:?} requires windows",
+                self.name,
+                name
+            ));
+        }
+        let identity = match (&self.local_tls_cert, &self.local_tls_key) {
+            (Some(cert), Some(key)) => Some((cert.clone(), key.clone())),
+            (None, None) => None,
+            _ => {
+                return Err(anyhow!(
+                    "tunnel {:?}: local_tls_cert and local_tls_key require each other",
+                    self.name
+                ))
+            }
+        };
+        match &self.local_tls_ca {
+            Some(ca) => {
+                tunnel = tunnel
+                    .with_local_tls(LocalTls {
+                        ca: ca.clone(),
+                        identity,
+                    })
+                    .map_err(|err| anyhow!("tunnel {:?}: {:#}", self.name, err))?;
+            }
+            None if identity.is_some() => {
+                return Err(anyhow!(
+                    "tunnel {:?}: local_tls_cert and local_tls_key require local_tls_ca",
+                    self.name
+                ));
+            }
+            None => {}
+        }
+        if let Some(path_prefix) = &self.path_prefix {
+            tunnel = tunnel.with_path_prefix(path_prefix);
+        }
+        if let Some(timeout) = self.http_timeout {
+            tunnel = tunnel.with_http_timeout(Duration::from_secs(timeout));
+        }
+        if !self.allowed_methods.is_empty() {
+            tunnel = tunnel.with_allowed_methods(
+                self.allowed_methods
+                    .iter()
+                    .map(|method| method.trim().to_ascii_uppercase()),
+            );
+        }
+        if self.interstitial {
+            tunnel = tunnel.with_interstitial();
+        }
+        if self.coalesce {
+            tunnel = tunnel.with_coalesce();
+        }
+        if self.rewrite_local_origin {
+            tunnel = tunnel.with_rewrite_local_origin();
+        }
+        if let Some(max) = self.max_request_body {
+            tunnel = tunnel.with_max_request_body(max);
+        }
+        if let Some(max) = self.max_response_body {
+            tunnel = tunnel.with_max_response_body(max);
+        }
+        if !self.request_headers.is_empty() {
+            tunnel = tunnel.with_request_headers(self.request_headers.clone());
+        }
+        if !self.response_headers.is_empty() {
+            tunnel = tunnel.with_response_headers(self.response_headers.clone());
+        }
+        for (host, upstream) in &self.upstreams {
+            tunnel = tunnel.with_upstream(host, *upstream);
+        }
+        if let Some(timeout) = self.first_byte_timeout {
+            tunnel = tunnel.with_first_byte_timeout(Duration::from_secs(timeout));
+        }
+        if self.first_byte_from_user {
+            tunnel = tunnel.with_first_byte_from_user();
+        }
+        if let Some(size) = self.max_chunk_size {
+            tunnel = tunnel.with_max_chunk_size(size);
+        }
+        if let Some(size) = self.max_datagram_size {
+            tunnel = tunnel.with_max_datagram_size(size);
+        }
+        if let Some(addr) = self.bind_addr {
+            tunnel = tunnel.with_bind_addr(addr);
+        }
+        if self.compress {
+            tunnel = tunnel.with_compression();
+        }
+        match self.tcp_keepalive_idle {
+            Some(idle) => {
+                tunnel = tunnel.with_tcp_keepalive(TcpKeepalive {
+                    idle: Duration::from_secs(idle),
+                    interval: self.tcp_keepalive_interval.map(Duration::from_secs),
+                    retries: self.tcp_keepalive_retries,
+                });
+            }
+            None if self.tcp_keepalive_interval.is_some()
+                || self.tcp_keepalive_retries.is_some() =>
+            {
+                return Err(anyhow!(
+                    "tunnel {:?}: tcp_keepalive_interval and tcp_keepalive_retries require tcp_keepalive_idle",
+                    self.name
+                ));
+            }
+            None => {}
+        }
+        for (key, value) in &self.labels {
+            tunnel = tunnel.with_label(key, value);
+        }
+        for target in &self.probe_targets {
+            tunnel = tunnel.with_probe_target(*target);
+        }
+        if let Some(shadow) = self.shadow {
+            tunnel = tunnel.with_shadow(shadow);
+        }
+        if self.echo {
+            tunnel = tunnel.with_echo();
+        }
+        if let Some(timeout) = self.wait_for_local {
+            tunnel = tunnel.with_wait_for_local(Duration::from_secs(timeout));
+        }
+        if self.loopback {
+            tunnel = tunnel.with_loopback();
+        }
+        if let Some(rate_limit) = self.rate_limit {
+            tunnel = tunnel.with_rate_limit(rate_limit);
+        }
+        Ok(tunnel)
+    }
+
+    /// Pin the random port or subdomain to the one assigned by the server,
+    /// the entrypoint is returned by [`super::Client::start_tunnel`].
+    ///
+    /// It's left as-is if the entrypoint doesn't tell, e.g. the server has neither ip nor domain.
+    pub fn with_assigned(mut self, entrypoint: &[String]) -> Self {
+        let Some(host) = entrypoint.first().map(|entrypoint| {
+            entrypoint
+                .split_once("://")
+                .map_or(&**entrypoint, |(_, host)| host)
+        }) else {
+            return self;
+        };
+        if self.random_subdomain {
+            if let Some((subdomain, _)) = host.split_once('.') {
+                self.subdomain = Some(subdomain.to_string());
+                self.random_subdomain = false;
+            }
+        } else if self.remote_port == 0 && self.domain.is_none() && self.subdomain.is_none() {
+            if let Some(port) = host
+                .rsplit_once(':')
+                .and_then(|(_, port)| port.parse().ok())
+            {
+                self.remote_port = port;
+            }
+        }
+        self
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_export() {
+        let local: SocketAddr = "127.0.0.1:3000".parse().unwrap();
+        let database: SocketAddr = "127.0.0.1:5432".parse().unwrap();
+        let http = Tunnel::new(
+            "web",
+            local,
+            RemoteConfig::Http(HttpRemoteConfig::RandomSubdomain),
+        )
+        .with_label("team", "infra")
+        .with_probe_target(database)
+        .with_upstream("Admin.example.com", "127.0.0.1:3001".parse().unwrap())
+        .with_path_prefix("/api")
+        .with_http_timeout(Duration::from_secs(30));
+        let tcp = Tunnel::new("db", database, RemoteConfig::Tcp(0))
+            .with_tcp_keepalive(TcpKeepalive::new(Duration::from_secs(60)))
+            .with_first_byte_timeout(Duration::from_secs(10))
+            .with_first_byte_from_user()
+            .with_max_chunk_size(1400)
+            .with_rate_limit(1024 * 1024);
+        let udp = Tunnel::new("dns", local, RemoteConfig::Udp(0))
+            .with_max_datagram_size(1024)
+            .with_bind_addr("127.0.0.2".parse().unwrap())
+            .with_compression();
+
+        let file = TunnelsFile {
+            tunnels: vec![
+                TunnelConfig::from_tunnel(&http)
+                    .with_assigned(&["http://k3x9a2bd.example.com/api".to_string()]),
+                TunnelConfig::from_tunnel(&tcp).with_assigned(&[
+                    "tcp://127.0.0.1:40001".to_string(),
+                    "tcp://example.com:40001".to_string(),
+                ]),
+                // the server has neither ip nor domain
+                TunnelConfig::from_tunnel(&udp).with_assigned(&[]),
+            ],
+        };
+        let content = file.to_toml().unwrap();
+        assert_eq!(
+            content,
+            r#"[[tunnel]]
+name = "web"
+type = "http"
+local_addr = "127.0.0.1:3000"
+subdomain = "k3x9a2bd"
+path_prefix = "/api"
+http_timeout = 30
+probe_targets = ["127.0.0.1:5432"]
+
+[tunnel.upstreams]
+"admin.example.com" = "127.0.0.1:3001"
+
+[tunnel.labels]
+team = "infra"
+
+[[tunnel]]
+name = "db"
+type = "tcp"
+local_addr = "127.0.0.1:5432"
+remote_port = 40001
+first_byte_timeout = 10
+first_byte_from_user = true
+max_chunk_size = 1400
+tcp_keepalive_idle = 60
+rate_limit = 1048576
+
+[[tunnel]]
+name = "dns"
+type = "udp"
+local_addr = "127.0.0.1:3000"
+max_datagram_size = 1024
+bind_addr = "127.0.0.2"
+compress = true
+"#
+        );
+        assert_eq!(TunnelsFile::parse(&content).unwrap(), file);
+    }
+
+    #[test]
+    fn test_to_tunnel() {
+        let file = TunnelsFile::parse(
+            r#"
+            [[tunnel]]
+            name = "db"
+            type = "tcp"
+            local_addr = "127.0.0.1:5432"
+            remote_port = 40001
+            tcp_keepalive_idle = 60
+            labels = { team = "infra" }
+
+            [[tunnel]]
+            name = "web"
+            type = "http"
+            local_addr = "127.0.0.1:3000"
+            random_subdomain = true
+            path_prefix = "/api"
+            allowed_methods = ["GET", "POST"]
+            request_headers = { set = { Host = "localhost:3000" }, remove = ["X-Debug"] }
+            "#,
+        )
+        .unwrap();
+        // the definitions survive the round trip
+        for config in &file.tunnels {
+            let tunnel = config.to_tunnel().unwrap();
+            assert_eq!(&TunnelConfig::from_tunnel(&tunnel), config);
+        }
+
+        let mut conflicting = file.tunnels[1].clone();
+        conflicting.subdomain = Some("web".to_string());
+        assert!(conflicting.to_tunnel().is_err());
+        let mut keepalive = file.tunnels[0].clone();
+        keepalive.tcp_keepalive_idle = None;
+        keepalive.tcp_keepalive_retries = Some(3);
+        assert!(keepalive.to_tunnel().is_err());
+        let mut identity = file.tunnels[1].clone();
+        identity.local_tls_cert = Some("cert.pem".into());
+        identity.local_tls_key = Some("key.pem".into());
+        assert!(identity.to_tunnel().is_err());
+    }
+}
@@ -0,0 +1,187 @@
+//! The handles of the tunnels started by [`super::Client::spawn_tunnel`].
+//!
+//! The handle is returned before the tunnel is registered, [`TunnelHandle::registered`]
+//! resolves once the server answers the first registration, by then the entrypoint is bound
+//! and the users reaching it are forwarded to the client.
+//! A tunnel registered again, e.g. after the server restarted, may be assigned
+//! another random port or subdomain, [`TunnelHandle::subscribe`] sees every registration.
+//! [`TunnelHandle::state`] tells whether the tunnel is connected or reconnecting meanwhile.
+//! The tunnel failed over to another server is registered again, [`Registration::server`]
+//! tells which one.
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Result;
+use tokio::sync::watch;
+
+use crate::pb::InitPayload;
+
+/// Registration is what the server assigned to a registered tunnel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Registration {
+    /// the control address of the server the tunnel is registered on, e.g. the backup server
+    /// after a failover.
+    pub server: SocketAddr,
+    pub tunnel_id: String,
+    /// the addresses the users reach the tunnel at, e.g. `tcp://1.2.3.4:6200`.
+    pub entrypoint: Vec<String>,
+    /// the port bound for the tunnel, e.g. the random one of `RemoteConfig::Tcp(0)`,
+    /// None for the http tunnels routed by the domain or subdomain, or if the server predates it.
+    pub remote_port: Option<u16>,
+    /// the subdomain of the http tunnel, e.g. the random one.
+    pub subdomain: Option<String>,
+    /// the custom domain of the http tunnel.
+    pub domain: Option<String>,
+}
+
+impl Registration {
+    pub(crate) fn new(server: SocketAddr, init: &InitPayload) -> Self {
+        Self {
+            server,
+            tunnel_id: init.tunnel_id.clone(),
+            entrypoint: init.assigned_entrypoint.clone(),
+            remote_port: u16::try_from(init.remote_port)
+                .ok()
+                .filter(|port| *port > 0),
+            subdomain: (!init.subdomain.is_empty()).then(|| init.subdomain.clone()),
+            domain: (!init.domain.is_empty()).then(|| init.domain.clone()),
+        }
+    }
+}
+
+/// TunnelState is the state of the control stream of a tunnel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TunnelState {
+    /// the tunnel is being registered the first time.
+    Connecting,
+    /// the tunnel is registered, the users are forwarded to the client.
+    Connected,
+    /// the control stream is lost, e.g. the server restarted,
+    /// the tunnel is registered again after the backoff.
+    Reconnecting { retries: u32, backoff: Duration },
+    /// the tunnel stopped, e.g. the shutdown is triggered or it failed.
+    Stopped,
+}
+
+/// TunnelHandle follows the registrations of a tunnel, dropping it doesn't stop the tunnel,
+/// the tunnel is stopped by its shutdown.
+pub struct TunnelHandle {
+    registration: watch::Receiver<Option<Registration>>,
+    state: watch::Receiver<TunnelState>,
+    /// the error the tunnel failed with.
+    failed: Arc<Mutex<Option<anyhow::Error>>>,
+}
+
+impl TunnelHandle {
+    pub(crate) fn new() -> (Self, Reporter) {
+        let (registration_tx, registration) = watch::channel(None);
+        let (state_tx, state) = watch::channel(TunnelState::Connecting);
+        let failed = Arc::new(Mutex::new(None));
+        let handle = Self {
+            registration,
+            state,
+            failed: Arc::clone(&failed),
+        };
+        let reporter = Reporter {
+            registration: registration_tx,
+            state: state_tx,
+            failed,
+        };
+        (handle, reporter)
+    }
+
+    /// Wait for the first registration of the tunnel, it fails if the tunnel fails before it,
+    /// e.g. the port is taken.
+    pub async fn registered(&mut self) -> Result<Registration> {
+        if let Ok(registration) = self.registration.wait_for(Option::is_some).await {
+            return Ok(registration.clone().unwrap());
+        }
+        Err(self
+            .failed
+            .lock()
+            .unwrap()
+            .take()
+            .unwrap_or_else(|| anyhow::anyhow!("the tunnel stopped before it's registered")))
+    }
+
+    /// Returns the latest registration of the tunnel, None if it isn't registered yet.
+    pub fn registration(&self) -> Option<Registration> {
+        self.registration.borrow().clone()
+    }
+
+    /// Returns a receiver changed on every registration of the tunnel.
+    pub fn subscribe(&self) -> watch::Receiver<Option<Registration>> {
+        self.registration.clone()
+    }
+
+    /// Returns a receiver of the state of the tunnel, e.g. to tell the users it's reconnecting.
+    pub fn state(&self) -> watch::Receiver<TunnelState> {
+        self.state.clone()
+    }
+}
+
+/// Reporter is held by the task of the tunnel to report to its handle.
+pub(crate) struct Reporter {
+    registration: watch::Sender<Option<Registration>>,
+    state: watch::Sender<TunnelState>,
+    failed: Arc<Mutex<Option<anyhow::Error>>>,
+}
+
+impl Reporter {
+    pub(crate) fn registered(&self, server: SocketAddr, init: &InitPayload) {
+        self.registration
+            .send_replace(Some(Registration::new(server, init)));
+        self.state.send_replace(TunnelState::Connected);
+    }
+
+    pub(crate) fn reconnecting(&self, retries: u32, backoff: Duration) {
+        self.state
+            .send_replace(TunnelState::Reconnecting { retries, backoff });
+    }
+
+    /// the error is reported before the registrations are closed,
+    /// so the handle waiting for them sees it.
+    pub(crate) fn stopped(self, err: Option<anyhow::Error>) {
+        if let Some(err) = err {
+            *self.failed.lock().unwrap() = Some(err);
+        }
+        self.state.send_replace(TunnelState::Stopped);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_tunnel_handle() {
+        let (mut handle, reporter) = TunnelHandle::new();
+        assert_eq!(handle.registration(), None);
+        assert_eq!(*handle.state().borrow(), TunnelState::Connecting);
+        let init = InitPayload {
+            tunnel_id: "id".to_string(),
+            assigned_entrypoint: vec!["tcp://127.0.0.1:6200".to_string()],
+            remote_port: 6200,
+            ..Default::default()
+        };
+        let server = SocketAddr::from(([127, 0, 0, 1], 6100));
+        reporter.registered(server, &init);
+        let registration = handle.registered().await.unwrap();
+        assert_eq!(registration.server, server);
+        assert_eq!(registration.remote_port, Some(6200));
+        assert_eq!(registration.subdomain, None);
+        assert_eq!(*handle.state().borrow(), TunnelState::Connected);
+        // the registration is kept after the tunnel stopped
+        reporter.stopped(None);
+        assert_eq!(handle.registered().await.unwrap(), registration);
+        assert_eq!(*handle.state().borrow(), TunnelState::Stopped);
+
+        let (mut handle, reporter) = TunnelHandle::new();
+        reporter.stopped(Some(anyhow::anyhow!("port taken")));
+        let err = handle.registered().await.unwrap_err();
+        assert_eq!(err.to_string(), "port taken");
+    }
+}
@@ -2,4 +2,11 @@
 #[allow(clippy::module_inception)]
 mod client;
 pub use client::*;
+pub mod config;
+mod handle;
+pub use handle::{Registration, TunnelHandle, TunnelState};
+mod probe;
+mod shadow;
+pub(crate) mod tls;
 pub mod tunnel;
+pub use tls::{ClientTls, LocalTls};
@@ -0,0 +1,119 @@
+//! Probes the local targets on behalf of the server.
+use std::{
+    io::ErrorKind,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    net::{TcpStream, UdpSocket},
+    time::timeout,
+};
+
+use crate::pb::{probe_payload::Protocol, ProbePayload, ProbeReport};
+
+/// used when the server doesn't give the timeout.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// the time to wait for the icmp port unreachable after sending the udp datagram.
+const UDP_REFUSED_WAIT: Duration = Duration::from_millis(200);
+
+/// probe checks whether the target is reachable, the target must be in the allowlist.
+pub(crate) async fn probe(allowlist: &[SocketAddr], payload: ProbePayload) -> ProbeReport {
+    let mut report = ProbeReport {
+        probe_id: payload.probe_id.clone(),
+        ..Default::default()
+    };
+    let target: SocketAddr = match payload.target.parse() {
+        Ok(target) => target,
+        Err(_) => {
+            report.error = format!("invalid target {:?}, expect ip:port", payload.target);
+            return report;
+        }
+    };
+    if !allowlist.contains(&target) {
+        report.error = format!("target {} is not allowed to probe", target);
+        return report;
+    }
+    let protocol = Protocol::try_from(payload.protocol).unwrap_or(Protocol::Tcp);
+    let max = match payload.timeout_ms {
+        0 => DEFAULT_TIMEOUT,
+        ms => Duration::from_millis(ms),
+    };
+
+    let start = Instant::now();
+    let result = match timeout(max, probe_target(protocol, target)).await {
+        Ok(result) => result,
+        Err(_) => Err(std::io::Error::new(ErrorKind::TimedOut, "probe timed out")),
+    };
+    report.latency_us = start.elapsed().as_micros() as u64;
+    match result {
+        Ok(()) => report.reachable = true,
+        Err(err) => report.error = err.to_string(),
+    }
+    report
+}
+
+async fn probe_target(protocol: Protocol, target: SocketAddr) -> std::io::Result<()> {
+    match protocol {
+        Protocol::Tcp => TcpStream::connect(target).await.map(|_| ()),
+        Protocol::Udp => {
+            let local_addr: SocketAddr = if target.is_ipv4() {
+                "0.0.0.0:0"
+            } else {
+                "[::]:0"
+            }
+            .parse()
+            .unwrap();
+            let socket = UdpSocket::bind(local_addr).await?;
+            socket.connect(target).await?;
+            socket.send(&[]).await?;
+            // the connected socket reports the icmp port unreachable as ConnectionRefused,
+            // no news is good news for udp.
+            let mut buf = [0; 1];
+            match timeout(UDP_REFUSED_WAIT, socket.recv(&mut buf)).await {
+                Ok(Err(err)) if err.kind() == ErrorKind::ConnectionRefused => Err(err),
+                _ => Ok(()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    fn payload(protocol: Protocol, target: SocketAddr) -> ProbePayload {
+        ProbePayload {
+            probe_id: "probe".to_string(),
+            protocol: protocol as i32,
+            target: target.to_string(),
+            timeout_ms: 1000,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_probe() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let open = listener.local_addr().unwrap();
+        let report = probe(&[open], payload(Protocol::Tcp, open)).await;
+        assert_eq!(report.probe_id, "probe");
+        assert!(report.reachable, "{}", report.error);
+
+        // not in the allowlist
+        let report = probe(&[], payload(Protocol::Tcp, open)).await;
+        assert!(!report.reachable);
+        assert!(report.error.contains("not allowed"));
+
+        drop(listener);
+        let report = probe(&[open], payload(Protocol::Tcp, open)).await;
+        assert!(!report.reachable);
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let open = socket.local_addr().unwrap();
+        let report = probe(&[open], payload(Protocol::Udp, open)).await;
+        assert!(report.reachable, "{}", report.error);
+    }
+}
@@ -0,0 +1,163 @@
+//! The shadow upstream of a tunnel, it receives a copy of the traffic sent to
+//! the local endpoint, e.g. a new version of the backend under test.
+//!
+//! The responses of the shadow are read and discarded. The shadow never slows down
+//! or breaks the local endpoint: when it falls behind, the copies are dropped instead
+//! of waiting for it, and the shadow connection is closed because it has missed bytes.
+use std::{
+    net::SocketAddr,
+    pin::Pin,
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use bytes::Bytes;
+use tokio::{
+    io::{self, AsyncWrite, AsyncWriteExt as _},
+    sync::mpsc::{self, error::TrySendError},
+};
+use tracing::{debug, warn};
+
+use crate::socket::{DialResult, Dialer};
+
+/// the chunks buffered for the shadow, e.g. while it's being dialed.
+const SHADOW_BUFFER: usize = 64;
+/// the time to drain the responses of the shadow after the traffic is done.
+const SHADOW_LINGER: Duration = Duration::from_secs(5);
+
+/// TeeWriter writes to the local endpoint, and mirrors the written bytes to the shadow.
+pub(crate) struct TeeWriter<W> {
+    inner: W,
+    shadow: Option<mpsc::Sender<Bytes>>,
+}
+
+impl<W> TeeWriter<W> {
+    /// the shadow of the dialer is dialed in the background, it's a plain writer without one.
+    pub(crate) fn new(inner: W, dialer: &Dialer) -> Self {
+        let shadow = dialer.dial_shadow().map(|dial| {
+            let (tx, rx) = mpsc::channel(SHADOW_BUFFER);
+            tokio::spawn(mirror(dial, dialer.shadow().unwrap(), rx));
+            tx
+        });
+        Self { inner, shadow }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for TeeWriter<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let n = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        if let (Some(shadow), true) = (&this.shadow, n > 0) {
+            if let Err(err) = shadow.try_send(Bytes::copy_from_slice(&buf[..n])) {
+                if let TrySendError::Full(_) = err {
+                    warn!("the shadow upstream falls behind, stop mirroring the connection");
+                }
+                this.shadow = None;
+            }
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        // the shadow is shut down once it has written the buffered chunks
+        this.shadow = None;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+/// mirror writes the chunks to the shadow and discards its responses.
+async fn mirror(
+    dial: Pin<Box<dyn std::future::Future<Output = DialResult> + Send>>,
+    shadow: SocketAddr,
+    mut chunks: mpsc::Receiver<Bytes>,
+) {
+    let (mut shadow_r, mut shadow_w) = match dial.await {
+        Ok(conn) => conn,
+        Err(err) => {
+            warn!(?shadow, ?err, "failed to connect to the shadow upstream");
+            return;
+        }
+    };
+
+    let mut sink = io::sink();
+    let discard = io::copy(&mut shadow_r, &mut sink);
+    tokio::pin!(discard);
+    let forward = async {
+        while let Some(chunk) = chunks.recv().await {
+            if let Err(err) = shadow_w.write_all(&chunk).await {
+                debug!(?shadow, ?err, "failed to write to the shadow upstream");
+                return;
+            }
+        }
+        let _ = shadow_w.shutdown().await;
+    };
+
+    tokio::select! {
+        // the shadow closed the connection, the rest chunks are dropped
+        _ = &mut discard => return,
+        _ = forward => {}
+    }
+    let _ = tokio::time::timeout(SHADOW_LINGER, discard).await;
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::{io::AsyncReadExt as _, net::TcpListener};
+
+    use super::*;
+    use crate::socket::dial_tcp;
+
+    fn tcp_dialer(addr: SocketAddr) -> Dialer {
+        Dialer::new(|addr, options| Box::pin(dial_tcp(addr, options)), addr)
+    }
+
+    #[tokio::test]
+    async fn test_tee() {
+        let shadow = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut dialer = tcp_dialer("127.0.0.1:1".parse().unwrap());
+        dialer.set_shadow(shadow.local_addr().unwrap());
+
+        let mut primary = Vec::new();
+        let mut tee = TeeWriter::new(&mut primary, &dialer);
+        tee.write_all(b"hello ").await.unwrap();
+        tee.write_all(b"shadow").await.unwrap();
+        tee.shutdown().await.unwrap();
+        drop(tee);
+        assert_eq!(primary, b"hello shadow");
+
+        let (mut conn, _) = shadow.accept().await.unwrap();
+        // the response of the shadow is discarded
+        conn.write_all(b"ignored").await.unwrap();
+        let mut mirrored = Vec::new();
+        conn.read_to_end(&mut mirrored).await.unwrap();
+        assert_eq!(mirrored, b"hello shadow");
+    }
+
+    #[tokio::test]
+    async fn test_tee_without_shadow_upstream() {
+        // nothing listens on the shadow, the writes go on
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut dialer = tcp_dialer("127.0.0.1:1".parse().unwrap());
+        dialer.set_shadow(closed.local_addr().unwrap());
+        drop(closed);
+
+        let mut primary = Vec::new();
+        let mut tee = TeeWriter::new(&mut primary, &dialer);
+        for _ in 0..SHADOW_BUFFER * 2 {
+            tee.write_all(b"data").await.unwrap();
+            tokio::task::yield_now().await;
+        }
+        tee.shutdown().await.unwrap();
+        drop(tee);
+        assert_eq!(primary.len(), SHADOW_BUFFER * 2 * 4);
+    }
+}
@@ -0,0 +1,145 @@
+use std::{
+    fs::File,
+    io::BufReader,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{Context as _, Result};
+use rustls::pki_types::ServerName;
+use tokio::net::TcpStream;
+use tokio_rustls::{client::TlsStream, TlsConnector};
+
+/// ClientTls connects to the control port of a server with the tls, see [`super::Client::with_tls`].
+#[derive(Clone)]
+pub struct ClientTls {
+    config: Arc<rustls::ClientConfig>,
+    /// verified against the certificate of the server, the ip of the server by default.
+    server_name: Option<ServerName<'static>>,
+}
+
+impl ClientTls {
+    /// Use the rustls config, the grpc requires the `h2` alpn, it's set if the config has none.
+    pub fn new(mut config: rustls::ClientConfig) -> Self {
+        if config.alpn_protocols.is_empty() {
+            config.alpn_protocols = vec![b"h2".to_vec()];
+        }
+        Self {
+            config: Arc::new(config),
+            server_name: None,
+        }
+    }
+
+    /// Trust only the certificates of the pem file, e.g. a self-signed certificate of the server.
+    ///
+    /// ```no_run
+    /// let tls = castled::client::ClientTls::from_ca_file("ca.pem").unwrap();
+    /// ```
+    pub fn from_ca_file(path: impl AsRef<Path>) -> Result<Self> {
+        let config = builder(path.as_ref())?.with_no_client_auth();
+        Ok(Self::new(config))
+    }
+
+    /// Verify the certificate of the server against the name instead of its ip,
+    /// e.g. the domain of the server.
+    pub fn with_server_name(mut self, name: &str) -> Result<Self> {
+        let name = ServerName::try_from(name.to_string())
+            .map_err(|_| anyhow::anyhow!("invalid server name: {}", name))?;
+        self.server_name = Some(name);
+        Ok(self)
+    }
+
+    pub(crate) async fn connect(&self, addr: SocketAddr) -> std::io::Result<TlsStream<TcpStream>> {
+        let stream = TcpStream::connect(addr).await?;
+        stream.set_nodelay(true)?;
+        let server_name = self
+            .server_name
+            .clone()
+            .unwrap_or_else(|| ServerName::IpAddress(addr.ip().into()));
+        TlsConnector::from(Arc::clone(&self.config))
+            .connect(server_name, stream)
+            .await
+    }
+}
+
+/// LocalTls dials the local endpoint with the tls, e.g. an https upstream of an http tunnel,
+/// see [`super::tunnel::Tunnel::with_local_tls`], the files are pem.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LocalTls {
+    /// the certificate of the local endpoint is verified against the certificates of the file,
+    /// it's verified against the ip of the endpoint.
+    pub ca: PathBuf,
+    /// the certificate chain and the private key presented to the local endpoint,
+    /// e.g. it requires the client certificates (mTLS).
+    pub identity: Option<(PathBuf, PathBuf)>,
+}
+
+impl LocalTls {
+    /// load the files, the errors tell which one is invalid.
+    pub(crate) fn connector(&self) -> Result<LocalConnector> {
+        let builder = builder(&self.ca)?;
+        let config = match &self.identity {
+            Some((cert, key)) => {
+                let certs = rustls_pemfile::certs(&mut open(cert)?)
+                    .collect::<Result<Vec<_>, _>>()
+                    .with_context(|| format!("invalid certificate {}", cert.display()))?;
+                anyhow::ensure!(!certs.is_empty(), "no certificate in {}", cert.display());
+                let key = rustls_pemfile::private_key(&mut open(key)?)
+                    .with_context(|| format!("invalid private key {}", key.display()))?
+                    .with_context(|| format!("no private key in {}", key.display()))?;
+                builder
+                    .with_client_auth_cert(certs, key)
+                    .context("the private key doesn't match the certificate")?
+            }
+            None => builder.with_no_client_auth(),
+        };
+        Ok(LocalConnector(TlsConnector::from(Arc::new(config))))
+    }
+}
+
+/// LocalConnector runs the handshakes of the connections dialed to the local endpoints.
+#[derive(Clone)]
+pub(crate) struct LocalConnector(TlsConnector);
+
+impl std::fmt::Debug for LocalConnector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("LocalConnector")
+    }
+}
+
+impl LocalConnector {
+    pub(crate) async fn connect(
+        &self,
+        addr: SocketAddr,
+        stream: TcpStream,
+    ) -> std::io::Result<TlsStream<TcpStream>> {
+        self.0
+            .connect(ServerName::IpAddress(addr.ip().into()), stream)
+            .await
+    }
+}
+
+fn open(path: &Path) -> Result<BufReader<File>> {
+    let file = File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    Ok(BufReader::new(file))
+}
+
+/// the config trusting only the certificates of the pem file.
+fn builder(
+    path: &Path,
+) -> Result<rustls::ConfigBuilder<rustls::ClientConfig, rustls::client::WantsClientCert>> {
+    let mut roots = rustls::RootCertStore::empty();
+    for cert in rustls_pemfile::certs(&mut open(path)?) {
+        let cert = cert.with_context(|| format!("invalid certificate {}", path.display()))?;
+        roots
+            .add(cert)
+            .with_context(|| format!("invalid certificate {}", path.display()))?;
+    }
+    anyhow::ensure!(!roots.is_empty(), "no certificate in {}", path.display());
+    Ok(rustls::ClientConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()?
+    .with_root_certificates(roots))
+}
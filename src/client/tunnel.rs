@@ -1,12 +1,20 @@
 //! Tunnel configuration for the client.
 //! Before starting a tunnel, you need to create a tunnel by this module.
-use std::net::SocketAddr;
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
 
 use bytes::Bytes;
+use serde::{Deserialize, Serialize};
 
+use super::LocalTls;
 use crate::{
+    compress::PAYLOAD_COMPRESSION,
+    crypto::PayloadKey,
     pb::{self, tunnel, HttpConfig, TcpConfig, UdpConfig},
-    socket::{dial_tcp, dial_udp, Dialer},
+    socket::{dial_echo, dial_tcp, dial_udp, Dialer, TcpKeepalive, MAX_DATAGRAM_SIZE},
 };
 
 /// Tunnel configuration for the client.
@@ -15,24 +23,488 @@ pub struct Tunnel<'a> {
     pub(crate) name: &'a str,
     pub(crate) dialer: Dialer,
     pub(crate) config: RemoteConfig<'a>,
+    pub(crate) labels: HashMap<String, String>,
+    pub(crate) http_timeout: Option<Duration>,
+    pub(crate) allowed_methods: Vec<String>,
+    pub(crate) interstitial: bool,
+    pub(crate) coalesce: bool,
+    pub(crate) rewrite_local_origin: bool,
+    pub(crate) max_request_body: Option<u64>,
+    pub(crate) max_response_body: Option<u64>,
+    pub(crate) request_headers: HeaderRules,
+    pub(crate) response_headers: HeaderRules,
+    /// the targets the server is allowed to probe, the local endpoint is always allowed.
+    pub(crate) probe_targets: Vec<SocketAddr>,
+    pub(crate) payload_key: Option<PayloadKey>,
+    pub(crate) echo: bool,
+    pub(crate) wait_for_local: Option<Duration>,
+    pub(crate) loopback: bool,
+    pub(crate) compressed: bool,
+    pub(crate) path_prefix: Option<String>,
+    pub(crate) first_byte_timeout: Option<Duration>,
+    pub(crate) first_byte_from_user: bool,
+    pub(crate) max_chunk_size: Option<usize>,
+    pub(crate) rate_limit: Option<u64>,
+    pub(crate) local_tls: Option<LocalTls>,
 }
 
 impl<'a> Tunnel<'a> {
     /// Create a new tunnel.
     pub fn new(name: &'a str, local_endpoint: SocketAddr, config: RemoteConfig<'a>) -> Self {
+        let mut dialer = Dialer::new(
+            match config {
+                RemoteConfig::Tcp(_) => |endpoint, options| Box::pin(dial_tcp(endpoint, options)),
+                RemoteConfig::Udp(_) => |endpoint, options| Box::pin(dial_udp(endpoint, options)),
+                RemoteConfig::Http(_) => |endpoint, options| Box::pin(dial_tcp(endpoint, options)),
+            },
+            local_endpoint,
+        );
+        if let RemoteConfig::Udp(_) = config {
+            dialer.options_mut().max_datagram_size = Some(MAX_DATAGRAM_SIZE);
+        }
+
         Self {
             name,
-            dialer: Dialer::new(
-                match config {
-                    RemoteConfig::Tcp(_) => |endpoint| Box::pin(dial_tcp(endpoint)),
-                    RemoteConfig::Udp(_) => |endpoint| Box::pin(dial_udp(endpoint)),
-                    RemoteConfig::Http(_) => |endpoint| Box::pin(dial_tcp(endpoint)),
-                },
-                local_endpoint,
-            ),
+            dialer,
             config,
+            labels: HashMap::new(),
+            http_timeout: None,
+            allowed_methods: Vec::new(),
+            interstitial: false,
+            coalesce: false,
+            rewrite_local_origin: false,
+            max_request_body: None,
+            max_response_body: None,
+            request_headers: HeaderRules::default(),
+            response_headers: HeaderRules::default(),
+            probe_targets: vec![local_endpoint],
+            payload_key: None,
+            echo: false,
+            wait_for_local: None,
+            loopback: false,
+            compressed: false,
+            path_prefix: None,
+            first_byte_timeout: None,
+            first_byte_from_user: false,
+            max_chunk_size: None,
+            rate_limit: None,
+            local_tls: None,
+        }
+    }
+
+    /// Attach a label to the tunnel, the labels show up in the admin api of the server
+    /// and the tunnels can be filtered by them.
+    ///
+    /// The key must be 1-63 characters of `[a-zA-Z0-9._/-]`, the value at most 255 characters.
+    pub fn with_label(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.labels.insert(key.into(), value.into());
+        self
+    }
+
+    /// Enable the OS-level keepalive on the connections dialed to the local endpoint,
+    /// it has no effect on udp tunnels.
+    pub fn with_tcp_keepalive(mut self, keepalive: TcpKeepalive) -> Self {
+        self.dialer.options_mut().tcp_keepalive = Some(keepalive);
+        self
+    }
+
+    /// Limit the time the server waits for the response of the local server,
+    /// the server returns `504 Gateway Timeout` if the headers aren't received after it,
+    /// or aborts the response if its body isn't finished, it only affects http tunnels.
+    pub fn with_http_timeout(mut self, timeout: Duration) -> Self {
+        self.http_timeout = Some(timeout);
+        self
+    }
+
+    /// Only forward the requests of the methods, e.g. `POST` of a webhook receiver,
+    /// the server returns `405 Method Not Allowed` for the others, it only affects http tunnels.
+    ///
+    /// All the methods are forwarded by default.
+    pub fn with_allowed_methods<M: Into<String>>(
+        mut self,
+        methods: impl IntoIterator<Item = M>,
+    ) -> Self {
+        self.allowed_methods = methods.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Show an interstitial page to the first-time visitors of the browsers,
+    /// they reach the local server after acknowledging it, e.g. to deter phishing.
+    /// The page is configured by the server, it only affects http tunnels.
+    pub fn with_interstitial(mut self) -> Self {
+        self.interstitial = true;
+        self
+    }
+
+    /// Forward only one of the concurrent identical GETs, e.g. a burst on a cold page,
+    /// the rest are served its response once it's cached, it only affects http tunnels.
+    ///
+    /// It takes effect only if the server caches the responses, the requests of the other
+    /// methods and the responses not cacheable are never shared.
+    pub fn with_coalesce(mut self) -> Self {
+        self.coalesce = true;
+        self
+    }
+
+    /// Rewrite the absolute urls of the local endpoint, e.g. `http://localhost:3000/login`,
+    /// to the public origin of the tunnel in the `Location` headers and the `text/html` bodies
+    /// of the responses, it only affects http tunnels.
+    ///
+    /// It mutates the content and costs a scan of the html bodies, the compressed ones
+    /// and the other content types are forwarded as they are.
+    pub fn with_rewrite_local_origin(mut self) -> Self {
+        self.rewrite_local_origin = true;
+        self
+    }
+
+    /// Limit the bytes of the request bodies, the server returns `413 Payload Too Large`
+    /// for the larger ones, it only affects http tunnels.
+    ///
+    /// The bodies are counted as they are streamed, the limit of the server still applies.
+    pub fn with_max_request_body(mut self, max: u64) -> Self {
+        self.max_request_body = Some(max);
+        self
+    }
+
+    /// Limit the bytes of the response bodies of the local server, the server returns
+    /// `502 Bad Gateway` or aborts the response once it's larger, it only affects http tunnels.
+    pub fn with_max_response_body(mut self, max: u64) -> Self {
+        self.max_response_body = Some(max);
+        self
+    }
+
+    /// Rewrite the headers of the requests before they are forwarded to the local server,
+    /// e.g. add `X-Forwarded-Host` or override the `Host` it expects,
+    /// it only affects http tunnels.
+    ///
+    /// The requests are routed by their own `Host` before the rewrite.
+    /// The registration fails for the invalid headers, the framing headers,
+    /// i.e. `Content-Length` and `Transfer-Encoding`, and the hop-by-hop ones.
+    pub fn with_request_headers(mut self, rules: HeaderRules) -> Self {
+        if let RemoteConfig::Http(_) = self.config {
+            self.request_headers = rules;
+        }
+        self
+    }
+
+    /// Rewrite the headers of the responses of the local server, e.g. strip a header
+    /// leaking its version, it only affects http tunnels.
+    ///
+    /// The same headers as [`Tunnel::with_request_headers`] can't be rewritten.
+    pub fn with_response_headers(mut self, rules: HeaderRules) -> Self {
+        if let RemoteConfig::Http(_) = self.config {
+            self.response_headers = rules;
+        }
+        self
+    }
+
+    /// Allow the server to probe the target through the tunnel,
+    /// e.g. the database the local endpoint depends on.
+    ///
+    /// Only the local endpoint can be probed by default, it's dialed by the tunnel anyway.
+    pub fn with_probe_target(mut self, target: SocketAddr) -> Self {
+        if !self.probe_targets.contains(&target) {
+            self.probe_targets.push(target);
+        }
+        self
+    }
+
+    /// Encrypt the payloads of the tunnel end-to-end with the key shared with the server,
+    /// e.g. when the control channel isn't protected by tls.
+    ///
+    /// The registration fails if the server has no key or doesn't support the encryption,
+    /// the connections fail if the server has another key.
+    pub fn with_payload_key(mut self, key: PayloadKey) -> Self {
+        self.payload_key = Some(key);
+        self
+    }
+
+    /// Mirror the traffic sent to the local endpoint to the shadow endpoint,
+    /// e.g. a new version of the local server, its responses are discarded.
+    ///
+    /// The local endpoint is unaffected, the shadow is skipped for the connection
+    /// once it's unreachable or falls behind. It has no effect on udp tunnels.
+    pub fn with_shadow(mut self, shadow: SocketAddr) -> Self {
+        if !matches!(self.config, RemoteConfig::Udp(_)) {
+            self.dialer.set_shadow(shadow);
+        }
+        self
+    }
+
+    /// Try the other addresses in order when the local endpoint doesn't connect
+    /// within 2 seconds or fails, e.g. a hostname resolved to several addresses,
+    /// the first one connected is taken for the connection.
+    ///
+    /// They may be probed as well. It has no effect on udp tunnels
+    /// since dialing a udp endpoint doesn't tell whether it's alive.
+    pub fn with_fallback_addrs(mut self, addrs: Vec<SocketAddr>) -> Self {
+        if !matches!(self.config, RemoteConfig::Udp(_)) {
+            for addr in &addrs {
+                if !self.probe_targets.contains(addr) {
+                    self.probe_targets.push(*addr);
+                }
+            }
+            self.dialer.set_fallbacks(addrs);
+        }
+        self
+    }
+
+    /// Dial the local endpoint with the tls, e.g. an https local server,
+    /// with the identity of the tls if the local server requires the client certificates.
+    ///
+    /// The files are loaded here, the invalid ones are an error.
+    /// It applies to the upstreams and the shadow too, it has no effect on udp tunnels.
+    pub fn with_local_tls(mut self, tls: LocalTls) -> anyhow::Result<Self> {
+        if !matches!(self.config, RemoteConfig::Udp(_)) {
+            self.dialer.options_mut().local_tls = Some(tls.connector()?);
+            self.local_tls = Some(tls);
+        }
+        Ok(self)
+    }
+
+    /// Forward the traffic to the named pipe instead of the local endpoint,
+    /// e.g. `\\.\pipe\name`, it has no effect on udp tunnels.
+    ///
+    /// The local endpoint is only used to describe the tunnel, e.g. in the logs.
+    #[cfg(windows)]
+    pub fn with_local_pipe(mut self, name: impl Into<String>) -> Self {
+        if matches!(self.config, RemoteConfig::Udp(_)) {
+            return self;
+        }
+        let mut dialer = Dialer::new(
+            |endpoint, options| Box::pin(crate::socket::dial_named_pipe(endpoint, options)),
+            self.dialer.addr(),
+        );
+        *dialer.options_mut() = self.dialer.options().clone();
+        dialer.options_mut().local_pipe = Some(name.into());
+        if let Some(shadow) = self.dialer.shadow() {
+            dialer.set_shadow(shadow);
+        }
+        self.dialer = dialer;
+        self
+    }
+
+    /// Register the tunnel only after the local endpoint accepts a connection,
+    /// e.g. when the local server is started together with the client.
+    ///
+    /// The endpoint is dialed repeatedly until it's ready, the tunnel fails to start
+    /// if it isn't ready within the timeout.
+    pub fn with_wait_for_local(mut self, timeout: Duration) -> Self {
+        self.wait_for_local = Some(timeout);
+        self
+    }
+
+    /// Bind the listener of the tunnel to the loopback of the server instead of all the interfaces,
+    /// so only the processes on the server can reach it, e.g. a reverse proxy in front of it.
+    ///
+    /// The http tunnels must be served by their own ports, see [`HttpRemoteConfig::Port`],
+    /// the registration fails for the domains and the subdomains.
+    pub fn with_loopback(mut self) -> Self {
+        self.loopback = true;
+        self
+    }
+
+    /// Close the connections nobody speaks on within the timeout after they are set up,
+    /// e.g. the probes of the scanners holding the connections, it only affects tcp tunnels.
+    ///
+    /// The bytes of both the user and the local endpoint count by default,
+    /// see [`Tunnel::with_first_byte_from_user`] for the server-speaks-first protocols.
+    pub fn with_first_byte_timeout(mut self, timeout: Duration) -> Self {
+        if let RemoteConfig::Tcp(_) = self.config {
+            self.first_byte_timeout = Some(timeout);
+        }
+        self
+    }
+
+    /// Only count the bytes of the user against [`Tunnel::with_first_byte_timeout`],
+    /// so the banner of a server-speaks-first protocol, e.g. ssh or smtp,
+    /// doesn't keep the silent connections open, it only affects tcp tunnels.
+    pub fn with_first_byte_from_user(mut self) -> Self {
+        if let RemoteConfig::Tcp(_) = self.config {
+            self.first_byte_from_user = true;
+        }
+        self
+    }
+
+    /// Cap the bytes of the data messages of the connections, e.g. a little under the path mtu,
+    /// the server negotiates the smaller one of it and its own, which caps the messages
+    /// of both sides, it's at least 512, it only affects tcp tunnels.
+    pub fn with_max_chunk_size(mut self, size: usize) -> Self {
+        if let RemoteConfig::Tcp(_) = self.config {
+            self.max_chunk_size = Some(size);
+        }
+        self
+    }
+
+    /// Cap the bytes per second of the tunnel in each direction, e.g. to leave the link
+    /// to the other traffic, the traffic over it waits instead of being dropped.
+    ///
+    /// The limit of the server still applies if it's smaller, it's at least 1.
+    pub fn with_rate_limit(mut self, bytes_per_second: u64) -> Self {
+        self.rate_limit = Some(bytes_per_second.max(1));
+        self
+    }
+
+    /// Echo the bytes back instead of forwarding them to the local endpoint,
+    /// e.g. to verify the tunnel works end-to-end without a local server, tcp tunnels only.
+    ///
+    /// The local endpoint is only used to describe the tunnel, e.g. in the logs.
+    pub fn with_echo(mut self) -> Self {
+        if !matches!(self.config, RemoteConfig::Tcp(_)) {
+            return self;
         }
+        let mut dialer = Dialer::new(
+            |endpoint, options| Box::pin(dial_echo(endpoint, options)),
+            self.dialer.addr(),
+        );
+        *dialer.options_mut() = self.dialer.options().clone();
+        if let Some(shadow) = self.dialer.shadow() {
+            dialer.set_shadow(shadow);
+        }
+        self.dialer = dialer;
+        self.echo = true;
+        self
     }
+
+    pub(crate) fn to_pb_tunnel(&self) -> pb::Tunnel {
+        let mut tunnel = self.config.to_pb_tunnel(self.name);
+        tunnel.labels = self.labels.clone();
+        tunnel.loopback = self.loopback;
+        tunnel.rate_limit = self.rate_limit.unwrap_or_default();
+        if let Some(tunnel::Config::Http(http)) = tunnel.config.as_mut() {
+            if let Some(timeout) = self.http_timeout {
+                // at least 1ms, 0 means no timeout.
+                http.request_timeout_ms = (timeout.as_millis() as u64).max(1);
+            }
+            http.allowed_methods = self.allowed_methods.clone();
+            http.interstitial = self.interstitial;
+            http.coalesce = self.coalesce;
+            // at least 1 byte, 0 means no limit.
+            http.max_request_body = self.max_request_body.map_or(0, |max| max.max(1));
+            http.max_response_body = self.max_response_body.map_or(0, |max| max.max(1));
+            if self.rewrite_local_origin {
+                http.rewrite_origins = local_origins(self.dialer.addr());
+            }
+            if let Some(path_prefix) = &self.path_prefix {
+                http.path_prefix = path_prefix.clone();
+            }
+            http.request_headers = self.request_headers.to_pb();
+            http.response_headers = self.response_headers.to_pb();
+        }
+        if let Some(tunnel::Config::Tcp(tcp)) = tunnel.config.as_mut() {
+            if let Some(timeout) = self.first_byte_timeout {
+                // at least 1ms, 0 keeps the silent connections open.
+                tcp.first_byte_timeout_ms = (timeout.as_millis() as u64).max(1);
+            }
+            tcp.first_byte_from_user = self.first_byte_from_user;
+            tcp.max_chunk_size = self
+                .max_chunk_size
+                .map_or(0, |size| size.clamp(1, u32::MAX as usize) as u32);
+        }
+        if let Some(tunnel::Config::Udp(udp)) = tunnel.config.as_mut() {
+            if self.compressed {
+                udp.compression = PAYLOAD_COMPRESSION.to_string();
+            }
+            udp.max_datagram_size = self
+                .dialer
+                .options()
+                .max_datagram_size
+                .map_or(0, |size| size as u32);
+        }
+        tunnel
+    }
+
+    /// Limit the size of the datagrams replied by the local endpoint,
+    /// the larger ones are dropped with a warning, it only affects udp tunnels.
+    ///
+    /// The size is capped at 65507, the max payload of a udp datagram over ipv4.
+    pub fn with_max_datagram_size(mut self, size: usize) -> Self {
+        if let RemoteConfig::Udp(_) = self.config {
+            self.dialer.options_mut().max_datagram_size = Some(size.min(MAX_DATAGRAM_SIZE));
+        }
+        self
+    }
+
+    /// Forward the http requests of the host to the upstream instead of the local endpoint,
+    /// e.g. `a.example.com` to a local vhost, it can be repeated for the hosts served by
+    /// a single tunnel, e.g. on its own port, it only affects http tunnels.
+    ///
+    /// The host is matched case-insensitively without the port, the requests of the other hosts
+    /// still go to the local endpoint, the upstream may be probed as well.
+    /// It has no effect with a local pipe.
+    pub fn with_upstream(mut self, host: &str, upstream: SocketAddr) -> Self {
+        if let RemoteConfig::Http(_) = self.config {
+            self.dialer.set_upstream(host, upstream);
+            if !self.probe_targets.contains(&upstream) {
+                self.probe_targets.push(upstream);
+            }
+        }
+        self
+    }
+
+    /// Only route the requests under the path prefix of the domain or subdomain to the tunnel,
+    /// e.g. `/api`, so the tunnels of the same host serve their own paths,
+    /// it only affects http tunnels.
+    ///
+    /// The longest prefix matching the request wins, a prefix matches by the whole segments,
+    /// e.g. `/api` matches `/api/users` but not `/apis`. The paths are forwarded as they are.
+    /// The registration fails for the tunnels served by their own ports.
+    pub fn with_path_prefix(mut self, path_prefix: impl Into<String>) -> Self {
+        if let RemoteConfig::Http(_) = self.config {
+            self.path_prefix = Some(path_prefix.into());
+        }
+        self
+    }
+
+    /// Compress the datagrams between the server and the client one by one, only the ones
+    /// getting smaller are sent compressed, it only affects udp tunnels.
+    ///
+    /// It's negotiated at the registration, the server predating it keeps them uncompressed.
+    pub fn with_compression(mut self) -> Self {
+        if let RemoteConfig::Udp(_) = self.config {
+            self.compressed = true;
+        }
+        self
+    }
+
+    /// Bind the sockets dialing the local endpoint to the local address, e.g. on a multi-homed
+    /// host, the replies of the local endpoint are received on the interface of the address,
+    /// it only affects udp tunnels.
+    ///
+    /// The address must be of the same family as the local endpoint.
+    pub fn with_bind_addr(mut self, addr: IpAddr) -> Self {
+        if let RemoteConfig::Udp(_) = self.config {
+            self.dialer.options_mut().bind_addr = Some(addr);
+        }
+        self
+    }
+}
+
+/// the origins the local server may call itself, a loopback server is called localhost
+/// as well, the port is omitted if it's the default one.
+fn local_origins(addr: SocketAddr) -> Vec<String> {
+    let hosts = if addr.ip().is_loopback() {
+        vec![
+            "localhost".to_string(),
+            "127.0.0.1".to_string(),
+            "[::1]".to_string(),
+        ]
+    } else {
+        match addr.ip() {
+            IpAddr::V4(ip) => vec![ip.to_string()],
+            IpAddr::V6(ip) => vec![format!("[{}]", ip)],
+        }
+    };
+    hosts
+        .into_iter()
+        .flat_map(|host| {
+            let mut origins = vec![format!("http://{}:{}", host, addr.port())];
+            if addr.port() == 80 {
+                origins.push(format!("http://{}", host));
+            }
+            origins
+        })
+        .collect()
 }
 
 /// configuration for the http tunnel.
@@ -61,9 +533,14 @@ impl<'a> RemoteConfig<'a> {
             config: Some(match self {
                 Self::Udp(port) => tunnel::Config::Udp(UdpConfig {
                     remote_port: *port as i32,
+                    compression: String::new(),
+                    max_datagram_size: 0,
                 }),
                 Self::Tcp(port) => tunnel::Config::Tcp(TcpConfig {
                     remote_port: *port as i32,
+                    first_byte_timeout_ms: 0,
+                    first_byte_from_user: false,
+                    max_chunk_size: 0,
                 }),
                 Self::Http(config) => tunnel::Config::Http(config.get_http_config()),
             }),
@@ -72,6 +549,38 @@ impl<'a> RemoteConfig<'a> {
     }
 }
 
+/// HeaderRules rewrite the headers of the http messages of a tunnel,
+/// see [`Tunnel::with_request_headers`].
+///
+/// The names are case-insensitive, the rules are applied in the order of remove, set and add.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HeaderRules {
+    /// appended to the headers, e.g. `X-Forwarded-Host`,
+    /// the `Host` replaces the one of the request instead of being duplicated.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub add: BTreeMap<String, String>,
+    /// all the values of the headers are dropped.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub remove: Vec<String>,
+    /// replace all the values of the headers, e.g. the `Host` expected by the local server.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub set: BTreeMap<String, String>,
+}
+
+impl HeaderRules {
+    pub fn is_empty(&self) -> bool {
+        self.add.is_empty() && self.remove.is_empty() && self.set.is_empty()
+    }
+
+    fn to_pb(&self) -> Option<pb::HeaderRules> {
+        (!self.is_empty()).then(|| pb::HeaderRules {
+            add: self.add.clone().into_iter().collect(),
+            remove: self.remove.clone(),
+            set: self.set.clone().into_iter().collect(),
+        })
+    }
+}
+
 impl HttpRemoteConfig<'_> {
     fn get_http_config(&self) -> HttpConfig {
         match self {
@@ -92,6 +601,16 @@ fn domain_config(domain: Bytes) -> HttpConfig {
         random_subdomain: false,
         remote_port: 0,
         subdomain: String::new(),
+        request_timeout_ms: 0,
+        allowed_methods: Vec::new(),
+        interstitial: false,
+        coalesce: false,
+        rewrite_origins: Vec::new(),
+        max_request_body: 0,
+        max_response_body: 0,
+        path_prefix: String::new(),
+        request_headers: None,
+        response_headers: None,
     }
 }
 
@@ -101,6 +620,16 @@ fn subdomain_config(subdomain: Bytes) -> HttpConfig {
         domain: String::new(),
         remote_port: 0,
         subdomain: String::from_utf8_lossy(subdomain.as_ref()).to_string(),
+        request_timeout_ms: 0,
+        allowed_methods: Vec::new(),
+        interstitial: false,
+        coalesce: false,
+        rewrite_origins: Vec::new(),
+        max_request_body: 0,
+        max_response_body: 0,
+        path_prefix: String::new(),
+        request_headers: None,
+        response_headers: None,
     }
 }
 
@@ -110,6 +639,16 @@ fn remote_port_config(remote_port: u16) -> HttpConfig {
         random_subdomain: false,
         domain: String::new(),
         subdomain: String::new(),
+        request_timeout_ms: 0,
+        allowed_methods: Vec::new(),
+        interstitial: false,
+        coalesce: false,
+        rewrite_origins: Vec::new(),
+        max_request_body: 0,
+        max_response_body: 0,
+        path_prefix: String::new(),
+        request_headers: None,
+        response_headers: None,
     }
 }
 
@@ -119,6 +658,16 @@ fn random_subdomain_config() -> HttpConfig {
         domain: String::new(),
         remote_port: 0,
         subdomain: String::new(),
+        request_timeout_ms: 0,
+        allowed_methods: Vec::new(),
+        interstitial: false,
+        coalesce: false,
+        rewrite_origins: Vec::new(),
+        max_request_body: 0,
+        max_response_body: 0,
+        path_prefix: String::new(),
+        request_headers: None,
+        response_headers: None,
     }
 }
 
@@ -129,5 +678,15 @@ fn random_remote_port_config() -> HttpConfig {
         remote_port: 0,
         subdomain: String::new(),
         random_subdomain: false,
+        request_timeout_ms: 0,
+        allowed_methods: Vec::new(),
+        interstitial: false,
+        coalesce: false,
+        rewrite_origins: Vec::new(),
+        max_request_body: 0,
+        max_response_body: 0,
+        path_prefix: String::new(),
+        request_headers: None,
+        response_headers: None,
     }
 }
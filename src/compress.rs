@@ -0,0 +1,101 @@
+//! The compression of the datagrams of the udp tunnels, for the links short of bandwidth.
+//!
+//! The udp tunnel asks for the [`PAYLOAD_COMPRESSION`] in [`crate::pb::UdpConfig`] and
+//! the server confirms it in [`crate::pb::InitPayload`], then each datagram is sent
+//! as a payload of its own, so the boundaries of the datagrams are kept.
+//!
+//! A payload starts with a flag byte, [`RAW`] or [`DEFLATE`], the datagram is deflated
+//! only if it gets smaller, the incompressible ones cost the flag byte.
+//! The payloads are compressed before they are sealed, see [`crate::crypto`].
+use std::io::{Read as _, Write as _};
+
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+use tonic::Status;
+
+use crate::socket::MAX_DATAGRAM_SIZE;
+
+/// the compression spoken by this build.
+pub(crate) const PAYLOAD_COMPRESSION: &str = "deflate";
+
+/// the datagram follows as it is.
+const RAW: u8 = 0;
+/// the deflated datagram follows.
+const DEFLATE: u8 = 1;
+
+/// compress the datagram into a payload.
+pub(crate) fn compress(datagram: &[u8]) -> Vec<u8> {
+    let mut encoder = DeflateEncoder::new(vec![DEFLATE], Compression::fast());
+    // writing to a vec never fails
+    let deflated = encoder
+        .write_all(datagram)
+        .and_then(|_| encoder.finish())
+        .expect("deflate into a vec");
+    if deflated.len() <= datagram.len() {
+        return deflated;
+    }
+    let mut payload = Vec::with_capacity(datagram.len() + 1);
+    payload.push(RAW);
+    payload.extend_from_slice(datagram);
+    payload
+}
+
+/// decompress the payload into the datagram, it fails on the malformed payloads
+/// and on the datagrams larger than a udp datagram, e.g. a deflate bomb.
+pub(crate) fn decompress(payload: &[u8]) -> Result<Vec<u8>, Status> {
+    match payload.split_first() {
+        Some((&RAW, datagram)) => Ok(datagram.to_vec()),
+        Some((&DEFLATE, deflated)) => {
+            let mut datagram = Vec::new();
+            DeflateDecoder::new(deflated)
+                .take(MAX_DATAGRAM_SIZE as u64 + 1)
+                .read_to_end(&mut datagram)
+                .map_err(|err| Status::data_loss(format!("invalid deflated datagram: {}", err)))?;
+            if datagram.len() > MAX_DATAGRAM_SIZE {
+                return Err(Status::data_loss("the deflated datagram is too large"));
+            }
+            Ok(datagram)
+        }
+        Some((flag, _)) => Err(Status::data_loss(format!(
+            "unknown compression flag {}",
+            flag
+        ))),
+        None => Err(Status::data_loss("empty compressed datagram")),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rand::RngCore as _;
+
+    use super::*;
+
+    #[test]
+    fn test_compress() {
+        let compressible = b"castle ".repeat(100);
+        let payload = compress(&compressible);
+        assert_eq!(payload[0], DEFLATE);
+        assert!(payload.len() < compressible.len());
+        assert_eq!(decompress(&payload).unwrap(), compressible);
+
+        let mut incompressible = vec![0; 512];
+        rand::thread_rng().fill_bytes(&mut incompressible);
+        let payload = compress(&incompressible);
+        assert_eq!(payload[0], RAW);
+        assert_eq!(payload.len(), incompressible.len() + 1);
+        assert_eq!(decompress(&payload).unwrap(), incompressible);
+
+        // an empty datagram is a datagram as well
+        assert_eq!(decompress(&compress(b"")).unwrap(), b"");
+    }
+
+    #[test]
+    fn test_decompress_invalid() {
+        assert!(decompress(b"").is_err());
+        assert!(decompress(&[2, 1, 2, 3]).is_err());
+        assert!(decompress(&[DEFLATE, 0xff, 0xff]).is_err());
+        // inflates beyond a udp datagram
+        let bomb = compress(&vec![0; MAX_DATAGRAM_SIZE + 1]);
+        assert_eq!(bomb[0], DEFLATE);
+        assert!(decompress(&bomb).is_err());
+    }
+}
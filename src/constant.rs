@@ -1,2 +1,53 @@
 // use 8K as the default buffer size when transferring io data.
 pub(crate) const DEFAULT_BUF_SIZE: usize = 8 * 1024;
+
+// the default max bytes sent by the client but not received by the data server
+// for each user connection, see `bridge::InFlight`.
+pub(crate) const DEFAULT_MAX_IN_FLIGHT_BYTES: usize = 1024 * 1024;
+
+// the default bytes of the buffer the udp tunnels read the datagrams into, see `udp::DatagramPool`.
+pub(crate) const DEFAULT_UDP_BUFFER_POOL: usize = 256 * 1024;
+
+// the max time given to the udp sessions to finish their exchanges
+// when the udp tunnel is closed or the server shuts down.
+pub(crate) const DEFAULT_UDP_DRAIN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(2);
+
+// the max time the server waits for the entrypoint of a closed tunnel to be released,
+// e.g. the listener is dropped and the port is back to the pool,
+// before telling the client or, if the client closed it, taking its registration again.
+// it's longer than the drain of the udp sessions.
+pub(crate) const ENTRYPOINT_RELEASE_TIMEOUT: std::time::Duration =
+    std::time::Duration::from_secs(3);
+
+// the max time the close hooks of the server may delay the teardown of a tunnel,
+// see `Server::on_tunnel_close`.
+pub(crate) const CLOSE_HOOKS_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+// the udp tunnel waits before reading again after a transient error of reading the datagrams,
+// e.g. the icmp port unreachable, the delay doubles after each error in a row up to the max.
+pub(crate) const UDP_RECV_ERROR_BACKOFF: std::time::Duration = std::time::Duration::from_millis(10);
+pub(crate) const UDP_RECV_ERROR_MAX_BACKOFF: std::time::Duration =
+    std::time::Duration::from_secs(1);
+
+// the accept loops wait before accepting again when the process runs out of the file descriptors,
+// the delay doubles after each error in a row up to the max, the warnings are logged once per interval.
+pub(crate) const ACCEPT_ERROR_BACKOFF: std::time::Duration = std::time::Duration::from_millis(10);
+pub(crate) const ACCEPT_ERROR_MAX_BACKOFF: std::time::Duration = std::time::Duration::from_secs(1);
+pub(crate) const ACCEPT_ERROR_WARN_INTERVAL: std::time::Duration =
+    std::time::Duration::from_secs(10);
+
+// the client re-registers the tunnel at most the times after the transient errors,
+// the backoff starts at the delay and doubles after each retry.
+pub(crate) const CLIENT_MAX_RETRIES: u32 = 3;
+pub(crate) const CLIENT_RETRY_BACKOFF: std::time::Duration = std::time::Duration::from_millis(500);
+
+// the interval of dialing the local endpoint when the client waits for it to be ready.
+pub(crate) const WAIT_FOR_LOCAL_INTERVAL: std::time::Duration =
+    std::time::Duration::from_millis(200);
+
+// the max time of dialing each address of a local endpoint resolved to several ones,
+// the next address is tried once it's exceeded.
+pub(crate) const DIAL_ATTEMPT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(2);
+
+// the version of this build, it's shown to the users and the peers.
+pub(crate) const VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -0,0 +1,170 @@
+//! The end-to-end encryption of the payloads of the data streams,
+//! it keeps the traffic confidential even if the control channel is plaintext.
+//!
+//! The client and the server share a 32-byte key out of band, the client asks for the
+//! [`PAYLOAD_CIPHER`] in [`crate::pb::RegisterReq`] and the server confirms it in
+//! [`crate::pb::InitPayload`], then the data of every `Sending` action and every
+//! [`crate::pb::TrafficToClient`] is sealed.
+//!
+//! Each data stream derives a key of each direction from the shared key and the connection id
+//! with HKDF-SHA256, so a nonce is the counter of the messages sent in the direction,
+//! it's never reused under a key. The reordered, replayed or tampered messages fail to open.
+use std::{fmt, str::FromStr};
+
+use ring::{aead, hkdf};
+use tonic::Status;
+
+/// the cipher spoken by this build.
+pub(crate) const PAYLOAD_CIPHER: &str = "chacha20-poly1305";
+
+const KEY_LEN: usize = 32;
+const HKDF_SALT: &[u8] = b"castle payload encryption v1";
+
+/// PayloadKey is the key shared by the client and the server,
+/// it's parsed from 64 hex characters, e.g. the output of `openssl rand -hex 32`.
+#[derive(Clone, PartialEq, Eq)]
+pub struct PayloadKey([u8; KEY_LEN]);
+
+impl PayloadKey {
+    /// Create the key from the raw bytes.
+    pub fn new(key: [u8; KEY_LEN]) -> Self {
+        Self(key)
+    }
+}
+
+impl FromStr for PayloadKey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.len() != KEY_LEN * 2 || !s.is_ascii() {
+            anyhow::bail!("the payload key must be {} hex characters", KEY_LEN * 2);
+        }
+        let mut key = [0; KEY_LEN];
+        for (i, byte) in key.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16)
+                .map_err(|_| anyhow::anyhow!("the payload key must be hex characters"))?;
+        }
+        Ok(Self(key))
+    }
+}
+
+impl fmt::Debug for PayloadKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // never log the key
+        f.write_str("PayloadKey(..)")
+    }
+}
+
+/// the direction of the messages of a data stream.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Direction {
+    ToClient,
+    ToServer,
+}
+
+/// PayloadCipher seals or opens the messages of a data stream in one direction.
+pub(crate) struct PayloadCipher {
+    key: aead::LessSafeKey,
+    /// the number of the messages sealed or opened.
+    counter: u64,
+}
+
+impl PayloadCipher {
+    pub(crate) fn new(key: &PayloadKey, connection_id: &str, direction: Direction) -> Self {
+        let direction: &[u8] = match direction {
+            Direction::ToClient => b"to client",
+            Direction::ToServer => b"to server",
+        };
+        let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, HKDF_SALT).extract(&key.0);
+        let info = [connection_id.as_bytes(), direction];
+        let okm = prk
+            .expand(&info, &aead::CHACHA20_POLY1305)
+            .expect("the key length is valid for hkdf");
+        Self {
+            key: aead::LessSafeKey::new(aead::UnboundKey::from(okm)),
+            counter: 0,
+        }
+    }
+
+    fn next_nonce(&mut self) -> aead::Nonce {
+        let mut nonce = [0; aead::NONCE_LEN];
+        nonce[aead::NONCE_LEN - 8..].copy_from_slice(&self.counter.to_be_bytes());
+        // a data stream never sends 2^64 messages
+        self.counter += 1;
+        aead::Nonce::assume_unique_for_key(nonce)
+    }
+
+    /// the data followed by the authentication tag.
+    pub(crate) fn seal(&mut self, mut data: Vec<u8>) -> Vec<u8> {
+        let nonce = self.next_nonce();
+        self.key
+            .seal_in_place_append_tag(nonce, aead::Aad::empty(), &mut data)
+            .expect("the data of a message is small enough to seal");
+        data
+    }
+
+    pub(crate) fn open(&mut self, mut data: Vec<u8>) -> Result<Vec<u8>, Status> {
+        let nonce = self.next_nonce();
+        let len = self
+            .key
+            .open_in_place(nonce, aead::Aad::empty(), &mut data)
+            .map_err(|_| {
+                Status::data_loss(
+                    "failed to decrypt the payload, the payload keys of the client and the server may differ",
+                )
+            })?
+            .len();
+        data.truncate(len);
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_payload_cipher() {
+        let key: PayloadKey = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f"
+            .parse()
+            .unwrap();
+        assert_eq!(format!("{:?}", key), "PayloadKey(..)");
+        assert!("0001".parse::<PayloadKey>().is_err());
+        assert!("zz".repeat(32).parse::<PayloadKey>().is_err());
+
+        let mut sealer = PayloadCipher::new(&key, "connection-1", Direction::ToServer);
+        let mut opener = PayloadCipher::new(&key, "connection-1", Direction::ToServer);
+        let first = sealer.seal(b"hello".to_vec());
+        assert_eq!(first.len(), 5 + aead::CHACHA20_POLY1305.tag_len());
+        assert_ne!(&first[..5], b"hello");
+        let second = sealer.seal(b"hello".to_vec());
+        assert_ne!(first, second);
+        assert_eq!(opener.open(first).unwrap(), b"hello");
+        assert_eq!(opener.open(second.clone()).unwrap(), b"hello");
+        // replayed
+        assert!(opener.open(second).is_err());
+
+        // the other direction, connection or key can't open it
+        let sealed = PayloadCipher::new(&key, "connection-1", Direction::ToServer).seal(vec![1]);
+        for mut opener in [
+            PayloadCipher::new(&key, "connection-1", Direction::ToClient),
+            PayloadCipher::new(&key, "connection-2", Direction::ToServer),
+            PayloadCipher::new(
+                &PayloadKey::new([7; KEY_LEN]),
+                "connection-1",
+                Direction::ToServer,
+            ),
+        ] {
+            assert!(opener.open(sealed.clone()).is_err());
+        }
+        // tampered
+        let mut sealed = sealed;
+        sealed[0] ^= 1;
+        let mut opener = PayloadCipher::new(&key, "connection-1", Direction::ToServer);
+        assert_eq!(
+            opener.open(sealed).unwrap_err().code(),
+            tonic::Code::DataLoss
+        );
+    }
+}
@@ -1,5 +1,36 @@
+use tracing::Level;
+use tracing_subscriber::{filter::LevelFilter, EnvFilter};
+
+/// Maps the `-q` / `-v` occurrences of the command line to a tracing level.
+///
+/// The level starts from `info`, each `-q` lowers it and each `-v` raises it:
+/// `-qq` error, `-q` warn, `-v` debug, `-vv` trace.
+///
+/// Returns None if neither flag is given, so the caller falls back to `RUST_LOG`.
+pub fn log_level(quiet: u8, verbose: u8) -> Option<Level> {
+    if quiet == 0 && verbose == 0 {
+        return None;
+    }
+
+    Some(match verbose as i16 - quiet as i16 {
+        i16::MIN..=-2 => Level::ERROR,
+        -1 => Level::WARN,
+        0 => Level::INFO,
+        1 => Level::DEBUG,
+        2.. => Level::TRACE,
+    })
+}
+
+/// the explicit level overrides `RUST_LOG`.
+fn env_filter(level: Option<Level>) -> EnvFilter {
+    match level {
+        Some(level) => EnvFilter::default().add_directive(LevelFilter::from_level(level).into()),
+        None => EnvFilter::from_default_env(),
+    }
+}
+
 #[cfg(feature = "debug")]
-pub fn setup_logging(default_console_port: u16) {
+pub fn setup_logging(default_console_port: u16, level: Option<Level>) {
     use std::net::Ipv4Addr;
     use tracing_subscriber::prelude::*;
 
@@ -11,13 +42,35 @@ pub fn setup_logging(default_console_port: u16) {
 
     // build a `Subscriber` by combining layers with a
     // `tracing_subscriber::Registry`:
+    //
+    // the level only filters the fmt layer, the console layer still
+    // receives all the task events it needs.
     tracing_subscriber::registry()
-        .with(tracing_subscriber::fmt::layer())
+        .with(tracing_subscriber::fmt::layer().with_filter(env_filter(level)))
         .with(console_layer)
         .init();
 }
 
 #[cfg(not(feature = "debug"))]
-pub fn setup_logging(_: u16) {
-    tracing_subscriber::fmt::init();
+pub fn setup_logging(_: u16, level: Option<Level>) {
+    tracing_subscriber::fmt()
+        .with_env_filter(env_filter(level))
+        .init();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_log_level() {
+        assert_eq!(log_level(0, 0), None);
+        assert_eq!(log_level(3, 0), Some(Level::ERROR));
+        assert_eq!(log_level(2, 0), Some(Level::ERROR));
+        assert_eq!(log_level(1, 0), Some(Level::WARN));
+        assert_eq!(log_level(1, 1), Some(Level::INFO));
+        assert_eq!(log_level(0, 1), Some(Level::DEBUG));
+        assert_eq!(log_level(0, 2), Some(Level::TRACE));
+        assert_eq!(log_level(0, 5), Some(Level::TRACE));
+    }
 }
@@ -1,21 +1,30 @@
-use std::vec;
+use std::{time::Duration, vec};
 
 use bytes::Bytes;
 use tokio::sync::{mpsc, oneshot};
 use tokio_util::sync::CancellationToken;
 use tonic::Status;
 
+use crate::server::HeaderRules;
+
 /// ClientEvent is used to communicate between the control server and data server.
 /// When the control server receives a client request, eventually it will
 /// send a ClientEvent to the data server if no error occurs.
 pub struct ClientEvent {
+    // the name of the tunnel, it's attached to the logs of the tunnel.
+    pub tunnel_name: String,
     // the payload of the event.
     pub payload: Payload,
+    // the listener of the tunnel is bound to the loopback of the server.
+    pub loopback: bool,
     // the data server will send back the status of the control server
     // after it handles this event.
     pub resp: oneshot::Sender<ClientEventResponse>,
     // when client exits, the control server will cancel the listener.
     pub close_listener: CancellationToken,
+    // held by the entrypoint until it's released after `close_listener` is cancelled,
+    // e.g. the listener is dropped and the port is back to the pool.
+    pub released: mpsc::Sender<()>,
     // data server sends events to this channel continuously.
     pub incoming_events: IncomingEventSender,
 }
@@ -28,34 +37,66 @@ pub enum ClientEventResponse {
         status: Option<Status>,
         // entrypoint is available if the registration is successful.
         entrypoint: Vec<String>,
+        /// the chunk size negotiated for the tcp tunnel, see [`crate::protocol::negotiate_chunk_size`].
+        max_chunk_size: Option<usize>,
+        /// the port bound for the tunnel, 0 if it's routed by the domain or subdomain.
+        remote_port: u16,
+        /// the subdomain of the http tunnel, e.g. the random one, empty otherwise.
+        subdomain: Bytes,
     },
 }
 
 impl ClientEventResponse {
-    pub fn registered(entrypoint: Vec<String>) -> Self {
+    pub fn registered(entrypoint: Vec<String>, remote_port: u16) -> Self {
         Self::Registered {
             status: None,
             entrypoint,
+            max_chunk_size: None,
+            remote_port,
+            subdomain: Bytes::new(),
         }
     }
 
+    pub fn with_max_chunk_size(mut self, chunk_size: Option<usize>) -> Self {
+        let Self::Registered { max_chunk_size, .. } = &mut self;
+        *max_chunk_size = chunk_size;
+        self
+    }
+
+    pub fn with_subdomain(mut self, assigned: Bytes) -> Self {
+        let Self::Registered { subdomain, .. } = &mut self;
+        *subdomain = assigned;
+        self
+    }
+
     pub fn registered_failed(status: Status) -> Self {
         Self::Registered {
             status: Some(status),
             entrypoint: vec![],
+            max_chunk_size: None,
+            remote_port: 0,
+            subdomain: Bytes::new(),
         }
     }
 }
 
 /// Payload is the data of the ClientEvent.
 #[derive(Debug, Clone)]
-#[allow(clippy::enum_variant_names)]
+#[allow(clippy::enum_variant_names, clippy::large_enum_variant)]
 pub enum Payload {
     RegisterTcp {
         port: u16,
+        /// None keeps the silent connections open.
+        first_byte_timeout: Option<FirstByteTimeout>,
+        /// the chunk size asked by the client, None means no preference.
+        max_chunk_size: Option<usize>,
     },
     RegisterUdp {
         port: u16,
+        /// the datagrams are compressed, see [`crate::compress`].
+        compressed: bool,
+        /// the largest datagram the client takes, None means no preference.
+        max_datagram_size: Option<usize>,
     },
     // RegisterHttp is used to notify the server to register a http tunnel.
     // must provide one of the following fields: port, subdomain, domain.
@@ -64,9 +105,48 @@ pub enum Payload {
         subdomain: Bytes,
         domain: Bytes,
         random_subdomain: bool,
+        /// the path prefix of the requests routed to the tunnel under the domain or subdomain,
+        /// empty matches all the paths.
+        path_prefix: String,
+        options: HttpOptions,
     },
 }
 
+/// FirstByteTimeout closes the tcp connections nobody speaks on within the timeout,
+/// e.g. the probes of the scanners holding the bridges of a tunnel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FirstByteTimeout {
+    pub timeout: Duration,
+    /// only the bytes of the user count, so the banner of a server-speaks-first protocol,
+    /// e.g. ssh, doesn't keep the silent connection open.
+    pub from_user: bool,
+}
+
+/// HttpOptions is how the server serves the requests of a http tunnel.
+#[derive(Debug, Clone, Default)]
+pub struct HttpOptions {
+    /// the max time to wait for the response headers, None means no timeout.
+    pub request_timeout: Option<Duration>,
+    /// the methods forwarded to the tunnel, empty allows all.
+    pub allowed_methods: Vec<http::Method>,
+    /// show the interstitial page to the first-time visitors.
+    pub interstitial: bool,
+    /// coalesce the concurrent identical GETs with the response cache.
+    pub coalesce: bool,
+    /// the origins of the local server rewritten to the public origin, empty disables the rewrite.
+    pub rewrite_origins: Vec<String>,
+    /// the public origin is https, i.e. the domains are served behind a tls proxy.
+    pub https: bool,
+    /// the max bytes of the request bodies, the larger ones get 413, None means no limit.
+    pub max_request_body: Option<u64>,
+    /// the max bytes of the response bodies, the larger ones are aborted, None means no limit.
+    pub max_response_body: Option<u64>,
+    /// the rewrite of the headers of the requests forwarded to the tunnel.
+    pub request_headers: HeaderRules,
+    /// the rewrite of the headers of the responses of the local server.
+    pub response_headers: HeaderRules,
+}
+
 /// IncomingEventSender is used to notify the server to add | remove to the connection list.
 pub type IncomingEventSender = mpsc::Sender<UserIncoming>;
 
@@ -0,0 +1,152 @@
+//! The credit based flow control of the data streams, since data stream version 2.
+//!
+//! Each direction of a data stream starts with [`crate::protocol::INITIAL_CREDITS`] credits,
+//! the sender spends a credit per message carrying data and waits when it's out of credits,
+//! the receiver grants the credits back once it has consumed the messages,
+//! so a slow receiver holds at most a window of messages instead of the whole buffers.
+//!
+//! The server grants the client by a [`crate::pb::TrafficToClient`] carrying only the credits,
+//! the client grants the server by the `Credit` action.
+//! The control messages(e.g. `Finished`) and the grants themselves don't spend credits.
+use std::{
+    future::poll_fn,
+    sync::Arc,
+    task::{ready, Context, Poll},
+};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio_util::sync::PollSemaphore;
+
+/// create the credits of a direction of a data stream,
+/// the [`SendCredits`] are spent by the sender, the [`CreditGranter`] is held by whom receives the grants.
+pub(crate) fn credits(initial: u32) -> (SendCredits, CreditGranter) {
+    let permits = Arc::new(Semaphore::new(initial as usize));
+    (
+        SendCredits {
+            permits: PollSemaphore::new(permits.clone()),
+            held: None,
+        },
+        CreditGranter { permits },
+    )
+}
+
+/// SendCredits are the messages the sender may still send.
+#[derive(Debug)]
+pub(crate) struct SendCredits {
+    permits: PollSemaphore,
+    /// the credit acquired but not spent yet, e.g. the channel of the messages is full.
+    held: Option<OwnedSemaphorePermit>,
+}
+
+impl SendCredits {
+    /// wait for a credit, false if the granter is gone, the stream won't grant anymore.
+    ///
+    /// The credit is held until [`SendCredits::spend`], so polling it again doesn't take another one.
+    pub(crate) fn poll_acquire(&mut self, cx: &mut Context<'_>) -> Poll<bool> {
+        if self.held.is_none() {
+            match ready!(self.permits.poll_acquire(cx)) {
+                Some(permit) => self.held = Some(permit),
+                None => return Poll::Ready(false),
+            }
+        }
+        Poll::Ready(true)
+    }
+
+    /// spend the held credit on the message just sent.
+    pub(crate) fn spend(&mut self) {
+        if let Some(permit) = self.held.take() {
+            permit.forget();
+        }
+    }
+
+    /// wait for a credit then spend it, false if the granter is gone.
+    pub(crate) async fn acquire(&mut self) -> bool {
+        let acquired = poll_fn(|cx| self.poll_acquire(cx)).await;
+        self.spend();
+        acquired
+    }
+}
+
+/// CreditGranter gives the credits granted by the receiver to the sender,
+/// the waiting sender gives up once it's dropped.
+#[derive(Debug)]
+pub(crate) struct CreditGranter {
+    permits: Arc<Semaphore>,
+}
+
+impl CreditGranter {
+    pub(crate) fn grant(&self, credits: u32) {
+        // a misbehaving peer can't overflow the semaphore
+        let room = Semaphore::MAX_PERMITS - self.permits.available_permits();
+        self.permits.add_permits((credits as usize).min(room));
+    }
+}
+
+impl Drop for CreditGranter {
+    fn drop(&mut self) {
+        self.permits.close();
+    }
+}
+
+/// Replenisher counts the messages consumed by the receiver,
+/// they are granted back by half of the window to save the grant messages.
+#[derive(Debug)]
+pub(crate) struct Replenisher {
+    batch: u32,
+    consumed: u32,
+}
+
+impl Replenisher {
+    pub(crate) fn new(window: u32) -> Self {
+        Self {
+            batch: (window / 2).max(1),
+            consumed: 0,
+        }
+    }
+
+    /// consume a message, Some(credits) to grant back to the sender.
+    pub(crate) fn consume(&mut self) -> Option<u32> {
+        self.consumed += 1;
+        if self.consumed < self.batch {
+            return None;
+        }
+        Some(std::mem::take(&mut self.consumed))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_credits() {
+        let (mut credits, granter) = self::credits(2);
+        assert!(credits.acquire().await);
+        assert!(credits.acquire().await);
+        // out of credits, the sender waits for the receiver
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), credits.acquire())
+                .await
+                .is_err()
+        );
+
+        granter.grant(1);
+        assert!(credits.acquire().await);
+        granter.grant(u32::MAX);
+        assert!(credits.acquire().await);
+
+        // the receiver is gone
+        drop(granter);
+        assert!(!credits.acquire().await);
+    }
+
+    #[test]
+    fn test_replenisher() {
+        let mut replenisher = Replenisher::new(4);
+        let grants: Vec<Option<u32>> = (0..5).map(|_| replenisher.consume()).collect();
+        assert_eq!(grants, [None, Some(2), None, Some(2), None]);
+        assert_eq!(Replenisher::new(1).consume(), Some(1));
+    }
+}
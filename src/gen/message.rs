@@ -4,7 +4,7 @@
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct ControlCommand {
-    #[prost(oneof="control_command::Payload", tags="1, 2")]
+    #[prost(oneof="control_command::Payload", tags="1, 2, 3, 4")]
     pub payload: ::core::option::Option<control_command::Payload>,
 }
 /// Nested message and enum types in `ControlCommand`.
@@ -16,6 +16,10 @@ pub mod control_command {
         Init(super::InitPayload),
         #[prost(message, tag="2")]
         Work(super::WorkPayload),
+        #[prost(message, tag="3")]
+        Probe(super::ProbePayload),
+        #[prost(message, tag="4")]
+        Close(super::ClosePayload),
     }
 }
 #[allow(clippy::derive_partial_eq_without_eq)]
@@ -25,6 +29,37 @@ pub struct InitPayload {
     pub tunnel_id: ::prost::alloc::string::String,
     #[prost(string, repeated, tag="2")]
     pub assigned_entrypoint: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+    /// data_stream_version is the data stream protocol version negotiated by the server,
+    /// the client must carry it in the `Start` action of every data stream.
+    #[prost(uint32, tag="3")]
+    pub data_stream_version: u32,
+    /// payload_cipher confirms the cipher asked by the client, empty means the payloads are plaintext.
+    #[prost(string, tag="4")]
+    pub payload_cipher: ::prost::alloc::string::String,
+    /// compression confirms the compression of the datagrams asked by the udp tunnel,
+    /// empty means they are uncompressed, e.g. the server doesn't speak it.
+    #[prost(string, tag="5")]
+    pub compression: ::prost::alloc::string::String,
+    /// max_chunk_size is the chunk size negotiated for the tcp tunnel, the client caps
+    /// its data messages at it, 0 means no limit is agreed, e.g. the server predates the negotiation.
+    #[prost(uint32, tag="6")]
+    pub max_chunk_size: u32,
+    /// server_version is the version of the server, e.g. 0.1.0, empty if the server predates it.
+    #[prost(string, tag="7")]
+    pub server_version: ::prost::alloc::string::String,
+    /// server_started_at is the unix time in seconds the server started at, 0 if it predates it.
+    #[prost(uint64, tag="8")]
+    pub server_started_at: u64,
+    /// remote_port is the port bound for the tunnel, e.g. the random one of `remote_port = 0`,
+    /// 0 for the http tunnels routed by the domain or subdomain, or if the server predates it.
+    #[prost(uint32, tag="9")]
+    pub remote_port: u32,
+    /// subdomain is the subdomain of the http tunnel, e.g. the random one, empty otherwise.
+    #[prost(string, tag="10")]
+    pub subdomain: ::prost::alloc::string::String,
+    /// domain is the custom domain of the http tunnel, empty otherwise.
+    #[prost(string, tag="11")]
+    pub domain: ::prost::alloc::string::String,
 }
 /// WorkPayload is sent when the server establishes a user connection.
 #[allow(clippy::derive_partial_eq_without_eq)]
@@ -33,6 +68,127 @@ pub struct WorkPayload {
     /// connection_id is the unique identifier of the connection which is assigned by the server.
     #[prost(string, tag="1")]
     pub connection_id: ::prost::alloc::string::String,
+    /// host is the host of the http request of the connection without the port, lowercase,
+    /// the client dials the upstream of the host if any, it's empty for the tcp and udp tunnels.
+    #[prost(string, tag="2")]
+    pub host: ::prost::alloc::string::String,
+}
+/// ProbePayload asks the client to check whether a local target is reachable,
+/// the client answers it with ReportProbe.
+/// the client refuses the targets which are not in the probe allowlist of the tunnel.
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ProbePayload {
+    /// probe_id is the unique identifier of the probe which is assigned by the server.
+    #[prost(string, tag="1")]
+    pub probe_id: ::prost::alloc::string::String,
+    #[prost(enumeration="probe_payload::Protocol", tag="2")]
+    pub protocol: i32,
+    /// target is the local address to probe, e.g. 127.0.0.1:5432.
+    #[prost(string, tag="3")]
+    pub target: ::prost::alloc::string::String,
+    /// timeout_ms is the max time of the probe, 0 means the default timeout of the client.
+    #[prost(uint64, tag="4")]
+    pub timeout_ms: u64,
+}
+/// Nested message and enum types in `ProbePayload`.
+pub mod probe_payload {
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+    #[repr(i32)]
+    pub enum Protocol {
+        /// connect to the target.
+        Tcp = 0,
+        /// send an empty datagram to the target.
+        Udp = 1,
+    }
+    impl Protocol {
+        /// String value of the enum field names used in the ProtoBuf definition.
+        ///
+        /// The values are not transformed in any way and thus are considered stable
+        /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+        pub fn as_str_name(&self) -> &'static str {
+            match self {
+                Protocol::Tcp => "Tcp",
+                Protocol::Udp => "Udp",
+            }
+        }
+        /// Creates an enum from field names used in the ProtoBuf definition.
+        pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+            match value {
+                "Tcp" => Some(Self::Tcp),
+                "Udp" => Some(Self::Udp),
+                _ => None,
+            }
+        }
+    }
+}
+/// ClosePayload is sent before the server closes the control stream of a tunnel,
+/// the client decides whether to register the tunnel again by the reason.
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ClosePayload {
+    #[prost(enumeration="close_payload::Reason", tag="1")]
+    pub reason: i32,
+    /// message is the detail for the humans, e.g. why the tunnel is evicted.
+    #[prost(string, tag="2")]
+    pub message: ::prost::alloc::string::String,
+}
+/// Nested message and enum types in `ClosePayload`.
+pub mod close_payload {
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+    #[repr(i32)]
+    pub enum Reason {
+        Unspecified = 0,
+        /// the server is shutting down, the tunnel can be registered again, e.g. on another server.
+        ServerShutdown = 1,
+        /// the tunnel is closed by the operator, e.g. through the admin api,
+        /// or replaced by a new registration of it from the same client.
+        Evicted = 2,
+        /// the client is no longer allowed to run the tunnel, it mustn't be registered again.
+        Revoked = 3,
+    }
+    impl Reason {
+        /// String value of the enum field names used in the ProtoBuf definition.
+        ///
+        /// The values are not transformed in any way and thus are considered stable
+        /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+        pub fn as_str_name(&self) -> &'static str {
+            match self {
+                Reason::Unspecified => "Unspecified",
+                Reason::ServerShutdown => "ServerShutdown",
+                Reason::Evicted => "Evicted",
+                Reason::Revoked => "Revoked",
+            }
+        }
+        /// Creates an enum from field names used in the ProtoBuf definition.
+        pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+            match value {
+                "Unspecified" => Some(Self::Unspecified),
+                "ServerShutdown" => Some(Self::ServerShutdown),
+                "Evicted" => Some(Self::Evicted),
+                "Revoked" => Some(Self::Revoked),
+                _ => None,
+            }
+        }
+    }
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ProbeReport {
+    #[prost(string, tag="1")]
+    pub probe_id: ::prost::alloc::string::String,
+    #[prost(bool, tag="2")]
+    pub reachable: bool,
+    /// latency_us is the time spent on the tcp connect or the udp send.
+    #[prost(uint64, tag="3")]
+    pub latency_us: u64,
+    /// error is the reason why the target is unreachable or the probe is refused.
+    #[prost(string, tag="4")]
+    pub error: ::prost::alloc::string::String,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ReportProbeResp {
 }
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
@@ -49,6 +205,14 @@ pub struct TrafficToServer {
     /// data is the traffic data.
     #[prost(bytes="vec", tag="3")]
     pub data: ::prost::alloc::vec::Vec<u8>,
+    /// version is the negotiated data stream protocol version,
+    /// it's only carried by the `Start` action, 0 is treated as version 1.
+    #[prost(uint32, tag="4")]
+    pub version: u32,
+    /// credit is the number of messages the server may send more,
+    /// it's only carried by the `Credit` action.
+    #[prost(uint32, tag="5")]
+    pub credit: u32,
 }
 /// Nested message and enum types in `TrafficToServer`.
 pub mod traffic_to_server {
@@ -67,6 +231,8 @@ pub mod traffic_to_server {
         /// generally, when something bad happens below:
         /// - can't dial the local upstream.
         Close = 3,
+        /// the client grants the server the credits to send more traffic, since version 2.
+        Credit = 4,
     }
     impl Action {
         /// String value of the enum field names used in the ProtoBuf definition.
@@ -79,6 +245,7 @@ pub mod traffic_to_server {
                 Action::Sending => "Sending",
                 Action::Finished => "Finished",
                 Action::Close => "Close",
+                Action::Credit => "Credit",
             }
         }
         /// Creates an enum from field names used in the ProtoBuf definition.
@@ -88,6 +255,7 @@ pub mod traffic_to_server {
                 "Sending" => Some(Self::Sending),
                 "Finished" => Some(Self::Finished),
                 "Close" => Some(Self::Close),
+                "Credit" => Some(Self::Credit),
                 _ => None,
             }
         }
@@ -96,14 +264,32 @@ pub mod traffic_to_server {
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct TrafficToClient {
-    #[prost(bytes="vec", tag="1")]
-    pub data: ::prost::alloc::vec::Vec<u8>,
+    #[prost(bytes="bytes", tag="1")]
+    pub data: ::prost::bytes::Bytes,
+    /// credit is the number of messages the client may send more, since version 2,
+    /// a message carrying the credit has no data.
+    #[prost(uint32, tag="2")]
+    pub credit: u32,
 }
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct RegisterReq {
     #[prost(message, optional, tag="1")]
     pub tunnel: ::core::option::Option<Tunnel>,
+    /// data_stream_version is the highest data stream protocol version the client speaks,
+    /// 0 means the client doesn't negotiate, it's treated as version 1.
+    #[prost(uint32, tag="2")]
+    pub data_stream_version: u32,
+    /// payload_cipher is the cipher encrypting the payloads of the data streams end-to-end
+    /// with the key shared by the client and the server, e.g. chacha20-poly1305,
+    /// empty means the payloads are plaintext.
+    #[prost(string, tag="3")]
+    pub payload_cipher: ::prost::alloc::string::String,
+    /// instance_id identifies the client process, e.g. a random uuid picked once it starts,
+    /// a tunnel registered again by the same instance replaces the previous registration,
+    /// empty means the registrations of the client are never replaced.
+    #[prost(string, tag="4")]
+    pub instance_id: ::prost::alloc::string::String,
 }
 /// Each tunnel is a bidirectional connection between the client and the server.
 /// Basically, one tunnel corresponds to one http2 connection.
@@ -117,12 +303,26 @@ pub struct Tunnel {
     /// name is the name of the tunnel.
     #[prost(string, tag="2")]
     pub name: ::prost::alloc::string::String,
+    /// labels are the key-value metadata of the tunnel, e.g. team, env.
+    /// the server shows them in the admin api and filters the tunnels by them.
+    #[prost(map="string, string", tag="6")]
+    pub labels: ::std::collections::HashMap<::prost::alloc::string::String, ::prost::alloc::string::String>,
+    /// loopback binds the listener of the tunnel to the loopback of the server,
+    /// so it's only reachable by the processes on the server, e.g. a reverse proxy.
+    /// the http tunnels served by the vhttp port(a domain or subdomain) can't be bound to it.
+    #[prost(bool, tag="7")]
+    pub loopback: bool,
+    /// rate_limit caps the bytes per second of the tunnel in each direction,
+    /// the limit of the server still applies if it's smaller.
+    /// 0 means the limit of the server.
+    #[prost(uint64, tag="8")]
+    pub rate_limit: u64,
     #[prost(oneof="tunnel::Config", tags="3, 4, 5")]
     pub config: ::core::option::Option<tunnel::Config>,
 }
 /// Nested message and enum types in `Tunnel`.
 pub mod tunnel {
-    #[allow(clippy::derive_partial_eq_without_eq)]
+    #[allow(clippy::derive_partial_eq_without_eq, clippy::large_enum_variant)]
 #[derive(Clone, PartialEq, ::prost::Oneof)]
     pub enum Config {
         #[prost(message, tag="3")]
@@ -162,6 +362,66 @@ pub struct HttpConfig {
     /// the server will listen on the remote_port to accept the http request.
     #[prost(int32, tag="4")]
     pub remote_port: i32,
+    /// request_timeout_ms is the max time to wait for the response of the local server,
+    /// the server returns 504 Gateway Timeout if the headers aren't received after it,
+    /// or aborts the response if its body isn't finished, 0 means no timeout.
+    #[prost(uint64, tag="5")]
+    pub request_timeout_ms: u64,
+    /// allowed_methods are the http methods forwarded to the tunnel, e.g. POST,
+    /// the server returns 405 Method Not Allowed for the others, empty allows all.
+    #[prost(string, repeated, tag="6")]
+    pub allowed_methods: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+    /// interstitial shows an interstitial page to the first-time visitors of the browsers,
+    /// they reach the local server after acknowledging it.
+    #[prost(bool, tag="7")]
+    pub interstitial: bool,
+    /// coalesce lets the concurrent identical GETs wait for the first one to be cached,
+    /// instead of forwarding all of them to the local server.
+    /// it only takes effect if the server caches the responses.
+    #[prost(bool, tag="8")]
+    pub coalesce: bool,
+    /// rewrite_origins are the origins of the local server, e.g. <http://localhost:3000>
+    /// of a dev server, they are replaced by the public origin of the tunnel in the location headers
+    /// and the text/html bodies of the responses, empty disables the rewrite.
+    #[prost(string, repeated, tag="9")]
+    pub rewrite_origins: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+    /// max_request_body is the max bytes of the request bodies, the larger ones get 413 Payload Too Large,
+    /// 0 means no limit, the limit of the server still applies.
+    #[prost(uint64, tag="10")]
+    pub max_request_body: u64,
+    /// max_response_body is the max bytes of the response bodies of the local server,
+    /// the larger ones are aborted, 0 means no limit, the limit of the server still applies.
+    #[prost(uint64, tag="11")]
+    pub max_response_body: u64,
+    /// path_prefix routes the requests under the path, e.g. /api, to the tunnel,
+    /// the tunnels of the same domain or subdomain are told apart by it,
+    /// the longest matching prefix wins, empty matches all the paths.
+    #[prost(string, tag="12")]
+    pub path_prefix: ::prost::alloc::string::String,
+    /// request_headers rewrite the headers of the requests before they are forwarded to the tunnel.
+    #[prost(message, optional, tag="13")]
+    pub request_headers: ::core::option::Option<HeaderRules>,
+    /// response_headers rewrite the headers of the responses of the local server.
+    #[prost(message, optional, tag="14")]
+    pub response_headers: ::core::option::Option<HeaderRules>,
+}
+/// HeaderRules rewrite the headers of the http messages, the names are case-insensitive,
+/// they are applied in the order of remove, set and add.
+/// the framing headers, i.e. Content-Length and Transfer-Encoding, and the hop-by-hop ones
+/// can't be rewritten.
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct HeaderRules {
+    /// add appends the headers, e.g. X-Forwarded-Host, the Host replaces the one of the request
+    /// instead of being duplicated.
+    #[prost(map="string, string", tag="1")]
+    pub add: ::std::collections::HashMap<::prost::alloc::string::String, ::prost::alloc::string::String>,
+    /// remove drops all the values of the headers.
+    #[prost(string, repeated, tag="2")]
+    pub remove: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+    /// set replaces all the values of the headers, e.g. the Host expected by the local server.
+    #[prost(map="string, string", tag="3")]
+    pub set: ::std::collections::HashMap<::prost::alloc::string::String, ::prost::alloc::string::String>,
 }
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
@@ -169,6 +429,18 @@ pub struct TcpConfig {
     /// if remote_port is empty, the server will assign a random port.
     #[prost(int32, tag="1")]
     pub remote_port: i32,
+    /// first_byte_timeout_ms closes the connections nobody speaks on within it after they are set up,
+    /// e.g. the probes of the scanners, 0 keeps them open.
+    #[prost(uint64, tag="2")]
+    pub first_byte_timeout_ms: u64,
+    /// first_byte_from_user only counts the bytes of the user against first_byte_timeout_ms,
+    /// e.g. the banner of a server-speaks-first protocol like ssh doesn't keep the connection open.
+    #[prost(bool, tag="3")]
+    pub first_byte_from_user: bool,
+    /// max_chunk_size caps the bytes of a data message of the connections, 0 means no preference,
+    /// the server answers the smaller one of it and its own in the InitPayload.
+    #[prost(uint32, tag="4")]
+    pub max_chunk_size: u32,
 }
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
@@ -176,6 +448,13 @@ pub struct UdpConfig {
     /// if remote_port is empty, the server will assign a random port.
     #[prost(int32, tag="1")]
     pub remote_port: i32,
+    /// compression asks for compressing the datagrams, e.g. deflate, empty means uncompressed.
+    #[prost(string, tag="2")]
+    pub compression: ::prost::alloc::string::String,
+    /// max_datagram_size is the largest datagram the local endpoint takes, 0 means no preference,
+    /// the server drops the larger ones of the users instead of sending them to the client.
+    #[prost(uint32, tag="3")]
+    pub max_datagram_size: u32,
 }
 include!("message.tonic.rs");
 // @@protoc_insertion_point(module)
\ No newline at end of file
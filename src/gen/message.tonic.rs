@@ -84,6 +84,8 @@ pub mod tunnel_service_client {
             self.inner = self.inner.max_encoding_message_size(limit);
             self
         }
+        /// the client sends Register to the server to create a tunnel.
+        /// the server returns a stream Control to the client, the following control messages are sent through the stream.
         pub async fn register(
             &mut self,
             request: impl tonic::IntoRequest<super::RegisterReq>,
@@ -109,6 +111,8 @@ pub mod tunnel_service_client {
                 .insert(GrpcMethod::new("message.TunnelService", "Register"));
             self.inner.server_streaming(req, path, codec).await
         }
+        /// server side: write traffic to the stream, and read traffic from the stream.
+        /// client side: read traffic from the stream, and write traffic to the stream.
         pub async fn data(
             &mut self,
             request: impl tonic::IntoStreamingRequest<Message = super::TrafficToServer>,
@@ -134,6 +138,32 @@ pub mod tunnel_service_client {
                 .insert(GrpcMethod::new("message.TunnelService", "Data"));
             self.inner.streaming(req, path, codec).await
         }
+        /// the client reports the result of the Probe control command.
+        pub async fn report_probe(
+            &mut self,
+            request: impl tonic::IntoRequest<super::ProbeReport>,
+        ) -> std::result::Result<
+            tonic::Response<super::ReportProbeResp>,
+            tonic::Status,
+        > {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/message.TunnelService/ReportProbe",
+            );
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(GrpcMethod::new("message.TunnelService", "ReportProbe"));
+            self.inner.unary(req, path, codec).await
+        }
     }
 }
 /// Generated server implementations.
@@ -149,6 +179,8 @@ pub mod tunnel_service_server {
             >
             + Send
             + 'static;
+        /// the client sends Register to the server to create a tunnel.
+        /// the server returns a stream Control to the client, the following control messages are sent through the stream.
         async fn register(
             &self,
             request: tonic::Request<super::RegisterReq>,
@@ -159,10 +191,17 @@ pub mod tunnel_service_server {
             >
             + Send
             + 'static;
+        /// server side: write traffic to the stream, and read traffic from the stream.
+        /// client side: read traffic from the stream, and write traffic to the stream.
         async fn data(
             &self,
             request: tonic::Request<tonic::Streaming<super::TrafficToServer>>,
         ) -> std::result::Result<tonic::Response<Self::DataStream>, tonic::Status>;
+        /// the client reports the result of the Probe control command.
+        async fn report_probe(
+            &self,
+            request: tonic::Request<super::ProbeReport>,
+        ) -> std::result::Result<tonic::Response<super::ReportProbeResp>, tonic::Status>;
     }
     #[derive(Debug)]
     pub struct TunnelServiceServer<T: TunnelService> {
@@ -339,6 +378,52 @@ pub mod tunnel_service_server {
                     };
                     Box::pin(fut)
                 }
+                "/message.TunnelService/ReportProbe" => {
+                    #[allow(non_camel_case_types)]
+                    struct ReportProbeSvc<T: TunnelService>(pub Arc<T>);
+                    impl<
+                        T: TunnelService,
+                    > tonic::server::UnaryService<super::ProbeReport>
+                    for ReportProbeSvc<T> {
+                        type Response = super::ReportProbeResp;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::ProbeReport>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move {
+                                <T as TunnelService>::report_probe(&inner, request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = ReportProbeSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
                 _ => {
                     Box::pin(async move {
                         Ok(
@@ -1,6 +1,11 @@
+use std::collections::HashMap;
+
 use tonic::Status;
 
-use crate::pb::RegisterReq;
+use crate::{
+    pb::{tunnel, RegisterReq},
+    server::HeaderRules,
+};
 
 pub(crate) fn validate_register_req(req: &RegisterReq) -> Option<Status> {
     if req.tunnel.is_none() {
@@ -10,5 +15,181 @@ pub(crate) fn validate_register_req(req: &RegisterReq) -> Option<Status> {
     if tunnel.config.is_none() {
         return Some(Status::invalid_argument("config is required"));
     }
+    if let Some(tunnel::Config::Http(http)) = &tunnel.config {
+        if let Some(method) = http
+            .allowed_methods
+            .iter()
+            .find(|method| http::Method::from_bytes(method.as_bytes()).is_err())
+        {
+            return Some(Status::invalid_argument(format!(
+                "invalid http method {:?}",
+                method
+            )));
+        }
+        if let Some(status) = validate_path_prefix(http) {
+            return Some(status);
+        }
+        for rules in [&http.request_headers, &http.response_headers]
+            .into_iter()
+            .flatten()
+        {
+            if let Err(status) = HeaderRules::try_from(rules) {
+                return Some(status);
+            }
+        }
+        // the vhttp port is shared by the tunnels
+        if tunnel.loopback
+            && (!http.domain.is_empty() || !http.subdomain.is_empty() || http.random_subdomain)
+        {
+            return Some(Status::invalid_argument(
+                "only the http tunnels with their own ports can be bound to the loopback",
+            ));
+        }
+    }
+    validate_labels(&tunnel.labels)
+}
+
+/// the path prefix tells apart the tunnels sharing a domain or subdomain of the vhttp port.
+fn validate_path_prefix(http: &crate::pb::HttpConfig) -> Option<Status> {
+    let path_prefix = &http.path_prefix;
+    if path_prefix.is_empty() {
+        return None;
+    }
+    if !path_prefix.starts_with('/')
+        || path_prefix
+            .chars()
+            .any(|c| c.is_whitespace() || c.is_control() || matches!(c, '?' | '#'))
+    {
+        return Some(Status::invalid_argument(format!(
+            "invalid path prefix {:?}, it must start with '/' and have no query or fragment",
+            path_prefix
+        )));
+    }
+    if http.domain.is_empty() && http.subdomain.is_empty() && !http.random_subdomain {
+        return Some(Status::invalid_argument(
+            "only the http tunnels of a domain or subdomain can be routed by the path prefix",
+        ));
+    }
+    None
+}
+
+const MAX_LABELS: usize = 32;
+const MAX_LABEL_KEY_LEN: usize = 63;
+const MAX_LABEL_VALUE_LEN: usize = 255;
+
+fn validate_labels(labels: &HashMap<String, String>) -> Option<Status> {
+    if labels.len() > MAX_LABELS {
+        return Some(Status::invalid_argument(format!(
+            "too many labels, at most {}",
+            MAX_LABELS
+        )));
+    }
+    for (key, value) in labels {
+        if key.is_empty()
+            || key.len() > MAX_LABEL_KEY_LEN
+            || !key
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '/' | '-'))
+        {
+            return Some(Status::invalid_argument(format!(
+                "invalid label key {:?}, it must be 1-{} characters of [a-zA-Z0-9._/-]",
+                key, MAX_LABEL_KEY_LEN
+            )));
+        }
+        if value.len() > MAX_LABEL_VALUE_LEN || value.chars().any(char::is_control) {
+            return Some(Status::invalid_argument(format!(
+                "invalid value of label {:?}, it must be at most {} printable characters",
+                key, MAX_LABEL_VALUE_LEN
+            )));
+        }
+    }
     None
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_validate_allowed_methods() {
+        let req = |methods: &[&str]| RegisterReq {
+            tunnel: Some(crate::pb::Tunnel {
+                config: Some(tunnel::Config::Http(crate::pb::HttpConfig {
+                    allowed_methods: methods.iter().map(|m| m.to_string()).collect(),
+                    ..Default::default()
+                })),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert!(validate_register_req(&req(&[])).is_none());
+        assert!(validate_register_req(&req(&["POST", "PUT"])).is_none());
+        assert!(validate_register_req(&req(&["POST", "BAD METHOD"])).is_some());
+    }
+
+    #[test]
+    fn test_validate_loopback() {
+        let req = |http: crate::pb::HttpConfig| RegisterReq {
+            tunnel: Some(crate::pb::Tunnel {
+                config: Some(tunnel::Config::Http(http)),
+                loopback: true,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert!(validate_register_req(&req(crate::pb::HttpConfig {
+            remote_port: 8080,
+            ..Default::default()
+        }))
+        .is_none());
+        assert!(validate_register_req(&req(crate::pb::HttpConfig {
+            subdomain: "foo".to_string(),
+            ..Default::default()
+        }))
+        .is_some());
+    }
+
+    #[test]
+    fn test_validate_path_prefix() {
+        let req = |subdomain: &str, path_prefix: &str| RegisterReq {
+            tunnel: Some(crate::pb::Tunnel {
+                config: Some(tunnel::Config::Http(crate::pb::HttpConfig {
+                    subdomain: subdomain.to_string(),
+                    path_prefix: path_prefix.to_string(),
+                    ..Default::default()
+                })),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert!(validate_register_req(&req("foo", "")).is_none());
+        assert!(validate_register_req(&req("foo", "/api")).is_none());
+        assert!(validate_register_req(&req("foo", "/api/v1/")).is_none());
+        assert!(validate_register_req(&req("foo", "api")).is_some());
+        assert!(validate_register_req(&req("foo", "/api?v=1")).is_some());
+        assert!(validate_register_req(&req("foo", "/a b")).is_some());
+        // the tunnel has its own port
+        assert!(validate_register_req(&req("", "/api")).is_some());
+    }
+
+    #[test]
+    fn test_validate_labels() {
+        let labels = |pairs: &[(&str, &str)]| {
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect::<HashMap<_, _>>()
+        };
+        assert!(validate_labels(&labels(&[])).is_none());
+        assert!(validate_labels(&labels(&[("team", "infra"), ("app.io/env", "")])).is_none());
+        assert!(validate_labels(&labels(&[("", "infra")])).is_some());
+        assert!(validate_labels(&labels(&[("team name", "infra")])).is_some());
+        assert!(validate_labels(&labels(&[("team", "in\nfra")])).is_some());
+        assert!(validate_labels(&labels(&[("team", &"x".repeat(256))])).is_some());
+
+        let too_many = (0..=MAX_LABELS)
+            .map(|i| (i.to_string(), String::new()))
+            .collect();
+        assert!(validate_labels(&too_many).is_some());
+    }
+}
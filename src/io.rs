@@ -1,7 +1,11 @@
-use crate::bridge::BridgeData;
+use crate::bridge::{BridgeData, DataReceiver};
+use crate::compress;
+use crate::crypto::PayloadCipher;
+use crate::flow::SendCredits;
 use crate::pb::traffic_to_server;
 use crate::pb::TrafficToClient;
 use crate::pb::TrafficToServer;
+use bytes::Bytes;
 use futures::ready;
 use futures::Stream;
 use std::fmt::Debug;
@@ -48,13 +52,49 @@ impl<T> Drop for CancellableReceiver<T> {
     }
 }
 
+/// Remaining is the rest of a message larger than the read buffer,
+/// e.g. a chunk of 64KiB read by the 8KiB buffer of `io::copy`, it's read first by the next reads.
+#[derive(Debug, Default)]
+pub(crate) struct Remaining {
+    data: Bytes,
+    read: usize,
+}
+
+impl Remaining {
+    /// put the rest into the buffer, false if nothing remains.
+    fn take(&mut self, buf: &mut io::ReadBuf<'_>) -> bool {
+        if self.read >= self.data.len() {
+            return false;
+        }
+        let n = buf.remaining().min(self.data.len() - self.read);
+        buf.put_slice(&self.data[self.read..self.read + n]);
+        self.read += n;
+        true
+    }
+
+    /// put the message into the buffer, the bytes not fitting are kept.
+    /// the empty message is put as it is, it tells the end.
+    fn put(&mut self, data: Bytes, buf: &mut io::ReadBuf<'_>) {
+        let n = buf.remaining().min(data.len());
+        buf.put_slice(&data[..n]);
+        if n < data.len() {
+            self.data = data;
+            self.read = n;
+        }
+    }
+}
+
 pub struct StreamingReader<T> {
     receiver: mpsc::Receiver<T>,
+    remaining: Remaining,
 }
 
 impl<T: Send> StreamingReader<T> {
     pub fn new(receiver: mpsc::Receiver<T>) -> Self {
-        Self { receiver }
+        Self {
+            receiver,
+            remaining: Remaining::default(),
+        }
     }
 }
 
@@ -65,9 +105,12 @@ impl AsyncRead for StreamingReader<TrafficToClient> {
         cx: &mut std::task::Context<'_>,
         buf: &mut io::ReadBuf<'_>,
     ) -> Poll<std::io::Result<()>> {
+        if self.remaining.take(buf) {
+            return Poll::Ready(Ok(()));
+        }
         match ready!(self.receiver.poll_recv(cx)) {
             Some(traffic) => {
-                buf.put_slice(traffic.data.as_slice());
+                self.remaining.put(traffic.data, buf);
                 Poll::Ready(Ok(()))
             }
             None => Poll::Ready(Ok(())),
@@ -75,46 +118,75 @@ impl AsyncRead for StreamingReader<TrafficToClient> {
     }
 }
 
-impl AsyncRead for StreamingReader<BridgeData> {
+/// read data from the bridge, it gives the bytes back to the in-flight cap of the bridge.
+impl AsyncRead for DataReceiver {
     fn poll_read(
         mut self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
         buf: &mut io::ReadBuf<'_>,
     ) -> Poll<std::io::Result<()>> {
-        match ready!(self.receiver.poll_recv(cx)) {
-            Some(data) => {
-                match data {
-                    BridgeData::Sender(_) => {
-                        panic!("we should not receive DataSender")
-                    }
-                    BridgeData::Data(data) => {
-                        buf.put_slice(data.as_slice());
-                    }
+        if self.remaining.take(buf) {
+            return Poll::Ready(Ok(()));
+        }
+        match ready!(self.poll_recv(cx)) {
+            Some(data) => match data {
+                BridgeData::Sender(_) => Poll::Ready(Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "unexpected sender after the bridge handshake",
+                ))),
+                BridgeData::Data(data) => {
+                    self.remaining.put(data.into(), buf);
+                    Poll::Ready(Ok(()))
                 }
-                Poll::Ready(Ok(()))
-            }
+            },
             None => Poll::Ready(Ok(())),
         }
     }
 }
 
+/// StreamingWriter sends the written bytes as the messages of a data stream.
+///
+/// Without a chunk size each write is sent as it is, e.g. what `io::copy` reads at once.
+/// With a chunk size the messages are at most that size, the larger writes are split
+/// and the smaller ones are coalesced until the chunk is full or the writer is flushed,
+/// `io::copy` flushes whenever the reader has nothing ready, so coalescing never holds
+/// the bytes of an idle connection. The larger chunks mean less per-message overhead
+/// and the smaller ones less head-of-line blocking of the other connections of the stream.
+///
+/// With the credits each message waits for a credit granted by the peer, see [`crate::flow`].
 pub struct StreamingWriter<T> {
     sender: PollSender<T>,
     wrapper: Box<dyn WriteDataWrapper<T>>,
+    chunk_size: Option<usize>,
+    /// the coalesced bytes not sent yet, it's empty without a chunk size.
+    pending: Vec<u8>,
+    credits: Option<SendCredits>,
 }
 
 pub trait WriteDataWrapper<T>: Send {
-    fn wrap_write(&self, buf: &[u8]) -> T;
+    fn wrap_write(&mut self, buf: &[u8]) -> T;
     fn wrap_shutdown(&self) -> T;
 }
 
 pub struct TrafficToServerWrapper {
     connection_id: String,
+    /// seals the data if the tunnel encrypts the payloads.
+    sealer: Option<PayloadCipher>,
+    /// compresses each write of the udp tunnels compressing the datagrams, before sealing.
+    compressed: bool,
 }
 
 impl TrafficToServerWrapper {
-    pub fn new(connection_id: String) -> Box<Self> {
-        Box::new(Self { connection_id })
+    pub fn new(
+        connection_id: String,
+        sealer: Option<PayloadCipher>,
+        compressed: bool,
+    ) -> Box<Self> {
+        Box::new(Self {
+            connection_id,
+            sealer,
+            compressed,
+        })
     }
 }
 
@@ -122,7 +194,7 @@ pub struct VecWrapper<T> {
     _phantom: std::marker::PhantomData<T>,
 }
 
-impl VecWrapper<Vec<u8>> {
+impl VecWrapper<Bytes> {
     pub fn new() -> Box<Self> {
         Box::new(Self {
             _phantom: std::marker::PhantomData,
@@ -130,22 +202,32 @@ impl VecWrapper<Vec<u8>> {
     }
 }
 
-impl WriteDataWrapper<Vec<u8>> for VecWrapper<Vec<u8>> {
-    fn wrap_write(&self, buf: &[u8]) -> Vec<u8> {
-        buf.to_vec()
+impl WriteDataWrapper<Bytes> for VecWrapper<Bytes> {
+    fn wrap_write(&mut self, buf: &[u8]) -> Bytes {
+        Bytes::copy_from_slice(buf)
     }
 
-    fn wrap_shutdown(&self) -> Vec<u8> {
-        Vec::new()
+    fn wrap_shutdown(&self) -> Bytes {
+        Bytes::new()
     }
 }
 
 impl WriteDataWrapper<TrafficToServer> for TrafficToServerWrapper {
-    fn wrap_write(&self, buf: &[u8]) -> TrafficToServer {
+    fn wrap_write(&mut self, buf: &[u8]) -> TrafficToServer {
+        let data = if self.compressed {
+            compress::compress(buf)
+        } else {
+            buf.to_vec()
+        };
+        let data = match &mut self.sealer {
+            Some(sealer) => sealer.seal(data),
+            None => data,
+        };
         TrafficToServer {
             connection_id: self.connection_id.clone(),
             action: traffic_to_server::Action::Sending as i32,
-            data: buf.to_vec(),
+            data,
+            ..Default::default()
         }
     }
 
@@ -163,9 +245,24 @@ impl<T: Send + Debug> StreamingWriter<T> {
         Self {
             sender: PollSender::new(sender),
             wrapper,
+            chunk_size: None,
+            pending: Vec::new(),
+            credits: None,
         }
     }
 
+    /// None sends the messages without waiting for the credits.
+    pub(crate) fn with_credits(mut self, credits: Option<SendCredits>) -> Self {
+        self.credits = credits;
+        self
+    }
+
+    /// None sends the writes as they are, the chunk size is at least 1.
+    pub fn with_chunk_size(mut self, chunk_size: Option<usize>) -> Self {
+        self.chunk_size = chunk_size.map(|size| size.max(1));
+        self
+    }
+
     fn poll_write_impl(
         &mut self,
         cx: &mut Context<'_>,
@@ -176,16 +273,65 @@ impl<T: Send + Debug> StreamingWriter<T> {
         if buf.is_empty() {
             return Poll::Ready(Ok(0));
         }
+        let Some(chunk_size) = self.chunk_size else {
+            return self.poll_send(cx, buf);
+        };
+
+        let room = chunk_size - self.pending.len();
+        if buf.len() < room {
+            self.pending.extend_from_slice(buf);
+            return Poll::Ready(Ok(buf.len()));
+        }
+        if self.pending.is_empty() {
+            return self.poll_send(cx, &buf[..chunk_size]);
+        }
+        // fill up the chunk, the rest of the buf is written by the next call
+        ready!(self.poll_send_pending(cx, &buf[..room]))?;
+        Poll::Ready(Ok(room))
+    }
 
+    /// send the pending bytes followed by the extra bytes as a message.
+    fn poll_send_pending(
+        &mut self,
+        cx: &mut Context<'_>,
+        extra: &[u8],
+    ) -> Poll<std::result::Result<(), std::io::Error>> {
+        if self.pending.is_empty() && extra.is_empty() {
+            return Poll::Ready(Ok(()));
+        }
+        let mut pending = std::mem::take(&mut self.pending);
+        pending.extend_from_slice(extra);
+        match self.poll_send(cx, &pending) {
+            Poll::Ready(result) => Poll::Ready(result.map(|_| ())),
+            Poll::Pending => {
+                pending.truncate(pending.len() - extra.len());
+                self.pending = pending;
+                Poll::Pending
+            }
+        }
+    }
+
+    fn poll_send(
+        &mut self,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::result::Result<usize, std::io::Error>> {
+        if let Some(credits) = &mut self.credits {
+            if !ready!(credits.poll_acquire(cx)) {
+                return Poll::Ready(Err(std::io::Error::other(
+                    "the data stream doesn't grant credits anymore",
+                )));
+            }
+        }
         match ready!(self.sender.poll_reserve(cx)) {
             Ok(_) => {
                 let wrapped_buf = self.wrapper.wrap_write(buf);
                 if let Err(err) = self.sender.send_item(wrapped_buf) {
                     debug!("failed to send data: {:?}", err);
-                    return Poll::Ready(Err(std::io::Error::new(
-                        std::io::ErrorKind::Other,
-                        "failed to send data",
-                    )));
+                    return Poll::Ready(Err(std::io::Error::other("failed to send data")));
+                }
+                if let Some(credits) = &mut self.credits {
+                    credits.spend();
                 }
             }
             Err(e) => {
@@ -197,11 +343,11 @@ impl<T: Send + Debug> StreamingWriter<T> {
     }
 
     fn poll_flush_impl(
-        &self,
-        _cx: &mut Context<'_>,
+        &mut self,
+        cx: &mut Context<'_>,
     ) -> Poll<std::result::Result<(), std::io::Error>> {
         debug!("flushing streaming");
-        Poll::Ready(Ok(()))
+        self.poll_send_pending(cx, &[])
     }
 
     fn poll_shutdown_impl(
@@ -209,23 +355,21 @@ impl<T: Send + Debug> StreamingWriter<T> {
         cx: &mut Context<'_>,
     ) -> Poll<std::result::Result<(), std::io::Error>> {
         debug!("shutting down streaming writer");
+        ready!(self.poll_send_pending(cx, &[]))?;
 
         match ready!(self.sender.poll_reserve(cx)) {
             Ok(_) => {
                 let shutdown_buf = self.wrapper.wrap_shutdown();
                 if let Err(err) = self.sender.send_item(shutdown_buf) {
                     debug!("failed to send data: {:?}", err);
-                    return Poll::Ready(Err(std::io::Error::new(
-                        std::io::ErrorKind::Other,
-                        "failed to send data",
-                    )));
+                    return Poll::Ready(Err(std::io::Error::other("failed to send data")));
                 }
             }
             Err(err) => {
-                return Poll::Ready(Err(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    format!("failed to shutdown: {:?}", err),
-                )));
+                return Poll::Ready(Err(std::io::Error::other(format!(
+                    "failed to shutdown: {:?}",
+                    err
+                ))));
             }
         }
 
@@ -245,7 +389,7 @@ macro_rules! generate_async_write_impl {
             }
 
             fn poll_flush(
-                self: std::pin::Pin<&mut Self>,
+                mut self: std::pin::Pin<&mut Self>,
                 cx: &mut Context<'_>,
             ) -> Poll<std::result::Result<(), std::io::Error>> {
                 self.poll_flush_impl(cx)
@@ -262,4 +406,117 @@ macro_rules! generate_async_write_impl {
 }
 
 generate_async_write_impl!(TrafficToServer);
-generate_async_write_impl!(Vec<u8>);
+generate_async_write_impl!(Bytes);
+
+/// shut the writer down, it gives up with `TimedOut` after the timeout,
+/// e.g. the peer doesn't take the end of the stream, the caller drops the connection then.
+///
+/// None waits for as long as it takes.
+pub(crate) async fn shutdown_within<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    timeout: Option<std::time::Duration>,
+) -> io::Result<()> {
+    use tokio::io::AsyncWriteExt as _;
+
+    let Some(timeout) = timeout else {
+        return writer.shutdown().await;
+    };
+    tokio::time::timeout(timeout, writer.shutdown())
+        .await
+        .unwrap_or_else(|_| {
+            Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "the writer isn't shut down in time",
+            ))
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+    use super::*;
+    use crate::flow;
+
+    #[tokio::test]
+    async fn test_streaming_reader_large_message() {
+        let (tx, rx) = mpsc::channel(16);
+        let mut reader = StreamingReader::new(rx);
+        let data: Vec<u8> = (0..10).collect();
+        for data in [data.clone(), vec![10], vec![]] {
+            tx.send(TrafficToClient {
+                data: data.into(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        }
+        // the message larger than the buffer is read by the next reads
+        let mut buf = [0; 4];
+        assert_eq!(reader.read(&mut buf).await.unwrap(), 4);
+        assert_eq!(buf, [0, 1, 2, 3]);
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(rest, (4..=10).collect::<Vec<u8>>());
+    }
+
+    #[tokio::test]
+    async fn test_streaming_writer_chunk_size() {
+        let (tx, mut rx) = mpsc::channel(16);
+        let mut writer =
+            StreamingWriter::new(tx, VecWrapper::<Bytes>::new()).with_chunk_size(Some(4));
+        writer.write_all(b"ab").await.unwrap();
+        assert!(rx.try_recv().is_err());
+        // coalesced with the pending bytes, then split
+        writer.write_all(b"cdefghij").await.unwrap();
+        writer.write_all(b"k").await.unwrap();
+        writer.flush().await.unwrap();
+        writer.write_all(b"l").await.unwrap();
+        writer.shutdown().await.unwrap();
+        drop(writer);
+
+        let mut messages = Vec::new();
+        while let Some(message) = rx.recv().await {
+            messages.push(message);
+        }
+        assert_eq!(
+            messages,
+            [&b"abcd"[..], b"efgh", b"ijk", b"l", b""].map(<[u8]>::to_vec)
+        );
+
+        // without a chunk size the writes are sent as they are
+        let (tx, mut rx) = mpsc::channel(16);
+        let mut writer = StreamingWriter::new(tx, VecWrapper::<Bytes>::new());
+        writer.write_all(b"ab").await.unwrap();
+        assert_eq!(rx.recv().await.unwrap(), &b"ab"[..]);
+    }
+
+    #[tokio::test]
+    async fn test_streaming_writer_credits() {
+        let (tx, mut rx) = mpsc::channel(16);
+        let (credits, granter) = flow::credits(1);
+        let mut writer =
+            StreamingWriter::new(tx, VecWrapper::<Bytes>::new()).with_credits(Some(credits));
+        writer.write_all(b"ab").await.unwrap();
+        // out of credits
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), writer.write_all(b"cd"))
+                .await
+                .is_err()
+        );
+        assert_eq!(rx.recv().await.unwrap(), &b"ab"[..]);
+        assert!(rx.try_recv().is_err());
+
+        granter.grant(1);
+        writer.write_all(b"cd").await.unwrap();
+        assert_eq!(rx.recv().await.unwrap(), &b"cd"[..]);
+        // the shutdown doesn't take a credit
+        writer.shutdown().await.unwrap();
+        assert_eq!(rx.recv().await.unwrap(), &b""[..]);
+
+        drop(granter);
+        assert!(writer.write_all(b"ef").await.is_err());
+    }
+}
@@ -1,17 +1,34 @@
+// tonic::Status is large, but returning it directly is the idiom of grpc handlers.
+#![allow(clippy::result_large_err)]
+
+pub(crate) mod auth;
 pub(crate) mod bridge;
+pub(crate) mod compress;
 pub(crate) mod constant;
+pub(crate) mod crypto;
 pub(crate) mod event;
+pub(crate) mod flow;
 pub(crate) mod helper;
 pub(crate) mod io;
+pub(crate) mod protocol;
 pub(crate) mod socket;
+pub(crate) mod timing;
 
 pub mod pb {
     include!("gen/message.rs");
+
+    /// the encoded descriptors of the messages and the service, it's served by the grpc reflection.
+    pub(crate) const FILE_DESCRIPTOR_SET: &[u8] = include_bytes!("gen/message.binpb");
 }
 
 pub mod client;
 pub mod debug;
+pub mod profile;
 pub mod server;
+pub mod signal;
 
 #[cfg(feature = "util")]
 pub mod util;
+
+pub use crypto::PayloadKey;
+pub use socket::TcpKeepalive;
@@ -0,0 +1,160 @@
+//! The profiles of the command line.
+//!
+//! A profile file(`~/.castle/config.toml` by default) holds named profiles,
+//! each profile is a table of the default values of the flags, e.g.
+//!
+//! ```toml
+//! [default]
+//! server_addr = ["127.0.0.1:6610"]
+//!
+//! [prod]
+//! server_addr = ["10.0.0.1:6610", "10.0.0.2:6610"]
+//! labels = { team = "infra" }
+//! tcp_keepalive_idle = 60
+//! ```
+//!
+//! `--profile` selects the profile, `default` is used if it's not given.
+//! The flags given on the command line override the values of the profile,
+//! `castle` and `castled` read the keys they know and ignore the rest.
+//! `castled` reloads the profile on `SIGHUP`, see [`crate::server::Reloader`].
+//!
+//! `castled --config` reads a file holding a single profile at the top level instead, e.g.
+//!
+//! ```toml
+//! domain = ["tunnel.example.com", "tunnel.example.org"]
+//! ip = ["203.0.113.10"]
+//! random_min_port = 20000
+//! random_max_port = 30000
+//! auth_token = "secret"
+//! ```
+use std::{
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context};
+use serde::de::DeserializeOwned;
+
+/// the profile used when `--profile` is not given.
+pub const DEFAULT_PROFILE: &str = "default";
+
+/// the path of the profile file, `~/.castle/config.toml`.
+pub fn default_path() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| Path::new(&home).join(".castle").join("config.toml"))
+}
+
+/// Loads the profile `name` from the file at `path`.
+///
+/// `path` and `name` fall back to [`default_path`] and [`DEFAULT_PROFILE`],
+/// the missing file or profile is an error only if they are given explicitly,
+/// otherwise the default value of the profile is returned.
+pub fn load<T: DeserializeOwned + Default>(
+    path: Option<&Path>,
+    name: Option<&str>,
+) -> anyhow::Result<T> {
+    let explicit = path.is_some() || name.is_some();
+    let Some(path) = path.map(Path::to_path_buf).or_else(default_path) else {
+        return Ok(T::default());
+    };
+    let content = match std::fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == ErrorKind::NotFound && !explicit => return Ok(T::default()),
+        Err(err) => {
+            return Err(err).with_context(|| format!("failed to read {}", path.display()));
+        }
+    };
+    parse(&content, name).with_context(|| format!("invalid profile file {}", path.display()))
+}
+
+/// Loads the file at `path` as a single profile, see `castled --config`.
+pub fn load_file<T: DeserializeOwned>(path: &Path) -> anyhow::Result<T> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    toml::from_str(&content).with_context(|| format!("invalid config file {}", path.display()))
+}
+
+fn parse<T: DeserializeOwned + Default>(content: &str, name: Option<&str>) -> anyhow::Result<T> {
+    let mut profiles: toml::Table = toml::from_str(content)?;
+    match profiles.remove(name.unwrap_or(DEFAULT_PROFILE)) {
+        Some(profile) => Ok(profile.try_into()?),
+        None => match name {
+            Some(name) => Err(anyhow!("profile {:?} not found", name)),
+            None => Ok(T::default()),
+        },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(Debug, Default, PartialEq, Deserialize)]
+    #[serde(default)]
+    struct Profile {
+        server_addr: Vec<String>,
+        tcp_keepalive_idle: Option<u64>,
+    }
+
+    #[test]
+    fn test_parse() {
+        let content = r#"
+            [default]
+            server_addr = ["127.0.0.1:6610"]
+
+            [prod]
+            server_addr = ["10.0.0.1:6610"]
+            tcp_keepalive_idle = 60
+            unknown = "ignored"
+        "#;
+        assert_eq!(
+            parse::<Profile>(content, None).unwrap(),
+            Profile {
+                server_addr: vec!["127.0.0.1:6610".to_string()],
+                tcp_keepalive_idle: None,
+            }
+        );
+        assert_eq!(
+            parse::<Profile>(content, Some("prod")).unwrap(),
+            Profile {
+                server_addr: vec!["10.0.0.1:6610".to_string()],
+                tcp_keepalive_idle: Some(60),
+            }
+        );
+        assert!(parse::<Profile>(content, Some("dev")).is_err());
+        assert_eq!(
+            parse::<Profile>("[prod]", None).unwrap(),
+            Profile::default()
+        );
+        assert!(parse::<Profile>("[default]\ntcp_keepalive_idle = \"60\"", None).is_err());
+    }
+
+    #[test]
+    fn test_load_missing_file() {
+        let path = std::env::temp_dir().join("castle-missing-profile.toml");
+        assert!(load::<Profile>(Some(&path), None).is_err());
+        assert!(load_file::<Profile>(&path).is_err());
+    }
+
+    #[test]
+    fn test_load_file() {
+        let path =
+            std::env::temp_dir().join(format!("castle-test-config-{}.toml", uuid::Uuid::new_v4()));
+        std::fs::write(
+            &path,
+            "server_addr = [\"10.0.0.1:6610\"]\ntcp_keepalive_idle = 60\n",
+        )
+        .unwrap();
+        assert_eq!(
+            load_file::<Profile>(&path).unwrap(),
+            Profile {
+                server_addr: vec!["10.0.0.1:6610".to_string()],
+                tcp_keepalive_idle: Some(60),
+            }
+        );
+        std::fs::write(&path, "tcp_keepalive_idle = \"60\"").unwrap();
+        assert!(load_file::<Profile>(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
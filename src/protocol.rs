@@ -0,0 +1,116 @@
+//! Versioning of the data stream protocol.
+//!
+//! The client announces the highest version it speaks in [`crate::pb::RegisterReq`],
+//! the server answers the negotiated version in [`crate::pb::InitPayload`],
+//! then every data stream must start with a `Start` action carrying the negotiated version
+//! before any traffic is sent.
+//!
+//! Version 2 adds the credit based flow control, see [`crate::flow`].
+//!
+//! The sizes of the data messages are negotiated the same way, the tcp tunnel announces
+//! its `max_chunk_size`, the server answers the smaller one of it and its `data_chunk_size`
+//! which caps the data messages of both sides, see [`negotiate_chunk_size`].
+//! The udp tunnel announces its `max_datagram_size`, the larger datagrams of the users
+//! are dropped by the server instead.
+use tonic::Status;
+
+/// the data stream protocol version spoken by this build.
+pub(crate) const DATA_STREAM_VERSION: u32 = 2;
+
+/// the lowest data stream protocol version this build still speaks.
+pub(crate) const MIN_DATA_STREAM_VERSION: u32 = 1;
+
+/// the credits of each direction of a flow controlled data stream when it starts.
+pub(crate) const INITIAL_CREDITS: u32 = 64;
+
+/// the smallest chunk size a peer can ask for, so it can't make the other side
+/// send a message per few bytes.
+pub(crate) const MIN_CHUNK_SIZE: usize = 512;
+
+/// whether the data stream of the version is flow controlled by the credits.
+pub(crate) fn flow_controlled(version: u32) -> bool {
+    version >= 2
+}
+
+/// the peers predating the negotiation send 0, they speak version 1.
+fn normalize(version: u32) -> u32 {
+    if version == 0 {
+        1
+    } else {
+        version
+    }
+}
+
+/// negotiate picks the highest version spoken by both sides.
+pub(crate) fn negotiate(peer_version: u32) -> Result<u32, Status> {
+    let version = normalize(peer_version).min(DATA_STREAM_VERSION);
+    if version < MIN_DATA_STREAM_VERSION {
+        return Err(unsupported(peer_version));
+    }
+    Ok(version)
+}
+
+/// accept checks the version chosen by the peer is spoken by this build.
+pub(crate) fn accept(version: u32) -> Result<u32, Status> {
+    let normalized = normalize(version);
+    if !(MIN_DATA_STREAM_VERSION..=DATA_STREAM_VERSION).contains(&normalized) {
+        return Err(unsupported(version));
+    }
+    Ok(normalized)
+}
+
+/// negotiate_chunk_size picks the smaller chunk size of both sides, None means no preference,
+/// the one of the peer is at least [`MIN_CHUNK_SIZE`].
+pub(crate) fn negotiate_chunk_size(ours: Option<usize>, peer: Option<usize>) -> Option<usize> {
+    let peer = peer.map(|size| size.max(MIN_CHUNK_SIZE));
+    match (ours, peer) {
+        (Some(ours), Some(peer)) => Some(ours.min(peer)),
+        (ours, peer) => ours.or(peer),
+    }
+}
+
+fn unsupported(version: u32) -> Status {
+    Status::failed_precondition(format!(
+        "unsupported data stream version {}, supported versions: {}..={}",
+        version, MIN_DATA_STREAM_VERSION, DATA_STREAM_VERSION,
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(negotiate(0).unwrap(), 1);
+        assert_eq!(negotiate(1).unwrap(), 1);
+        assert_eq!(negotiate(2).unwrap(), 2);
+        // a newer peer falls back to our version
+        assert_eq!(
+            negotiate(DATA_STREAM_VERSION + 1).unwrap(),
+            DATA_STREAM_VERSION
+        );
+    }
+
+    #[test]
+    fn test_accept() {
+        assert_eq!(accept(0).unwrap(), 1);
+        assert_eq!(accept(DATA_STREAM_VERSION).unwrap(), DATA_STREAM_VERSION);
+        let status = accept(DATA_STREAM_VERSION + 1).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+        assert!(!flow_controlled(accept(0).unwrap()));
+        assert!(flow_controlled(2));
+    }
+
+    #[test]
+    fn test_negotiate_chunk_size() {
+        assert_eq!(negotiate_chunk_size(None, None), None);
+        assert_eq!(negotiate_chunk_size(Some(4096), None), Some(4096));
+        assert_eq!(negotiate_chunk_size(None, Some(1400)), Some(1400));
+        assert_eq!(negotiate_chunk_size(Some(4096), Some(1400)), Some(1400));
+        assert_eq!(negotiate_chunk_size(Some(1024), Some(1400)), Some(1024));
+        // the peer can't ask for the tiny chunks
+        assert_eq!(negotiate_chunk_size(None, Some(1)), Some(MIN_CHUNK_SIZE));
+        assert_eq!(negotiate_chunk_size(Some(64), Some(1)), Some(64));
+    }
+}
@@ -0,0 +1,461 @@
+//! The admin api served on the control port.
+//!
+//! Like the landing page, it answers the plain http requests,
+//! the grpc requests are passed through untouched.
+//! The requests must have the auth token of the server as `Authorization: Bearer <token>`
+//! if it's set, the others are answered with `401 Unauthorized`.
+//!
+//! - `GET /admin/tunnels` lists the tunnels, repeat `label=key=value` to filter them,
+//!   e.g. `/admin/tunnels?label=team=infra&label=env=prod`.
+//! - `GET /admin/stats` reports the usage of the server, e.g. the remaining ports
+//!   and the number of the user connections of each tunnel, the usage of the memory budget
+//!   and the rate limit of the control server, the registrations pending and shed.
+//! - `POST /admin/tunnels/{id}/probe?target=127.0.0.1:5432` asks the client of the tunnel
+//!   whether the local target is reachable, `protocol` is `tcp`(default) or `udp`,
+//!   `timeout_ms` defaults to 3000.
+//! - `DELETE /admin/tunnels/{id}?reason=evicted&message=...` closes the tunnel, `reason` is
+//!   `evicted`(default) or `revoked`, the client doesn't register the tunnel again either way,
+//!   `revoked` tells it the tunnel isn't allowed anymore.
+//! - `GET /admin/openapi.json` describes the endpoints above, see [`super::openapi`].
+use std::{
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::future::{BoxFuture, Either, FutureExt as _};
+use tonic::{
+    body::BoxBody,
+    codegen::{
+        http::{header, HeaderValue, Method, Request, Response, StatusCode},
+        Body as _,
+    },
+    transport::Body,
+    Status,
+};
+use tower::{Layer, Service};
+
+use schemars::JsonSchema;
+use serde::Serialize;
+
+use super::{
+    admission::{RegistrationQueue, RegistrationStats},
+    connection::ConnectionRegistry,
+    landing_page::is_grpc,
+    openapi,
+    port::{PortManager, PortStats},
+    probe::Probes,
+    rate_limit::{RateLimitStats, RateLimiter},
+    registry::TunnelRegistry,
+    tunnel::udp::{UdpSessions, UdpStats},
+};
+use crate::{
+    auth::RequireToken,
+    bridge::MemoryBudget,
+    pb::{close_payload::Reason, probe_payload::Protocol, ClosePayload},
+};
+
+const TUNNELS_PATH: &str = "/admin/tunnels";
+const STATS_PATH: &str = "/admin/stats";
+const OPENAPI_PATH: &str = "/admin/openapi.json";
+const PROBE_SUFFIX: &str = "/probe";
+const DEFAULT_PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// AdminLayer serves the admin api, it passes all the requests through if it's disabled.
+#[derive(Debug, Clone)]
+pub(crate) struct AdminLayer {
+    state: Option<State>,
+}
+
+impl AdminLayer {
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new(
+        tunnels: TunnelRegistry,
+        ports: PortManager,
+        probes: Probes,
+        connections: ConnectionRegistry,
+        memory_budget: MemoryBudget,
+        udp_sessions: UdpSessions,
+        rate_limiter: RateLimiter,
+        registrations: RegistrationQueue,
+        auth: RequireToken,
+    ) -> Self {
+        Self {
+            state: Some(State {
+                auth,
+                tunnels,
+                ports,
+                probes,
+                connections,
+                memory_budget,
+                udp_sessions,
+                rate_limiter,
+                registrations,
+            }),
+        }
+    }
+
+    pub(crate) fn disabled() -> Self {
+        Self { state: None }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct State {
+    tunnels: TunnelRegistry,
+    ports: PortManager,
+    probes: Probes,
+    connections: ConnectionRegistry,
+    memory_budget: MemoryBudget,
+    udp_sessions: UdpSessions,
+    rate_limiter: RateLimiter,
+    registrations: RegistrationQueue,
+    auth: RequireToken,
+}
+
+/// the usage of the server.
+#[derive(Serialize, JsonSchema)]
+pub(super) struct Stats {
+    ports: PortStats,
+    /// the user connections being served.
+    connections: usize,
+    /// the registered tunnels sorted by the name, with their user connections.
+    tunnels: Vec<TunnelStats>,
+    memory: MemoryStats,
+    udp: UdpStats,
+    rate_limit: RateLimitStats,
+    registrations: RegistrationStats,
+}
+
+#[derive(Serialize, JsonSchema)]
+struct MemoryStats {
+    /// null means unlimited.
+    budget: Option<usize>,
+    /// the bytes in flight of all the user connections.
+    used: usize,
+}
+
+#[derive(Serialize, JsonSchema)]
+struct TunnelStats {
+    id: String,
+    name: String,
+    /// the domains or the addresses of the tunnel, e.g. to slice the stats by the subdomain.
+    entrypoint: Vec<String>,
+    connections: usize,
+}
+
+impl<S> Layer<S> for AdminLayer {
+    type Service = Admin<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Admin {
+            inner,
+            state: self.state.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Admin<S> {
+    inner: S,
+    state: Option<State>,
+}
+
+impl<S> Service<Request<Body>> for Admin<S>
+where
+    S: Service<Request<Body>, Response = Response<BoxBody>>,
+    S::Error: Send + 'static,
+{
+    type Response = Response<BoxBody>;
+    type Error = S::Error;
+    type Future = Either<S::Future, BoxFuture<'static, Result<Self::Response, Self::Error>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        match &self.state {
+            Some(state) if !is_grpc(&req) && req.uri().path().starts_with("/admin/") => {
+                let state = state.clone();
+                Either::Right(async move { Ok(handle(&state, &req).await) }.boxed())
+            }
+            _ => Either::Left(self.inner.call(req)),
+        }
+    }
+}
+
+async fn handle(state: &State, req: &Request<Body>) -> Response<BoxBody> {
+    let authorization = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok());
+    if let Err(status) = state.auth.verify(authorization) {
+        let mut response = text(StatusCode::UNAUTHORIZED, status.message());
+        response
+            .headers_mut()
+            .insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+        return response;
+    }
+    let path = req.uri().path();
+    if let Some(tunnel_id) = probe_tunnel_id(path) {
+        if req.method() != Method::POST {
+            return text(StatusCode::METHOD_NOT_ALLOWED, "method not allowed");
+        }
+        return probe(state, tunnel_id, req.uri().query().unwrap_or_default()).await;
+    }
+    if let Some(tunnel_id) = tunnel_id(path) {
+        if req.method() != Method::DELETE {
+            return text(StatusCode::METHOD_NOT_ALLOWED, "method not allowed");
+        }
+        return close(state, tunnel_id, req.uri().query().unwrap_or_default());
+    }
+    if path != TUNNELS_PATH && path != STATS_PATH && path != OPENAPI_PATH {
+        return text(StatusCode::NOT_FOUND, "not found");
+    }
+    if req.method() != Method::GET {
+        return text(StatusCode::METHOD_NOT_ALLOWED, "method not allowed");
+    }
+
+    let body = if path == OPENAPI_PATH {
+        serde_json::to_string(&openapi::document())
+    } else if path == STATS_PATH {
+        let counts = state.connections.count_by_tunnel();
+        serde_json::to_string(&Stats {
+            ports: state.ports.stats(),
+            connections: state.connections.len(),
+            tunnels: state
+                .tunnels
+                .list(&[])
+                .into_iter()
+                .map(|tunnel| TunnelStats {
+                    connections: counts.get(tunnel.id.as_str()).copied().unwrap_or_default(),
+                    id: tunnel.id,
+                    name: tunnel.name,
+                    entrypoint: tunnel.entrypoint,
+                })
+                .collect(),
+            memory: MemoryStats {
+                budget: state.memory_budget.max(),
+                used: state.memory_budget.used(),
+            },
+            udp: state.udp_sessions.stats(),
+            rate_limit: state.rate_limiter.stats(),
+            registrations: state.registrations.stats(),
+        })
+    } else {
+        let labels = match parse_label_filter(req.uri().query().unwrap_or_default()) {
+            Ok(labels) => labels,
+            Err(err) => return text(StatusCode::BAD_REQUEST, &err),
+        };
+        serde_json::to_string(&state.tunnels.list(&labels))
+    };
+    response(StatusCode::OK, "application/json", body.unwrap())
+}
+
+/// the tunnel id of `/admin/tunnels/{id}/probe`.
+fn probe_tunnel_id(path: &str) -> Option<&str> {
+    path.strip_prefix(TUNNELS_PATH)?
+        .strip_prefix('/')?
+        .strip_suffix(PROBE_SUFFIX)
+        .filter(|id| !id.is_empty() && !id.contains('/'))
+}
+
+/// the tunnel id of `/admin/tunnels/{id}`.
+fn tunnel_id(path: &str) -> Option<&str> {
+    path.strip_prefix(TUNNELS_PATH)?
+        .strip_prefix('/')
+        .filter(|id| !id.is_empty() && !id.contains('/'))
+}
+
+fn close(state: &State, tunnel_id: &str, query: &str) -> Response<BoxBody> {
+    let close = match parse_close_query(query) {
+        Ok(close) => close,
+        Err(err) => return text(StatusCode::BAD_REQUEST, &err),
+    };
+    if !state.tunnels.close(tunnel_id, close) {
+        return text(StatusCode::NOT_FOUND, "tunnel not found");
+    }
+    text(StatusCode::OK, "closed")
+}
+
+/// parse the `reason` and `message` of the close query.
+fn parse_close_query(query: &str) -> Result<ClosePayload, String> {
+    let mut reason = Reason::Evicted;
+    let mut message = String::new();
+    for (name, value) in form_urlencoded::parse(query.as_bytes()) {
+        match name.as_ref() {
+            "reason" => {
+                reason = match value.as_ref() {
+                    "evicted" => Reason::Evicted,
+                    "revoked" => Reason::Revoked,
+                    _ => {
+                        return Err(format!(
+                            "invalid reason {:?}, expect evicted or revoked",
+                            value
+                        ))
+                    }
+                }
+            }
+            "message" => message = value.to_string(),
+            _ => {}
+        }
+    }
+    Ok(ClosePayload {
+        reason: reason as i32,
+        message,
+    })
+}
+
+async fn probe(state: &State, tunnel_id: &str, query: &str) -> Response<BoxBody> {
+    let (protocol, target, timeout) = match parse_probe_query(query) {
+        Ok(probe) => probe,
+        Err(err) => return text(StatusCode::BAD_REQUEST, &err),
+    };
+    match state
+        .probes
+        .probe(tunnel_id, protocol, target, timeout)
+        .await
+    {
+        Ok(result) => response(
+            StatusCode::OK,
+            "application/json",
+            serde_json::to_string(&result).unwrap(),
+        ),
+        Err(status) => text(
+            match status.code() {
+                tonic::Code::NotFound => StatusCode::NOT_FOUND,
+                tonic::Code::DeadlineExceeded => StatusCode::GATEWAY_TIMEOUT,
+                _ => StatusCode::BAD_GATEWAY,
+            },
+            status.message(),
+        ),
+    }
+}
+
+/// parse the `protocol`, `target` and `timeout_ms` of the probe query.
+fn parse_probe_query(query: &str) -> Result<(Protocol, String, Duration), String> {
+    let mut protocol = Protocol::Tcp;
+    let mut target = None;
+    let mut timeout = DEFAULT_PROBE_TIMEOUT;
+    for (name, value) in form_urlencoded::parse(query.as_bytes()) {
+        match name.as_ref() {
+            "protocol" => {
+                protocol = match value.as_ref() {
+                    "tcp" => Protocol::Tcp,
+                    "udp" => Protocol::Udp,
+                    _ => return Err(format!("invalid protocol {:?}, expect tcp or udp", value)),
+                }
+            }
+            "target" => target = Some(value.to_string()),
+            "timeout_ms" => match value.parse::<u64>() {
+                Ok(ms) if ms > 0 => timeout = Duration::from_millis(ms),
+                _ => return Err(format!("invalid timeout_ms {:?}", value)),
+            },
+            _ => {}
+        }
+    }
+    let target = target.ok_or("missing target")?;
+    Ok((protocol, target, timeout))
+}
+
+/// parse the `label=key=value` pairs of the query.
+fn parse_label_filter(query: &str) -> Result<Vec<(String, String)>, String> {
+    form_urlencoded::parse(query.as_bytes())
+        .filter(|(name, _)| name == "label")
+        .map(|(_, label)| match label.split_once('=') {
+            Some((key, value)) => Ok((key.to_string(), value.to_string())),
+            None => Err(format!(
+                "invalid label filter {:?}, expect key=value",
+                label
+            )),
+        })
+        .collect()
+}
+
+fn text(status: StatusCode, body: &str) -> Response<BoxBody> {
+    response(status, "text/plain; charset=utf-8", body.to_string())
+}
+
+fn response(status: StatusCode, content_type: &'static str, body: String) -> Response<BoxBody> {
+    let mut response = Response::new(
+        Body::from(body)
+            .map_err(|err| Status::internal(err.to_string()))
+            .boxed_unsync(),
+    );
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+    response
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_label_filter() {
+        assert_eq!(parse_label_filter("").unwrap(), vec![]);
+        assert_eq!(
+            parse_label_filter("label=team=infra&label=app.io%2Fenv=prod&other=1").unwrap(),
+            vec![
+                ("team".to_string(), "infra".to_string()),
+                ("app.io/env".to_string(), "prod".to_string()),
+            ]
+        );
+        assert!(parse_label_filter("label=team").is_err());
+    }
+
+    #[test]
+    fn test_parse_probe() {
+        assert_eq!(probe_tunnel_id("/admin/tunnels/abc/probe"), Some("abc"));
+        assert_eq!(probe_tunnel_id("/admin/tunnels//probe"), None);
+        assert_eq!(probe_tunnel_id("/admin/tunnels/a/b/probe"), None);
+        assert_eq!(probe_tunnel_id("/admin/tunnels"), None);
+
+        assert_eq!(
+            parse_probe_query("target=127.0.0.1%3A5432").unwrap(),
+            (
+                Protocol::Tcp,
+                "127.0.0.1:5432".to_string(),
+                DEFAULT_PROBE_TIMEOUT
+            )
+        );
+        assert_eq!(
+            parse_probe_query("target=127.0.0.1:53&protocol=udp&timeout_ms=100").unwrap(),
+            (
+                Protocol::Udp,
+                "127.0.0.1:53".to_string(),
+                Duration::from_millis(100)
+            )
+        );
+        assert!(parse_probe_query("").is_err());
+        assert!(parse_probe_query("target=127.0.0.1:53&protocol=icmp").is_err());
+        assert!(parse_probe_query("target=127.0.0.1:53&timeout_ms=0").is_err());
+    }
+
+    #[test]
+    fn test_parse_close() {
+        assert_eq!(tunnel_id("/admin/tunnels/abc"), Some("abc"));
+        assert_eq!(tunnel_id("/admin/tunnels/"), None);
+        assert_eq!(tunnel_id("/admin/tunnels/abc/probe"), None);
+        assert_eq!(tunnel_id("/admin/tunnels"), None);
+        assert_eq!(tunnel_id("/admin/tunnelsabc"), None);
+
+        assert_eq!(
+            parse_close_query("").unwrap(),
+            ClosePayload {
+                reason: Reason::Evicted as i32,
+                message: String::new(),
+            }
+        );
+        assert_eq!(
+            parse_close_query("reason=revoked&message=the%20key%20is%20revoked").unwrap(),
+            ClosePayload {
+                reason: Reason::Revoked as i32,
+                message: "the key is revoked".to_string(),
+            }
+        );
+        assert!(parse_close_query("reason=shutdown").is_err());
+    }
+}
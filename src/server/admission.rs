@@ -0,0 +1,153 @@
+//! The load shedding of the registrations, so an overloaded server answers the clients
+//! right away instead of queueing them up.
+//!
+//! A registration is pending from the request until the tunnel is opened or rejected,
+//! e.g. it waits for the data server to open the entrypoint and for the open hooks.
+//! The registrations beyond `max_pending_registrations` are rejected with `UNAVAILABLE`,
+//! the clients retry them after their backoff, maybe on another server.
+//!
+//! The registrations are queued for the data server as the events of a channel of the same capacity,
+//! so the data server is never sent more than `max_pending_registrations` of them.
+//! It opens the entrypoints one by one, the domains and the ports are checked then taken
+//! without racing each other, the opening takes little time compared to the open hooks.
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use schemars::JsonSchema;
+use serde::Serialize;
+use tokio::sync::{mpsc, OwnedSemaphorePermit, Semaphore};
+use tonic::Status;
+use tracing::warn;
+
+use crate::event::ClientEvent;
+
+#[derive(Debug, Clone)]
+pub(crate) struct RegistrationQueue {
+    max: usize,
+    permits: Arc<Semaphore>,
+    shed: Arc<AtomicU64>,
+    /// the channel of the events to the data server, it's weak so the channel still closes.
+    events: Option<mpsc::WeakSender<ClientEvent>>,
+}
+
+/// the usage of the registration queue, it's reported by `GET /admin/stats`.
+#[derive(Debug, Serialize, PartialEq, JsonSchema)]
+pub(crate) struct RegistrationStats {
+    max_pending: usize,
+    pending: usize,
+    /// the registrations rejected because the queue is full.
+    shed: u64,
+    /// the pending registrations queued for the data server to open their entrypoints.
+    queued: usize,
+}
+
+impl RegistrationQueue {
+    /// the max is at least 1.
+    pub(crate) fn new(max: usize) -> Self {
+        let max = max.max(1);
+        Self {
+            max,
+            permits: Arc::new(Semaphore::new(max)),
+            shed: Arc::new(AtomicU64::new(0)),
+            events: None,
+        }
+    }
+
+    /// report the backlog of the events of the registrations sent to the data server.
+    pub(crate) fn with_events(mut self, events: &mpsc::Sender<ClientEvent>) -> Self {
+        self.events = Some(events.downgrade());
+        self
+    }
+
+    /// take a place in the queue, it's held until the registration is done.
+    pub(crate) fn enter(&self) -> Result<OwnedSemaphorePermit, Status> {
+        Arc::clone(&self.permits).try_acquire_owned().map_err(|_| {
+            self.shed.fetch_add(1, Ordering::Relaxed);
+            warn!(
+                max = self.max,
+                "too many pending registrations, shed the registration"
+            );
+            Status::unavailable(format!(
+                "the server is overloaded with {} pending registrations, retry later",
+                self.max
+            ))
+        })
+    }
+
+    pub(crate) fn stats(&self) -> RegistrationStats {
+        RegistrationStats {
+            max_pending: self.max,
+            pending: self.max - self.permits.available_permits(),
+            shed: self.shed.load(Ordering::Relaxed),
+            queued: self
+                .events
+                .as_ref()
+                .and_then(mpsc::WeakSender::upgrade)
+                .map_or(0, |events| events.max_capacity() - events.capacity()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::sync::oneshot;
+    use tokio_util::sync::CancellationToken;
+
+    use super::*;
+    use crate::event::Payload;
+
+    #[test]
+    fn test_registration_queue() {
+        let queue = RegistrationQueue::new(2);
+        let first = queue.enter().unwrap();
+        let _second = queue.enter().unwrap();
+        assert_eq!(queue.enter().unwrap_err().code(), tonic::Code::Unavailable);
+        assert_eq!(
+            queue.stats(),
+            RegistrationStats {
+                max_pending: 2,
+                pending: 2,
+                shed: 1,
+                queued: 0,
+            }
+        );
+
+        // the place is freed when the registration is done
+        drop(first);
+        assert!(queue.enter().is_ok());
+        assert_eq!(RegistrationQueue::new(0).stats().max_pending, 1);
+    }
+
+    #[tokio::test]
+    async fn test_queued_events() {
+        let (event_tx, mut event_rx) = mpsc::channel(2);
+        let queue = RegistrationQueue::new(2).with_events(&event_tx);
+        let (released, _) = mpsc::channel(1);
+        let (incoming_events, _) = mpsc::channel(1);
+        event_tx
+            .send(ClientEvent {
+                tunnel_name: "test".to_string(),
+                payload: Payload::RegisterUdp {
+                    port: 0,
+                    compressed: false,
+                    max_datagram_size: None,
+                },
+                loopback: false,
+                resp: oneshot::channel().0,
+                close_listener: CancellationToken::new(),
+                released,
+                incoming_events,
+            })
+            .await
+            .unwrap();
+        assert_eq!(queue.stats().queued, 1);
+        event_rx.recv().await.unwrap();
+        assert_eq!(queue.stats().queued, 0);
+
+        // the queue doesn't keep the channel open
+        drop(event_tx);
+        assert!(event_rx.recv().await.is_none());
+    }
+}
@@ -0,0 +1,331 @@
+//! The weighted sharing of the bandwidth of the data streams between the tunnels,
+//! so the tunnels of a higher weight, e.g. a paid tier, get more of a saturated link.
+//!
+//! The server paces the data streams of each direction at `bandwidth_limit`, it should be
+//! a bit below the capacity of the link, so the traffic queues up in the server
+//! where it's scheduled instead of the network.
+//! The fairness model is the weighted fair sharing between the tunnels with traffic queued:
+//! - a tunnel with traffic queued gets `weight / sum of the weights of the queued tunnels`
+//!   of the limit, e.g. a tunnel of weight 3 gets 75% next to a busy tunnel of weight 1.
+//! - the bandwidth unused by the idle tunnels is shared by the busy ones, a tunnel alone
+//!   gets the whole limit whatever its weight is.
+//! - the connections of a tunnel share its bandwidth, opening more connections doesn't
+//!   take more from the other tunnels.
+//!
+//! The weight of a tunnel is taken from its labels by the [`TunnelWeight`] rules of the server,
+//! the labels are set by the clients, see [`super::Server::on_tunnel_open`] to reject
+//! the tunnels claiming a tier they aren't entitled to.
+//!
+//! Besides the sharing, each tunnel can be capped at `tunnel_rate_limit` by a [`TunnelRate`]
+//! of each direction, e.g. so a single tunnel can't take the whole link while the others are idle.
+//! The messages over the cap wait for the tokens instead of being dropped, so the data streams
+//! push back on the senders.
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::time::Instant;
+
+/// the weight of the tunnels matching no rules.
+pub(crate) const DEFAULT_WEIGHT: u32 = 1;
+/// the idle tunnels are pruned when there are more tunnels.
+const PRUNE_FLOWS: usize = 1024;
+
+/// TunnelWeight gives the weight to the tunnels with the label, it's parsed from `key=value:weight`,
+/// e.g. `tier=paid:4`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TunnelWeight {
+    pub key: String,
+    pub value: String,
+    /// at least 1.
+    pub weight: u32,
+}
+
+impl FromStr for TunnelWeight {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (label, weight) = s
+            .rsplit_once(':')
+            .ok_or_else(|| anyhow::anyhow!("the tunnel weight must be key=value:weight"))?;
+        let (key, value) = label
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("the tunnel weight must be key=value:weight"))?;
+        let weight: u32 = weight
+            .parse()
+            .map_err(|_| anyhow::anyhow!("invalid weight {:?}", weight))?;
+        anyhow::ensure!(weight > 0, "the weight must be at least 1");
+        Ok(Self {
+            key: key.to_string(),
+            value: value.to_string(),
+            weight,
+        })
+    }
+}
+
+/// the weight of the first rule matching the labels.
+pub(crate) fn weight_of<'a>(
+    rules: &[TunnelWeight],
+    mut labels: impl FnMut(&str) -> Option<&'a String>,
+) -> u32 {
+    rules
+        .iter()
+        .find(|rule| labels(&rule.key).is_some_and(|value| *value == rule.value))
+        .map_or(DEFAULT_WEIGHT, |rule| rule.weight)
+}
+
+/// Bandwidth paces the messages of a direction of the data streams.
+#[derive(Debug, Clone)]
+pub(crate) struct Bandwidth {
+    inner: Option<Arc<Inner>>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    /// bytes per second.
+    rate: f64,
+    flows: Mutex<HashMap<Arc<str>, Flow>>,
+}
+
+/// the traffic of a tunnel.
+#[derive(Debug)]
+struct Flow {
+    weight: u32,
+    /// when the messages reserved so far are sent at the share of the tunnel,
+    /// the tunnel has traffic queued until then.
+    busy_until: Instant,
+}
+
+impl Bandwidth {
+    /// bytes per second, None means unlimited.
+    pub(crate) fn new(rate: Option<u64>) -> Self {
+        Self {
+            inner: rate.map(|rate| {
+                Arc::new(Inner {
+                    rate: rate.max(1) as f64,
+                    flows: Mutex::new(HashMap::new()),
+                })
+            }),
+        }
+    }
+
+    /// wait for the turn of the tunnel to send the bytes.
+    pub(crate) async fn acquire(&self, tunnel_id: &Arc<str>, weight: u32, bytes: usize) {
+        let Some(inner) = &self.inner else {
+            return;
+        };
+        if let Some(start) = inner.reserve(tunnel_id, weight, bytes, Instant::now()) {
+            tokio::time::sleep_until(start).await;
+        }
+    }
+}
+
+/// TunnelRate is the token bucket of a direction of a tunnel, the connections of the tunnel share it.
+/// The bucket holds a second of the rate, so a tunnel idle for a while sends a burst of it at once.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct TunnelRate {
+    bucket: Option<Arc<Mutex<Bucket>>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// bytes per second.
+    rate: f64,
+    /// it's negative while the bytes taken are waiting for the tokens.
+    tokens: f64,
+    updated: Instant,
+}
+
+/// TunnelRates are the rates of both directions of a tunnel.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct TunnelRates {
+    pub to_client: TunnelRate,
+    pub to_user: TunnelRate,
+}
+
+impl TunnelRates {
+    /// bytes per second of each direction, None means unlimited.
+    pub(crate) fn new(rate: Option<u64>) -> Self {
+        Self {
+            to_client: TunnelRate::new(rate),
+            to_user: TunnelRate::new(rate),
+        }
+    }
+}
+
+/// the rate limit of a tunnel, the smaller one of the limits of the server and the tunnel,
+/// 0 of the tunnel means none.
+pub(crate) fn tunnel_rate_limit(server: Option<u64>, tunnel: u64) -> Option<u64> {
+    match (server, tunnel) {
+        (server, 0) => server,
+        (Some(server), tunnel) => Some(server.min(tunnel)),
+        (None, tunnel) => Some(tunnel),
+    }
+}
+
+impl TunnelRate {
+    /// bytes per second, None means unlimited.
+    pub(crate) fn new(rate: Option<u64>) -> Self {
+        Self {
+            bucket: rate.map(|rate| {
+                let rate = rate.max(1) as f64;
+                Arc::new(Mutex::new(Bucket {
+                    rate,
+                    tokens: rate,
+                    updated: Instant::now(),
+                }))
+            }),
+        }
+    }
+
+    /// wait for the tokens of the bytes.
+    pub(crate) async fn acquire(&self, bytes: usize) {
+        let Some(bucket) = &self.bucket else {
+            return;
+        };
+        let ready = bucket.lock().unwrap().take(bytes, Instant::now());
+        if let Some(ready) = ready {
+            tokio::time::sleep_until(ready).await;
+        }
+    }
+}
+
+impl Bucket {
+    /// take the tokens of the bytes, None if they can be sent now,
+    /// or when the tokens taken so far are refilled.
+    fn take(&mut self, bytes: usize, now: Instant) -> Option<Instant> {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.updated = self.updated.max(now);
+        self.tokens -= bytes as f64;
+        (self.tokens < 0.0).then(|| now + Duration::from_secs_f64(-self.tokens / self.rate))
+    }
+}
+
+impl Inner {
+    /// reserve the bytes at the share of the tunnel, None if they can be sent now,
+    /// or when they can be sent after the bytes reserved before.
+    fn reserve(
+        &self,
+        tunnel_id: &Arc<str>,
+        weight: u32,
+        bytes: usize,
+        now: Instant,
+    ) -> Option<Instant> {
+        let weight = weight.max(1);
+        let mut flows = self.flows.lock().unwrap();
+        if flows.len() >= PRUNE_FLOWS && !flows.contains_key(tunnel_id) {
+            flows.retain(|_, flow| flow.busy_until > now);
+        }
+        let queued: u64 = flows
+            .iter()
+            .filter(|(id, flow)| *id != tunnel_id && flow.busy_until > now)
+            .map(|(_, flow)| u64::from(flow.weight))
+            .sum();
+        let share = self.rate * f64::from(weight) / (u64::from(weight) + queued) as f64;
+
+        let flow = flows.entry(Arc::clone(tunnel_id)).or_insert(Flow {
+            weight,
+            busy_until: now,
+        });
+        flow.weight = weight;
+        let start = flow.busy_until.max(now);
+        flow.busy_until = start + Duration::from_secs_f64(bytes as f64 / share);
+        (start > now).then_some(start)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_tunnel_weight() {
+        let rule: TunnelWeight = "tier=paid:4".parse().unwrap();
+        assert_eq!(
+            rule,
+            TunnelWeight {
+                key: "tier".to_string(),
+                value: "paid".to_string(),
+                weight: 4,
+            }
+        );
+        assert!("tier=paid".parse::<TunnelWeight>().is_err());
+        assert!("tier:4".parse::<TunnelWeight>().is_err());
+        assert!("tier=paid:0".parse::<TunnelWeight>().is_err());
+
+        let labels = HashMap::from([("tier".to_string(), "paid".to_string())]);
+        let rules = [rule];
+        assert_eq!(weight_of(&rules, |key| labels.get(key)), 4);
+        assert_eq!(weight_of(&rules, |_| None), DEFAULT_WEIGHT);
+    }
+
+    #[tokio::test]
+    async fn test_bandwidth_share() {
+        let inner = Inner {
+            rate: 1000.0,
+            flows: Mutex::new(HashMap::new()),
+        };
+        let paid: Arc<str> = Arc::from("paid");
+        let free: Arc<str> = Arc::from("free");
+        let now = Instant::now();
+
+        // alone, a tunnel gets the whole rate
+        assert_eq!(inner.reserve(&free, 1, 100, now), None);
+        assert_eq!(
+            inner.reserve(&free, 1, 100, now),
+            Some(now + Duration::from_millis(100))
+        );
+        // next to the queued tunnel of weight 1, it gets 3/4 of the rate
+        assert_eq!(inner.reserve(&paid, 3, 750, now), None);
+        assert_eq!(
+            inner.reserve(&paid, 3, 750, now),
+            Some(now + Duration::from_secs(1))
+        );
+        // and the other one gets 1/4
+        let later = now + Duration::from_millis(200);
+        assert_eq!(inner.reserve(&free, 1, 250, later), None);
+        assert_eq!(
+            inner.reserve(&free, 1, 250, later),
+            Some(later + Duration::from_secs(1))
+        );
+
+        assert!(Bandwidth::new(None).inner.is_none());
+    }
+
+    #[test]
+    fn test_tunnel_rate() {
+        let now = Instant::now();
+        let mut bucket = Bucket {
+            rate: 1000.0,
+            tokens: 1000.0,
+            updated: now,
+        };
+        // a second of the rate at once
+        assert_eq!(bucket.take(600, now), None);
+        assert_eq!(bucket.take(400, now), None);
+        // the rest wait for the tokens
+        assert_eq!(
+            bucket.take(500, now),
+            Some(now + Duration::from_millis(500))
+        );
+        assert_eq!(bucket.take(500, now), Some(now + Duration::from_secs(1)));
+        // the tokens are refilled over time, up to a second of the rate
+        let later = now + Duration::from_secs(10);
+        assert_eq!(bucket.take(1000, later), None);
+        assert_eq!(
+            bucket.take(100, later),
+            Some(later + Duration::from_millis(100))
+        );
+
+        // the smaller limit wins, 0 of the tunnel means the limit of the server
+        assert_eq!(tunnel_rate_limit(Some(1000), 0), Some(1000));
+        assert_eq!(tunnel_rate_limit(Some(1000), 500), Some(500));
+        assert_eq!(tunnel_rate_limit(Some(1000), 5000), Some(1000));
+        assert_eq!(tunnel_rate_limit(None, 500), Some(500));
+        assert_eq!(tunnel_rate_limit(None, 0), None);
+    }
+}
@@ -0,0 +1,166 @@
+//! The registry of the user connections served by the data streams of the clients.
+//!
+//! It's looked up by every message of the data streams, and updated by every
+//! `UserIncoming::Add/Remove` of all the tunnels, so it's sharded by the hash of
+//! the connection id to keep the tunnels from contending on a single lock,
+//! see `benches/registry.rs`.
+use std::{collections::HashMap, sync::Arc};
+
+use bytes::Bytes;
+use dashmap::DashMap;
+use tokio_util::sync::CancellationToken;
+
+use super::bandwidth::TunnelRates;
+use crate::{bridge::DataSenderBridge, crypto::PayloadKey};
+
+/// Connection is the server side of a user connection.
+#[derive(Clone)]
+pub(crate) struct Connection {
+    /// the id of the tunnel serving the connection.
+    pub tunnel_id: Arc<str>,
+    pub bridge: DataSenderBridge,
+    /// cancelled when the user connection is removed,
+    /// it stops forwarding the traffic to the data stream of the client.
+    pub closed: CancellationToken,
+    /// the key of the payloads if the tunnel encrypts them.
+    pub payload_key: Option<PayloadKey>,
+    /// the share of the bandwidth of the tunnel, see `super::bandwidth`.
+    pub weight: u32,
+    /// the rate limits of the tunnel, shared by its connections.
+    pub rates: TunnelRates,
+    /// the max bytes of a message sent to the client, e.g. negotiated by the tcp tunnel,
+    /// None never splits the data, e.g. the datagrams of the udp tunnels.
+    pub chunk_size: Option<usize>,
+}
+
+#[derive(Clone)]
+pub(crate) struct ConnectionRegistry {
+    connections: Arc<DashMap<Bytes, Connection>>,
+}
+
+impl ConnectionRegistry {
+    pub(crate) fn new() -> Self {
+        Self::with_shard_amount(default_shard_amount())
+    }
+
+    /// the shard amount must be a power of two and larger than 1.
+    pub(crate) fn with_shard_amount(shard_amount: usize) -> Self {
+        Self {
+            connections: Arc::new(DashMap::with_shard_amount(shard_amount)),
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn add(
+        &self,
+        id: Bytes,
+        tunnel_id: Arc<str>,
+        bridge: DataSenderBridge,
+        payload_key: Option<PayloadKey>,
+        weight: u32,
+        rates: TunnelRates,
+        chunk_size: Option<usize>,
+    ) {
+        self.connections.insert(
+            id,
+            Connection {
+                tunnel_id,
+                bridge,
+                closed: CancellationToken::new(),
+                payload_key,
+                weight,
+                rates,
+                chunk_size,
+            },
+        );
+    }
+
+    /// the connection is cloned out of the shard, so the shard isn't locked
+    /// while the caller is waiting on the connection.
+    pub(crate) fn get(&self, id: &Bytes) -> Option<Connection> {
+        self.connections
+            .get(id)
+            .map(|connection| connection.value().clone())
+    }
+
+    /// remove the connection and cancel its `closed` token.
+    pub(crate) fn remove(&self, id: &Bytes) -> Option<Connection> {
+        let (_, connection) = self.connections.remove(id)?;
+        connection.closed.cancel();
+        Some(connection)
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.connections.len()
+    }
+
+    /// the number of the connections of each tunnel, it walks all the shards.
+    pub(crate) fn count_by_tunnel(&self) -> HashMap<Arc<str>, usize> {
+        let mut counts = HashMap::new();
+        for connection in self.connections.iter() {
+            *counts.entry(connection.tunnel_id.clone()).or_default() += 1;
+        }
+        counts
+    }
+}
+
+impl std::fmt::Debug for ConnectionRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ConnectionRegistry")
+            .field("len", &self.len())
+            .finish()
+    }
+}
+
+/// 4 shards per core like dashmap, but at least 4 for the small machines.
+fn default_shard_amount() -> usize {
+    let cores = std::thread::available_parallelism().map_or(1, usize::from);
+    (cores * 4).next_power_of_two().max(4)
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::bridge::{InFlight, MemoryBudget};
+
+    #[test]
+    fn test_registry() {
+        let registry = ConnectionRegistry::with_shard_amount(4);
+        let (tx, _rx) = mpsc::channel(1);
+        let bridge = DataSenderBridge::new(
+            tx,
+            CancellationToken::new(),
+            InFlight::new(1024, MemoryBudget::new(None)),
+        );
+        let ids: Vec<Bytes> = (0..64)
+            .map(|i| Bytes::from(format!("connection-{}", i)))
+            .collect();
+        let tunnels: [Arc<str>; 2] = [Arc::from("a"), Arc::from("b")];
+        for (i, id) in ids.iter().enumerate() {
+            registry.add(
+                id.clone(),
+                tunnels[i % 2].clone(),
+                bridge.clone(),
+                None,
+                1,
+                TunnelRates::default(),
+                None,
+            );
+        }
+        assert_eq!(registry.len(), ids.len());
+        assert_eq!(
+            registry.count_by_tunnel(),
+            HashMap::from([(tunnels[0].clone(), 32), (tunnels[1].clone(), 32)])
+        );
+
+        let connection = registry.get(&ids[0]).unwrap();
+        assert!(!connection.closed.is_cancelled());
+        assert!(registry.remove(&ids[0]).is_some());
+        assert!(connection.closed.is_cancelled());
+        assert!(registry.get(&ids[0]).is_none());
+        assert!(registry.remove(&ids[0]).is_none());
+        assert_eq!(registry.len(), ids.len() - 1);
+    }
+}
@@ -1,8 +1,16 @@
+use crate::auth::{self, RequireToken};
+use crate::bridge::{DataSenderBridge, SendDataError};
+use crate::compress::PAYLOAD_COMPRESSION;
+use crate::crypto::{Direction, PayloadCipher, PayloadKey, PAYLOAD_CIPHER};
 use crate::event::ClientEventResponse;
+use crate::flow::{self, CreditGranter, Replenisher};
 use crate::helper::validate_register_req;
 use crate::pb::control_command::Payload;
-use crate::pb::{traffic_to_server, ControlCommand, TrafficToServer};
-use crate::{bridge, constant, event};
+use crate::pb::{
+    close_payload::Reason, traffic_to_server, ClosePayload, ControlCommand, ProbeReport,
+    ReportProbeResp, TrafficToServer,
+};
+use crate::{constant, event, protocol};
 use crate::{
     io::CancellableReceiver,
     pb::{
@@ -12,21 +20,43 @@ use crate::{
     },
 };
 use anyhow::Context as _;
-use async_shutdown::{ShutdownManager, ShutdownSignal};
+use async_shutdown::ShutdownManager;
 use bytes::Bytes;
-use dashmap::DashMap;
-use futures::StreamExt;
-use std::sync::Arc;
-use std::{net::ToSocketAddrs, pin::Pin};
-use tokio::sync::mpsc;
-use tokio::sync::oneshot;
+use futures::{future::Either, StreamExt};
+use std::{
+    net::{SocketAddr, ToSocketAddrs},
+    pin::Pin,
+};
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, oneshot, watch};
 use tokio_stream::{wrappers::ReceiverStream, Stream};
 use tokio_util::sync::CancellationToken;
-use tonic::{transport::Server as GrpcServer, Request, Response, Status, Streaming};
-use tracing::{error, info};
+use tonic::{
+    service::interceptor::InterceptedService,
+    transport::{server::TcpIncoming, Server as GrpcServer},
+    Request, Response, Status, Streaming,
+};
+use tracing::{debug, error, info, info_span, warn, Instrument as _};
 use uuid::Uuid;
 
+use super::admin::AdminLayer;
+use super::admission::RegistrationQueue;
+use super::bandwidth::{tunnel_rate_limit, weight_of, Bandwidth, TunnelRates, TunnelWeight};
+use super::connection::ConnectionRegistry;
 use super::data_server::DataServer;
+use super::hooks::Hooks;
+use super::landing_page::LandingPageLayer;
+use super::log_sampler::ConnectionLogSampler;
+use super::probe::Probes;
+use super::rate_limit::RateLimiter;
+use super::registration_limit::RegistrationLimit;
+use super::registry::{Claim, TunnelIdentity, TunnelInfo, TunnelRegistry};
+use super::reload::Reloader;
+use super::tls::{self, ControlTls};
 use super::Config;
 
 type GrpcResult<T> = Result<T, Status>;
@@ -60,18 +90,106 @@ pub struct Server {
 
     /// handler is the grpc handler of the control server.
     handler: ControlHandler,
+
+    /// admin serves the admin api on the control port if it's enabled.
+    admin: AdminLayer,
+
+    /// reloader applies the reloaded config.
+    reloader: Reloader,
+
+    /// grpc_reflection serves the grpc server reflection if it's enabled.
+    grpc_reflection: bool,
+
+    /// vhttp_optional runs the server without the vhttp server if its port can't be bound.
+    vhttp_optional: bool,
+
+    /// auth rejects the rpcs of the clients without the auth token if it's set.
+    auth: RequireToken,
+
+    /// control_tls serves the control port with the tls if it's set.
+    control_tls: Option<ControlTls>,
+
+    /// ready is Some(true) once the ports are bound, Some(false) if the server fails to start.
+    ready: watch::Sender<Option<bool>>,
 }
 
 impl Server {
     /// Create a new server instance.
     pub fn new(config: Config, shutdown: ShutdownManager<i8>) -> Self {
-        let (event_tx, event_rx) = mpsc::channel(1024);
+        // the pending registrations are bounded by the queue, so are the events.
+        let (event_tx, event_rx) = mpsc::channel(config.max_pending_registrations.max(1));
+        let registrations =
+            RegistrationQueue::new(config.max_pending_registrations).with_events(&event_tx);
 
         let server = GrpcServer::builder()
             .http2_keepalive_interval(Some(tokio::time::Duration::from_secs(60)))
             .http2_keepalive_timeout(Some(tokio::time::Duration::from_secs(3)));
-        let events = DataServer::new(config.vhttp_port, config.entrypoint);
-        let handler = ControlHandler::new(shutdown.wait_shutdown_triggered(), event_tx);
+        let running_config = config.clone();
+        let events = DataServer::new(
+            SocketAddr::new(config.vhttp_bind_ip, config.vhttp_port),
+            config.entrypoint,
+            config.tcp_keepalive,
+            config.max_pending_connections,
+            config.max_in_flight_bytes,
+            config.data_chunk_size,
+            config.max_request_deadline,
+            config.max_request_body,
+            config.max_response_body,
+            config.max_udp_sessions,
+            config.udp_idle_timeout,
+            config.udp_buffer_pool,
+            config.close_timeout,
+            config.memory_budget,
+            config.http_cache,
+            config.interstitial_page,
+        );
+        let tunnels = TunnelRegistry::new();
+        let probes = Probes::new();
+        let connections = ConnectionRegistry::new();
+        let rate_limiter = RateLimiter::new(config.control_rate_limit);
+        let payload_key = config.payload_key.clone();
+        let auth = RequireToken::new(config.auth_token.as_deref());
+        let admin = if config.admin_api {
+            AdminLayer::new(
+                tunnels.clone(),
+                events.port_manager(),
+                probes.clone(),
+                connections.clone(),
+                events.memory_budget(),
+                events.udp_sessions(),
+                rate_limiter.clone(),
+                registrations.clone(),
+                auth.clone(),
+            )
+        } else {
+            AdminLayer::disabled()
+        };
+        let reloader = Reloader::new(events.settings(), running_config);
+        let handler = ControlHandler::new(
+            shutdown.clone(),
+            event_tx,
+            tunnels,
+            probes,
+            connections,
+            rate_limiter,
+            payload_key,
+        )
+        .with_registrations(registrations)
+        .with_registration_limit(config.registration_rate_limit)
+        .with_bandwidth(config.bandwidth_limit, config.tunnel_weights)
+        .with_tunnel_rate_limit(config.tunnel_rate_limit)
+        .with_connection_log_sample(config.connection_log_sample)
+        .with_data_send_timeout(config.data_send_timeout)
+        .with_disabled_tunnels(
+            [
+                ("tcp", config.disable_tcp),
+                ("udp", config.disable_udp),
+                ("http", config.disable_http),
+            ]
+            .into_iter()
+            .filter_map(|(kind, disabled)| disabled.then_some(kind))
+            .collect(),
+        );
 
         Self {
             control_port: config.control_port,
@@ -80,10 +198,84 @@ impl Server {
             shutdown,
             handler,
             event_rx,
+            admin,
+            reloader,
+            grpc_reflection: config.grpc_reflection,
+            vhttp_optional: config.vhttp_optional,
+            auth,
+            control_tls: config.control_tls.clone(),
+            ready: watch::channel(None).0,
         }
     }
 
+    /// Wait until the server is accepting, i.e. both the control port and the vhttp port are bound,
+    /// or only the control port if the vhttp port is optional and can't be bound,
+    /// so the embedders can tell the server is up without sleeping, e.g. to pass a health check.
+    ///
+    /// It resolves to false if the server fails to start or is dropped before [`Server::run`].
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use async_shutdown::ShutdownManager;
+    ///
+    /// async fn run_server() {
+    ///     let server = castled::server::Server::new(Default::default(), ShutdownManager::new());
+    ///     let ready = server.ready();
+    ///     tokio::spawn(server.run());
+    ///     assert!(ready.await);
+    /// }
+    /// ```
+    pub fn ready(&self) -> impl std::future::Future<Output = bool> + Send + 'static {
+        let mut ready = self.ready.subscribe();
+        async move {
+            ready
+                .wait_for(Option::is_some)
+                .await
+                .is_ok_and(|ready| *ready == Some(true))
+        }
+    }
+
+    /// Run the hook when a tunnel is registered, it's awaited before the client is answered,
+    /// e.g. to record the tunnel in a database.
+    ///
+    /// The open hooks run in the order they are added, after the entrypoint of the tunnel is opened.
+    /// The first error skips the rest hooks and rejects the tunnel with `PERMISSION_DENIED`,
+    /// its entrypoint is closed and the close hooks don't run for it.
+    ///
+    /// A slow hook delays the registration, the client gives up after its timeout.
+    pub fn on_tunnel_open<F, Fut>(&mut self, hook: F)
+    where
+        F: Fn(TunnelInfo) -> Fut + Send + Sync + 'static,
+        Fut: std::future::Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        self.handler.hooks.add_open(hook);
+    }
+
+    /// Run the hook when a tunnel is torn down, e.g. the client is gone or the server shuts down.
+    ///
+    /// The close hooks run in the order they are added, for each tunnel passing the open hooks,
+    /// before its entrypoint is closed, so a hook delays the teardown while it runs, up to 5s
+    /// for all of them, e.g. to drain the users elsewhere. The entrypoint is closed with the server
+    /// on shutdown though. The tunnel is listed by the admin api until they finish,
+    /// the errors are logged and the rest hooks still run.
+    pub fn on_tunnel_close<F, Fut>(&mut self, hook: F)
+    where
+        F: Fn(TunnelInfo) -> Fut + Send + Sync + 'static,
+        Fut: std::future::Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        self.handler.hooks.add_close(hook);
+    }
+
+    /// Get the reloader to change the config of the running server,
+    /// see [`Reloader::reload`].
+    pub fn reloader(&self) -> Reloader {
+        self.reloader.clone()
+    }
+
     /// Run the server, this function blocks on the shutdown future.
+    /// It fails if the control port or the vhttp port can't be bound, see [`Server::ready`],
+    /// the error tells which one, the server runs without the vhttp port if `vhttp_optional` is set.
     ///
     /// # Examples
     ///
@@ -100,7 +292,7 @@ impl Server {
     ///     server.run().await.unwrap();
     /// }
     /// ```
-    pub async fn run(mut self) -> anyhow::Result<()> {
+    pub async fn run(self) -> anyhow::Result<()> {
         let addr = format!("0.0.0.0:{}", self.control_port)
             .to_socket_addrs()
             .context("parse control port")?
@@ -111,23 +303,101 @@ impl Server {
         let shutdown_listener_control_server = self.shutdown.wait_shutdown_triggered();
         let shutdown_listener_event_bus = self.shutdown.wait_shutdown_triggered();
 
+        // the ports are bound before serving, so the server is ready once both are.
         let event_bus = self.event_bus;
+        let vhttp_optional = self.vhttp_optional;
+        let control_tls = self.control_tls.as_ref();
+        let listeners = async {
+            // the invalid certificate fails the startup before any port is bound.
+            let acceptor = control_tls
+                .map(ControlTls::acceptor)
+                .transpose()
+                .context("load the tls of the control port")?;
+            let vhttp_listener = match event_bus.bind().await {
+                Ok(listener) => Some(listener),
+                Err(status) if vhttp_optional => {
+                    warn!(
+                        addr = %event_bus.vhttp_addr(),
+                        err = status.message(),
+                        "failed to bind the vhttp port, the server runs without the vhttp server"
+                    );
+                    None
+                }
+                Err(status) => {
+                    return Err(anyhow::Error::new(status)
+                        .context(format!("bind the vhttp port {}", event_bus.vhttp_addr())));
+                }
+            };
+            let control_listener = TcpListener::bind(addr)
+                .await
+                .with_context(|| format!("bind the control port {}", addr))?;
+            anyhow::Ok((vhttp_listener, control_listener, acceptor))
+        };
+        let (vhttp_listener, control_listener, acceptor) = match listeners.await {
+            Ok(listeners) => listeners,
+            Err(err) => {
+                error!(err = ?err, "failed to start the server");
+                self.ready.send_replace(Some(false));
+                self.shutdown.trigger_shutdown_token(1);
+                return Err(err);
+            }
+        };
+        let incoming = match acceptor {
+            Some(acceptor) => Either::Right(tls::incoming(control_listener, acceptor)),
+            None => Either::Left(
+                TcpIncoming::from_listener(control_listener, false, None)
+                    .map_err(|err| anyhow::anyhow!(err))?,
+            ),
+        };
+        // the shutdown completes once the data server quits
+        let delay = self.shutdown.delay_shutdown_token();
         tokio::spawn(async move {
+            let _delay = delay;
             event_bus
-                .listen(shutdown_listener_event_bus, self.event_rx)
+                .serve(vhttp_listener, shutdown_listener_event_bus, self.event_rx)
                 .await
         });
 
+        // the reflection requires the auth token as the TunnelService does.
+        let reflection = self.grpc_reflection.then(|| {
+            let reflection = tonic_reflection::server::Builder::configure()
+                .register_encoded_file_descriptor_set(crate::pb::FILE_DESCRIPTOR_SET)
+                .build()
+                .expect("the file descriptor set is generated from the proto");
+            InterceptedService::new(reflection, self.auth.clone())
+        });
+
         info!(?addr, "starting control server");
+        self.ready.send_replace(Some(true));
 
-        if let Err(err) = self
+        let router = self
             .control_server
-            .add_service(TunnelServiceServer::new(self.handler))
-            .serve_with_shutdown(addr, async {
-                shutdown_listener_control_server.await;
-            })
-            .await
-        {
+            // accept http/1.1 to answer the browsers with the landing page.
+            .accept_http1(true)
+            .layer(self.admin)
+            .layer(LandingPageLayer)
+            .add_service(TunnelServiceServer::with_interceptor(
+                self.handler,
+                self.auth,
+            ))
+            .add_optional_service(reflection);
+        let shutdown = async {
+            shutdown_listener_control_server.await;
+        };
+        // the plaintext and the tls connections are different types of the incoming.
+        let served = match incoming {
+            Either::Left(incoming) => {
+                router
+                    .serve_with_incoming_shutdown(incoming, shutdown)
+                    .await
+            }
+            Either::Right(incoming) => {
+                router
+                    .serve_with_incoming_shutdown(incoming, shutdown)
+                    .await
+            }
+        };
+        if let Err(err) = served {
             error!(err = ?err, "server quit");
             self.shutdown.trigger_shutdown_token(1);
         } else {
@@ -142,18 +412,132 @@ impl Server {
 /// it implements the grpc TunnelService.
 struct ControlHandler {
     event_tx: mpsc::Sender<event::ClientEvent>,
-    bridges: Arc<DashMap<Bytes, bridge::DataSenderBridge>>,
-    close_sender_notifiers: Arc<DashMap<Bytes, CancellationToken>>,
-    shutdown: ShutdownSignal<i8>,
+    connections: ConnectionRegistry,
+    shutdown: ShutdownManager<i8>,
+    tunnels: TunnelRegistry,
+    probes: Probes,
+    hooks: Hooks,
+    /// limits the inbound messages of each client.
+    rate_limiter: RateLimiter,
+    /// sheds the registrations when the server is overloaded.
+    registrations: RegistrationQueue,
+    /// limits the registrations of each auth token.
+    registration_limit: RegistrationLimit,
+    payload_key: Option<PayloadKey>,
+    /// paces the traffic sent to the clients.
+    to_client: Bandwidth,
+    /// paces the traffic sent by the clients.
+    to_user: Bandwidth,
+    tunnel_weights: Arc<[TunnelWeight]>,
+    /// caps the bytes per second of each tunnel in each direction.
+    tunnel_rate_limit: Option<u64>,
+    /// logs 1 in the number of the user connections of each tunnel.
+    connection_log_sample: Option<u32>,
+    /// the types of the tunnels rejected at the registration, e.g. `udp`.
+    disabled_tunnels: Vec<&'static str>,
+    /// the max time the data of the clients waits for the user connections to take it.
+    data_send_timeout: Option<Duration>,
+    /// the unix time in seconds the server started at, it's told to the clients.
+    started_at: u64,
 }
 
 impl ControlHandler {
-    fn new(shutdown: ShutdownSignal<i8>, event_tx: mpsc::Sender<event::ClientEvent>) -> Self {
+    fn new(
+        shutdown: ShutdownManager<i8>,
+        event_tx: mpsc::Sender<event::ClientEvent>,
+        tunnels: TunnelRegistry,
+        probes: Probes,
+        connections: ConnectionRegistry,
+        rate_limiter: RateLimiter,
+        payload_key: Option<PayloadKey>,
+    ) -> Self {
         Self {
-            bridges: Arc::new(DashMap::new()),
-            close_sender_notifiers: Arc::new(DashMap::new()),
+            connections,
             event_tx,
             shutdown,
+            tunnels,
+            probes,
+            hooks: Hooks::default(),
+            rate_limiter,
+            registrations: RegistrationQueue::new(Config::default().max_pending_registrations),
+            registration_limit: RegistrationLimit::default(),
+            payload_key,
+            to_client: Bandwidth::new(None),
+            to_user: Bandwidth::new(None),
+            tunnel_weights: Arc::from([]),
+            tunnel_rate_limit: None,
+            connection_log_sample: None,
+            disabled_tunnels: Vec::new(),
+            data_send_timeout: None,
+            started_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_secs()),
+        }
+    }
+
+    /// shed the registrations beyond the pending ones of the queue.
+    fn with_registrations(mut self, registrations: RegistrationQueue) -> Self {
+        self.registrations = registrations;
+        self
+    }
+
+    /// cap the registrations per minute of each auth token.
+    fn with_registration_limit(mut self, limit: Option<u32>) -> Self {
+        self.registration_limit = RegistrationLimit::new(limit);
+        self
+    }
+
+    /// share the bandwidth of each direction between the tunnels by their weights.
+    fn with_bandwidth(mut self, limit: Option<u64>, weights: Vec<TunnelWeight>) -> Self {
+        self.to_client = Bandwidth::new(limit);
+        self.to_user = Bandwidth::new(limit);
+        self.tunnel_weights = Arc::from(weights);
+        self
+    }
+
+    /// cap the bytes per second of each tunnel in each direction, the tunnels may ask for less.
+    fn with_tunnel_rate_limit(mut self, limit: Option<u64>) -> Self {
+        self.tunnel_rate_limit = limit;
+        self
+    }
+
+    /// log the open and close of 1 in `every` user connections of each tunnel.
+    fn with_connection_log_sample(mut self, every: Option<u32>) -> Self {
+        self.connection_log_sample = every;
+        self
+    }
+
+    /// close the user connections not taking the data of the client within the timeout.
+    fn with_data_send_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.data_send_timeout = timeout;
+        self
+    }
+
+    /// reject the registrations of the types of the tunnels, see [`kind_of`].
+    fn with_disabled_tunnels(mut self, kinds: Vec<&'static str>) -> Self {
+        self.disabled_tunnels = kinds;
+        self
+    }
+
+    /// close the previous registration of the tunnel and wait for its entrypoint released.
+    async fn replace(&self, previous: Claim) {
+        warn!(
+            previous = previous.id,
+            "the tunnel is registered again by the client, replace the previous one"
+        );
+        // the previous one may be still registering, it closes itself once it's registered
+        self.tunnels.close(&previous.id, replaced());
+        if tokio::time::timeout(
+            constant::ENTRYPOINT_RELEASE_TIMEOUT,
+            previous.released.cancelled(),
+        )
+        .await
+        .is_err()
+        {
+            warn!(
+                previous = previous.id,
+                "the entrypoint of the previous one isn't released in time"
+            );
         }
     }
 }
@@ -162,23 +546,93 @@ impl ControlHandler {
 impl TunnelService for ControlHandler {
     type RegisterStream = RegisterStream;
 
+    ///
+    /// A tunnel registered again by the same client(the ip, the instance id, the name and the type
+    /// of the tunnel) replaces the previous registration, e.g. the client reconnected before the server noticed
+    /// the previous control stream is gone. The previous one is closed as evicted,
+    /// so its client doesn't retry it, and the new one waits for its entrypoint released,
+    /// so it can take the same port or subdomain.
+    /// The clients without an instance id are never replaced, they can't be told apart.
     async fn register(&self, req: Request<RegisterReq>) -> GrpcResponse<self::RegisterStream> {
+        let client = req.remote_addr().map(|addr| addr.ip());
+        self.rate_limiter.check(client)?;
+        self.registration_limit
+            .check(auth::authorization(req.metadata()))?;
+        // held until the tunnel is opened or rejected
+        let _pending = self.registrations.enter()?;
         let req = req.into_inner();
         if let Some(status) = validate_register_req(&req) {
             return Err(status);
         }
+        let kind = kind_of(req.tunnel.as_ref().unwrap().config.as_ref().unwrap());
+        if self.disabled_tunnels.contains(&kind) {
+            return Err(Status::permission_denied(format!(
+                "the {} tunnels are disabled by the server",
+                kind
+            )));
+        }
+        let data_stream_version = protocol::negotiate(req.data_stream_version)?;
+        let payload_key = self.payload_key(&req.payload_cipher)?;
+        let payload_cipher = req.payload_cipher.clone();
+        // the unknown compressions are ignored, the datagrams stay uncompressed then.
+        let compressed = matches!(
+            req.tunnel.as_ref().unwrap().config.as_ref().unwrap(),
+            Udp(udp) if udp.compression == PAYLOAD_COMPRESSION
+        );
 
         let (outbound_streaming_tx, outbound_streaming_rx) = mpsc::channel(256);
         let outbound_streaming_tx_init_message = outbound_streaming_tx.clone();
-        let (entrypoint_tx, entrypoint_rx) = oneshot::channel();
+        // the init payload carrying only what the data server assigned
+        let (entrypoint_tx, entrypoint_rx) = oneshot::channel::<InitPayload>();
+        let tunnel_id = Uuid::new_v4().to_string();
+        let tunnel_name = req.tunnel.as_ref().unwrap().name.clone();
+        let span = info_span!("tunnel", id = %tunnel_id, name = %tunnel_name);
+        let identity = (!req.instance_id.is_empty()).then(|| TunnelIdentity {
+            client,
+            instance: req.instance_id.clone(),
+            name: tunnel_name.clone(),
+            kind,
+        });
+        // held until the entrypoint is released
+        let mut claim = None;
+        if let Some(identity) = &identity {
+            let (guard, previous) = self.tunnels.claim(identity.clone(), &tunnel_id);
+            claim = Some(guard);
+            if let Some(previous) = previous {
+                self.replace(previous).instrument(span.clone()).await;
+            }
+        }
+        let connection_tunnel_id: Arc<str> = Arc::from(tunnel_id.as_str());
+        let weight = weight_of(&self.tunnel_weights, |key| {
+            req.tunnel.as_ref().unwrap().labels.get(key)
+        });
+        let rate_limit = tunnel_rate_limit(
+            self.tunnel_rate_limit,
+            req.tunnel.as_ref().unwrap().rate_limit,
+        );
+        if let Some(rate_limit) = rate_limit {
+            debug!(tunnel_id, rate_limit, "the tunnel is rate limited");
+        }
+        // the connections of the tunnel share the buckets
+        let rates = TunnelRates::new(rate_limit);
+        let init_tunnel_id = tunnel_id.clone();
+        let server_started_at = self.started_at;
         tokio::spawn(async move {
             // wait for the entrypoint assigned by the server
-            let tenant_id = Uuid::new_v4();
-            if let Ok(entrypoint) = entrypoint_rx.await {
+            if let Ok(assigned) = entrypoint_rx.await {
                 let init_command = ControlCommand {
                     payload: Some(Payload::Init(InitPayload {
-                        tunnel_id: tenant_id.to_string(),
-                        assigned_entrypoint: entrypoint,
+                        tunnel_id: init_tunnel_id,
+                        data_stream_version,
+                        payload_cipher,
+                        compression: if compressed {
+                            PAYLOAD_COMPRESSION.to_string()
+                        } else {
+                            String::new()
+                        },
+                        server_version: constant::VERSION.to_string(),
+                        server_started_at,
+                        ..assigned
                     })),
                 };
                 outbound_streaming_tx_init_message
@@ -190,24 +644,43 @@ impl TunnelService for ControlHandler {
             }
         });
 
+        // cancelled to tear the tunnel down, e.g. close its entrypoint.
         let register_cancel = CancellationToken::new();
+        // cancelled once the client is gone, the tunnel is torn down after the close hooks.
+        let client_gone = CancellationToken::new();
         let event_tx = self.event_tx.clone();
 
         let (resp_tx, resp_rx) = oneshot::channel();
+        let (closer, mut closed) = oneshot::channel::<ClosePayload>();
+        // closed once the entrypoint is released.
+        let (released_tx, mut released) = mpsc::channel::<()>(1);
         let (user_incoming_tx, mut user_incoming_rx) = mpsc::channel::<event::UserIncoming>(1024);
 
+        let loopback = req.tunnel.as_ref().unwrap().loopback;
         match req.tunnel.as_ref().unwrap().config.as_ref().unwrap() {
             Tcp(tcp) => {
                 info!(
+                    tunnel_name,
                     remote_port = tcp.remote_port,
                     "registering tcp tunnel on remote_port"
                 );
                 event_tx
                     .send(event::ClientEvent {
+                        tunnel_name: tunnel_name.clone(),
                         payload: event::Payload::RegisterTcp {
                             port: tcp.remote_port as u16,
+                            first_byte_timeout: (tcp.first_byte_timeout_ms > 0).then(|| {
+                                event::FirstByteTimeout {
+                                    timeout: Duration::from_millis(tcp.first_byte_timeout_ms),
+                                    from_user: tcp.first_byte_from_user,
+                                }
+                            }),
+                            max_chunk_size: (tcp.max_chunk_size > 0)
+                                .then_some(tcp.max_chunk_size as usize),
                         },
+                        loopback,
                         close_listener: register_cancel.clone(),
+                        released: released_tx,
                         incoming_events: user_incoming_tx,
                         resp: resp_tx,
                     })
@@ -218,15 +691,52 @@ impl TunnelService for ControlHandler {
                     })?;
             }
             Http(http) => {
+                info!(tunnel_name, "registering http tunnel");
                 event_tx
                     .send(event::ClientEvent {
+                        tunnel_name: tunnel_name.clone(),
                         payload: event::Payload::RegisterHttp {
                             port: http.remote_port as u16,
                             subdomain: Bytes::from(http.subdomain.to_owned()),
                             domain: Bytes::from(http.domain.to_owned()),
                             random_subdomain: http.random_subdomain,
+                            path_prefix: http.path_prefix.clone(),
+                            options: event::HttpOptions {
+                                request_timeout: (http.request_timeout_ms > 0)
+                                    .then(|| Duration::from_millis(http.request_timeout_ms)),
+                                // validated by validate_register_req
+                                allowed_methods: http
+                                    .allowed_methods
+                                    .iter()
+                                    .filter_map(|method| {
+                                        http::Method::from_bytes(method.as_bytes()).ok()
+                                    })
+                                    .collect(),
+                                interstitial: http.interstitial,
+                                coalesce: http.coalesce,
+                                rewrite_origins: http.rewrite_origins.clone(),
+                                // decided by the data server with its entrypoint config
+                                https: false,
+                                max_request_body: (http.max_request_body > 0)
+                                    .then_some(http.max_request_body),
+                                max_response_body: (http.max_response_body > 0)
+                                    .then_some(http.max_response_body),
+                                // validated by validate_register_req
+                                request_headers: http
+                                    .request_headers
+                                    .as_ref()
+                                    .and_then(|rules| rules.try_into().ok())
+                                    .unwrap_or_default(),
+                                response_headers: http
+                                    .response_headers
+                                    .as_ref()
+                                    .and_then(|rules| rules.try_into().ok())
+                                    .unwrap_or_default(),
+                            },
                         },
+                        loopback,
                         close_listener: register_cancel.clone(),
+                        released: released_tx,
                         incoming_events: user_incoming_tx,
                         resp: resp_tx,
                     })
@@ -237,12 +747,23 @@ impl TunnelService for ControlHandler {
                     })?;
             }
             Udp(udp) => {
+                info!(
+                    tunnel_name,
+                    remote_port = udp.remote_port,
+                    "registering udp tunnel on remote_port"
+                );
                 event_tx
                     .send(event::ClientEvent {
+                        tunnel_name: tunnel_name.clone(),
                         payload: event::Payload::RegisterUdp {
                             port: udp.remote_port as u16,
+                            compressed,
+                            max_datagram_size: (udp.max_datagram_size > 0)
+                                .then_some(udp.max_datagram_size as usize),
                         },
+                        loopback,
                         close_listener: register_cancel.clone(),
+                        released: released_tx,
                         incoming_events: user_incoming_tx,
                         resp: resp_tx,
                     })
@@ -254,10 +775,63 @@ impl TunnelService for ControlHandler {
             }
         }
 
+        // the data sent to the client is split at the negotiated chunk size of the tcp tunnel,
+        // or 8KiB, the datagrams of the udp tunnels are never split.
+        let mut chunk_size = (kind != "udp").then_some(constant::DEFAULT_BUF_SIZE);
+        // the tunnel passing the open hooks, the close hooks run for it.
+        let mut registered = None;
         match resp_rx.await {
-            Ok(ClientEventResponse::Registered { status, entrypoint }) => match status {
+            Ok(ClientEventResponse::Registered {
+                status,
+                entrypoint,
+                max_chunk_size,
+                remote_port,
+                subdomain,
+            }) => match status {
                 None => {
-                    entrypoint_tx.send(entrypoint).unwrap();
+                    chunk_size = max_chunk_size.or(chunk_size);
+                    let tunnel = req.tunnel.as_ref().unwrap();
+                    let info = TunnelInfo {
+                        id: tunnel_id.clone(),
+                        name: tunnel.name.clone(),
+                        kind,
+                        entrypoint: entrypoint.clone(),
+                        labels: tunnel.labels.clone().into_iter().collect(),
+                    };
+                    if let Err(err) = self.hooks.open(&info).instrument(span.clone()).await {
+                        warn!(parent: &span, ?err, "the tunnel is rejected by the open hook");
+                        // close the entrypoint
+                        register_cancel.cancel();
+                        return Err(Status::permission_denied(format!(
+                            "the tunnel is rejected: {:#}",
+                            err
+                        )));
+                    }
+                    self.tunnels.insert(info.clone());
+                    self.tunnels.on_close(&tunnel_id, closer);
+                    // replaced while it's registering, the replacing one missed the closer
+                    if identity
+                        .as_ref()
+                        .is_some_and(|identity| !self.tunnels.holds(identity, &tunnel_id))
+                    {
+                        self.tunnels.close(&tunnel_id, replaced());
+                    }
+                    self.probes
+                        .attach(tunnel_id.clone(), &outbound_streaming_tx);
+                    registered = Some(info);
+                    entrypoint_tx
+                        .send(InitPayload {
+                            assigned_entrypoint: entrypoint,
+                            max_chunk_size: max_chunk_size.map_or(0, |size| size as u32),
+                            remote_port: remote_port as u32,
+                            subdomain: String::from_utf8_lossy(&subdomain).into_owned(),
+                            domain: match tunnel.config.as_ref() {
+                                Some(Http(http)) => http.domain.clone(),
+                                _ => String::new(),
+                            },
+                            ..Default::default()
+                        })
+                        .unwrap();
                 }
                 Some(status) => {
                     outbound_streaming_tx.send(Err(status)).await.unwrap();
@@ -274,57 +848,97 @@ impl TunnelService for ControlHandler {
             }
         }
 
-        let shutdown_listener = self.shutdown.clone();
-        let bridges = self.bridges.clone();
+        let shutdown_listener = self.shutdown.wait_shutdown_triggered();
+        let connections = self.connections.clone();
         let register_cancel_listener = register_cancel.clone();
-        let close_sender_notifiers = Arc::clone(&self.close_sender_notifiers);
+        let client_gone_listener = client_gone.clone();
+        let tunnels = self.tunnels.clone();
+        let probes = self.probes.clone();
+        let hooks = self.hooks.clone();
+        let mut log_sampler = ConnectionLogSampler::new(self.connection_log_sample);
         tokio::spawn(async move {
-            loop {
+            // the entrypoint is released once the task ends
+            let _claim = claim;
+            // the close command told to the client once the tunnel is torn down
+            let close = loop {
                 tokio::select! {
                     _ = shutdown_listener.clone() => {
                         info!("server closed, close the control stream");
-                        return;
+                        // the client registers the tunnel again, e.g. on another server
+                        let close = ClosePayload {
+                            reason: Reason::ServerShutdown as i32,
+                            message: "the server is shutting down".to_string(),
+                        };
+                        let _ = outbound_streaming_tx.send(Ok(close_command(close))).await;
+                        break None;
                     }
                     _ = register_cancel_listener.cancelled() => {
                         info!("register cancelled, close the control stream");
+                        // the claim is held until the entrypoint is released
+                        let _ = tokio::time::timeout(constant::ENTRYPOINT_RELEASE_TIMEOUT, released.recv()).await;
                         return;
                     }
+                    _ = client_gone_listener.cancelled() => {
+                        info!("the client is gone, close the tunnel");
+                        break None;
+                    }
+                    Ok(close) = &mut closed => {
+                        info!(reason = close.reason().as_str_name(), message = close.message, "tunnel closed, close the control stream");
+                        break Some(close);
+                    }
                     Some(connection) = user_incoming_rx.recv() => {
                         match connection {
                             event::UserIncoming::Add(bridge) => {
                                 let bridge_id = String::from_utf8_lossy(bridge.id.to_vec().as_slice()).to_string();
-                                info!(bridge_id = bridge_id, "new user connection");
-                                bridges.insert(bridge.id, bridge.inner);
+                                if log_sampler.open(&bridge.id) {
+                                    info!(bridge_id = bridge_id, "new user connection");
+                                }
+                                connections.add(bridge.id, connection_tunnel_id.clone(), bridge.inner, payload_key.clone(), weight, rates.clone(), chunk_size);
                                 outbound_streaming_tx
                                     .send(Ok(ControlCommand {
-                                        payload: Some(Payload::Work(WorkPayload { connection_id: bridge_id })),
+                                        payload: Some(Payload::Work(WorkPayload { connection_id: bridge_id, host: bridge.host })),
                                     }))
                                     .await
                                     .context("failed to send work command")
                                     .unwrap();
                             }
                             event::UserIncoming::Remove(bridge_id) => {
-                                {
+                                if log_sampler.close(&bridge_id) {
                                     let bridge_id = String::from_utf8_lossy(bridge_id.to_vec().as_slice()).to_string();
                                     info!(bridge_id = bridge_id, "remove user connection");
                                 }
-                                bridges.remove(&bridge_id);
-                                if let Some(notifier) = close_sender_notifiers.remove(&bridge_id) {
-                                    // the close_sender register in Start action,
-                                    // so it might be None.
-                                    notifier.1.cancel();
-                                };
+                                // it stops forwarding the traffic to the client.
+                                connections.remove(&bridge_id);
                             }
                         }
                     }
                 }
+            };
+
+            if let Some(info) = &registered {
+                // the entrypoint still serves the users while the close hooks run
+                if tokio::time::timeout(constant::CLOSE_HOOKS_TIMEOUT, hooks.close(info)).await.is_err() {
+                    warn!(timeout = ?constant::CLOSE_HOOKS_TIMEOUT, "the close hooks haven't finished in time, close the tunnel anyway");
+                }
             }
-        });
+            // close the entrypoint first, so its port can be registered again
+            // once the client is told, the close command is still delivered
+            register_cancel.cancel();
+            // the claim is held until the entrypoint is released
+            if tokio::time::timeout(constant::ENTRYPOINT_RELEASE_TIMEOUT, released.recv()).await.is_err() {
+                warn!("the entrypoint isn't released in time, close the control stream anyway");
+            }
+            if let Some(close) = close {
+                let _ = outbound_streaming_tx.send(Ok(close_command(close))).await;
+            }
+            if registered.is_some() {
+                tunnels.remove(&tunnel_id);
+                probes.detach(&tunnel_id);
+            }
+        }.instrument(span));
 
-        let control_stream = Box::pin(CancellableReceiver::new(
-            register_cancel,
-            outbound_streaming_rx,
-        )) as self::RegisterStream;
+        let control_stream = Box::pin(CancellableReceiver::new(client_gone, outbound_streaming_rx))
+            as self::RegisterStream;
         Ok(Response::new(control_stream))
     }
 
@@ -338,43 +952,125 @@ impl TunnelService for ControlHandler {
         &self,
         req: Request<Streaming<TrafficToServer>>,
     ) -> GrpcResponse<self::DataStream> {
-        let bridges = self.bridges.clone();
+        let connections = self.connections.clone();
+        let client = req.remote_addr().map(|addr| addr.ip());
+        let rate_limiter = self.rate_limiter.clone();
+        let to_client = self.to_client.clone();
+        let to_user = self.to_user.clone();
+        let data_send_timeout = self.data_send_timeout;
         let mut inbound_stream = req.into_inner();
         let (outbound_tx, outbound_rx) = mpsc::channel(256);
 
-        let shutdown_listener = self.shutdown.clone();
-        let close_sender_notifiers = Arc::clone(&self.close_sender_notifiers);
+        let shutdown_listener = self.shutdown.wait_shutdown_triggered();
+        // the shutdown completes once the data streams end, e.g. the udp sessions drained.
+        let delay = self.shutdown.delay_shutdown_token().ok();
         tokio::spawn(async move {
-            let mut stream_started = false;
-            loop {
+            let _delay = delay;
+            let mut state = DataStreamState::Handshaking;
+            // opens the payloads of the client if the tunnel encrypts them.
+            let mut opener = None;
+            // the credits of the server granted by the client, and the messages of the client to grant back.
+            let mut granter: Option<CreditGranter> = None;
+            let mut replenisher: Option<Replenisher> = None;
+            // forwards the traffic of the bridge to the client, it returns once the bridge is closed.
+            let mut forwarder: Option<tokio::task::JoinHandle<()>> = None;
+            // the replies of the client are still forwarded for a while after the shutdown,
+            // the udp sessions are draining, see `Udp::serve`.
+            let drain_deadline = tokio::time::sleep(Duration::ZERO);
+            tokio::pin!(drain_deadline);
+            let mut draining = false;
+            'stream: loop {
                 tokio::select! {
-                    _ = shutdown_listener.clone() => { break }
-                    Some(traffic) = inbound_stream.next() => {
+                    _ = shutdown_listener.clone(), if !draining => {
+                        draining = true;
+                        drain_deadline
+                            .as_mut()
+                            .reset(tokio::time::Instant::now() + constant::DEFAULT_UDP_DRAIN_TIMEOUT);
+                    }
+                    _ = &mut drain_deadline, if draining => { break }
+                    // with the flow control, the client keeps the stream open for the grants
+                    // until the stream ends, so it's ended once all the traffic to the client is sent.
+                    _ = async { forwarder.as_mut().unwrap().await }, if forwarder.is_some() && granter.is_some() => {
+                        debug!("the bridge is closed, close the data streaming");
+                        break;
+                    }
+                    traffic = inbound_stream.next() => {
+                        // the client closed the data stream
+                        let Some(traffic) = traffic else { break };
+                        let throttle = rate_limiter.throttle(client);
+                        tokio::pin!(throttle);
+                        // the throttled message doesn't hold up the shutdown and the drain
+                        loop {
+                            tokio::select! {
+                                _ = &mut throttle => break,
+                                _ = shutdown_listener.clone(), if !draining => {
+                                    draining = true;
+                                    drain_deadline
+                                        .as_mut()
+                                        .reset(tokio::time::Instant::now() + constant::DEFAULT_UDP_DRAIN_TIMEOUT);
+                                }
+                                _ = &mut drain_deadline, if draining => break 'stream,
+                            }
+                        }
                         match traffic {
                             Ok(traffic) => {
+                                if traffic.action == traffic_to_server::Action::Credit as i32 {
+                                    // the connection may be removed already, e.g. after the finished action.
+                                    if let Err(status) = state.transit(traffic_to_server::Action::Credit, traffic.version) {
+                                        error!(bridge_id = traffic.connection_id, ?status, "invalid data stream");
+                                        let _ = outbound_tx.send(Err(status)).await;
+                                        return;
+                                    }
+                                    if let Some(granter) = &granter {
+                                        granter.grant(traffic.credit);
+                                    }
+                                    continue;
+                                }
                                 let bridge_id_str = traffic.connection_id;
                                 let bridge_id = Bytes::copy_from_slice(bridge_id_str.as_bytes());
-                                let bridge = bridges
-                                    .get(&bridge_id)
-                                    .context(format!("connection {:?} not found, traffic action: {}", bridge_id, traffic.action))
-                                    .unwrap();
-                                let bridge = bridge.value();
+                                // cloned out of the registry, the sending may wait for the in-flight cap.
+                                let Some(connection) = connections.get(&bridge_id) else {
+                                    // e.g. the traffic in flight when the user closed the connection
+                                    debug!(bridge_id = bridge_id_str, action = traffic.action, "connection not found, dropped the traffic");
+                                    continue;
+                                };
+                                let bridge = connection.bridge;
 
-                                match traffic_to_server::Action::try_from(traffic.action) {
+                                let action = traffic_to_server::Action::try_from(traffic.action);
+                                if let Ok(action) = action {
+                                    if let Err(status) = state.transit(action, traffic.version) {
+                                        error!(bridge_id = bridge_id_str, ?status, "invalid data stream");
+                                        let _ = outbound_tx.send(Err(status)).await;
+                                        bridge.close();
+                                        return;
+                                    }
+                                }
+
+                                match action {
                                     Ok(traffic_to_server::Action::Start) => {
                                         info!(
                                             bridge_id = bridge_id_str,
+                                            version = state.version(),
                                             "received start action, I am gonna start streaming",
                                         );
-                                        if stream_started {
-                                            error!("duplicate start action");
-                                            return;
-                                        }
-                                        stream_started = true;
 
-                                        let close_sender = CancellationToken::new();
-                                        let close_sender_listener = close_sender.clone();
-                                        close_sender_notifiers.insert(bridge_id, close_sender);
+                                        let close_sender_listener = connection.closed;
+                                        let mut sealer = connection.payload_key.as_ref().map(|key| {
+                                            opener = Some(PayloadCipher::new(key, &bridge_id_str, Direction::ToServer));
+                                            PayloadCipher::new(key, &bridge_id_str, Direction::ToClient)
+                                        });
+                                        // the data is sealed in place, so it's copied out of the shared buffer.
+                                        let mut seal = move |data: Bytes| match &mut sealer {
+                                            Some(sealer) => Bytes::from(sealer.seal(data.to_vec())),
+                                            None => data,
+                                        };
+                                        let mut credits = None;
+                                        if state.version().is_some_and(protocol::flow_controlled) {
+                                            let (send_credits, send_granter) = flow::credits(protocol::INITIAL_CREDITS);
+                                            credits = Some(send_credits);
+                                            granter = Some(send_granter);
+                                            replenisher = Some(Replenisher::new(protocol::INITIAL_CREDITS));
+                                        }
 
                                         // we read data from transfer_rx, then forward the data to outbound_tx
                                         let (transfer_tx, mut transfer_rx) = mpsc::channel(256);
@@ -383,29 +1079,28 @@ impl TunnelService for ControlHandler {
                                             .context("failed to send streaming_tx to data_sender_sender")
                                             .unwrap();
                                         let outbound_tx = outbound_tx.clone();
-                                        tokio::spawn(async move {
+                                        let to_client = to_client.clone();
+                                        let (tunnel_id, weight, rate) = (connection.tunnel_id, connection.weight, connection.rates.to_client);
+                                        let chunk_size = connection.chunk_size;
+                                        forwarder = Some(tokio::spawn(async move {
                                             loop {
                                                 tokio::select! {
                                                     // server -> client
                                                     Some(data) = transfer_rx.recv() => {
-                                                        if data.len() <= constant::DEFAULT_BUF_SIZE {
+                                                        for data in split_chunks(data, chunk_size) {
+                                                            // the granter is gone with the data stream
+                                                            if let Some(credits) = &mut credits {
+                                                                if !credits.acquire().await {
+                                                                    return;
+                                                                }
+                                                            }
+                                                            to_client.acquire(&tunnel_id, weight, data.len()).await;
+                                                            rate.acquire(data.len()).await;
                                                             outbound_tx
-                                                                .send(Ok(TrafficToClient { data }))
+                                                                .send(Ok(TrafficToClient { data: seal(data), ..Default::default() }))
                                                                 .await
                                                                 .context("failed to send traffic to outbound channel")
                                                                 .unwrap();
-                                                        } else {
-                                                            // read data chunk by chunk, then send to the client
-                                                            // if data is empty, the first chunk will be none.
-                                                            // which means we have no change to send the data.
-                                                            // so if the length of data is less than 8192, we can send it directly.
-                                                            for data in data.chunks(constant::DEFAULT_BUF_SIZE) {
-                                                                outbound_tx
-                                                                    .send(Ok(TrafficToClient { data: data.to_vec() }))
-                                                                    .await
-                                                                    .context("failed to send traffic to outbound channel")
-                                                                    .unwrap();
-                                                            }
                                                         }
                                                     }
                                                     _ = close_sender_listener.cancelled() => {
@@ -419,7 +1114,7 @@ impl TunnelService for ControlHandler {
                                                     }
                                                 }
                                             }
-                                        });
+                                        }));
                                     }
                                     Ok(traffic_to_server::Action::Sending) => {
                                         info!(
@@ -427,21 +1122,52 @@ impl TunnelService for ControlHandler {
                                             "client is sending traffic",
                                         );
                                         // client -> server
-                                        bridge.send_data(traffic.data).await.unwrap();
+                                        let data = match &mut opener {
+                                            Some(opener) => match opener.open(traffic.data) {
+                                                Ok(data) => data,
+                                                Err(status) => {
+                                                    error!(bridge_id = bridge_id_str, ?status, "invalid payload");
+                                                    let _ = outbound_tx.send(Err(status)).await;
+                                                    bridge.close();
+                                                    return;
+                                                }
+                                            },
+                                            None => traffic.data,
+                                        };
+                                        to_user.acquire(&connection.tunnel_id, connection.weight, data.len()).await;
+                                        connection.rates.to_user.acquire(data.len()).await;
+                                        if !forward_to_user(&bridge, &bridge_id_str, data, data_send_timeout, &outbound_tx).await {
+                                            bridge.close();
+                                            return;
+                                        }
+                                        // the data is taken by the data server, the client may send more.
+                                        if let Some(credit) = replenisher.as_mut().and_then(Replenisher::consume) {
+                                            let _ = outbound_tx
+                                                .send(Ok(TrafficToClient { credit, ..Default::default() }))
+                                                .await;
+                                        }
                                     }
                                     Ok(traffic_to_server::Action::Finished) => {
                                         info!(
                                             bridge_id = bridge_id_str,
                                             "client finished sending traffic",
                                         );
-                                        bridge.send_data(vec![]).await.unwrap();
-                                        return; // close the data streaming
+                                        if !forward_to_user(&bridge, &bridge_id_str, vec![], data_send_timeout, &outbound_tx).await {
+                                            bridge.close();
+                                            return;
+                                        }
+                                        if granter.is_none() {
+                                            return; // close the data streaming
+                                        }
+                                        // keep receiving the credits of the traffic to the client
                                     }
                                     Ok(traffic_to_server::Action::Close) => {
                                         info!(bridge_id = bridge_id_str, "client closed streaming");
                                         bridge.close();
                                         return; // close the data streaming
                                     }
+                                    // granted before looking up the connection
+                                    Ok(traffic_to_server::Action::Credit) => {}
                                     Err(_) => {
                                         error!("invalid traffic action: {}", traffic.action);
                                         bridge.close();
@@ -462,6 +1188,159 @@ impl TunnelService for ControlHandler {
             Box::pin(response_streaming) as self::DataStream
         ))
     }
+
+    async fn report_probe(&self, req: Request<ProbeReport>) -> GrpcResponse<ReportProbeResp> {
+        self.rate_limiter
+            .check(req.remote_addr().map(|addr| addr.ip()))?;
+        let report = req.into_inner();
+        let probe_id = report.probe_id.clone();
+        if !self.probes.report(report) {
+            // the probe has timed out, nobody waits for it.
+            return Err(Status::not_found(format!("probe {} not found", probe_id)));
+        }
+        Ok(Response::new(ReportProbeResp {}))
+    }
+}
+
+impl ControlHandler {
+    /// the key encrypting the payloads of the tunnel, the cipher is asked by the client.
+    fn payload_key(&self, cipher: &str) -> GrpcResult<Option<PayloadKey>> {
+        if cipher.is_empty() {
+            return Ok(None);
+        }
+        if cipher != PAYLOAD_CIPHER {
+            return Err(Status::failed_precondition(format!(
+                "unsupported payload cipher {:?}, supported: {}",
+                cipher, PAYLOAD_CIPHER
+            )));
+        }
+        match &self.payload_key {
+            Some(key) => Ok(Some(key.clone())),
+            None => Err(Status::failed_precondition(
+                "the server has no payload key configured, register without the payload encryption",
+            )),
+        }
+    }
+}
+
+/// the close of the registration replaced by a new one of the same tunnel.
+fn replaced() -> ClosePayload {
+    ClosePayload {
+        reason: Reason::Evicted as i32,
+        message: "replaced by a new registration of the tunnel".to_string(),
+    }
+}
+
+/// forward the data of the client to the user connection, false if the connection is gone,
+/// or it's stuck, then the data stream ends with the reason.
+async fn forward_to_user(
+    bridge: &DataSenderBridge,
+    bridge_id: &str,
+    data: Vec<u8>,
+    timeout: Option<Duration>,
+    outbound_tx: &mpsc::Sender<Result<TrafficToClient, Status>>,
+) -> bool {
+    match bridge.send_data_within(data, timeout).await {
+        Ok(()) => true,
+        Err(SendDataError::Closed) => {
+            debug!(bridge_id, "the user connection is closed, stop forwarding");
+            false
+        }
+        Err(SendDataError::Timeout) => {
+            warn!(
+                bridge_id,
+                ?timeout,
+                "the user connection hasn't taken the data in time, close it"
+            );
+            let _ = outbound_tx
+                .send(Err(Status::deadline_exceeded(
+                    "the user connection is stuck, it hasn't taken the data in time",
+                )))
+                .await;
+            false
+        }
+    }
+}
+
+/// split the data sent to the client into the chunks of at most the chunk size,
+/// the data fitting in a chunk is sent as it is, e.g. the empty data telling the end.
+/// None never splits the data, e.g. a datagram.
+///
+/// The chunks share the buffer of the data without copying.
+fn split_chunks(data: Bytes, chunk_size: Option<usize>) -> Vec<Bytes> {
+    match chunk_size {
+        Some(chunk_size) if data.len() > chunk_size => (0..data.len())
+            .step_by(chunk_size)
+            .map(|start| data.slice(start..data.len().min(start + chunk_size)))
+            .collect(),
+        _ => vec![data],
+    }
+}
+
+/// the type of the tunnel, tcp, udp or http.
+fn kind_of(config: &crate::pb::tunnel::Config) -> &'static str {
+    match config {
+        Tcp(_) => "tcp",
+        Udp(_) => "udp",
+        Http(_) => "http",
+    }
+}
+
+/// the last command of a control stream, it tells the client why the tunnel is closed.
+fn close_command(close: ClosePayload) -> ControlCommand {
+    ControlCommand {
+        payload: Some(Payload::Close(close)),
+    }
+}
+
+/// DataStreamState is the state machine of a data stream.
+///
+/// The client must open the data stream with the `Start` action carrying
+/// the negotiated version, only then the traffic is accepted.
+/// The `Close` action is accepted in any state, because the client
+/// closes the stream without starting it when it can't dial the local endpoint.
+#[derive(Debug, PartialEq)]
+enum DataStreamState {
+    Handshaking,
+    Streaming { version: u32 },
+}
+
+impl DataStreamState {
+    /// transit validates the action against the current state, then moves to the next state.
+    fn transit(&mut self, action: traffic_to_server::Action, version: u32) -> GrpcResult<()> {
+        use traffic_to_server::Action;
+
+        match (&self, action) {
+            (Self::Handshaking, Action::Start) => {
+                *self = Self::Streaming {
+                    version: protocol::accept(version)?,
+                };
+                Ok(())
+            }
+            (Self::Streaming { .. }, Action::Start) => {
+                Err(Status::failed_precondition("duplicate start action"))
+            }
+            (Self::Handshaking, Action::Sending | Action::Finished | Action::Credit) => Err(
+                Status::failed_precondition("the data stream must start with the start action"),
+            ),
+            (Self::Streaming { version }, Action::Credit)
+                if !protocol::flow_controlled(*version) =>
+            {
+                Err(Status::failed_precondition(format!(
+                    "the credit action isn't spoken by the data stream version {}",
+                    version
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn version(&self) -> Option<u32> {
+        match self {
+            Self::Handshaking => None,
+            Self::Streaming { version } => Some(*version),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -481,6 +1360,7 @@ mod tests {
                     domain: vec!["example.com".to_string()],
                     ..Default::default()
                 },
+                ..Default::default()
             },
             shutdown.clone(),
         );
@@ -493,4 +1373,55 @@ mod tests {
 
         shutdown.trigger_shutdown(0).unwrap();
     }
+
+    #[test]
+    fn test_data_stream_state() {
+        use traffic_to_server::Action;
+
+        let mut state = DataStreamState::Handshaking;
+        assert!(state.transit(Action::Sending, 0).is_err());
+        assert!(state.transit(Action::Finished, 0).is_err());
+        assert_eq!(state, DataStreamState::Handshaking);
+        assert!(state
+            .transit(Action::Start, protocol::DATA_STREAM_VERSION + 1)
+            .is_err());
+
+        state.transit(Action::Start, 0).unwrap();
+        assert_eq!(state.version(), Some(1));
+        state.transit(Action::Sending, 0).unwrap();
+        assert!(state.transit(Action::Start, 1).is_err());
+        state.transit(Action::Finished, 0).unwrap();
+        // the version 1 isn't flow controlled
+        assert!(state.transit(Action::Credit, 0).is_err());
+
+        let mut state = DataStreamState::Handshaking;
+        assert!(state.transit(Action::Credit, 0).is_err());
+        state.transit(Action::Start, 2).unwrap();
+        state.transit(Action::Credit, 0).unwrap();
+
+        // the client closes the stream without starting it if it can't dial the local endpoint
+        let mut state = DataStreamState::Handshaking;
+        state.transit(Action::Close, 0).unwrap();
+    }
+
+    #[test]
+    fn test_split_chunks() {
+        assert_eq!(split_chunks(Bytes::new(), Some(4)), vec![Bytes::new()]);
+        assert_eq!(
+            split_chunks(Bytes::from_static(&[1, 2, 3, 4]), Some(4)),
+            vec![&[1, 2, 3, 4][..]]
+        );
+        assert_eq!(
+            split_chunks(Bytes::from_static(&[1, 2, 3, 4, 5]), Some(2)),
+            vec![&[1, 2][..], &[3, 4], &[5]]
+        );
+        // the chunks larger than 8KiB are kept, e.g. the negotiated chunk size of 64KiB
+        let data = Bytes::from(vec![7; 64 * 1024]);
+        assert_eq!(
+            split_chunks(data.clone(), Some(64 * 1024)),
+            vec![data.clone()]
+        );
+        // a datagram is never split
+        assert_eq!(split_chunks(data.clone(), None), vec![data]);
+    }
 }
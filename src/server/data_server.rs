@@ -1,15 +1,21 @@
 use crate::{
+    bridge::MemoryBudget,
     event::{self, ClientEventResponse, Payload},
+    protocol,
     server::port::{Available, PortManager},
-    socket::create_tcp_listener,
+    socket::{create_tcp_listener_on, TcpKeepalive},
 };
 
 use super::{
+    reload::Settings,
+    subdomain,
     tunnel::{
         create_socket,
-        http::{DynamicRegistry, FixedRegistry, Http},
+        http::{DynamicRegistry, FixedRegistry, Http, HttpTarget, LookupRequest},
+        http_cache::ResponseCache,
+        interstitial::Interstitial,
         tcp::Tcp,
-        udp::Udp,
+        udp::{Udp, UdpSessions},
     },
     EntrypointConfig,
 };
@@ -18,47 +24,145 @@ use bytes::Bytes;
 use rand::prelude::*;
 use rand::rngs::StdRng;
 use std::{
-    sync::Arc,
-    time::{SystemTime, UNIX_EPOCH},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 use tokio::{
     net::{TcpListener, UdpSocket},
     spawn,
-    sync::mpsc,
+    sync::{mpsc, oneshot, Semaphore},
 };
 use tokio_util::sync::CancellationToken;
 use tonic::Status;
-use tracing::info;
+use tracing::{info, info_span, warn, Instrument as _};
+
+/// the random subdomains tried before giving up, they're taken by the other tunnels.
+const RANDOM_SUBDOMAIN_ATTEMPTS: usize = 64;
 
 /// DataServer is responsible for handling the data transfer
 /// between user connection and Grpc Server(of Control Server).
 pub(crate) struct DataServer {
-    vhttp_port: u16,
+    vhttp_addr: SocketAddr,
     http_registry: DynamicRegistry,
-    entrypoint_config: EntrypointConfig,
     port_manager: PortManager,
+    /// the settings of the new tunnels, they are replaced when the config is reloaded.
+    settings: Arc<RwLock<Settings>>,
+    /// limits the user connections waiting for the data stream of the client,
+    /// it's shared by all the tunnels of the server.
+    connection_setups: Arc<Semaphore>,
+    /// caps the bytes in flight of all the tunnels of the server.
+    memory_budget: MemoryBudget,
+    /// counts the sessions of all the udp tunnels of the server.
+    udp_sessions: UdpSessions,
+    /// the response cache of all the http tunnels, None if it's disabled.
+    http_cache: Option<ResponseCache>,
+    /// the page of the http tunnels with the interstitial enabled.
+    interstitial: Interstitial,
+    /// false if the server runs without the vhttp server, see `Config::vhttp_optional`.
+    vhttp_serving: bool,
 }
 
 impl DataServer {
-    pub(crate) fn new(vhttp_port: u16, entrypoint_config: EntrypointConfig) -> Self {
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new(
+        vhttp_addr: SocketAddr,
+        entrypoint_config: EntrypointConfig,
+        tcp_keepalive: Option<TcpKeepalive>,
+        max_pending_connections: usize,
+        max_in_flight_bytes: usize,
+        data_chunk_size: Option<usize>,
+        max_request_deadline: Option<Duration>,
+        max_request_body: Option<u64>,
+        max_response_body: Option<u64>,
+        max_udp_sessions: Option<usize>,
+        udp_idle_timeout: Option<Duration>,
+        udp_buffer_pool: usize,
+        close_timeout: Option<Duration>,
+        memory_budget: Option<usize>,
+        http_cache: Option<usize>,
+        interstitial_page: Option<String>,
+    ) -> Self {
         let http_registry = DynamicRegistry::new();
         let port_manager = PortManager::new(
             entrypoint_config.port_range.clone(),
             entrypoint_config.exclude_ports.clone(),
-        );
+        )
+        .with_ephemeral_ports(entrypoint_config.ephemeral_ports);
         Self {
-            vhttp_port,
+            vhttp_addr,
             http_registry,
             port_manager,
-            entrypoint_config,
+            settings: Arc::new(RwLock::new(Settings {
+                entrypoint: entrypoint_config,
+                tcp_keepalive,
+                max_in_flight_bytes,
+                data_chunk_size,
+                max_request_deadline,
+                max_request_body,
+                max_response_body,
+                max_udp_sessions,
+                udp_idle_timeout,
+                udp_buffer_pool,
+                close_timeout,
+            })),
+            connection_setups: Arc::new(Semaphore::new(max_pending_connections.max(1))),
+            memory_budget: MemoryBudget::new(memory_budget),
+            udp_sessions: UdpSessions::default(),
+            http_cache: http_cache.map(ResponseCache::new),
+            interstitial: Interstitial::new(interstitial_page),
+            vhttp_serving: true,
         }
     }
 
+    pub(crate) fn memory_budget(&self) -> MemoryBudget {
+        self.memory_budget.clone()
+    }
+
+    pub(crate) fn udp_sessions(&self) -> UdpSessions {
+        self.udp_sessions.clone()
+    }
+
+    pub(crate) fn port_manager(&self) -> PortManager {
+        self.port_manager.clone()
+    }
+
+    pub(crate) fn settings(&self) -> Arc<RwLock<Settings>> {
+        Arc::clone(&self.settings)
+    }
+
+    fn current_settings(&self) -> Settings {
+        self.settings.read().unwrap().clone()
+    }
+
+    #[cfg(test)]
     pub(crate) async fn listen(
         self,
         shutdown: ShutdownSignal<i8>,
+        receiver: mpsc::Receiver<event::ClientEvent>,
+    ) -> anyhow::Result<()> {
+        let vhttp_listener = self.bind().await?;
+        self.serve(Some(vhttp_listener), shutdown, receiver).await
+    }
+
+    pub(crate) fn vhttp_addr(&self) -> SocketAddr {
+        self.vhttp_addr
+    }
+
+    /// bind the vhttp port, it's served by [`DataServer::serve`].
+    pub(crate) async fn bind(&self) -> Result<TcpListener, Status> {
+        create_tcp_listener_on(self.vhttp_addr).await
+    }
+
+    /// serve the events of the registrations, None runs without the vhttp server,
+    /// the http tunnels of the domains and subdomains are rejected then.
+    pub(crate) async fn serve(
+        mut self,
+        vhttp_listener: Option<TcpListener>,
+        shutdown: ShutdownSignal<i8>,
         mut receiver: mpsc::Receiver<event::ClientEvent>,
     ) -> anyhow::Result<()> {
+        self.vhttp_serving = vhttp_listener.is_some();
         let this = Arc::new(self);
 
         // start the vhttp tunnel, all the http requests to the vhttp server(with the vhttp_port)
@@ -71,10 +175,16 @@ impl DataServer {
             cancel_w.cancel();
         });
 
-        let http_tunnel = Http::new(Arc::new(Box::new(this.http_registry.clone())));
-        let tcp_listener = create_tcp_listener(this.vhttp_port).await?;
-        tokio::spawn(async move {
-            http_tunnel.serve_with_listener(tcp_listener, cancel).await;
+        // the external base path is of the vhttp server, the tunnels of their own ports are reached directly
+        let http_tunnel = this
+            .http_tunnel(this.http_registry.clone())
+            .with_base_path(this.current_settings().entrypoint.external_base_path);
+        let vhttp = vhttp_listener.map(|vhttp_listener| {
+            tokio::spawn(async move {
+                http_tunnel
+                    .serve_with_listener(vhttp_listener, cancel)
+                    .await;
+            })
         });
 
         let since_the_epoch = SystemTime::now()
@@ -93,64 +203,94 @@ impl DataServer {
                         Some(event) => event,
                         None => break,
                     };
+                    let settings = this.current_settings();
+                    let span = info_span!("tunnel", name = %event.tunnel_name);
                     match event.payload {
-                        event::Payload::RegisterTcp { port } => {
+                        event::Payload::RegisterTcp { port, first_byte_timeout, max_chunk_size } => {
                             let result: Result<(Available, TcpListener), tonic::Status> =
-                                create_socket::<Tcp>(port, &mut this.port_manager.clone()).await;
+                                create_socket::<Tcp>(bind_ip(event.loopback), port, &mut this.port_manager.clone()).await;
                             match result {
                                 Ok((available_port, listener)) => {
                                     let cancel = event.close_listener;
+                                    let released = event.released;
                                     let conn_event_chan = event.incoming_events;
-                                    event
-                                        .resp
-                                        .send(ClientEventResponse::registered(
-                                            this.entrypoint_config
-                                                .make_entrypoint(&event.payload, *available_port),
-                                        ))
-                                        .unwrap(); // success
+                                    let entrypoint = make_entrypoint(
+                                        &settings.entrypoint,
+                                        &event.payload,
+                                        *available_port,
+                                        event.loopback,
+                                    );
+                                    let data_chunk_size = protocol::negotiate_chunk_size(settings.data_chunk_size, max_chunk_size);
+                                    let resp = ClientEventResponse::registered(entrypoint, *available_port).with_max_chunk_size(data_chunk_size);
+                                    if !ack(event.resp, resp) {
+                                        // the listener and the port are released right away
+                                        continue;
+                                    }
+                                    let connection_setups = Arc::clone(&this.connection_setups);
+                                    let memory_budget = this.memory_budget.clone();
                                     spawn(async move {
-                                        Tcp::new(listener, conn_event_chan.clone())
+                                        Tcp::new(listener, conn_event_chan.clone(), connection_setups)
+                                            .with_tcp_keepalive(settings.tcp_keepalive)
+                                            .with_max_in_flight_bytes(settings.max_in_flight_bytes)
+                                            .with_data_chunk_size(data_chunk_size)
+                                            .with_memory_budget(memory_budget)
+                                            .with_first_byte_timeout(first_byte_timeout)
+                                            .with_close_timeout(settings.close_timeout)
                                             .serve(cancel)
                                             .await;
                                         info!(port = *available_port, "tcp server closed");
-                                    });
+                                        // the listener is dropped by now
+                                        drop(available_port);
+                                        drop(released);
+                                    }.instrument(span));
                                 }
                                 Err(status) => {
-                                    event
-                                        .resp
-                                        .send(ClientEventResponse::registered_failed(status))
-                                        .unwrap();
+                                    ack(event.resp, ClientEventResponse::registered_failed(status));
                                 }
                             }
                         }
-                        event::Payload::RegisterUdp { port } => {
+                        event::Payload::RegisterUdp { port, compressed, max_datagram_size } => {
                             let result: Result<(Available, UdpSocket), tonic::Status> =
-                                create_socket::<Udp>(port, &mut this.port_manager.clone()).await;
+                                create_socket::<Udp>(bind_ip(event.loopback), port, &mut this.port_manager.clone()).await;
 
                             match result {
                                 Ok((available_port, socket)) => {
                                     let socket = socket;
                                     let cancel = event.close_listener;
+                                    let released = event.released;
                                     let conn_event_chan = event.incoming_events;
-                                    event
-                                        .resp
-                                        .send(ClientEventResponse::registered(
-                                            this.entrypoint_config
-                                                .make_entrypoint(&event.payload, *available_port),
-                                        ))
-                                        .unwrap(); // success
+                                    let entrypoint = make_entrypoint(
+                                        &settings.entrypoint,
+                                        &event.payload,
+                                        *available_port,
+                                        event.loopback,
+                                    );
+                                    if !ack(event.resp, ClientEventResponse::registered(entrypoint, *available_port)) {
+                                        // the listener and the port are released right away
+                                        continue;
+                                    }
+                                    let memory_budget = this.memory_budget.clone();
+                                    let sessions = this.udp_sessions.clone();
                                     spawn(async move {
                                         Udp::new(socket, conn_event_chan.clone())
+                                            .with_max_in_flight_bytes(settings.max_in_flight_bytes)
+                                            .with_memory_budget(memory_budget)
+                                            .with_max_sessions(settings.max_udp_sessions)
+                                            .with_idle_timeout(settings.udp_idle_timeout)
+                                            .with_buffer_pool(settings.udp_buffer_pool)
+                                            .with_compression(compressed)
+                                            .with_max_datagram_size(max_datagram_size)
+                                            .with_sessions(sessions)
                                             .serve(cancel)
                                             .await;
                                         info!(port = *available_port, "udp server closed");
-                                    });
+                                        // the socket is dropped by now
+                                        drop(available_port);
+                                        drop(released);
+                                    }.instrument(span));
                                 }
                                 Err(status) => {
-                                    event
-                                        .resp
-                                        .send(ClientEventResponse::registered_failed(status))
-                                        .unwrap();
+                                    ack(event.resp, ClientEventResponse::registered_failed(status));
                                 }
                             }
                         }
@@ -159,51 +299,79 @@ impl DataServer {
                             mut subdomain,
                             domain,
                             random_subdomain,
+                            path_prefix,
+                            mut options,
                         } => {
+                            // the scheme of the entrypoint, see `EntrypointConfig::make_entrypoint`.
+                            options.https = settings.entrypoint.vhttp_behind_proxy_tls
+                                && (!domain.is_empty() || !subdomain.is_empty() || random_subdomain);
+                            // the limits of the server cap the ones of the tunnel
+                            options.max_request_body =
+                                min_limit(options.max_request_body, settings.max_request_body);
+                            options.max_response_body =
+                                min_limit(options.max_response_body, settings.max_response_body);
                             let subdomain_c = subdomain.clone();
                             let domain_c = domain.clone();
                             let domain_c2 = domain.clone();
+                            let incoming_events = event.incoming_events.clone();
                             let resp_status = shutdown
                                 .wrap_cancel(this.register_http(
                                     event.close_listener.clone(),
+                                    event.released.clone(),
                                     domain,
                                     &mut subdomain,
                                     random_subdomain,
+                                    &path_prefix,
                                     &mut port,
-                                    event.incoming_events,
+                                    event.loopback,
+                                    HttpTarget::new(event.incoming_events, options.clone()),
                                     &mut rng,
-                                ))
+                                ).instrument(span))
                                 .await;
                             let this = Arc::clone(&this);
                             if let Ok(Some(status)) = resp_status {
-                                event
-                                    .resp
-                                    .send(ClientEventResponse::registered_failed(status))
-                                    .unwrap();
+                                ack(event.resp, ClientEventResponse::registered_failed(status));
                             } else {
                                 // means register successfully
                                 // listen the close_listener to cancel the unregister domain/subdomain.
+                                // the tunnel routed by the domain or subdomain has no port of its own
+                                let remote_port = if subdomain.is_empty() && domain_c2.is_empty() { port } else { 0 };
+                                let assigned_subdomain = subdomain.clone();
                                 let payload = Payload::RegisterHttp {
                                     port,
                                     subdomain,
                                     domain: domain_c2,
                                     random_subdomain,
+                                    path_prefix: path_prefix.clone(),
+                                    options,
+                                };
+                                let entrypoint = if event.loopback {
+                                    settings.entrypoint.make_loopback_entrypoint(&payload, port)
+                                } else {
+                                    this.routable_entrypoint(
+                                        &payload,
+                                        settings.entrypoint.make_entrypoint(&payload, port),
+                                        &incoming_events,
+                                    )
                                 };
-                                event
-                                    .resp
-                                    .send(ClientEventResponse::registered(
-                                        this.entrypoint_config.make_entrypoint(&payload, port),
-                                    ))
-                                    .unwrap();
+                                let resp = ClientEventResponse::registered(entrypoint, remote_port)
+                                    .with_subdomain(assigned_subdomain);
+                                if !ack(event.resp, resp) {
+                                    // unregister the domain and close the listener of the port below
+                                    event.close_listener.cancel();
+                                }
 
                                 tokio::spawn(async move {
                                     event.close_listener.cancelled().await;
                                     if !subdomain_c.is_empty() {
-                                        this.http_registry.unregister_subdomain(subdomain_c);
+                                        this.http_registry
+                                            .unregister_subdomain(subdomain_c, &path_prefix);
                                     }
                                     if !domain_c.is_empty() {
-                                        this.http_registry.unregister_domain(domain_c);
+                                        this.http_registry
+                                            .unregister_domain(domain_c, &path_prefix);
                                     }
+                                    drop(event.released);
                                 });
                             }
                         }
@@ -212,95 +380,206 @@ impl DataServer {
             }
         }
 
+        // the vhttp port is released before quitting, so it can be bound again, e.g. by a restart
+        if let Some(vhttp) = vhttp {
+            let _ = vhttp.await;
+        }
         info!("data server quit");
         Ok(())
     }
 
+    fn http_tunnel(&self, lookup: impl LookupRequest + 'static) -> Http {
+        let settings = self.current_settings();
+        Http::new(
+            Arc::new(Box::new(lookup)),
+            Arc::clone(&self.connection_setups),
+        )
+        .with_tcp_keepalive(settings.tcp_keepalive)
+        .with_max_in_flight_bytes(settings.max_in_flight_bytes)
+        .with_max_request_deadline(settings.max_request_deadline)
+        .with_memory_budget(self.memory_budget.clone())
+        .with_cache(self.http_cache.clone())
+        .with_interstitial(self.interstitial.clone())
+    }
+
     #[allow(clippy::too_many_arguments)]
     async fn register_http(
         &self,
         shutdown: CancellationToken,
+        released: mpsc::Sender<()>,
         domain: Bytes,
         subdomain: &mut Bytes,
         random_subdomain: bool,
+        path_prefix: &str,
         port: &mut u16,
-        conn_event_chan: mpsc::Sender<event::UserIncoming>,
+        loopback: bool,
+        target: HttpTarget,
         rng: &mut StdRng,
     ) -> Option<Status> {
+        if (!domain.is_empty() || !subdomain.is_empty() || random_subdomain) && !self.vhttp_serving
+        {
+            return Some(Status::failed_precondition(
+                "the vhttp server isn't running, register with a port instead of a domain or subdomain",
+            ));
+        }
         if !domain.is_empty() {
             // forward the http request from this domain to control server.
-            if self.http_registry.domain_registered(&domain) {
+            if self.http_registry.domain_registered(&domain, path_prefix) {
                 return Some(Status::already_exists("domain already registered"));
             }
             self.http_registry
-                .register_domain(domain, conn_event_chan.clone());
+                .register_domain(domain, path_prefix, target);
             return None;
         }
 
+        if (!subdomain.is_empty() || random_subdomain)
+            && self.current_settings().entrypoint.domain.is_empty()
+        {
+            // the subdomain is served under the domains of the server.
+            return Some(Status::failed_precondition(
+                "the server has no domains configured, register with a custom domain or a port instead of a subdomain",
+            ));
+        }
+
         if subdomain.is_empty() && random_subdomain {
-            loop {
-                let subdomain2 = Bytes::from(self.generate_random_subdomain(8, rng).await);
-                if !self.http_registry.subdomain_registered(&subdomain2) {
-                    *subdomain = subdomain2;
-                    break;
-                }
+            let words = self.current_settings().entrypoint.subdomain_words;
+            let available = (0..RANDOM_SUBDOMAIN_ATTEMPTS)
+                .map(|_| Bytes::from(subdomain::random_subdomain(words.as_ref(), rng)))
+                .find(|subdomain| !self.http_registry.subdomain_in_use(subdomain));
+            match available {
+                Some(available) => *subdomain = available,
+                // e.g. a small word list is used up
+                None => return Some(Status::resource_exhausted("no available random subdomain")),
             }
             info!(?subdomain, "random subdomain generated");
         }
 
         if !subdomain.is_empty() {
             // forward the http request from this subdomain to control server.
-            if self.http_registry.subdomain_registered(subdomain) {
+            if self
+                .http_registry
+                .subdomain_registered(subdomain, path_prefix)
+            {
                 return Some(Status::already_exists("subdomain already registered"));
             }
 
-            info!("subdomain registered: {:?}", subdomain);
+            info!(path_prefix, "subdomain registered: {:?}", subdomain);
             self.http_registry
-                .register_subdomain(subdomain.clone(), conn_event_chan);
+                .register_subdomain(subdomain.clone(), path_prefix, target);
             return None;
         }
 
-        if *port != 0 {
-            match create_socket::<Tcp>(*port, &mut self.port_manager.clone()).await {
-                Ok((available_port, listener)) => {
-                    spawn(async move {
+        // neither a domain nor a subdomain, the tunnel is served by its own port(random if 0),
+        // it's allocated by the port manager like the tcp tunnels.
+        match create_socket::<Tcp>(bind_ip(loopback), *port, &mut self.port_manager.clone()).await {
+            Ok((available_port, listener)) => {
+                *port = *available_port;
+                let http_tunnel = self.http_tunnel(FixedRegistry::new(target));
+                spawn(
+                    async move {
                         info!(port = *available_port, "http server started");
-                        Http::new(Arc::new(Box::new(FixedRegistry::new(conn_event_chan))))
-                            .serve_with_listener(listener, shutdown)
-                            .await;
-                    });
-                    None
-                }
-                Err(status) => Some(status),
-            }
-        } else {
-            let result = create_socket::<Tcp>(*port, &mut self.port_manager.clone()).await;
-            match result {
-                Ok((available_port, listener)) => {
-                    *port = *available_port;
-                    let conn_event_chan = conn_event_chan.clone();
-                    spawn(async move {
-                        Http::new(Arc::new(Box::new(FixedRegistry::new(conn_event_chan))))
-                            .serve_with_listener(listener, shutdown)
-                            .await;
+                        http_tunnel.serve_with_listener(listener, shutdown).await;
                         drop(available_port);
-                    });
-                    None
-                }
-                Err(err) => Some(err),
+                        drop(released);
+                    }
+                    .in_current_span(),
+                );
+                None
             }
+            Err(status) => Some(status),
         }
     }
 
-    async fn generate_random_subdomain(&self, length: usize, rng: &mut StdRng) -> String {
-        static CHARSET: &[u8] = b"abcdefghijklmnopqrstuvwxyz0123456789";
-        let subdomain: String = (0..length)
-            .map(|_| {
-                let idx: usize = rng.gen_range(0..CHARSET.len());
-                CHARSET[idx] as char
+    /// keep the entrypoints that the vhttp server routes to the tunnel,
+    /// e.g. `bar.example.com` of the subdomain `bar` is shadowed if another tunnel
+    /// has registered it as a custom domain.
+    fn routable_entrypoint(
+        &self,
+        payload: &Payload,
+        entrypoint: Vec<String>,
+        sender: &mpsc::Sender<event::UserIncoming>,
+    ) -> Vec<String> {
+        let Payload::RegisterHttp {
+            subdomain, domain, ..
+        } = payload
+        else {
+            return entrypoint;
+        };
+        if subdomain.is_empty() && domain.is_empty() {
+            // the tunnel is served by its own port.
+            return entrypoint;
+        }
+
+        entrypoint
+            .into_iter()
+            .filter(|entrypoint| {
+                let host = entrypoint
+                    .split_once("://")
+                    .map_or(entrypoint.as_str(), |(_, host)| host);
+                // the path prefix of the tunnel follows the host
+                let (host, path) = host
+                    .find('/')
+                    .map_or((host, "/"), |slash| host.split_at(slash));
+                let routable = self
+                    .http_registry
+                    .route(host, path)
+                    .is_some_and(|routed| routed.sender.same_channel(sender));
+                if !routable {
+                    warn!(
+                        entrypoint,
+                        "entrypoint isn't routable to the tunnel, skip it"
+                    );
+                }
+                routable
             })
-            .collect();
-        subdomain
+            .collect()
+    }
+}
+
+/// the ip the listener of the tunnel is bound to, see [`event::ClientEvent::loopback`].
+fn bind_ip(loopback: bool) -> IpAddr {
+    if loopback {
+        IpAddr::from(Ipv4Addr::LOCALHOST)
+    } else {
+        IpAddr::from(Ipv4Addr::UNSPECIFIED)
+    }
+}
+
+/// the entrypoint of the tcp and udp tunnels, the loopback ones are only reachable on the server.
+fn make_entrypoint(
+    entrypoint: &EntrypointConfig,
+    payload: &Payload,
+    port: u16,
+    loopback: bool,
+) -> Vec<String> {
+    if loopback {
+        entrypoint.make_loopback_entrypoint(payload, port)
+    } else {
+        entrypoint.make_entrypoint(payload, port)
+    }
+}
+
+/// send the response of the registration to the control server,
+/// false if it's gone, e.g. the client disconnected during the registration,
+/// the caller releases what's just registered then.
+fn ack(resp: oneshot::Sender<ClientEventResponse>, response: ClientEventResponse) -> bool {
+    match resp.send(response) {
+        Ok(()) => true,
+        Err(response) => {
+            warn!(
+                ?response,
+                "the registration is abandoned by the control server, release it"
+            );
+            false
+        }
+    }
+}
+
+/// the smaller one of the limits, None means no limit.
+fn min_limit(a: Option<u64>, b: Option<u64>) -> Option<u64> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
     }
 }
 
@@ -315,17 +594,51 @@ mod test {
 
     #[tokio::test]
     async fn test_cannot_listen_on_same_vhttp_port() {
-        // debug::setup_logging(6669); // enable for debug
+        // debug::setup_logging(6669, None); // enable for debug
         let (_t1, r1) = mpsc::channel(10);
         let shutdown1 = ShutdownManager::new();
         let signal1 = shutdown1.wait_shutdown_triggered();
-        let s1 = DataServer::new(3100, EntrypointConfig::default());
+        let s1 = DataServer::new(
+            SocketAddr::from(([0, 0, 0, 0], 3100)),
+            EntrypointConfig::default(),
+            None,
+            1,
+            1024,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            0,
+            None,
+            None,
+            None,
+            None,
+        );
         let h1 = tokio::spawn(async move { s1.listen(signal1, r1).await });
 
         sleep(std::time::Duration::from_millis(10)).await;
 
         let (_t2, r2) = mpsc::channel(10);
-        let s2 = DataServer::new(3100, EntrypointConfig::default());
+        let s2 = DataServer::new(
+            SocketAddr::from(([0, 0, 0, 0], 3100)),
+            EntrypointConfig::default(),
+            None,
+            1,
+            1024,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            0,
+            None,
+            None,
+            None,
+            None,
+        );
         let shutdown2 = ShutdownManager::new();
         let h2 = s2.listen(shutdown2.wait_shutdown_triggered(), r2).await;
         assert!(h2.is_err());
@@ -334,4 +647,182 @@ mod test {
         assert!(h1.0.is_ok());
         shutdown2.trigger_shutdown(0).unwrap();
     }
+
+    #[tokio::test]
+    async fn test_vhttp_bind_ip() {
+        let (_tx, rx) = mpsc::channel(10);
+        let shutdown = ShutdownManager::new();
+        let server = DataServer::new(
+            SocketAddr::from(([127, 0, 0, 1], 3102)),
+            EntrypointConfig::default(),
+            None,
+            1,
+            1024,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            0,
+            None,
+            None,
+            None,
+            None,
+        );
+        let handle = tokio::spawn(server.listen(shutdown.wait_shutdown_triggered(), rx));
+        sleep(std::time::Duration::from_millis(10)).await;
+
+        assert!(tokio::net::TcpStream::connect("127.0.0.1:3102")
+            .await
+            .is_ok());
+        // the port of the other interfaces is still free
+        assert!(TcpListener::bind("127.0.0.2:3102").await.is_ok());
+
+        shutdown.trigger_shutdown(0).unwrap();
+        assert!(handle.await.unwrap().is_ok());
+    }
+
+    #[test]
+    fn test_routable_entrypoint() {
+        let server = DataServer::new(
+            SocketAddr::from(([0, 0, 0, 0], 3101)),
+            EntrypointConfig {
+                domain: vec!["example.com".to_string(), "example.org".to_string()],
+                ..Default::default()
+            },
+            None,
+            1,
+            1024,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            0,
+            None,
+            None,
+            None,
+            None,
+        );
+        let (bar, _r1) = mpsc::channel(1);
+        let (custom, _r2) = mpsc::channel(1);
+        server
+            .http_registry
+            .register_subdomain(Bytes::from_static(b"bar"), "", bar.clone());
+        // shadows the subdomain bar on example.org
+        server
+            .http_registry
+            .register_domain(Bytes::from_static(b"bar.example.org"), "", custom);
+
+        let payload = Payload::RegisterHttp {
+            port: 0,
+            subdomain: Bytes::from_static(b"bar"),
+            domain: Bytes::new(),
+            random_subdomain: false,
+            path_prefix: String::new(),
+            options: Default::default(),
+        };
+        let entrypoint = server
+            .current_settings()
+            .entrypoint
+            .make_entrypoint(&payload, 0);
+        assert_eq!(
+            entrypoint,
+            vec!["http://bar.example.com", "http://bar.example.org"]
+        );
+        assert_eq!(
+            server.routable_entrypoint(&payload, entrypoint, &bar),
+            vec!["http://bar.example.com"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_abandoned_registration() {
+        let (events, rx) = mpsc::channel(10);
+        let shutdown = ShutdownManager::new();
+        let server = DataServer::new(
+            SocketAddr::from(([127, 0, 0, 1], 3103)),
+            EntrypointConfig::default(),
+            None,
+            1,
+            1024,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            0,
+            None,
+            None,
+            None,
+            None,
+        );
+        let handle = tokio::spawn(server.listen(shutdown.wait_shutdown_triggered(), rx));
+
+        let register = |payload: Payload| {
+            let (resp, resp_rx) = oneshot::channel();
+            let (released, _) = mpsc::channel(1);
+            let (incoming_events, _) = mpsc::channel(1);
+            let event = event::ClientEvent {
+                tunnel_name: "test".to_string(),
+                payload,
+                loopback: false,
+                resp,
+                close_listener: CancellationToken::new(),
+                released,
+                incoming_events,
+            };
+            (event, resp_rx)
+        };
+        let port = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let domain = Payload::RegisterHttp {
+            port: 0,
+            subdomain: Bytes::new(),
+            domain: Bytes::from_static(b"example.com"),
+            random_subdomain: false,
+            path_prefix: String::new(),
+            options: Default::default(),
+        };
+
+        // the client disconnected before the ack
+        for payload in [
+            Payload::RegisterTcp {
+                port,
+                first_byte_timeout: None,
+                max_chunk_size: None,
+            },
+            domain.clone(),
+        ] {
+            let (event, resp_rx) = register(payload);
+            drop(resp_rx);
+            events.send(event).await.unwrap();
+        }
+        sleep(std::time::Duration::from_millis(50)).await;
+
+        // the port and the domain are released, the server keeps serving
+        for payload in [
+            Payload::RegisterTcp {
+                port,
+                first_byte_timeout: None,
+                max_chunk_size: None,
+            },
+            domain,
+        ] {
+            let (event, resp_rx) = register(payload);
+            events.send(event).await.unwrap();
+            let ClientEventResponse::Registered { status, .. } = resp_rx.await.unwrap();
+            assert!(status.is_none(), "{:?}", status);
+        }
+
+        shutdown.trigger_shutdown(0).unwrap();
+        assert!(handle.await.unwrap().is_ok());
+    }
 }
@@ -0,0 +1,120 @@
+//! The hooks of the tunnel lifecycle, see [`super::Server::on_tunnel_open`]
+//! and [`super::Server::on_tunnel_close`] for the ordering and the errors.
+use std::{future::Future, pin::Pin, sync::Arc};
+
+use tracing::warn;
+
+use super::registry::TunnelInfo;
+
+type Hook = Arc<
+    dyn Fn(TunnelInfo) -> Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send>> + Send + Sync,
+>;
+
+#[derive(Clone, Default)]
+pub(crate) struct Hooks {
+    open: Vec<Hook>,
+    close: Vec<Hook>,
+}
+
+impl Hooks {
+    pub(crate) fn add_open<F, Fut>(&mut self, hook: F)
+    where
+        F: Fn(TunnelInfo) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        self.open
+            .push(Arc::new(move |tunnel| Box::pin(hook(tunnel))));
+    }
+
+    pub(crate) fn add_close<F, Fut>(&mut self, hook: F)
+    where
+        F: Fn(TunnelInfo) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        self.close
+            .push(Arc::new(move |tunnel| Box::pin(hook(tunnel))));
+    }
+
+    /// the error of the first failed hook.
+    pub(crate) async fn open(&self, tunnel: &TunnelInfo) -> anyhow::Result<()> {
+        for hook in &self.open {
+            hook(tunnel.clone()).await?;
+        }
+        Ok(())
+    }
+
+    pub(crate) async fn close(&self, tunnel: &TunnelInfo) {
+        for hook in &self.close {
+            if let Err(err) = hook(tunnel.clone()).await {
+                warn!(tunnel_id = tunnel.id, ?err, "the tunnel close hook failed");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_hooks() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let mut hooks = Hooks::default();
+        for (i, reject) in [(1, false), (2, true), (3, false)] {
+            let open_calls = Arc::clone(&calls);
+            hooks.add_open(move |tunnel: TunnelInfo| {
+                let calls = Arc::clone(&open_calls);
+                async move {
+                    calls
+                        .lock()
+                        .unwrap()
+                        .push(format!("open{} {}", i, tunnel.name));
+                    if reject && tunnel.name == "rejected" {
+                        anyhow::bail!("rejected by hook {}", i);
+                    }
+                    Ok(())
+                }
+            });
+            let close_calls = Arc::clone(&calls);
+            hooks.add_close(move |tunnel: TunnelInfo| {
+                let calls = Arc::clone(&close_calls);
+                async move {
+                    calls
+                        .lock()
+                        .unwrap()
+                        .push(format!("close{} {}", i, tunnel.name));
+                    anyhow::ensure!(i != 1, "failed to close");
+                    Ok(())
+                }
+            });
+        }
+        let tunnel = |name: &str| TunnelInfo {
+            id: "1".to_string(),
+            name: name.to_string(),
+            kind: "tcp",
+            entrypoint: vec![],
+            labels: Default::default(),
+        };
+
+        assert!(hooks.open(&tunnel("test")).await.is_ok());
+        // the failed close hook doesn't stop the rest
+        hooks.close(&tunnel("test")).await;
+        let err = hooks.open(&tunnel("rejected")).await.unwrap_err();
+        assert_eq!(err.to_string(), "rejected by hook 2");
+        assert_eq!(
+            *calls.lock().unwrap(),
+            [
+                "open1 test",
+                "open2 test",
+                "open3 test",
+                "close1 test",
+                "close2 test",
+                "close3 test",
+                "open1 rejected",
+                "open2 rejected",
+            ]
+        );
+    }
+}
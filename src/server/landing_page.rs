@@ -0,0 +1,85 @@
+//! A friendly page for the plain http requests to the control port.
+//!
+//! The control port speaks grpc, which is confusing when someone opens it in a browser.
+//! The control server accepts http/1.1 besides h2 (hyper sniffs the connection preface),
+//! this layer answers the non-grpc requests with a small html page,
+//! the grpc requests are passed through untouched.
+use std::task::{Context, Poll};
+
+use futures::future::{self, Either, Ready};
+use tonic::{
+    body::BoxBody,
+    codegen::{
+        http::{header, HeaderValue, Request, Response},
+        Body as _,
+    },
+    transport::Body,
+    Status,
+};
+use tower::{Layer, Service};
+
+use crate::constant;
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct LandingPageLayer;
+
+impl<S> Layer<S> for LandingPageLayer {
+    type Service = LandingPage<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        LandingPage { inner }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct LandingPage<S> {
+    inner: S,
+}
+
+impl<S> Service<Request<Body>> for LandingPage<S>
+where
+    S: Service<Request<Body>, Response = Response<BoxBody>>,
+{
+    type Response = Response<BoxBody>;
+    type Error = S::Error;
+    type Future = Either<S::Future, Ready<Result<Self::Response, Self::Error>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        if is_grpc(&req) {
+            Either::Left(self.inner.call(req))
+        } else {
+            Either::Right(future::ok(landing_page()))
+        }
+    }
+}
+
+pub(crate) fn is_grpc<B>(req: &Request<B>) -> bool {
+    req.headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/grpc"))
+}
+
+fn landing_page() -> Response<BoxBody> {
+    let html = format!(
+        "<!DOCTYPE html>\n<html>\n<head><title>castle</title></head>\n<body>\n\
+         <h1>This is a castle control endpoint</h1>\n\
+         <p>Use the castle client to register tunnels on this port.</p>\n\
+         <p>version: {}</p>\n</body>\n</html>\n",
+        constant::VERSION,
+    );
+    let mut response = Response::new(
+        Body::from(html)
+            .map_err(|err| Status::internal(err.to_string()))
+            .boxed_unsync(),
+    );
+    response.headers_mut().insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("text/html; charset=utf-8"),
+    );
+    response
+}
@@ -0,0 +1,66 @@
+//! The sampling of the logs of the user connections, so a busy tunnel logs
+//! a representative share of its connections instead of drowning the other logs.
+//!
+//! Each tunnel logs 1 in `connection_log_sample` of its connections, starting from the first one,
+//! the close of a connection is logged only if its open is.
+use std::collections::HashSet;
+
+use bytes::Bytes;
+
+/// ConnectionLogSampler decides which connections of a tunnel are logged,
+/// it's owned by the control stream of the tunnel.
+#[derive(Debug)]
+pub(crate) struct ConnectionLogSampler {
+    every: u64,
+    opened: u64,
+    /// the logged connections still open.
+    sampled: HashSet<Bytes>,
+}
+
+impl ConnectionLogSampler {
+    /// None logs every connection, the rate is at least 1.
+    pub(crate) fn new(every: Option<u32>) -> Self {
+        Self {
+            every: u64::from(every.unwrap_or(1).max(1)),
+            opened: 0,
+            sampled: HashSet::new(),
+        }
+    }
+
+    /// true if the open of the connection is logged.
+    pub(crate) fn open(&mut self, id: &Bytes) -> bool {
+        let sampled = self.opened.is_multiple_of(self.every);
+        self.opened += 1;
+        if sampled && self.every > 1 {
+            self.sampled.insert(id.clone());
+        }
+        sampled
+    }
+
+    /// true if the close of the connection is logged.
+    pub(crate) fn close(&mut self, id: &Bytes) -> bool {
+        self.every == 1 || self.sampled.remove(id)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_connection_log_sampler() {
+        let ids: Vec<Bytes> = (0..7).map(|i| Bytes::from(i.to_string())).collect();
+        let mut sampler = ConnectionLogSampler::new(Some(3));
+        let opened: Vec<bool> = ids.iter().map(|id| sampler.open(id)).collect();
+        assert_eq!(opened, [true, false, false, true, false, false, true]);
+        let closed: Vec<bool> = ids.iter().map(|id| sampler.close(id)).collect();
+        assert_eq!(closed, opened);
+        // closed once
+        assert!(!sampler.close(&ids[0]));
+
+        let mut sampler = ConnectionLogSampler::new(None);
+        assert!(ids.iter().all(|id| sampler.open(id)));
+        assert!(ids.iter().all(|id| sampler.close(id)));
+        assert!(ConnectionLogSampler::new(Some(0)).open(&ids[0]));
+    }
+}
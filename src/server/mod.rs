@@ -1,31 +1,235 @@
+mod admin;
+mod admission;
+mod bandwidth;
+mod connection;
 mod control_server;
 mod data_server;
+mod hooks;
+mod landing_page;
+mod log_sampler;
+mod openapi;
 mod port;
+mod probe;
+mod rate_limit;
+mod registration_limit;
+mod registry;
+mod reload;
+mod subdomain;
+mod tls;
 mod tunnel;
+pub use bandwidth::TunnelWeight;
 pub use control_server::Server;
+pub use registry::TunnelInfo;
+pub use reload::{Reloaded, Reloader};
+pub use subdomain::SubdomainWords;
+pub use tls::ControlTls;
+pub(crate) use tunnel::headers::HeaderRules;
 
 use std::net::IpAddr;
 use std::ops::RangeInclusive;
+use std::time::Duration;
 
-use crate::event;
+use crate::{constant, crypto::PayloadKey, event, socket::TcpKeepalive};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Config {
     /// control_port is the port of the control server.
     ///
     /// the client will connect to this port to register a tunnel.
     pub control_port: u16,
     pub vhttp_port: u16,
+    /// vhttp_bind_ip is the address the vhttp server listens on, e.g. `127.0.0.1`
+    /// when it's only reached through a local reverse proxy.
+    ///
+    /// it's `0.0.0.0` by default, the listeners of the tunnels are not affected.
+    pub vhttp_bind_ip: IpAddr,
+    /// vhttp_optional keeps the server running without the vhttp server if the vhttp port
+    /// can't be bound, e.g. it's taken, the http tunnels of the domains and subdomains
+    /// are rejected with `FAILED_PRECONDITION` then, the rest of the tunnels work.
+    ///
+    /// the server fails to start by default, it always fails without the control port.
+    pub vhttp_optional: bool,
     pub entrypoint: EntrypointConfig,
+    /// tcp_keepalive enables the OS-level keepalive on the accepted user connections.
+    pub tcp_keepalive: Option<TcpKeepalive>,
+    /// max_pending_connections limits the user connections waiting for the client
+    /// to set up the data streams, the new connections wait when it's reached,
+    /// the http requests still waiting after a second get 429 with `Retry-After`.
+    ///
+    /// it's shared by all the tunnels of the server, at least 1.
+    pub max_pending_connections: usize,
+    /// max_pending_registrations limits the registrations waiting for the entrypoints
+    /// and the open hooks, the registrations beyond it are rejected with `UNAVAILABLE`
+    /// right away, the clients retry them later.
+    ///
+    /// it's at least 1, it's the capacity of the queue of the registrations to the data server as well,
+    /// the rejected and the queued ones are reported by `GET /admin/stats`.
+    pub max_pending_registrations: usize,
+    /// max_in_flight_bytes caps the bytes sent by the client but not written to
+    /// the user connection yet, the server pauses reading the data stream of the client
+    /// when it's reached, so a slow user doesn't make the data pile up in the memory.
+    ///
+    /// it applies to each user connection, at least 1.
+    pub max_in_flight_bytes: usize,
+    /// data_chunk_size caps the bytes of a message sent to the client for the tcp tunnels,
+    /// the larger reads of the user connections are split and the smaller ones are coalesced
+    /// until the user pauses.
+    /// the larger chunks cost less per byte, the smaller ones delay the other connections
+    /// of the client less.
+    /// a client asking for a smaller `max_chunk_size` lowers it for its tunnel.
+    /// the chunks larger than 8KiB are sent as they are, e.g. 64KiB.
+    ///
+    /// None sends what's read at once, at most 8KiB.
+    pub data_chunk_size: Option<usize>,
+    /// max_request_deadline lets the users of the http tunnels shorten the timeout of their requests
+    /// with the `X-Tunnel-Deadline` header in the `grpc-timeout` format, e.g. `500m` or `10S`,
+    /// the deadlines longer than it are capped, 504 is returned when it's exceeded.
+    /// the timeout of the tunnel still applies if it's shorter.
+    ///
+    /// None ignores the header.
+    pub max_request_deadline: Option<Duration>,
+    /// max_request_body caps the bytes of the request bodies of the http tunnels,
+    /// the larger ones get 413 Payload Too Large, the bodies are counted as they flow.
+    /// the limit of the tunnel still applies if it's smaller.
+    ///
+    /// None means unlimited.
+    pub max_request_body: Option<u64>,
+    /// max_response_body caps the bytes of the response bodies of the http tunnels,
+    /// the larger ones get 502 before the headers are sent, or are aborted while streaming.
+    /// the limit of the tunnel still applies if it's smaller.
+    ///
+    /// None means unlimited.
+    pub max_response_body: Option<u64>,
+    /// max_udp_sessions caps the sessions of each udp tunnel, a session is the datagrams of a peer,
+    /// the least recently used one is evicted for a new peer, so the spoofed source addresses
+    /// can't pile up the sessions. the evictions are reported by `GET /admin/stats`.
+    ///
+    /// None means unlimited.
+    pub max_udp_sessions: Option<usize>,
+    /// udp_idle_timeout closes the udp sessions without any datagram in either direction for it,
+    /// the bridge to the client is removed, the next datagram of the peer opens a new session.
+    ///
+    /// None keeps them until they are evicted or the tunnel closes.
+    pub udp_idle_timeout: Option<Duration>,
+    /// udp_buffer_pool is the bytes of the buffer each udp tunnel reads the datagrams into,
+    /// a new one is allocated when it's used up, so the datagrams don't allocate one by one.
+    /// it's at least a max datagram, the buffers are held until their datagrams are sent.
+    ///
+    /// 0 reads each datagram into its own buffer.
+    pub udp_buffer_pool: usize,
+    /// memory_budget caps the bytes in flight of all the user connections, see `max_in_flight_bytes`,
+    /// the new connections wait and the datagrams of the new udp peers are dropped when it's reached,
+    /// the http requests get 429 like `max_pending_connections`.
+    ///
+    /// None means unlimited, the usage is reported by `GET /admin/stats` anyway.
+    pub memory_budget: Option<usize>,
+    /// http_cache caps the bytes of the GET responses cached by the http tunnels,
+    /// the responses are cached as `Cache-Control` allows.
+    ///
+    /// None disables the cache.
+    pub http_cache: Option<usize>,
+    /// interstitial_page is the html shown by the http tunnels with the interstitial enabled,
+    /// `{{host}}` and `{{continue_url}}` in it are replaced by the host and the link to pass it.
+    ///
+    /// None means the built-in page.
+    pub interstitial_page: Option<String>,
+    /// control_rate_limit caps the messages per second sent by each client ip to the control server,
+    /// e.g. the registrations and the messages of the data streams, the bursts of the same number are allowed.
+    /// the registrations exceeding it are rejected, the data streams are throttled.
+    ///
+    /// None means unlimited, the usage is reported by `GET /admin/stats`.
+    pub control_rate_limit: Option<u32>,
+    /// payload_key lets the clients encrypt the payloads of their tunnels end-to-end with it,
+    /// the clients must have the same key, the clients without a key stay plaintext.
+    ///
+    /// None rejects the clients asking for the encryption.
+    pub payload_key: Option<PayloadKey>,
+    /// auth_token is required from the clients in the `authorization` metadata of every rpc,
+    /// e.g. on a public control port, the others are rejected with `UNAUTHENTICATED`.
+    /// The admin api and the grpc reflection require it too, as `Authorization: Bearer <token>`.
+    ///
+    /// None lets any client reach the control port register the tunnels.
+    pub auth_token: Option<String>,
+    /// registration_rate_limit caps the registrations per minute of each auth token,
+    /// e.g. so a leaked token can't churn the tunnels through the port pool.
+    /// the registrations exceeding it are rejected with `RESOURCE_EXHAUSTED` and a `retry-after`,
+    /// the clients without a token share the limit.
+    ///
+    /// None means unlimited.
+    pub registration_rate_limit: Option<u32>,
+    /// control_tls serves the control port with the tls, the clients connect with
+    /// [`crate::client::Client::with_tls`], e.g. to keep the auth token off the wire.
+    ///
+    /// None serves it in plaintext.
+    pub control_tls: Option<ControlTls>,
+    /// bandwidth_limit paces the data streams of each direction at the bytes per second,
+    /// the tunnels with traffic queued share it by their weights, see `tunnel_weights`.
+    /// it should be a bit below the capacity of the link, so the traffic queues up in the server.
+    ///
+    /// None means unlimited, the weights take no effect.
+    pub bandwidth_limit: Option<u64>,
+    /// tunnel_weights give the weights to the tunnels by their labels, the first matching rule wins,
+    /// the rest of the tunnels weigh 1.
+    ///
+    /// the labels are set by the clients, the open hooks may reject the ones they don't trust.
+    pub tunnel_weights: Vec<TunnelWeight>,
+    /// tunnel_rate_limit caps the bytes per second of each tunnel in each direction,
+    /// the connections of a tunnel share it, the traffic over it waits instead of being dropped.
+    /// the tunnels may ask for a lower one, see `Tunnel::with_rate_limit` of the client.
+    ///
+    /// None means unlimited unless the tunnel asks for a limit.
+    pub tunnel_rate_limit: Option<u64>,
+    /// connection_log_sample logs the open and close of 1 in the number of the user connections
+    /// of each tunnel, starting from the first one, so the busy tunnels don't drown the logs.
+    ///
+    /// None logs all of them.
+    pub connection_log_sample: Option<u32>,
+    /// data_send_timeout is the max time the data of a client waits for the user connection to take it,
+    /// e.g. the user stops reading, the stuck connection is closed after it
+    /// and the data stream of the client ends with `DEADLINE_EXCEEDED`.
+    ///
+    /// None waits for as long as it takes.
+    pub data_send_timeout: Option<Duration>,
+    /// close_timeout caps the shutdown of each side of a closing tcp connection,
+    /// e.g. the client doesn't take the end of the stream, the connection is dropped after it.
+    ///
+    /// None waits for as long as it takes.
+    pub close_timeout: Option<Duration>,
+    /// disable_tcp, disable_udp and disable_http reject the registrations of the tunnels of the type
+    /// with `PERMISSION_DENIED` before any port or domain is taken, e.g. to offer only the http tunnels.
+    pub disable_tcp: bool,
+    pub disable_udp: bool,
+    pub disable_http: bool,
+    /// admin_api serves the admin api on the control port, e.g. `GET /admin/tunnels`, `GET /admin/stats`,
+    /// `POST /admin/tunnels/{id}/probe`.
+    pub admin_api: bool,
+    /// grpc_reflection serves the grpc server reflection on the control port, so the tools
+    /// like grpcurl explore the TunnelService without the proto files.
+    ///
+    /// it's off by default, the reflection answers anyone reaching the control port.
+    pub grpc_reflection: bool,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct EntrypointConfig {
     pub domain: Vec<String>,
     pub ip: Vec<IpAddr>,
     pub vhttp_behind_proxy_tls: bool,
     pub port_range: RangeInclusive<u16>,
     pub exclude_ports: Vec<u16>,
+    /// the tunnels asking for a random port get any free port picked by the os,
+    /// instead of a port of `port_range`, the explicit ports are still checked against the range.
+    pub ephemeral_ports: bool,
+    /// the random subdomains are drawn from the words, e.g. `brave-otter-1234`,
+    /// None makes them of 8 random letters and digits.
+    pub subdomain_words: Option<SubdomainWords>,
+    /// the vhttp server is served under the path of a larger site, e.g. `/tunnels`
+    /// of `https://site.com/tunnels/...` proxied to it, without the trailing `/`.
+    /// the requests must start with it, it's stripped before the tunnels see them,
+    /// and it's put back into the urls made by the server, e.g. the entrypoints and the interstitial.
+    ///
+    /// None serves the vhttp server at the root.
+    pub external_base_path: Option<String>,
 }
 
 impl Default for Config {
@@ -33,7 +237,39 @@ impl Default for Config {
         Config {
             control_port: 6610,
             vhttp_port: 6611,
+            vhttp_bind_ip: IpAddr::from([0, 0, 0, 0]),
+            vhttp_optional: false,
             entrypoint: Default::default(),
+            tcp_keepalive: None,
+            max_pending_connections: 1024,
+            max_pending_registrations: 1024,
+            max_in_flight_bytes: constant::DEFAULT_MAX_IN_FLIGHT_BYTES,
+            data_chunk_size: None,
+            max_request_deadline: None,
+            max_request_body: None,
+            max_response_body: None,
+            max_udp_sessions: None,
+            udp_idle_timeout: None,
+            udp_buffer_pool: constant::DEFAULT_UDP_BUFFER_POOL,
+            memory_budget: None,
+            http_cache: None,
+            interstitial_page: None,
+            control_rate_limit: None,
+            payload_key: None,
+            auth_token: None,
+            registration_rate_limit: None,
+            control_tls: None,
+            bandwidth_limit: None,
+            tunnel_weights: Vec::new(),
+            tunnel_rate_limit: None,
+            connection_log_sample: None,
+            data_send_timeout: None,
+            close_timeout: None,
+            disable_tcp: false,
+            disable_udp: false,
+            disable_http: false,
+            admin_api: false,
+            grpc_reflection: false,
         }
     }
 }
@@ -46,6 +282,9 @@ impl Default for EntrypointConfig {
             vhttp_behind_proxy_tls: false,
             port_range: 1024..=65535,
             exclude_ports: Vec::new(),
+            ephemeral_ports: false,
+            subdomain_words: None,
+            external_base_path: None,
         }
     }
 }
@@ -55,12 +294,20 @@ impl EntrypointConfig {
         let mut entrypoints: Vec<String> = Vec::new();
 
         let uri_parts = self.get_uri_parts(payload);
+        // the tunnels sharing a domain or subdomain are reached under their path prefixes
+        let path = match payload {
+            event::Payload::RegisterHttp { path_prefix, .. } if !uri_parts.include_port => {
+                path_prefix.trim_end_matches('/')
+            }
+            _ => "",
+        };
+        let base_path = self.external_base_path.as_deref().unwrap_or_default();
 
         for host in &uri_parts.host {
             let entrypoint = if uri_parts.include_port {
                 format!("{}://{}:{}", uri_parts.scheme, host, port)
             } else {
-                format!("{}://{}", uri_parts.scheme, host)
+                format!("{}://{}{}{}", uri_parts.scheme, host, base_path, path)
             };
             entrypoints.push(entrypoint.to_string());
         }
@@ -68,7 +315,18 @@ impl EntrypointConfig {
         entrypoints
     }
 
-    fn get_uri_parts<'a>(&'a self, payload: &'a event::Payload) -> PartsOfUri {
+    /// the entrypoint of a tunnel bound to the loopback of the server,
+    /// it's only reachable on the server, so the ips and domains aren't used.
+    pub(crate) fn make_loopback_entrypoint(
+        &self,
+        payload: &event::Payload,
+        port: u16,
+    ) -> Vec<String> {
+        let scheme = self.get_uri_parts(payload).scheme;
+        vec![format!("{}://127.0.0.1:{}", scheme, port)]
+    }
+
+    fn get_uri_parts<'a>(&'a self, payload: &'a event::Payload) -> PartsOfUri<'a> {
         match payload {
             event::Payload::RegisterTcp { .. } => self.make_parts_of_uri("tcp"),
             event::Payload::RegisterUdp { .. } => self.make_parts_of_uri("udp"),
@@ -115,7 +373,7 @@ impl EntrypointConfig {
         }
     }
 
-    fn make_parts_of_uri<'a>(&'a self, schema: &'a str) -> PartsOfUri {
+    fn make_parts_of_uri<'a>(&'a self, schema: &'a str) -> PartsOfUri<'a> {
         let mut host: Vec<&str> = Vec::new();
         for ip in &self.ip {
             host.push(Box::leak(ip.to_string().into_boxed_str()));
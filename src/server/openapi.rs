@@ -0,0 +1,192 @@
+//! The OpenAPI document of the admin api, it's served by `GET /admin/openapi.json`,
+//! e.g. to generate the clients of the admin api.
+//!
+//! The schemas of the responses are derived from the types serialized by the admin api,
+//! so they can't drift apart from the responses.
+use schemars::gen::SchemaSettings;
+use serde_json::{json, Value};
+
+use super::{admin::Stats, probe::ProbeResult, registry::TunnelInfo};
+
+/// the OpenAPI 3.0 document of the admin api.
+pub(crate) fn document() -> Value {
+    let mut gen = SchemaSettings::openapi3().into_generator();
+    let tunnels = gen.subschema_for::<Vec<TunnelInfo>>();
+    let stats = gen.subschema_for::<Stats>();
+    let probe = gen.subschema_for::<ProbeResult>();
+    let schemas = gen.take_definitions();
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "castled admin api",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "paths": {
+            "/admin/tunnels": {
+                "get": {
+                    "operationId": "listTunnels",
+                    "summary": "List the registered tunnels.",
+                    "parameters": [{
+                        "name": "label",
+                        "in": "query",
+                        "description": "only the tunnels with the label, e.g. team=infra, repeat it to match all of them.",
+                        "style": "form",
+                        "explode": true,
+                        "schema": { "type": "array", "items": { "type": "string" } },
+                    }],
+                    "responses": {
+                        "200": json_response("the tunnels.", tunnels),
+                        "400": text_response("invalid label filter."),
+                    },
+                },
+            },
+            "/admin/tunnels/{id}": {
+                "delete": {
+                    "operationId": "closeTunnel",
+                    "summary": "Close the tunnel, the client doesn't register it again.",
+                    "parameters": [
+                        tunnel_id(),
+                        {
+                            "name": "reason",
+                            "in": "query",
+                            "description": "revoked tells the client the tunnel isn't allowed anymore.",
+                            "schema": { "type": "string", "enum": ["evicted", "revoked"], "default": "evicted" },
+                        },
+                        {
+                            "name": "message",
+                            "in": "query",
+                            "description": "told to the client.",
+                            "schema": { "type": "string" },
+                        },
+                    ],
+                    "responses": {
+                        "200": text_response("the tunnel is closed."),
+                        "400": text_response("invalid reason."),
+                        "404": text_response("the tunnel isn't found."),
+                    },
+                },
+            },
+            "/admin/tunnels/{id}/probe": {
+                "post": {
+                    "operationId": "probeTunnel",
+                    "summary": "Ask the client of the tunnel whether the local target is reachable.",
+                    "parameters": [
+                        tunnel_id(),
+                        {
+                            "name": "target",
+                            "in": "query",
+                            "required": true,
+                            "description": "the local address, e.g. 127.0.0.1:5432.",
+                            "schema": { "type": "string" },
+                        },
+                        {
+                            "name": "protocol",
+                            "in": "query",
+                            "schema": { "type": "string", "enum": ["tcp", "udp"], "default": "tcp" },
+                        },
+                        {
+                            "name": "timeout_ms",
+                            "in": "query",
+                            "schema": { "type": "integer", "format": "uint64", "minimum": 1, "default": 3000 },
+                        },
+                    ],
+                    "responses": {
+                        "200": json_response("the report of the client.", probe),
+                        "400": text_response("invalid probe query."),
+                        "404": text_response("the tunnel isn't found."),
+                        "502": text_response("the client failed to probe."),
+                        "504": text_response("the client didn't report in time."),
+                    },
+                },
+            },
+            "/admin/stats": {
+                "get": {
+                    "operationId": "getStats",
+                    "summary": "Report the usage of the server.",
+                    "responses": {
+                        "200": json_response("the usage.", stats),
+                    },
+                },
+            },
+            "/admin/openapi.json": {
+                "get": {
+                    "operationId": "getOpenApi",
+                    "summary": "This document.",
+                    "responses": {
+                        "200": json_response("the OpenAPI document.", json!({ "type": "object" })),
+                    },
+                },
+            },
+        },
+        // the token is required only if the server has an auth token.
+        "security": [{ "bearer": [] }, {}],
+        "components": {
+            "schemas": schemas,
+            "securitySchemes": {
+                "bearer": {
+                    "type": "http",
+                    "scheme": "bearer",
+                    "description": "the auth token of the server.",
+                },
+            },
+        },
+    })
+}
+
+fn tunnel_id() -> Value {
+    json!({
+        "name": "id",
+        "in": "path",
+        "required": true,
+        "description": "the id assigned at the registration.",
+        "schema": { "type": "string" },
+    })
+}
+
+fn json_response(description: &str, schema: impl serde::Serialize) -> Value {
+    json!({
+        "description": description,
+        "content": { "application/json": { "schema": schema } },
+    })
+}
+
+fn text_response(description: &str) -> Value {
+    json!({
+        "description": description,
+        "content": { "text/plain": { "schema": { "type": "string" } } },
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_document() {
+        let document = document();
+        assert_eq!(document["openapi"], "3.0.3");
+        let schemas = &document["components"]["schemas"];
+        for name in [
+            "TunnelInfo",
+            "Stats",
+            "PortStats",
+            "ProbeResult",
+            "RegistrationStats",
+            "UdpStats",
+        ] {
+            assert!(schemas[name].is_object(), "{}", name);
+        }
+        // the serde renames are followed
+        assert!(schemas["TunnelInfo"]["properties"]["type"].is_object());
+        assert_eq!(
+            document["paths"]["/admin/tunnels"]["get"]["responses"]["200"]["content"]
+                ["application/json"]["schema"]["items"]["$ref"],
+            "#/components/schemas/TunnelInfo"
+        );
+        assert_eq!(
+            document["components"]["securitySchemes"]["bearer"]["scheme"],
+            "bearer"
+        );
+    }
+}
@@ -5,17 +5,28 @@ use dashmap::DashSet;
 use rand::prelude::*;
 use rand::rngs::StdRng;
 use rand::seq::IteratorRandom;
+use schemars::JsonSchema;
+use serde::Serialize;
+use tonic::Status;
+use tracing::{debug, warn};
 
-#[derive(Clone)]
+/// warn when the remaining ports drop below this percent of the total.
+const LOW_PORTS_PERCENT: usize = 10;
+
+#[derive(Clone, Debug)]
 pub struct PortManager {
     min: u16,
     max: u16,
+    /// the number of ports in the range, excluding the `exclude_ports`.
+    total: usize,
     exclude_ports: Arc<DashSet<u16>>,
     /// TODO(sword): remove this mutex.
     /// we don't need it because we only has one actual consumer of PortManager
     /// but the caller code requires `Sync`, so we have to use `Mutex`.
     rng: Arc<Mutex<StdRng>>,
     pool: Arc<DashSet<u16>>,
+    /// the random ports are picked by the os, see `EntrypointConfig::ephemeral_ports`.
+    ephemeral: bool,
 }
 
 impl PortManager {
@@ -26,6 +37,7 @@ impl PortManager {
             .filter(|port| !exclude_ports.contains(port))
             .collect();
 
+        let total = ports.len();
         let pool = DashSet::from_iter(ports);
         let since_the_epoch = SystemTime::now()
             .duration_since(UNIX_EPOCH)
@@ -36,14 +48,40 @@ impl PortManager {
             rng: Arc::new(Mutex::new(rng)),
             min,
             max,
+            total,
             exclude_ports: Arc::new(exclude_ports.into_iter().collect()),
             pool: Arc::new(pool),
+            ephemeral: false,
         }
     }
 
+    pub(crate) fn with_ephemeral_ports(mut self, ephemeral: bool) -> Self {
+        self.ephemeral = ephemeral;
+        self
+    }
+
+    /// true if the random ports bypass the pool.
+    pub(crate) fn ephemeral(&self) -> bool {
+        self.ephemeral
+    }
+
     // check if the port is a valid port, doesn't guarantee the port is available.
-    pub fn allow(&self, port: u16) -> bool {
-        port >= self.min && port <= self.max && !self.exclude_ports.contains(&port)
+    //
+    // the error names the allowed range, so the client knows which ports to ask for.
+    pub fn check(&self, port: u16) -> Result<(), Status> {
+        if port < self.min || port > self.max {
+            return Err(Status::permission_denied(format!(
+                "port {} is outside the allowed range {}-{}",
+                port, self.min, self.max
+            )));
+        }
+        if self.exclude_ports.contains(&port) {
+            return Err(Status::permission_denied(format!(
+                "port {} is excluded, the allowed range is {}-{} without the excluded ports",
+                port, self.min, self.max
+            )));
+        }
+        Ok(())
     }
 
     // take a port from the pool.
@@ -56,10 +94,11 @@ impl PortManager {
             return None;
         }
         self.pool.remove(&port);
+        self.warn_low_ports();
 
         Some(Available {
             port,
-            pool: Arc::clone(&self.pool),
+            pool: Some(Arc::clone(&self.pool)),
             unavailable: false,
         })
     }
@@ -69,23 +108,78 @@ impl PortManager {
         let mut rng = self.rng.lock().unwrap();
         let mut rng = &mut *rng;
         let port = *self.pool.iter().choose(&mut rng)?;
-        println!("get port: {}", port);
+        debug!(port, "picked a random port");
         self.take(port)
     }
+
+    /// the error of a random port asked when the pool runs out, it names the allowed range,
+    /// so the operator knows which one to widen.
+    pub(crate) fn exhausted(&self) -> Status {
+        Status::resource_exhausted(format!(
+            "no available port in the allowed range {}-{}",
+            self.min, self.max
+        ))
+    }
+
+    /// the number of ports in the allowed range.
+    pub fn total(&self) -> usize {
+        self.total
+    }
+
+    /// the number of ports left for the new tunnels,
+    /// the ports failed to bind are not counted, they never come back to the pool.
+    pub fn remaining(&self) -> usize {
+        self.pool.len()
+    }
+
+    pub(crate) fn stats(&self) -> PortStats {
+        PortStats {
+            total: self.total(),
+            remaining: self.remaining(),
+        }
+    }
+
+    /// warn once the remaining ports cross the threshold.
+    fn warn_low_ports(&self) {
+        let threshold = self.total * LOW_PORTS_PERCENT / 100;
+        let remaining = self.remaining();
+        if remaining + 1 == threshold {
+            warn!(
+                remaining,
+                total = self.total,
+                "available ports are running low, new tunnels will fail once they run out"
+            );
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, JsonSchema)]
+pub(crate) struct PortStats {
+    pub(crate) total: usize,
+    pub(crate) remaining: usize,
 }
 
 #[derive(Debug)]
 pub struct Available {
     /// the port number.
     port: u16,
-    /// the pool may not exist if creates `Available` directly.
-    pool: Arc<DashSet<u16>>,
+    /// None if the port is picked by the os, it never goes to the pool.
+    pool: Option<Arc<DashSet<u16>>>,
     /// when bind fails, set this to true.
     /// we don't return the port to the pool when it's unavailable.
     unavailable: bool,
 }
 
 impl Available {
+    /// the port picked by the os.
+    pub(crate) fn ephemeral(port: u16) -> Self {
+        Self {
+            port,
+            pool: None,
+            unavailable: false,
+        }
+    }
+
     pub(crate) fn unavailable(&mut self) {
         self.unavailable = true;
     }
@@ -96,7 +190,9 @@ impl Drop for Available {
         if self.unavailable {
             return;
         }
-        self.pool.insert(self.port);
+        if let Some(pool) = &self.pool {
+            pool.insert(self.port);
+        }
     }
 }
 
@@ -120,6 +216,16 @@ mod test {
 
     use super::*;
 
+    #[test]
+    fn test_ephemeral_port() {
+        let port_manager = PortManager::new(2000..=2001, vec![]).with_ephemeral_ports(true);
+        assert!(port_manager.ephemeral());
+        drop(Available::ephemeral(3000));
+        // the ports picked by the os never go to the pool
+        assert_eq!(port_manager.remaining(), 2);
+        assert!(port_manager.take(3000).is_none());
+    }
+
     #[test]
     fn test_port_manager() {
         let port_range: RangeInclusive<u16> = 2000..=2100;
@@ -148,6 +254,73 @@ mod test {
         drop(ports);
     }
 
+    #[test]
+    fn test_check() {
+        let port_manager = PortManager::new(4000..=4009, vec![4005]);
+        assert!(port_manager.check(4000).is_ok());
+        assert!(port_manager.check(4009).is_ok());
+
+        let err = port_manager.check(4010).unwrap_err();
+        assert_eq!(err.code(), tonic::Code::PermissionDenied);
+        assert_eq!(
+            err.message(),
+            "port 4010 is outside the allowed range 4000-4009"
+        );
+        let err = port_manager.check(4005).unwrap_err();
+        assert_eq!(err.code(), tonic::Code::PermissionDenied);
+        assert!(err.message().contains("4000-4009"));
+
+        // no restriction
+        let port_manager = PortManager::new(1..=65535, vec![]);
+        assert!([1, 80, 65535]
+            .into_iter()
+            .all(|port| port_manager.check(port).is_ok()));
+    }
+
+    #[test]
+    fn test_remaining_ports() {
+        let mut port_manager = PortManager::new(4000..=4009, vec![4005, 5000]);
+        assert_eq!(port_manager.total(), 9);
+        assert_eq!(port_manager.remaining(), 9);
+
+        let taken = port_manager.take(4000).unwrap();
+        let mut unavailable = port_manager.get().unwrap();
+        unavailable.unavailable();
+        assert_eq!(port_manager.remaining(), 7);
+
+        drop(taken);
+        drop(unavailable);
+        assert_eq!(
+            port_manager.stats(),
+            PortStats {
+                total: 9,
+                remaining: 8
+            }
+        );
+    }
+
+    #[test]
+    fn test_tiny_range_exhausted() {
+        let mut port_manager = PortManager::new(4000..=4002, vec![4001]);
+        let first = port_manager.get().unwrap();
+        let second = port_manager.get().unwrap();
+        let mut taken = vec![*first, *second];
+        taken.sort();
+        assert_eq!(taken, [4000, 4002]);
+        assert!(port_manager.get().is_none());
+        assert_eq!(
+            port_manager.exhausted().message(),
+            "no available port in the allowed range 4000-4002"
+        );
+
+        // the port freed by the closed tunnel is handed out again
+        let freed = *second;
+        drop(second);
+        assert_eq!(port_manager.remaining(), 1);
+        assert_eq!(*port_manager.get().unwrap(), freed);
+        drop(first);
+    }
+
     #[test]
     fn test_it_shares_same_memory() {
         let port_range: RangeInclusive<u16> = 3000..=3011;
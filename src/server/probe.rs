@@ -0,0 +1,183 @@
+//! The probes sent to the clients, the admin api uses them to tell
+//! whether the local targets behind a tunnel are reachable.
+//!
+//! The server sends a `Probe` command through the control stream of the tunnel,
+//! the client probes the target and answers with the `ReportProbe` rpc.
+use std::{sync::Arc, time::Duration};
+
+use dashmap::DashMap;
+use schemars::JsonSchema;
+use serde::Serialize;
+use tokio::sync::{mpsc, oneshot};
+use tonic::Status;
+use uuid::Uuid;
+
+use crate::pb::{
+    control_command::Payload, probe_payload::Protocol, ControlCommand, ProbePayload, ProbeReport,
+};
+
+/// the extra time given to the client to report the probe.
+const REPORT_GRACE: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, PartialEq, Serialize, JsonSchema)]
+pub(crate) struct ProbeResult {
+    pub reachable: bool,
+    pub latency_us: u64,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub error: String,
+}
+
+impl From<ProbeReport> for ProbeResult {
+    fn from(report: ProbeReport) -> Self {
+        Self {
+            reachable: report.reachable,
+            latency_us: report.latency_us,
+            error: report.error,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Probes {
+    /// the control streams of the tunnels, keyed by the tunnel id.
+    /// they are weak, so they never keep the control streams open.
+    controls: Arc<DashMap<String, mpsc::WeakSender<Result<ControlCommand, Status>>>>,
+    /// the probes waiting for the report, keyed by the probe id.
+    pending: Arc<DashMap<String, oneshot::Sender<ProbeReport>>>,
+}
+
+impl Probes {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn attach(
+        &self,
+        tunnel_id: String,
+        control: &mpsc::Sender<Result<ControlCommand, Status>>,
+    ) {
+        self.controls.insert(tunnel_id, control.downgrade());
+    }
+
+    pub(crate) fn detach(&self, tunnel_id: &str) {
+        self.controls.remove(tunnel_id);
+    }
+
+    /// probe asks the client of the tunnel to probe the target,
+    /// and waits for the report.
+    pub(crate) async fn probe(
+        &self,
+        tunnel_id: &str,
+        protocol: Protocol,
+        target: String,
+        timeout: Duration,
+    ) -> Result<ProbeResult, Status> {
+        let control = self
+            .controls
+            .get(tunnel_id)
+            .and_then(|control| control.upgrade())
+            .ok_or_else(|| Status::not_found(format!("tunnel {} not found", tunnel_id)))?;
+
+        let probe_id = Uuid::new_v4().to_string();
+        let (report_tx, report_rx) = oneshot::channel();
+        self.pending.insert(probe_id.clone(), report_tx);
+        let _pending = PendingGuard {
+            pending: &self.pending,
+            probe_id: &probe_id,
+        };
+
+        control
+            .send(Ok(ControlCommand {
+                payload: Some(Payload::Probe(ProbePayload {
+                    probe_id: probe_id.clone(),
+                    protocol: protocol as i32,
+                    target,
+                    timeout_ms: timeout.as_millis() as u64,
+                })),
+            }))
+            .await
+            .map_err(|_| Status::unavailable("the control stream is closed"))?;
+
+        match tokio::time::timeout(timeout + REPORT_GRACE, report_rx).await {
+            Ok(Ok(report)) => Ok(report.into()),
+            Ok(Err(_)) => Err(Status::unavailable("the probe is dropped")),
+            Err(_) => Err(Status::deadline_exceeded(
+                "the client didn't report the probe in time",
+            )),
+        }
+    }
+
+    /// report completes the pending probe, it returns false if the probe
+    /// is unknown or has timed out.
+    pub(crate) fn report(&self, report: ProbeReport) -> bool {
+        match self.pending.remove(&report.probe_id) {
+            Some((_, report_tx)) => report_tx.send(report).is_ok(),
+            None => false,
+        }
+    }
+}
+
+/// removes the pending probe when the probe finishes or is cancelled.
+struct PendingGuard<'a> {
+    pending: &'a DashMap<String, oneshot::Sender<ProbeReport>>,
+    probe_id: &'a str,
+}
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        self.pending.remove(self.probe_id);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_probe() {
+        let probes = Probes::new();
+        let err = probes
+            .probe(
+                "missing",
+                Protocol::Tcp,
+                "127.0.0.1:80".to_string(),
+                Duration::from_millis(10),
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::NotFound);
+
+        let (control_tx, mut control_rx) = mpsc::channel(1);
+        probes.attach("tunnel".to_string(), &control_tx);
+        let client = probes.clone();
+        tokio::spawn(async move {
+            let command = control_rx.recv().await.unwrap().unwrap();
+            let Some(Payload::Probe(probe)) = command.payload else {
+                panic!("expect probe command");
+            };
+            assert_eq!(probe.target, "127.0.0.1:80");
+            assert!(client.report(ProbeReport {
+                probe_id: probe.probe_id,
+                reachable: true,
+                latency_us: 42,
+                error: String::new(),
+            }));
+        });
+        let result = probes
+            .probe(
+                "tunnel",
+                Protocol::Tcp,
+                "127.0.0.1:80".to_string(),
+                Duration::from_secs(1),
+            )
+            .await
+            .unwrap();
+        assert!(result.reachable);
+        assert_eq!(result.latency_us, 42);
+        assert!(probes.pending.is_empty());
+        assert!(!probes.report(ProbeReport::default()));
+
+        probes.detach("tunnel");
+        assert!(probes.controls.is_empty());
+    }
+}
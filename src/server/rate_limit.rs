@@ -0,0 +1,195 @@
+//! The rate limit of the messages sent by each client to the control server,
+//! so a buggy client flooding the registrations or the data streams doesn't starve the others.
+//!
+//! Each client ip has a token bucket refilled by `rate` tokens per second, up to `rate` tokens,
+//! every inbound message takes a token:
+//! - the registrations and the probe reports are rejected with `RESOURCE_EXHAUSTED` without a token.
+//! - the messages of the data streams wait for a token, the client is throttled instead,
+//!   because they carry the traffic of the user connections.
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use schemars::JsonSchema;
+use serde::Serialize;
+use tonic::Status;
+use tracing::warn;
+
+/// the buckets are pruned when there are more clients, the full ones are dropped.
+const PRUNE_BUCKETS: usize = 1024;
+
+#[derive(Debug, Clone)]
+pub(crate) struct RateLimiter {
+    inner: Option<Arc<Inner>>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    rate: u32,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+    rejected: AtomicU64,
+    throttled: AtomicU64,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// negative if the throttled messages are waiting for the tokens.
+    tokens: f64,
+    updated: Instant,
+}
+
+/// the usage of the rate limit, it's reported by `GET /admin/stats`.
+#[derive(Debug, Serialize, PartialEq, JsonSchema)]
+pub(crate) struct RateLimitStats {
+    /// the messages per second of each client, null means unlimited.
+    rate: Option<u32>,
+    /// the rejected registrations and probe reports.
+    rejected: u64,
+    /// the messages of the data streams waited for the tokens.
+    throttled: u64,
+}
+
+impl RateLimiter {
+    /// None means unlimited, the rate is at least 1.
+    pub(crate) fn new(rate: Option<u32>) -> Self {
+        Self {
+            inner: rate.map(|rate| {
+                Arc::new(Inner {
+                    rate: rate.max(1),
+                    buckets: Mutex::new(HashMap::new()),
+                    rejected: AtomicU64::new(0),
+                    throttled: AtomicU64::new(0),
+                })
+            }),
+        }
+    }
+
+    /// take a token of the client, the error is returned to the client.
+    pub(crate) fn check(&self, client: Option<IpAddr>) -> Result<(), Status> {
+        let (Some(inner), Some(client)) = (&self.inner, client) else {
+            return Ok(());
+        };
+        if inner.take(client, Instant::now(), false).is_some() {
+            inner.rejected.fetch_add(1, Ordering::Relaxed);
+            warn!(%client, "the client exceeds the rate limit of the control server");
+            return Err(Status::resource_exhausted(format!(
+                "the client exceeds the rate limit of {} messages per second",
+                inner.rate
+            )));
+        }
+        Ok(())
+    }
+
+    /// wait for a token of the client.
+    pub(crate) async fn throttle(&self, client: Option<IpAddr>) {
+        let (Some(inner), Some(client)) = (&self.inner, client) else {
+            return;
+        };
+        if let Some(wait) = inner.take(client, Instant::now(), true) {
+            inner.throttled.fetch_add(1, Ordering::Relaxed);
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    pub(crate) fn stats(&self) -> RateLimitStats {
+        match &self.inner {
+            Some(inner) => RateLimitStats {
+                rate: Some(inner.rate),
+                rejected: inner.rejected.load(Ordering::Relaxed),
+                throttled: inner.throttled.load(Ordering::Relaxed),
+            },
+            None => RateLimitStats {
+                rate: None,
+                rejected: 0,
+                throttled: 0,
+            },
+        }
+    }
+}
+
+impl Inner {
+    /// None if a token is taken, or the time to wait for it, it's reserved if `wait` is true.
+    fn take(&self, client: IpAddr, now: Instant, wait: bool) -> Option<Duration> {
+        let rate = f64::from(self.rate);
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= PRUNE_BUCKETS && !buckets.contains_key(&client) {
+            buckets.retain(|_, bucket| bucket.refill(rate, now) < rate);
+        }
+        let bucket = buckets.entry(client).or_insert(Bucket {
+            tokens: rate,
+            updated: now,
+        });
+        let tokens = bucket.refill(rate, now);
+        if tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return None;
+        }
+        if wait {
+            bucket.tokens -= 1.0;
+        }
+        Some(Duration::from_secs_f64((1.0 - tokens) / rate))
+    }
+}
+
+impl Bucket {
+    fn refill(&mut self, rate: f64, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(rate);
+        self.updated = now;
+        self.tokens
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter() {
+        let limiter = RateLimiter::new(Some(2));
+        let inner = limiter.inner.as_ref().unwrap();
+        let client = IpAddr::from([127, 0, 0, 1]);
+        let now = Instant::now();
+
+        assert!(inner.take(client, now, false).is_none());
+        assert!(inner.take(client, now, false).is_none());
+        assert_eq!(
+            inner.take(client, now, false),
+            Some(Duration::from_millis(500))
+        );
+        // another client has its own bucket
+        assert!(inner
+            .take(IpAddr::from([127, 0, 0, 2]), now, false)
+            .is_none());
+
+        // the throttled messages queue up for the tokens
+        assert_eq!(
+            inner.take(client, now, true),
+            Some(Duration::from_millis(500))
+        );
+        assert_eq!(inner.take(client, now, true), Some(Duration::from_secs(1)));
+        let later = now + Duration::from_secs(1);
+        assert!(inner.take(client, later, false).is_some());
+        assert!(inner
+            .take(client, later + Duration::from_millis(500), false)
+            .is_none());
+
+        assert!(limiter.check(Some(client)).is_err());
+        assert!(limiter.check(None).is_ok());
+        assert_eq!(
+            limiter.stats(),
+            RateLimitStats {
+                rate: Some(2),
+                rejected: 1,
+                throttled: 0,
+            }
+        );
+        assert!(RateLimiter::new(None).check(Some(client)).is_ok());
+    }
+}
@@ -0,0 +1,152 @@
+//! The rate limit of the registrations of each auth token,
+//! so a leaked token can't churn the tunnels, e.g. scan the port pool by registering
+//! and closing them quickly from many ips, which the rate limit of each ip doesn't catch.
+//!
+//! Each token has a window of a minute counting its registrations,
+//! the registrations beyond the limit within the window are rejected with `RESOURCE_EXHAUSTED`,
+//! the seconds until the window ends are told in the message and the `retry-after` metadata.
+//! The clients without a token share a window.
+//!
+//! The tokens are kept by their SHA-256 digests, the ended windows are dropped once a window.
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use ring::digest;
+use tonic::Status;
+use tracing::warn;
+
+/// the registrations of each token are counted in the windows of the length.
+const WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct RegistrationLimit {
+    inner: Option<Arc<Inner>>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    /// the registrations of each token per window.
+    limit: u32,
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    windows: HashMap<Vec<u8>, Window>,
+    pruned: Instant,
+}
+
+#[derive(Debug)]
+struct Window {
+    started: Instant,
+    registrations: u32,
+}
+
+impl RegistrationLimit {
+    /// None means unlimited, the limit is at least 1.
+    pub(crate) fn new(limit: Option<u32>) -> Self {
+        Self {
+            inner: limit.map(|limit| {
+                Arc::new(Inner {
+                    limit: limit.max(1),
+                    state: Mutex::new(State {
+                        windows: HashMap::new(),
+                        pruned: Instant::now(),
+                    }),
+                })
+            }),
+        }
+    }
+
+    /// count a registration with the value of the `authorization` metadata,
+    /// the error is returned to the client.
+    pub(crate) fn check(&self, authorization: Option<&str>) -> Result<(), Status> {
+        let Some(inner) = &self.inner else {
+            return Ok(());
+        };
+        let token = digest::digest(&digest::SHA256, authorization.unwrap_or("").as_bytes());
+        let Some(retry_after) = inner.take(token.as_ref(), Instant::now()) else {
+            return Ok(());
+        };
+        // rounded up, so the retry isn't rejected again
+        let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+        warn!(
+            retry_after = secs,
+            "the auth token exceeds the registration rate limit"
+        );
+        let mut status = Status::resource_exhausted(format!(
+            "the auth token exceeds the limit of {} registrations per minute, retry after {}s",
+            inner.limit, secs
+        ));
+        status.metadata_mut().insert("retry-after", secs.into());
+        Err(status)
+    }
+}
+
+impl Inner {
+    /// None if the registration is counted, or the time until the window of the token ends.
+    fn take(&self, token: &[u8], now: Instant) -> Option<Duration> {
+        let mut state = self.state.lock().unwrap();
+        if now.saturating_duration_since(state.pruned) >= WINDOW {
+            state
+                .windows
+                .retain(|_, window| now.saturating_duration_since(window.started) < WINDOW);
+            state.pruned = now;
+        }
+        let window = state.windows.entry(token.to_vec()).or_insert(Window {
+            started: now,
+            registrations: 0,
+        });
+        let elapsed = now.saturating_duration_since(window.started);
+        if elapsed >= WINDOW {
+            window.started = now;
+            window.registrations = 0;
+        } else if window.registrations >= self.limit {
+            return Some(WINDOW - elapsed);
+        }
+        window.registrations += 1;
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_registration_limit() {
+        let limit = RegistrationLimit::new(Some(2));
+        let inner = limit.inner.as_ref().unwrap();
+        let now = Instant::now();
+
+        assert!(inner.take(b"a", now).is_none());
+        assert!(inner.take(b"a", now + Duration::from_secs(10)).is_none());
+        assert_eq!(
+            inner.take(b"a", now + Duration::from_secs(20)),
+            Some(Duration::from_secs(40))
+        );
+        // another token has its own window
+        assert!(inner.take(b"b", now + Duration::from_secs(20)).is_none());
+        // the window of the token ends
+        assert!(inner.take(b"a", now + WINDOW).is_none());
+
+        // the ended windows are dropped
+        let later = now + WINDOW * 3;
+        assert!(inner.take(b"c", later).is_none());
+        assert_eq!(inner.state.lock().unwrap().windows.len(), 1);
+
+        assert!(limit.check(Some("Bearer secret")).is_ok());
+        assert!(limit.check(Some("Bearer secret")).is_ok());
+        let status = limit.check(Some("Bearer secret")).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+        assert_eq!(status.metadata().get("retry-after").unwrap(), "60");
+        // the clients without a token share a window
+        assert!(limit.check(None).is_ok());
+        assert!(limit.check(None).is_ok());
+        assert!(limit.check(None).is_err());
+        assert!(RegistrationLimit::new(None).check(None).is_ok());
+    }
+}
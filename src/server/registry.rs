@@ -0,0 +1,249 @@
+//! The registry of the tunnels served by the server, it's read by the admin api.
+use std::{collections::BTreeMap, net::IpAddr, sync::Arc};
+
+use dashmap::DashMap;
+use schemars::JsonSchema;
+use serde::Serialize;
+use tokio::sync::oneshot;
+use tokio_util::sync::{CancellationToken, DropGuard};
+
+use crate::pb::ClosePayload;
+
+/// TunnelInfo is the metadata of a registered tunnel.
+#[derive(Debug, Clone, PartialEq, Serialize, JsonSchema)]
+pub struct TunnelInfo {
+    /// assigned by the server at the registration.
+    pub id: String,
+    pub name: String,
+    /// tcp, udp or http.
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub entrypoint: Vec<String>,
+    pub labels: BTreeMap<String, String>,
+}
+
+impl TunnelInfo {
+    /// matches returns true if the tunnel has all the labels.
+    fn matches(&self, labels: &[(String, String)]) -> bool {
+        labels
+            .iter()
+            .all(|(key, value)| self.labels.get(key) == Some(value))
+    }
+}
+
+/// TunnelIdentity is the same tunnel registered by a client again,
+/// e.g. it reconnected before the server noticed the previous control stream is gone.
+///
+/// The clients behind a nat share the ip, and the processes of a host may run
+/// the same tunnel, so the instance of the client tells them apart.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct TunnelIdentity {
+    pub client: Option<IpAddr>,
+    /// the `instance_id` of the registration, never empty.
+    pub instance: String,
+    pub name: String,
+    pub kind: &'static str,
+}
+
+/// Claim is the registration holding a [`TunnelIdentity`].
+#[derive(Debug, Clone)]
+pub(crate) struct Claim {
+    pub id: String,
+    /// cancelled once the registration is gone, e.g. its entrypoint is released.
+    pub released: CancellationToken,
+}
+
+/// ClaimGuard gives up the claim of the registration once it's dropped.
+pub(crate) struct ClaimGuard {
+    registry: TunnelRegistry,
+    identity: TunnelIdentity,
+    id: String,
+    _released: DropGuard,
+}
+
+impl Drop for ClaimGuard {
+    fn drop(&mut self) {
+        // the identity may be claimed by the new registration already
+        self.registry
+            .identities
+            .remove_if(&self.identity, |_, claim| claim.id == self.id);
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct TunnelRegistry {
+    tunnels: Arc<DashMap<String, TunnelInfo>>,
+    /// close the control streams of the tunnels with the reason told to the clients.
+    closers: Arc<DashMap<String, oneshot::Sender<ClosePayload>>>,
+    /// the registrations of the identities, opened or not.
+    identities: Arc<DashMap<TunnelIdentity, Claim>>,
+}
+
+impl TunnelRegistry {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn insert(&self, tunnel: TunnelInfo) {
+        self.tunnels.insert(tunnel.id.clone(), tunnel);
+    }
+
+    pub(crate) fn remove(&self, id: &str) {
+        self.tunnels.remove(id);
+        self.closers.remove(id);
+    }
+
+    /// set the closer of the tunnel, the control stream closes when it receives the reason.
+    pub(crate) fn on_close(&self, id: &str, closer: oneshot::Sender<ClosePayload>) {
+        self.closers.insert(id.to_string(), closer);
+    }
+
+    /// close the tunnel, false if it's not found.
+    pub(crate) fn close(&self, id: &str, close: ClosePayload) -> bool {
+        match self.closers.remove(id) {
+            Some((_, closer)) => closer.send(close).is_ok(),
+            None => false,
+        }
+    }
+
+    /// claim the identity for the registration, it returns the guard of the claim
+    /// and the previous registration of the identity to replace if any.
+    pub(crate) fn claim(&self, identity: TunnelIdentity, id: &str) -> (ClaimGuard, Option<Claim>) {
+        let released = CancellationToken::new();
+        let claim = Claim {
+            id: id.to_string(),
+            released: released.clone(),
+        };
+        let previous = self.identities.insert(identity.clone(), claim);
+        let guard = ClaimGuard {
+            registry: self.clone(),
+            identity,
+            id: id.to_string(),
+            _released: released.drop_guard(),
+        };
+        (guard, previous)
+    }
+
+    /// true if the registration still holds the identity, i.e. it's not replaced.
+    pub(crate) fn holds(&self, identity: &TunnelIdentity, id: &str) -> bool {
+        self.identities
+            .get(identity)
+            .is_some_and(|claim| claim.id == id)
+    }
+
+    /// list returns the tunnels having all the labels, sorted by the name and id.
+    pub(crate) fn list(&self, labels: &[(String, String)]) -> Vec<TunnelInfo> {
+        let mut tunnels: Vec<TunnelInfo> = self
+            .tunnels
+            .iter()
+            .filter(|tunnel| tunnel.matches(labels))
+            .map(|tunnel| tunnel.value().clone())
+            .collect();
+        tunnels.sort_by(|a, b| (&a.name, &a.id).cmp(&(&b.name, &b.id)));
+        tunnels
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn tunnel(id: &str, labels: &[(&str, &str)]) -> TunnelInfo {
+        TunnelInfo {
+            id: id.to_string(),
+            name: "test".to_string(),
+            kind: "tcp",
+            entrypoint: vec![],
+            labels: labels
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_list_by_labels() {
+        let registry = TunnelRegistry::new();
+        registry.insert(tunnel("1", &[("team", "infra"), ("env", "prod")]));
+        registry.insert(tunnel("2", &[("team", "infra"), ("env", "dev")]));
+        registry.insert(tunnel("3", &[]));
+
+        let ids = |labels: &[(&str, &str)]| {
+            let labels: Vec<(String, String)> = labels
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect();
+            registry
+                .list(&labels)
+                .into_iter()
+                .map(|tunnel| tunnel.id)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ids(&[]), vec!["1", "2", "3"]);
+        assert_eq!(ids(&[("team", "infra")]), vec!["1", "2"]);
+        assert_eq!(ids(&[("team", "infra"), ("env", "dev")]), vec!["2"]);
+        assert!(ids(&[("team", "web")]).is_empty());
+
+        registry.remove("1");
+        assert_eq!(ids(&[("team", "infra")]), vec!["2"]);
+    }
+
+    #[test]
+    fn test_close() {
+        let registry = TunnelRegistry::new();
+        registry.insert(tunnel("1", &[]));
+        let (closer, mut closed) = oneshot::channel();
+        registry.on_close("1", closer);
+
+        let close = ClosePayload {
+            reason: crate::pb::close_payload::Reason::Evicted as i32,
+            message: "bye".to_string(),
+        };
+        assert!(!registry.close("2", close.clone()));
+        assert!(registry.close("1", close.clone()));
+        assert_eq!(closed.try_recv().unwrap(), close);
+        // a tunnel is closed once
+        assert!(!registry.close("1", close));
+    }
+
+    #[test]
+    fn test_claim() {
+        let registry = TunnelRegistry::new();
+        let identity = TunnelIdentity {
+            client: Some(IpAddr::from([127, 0, 0, 1])),
+            instance: "a".to_string(),
+            name: "test".to_string(),
+            kind: "tcp",
+        };
+        let (first, previous) = registry.claim(identity.clone(), "1");
+        assert!(previous.is_none());
+        assert!(registry.holds(&identity, "1"));
+
+        // claimed again by the same client
+        let (second, previous) = registry.claim(identity.clone(), "2");
+        let previous = previous.unwrap();
+        assert_eq!(previous.id, "1");
+        assert!(!registry.holds(&identity, "1"));
+        assert!(!previous.released.is_cancelled());
+        // the replaced one doesn't give up the claim of the new one
+        drop(first);
+        assert!(previous.released.is_cancelled());
+        assert!(registry.holds(&identity, "2"));
+
+        // another client
+        let other = TunnelIdentity {
+            client: Some(IpAddr::from([127, 0, 0, 2])),
+            ..identity.clone()
+        };
+        assert!(registry.claim(other, "3").1.is_none());
+        // another instance of the same ip, e.g. behind a nat
+        let other = TunnelIdentity {
+            instance: "b".to_string(),
+            ..identity.clone()
+        };
+        assert!(registry.claim(other, "4").1.is_none());
+
+        drop(second);
+        assert!(!registry.holds(&identity, "2"));
+    }
+}
@@ -0,0 +1,231 @@
+//! Reloading the config of a running server, e.g. on `SIGHUP`.
+//!
+//! Only the settings read when a tunnel is registered are reloaded,
+//! the registered tunnels keep running with the settings they started with.
+//! The rest of the config(e.g. the ports) requires a restart,
+//! the changes of them are logged and ignored.
+use std::{
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
+};
+
+use tracing::{info, warn};
+
+use super::{Config, EntrypointConfig};
+use crate::socket::TcpKeepalive;
+
+/// Settings are read by the data server when a tunnel is registered.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Settings {
+    /// only the domain, ip, vhttp_behind_proxy_tls and subdomain_words are read,
+    /// the ports are taken from the port manager created at the startup,
+    /// the external_base_path is of the vhttp server started with the server.
+    pub entrypoint: EntrypointConfig,
+    pub tcp_keepalive: Option<TcpKeepalive>,
+    pub max_in_flight_bytes: usize,
+    pub data_chunk_size: Option<usize>,
+    pub max_request_deadline: Option<Duration>,
+    pub max_request_body: Option<u64>,
+    pub max_response_body: Option<u64>,
+    pub max_udp_sessions: Option<usize>,
+    pub udp_idle_timeout: Option<Duration>,
+    pub udp_buffer_pool: usize,
+    pub close_timeout: Option<Duration>,
+}
+
+/// Reloader applies the reloaded config to the running server,
+/// it's created by [`super::Server::reloader`].
+#[derive(Debug, Clone)]
+pub struct Reloader {
+    settings: Arc<RwLock<Settings>>,
+    running: Arc<Mutex<Config>>,
+}
+
+/// the changed keys of a reload.
+#[derive(Debug, Default, PartialEq)]
+pub struct Reloaded {
+    /// the keys applied to the tunnels registered from now on.
+    pub applied: Vec<&'static str>,
+    /// the keys ignored until the server restarts.
+    pub requires_restart: Vec<&'static str>,
+}
+
+impl Reloader {
+    pub(crate) fn new(settings: Arc<RwLock<Settings>>, config: Config) -> Self {
+        Self {
+            settings,
+            running: Arc::new(Mutex::new(config)),
+        }
+    }
+
+    /// Apply the config, the keys which can't be changed at runtime keep the running values.
+    pub fn reload(&self, config: Config) -> Reloaded {
+        let mut running = self.running.lock().unwrap();
+        let mut reloaded = Reloaded::default();
+
+        macro_rules! diff {
+            ($list:ident, $name:literal, $($key:ident).+) => {
+                if running.$($key).+ != config.$($key).+ {
+                    reloaded.$list.push($name);
+                }
+            };
+        }
+        diff!(requires_restart, "control_port", control_port);
+        diff!(requires_restart, "vhttp_port", vhttp_port);
+        diff!(requires_restart, "vhttp_bind_ip", vhttp_bind_ip);
+        diff!(requires_restart, "vhttp_optional", vhttp_optional);
+        diff!(requires_restart, "port_range", entrypoint.port_range);
+        diff!(requires_restart, "exclude_ports", entrypoint.exclude_ports);
+        diff!(
+            requires_restart,
+            "ephemeral_ports",
+            entrypoint.ephemeral_ports
+        );
+        diff!(
+            requires_restart,
+            "external_base_path",
+            entrypoint.external_base_path
+        );
+        diff!(
+            requires_restart,
+            "max_pending_connections",
+            max_pending_connections
+        );
+        diff!(
+            requires_restart,
+            "max_pending_registrations",
+            max_pending_registrations
+        );
+        diff!(requires_restart, "memory_budget", memory_budget);
+        diff!(requires_restart, "http_cache", http_cache);
+        diff!(requires_restart, "interstitial_page", interstitial_page);
+        diff!(requires_restart, "control_rate_limit", control_rate_limit);
+        diff!(requires_restart, "payload_key", payload_key);
+        diff!(requires_restart, "auth_token", auth_token);
+        diff!(
+            requires_restart,
+            "registration_rate_limit",
+            registration_rate_limit
+        );
+        diff!(requires_restart, "control_tls", control_tls);
+        diff!(requires_restart, "bandwidth_limit", bandwidth_limit);
+        diff!(requires_restart, "tunnel_weights", tunnel_weights);
+        diff!(requires_restart, "tunnel_rate_limit", tunnel_rate_limit);
+        diff!(
+            requires_restart,
+            "connection_log_sample",
+            connection_log_sample
+        );
+        diff!(requires_restart, "data_send_timeout", data_send_timeout);
+        diff!(requires_restart, "disable_tcp", disable_tcp);
+        diff!(requires_restart, "disable_udp", disable_udp);
+        diff!(requires_restart, "disable_http", disable_http);
+        diff!(requires_restart, "admin_api", admin_api);
+        diff!(requires_restart, "grpc_reflection", grpc_reflection);
+        diff!(applied, "domain", entrypoint.domain);
+        diff!(applied, "ip", entrypoint.ip);
+        diff!(
+            applied,
+            "vhttp_behind_proxy_tls",
+            entrypoint.vhttp_behind_proxy_tls
+        );
+        diff!(applied, "subdomain_words", entrypoint.subdomain_words);
+        diff!(applied, "tcp_keepalive", tcp_keepalive);
+        diff!(applied, "max_in_flight_bytes", max_in_flight_bytes);
+        diff!(applied, "data_chunk_size", data_chunk_size);
+        diff!(applied, "max_request_deadline", max_request_deadline);
+        diff!(applied, "max_request_body", max_request_body);
+        diff!(applied, "max_response_body", max_response_body);
+        diff!(applied, "max_udp_sessions", max_udp_sessions);
+        diff!(applied, "udp_idle_timeout", udp_idle_timeout);
+        diff!(applied, "udp_buffer_pool", udp_buffer_pool);
+        diff!(applied, "close_timeout", close_timeout);
+
+        running.entrypoint.domain = config.entrypoint.domain;
+        running.entrypoint.ip = config.entrypoint.ip;
+        running.entrypoint.vhttp_behind_proxy_tls = config.entrypoint.vhttp_behind_proxy_tls;
+        running.entrypoint.subdomain_words = config.entrypoint.subdomain_words;
+        running.tcp_keepalive = config.tcp_keepalive;
+        running.max_in_flight_bytes = config.max_in_flight_bytes;
+        running.data_chunk_size = config.data_chunk_size;
+        running.max_request_deadline = config.max_request_deadline;
+        running.max_request_body = config.max_request_body;
+        running.max_response_body = config.max_response_body;
+        running.max_udp_sessions = config.max_udp_sessions;
+        running.udp_idle_timeout = config.udp_idle_timeout;
+        running.udp_buffer_pool = config.udp_buffer_pool;
+        running.close_timeout = config.close_timeout;
+        *self.settings.write().unwrap() = Settings::from(&*running);
+
+        if !reloaded.requires_restart.is_empty() {
+            warn!(keys = ?reloaded.requires_restart, "the changes require a restart, ignored");
+        }
+        info!(keys = ?reloaded.applied, "config reloaded");
+        reloaded
+    }
+}
+
+impl From<&Config> for Settings {
+    fn from(config: &Config) -> Self {
+        Self {
+            entrypoint: config.entrypoint.clone(),
+            tcp_keepalive: config.tcp_keepalive,
+            max_in_flight_bytes: config.max_in_flight_bytes,
+            data_chunk_size: config.data_chunk_size,
+            max_request_deadline: config.max_request_deadline,
+            max_request_body: config.max_request_body,
+            max_response_body: config.max_response_body,
+            max_udp_sessions: config.max_udp_sessions,
+            udp_idle_timeout: config.udp_idle_timeout,
+            udp_buffer_pool: config.udp_buffer_pool,
+            close_timeout: config.close_timeout,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_reload() {
+        let config = Config::default();
+        let settings = Arc::new(RwLock::new(Settings::from(&config)));
+        let reloader = Reloader::new(settings.clone(), config);
+
+        let reloaded = reloader.reload(Config {
+            control_port: 7610,
+            entrypoint: EntrypointConfig {
+                domain: vec!["example.com".to_string()],
+                port_range: 2000..=3000,
+                ..Default::default()
+            },
+            max_in_flight_bytes: 1024,
+            ..Default::default()
+        });
+        assert_eq!(
+            reloaded,
+            Reloaded {
+                applied: vec!["domain", "max_in_flight_bytes"],
+                requires_restart: vec!["control_port", "port_range"],
+            }
+        );
+        let settings = settings.read().unwrap().clone();
+        assert_eq!(settings.entrypoint.domain, vec!["example.com"]);
+        assert_eq!(settings.entrypoint.port_range, 1024..=65535);
+        assert_eq!(settings.max_in_flight_bytes, 1024);
+
+        // the ignored keys are reported again until the restart
+        let reloaded = reloader.reload(Config {
+            control_port: 7610,
+            ..Default::default()
+        });
+        assert_eq!(
+            reloaded,
+            Reloaded {
+                applied: vec!["domain", "max_in_flight_bytes"],
+                requires_restart: vec!["control_port"],
+            }
+        );
+    }
+}
@@ -0,0 +1,129 @@
+//! The random subdomains assigned to the http tunnels asking for one,
+//! e.g. `k3x9a2bd` by default, or `brave-otter-1234` drawn from the [`SubdomainWords`].
+use rand::{seq::SliceRandom, Rng};
+use serde::Deserialize;
+
+const RANDOM_LENGTH: usize = 8;
+const CHARSET: &[u8] = b"abcdefghijklmnopqrstuvwxyz0123456789";
+
+const ADJECTIVES: &[&str] = &[
+    "amber", "bold", "brave", "bright", "calm", "clever", "cosmic", "crisp", "daring", "eager",
+    "fancy", "gentle", "golden", "happy", "jolly", "keen", "lively", "lucky", "merry", "misty",
+    "noble", "proud", "quick", "quiet", "rapid", "shiny", "silent", "swift", "tidy", "vivid",
+    "witty", "zesty",
+];
+const NOUNS: &[&str] = &[
+    "badger", "beaver", "bison", "cobra", "condor", "coyote", "crane", "dolphin", "eagle",
+    "falcon", "ferret", "gecko", "heron", "ibis", "jaguar", "koala", "lemur", "lynx", "marmot",
+    "moose", "newt", "otter", "panda", "puffin", "quail", "raven", "salmon", "tiger", "walrus",
+    "wombat", "yak", "zebra",
+];
+
+/// SubdomainWords makes the random subdomains of an adjective, a noun and a number,
+/// e.g. `brave-otter-1234`, the default ones are built in.
+///
+/// A word repeated in the list is drawn more often.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct SubdomainWords {
+    pub adjectives: Vec<String>,
+    pub nouns: Vec<String>,
+}
+
+impl Default for SubdomainWords {
+    fn default() -> Self {
+        Self {
+            adjectives: ADJECTIVES.iter().map(|word| word.to_string()).collect(),
+            nouns: NOUNS.iter().map(|word| word.to_string()).collect(),
+        }
+    }
+}
+
+impl SubdomainWords {
+    /// the lists must not be empty, the words are lowercase letters, digits and `-`.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        for (name, words) in [("adjectives", &self.adjectives), ("nouns", &self.nouns)] {
+            anyhow::ensure!(
+                !words.is_empty(),
+                "the {} of the subdomain words are empty",
+                name
+            );
+            if let Some(word) = words.iter().find(|word| !is_label(word)) {
+                anyhow::bail!(
+                    "invalid subdomain word {:?}, expect lowercase letters, digits and '-'",
+                    word
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// a random subdomain, of the words if any.
+pub(crate) fn random_subdomain(words: Option<&SubdomainWords>, rng: &mut impl Rng) -> String {
+    match words {
+        Some(words) => format!(
+            "{}-{}-{:04}",
+            words.adjectives.choose(rng).map_or("", String::as_str),
+            words.nouns.choose(rng).map_or("", String::as_str),
+            rng.gen_range(0..10000)
+        ),
+        None => (0..RANDOM_LENGTH)
+            .map(|_| CHARSET[rng.gen_range(0..CHARSET.len())] as char)
+            .collect(),
+    }
+}
+
+fn is_label(word: &str) -> bool {
+    !word.is_empty()
+        && !word.starts_with('-')
+        && !word.ends_with('-')
+        && word
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-')
+}
+
+#[cfg(test)]
+mod test {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+
+    #[test]
+    fn test_random_subdomain() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let subdomain = random_subdomain(None, &mut rng);
+        assert_eq!(subdomain.len(), RANDOM_LENGTH);
+        assert!(subdomain.bytes().all(|b| CHARSET.contains(&b)));
+
+        let words = SubdomainWords::default();
+        assert!(words.validate().is_ok());
+        let subdomain = random_subdomain(Some(&words), &mut rng);
+        let parts: Vec<&str> = subdomain.split('-').collect();
+        assert_eq!(parts.len(), 3, "{}", subdomain);
+        assert!(words.adjectives.iter().any(|word| word == parts[0]));
+        assert!(words.nouns.iter().any(|word| word == parts[1]));
+        assert_eq!(parts[2].len(), 4);
+
+        let words = SubdomainWords {
+            adjectives: vec!["brave".to_string()],
+            nouns: vec!["otter".to_string()],
+        };
+        assert!(random_subdomain(Some(&words), &mut rng).starts_with("brave-otter-"));
+        for invalid in [
+            SubdomainWords {
+                adjectives: vec![],
+                nouns: vec!["otter".to_string()],
+            },
+            SubdomainWords {
+                adjectives: vec!["Brave".to_string()],
+                nouns: vec!["otter".to_string()],
+            },
+            SubdomainWords {
+                adjectives: vec!["brave".to_string()],
+                nouns: vec!["ot.ter".to_string()],
+            },
+        ] {
+            assert!(invalid.validate().is_err());
+        }
+    }
+}
@@ -0,0 +1,155 @@
+//! The tls of the control port, the clients connect with [`crate::client::ClientTls`].
+//!
+//! The handshakes run in their own tasks, so a slow client doesn't hold the others,
+//! the connections not finishing it within [`HANDSHAKE_TIMEOUT`] are dropped.
+use std::{
+    fs::File,
+    io::{self, BufReader},
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use anyhow::Context as _;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+};
+use tokio_rustls::{server::TlsStream, TlsAcceptor};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::transport::server::{Connected, TcpConnectInfo};
+use tracing::{debug, error};
+
+use crate::socket::AcceptBackoff;
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// ControlTls is the certificate chain and the private key of the control port, both in pem.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ControlTls {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+impl ControlTls {
+    /// load the files, the errors tell which one is invalid.
+    pub(crate) fn acceptor(&self) -> anyhow::Result<TlsAcceptor> {
+        let certs = rustls_pemfile::certs(&mut open(&self.cert)?)
+            .collect::<Result<Vec<_>, _>>()
+            .with_context(|| format!("invalid certificate {}", self.cert.display()))?;
+        anyhow::ensure!(
+            !certs.is_empty(),
+            "no certificate in {}",
+            self.cert.display()
+        );
+        let key = rustls_pemfile::private_key(&mut open(&self.key)?)
+            .with_context(|| format!("invalid private key {}", self.key.display()))?
+            .with_context(|| format!("no private key in {}", self.key.display()))?;
+        let mut config = rustls::ServerConfig::builder_with_provider(Arc::new(
+            rustls::crypto::ring::default_provider(),
+        ))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .context("the private key doesn't match the certificate")?;
+        // http/1.1 for the landing page and the admin api on the same port.
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+}
+
+fn open(path: &Path) -> anyhow::Result<BufReader<File>> {
+    let file = File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    Ok(BufReader::new(file))
+}
+
+/// accept the tls connections of the listener until the stream is dropped.
+pub(crate) fn incoming(
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+) -> ReceiverStream<io::Result<TlsConn>> {
+    let (tx, rx) = mpsc::channel(64);
+    tokio::spawn(async move {
+        let mut backoff = AcceptBackoff::new();
+        loop {
+            let (stream, addr) = tokio::select! {
+                _ = tx.closed() => return,
+                accepted = backoff.accept(&listener) => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(err) => {
+                        error!(?err, "failed to accept the control connection");
+                        let _ = tx.send(Err(err)).await;
+                        return;
+                    }
+                },
+            };
+            let acceptor = acceptor.clone();
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => {
+                        let _ = tx.send(Ok(TlsConn(stream))).await;
+                    }
+                    Ok(Err(err)) => debug!(?addr, ?err, "the tls handshake failed"),
+                    Err(_) => debug!(?addr, "the tls handshake timed out"),
+                }
+            });
+        }
+    });
+    ReceiverStream::new(rx)
+}
+
+/// TlsConn is a tls connection of the control port,
+/// it tells the address of the client like a plaintext one.
+pub(crate) struct TlsConn(TlsStream<TcpStream>);
+
+impl Connected for TlsConn {
+    type ConnectInfo = TcpConnectInfo;
+
+    fn connect_info(&self) -> Self::ConnectInfo {
+        self.0.get_ref().0.connect_info()
+    }
+}
+
+impl AsyncRead for TlsConn {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for TlsConn {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().0).poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().0).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.0.is_write_vectored()
+    }
+}
@@ -0,0 +1,169 @@
+//! The rewrite of the headers of the http tunnels, e.g. adding `X-Forwarded-Host`,
+//! stripping a header of the proxy or overriding the `Host` expected by the local server.
+//!
+//! The rules of the requests are applied after the requests are routed, so the `Host`
+//! still routes them to the tunnel, the rules of the responses are applied to the responses
+//! of the local server before they are cached.
+//! The bodies are streamed as they are, so the headers framing them can't be rewritten.
+use http::{header, HeaderMap, HeaderName, HeaderValue};
+use tonic::Status;
+
+use super::http::is_hop_by_hop;
+use crate::pb;
+
+/// the headers framing the bodies, e.g. a chunked body without its `Transfer-Encoding`
+/// would be read as the next message by the local server.
+const FRAMING_HEADERS: [HeaderName; 2] = [header::CONTENT_LENGTH, header::TRANSFER_ENCODING];
+
+/// HeaderRules are the validated rules of [`pb::HeaderRules`], applied in the order of
+/// remove, set and add.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct HeaderRules {
+    remove: Vec<HeaderName>,
+    set: Vec<(HeaderName, HeaderValue)>,
+    add: Vec<(HeaderName, HeaderValue)>,
+}
+
+impl HeaderRules {
+    pub(crate) fn apply(&self, headers: &mut HeaderMap) {
+        for name in &self.remove {
+            headers.remove(name);
+        }
+        for (name, value) in &self.set {
+            headers.insert(name, value.clone());
+        }
+        for (name, value) in &self.add {
+            // a request has a single host
+            if name == header::HOST {
+                headers.insert(name, value.clone());
+            } else {
+                headers.append(name, value.clone());
+            }
+        }
+    }
+}
+
+impl TryFrom<&pb::HeaderRules> for HeaderRules {
+    type Error = Status;
+
+    fn try_from(rules: &pb::HeaderRules) -> Result<Self, Self::Error> {
+        let remove = rules
+            .remove
+            .iter()
+            .map(|name| parse_name(name))
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            remove,
+            set: parse_headers(rules.set.iter())?,
+            add: parse_headers(rules.add.iter())?,
+        })
+    }
+}
+
+/// the headers are sorted by the names, the maps of the payload have no order.
+fn parse_headers<'a>(
+    headers: impl Iterator<Item = (&'a String, &'a String)>,
+) -> Result<Vec<(HeaderName, HeaderValue)>, Status> {
+    let mut parsed = headers
+        .map(|(name, value)| {
+            let value = HeaderValue::from_str(value).map_err(|_| {
+                Status::invalid_argument(format!("invalid value of header {:?}", name))
+            })?;
+            Ok((parse_name(name)?, value))
+        })
+        .collect::<Result<Vec<_>, Status>>()?;
+    parsed.sort_by(|(a, _), (b, _)| a.as_str().cmp(b.as_str()));
+    Ok(parsed)
+}
+
+/// the names are case-insensitive, they are lowercased.
+fn parse_name(name: &str) -> Result<HeaderName, Status> {
+    let name = HeaderName::from_bytes(name.as_bytes())
+        .map_err(|_| Status::invalid_argument(format!("invalid header name {:?}", name)))?;
+    if FRAMING_HEADERS.contains(&name) || is_hop_by_hop(name.as_str()) {
+        return Err(Status::invalid_argument(format!(
+            "the header {} can't be rewritten",
+            name
+        )));
+    }
+    Ok(name)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_header_rules() {
+        let rules = HeaderRules::try_from(&pb::HeaderRules {
+            add: [
+                ("X-Forwarded-Host", "example.com"),
+                ("Vary", "Origin"),
+                ("HOST", "localhost:3000"),
+            ]
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect(),
+            remove: vec!["X-Debug".to_string()],
+            set: [("User-Agent", "castle")]
+                .into_iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+        })
+        .unwrap();
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::HOST, HeaderValue::from_static("foo.example.com"));
+        headers.insert("x-debug", HeaderValue::from_static("1"));
+        headers.append(header::USER_AGENT, HeaderValue::from_static("curl"));
+        headers.append(header::USER_AGENT, HeaderValue::from_static("wget"));
+        headers.insert(header::VARY, HeaderValue::from_static("Accept"));
+        headers.insert(
+            header::TRANSFER_ENCODING,
+            HeaderValue::from_static("chunked"),
+        );
+        rules.apply(&mut headers);
+
+        assert!(!headers.contains_key("x-debug"));
+        assert_eq!(headers.get_all(header::HOST).iter().count(), 1);
+        assert_eq!(headers[header::HOST], "localhost:3000");
+        assert_eq!(headers["x-forwarded-host"], "example.com");
+        assert_eq!(
+            headers
+                .get_all(header::USER_AGENT)
+                .iter()
+                .collect::<Vec<_>>(),
+            vec!["castle"]
+        );
+        assert_eq!(
+            headers.get_all(header::VARY).iter().collect::<Vec<_>>(),
+            vec!["Accept", "Origin"]
+        );
+        assert_eq!(headers[header::TRANSFER_ENCODING], "chunked");
+    }
+
+    #[test]
+    fn test_invalid_header_rules() {
+        let remove = |name: &str| {
+            HeaderRules::try_from(&pb::HeaderRules {
+                remove: vec![name.to_string()],
+                ..Default::default()
+            })
+        };
+        assert!(remove("X-Debug").is_ok());
+        assert!(remove("bad name").is_err());
+        assert!(remove("Transfer-Encoding").is_err());
+        assert!(remove("content-length").is_err());
+        assert!(remove("Connection").is_err());
+
+        let set = |name: &str, value: &str| {
+            HeaderRules::try_from(&pb::HeaderRules {
+                set: [(name.to_string(), value.to_string())].into(),
+                ..Default::default()
+            })
+        };
+        assert!(set("Host", "localhost").is_ok());
+        assert!(set("Host", "local\nhost").is_err());
+        assert!(set("TRANSFER-ENCODING", "identity").is_err());
+    }
+}
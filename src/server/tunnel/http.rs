@@ -1,11 +1,17 @@
-use crate::bridge::BridgeData;
-use crate::event::{self, IncomingEventSender};
-
+use crate::bridge::{BridgeData, DataReceiver, MemoryBudget};
+use crate::constant;
+use crate::event::{HttpOptions, IncomingEventSender};
+use crate::socket::{set_tcp_keepalive, AcceptBackoff, TcpKeepalive};
+use crate::timing::{self, SetupTimer};
+
+use super::http_cache::{CacheLookup, ResponseCache};
+use super::interstitial::Interstitial;
+use super::rewrite::OriginRewriter;
 use super::{init_data_sender_bridge, BridgeResult};
 use anyhow::{Context as _, Result};
 use bytes::{BufMut as _, Bytes};
 use dashmap::DashMap;
-use futures::TryStreamExt;
+use futures::{Stream, StreamExt as _, TryStreamExt};
 use http::response::Builder;
 use http::{HeaderValue, StatusCode};
 use http_body::Frame;
@@ -17,41 +23,153 @@ use hyper::{body::Incoming, Request, Response};
 use hyper_util::rt::TokioIo;
 use std::convert::Infallible;
 use std::io::Write;
+use std::pin::Pin;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
 use tokio::net::TcpListener;
-use tokio::sync::{mpsc, oneshot};
+use tokio::sync::{mpsc, oneshot, Semaphore, SemaphorePermit};
 use tokio_stream::wrappers::ReceiverStream;
 use tokio_util::sync::CancellationToken;
-use tracing::{debug, error, info, info_span, Instrument as _};
+use tracing::{debug, error, info, info_span, warn, Instrument as _};
 
 static EMPTY_HOST: HeaderValue = HeaderValue::from_static("");
 const MAX_HEADERS: usize = 124;
 const MAX_HEADER_SIZE: usize = 4 * 1024; // 4k
+/// the headers of the connection to the local server, they aren't forwarded either way.
+const HOP_BY_HOP_HEADERS: [&str; 3] = ["connection", "keep-alive", "proxy-connection"];
+/// how long a request waits for the limits of the server, e.g. the pending connections
+/// and the memory budget, before it's rejected with 429.
+const ADMISSION_TIMEOUT: Duration = Duration::from_secs(1);
+/// the `Retry-After` seconds of the rejected requests.
+const RETRY_AFTER_SECS: u64 = 1;
+/// the deadline of the request asked by the user, see [`parse_deadline`],
+/// it's for the tunnel so it isn't forwarded.
+const DEADLINE_HEADER: &str = "x-tunnel-deadline";
+
+/// the body of the responses, it errors to abort the response, e.g. the body is too large.
+pub(crate) type ResponseBody = BoxBody<Bytes, std::io::Error>;
 
 pub(crate) struct Http {
     lookup: Arc<Box<dyn LookupRequest>>,
+    tcp_keepalive: Option<TcpKeepalive>,
+    connection_setups: Arc<Semaphore>,
+    max_in_flight_bytes: usize,
+    /// caps the deadlines of the requests, None ignores them.
+    max_request_deadline: Option<Duration>,
+    memory_budget: MemoryBudget,
+    cache: Option<ResponseCache>,
+    interstitial: Interstitial,
+    /// the external base path stripped from the requests, see [`Http::with_base_path`].
+    base_path: Option<String>,
 }
 
 /// LookupRequest is a trait that provides a method to
-/// lookups the request and returns [`IncomingEventSender`].
+/// lookups the request and returns [`HttpTarget`].
 ///
-/// http tunnel will use [`IncomingEventSender`] to create a bridge
+/// http tunnel will use the [`IncomingEventSender`] of the target to create a bridge
 /// between the control server and data server when receives a request.
 pub(crate) trait LookupRequest: Send + Sync {
-    fn lookup(&self, req: &Request<Incoming>) -> Option<IncomingEventSender>;
+    fn lookup(&self, req: &Request<Incoming>) -> Option<HttpTarget>;
+}
+
+/// HttpTarget is the tunnel serving the matched requests.
+#[derive(Clone)]
+pub(crate) struct HttpTarget {
+    pub sender: IncomingEventSender,
+    pub options: Arc<HttpOptions>,
+}
+
+impl HttpTarget {
+    pub(crate) fn new(sender: IncomingEventSender, options: HttpOptions) -> Self {
+        Self {
+            sender,
+            options: Arc::new(options),
+        }
+    }
+}
+
+impl From<IncomingEventSender> for HttpTarget {
+    fn from(sender: IncomingEventSender) -> Self {
+        Self::new(sender, HttpOptions::default())
+    }
 }
 
 impl Clone for Http {
     fn clone(&self) -> Self {
         Self {
             lookup: Arc::clone(&self.lookup),
+            tcp_keepalive: self.tcp_keepalive,
+            connection_setups: Arc::clone(&self.connection_setups),
+            max_in_flight_bytes: self.max_in_flight_bytes,
+            max_request_deadline: self.max_request_deadline,
+            memory_budget: self.memory_budget.clone(),
+            cache: self.cache.clone(),
+            interstitial: self.interstitial.clone(),
+            base_path: self.base_path.clone(),
         }
     }
 }
 
 impl Http {
-    pub(crate) fn new(lookup: Arc<Box<dyn LookupRequest>>) -> Self {
-        Self { lookup }
+    pub(crate) fn new(
+        lookup: Arc<Box<dyn LookupRequest>>,
+        connection_setups: Arc<Semaphore>,
+    ) -> Self {
+        Self {
+            lookup,
+            tcp_keepalive: None,
+            connection_setups,
+            max_in_flight_bytes: constant::DEFAULT_MAX_IN_FLIGHT_BYTES,
+            max_request_deadline: None,
+            memory_budget: MemoryBudget::new(None),
+            cache: None,
+            interstitial: Interstitial::default(),
+            base_path: None,
+        }
+    }
+
+    pub(crate) fn with_tcp_keepalive(mut self, keepalive: Option<TcpKeepalive>) -> Self {
+        self.tcp_keepalive = keepalive;
+        self
+    }
+
+    pub(crate) fn with_max_in_flight_bytes(mut self, max_in_flight_bytes: usize) -> Self {
+        self.max_in_flight_bytes = max_in_flight_bytes;
+        self
+    }
+
+    /// the users shorten the timeout of their requests up to the max, None ignores the deadlines.
+    pub(crate) fn with_max_request_deadline(mut self, max: Option<Duration>) -> Self {
+        self.max_request_deadline = max;
+        self
+    }
+
+    /// the new requests wait while the budget is exhausted.
+    pub(crate) fn with_memory_budget(mut self, memory_budget: MemoryBudget) -> Self {
+        self.memory_budget = memory_budget;
+        self
+    }
+
+    /// the cacheable GET responses are served from the cache, None disables the cache.
+    pub(crate) fn with_cache(mut self, cache: Option<ResponseCache>) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    /// the page shown by the tunnels with the interstitial enabled.
+    pub(crate) fn with_interstitial(mut self, interstitial: Interstitial) -> Self {
+        self.interstitial = interstitial;
+        self
+    }
+
+    /// the requests must start with the base path, e.g. `/tunnels`, it's stripped before
+    /// they are routed and forwarded, the urls made for the users are put under it.
+    pub(crate) fn with_base_path(mut self, base_path: Option<String>) -> Self {
+        self.base_path = base_path
+            .map(|base_path| base_path.trim_end_matches('/').to_string())
+            .filter(|base_path| !base_path.is_empty());
+        self
     }
 
     pub(crate) async fn serve_with_listener(
@@ -61,6 +179,7 @@ impl Http {
     ) {
         let this = Arc::new(self);
         let http1_builder = Arc::new(http1::Builder::new());
+        let mut backoff = AcceptBackoff::new();
 
         loop {
             let cancel = cancel.clone();
@@ -68,7 +187,19 @@ impl Http {
                 _ = cancel.cancelled() => {
                     break;
                 },
-                Ok((stream, _addr)) = listener.accept() => {
+                result = backoff.accept(&listener) => {
+                    let stream = match result {
+                        Ok((stream, _addr)) => stream,
+                        Err(err) => {
+                            error!(?err, "failed to accept connection, stop the http server");
+                            break;
+                        }
+                    };
+                    if let Some(keepalive) = &this.tcp_keepalive {
+                        if let Err(err) = set_tcp_keepalive(&stream, keepalive) {
+                            warn!(err = ?err, "failed to set tcp keepalive");
+                        }
+                    }
                     let this = Arc::clone(&this);
                     let http1_builder = Arc::clone(&http1_builder);
 
@@ -79,7 +210,7 @@ impl Http {
                             let new_service = service_fn(move |req| {
                                 let http_tunnel = this.clone();
                                 async move {
-                                    Ok::<Response<BoxBody<Bytes, Infallible>>, hyper::Error>(
+                                    Ok::<Response<ResponseBody>, hyper::Error>(
                                         http_tunnel.call(req).await,
                                     )
                                 }
@@ -87,7 +218,9 @@ impl Http {
 
                             tokio::select! {
                                 _ = cancel.cancelled() => {}
-                                _ = http1_builder.serve_connection(io, new_service) => {
+                                // the upgraded connections, e.g. the websockets, are served
+                                // by the tasks of their requests
+                                _ = http1_builder.serve_connection(io, new_service).with_upgrades() => {
                                     info!("http1 connection closed");
                                 }
                             }
@@ -100,36 +233,150 @@ impl Http {
         info!("http server stopped");
     }
 
-    async fn call(&self, req: Request<Incoming>) -> Response<BoxBody<Bytes, Infallible>> {
+    async fn call(&self, mut req: Request<Incoming>) -> Response<ResponseBody> {
+        let base_path = self.base_path.as_deref().unwrap_or_default();
+        if !base_path.is_empty() {
+            match strip_base_path(req.uri(), base_path) {
+                Some(uri) => *req.uri_mut() = uri,
+                None => return not_found(),
+            }
+        }
         let sender = self.lookup.lookup(&req);
         if sender.is_none() {
+            return not_found();
+        }
+        let target = sender.unwrap();
+        let allowed_methods = &target.options.allowed_methods;
+        if !allowed_methods.is_empty() && !allowed_methods.contains(req.method()) {
+            let allow = allowed_methods
+                .iter()
+                .map(http::Method::as_str)
+                .collect::<Vec<_>>()
+                .join(", ");
             return Response::builder()
-                .status(404)
-                .body(BoxBody::new(Full::new(Bytes::from_static(b"not found"))))
+                .status(StatusCode::METHOD_NOT_ALLOWED)
+                .header(http::header::ALLOW, allow)
+                .body(full(Bytes::from_static(b"method not allowed")))
                 .unwrap();
         }
-        let event_sender = sender.unwrap();
-        let bridge = match init_data_sender_bridge(event_sender).await {
+        let max_request_body = target.options.max_request_body;
+        if content_length(req.headers()).is_some_and(|length| exceeds(length, max_request_body)) {
+            return payload_too_large();
+        }
+        if target.options.interstitial {
+            if let Some(response) = self.interstitial.intercept(&req, base_path) {
+                return response.map(|body| body.map_err(|never| match never {}).boxed());
+            }
+        }
+        let mut cache = self
+            .cache
+            .as_ref()
+            .and_then(|cache| Some((cache, cache.lookup(&req)?)));
+        if let Some((cache, lookup)) = &mut cache {
+            if let Some((builder, body)) = cache.get(lookup, &target.options) {
+                return builder.body(full(body)).unwrap();
+            }
+            // the response of the identical request in flight may be cached by now
+            if target.options.coalesce && cache.coalesce(lookup).await {
+                if let Some((builder, body)) = cache.get(lookup, &target.options) {
+                    return builder.body(full(body)).unwrap();
+                }
+            }
+        }
+        let mut timer = SetupTimer::start();
+        let permit = match self.admit().await {
+            Ok(permit) => permit,
+            Err(response) => return response,
+        };
+        let admission = timer.lap();
+        let host = req
+            .headers()
+            .get(http::header::HOST)
+            .and_then(|host| host.to_str().ok())
+            .map(|host| strip_port(host).to_ascii_lowercase())
+            .unwrap_or_default();
+        let bridge = init_data_sender_bridge(
+            target.sender,
+            host,
+            self.max_in_flight_bytes,
+            self.memory_budget.clone(),
+        )
+        .await;
+        drop(permit);
+        let bridge_setup = timer.lap();
+        let bridge = match bridge {
             Ok(bridge) => bridge,
             Err(err) => {
                 error!(err = ?err, "failed to create bridge");
                 return Response::builder()
                     .status(502)
-                    .body(BoxBody::new(Full::new(Bytes::from_static(
-                        b"local server error",
-                    ))))
+                    .body(full(Bytes::from_static(b"local server error")))
                     .unwrap();
             }
         };
 
-        Self::handle_http_request(req, bridge).await
+        let request_timeout = request_timeout(&req, &target.options, self.max_request_deadline);
+        let response = Self::handle_http_request(
+            req,
+            bridge,
+            &target.options,
+            request_timeout,
+            cache,
+            base_path,
+        )
+        .await;
+        let first_byte = timer.lap();
+        debug!(
+            target: timing::TARGET,
+            ?admission,
+            bridge = ?bridge_setup,
+            ?first_byte,
+            total = ?timer.total(),
+            status = response.status().as_u16(),
+            "http request set up"
+        );
+        response
+    }
+
+    /// wait for the limits of the server, the browsers and the api clients get
+    /// a 429 with `Retry-After` instead of hanging if they are still hit after [`ADMISSION_TIMEOUT`].
+    async fn admit(&self) -> Result<SemaphorePermit<'_>, Response<ResponseBody>> {
+        let admit = async {
+            self.memory_budget.wait_available().await;
+            self.connection_setups.acquire().await.unwrap()
+        };
+        match tokio::time::timeout(ADMISSION_TIMEOUT, admit).await {
+            Ok(permit) => Ok(permit),
+            Err(_) => {
+                warn!("the limits of the server are hit, rejected the request");
+                Err(Response::builder()
+                    .status(StatusCode::TOO_MANY_REQUESTS)
+                    .header(http::header::RETRY_AFTER, RETRY_AFTER_SECS)
+                    .body(full(Bytes::from_static(b"too many requests")))
+                    .unwrap())
+            }
+        }
     }
 
     async fn handle_http_request(
-        req: Request<Incoming>,
+        mut req: Request<Incoming>,
         bridge: BridgeResult,
-    ) -> Response<BoxBody<Bytes, Infallible>> {
-        let (headers, mut body_stream) = request_to_stream(req)
+        options: &Arc<HttpOptions>,
+        request_timeout: Option<Duration>,
+        cache: Option<(&ResponseCache, CacheLookup)>,
+        base_path: &str,
+    ) -> Response<ResponseBody> {
+        let rewriter = (!options.rewrite_origins.is_empty()).then(|| {
+            // the local paths are under the base path stripped from the request
+            let public = format!("{}{}", public_origin(&req, options), base_path);
+            OriginRewriter::new(&options.rewrite_origins, &public)
+        });
+        // after the public origin is taken from the host
+        options.request_headers.apply(req.headers_mut());
+        let on_upgrade = is_upgrade(req.headers()).then(|| hyper::upgrade::on(&mut req));
+        // the timeout covers the whole response, not only its headers
+        let deadline = request_timeout.map(|timeout| tokio::time::Instant::now() + timeout);
+        let (headers, chunked, mut body_stream) = request_to_stream(req)
             .await
             .with_context(|| {
                 bridge.remove_bridge_sender.cancel();
@@ -140,10 +387,14 @@ impl Http {
         let data_sender = bridge.data_sender.clone();
         let remove_bridge_sender = bridge.remove_bridge_sender.clone();
         let client_cancel_receiver = bridge.client_cancel_receiver.clone();
+        let max_request_body = options.max_request_body;
+        // the request body is counted as it flows, the Content-Length may be absent or wrong
+        let (too_large_tx, mut too_large_rx) = oneshot::channel::<()>();
 
         tokio::spawn(async move {
+            let mut received = 0u64;
             data_sender
-                .send(headers)
+                .send(headers.into())
                 .await
                 .with_context(|| {
                     remove_bridge_sender.cancel();
@@ -159,13 +410,29 @@ impl Http {
                     data = body_stream.try_next() => {
                         match data {
                             Ok(Some(data)) => {
-                                data_sender.send(data.to_vec()).await.unwrap();
+                                received += data.len() as u64;
+                                if exceeds(received, max_request_body) {
+                                    debug!(?max_request_body, "the request body is too large");
+                                    let _ = too_large_tx.send(());
+                                    remove_bridge_sender.cancel();
+                                    return;
+                                }
+                                let data = if chunked {
+                                    Bytes::from(encode_chunk(&data))
+                                } else {
+                                    data
+                                };
+                                data_sender.send(data).await.unwrap();
                             }
                             Ok(None) => {
-                                // TODO(sword): reafctor this logic into io module
-                                // sending a empty vec to indicate the end of the body
-                                // then io::copy() will finish the transfer on the client side.
-                                data_sender.send(vec![]).await.unwrap();
+                                if chunked {
+                                    // the trailers are dropped
+                                    let _ = data_sender.send(Bytes::from_static(b"0\r\n\r\n")).await;
+                                }
+                                // the connection to the local server isn't half closed,
+                                // some servers drop the response being handled on it,
+                                // the request is sent with `connection: close` instead,
+                                // the bridge is closed once the local server closes it.
                                 return;
                             }
                             Err(err) => {
@@ -190,30 +457,113 @@ impl Http {
             Some(header_tx), // wrapping Option for send once
             body_tx,
             client_cancel_receiver,
-            bridge.remove_bridge_sender,
+            bridge.remove_bridge_sender.clone(),
         ));
 
         tokio::select! {
+            // the removed bridge may close the other branches as well
+            biased;
+            Ok(()) = &mut too_large_rx => payload_too_large(),
             _ = bridge.client_cancel_receiver.cancelled() => {
                 Response::builder()
                     .status(500)
-                    .body(BoxBody::new(Full::new(Bytes::from_static(b"client cancelled"))))
+                    .body(full(Bytes::from_static(b"client cancelled")))
+                    .unwrap()
+            }
+            _ = async {
+                match deadline {
+                    Some(deadline) => tokio::time::sleep_until(deadline).await,
+                    None => std::future::pending().await,
+                }
+            } => {
+                // the backend doesn't respond the headers in time,
+                // removing the bridge closes the data stream of the client.
+                debug!(?request_timeout, "http request timed out");
+                bridge.remove_bridge_sender.cancel();
+                Response::builder()
+                    .status(StatusCode::GATEWAY_TIMEOUT)
+                    .body(full(Bytes::from_static(b"gateway timeout")))
                     .unwrap()
             }
             header = header_rx => {
-                // get response builder from header
-                let http_builder = header.unwrap();
+                // get response builder from header,
+                // the sender is dropped if the bridge is closed before the headers,
+                // e.g. the client fails to dial the local server.
+                let Ok(http_builder) = header else {
+                    debug!("the bridge closed before the response headers");
+                    return Response::builder()
+                        .status(StatusCode::BAD_GATEWAY)
+                        .body(full(Bytes::from_static(b"local server error")))
+                        .unwrap();
+                };
                 match http_builder {
                     Ok(http_builder) => {
-                        let stream = ReceiverStream::new(body_rx);
+                        let mut head = http_builder.body(()).unwrap();
+                        if head.status() == StatusCode::SWITCHING_PROTOCOLS {
+                            let Some(on_upgrade) = on_upgrade else {
+                                debug!("the local server switched protocols unasked");
+                                bridge.remove_bridge_sender.cancel();
+                                return Response::builder()
+                                    .status(StatusCode::BAD_GATEWAY)
+                                    .body(full(Bytes::from_static(b"local server error")))
+                                    .unwrap();
+                            };
+                            tokio::spawn(copy_upgraded(
+                                on_upgrade,
+                                body_rx,
+                                bridge.data_sender,
+                                bridge.remove_bridge_sender,
+                            ));
+                            // the connection header of the local server isn't forwarded
+                            head.headers_mut().insert(
+                                http::header::CONNECTION,
+                                HeaderValue::from_static("upgrade"),
+                            );
+                            options.response_headers.apply(head.headers_mut());
+                            return head.map(|()| full(Bytes::new()));
+                        }
+                        let max_response_body = options.max_response_body;
+                        if content_length(head.headers())
+                            .is_some_and(|length| exceeds(length, max_response_body))
+                        {
+                            debug!(?max_response_body, "the response body is too large");
+                            bridge.remove_bridge_sender.cancel();
+                            return Response::builder()
+                                .status(StatusCode::BAD_GATEWAY)
+                                .body(full(Bytes::from_static(b"response too large")))
+                                .unwrap();
+                        }
+                        let body_rewriter = rewriter
+                            .filter(|rewriter| rewriter.rewrite_headers(head.headers_mut()));
+                        options.response_headers.apply(head.headers_mut());
+                        let mut pending = cache
+                            .and_then(|(cache, lookup)| cache.store(lookup, options, &head));
+                        let stream = rewrite_body(ReceiverStream::new(body_rx), body_rewriter);
+                        let stream = limit_body(
+                            stream,
+                            max_response_body,
+                            bridge.remove_bridge_sender.clone(),
+                        );
+                        let stream = deadline_body(
+                            stream,
+                            deadline,
+                            bridge.remove_bridge_sender.clone(),
+                        );
+                        let stream = stream.inspect(move |frame| {
+                            if let (Some(pending), Ok(frame)) = (&mut pending, frame) {
+                                if let Some(data) = frame.data_ref() {
+                                    pending.push(data);
+                                }
+                            }
+                        });
                         let body = BoxBody::new(StreamBody::new(stream));
-                        http_builder.body(body).unwrap()
+                        head.map(|()| body)
                     },
                     Err(err) => {
                         error!(err = ?err, "failed to get response builder");
                         Response::builder()
                             .status(500)
-                            .body(BoxBody::new(Full::new(Bytes::from_static(b"failed to get response builder"))))
+                            .body(full(Bytes::from_static(b"failed to get response builder")))
                             .unwrap()
                     },
                 }
@@ -286,6 +636,9 @@ impl ResponseHeaderScanner {
                     _ => http::Version::HTTP_11,
                 });
         for header in resp.headers {
+            if is_hop_by_hop(header.name) {
+                continue;
+            }
             http_builder = http_builder.header(header.name, header.value);
         }
         Ok(Some((http_builder, body_part.to_vec())))
@@ -303,7 +656,7 @@ impl ResponseHeaderScanner {
 }
 
 async fn receive_response(
-    mut data_receiver: mpsc::Receiver<BridgeData>,
+    mut data_receiver: DataReceiver,
     mut header_tx: Option<oneshot::Sender<Result<Builder>>>,
     body_tx: mpsc::Sender<Result<Frame<Bytes>, Infallible>>,
     client_cancel_receiver: CancellationToken,
@@ -352,8 +705,9 @@ async fn receive_response(
                             let _ = body_tx.send(Ok(frame)).await;
                         }
                     }
-                    _ => {
-                        panic!("unexpected data type");
+                    BridgeData::Sender(_) => {
+                        error!("unexpected sender after the bridge handshake");
+                        break;
                     }
                 }
             }
@@ -362,10 +716,18 @@ async fn receive_response(
     remove_bridge_sender.cancel();
 }
 
+/// the raw head of the request and its body, true if the body is chunked,
+/// the body is decoded by hyper, so it's encoded again as the head tells.
 async fn request_to_stream(
     req: Request<Incoming>,
-) -> Result<(Vec<u8>, StreamBody<BodyDataStream<Incoming>>)> {
+) -> Result<(Vec<u8>, bool, StreamBody<BodyDataStream<Incoming>>)> {
     let (parts, body) = req.into_parts();
+    let chunked = parts
+        .headers
+        .get(http::header::TRANSFER_ENCODING)
+        .and_then(|encoding| encoding.to_str().ok())
+        .and_then(|encoding| encoding.rsplit(',').next())
+        .is_some_and(|encoding| encoding.trim().eq_ignore_ascii_case("chunked"));
     let mut buf = vec![].writer();
 
     // e.g. GET / HTTP/1.1
@@ -375,46 +737,377 @@ async fn request_to_stream(
         parts.method, parts.uri, parts.version
     )?;
     for (key, value) in parts.headers.iter() {
+        if is_hop_by_hop(key.as_str()) || key == DEADLINE_HEADER {
+            continue;
+        }
         write!(buf, "{}: {}\r\n", key, value.to_str().unwrap())?;
     }
-    let _ = buf.write(b"\r\n")?;
+    if is_upgrade(&parts.headers) {
+        // the connection is switched to the other protocol if the local server agrees,
+        // see `copy_upgraded`
+        let _ = buf.write(b"connection: upgrade\r\n\r\n")?;
+    } else {
+        // one request per connection of the local server, see `handle_http_request`
+        let _ = buf.write(b"connection: close\r\n\r\n")?;
+    }
 
     let body = StreamBody::new(body.into_data_stream());
-    Ok((buf.into_inner(), body))
+    Ok((buf.into_inner(), chunked, body))
+}
+
+/// the request asks to switch the connection to another protocol, e.g. a websocket,
+/// `Connection: Upgrade` with the `Upgrade` of the protocol.
+fn is_upgrade(headers: &http::HeaderMap) -> bool {
+    headers.contains_key(http::header::UPGRADE)
+        && headers
+            .get_all(http::header::CONNECTION)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|token| token.trim().eq_ignore_ascii_case("upgrade"))
+}
+
+/// copy the bytes between the upgraded connection of the user and the bridge
+/// after the local server switched protocols, e.g. the frames of a websocket,
+/// like a tcp tunnel until either side closes.
+async fn copy_upgraded(
+    on_upgrade: hyper::upgrade::OnUpgrade,
+    mut body_rx: mpsc::Receiver<Result<Frame<Bytes>, Infallible>>,
+    data_sender: mpsc::Sender<Bytes>,
+    remove_bridge_sender: CancellationToken,
+) {
+    let upgraded = match on_upgrade.await {
+        Ok(upgraded) => upgraded,
+        Err(err) => {
+            debug!(?err, "failed to upgrade the connection");
+            remove_bridge_sender.cancel();
+            return;
+        }
+    };
+    let (mut reader, mut writer) = tokio::io::split(TokioIo::new(upgraded));
+    let to_local = async {
+        let mut buf = vec![0; constant::DEFAULT_BUF_SIZE];
+        loop {
+            match reader.read(&mut buf).await {
+                Ok(0) => return,
+                Ok(n) => {
+                    if data_sender
+                        .send(Bytes::copy_from_slice(&buf[..n]))
+                        .await
+                        .is_err()
+                    {
+                        return;
+                    }
+                }
+                Err(err) => {
+                    debug!(?err, "failed to read the upgraded connection");
+                    return;
+                }
+            }
+        }
+    };
+    let to_user = async {
+        // the frames end once the local server closes the connection
+        while let Some(Ok(frame)) = body_rx.recv().await {
+            let Ok(data) = frame.into_data() else {
+                continue;
+            };
+            if writer.write_all(&data).await.is_err() {
+                return;
+            }
+        }
+        let _ = writer.shutdown().await;
+    };
+    tokio::select! {
+        _ = to_local => {}
+        _ = to_user => {}
+    }
+    remove_bridge_sender.cancel();
+}
+
+fn encode_chunk(data: &[u8]) -> Vec<u8> {
+    let mut chunk = format!("{:x}\r\n", data.len()).into_bytes();
+    chunk.reserve(data.len() + 2);
+    chunk.extend_from_slice(data);
+    chunk.extend_from_slice(b"\r\n");
+    chunk
+}
+
+/// the timeout of the request, the shorter one of the tunnel and the deadline of the user,
+/// the deadline is capped at the max.
+fn request_timeout<B>(
+    req: &Request<B>,
+    options: &HttpOptions,
+    max_deadline: Option<Duration>,
+) -> Option<Duration> {
+    let deadline = max_deadline.and_then(|max| {
+        let value = req.headers().get(DEADLINE_HEADER)?;
+        match value.to_str().ok().and_then(parse_deadline) {
+            Some(deadline) => Some(deadline.min(max)),
+            None => {
+                debug!(?value, "ignored the invalid deadline of the request");
+                None
+            }
+        }
+    });
+    match (options.request_timeout, deadline) {
+        (Some(timeout), Some(deadline)) => Some(timeout.min(deadline)),
+        (timeout, deadline) => timeout.or(deadline),
+    }
+}
+
+/// parse the deadline in the `grpc-timeout` format, at most 8 digits followed by the unit,
+/// `H`(hours), `M`(minutes), `S`(seconds), `m`(milliseconds), `u`(microseconds) or `n`(nanoseconds),
+/// e.g. `500m`.
+fn parse_deadline(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if !value.is_ascii() || value.len() < 2 {
+        return None;
+    }
+    let (digits, unit) = value.split_at(value.len() - 1);
+    if digits.len() > 8 || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let n: u64 = digits.parse().ok()?;
+    Some(match unit {
+        "H" => Duration::from_secs(n * 60 * 60),
+        "M" => Duration::from_secs(n * 60),
+        "S" => Duration::from_secs(n),
+        "m" => Duration::from_millis(n),
+        "u" => Duration::from_micros(n),
+        "n" => Duration::from_nanos(n),
+        _ => return None,
+    })
+}
+
+/// the origin the user requested, e.g. `https://foo.example.com`,
+/// the scheme is told by the proxy in front of the server if any.
+fn public_origin<B>(req: &Request<B>, options: &HttpOptions) -> String {
+    let headers = req.headers();
+    let scheme = headers
+        .get("x-forwarded-proto")
+        .and_then(|proto| proto.to_str().ok())
+        .and_then(|proto| proto.split(',').next())
+        .map(str::trim)
+        .filter(|proto| matches!(*proto, "http" | "https"))
+        .unwrap_or(if options.https { "https" } else { "http" });
+    let host = headers
+        .get(http::header::HOST)
+        .unwrap_or(&EMPTY_HOST)
+        .to_str()
+        .unwrap_or_default();
+    format!("{}://{}", scheme, host)
 }
 
-/// FixedRegistry is a registry that always returns the fixed sender.
+/// the uri stripped of the base path, None if the path isn't under it,
+/// the base path matches the whole segments, e.g. `/tunnels` doesn't match `/tunnelsfoo`.
+fn strip_base_path(uri: &http::Uri, base_path: &str) -> Option<http::Uri> {
+    let rest = uri.path().strip_prefix(base_path)?;
+    if !rest.is_empty() && !rest.starts_with('/') {
+        return None;
+    }
+    let path = if rest.is_empty() { "/" } else { rest };
+    let path_and_query = match uri.query() {
+        Some(query) => format!("{}?{}", path, query),
+        None => path.to_string(),
+    };
+    let mut parts = uri.clone().into_parts();
+    parts.path_and_query = Some(path_and_query.parse().ok()?);
+    http::Uri::from_parts(parts).ok()
+}
+
+/// rewrite the frames of the body if any, the bytes held back by the rewriter are sent at the end.
+fn rewrite_body(
+    stream: ReceiverStream<Result<Frame<Bytes>, Infallible>>,
+    rewriter: Option<OriginRewriter>,
+) -> Pin<Box<dyn Stream<Item = Result<Frame<Bytes>, Infallible>> + Send + Sync>> {
+    let Some(rewriter) = rewriter else {
+        return Box::pin(stream);
+    };
+    Box::pin(futures::stream::unfold(
+        (stream, Some(rewriter)),
+        |(mut stream, mut rewriter)| async move {
+            let frame = match stream.next().await {
+                Some(Ok(frame)) => {
+                    frame.map_data(|data| Bytes::from(rewriter.as_mut().unwrap().rewrite(&data)))
+                }
+                None => Frame::data(Bytes::from(rewriter.take()?.finish())),
+            };
+            Some((Ok(frame), (stream, rewriter)))
+        },
+    ))
+}
+
+/// abort the body once it's larger than the max, the bridge is removed to close the local connection.
+///
+/// The frames are counted as they flow, so the response is cut
+/// instead of ending cleanly when the body is longer than expected.
+fn limit_body(
+    stream: impl Stream<Item = Result<Frame<Bytes>, Infallible>>,
+    max: Option<u64>,
+    remove_bridge_sender: CancellationToken,
+) -> impl Stream<Item = Result<Frame<Bytes>, std::io::Error>> {
+    let mut sent = 0u64;
+    stream.map(move |frame| {
+        let frame = frame.unwrap_or_else(|never| match never {});
+        if let Some(data) = frame.data_ref() {
+            sent += data.len() as u64;
+            if exceeds(sent, max) {
+                debug!(?max, "the response body is too large, aborted");
+                remove_bridge_sender.cancel();
+                return Err(std::io::Error::other("the response body is too large"));
+            }
+        }
+        Ok(frame)
+    })
+}
+
+/// abort the response body unfinished at the deadline of the request.
+fn deadline_body(
+    stream: impl Stream<Item = Result<Frame<Bytes>, std::io::Error>> + Send + Sync + 'static,
+    deadline: Option<tokio::time::Instant>,
+    remove_bridge_sender: CancellationToken,
+) -> impl Stream<Item = Result<Frame<Bytes>, std::io::Error>> {
+    let state = (Box::pin(stream), deadline, remove_bridge_sender);
+    futures::stream::unfold(Some(state), |state| async move {
+        let (mut stream, deadline, remove_bridge_sender) = state?;
+        let frame = match deadline {
+            Some(deadline) => match tokio::time::timeout_at(deadline, stream.next()).await {
+                Ok(frame) => frame,
+                Err(_) => {
+                    debug!("the response body isn't finished in time, aborted");
+                    remove_bridge_sender.cancel();
+                    let err = std::io::Error::other("the response body isn't finished in time");
+                    return Some((Err(err), None));
+                }
+            },
+            None => stream.next().await,
+        };
+        frame.map(|frame| (frame, Some((stream, deadline, remove_bridge_sender))))
+    })
+}
+
+fn exceeds(length: u64, max: Option<u64>) -> bool {
+    max.is_some_and(|max| length > max)
+}
+
+fn content_length(headers: &http::HeaderMap) -> Option<u64> {
+    headers
+        .get(http::header::CONTENT_LENGTH)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+fn not_found() -> Response<ResponseBody> {
+    Response::builder()
+        .status(404)
+        .body(full(Bytes::from_static(b"not found")))
+        .unwrap()
+}
+
+fn payload_too_large() -> Response<ResponseBody> {
+    Response::builder()
+        .status(StatusCode::PAYLOAD_TOO_LARGE)
+        .header(http::header::CONNECTION, "close")
+        .body(full(Bytes::from_static(b"payload too large")))
+        .unwrap()
+}
+
+fn full(body: Bytes) -> ResponseBody {
+    Full::new(body).map_err(|never| match never {}).boxed()
+}
+
+pub(super) fn is_hop_by_hop(name: &str) -> bool {
+    HOP_BY_HOP_HEADERS
+        .iter()
+        .any(|header| header.eq_ignore_ascii_case(name))
+}
+
+/// FixedRegistry is a registry that always returns the fixed target.
 #[derive(Clone)]
 pub(crate) struct FixedRegistry {
-    sender: mpsc::Sender<event::UserIncoming>,
+    target: HttpTarget,
 }
 
 impl FixedRegistry {
-    pub(crate) fn new(sender: mpsc::Sender<event::UserIncoming>) -> Self {
-        Self { sender }
+    pub(crate) fn new(target: impl Into<HttpTarget>) -> Self {
+        Self {
+            target: target.into(),
+        }
     }
 }
 
 impl LookupRequest for FixedRegistry {
-    fn lookup(&self, _: &Request<Incoming>) -> Option<mpsc::Sender<event::UserIncoming>> {
-        Some(self.sender.clone())
+    fn lookup(&self, _: &Request<Incoming>) -> Option<HttpTarget> {
+        Some(self.target.clone())
     }
 }
 
 /// DynamicRegistry is a registry that can register and unregister the domain and subdomain.
+///
+/// The tunnels of the same domain or subdomain are told apart by their path prefixes.
 #[derive(Clone, Default)]
 pub(crate) struct DynamicRegistry {
-    domains: Arc<DashMap<Bytes, mpsc::Sender<event::UserIncoming>>>,
-    subdomains: Arc<DashMap<Bytes, mpsc::Sender<event::UserIncoming>>>,
+    domains: Arc<DashMap<Bytes, Routes>>,
+    subdomains: Arc<DashMap<Bytes, Routes>>,
+}
+
+/// Routes are the tunnels of a host, the longest path prefix matching the request wins.
+#[derive(Clone, Default)]
+struct Routes(Vec<(String, HttpTarget)>);
+
+impl Routes {
+    fn insert(&mut self, path_prefix: &str, target: HttpTarget) {
+        let path_prefix = normalize_path_prefix(path_prefix);
+        self.0.retain(|(prefix, _)| prefix != path_prefix);
+        self.0.push((path_prefix.to_string(), target));
+        self.0
+            .sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+    }
+
+    fn remove(&mut self, path_prefix: &str) {
+        let path_prefix = normalize_path_prefix(path_prefix);
+        self.0.retain(|(prefix, _)| prefix != path_prefix);
+    }
+
+    fn contains(&self, path_prefix: &str) -> bool {
+        let path_prefix = normalize_path_prefix(path_prefix);
+        self.0.iter().any(|(prefix, _)| prefix == path_prefix)
+    }
+
+    fn matched(&self, path: &str) -> Option<HttpTarget> {
+        self.0
+            .iter()
+            .find(|(prefix, _)| path_matches(path, prefix))
+            .map(|(_, target)| target.clone())
+    }
 }
 
 impl LookupRequest for DynamicRegistry {
-    fn lookup(&self, req: &Request<Incoming>) -> Option<mpsc::Sender<event::UserIncoming>> {
+    fn lookup(&self, req: &Request<Incoming>) -> Option<HttpTarget> {
         let host = req.headers().get("host").unwrap_or(&EMPTY_HOST);
-        let host = host.to_str().unwrap_or_default();
-        debug!(host, "matching host");
+        self.route(host.to_str().unwrap_or_default(), req.uri().path())
+    }
+}
+
+impl DynamicRegistry {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// route returns the target which serves the requests to the host and the path.
+    ///
+    /// the full host is matched against the domains first,
+    /// then the first label of the host is matched against the subdomains,
+    /// the longest path prefix of the matched host wins.
+    pub(crate) fn route(&self, host: &str, path: &str) -> Option<HttpTarget> {
+        let host = strip_port(host);
+        debug!(host, path, "matching host");
         // match the host
-        let result = self.get_domain(Bytes::copy_from_slice(host.as_bytes()));
+        let result = self.get_domain(Bytes::copy_from_slice(host.as_bytes()), path);
         if result.is_some() {
             return result;
         }
@@ -423,56 +1116,97 @@ impl LookupRequest for DynamicRegistry {
         let subdomain = host.split('.').next().unwrap_or_default();
         debug!("matching subdomain");
         let subdomain = Bytes::copy_from_slice(subdomain.as_bytes());
-        self.get_subdomain(subdomain)
-    }
-}
-
-impl DynamicRegistry {
-    pub(crate) fn new() -> Self {
-        Self::default()
+        self.get_subdomain(subdomain, path)
     }
 
     pub(crate) fn register_domain(
         &self,
         domain: Bytes,
-        conn_event_chan: mpsc::Sender<event::UserIncoming>,
+        path_prefix: &str,
+        target: impl Into<HttpTarget>,
     ) {
-        self.domains.insert(domain, conn_event_chan);
+        register(&self.domains, domain, path_prefix, target.into());
+    }
+
+    pub(crate) fn unregister_domain(&self, domain: Bytes, path_prefix: &str) {
+        unregister(&self.domains, domain, path_prefix);
     }
 
-    pub(crate) fn unregister_domain(&self, domain: Bytes) {
-        self.domains.remove(&domain);
+    pub(crate) fn domain_registered(&self, domain: &Bytes, path_prefix: &str) -> bool {
+        registered(&self.domains, domain, path_prefix)
     }
 
-    pub(crate) fn domain_registered(&self, domain: &Bytes) -> bool {
-        self.domains.contains_key(domain)
+    pub(crate) fn get_domain(&self, domain: Bytes, path: &str) -> Option<HttpTarget> {
+        self.domains.get(&domain)?.matched(path)
     }
 
-    pub(crate) fn get_domain(&self, domain: Bytes) -> Option<mpsc::Sender<event::UserIncoming>> {
-        self.domains.get(&domain).map(|x| x.value().clone())
+    pub(crate) fn subdomain_registered(&self, subdomain: &Bytes, path_prefix: &str) -> bool {
+        registered(&self.subdomains, subdomain, path_prefix)
     }
 
-    pub(crate) fn subdomain_registered(&self, subdomain: &Bytes) -> bool {
+    /// the subdomain is registered by any tunnel, whatever its path prefix.
+    pub(crate) fn subdomain_in_use(&self, subdomain: &Bytes) -> bool {
         self.subdomains.contains_key(subdomain)
     }
 
-    pub(crate) fn unregister_subdomain(&self, subdomain: Bytes) {
-        self.subdomains.remove(&subdomain);
+    pub(crate) fn unregister_subdomain(&self, subdomain: Bytes, path_prefix: &str) {
+        unregister(&self.subdomains, subdomain, path_prefix);
     }
 
-    pub(crate) fn get_subdomain(
-        &self,
-        subdomain: Bytes,
-    ) -> Option<mpsc::Sender<event::UserIncoming>> {
-        self.subdomains.get(&subdomain).map(|x| x.value().clone())
+    pub(crate) fn get_subdomain(&self, subdomain: Bytes, path: &str) -> Option<HttpTarget> {
+        self.subdomains.get(&subdomain)?.matched(path)
     }
 
     pub(crate) fn register_subdomain(
         &self,
         subdomain: Bytes,
-        conn_event_chan: mpsc::Sender<event::UserIncoming>,
+        path_prefix: &str,
+        target: impl Into<HttpTarget>,
     ) {
-        self.subdomains.insert(subdomain, conn_event_chan);
+        register(&self.subdomains, subdomain, path_prefix, target.into());
+    }
+}
+
+fn register(hosts: &DashMap<Bytes, Routes>, host: Bytes, path_prefix: &str, target: HttpTarget) {
+    hosts.entry(host).or_default().insert(path_prefix, target);
+}
+
+fn unregister(hosts: &DashMap<Bytes, Routes>, host: Bytes, path_prefix: &str) {
+    if let Some(mut routes) = hosts.get_mut(&host) {
+        routes.remove(path_prefix);
+    }
+    // the host is gone with its last tunnel
+    hosts.remove_if(&host, |_, routes| routes.0.is_empty());
+}
+
+fn registered(hosts: &DashMap<Bytes, Routes>, host: &Bytes, path_prefix: &str) -> bool {
+    hosts
+        .get(host)
+        .is_some_and(|routes| routes.contains(path_prefix))
+}
+
+/// the trailing `/` is ignored, e.g. `/api/` is `/api` and `/` matches all the paths like the empty one.
+fn normalize_path_prefix(path_prefix: &str) -> &str {
+    path_prefix.trim_end_matches('/')
+}
+
+/// the prefix matches by the whole segments, e.g. `/api` matches `/api` and `/api/users` but not `/apis`.
+fn path_matches(path: &str, prefix: &str) -> bool {
+    match path.strip_prefix(prefix) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+/// strip the port of the host header, e.g. `example.com:8080` -> `example.com`.
+fn strip_port(host: &str) -> &str {
+    if host.starts_with('[') {
+        // ipv6 address, e.g. [::1]:8080
+        return host.find(']').map(|end| &host[..=end]).unwrap_or(host);
+    }
+    match host.rsplit_once(':') {
+        Some((name, port)) if port.bytes().all(|b| b.is_ascii_digit()) => name,
+        _ => host,
     }
 }
 
@@ -484,6 +1218,7 @@ mod test {
     use tokio::sync::oneshot;
 
     use super::*;
+    use crate::{bridge::InFlight, event};
 
     #[tokio::test]
     async fn test_the_cloned_http_shares_same_registrations() {
@@ -491,21 +1226,22 @@ mod test {
         let http2 = http1.clone();
 
         let (tx1, mut rx1) = mpsc::channel(1);
-        http1.register_domain(Bytes::from_static(b"example1.com"), tx1.clone());
-        http2.register_domain(Bytes::from_static(b"example2.com"), tx1.clone());
+        http1.register_domain(Bytes::from_static(b"example1.com"), "", tx1.clone());
+        http2.register_domain(Bytes::from_static(b"example2.com"), "", tx1.clone());
 
         let (tx2, mut rx2) = mpsc::channel(1);
-        http1.register_subdomain(Bytes::from_static(b"foo"), tx2.clone());
-        http2.register_subdomain(Bytes::from_static(b"bar"), tx2.clone());
+        http1.register_subdomain(Bytes::from_static(b"foo"), "", tx2.clone());
+        http2.register_subdomain(Bytes::from_static(b"bar"), "", tx2.clone());
 
-        assert!(http1.domain_registered(&Bytes::from_static(b"example2.com")));
-        assert!(http2.domain_registered(&Bytes::from_static(b"example1.com")));
-        assert!(http1.subdomain_registered(&Bytes::from_static(b"bar")));
-        assert!(http2.subdomain_registered(&Bytes::from_static(b"foo")));
+        assert!(http1.domain_registered(&Bytes::from_static(b"example2.com"), ""));
+        assert!(http2.domain_registered(&Bytes::from_static(b"example1.com"), ""));
+        assert!(http1.subdomain_registered(&Bytes::from_static(b"bar"), ""));
+        assert!(http2.subdomain_registered(&Bytes::from_static(b"foo"), ""));
 
         assert!(http1
-            .get_domain(Bytes::from_static(b"example2.com"))
+            .get_domain(Bytes::from_static(b"example2.com"), "/")
             .unwrap()
+            .sender
             .send(event::UserIncoming::Remove(Bytes::from_static(
                 b"example2.com",
             )))
@@ -515,25 +1251,204 @@ mod test {
         assert!(received.is_some());
 
         assert!(http2
-            .get_subdomain(Bytes::from_static(b"foo"))
+            .get_subdomain(Bytes::from_static(b"foo"), "/")
             .unwrap()
+            .sender
             .send(event::UserIncoming::Remove(Bytes::from_static(b"foo.com")))
             .await
             .is_ok());
         let received = rx2.recv().await;
         assert!(received.is_some());
 
-        http2.unregister_subdomain(Bytes::from_static(b"foo"));
-        assert!(!http1.subdomain_registered(&Bytes::from_static(b"foo")));
-        assert!(http1.subdomain_registered(&Bytes::from_static(b"bar")));
+        http2.unregister_subdomain(Bytes::from_static(b"foo"), "");
+        assert!(!http1.subdomain_registered(&Bytes::from_static(b"foo"), ""));
+        assert!(http1.subdomain_registered(&Bytes::from_static(b"bar"), ""));
 
-        http1.unregister_domain(Bytes::from_static(b"example2.com"));
-        assert!(!http2.domain_registered(&Bytes::from_static(b"example2.com")));
-        assert!(http2.domain_registered(&Bytes::from_static(b"example1.com")));
+        http1.unregister_domain(Bytes::from_static(b"example2.com"), "");
+        assert!(!http2.domain_registered(&Bytes::from_static(b"example2.com"), ""));
+        assert!(http2.domain_registered(&Bytes::from_static(b"example1.com"), ""));
+    }
+
+    #[tokio::test]
+    async fn test_admit() {
+        let (tx, _rx) = mpsc::channel(1);
+        let lookup: Arc<Box<dyn LookupRequest>> = Arc::new(Box::new(FixedRegistry::new(tx)));
+        let http = Http::new(Arc::clone(&lookup), Arc::new(Semaphore::new(1)));
+        assert!(http.admit().await.is_ok());
+
+        // no connection setup is available
+        let http = Http::new(lookup, Arc::new(Semaphore::new(0)));
+        let response = http.admit().await.unwrap_err();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()["retry-after"], "1");
+    }
+
+    #[tokio::test]
+    async fn test_limit_body() {
+        let frames = || {
+            futures::stream::iter(
+                [&b"hello"[..], b" ", b"world"]
+                    .map(|data| Ok::<_, Infallible>(Frame::data(Bytes::from_static(data)))),
+            )
+        };
+        // exactly the max
+        let cancel = CancellationToken::new();
+        let body: Vec<_> = limit_body(frames(), Some(11), cancel.clone())
+            .collect()
+            .await;
+        assert!(body.iter().all(Result::is_ok));
+        assert!(!cancel.is_cancelled());
+
+        // a byte over the max
+        let body: Vec<_> = limit_body(frames(), Some(10), cancel.clone())
+            .collect()
+            .await;
+        assert_eq!(body.iter().filter(|frame| frame.is_ok()).count(), 2);
+        assert!(body[2].is_err());
+        assert!(cancel.is_cancelled());
+
+        let body: Vec<_> = limit_body(frames(), None, CancellationToken::new())
+            .collect()
+            .await;
+        assert_eq!(body.len(), 3);
+
+        let mut headers = http::HeaderMap::new();
+        assert_eq!(content_length(&headers), None);
+        headers.insert(http::header::CONTENT_LENGTH, HeaderValue::from_static("11"));
+        assert_eq!(content_length(&headers), Some(11));
+        assert!(!exceeds(11, Some(11)));
+        assert!(exceeds(12, Some(11)));
+        assert!(!exceeds(u64::MAX, None));
+    }
+
+    #[test]
+    fn test_request_deadline() {
+        assert_eq!(parse_deadline("500m"), Some(Duration::from_millis(500)));
+        assert_eq!(parse_deadline(" 2S "), Some(Duration::from_secs(2)));
+        assert_eq!(parse_deadline("1H"), Some(Duration::from_secs(3600)));
+        assert_eq!(parse_deadline("10n"), Some(Duration::from_nanos(10)));
+        for invalid in ["", "m", "10", "10s", "-1S", "123456789S", "1.5S", "1Ｓ"] {
+            assert_eq!(parse_deadline(invalid), None, "{}", invalid);
+        }
+
+        let request = |deadline: &str| {
+            Request::builder()
+                .header(DEADLINE_HEADER, deadline)
+                .body(())
+                .unwrap()
+        };
+        let max = Some(Duration::from_secs(10));
+        let mut options = HttpOptions::default();
+        assert_eq!(
+            request_timeout(&request("1S"), &options, max),
+            Some(Duration::from_secs(1))
+        );
+        // capped at the max, ignored without it
+        assert_eq!(request_timeout(&request("1M"), &options, max), max);
+        assert_eq!(request_timeout(&request("1S"), &options, None), None);
+        assert_eq!(request_timeout(&request("soon"), &options, max), None);
+        // the timeout of the tunnel applies if it's shorter
+        options.request_timeout = Some(Duration::from_secs(2));
+        assert_eq!(
+            request_timeout(&request("5S"), &options, max),
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(
+            request_timeout(&request("500m"), &options, max),
+            Some(Duration::from_millis(500))
+        );
+    }
+
+    #[test]
+    fn test_strip_base_path() {
+        let strip = |uri: &str| {
+            strip_base_path(&uri.parse().unwrap(), "/tunnels").map(|uri| uri.to_string())
+        };
+        assert_eq!(strip("/tunnels/api?id=1").as_deref(), Some("/api?id=1"));
+        assert_eq!(strip("/tunnels").as_deref(), Some("/"));
+        assert_eq!(strip("/tunnels/").as_deref(), Some("/"));
+        assert_eq!(strip("/tunnelsfoo"), None);
+        assert_eq!(strip("/api"), None);
+    }
+
+    #[test]
+    fn test_is_upgrade() {
+        let headers = |pairs: &[(&'static str, &'static str)]| {
+            let mut headers = http::HeaderMap::new();
+            for (name, value) in pairs {
+                headers.append(*name, HeaderValue::from_static(value));
+            }
+            headers
+        };
+        assert!(is_upgrade(&headers(&[
+            ("connection", "keep-alive, Upgrade"),
+            ("upgrade", "websocket"),
+        ])));
+        assert!(!is_upgrade(&headers(&[("connection", "upgrade")])));
+        assert!(!is_upgrade(&headers(&[
+            ("connection", "close"),
+            ("upgrade", "websocket"),
+        ])));
+    }
+
+    #[test]
+    fn test_route() {
+        let registry = DynamicRegistry::new();
+        let (foo, _rx1) = mpsc::channel(1);
+        let (custom, _rx2) = mpsc::channel(1);
+        registry.register_subdomain(Bytes::from_static(b"foo"), "", foo.clone());
+        registry.register_domain(Bytes::from_static(b"foo.example.org"), "", custom.clone());
+
+        let routed = |host: &str, path: &str, sender: &mpsc::Sender<event::UserIncoming>| {
+            registry
+                .route(host, path)
+                .is_some_and(|routed| routed.sender.same_channel(sender))
+        };
+        assert!(routed("foo.example.com", "/", &foo));
+        assert!(routed("foo.example.com:6611", "/", &foo));
+        assert!(routed("foo.example.org", "/", &custom));
+        assert!(routed("foo.example.org:80", "/", &custom));
+        assert!(registry.route("bar.example.com", "/").is_none());
+        assert!(registry.route("[::1]:6611", "/").is_none());
+
+        // the tunnels of the same subdomain are told apart by the path prefixes
+        let (api, _rx3) = mpsc::channel(1);
+        let (users, _rx4) = mpsc::channel(1);
+        let (web, _rx5) = mpsc::channel(1);
+        registry.register_subdomain(Bytes::from_static(b"pr-123"), "/api", api.clone());
+        registry.register_subdomain(Bytes::from_static(b"pr-123"), "/api/users/", users.clone());
+        registry.register_subdomain(Bytes::from_static(b"pr-123"), "/web", web.clone());
+        assert!(routed("pr-123.example.com", "/api", &api));
+        assert!(routed("pr-123.example.com", "/api/orders", &api));
+        // the longest prefix wins
+        assert!(routed("pr-123.example.com", "/api/users", &users));
+        assert!(routed("pr-123.example.com", "/api/users/1", &users));
+        assert!(routed("pr-123.example.com", "/web/index.html", &web));
+        // the prefixes match by the whole segments
+        assert!(registry.route("pr-123.example.com", "/apis").is_none());
+        assert!(registry.route("pr-123.example.com", "/").is_none());
+        assert!(registry.subdomain_registered(&Bytes::from_static(b"pr-123"), "/api/users"));
+        assert!(!registry.subdomain_registered(&Bytes::from_static(b"pr-123"), ""));
+
+        // the tunnel without a prefix serves the rest
+        registry.register_subdomain(Bytes::from_static(b"pr-123"), "/", foo.clone());
+        assert!(routed("pr-123.example.com", "/apis", &foo));
+        assert!(routed("pr-123.example.com", "/api", &api));
+
+        registry.unregister_subdomain(Bytes::from_static(b"pr-123"), "/api");
+        assert!(routed("pr-123.example.com", "/api/orders", &foo));
+        for prefix in ["", "/api/users", "/web"] {
+            registry.unregister_subdomain(Bytes::from_static(b"pr-123"), prefix);
+        }
+        assert!(registry
+            .subdomains
+            .get(&Bytes::from_static(b"pr-123"))
+            .is_none());
     }
 
     #[tokio::test]
     async fn test_receive_response() {
+        #[allow(clippy::type_complexity)]
         struct Case<'a> {
             name: &'a str,
             send_fn: Box<
@@ -608,6 +1523,21 @@ mod test {
                 expected_header: "HTTP/1.1 200 OK\r\ncontent-length: 5\r\n\r\n",
                 expected_body: b"hello",
             },
+            Case {
+                name: "the connection headers of the local server are dropped",
+                send_fn: Box::new(|tx| {
+                    Box::pin(async move {
+                        let _ = tx
+                            .send(BridgeData::Data(
+                                b"HTTP/1.1 200 OK\r\nConnection: close\r\ncontent-length: 5\r\n\r\nhello"
+                                    .to_vec(),
+                            ))
+                            .await;
+                    })
+                }),
+                expected_header: "HTTP/1.1 200 OK\r\ncontent-length: 5\r\n\r\n",
+                expected_body: b"hello",
+            },
         ];
 
         for case in cases {
@@ -622,7 +1552,7 @@ mod test {
             let remove_bridge_receiver = remove_bridge_sender.clone();
 
             tokio::spawn(receive_response(
-                data_rx,
+                DataReceiver::new(data_rx, InFlight::new(1024, MemoryBudget::new(None))),
                 Some(header_tx),
                 body_tx,
                 client_cancel_receiver,
@@ -0,0 +1,633 @@
+//! The response cache of the http tunnels, it serves the repeated GETs
+//! without a round trip to the client of the tunnel, e.g. the static assets of a demo site.
+//!
+//! It's a shared cache of the explicit freshness only, the response is stored if:
+//! - the request is a GET without `Authorization`, `Range` and `Cache-Control: no-store`.
+//! - the response is a 200 with `max-age` or `s-maxage`, without `no-store`, `private`,
+//!   `no-cache`, `Set-Cookie` and `Vary: *`, and the body has a `Content-Length`.
+//!
+//! Each url keeps one variant of the `Vary` headers, the entries are tied to the tunnel
+//! which served them, so a re-registered subdomain never serves the responses of the previous one.
+//!
+//! The tunnels with `coalesce` forward only one of the concurrent identical GETs,
+//! the rest wait for its response to be stored and are served from the cache,
+//! they're forwarded on their own if it isn't cacheable, e.g. an error, or it takes too long.
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex, Weak},
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use http::{header, response::Builder, HeaderMap, HeaderName, HeaderValue, Method, StatusCode};
+use hyper::{Request, Response};
+use tokio::sync::watch;
+use tracing::debug;
+
+use crate::event::HttpOptions;
+
+/// the max time a coalesced request waits for the identical one in flight,
+/// it's forwarded to the tunnel after it.
+const COALESCE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct CacheKey {
+    host: String,
+    uri: String,
+}
+
+/// ResponseCache is shared by all the http tunnels of the server,
+/// the least recently used responses are evicted when it's full.
+#[derive(Clone)]
+pub(crate) struct ResponseCache {
+    inner: Arc<Mutex<Entries>>,
+    /// the coalesced requests being forwarded, the waiting ones are woken up
+    /// when the sender of the [`Flight`] is dropped.
+    flights: Arc<Mutex<HashMap<CacheKey, watch::Receiver<()>>>>,
+    max_bytes: usize,
+}
+
+#[derive(Default)]
+struct Entries {
+    entries: HashMap<CacheKey, Entry>,
+    /// the keys by the tick of the last use.
+    recent: BTreeMap<u64, CacheKey>,
+    tick: u64,
+    bytes: usize,
+}
+
+struct Entry {
+    /// the tunnel serving the response.
+    owner: Weak<HttpOptions>,
+    /// the request headers named by `Vary` and their values.
+    vary: Vec<(HeaderName, Option<HeaderValue>)>,
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    stored_at: Instant,
+    /// the `Age` of the response when it's stored.
+    initial_age: Duration,
+    fresh_for: Duration,
+    tick: u64,
+}
+
+impl Entry {
+    fn size(&self) -> usize {
+        let headers: usize = self
+            .headers
+            .iter()
+            .map(|(name, value)| name.as_str().len() + value.len())
+            .sum();
+        headers + self.body.len()
+    }
+
+    fn age(&self) -> Duration {
+        self.initial_age + self.stored_at.elapsed()
+    }
+}
+
+/// CacheLookup is what the request tells the cache before it's forwarded to the tunnel.
+pub(crate) struct CacheLookup {
+    key: CacheKey,
+    /// the request headers, only the names of `Vary` are used.
+    headers: HeaderMap,
+    /// false if the request requires the response from the backend, e.g. `no-cache`.
+    serve: bool,
+    /// false if the response must not be stored, e.g. `no-store`.
+    store: bool,
+    /// set if the request leads the coalesced ones.
+    flight: Option<Flight>,
+}
+
+/// Flight is held by the coalesced request forwarded to the tunnel,
+/// until its response is stored or turns out not cacheable.
+pub(crate) struct Flight {
+    flights: Arc<Mutex<HashMap<CacheKey, watch::Receiver<()>>>>,
+    key: CacheKey,
+    _done: watch::Sender<()>,
+}
+
+impl Drop for Flight {
+    fn drop(&mut self) {
+        // the waiting requests are woken up once the sender is dropped right after
+        self.flights.lock().unwrap().remove(&self.key);
+    }
+}
+
+impl ResponseCache {
+    pub(crate) fn new(max_bytes: usize) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Entries::default())),
+            flights: Arc::new(Mutex::new(HashMap::new())),
+            max_bytes,
+        }
+    }
+
+    /// None if the request is never served from or stored to the cache.
+    pub(crate) fn lookup<B>(&self, req: &Request<B>) -> Option<CacheLookup> {
+        let headers = req.headers();
+        if req.method() != Method::GET
+            || headers.contains_key(header::AUTHORIZATION)
+            || headers.contains_key(header::RANGE)
+            // e.g. a websocket, the connection is switched to the other protocol
+            || headers.contains_key(header::UPGRADE)
+        {
+            return None;
+        }
+        let directives = CacheControl::parse(headers);
+        let no_cache = directives.no_cache
+            || directives.max_age == Some(0)
+            || headers
+                .get(header::PRAGMA)
+                .is_some_and(|pragma| pragma.as_bytes().eq_ignore_ascii_case(b"no-cache"));
+        let host = headers
+            .get(header::HOST)
+            .and_then(|host| host.to_str().ok())
+            .or_else(|| req.uri().authority().map(|authority| authority.as_str()))
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        let uri = req
+            .uri()
+            .path_and_query()
+            .map_or("/", |path| path.as_str())
+            .to_string();
+        Some(CacheLookup {
+            key: CacheKey { host, uri },
+            headers: headers.clone(),
+            serve: !no_cache,
+            store: !directives.no_store,
+            flight: None,
+        })
+    }
+
+    /// Coalesce the request with the identical one in flight, it returns false if the request
+    /// should be forwarded to the tunnel, or true after the identical one is given up,
+    /// then the response is looked up again.
+    ///
+    /// The request can't be coalesced if it can't be served from or stored to the cache.
+    pub(crate) async fn coalesce(&self, lookup: &mut CacheLookup) -> bool {
+        if !lookup.serve || !lookup.store {
+            return false;
+        }
+        let mut done = {
+            let mut flights = self.flights.lock().unwrap();
+            match flights.get(&lookup.key) {
+                Some(done) => done.clone(),
+                None => {
+                    let (tx, rx) = watch::channel(());
+                    flights.insert(lookup.key.clone(), rx);
+                    lookup.flight = Some(Flight {
+                        flights: Arc::clone(&self.flights),
+                        key: lookup.key.clone(),
+                        _done: tx,
+                    });
+                    return false;
+                }
+            }
+        };
+        debug!(key = ?lookup.key, "http request coalesced");
+        // nothing is sent, it returns once the flight is dropped
+        let _ = tokio::time::timeout(COALESCE_TIMEOUT, done.changed()).await;
+        true
+    }
+
+    /// the cached response of the tunnel, it's a builder with the `Age` header and the body.
+    pub(crate) fn get(
+        &self,
+        lookup: &CacheLookup,
+        owner: &Arc<HttpOptions>,
+    ) -> Option<(Builder, Bytes)> {
+        if !lookup.serve {
+            return None;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        let entry = inner.entries.get(&lookup.key)?;
+        if !Weak::ptr_eq(&entry.owner, &Arc::downgrade(owner))
+            || entry.stored_at.elapsed() >= entry.fresh_for
+        {
+            // served by a previous tunnel or stale
+            inner.remove(&lookup.key);
+            return None;
+        }
+        if !entry
+            .vary
+            .iter()
+            .all(|(name, value)| lookup.headers.get(name) == value.as_ref())
+        {
+            return None;
+        }
+
+        let tick = inner.touch();
+        let entry = inner.entries.get_mut(&lookup.key).unwrap();
+        let old_tick = std::mem::replace(&mut entry.tick, tick);
+        let mut builder = Builder::new().status(entry.status);
+        for (name, value) in entry.headers.iter() {
+            builder = builder.header(name, value);
+        }
+        let builder = builder.header(header::AGE, entry.age().as_secs());
+        let body = entry.body.clone();
+        let key = inner.recent.remove(&old_tick).unwrap();
+        inner.recent.insert(tick, key);
+        debug!(key = ?lookup.key, "http cache hit");
+        Some((builder, body))
+    }
+
+    /// Start storing the response, None if it isn't cacheable,
+    /// the body is passed to [`PendingResponse::push`] as it's sent to the user.
+    pub(crate) fn store(
+        &self,
+        lookup: CacheLookup,
+        owner: &Arc<HttpOptions>,
+        response: &Response<()>,
+    ) -> Option<PendingResponse> {
+        if !lookup.store {
+            return None;
+        }
+        // the followers of the flight are released if the response isn't stored
+        let flight = lookup.flight;
+        let status = response.status();
+        let headers = response.headers();
+        if status != StatusCode::OK
+            || headers.contains_key(header::SET_COOKIE)
+            || headers.contains_key(header::TRANSFER_ENCODING)
+        {
+            return None;
+        }
+        let directives = CacheControl::parse(headers);
+        if directives.no_store || directives.no_cache || directives.private {
+            return None;
+        }
+        let max_age = directives.s_maxage.or(directives.max_age)?;
+        let age = headers
+            .get(header::AGE)
+            .and_then(|age| age.to_str().ok()?.parse().ok())
+            .unwrap_or(0u64);
+        let fresh_for = Duration::from_secs(max_age.checked_sub(age).filter(|n| *n > 0)?);
+        let content_length: usize = headers
+            .get(header::CONTENT_LENGTH)
+            .and_then(|length| length.to_str().ok()?.parse().ok())?;
+        if content_length > self.max_bytes {
+            return None;
+        }
+
+        let mut vary = Vec::new();
+        for value in headers.get_all(header::VARY) {
+            for name in value.to_str().ok()?.split(',') {
+                let name = name.trim();
+                if name == "*" {
+                    return None;
+                }
+                let name = HeaderName::from_bytes(name.as_bytes()).ok()?;
+                let value = lookup.headers.get(&name).cloned();
+                vary.push((name, value));
+            }
+        }
+
+        Some(PendingResponse {
+            cache: self.clone(),
+            key: lookup.key,
+            entry: Some(Entry {
+                owner: Arc::downgrade(owner),
+                vary,
+                status,
+                headers: {
+                    let mut headers = headers.clone();
+                    headers.remove(header::AGE);
+                    headers
+                },
+                body: Bytes::new(),
+                stored_at: Instant::now(),
+                initial_age: Duration::from_secs(age),
+                fresh_for,
+                tick: 0,
+            }),
+            content_length,
+            body: Vec::with_capacity(content_length),
+            flight,
+        })
+    }
+
+    fn insert(&self, key: CacheKey, mut entry: Entry) {
+        let size = entry.size();
+        if size > self.max_bytes {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        inner.remove(&key);
+        while inner.bytes + size > self.max_bytes {
+            let Some((_, oldest)) = inner.recent.pop_first() else {
+                break;
+            };
+            inner.remove(&oldest);
+        }
+        entry.tick = inner.touch();
+        inner.recent.insert(entry.tick, key.clone());
+        inner.bytes += size;
+        debug!(?key, size, "http response cached");
+        inner.entries.insert(key, entry);
+    }
+
+    #[cfg(test)]
+    fn bytes(&self) -> usize {
+        self.inner.lock().unwrap().bytes
+    }
+}
+
+impl Entries {
+    fn touch(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    fn remove(&mut self, key: &CacheKey) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.recent.remove(&entry.tick);
+            self.bytes -= entry.size();
+        }
+    }
+}
+
+/// PendingResponse collects the body of a cacheable response,
+/// it's stored once the whole body of the `Content-Length` is received.
+pub(crate) struct PendingResponse {
+    cache: ResponseCache,
+    key: CacheKey,
+    entry: Option<Entry>,
+    content_length: usize,
+    body: Vec<u8>,
+    /// released once the response is stored or given up.
+    flight: Option<Flight>,
+}
+
+impl PendingResponse {
+    pub(crate) fn push(&mut self, data: &[u8]) {
+        if self.entry.is_none() {
+            return;
+        }
+        if self.body.len() + data.len() > self.content_length {
+            // the body doesn't match the content length
+            self.entry = None;
+            self.flight = None;
+            return;
+        }
+        self.body.extend_from_slice(data);
+        if self.body.len() == self.content_length {
+            let mut entry = self.entry.take().unwrap();
+            entry.body = Bytes::from(std::mem::take(&mut self.body));
+            self.cache.insert(self.key.clone(), entry);
+            self.flight = None;
+        }
+    }
+}
+
+/// the directives of `Cache-Control` read by the cache.
+#[derive(Debug, Default)]
+struct CacheControl {
+    no_store: bool,
+    no_cache: bool,
+    private: bool,
+    max_age: Option<u64>,
+    s_maxage: Option<u64>,
+}
+
+impl CacheControl {
+    fn parse(headers: &HeaderMap) -> Self {
+        let mut directives = Self::default();
+        for value in headers.get_all(header::CACHE_CONTROL) {
+            let Ok(value) = value.to_str() else {
+                continue;
+            };
+            for directive in value.split(',') {
+                let (name, arg) = directive
+                    .split_once('=')
+                    .map_or((directive, None), |(name, arg)| (name, Some(arg)));
+                let seconds = arg.and_then(|arg| arg.trim().trim_matches('"').parse().ok());
+                match name.trim().to_ascii_lowercase().as_str() {
+                    "no-store" => directives.no_store = true,
+                    "no-cache" => directives.no_cache = true,
+                    "private" => directives.private = true,
+                    "max-age" => directives.max_age = seconds,
+                    "s-maxage" => directives.s_maxage = seconds,
+                    _ => {}
+                }
+            }
+        }
+        directives
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn request(headers: &[(&str, &str)]) -> Request<()> {
+        let mut builder = Request::get("/static/app.js").header("host", "foo.example.com");
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        builder.body(()).unwrap()
+    }
+
+    fn response(body: &[u8], headers: &[(&str, &str)]) -> Response<()> {
+        let mut builder = Response::builder()
+            .status(200)
+            .header("content-length", body.len());
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        builder.body(()).unwrap()
+    }
+
+    fn cache_response(
+        cache: &ResponseCache,
+        owner: &Arc<HttpOptions>,
+        req: &Request<()>,
+        body: &[u8],
+        headers: &[(&str, &str)],
+    ) -> bool {
+        let lookup = cache.lookup(req).unwrap();
+        match cache.store(lookup, owner, &response(body, headers)) {
+            Some(mut pending) => {
+                let (head, tail) = body.split_at(body.len() / 2);
+                pending.push(head);
+                pending.push(tail);
+                true
+            }
+            None => false,
+        }
+    }
+
+    #[test]
+    fn test_response_cache() {
+        let cache = ResponseCache::new(1024);
+        let owner = Arc::new(HttpOptions::default());
+        let req = request(&[("accept-encoding", "gzip")]);
+        assert!(cache.get(&cache.lookup(&req).unwrap(), &owner).is_none());
+
+        assert!(cache_response(
+            &cache,
+            &owner,
+            &req,
+            b"console.log(1)",
+            &[
+                ("cache-control", "public, max-age=60"),
+                ("vary", "Accept-Encoding")
+            ],
+        ));
+        let (builder, body) = cache.get(&cache.lookup(&req).unwrap(), &owner).unwrap();
+        assert_eq!(body, Bytes::from_static(b"console.log(1)"));
+        assert_eq!(builder.headers_ref().unwrap()["age"], "0");
+
+        // another variant
+        let br = request(&[("accept-encoding", "br")]);
+        assert!(cache.get(&cache.lookup(&br).unwrap(), &owner).is_none());
+        // the request requires the response from the backend
+        let no_cache = request(&[("accept-encoding", "gzip"), ("cache-control", "no-cache")]);
+        assert!(cache
+            .get(&cache.lookup(&no_cache).unwrap(), &owner)
+            .is_none());
+        // another tunnel registered the subdomain
+        let other = Arc::new(HttpOptions::default());
+        assert!(cache.get(&cache.lookup(&req).unwrap(), &other).is_none());
+        assert_eq!(cache.bytes(), 0);
+    }
+
+    #[test]
+    fn test_response_not_cacheable() {
+        let cache = ResponseCache::new(1024);
+        let owner = Arc::new(HttpOptions::default());
+        let req = request(&[]);
+        for headers in [
+            &[][..],
+            &[("cache-control", "no-store, max-age=60")],
+            &[("cache-control", "private, max-age=60")],
+            &[("cache-control", "max-age=60"), ("vary", "*")],
+            &[("cache-control", "max-age=60"), ("set-cookie", "a=b")],
+            &[("cache-control", "max-age=60"), ("age", "60")],
+        ] {
+            assert!(!cache_response(&cache, &owner, &req, b"body", headers));
+        }
+        // the request forbids storing the response
+        let no_store = request(&[("cache-control", "no-store")]);
+        assert!(!cache_response(
+            &cache,
+            &owner,
+            &no_store,
+            b"body",
+            &[("cache-control", "max-age=60")],
+        ));
+        let auth = request(&[("authorization", "Bearer token")]);
+        assert!(cache.lookup(&auth).is_none());
+        let post = Request::post("/").body(()).unwrap();
+        assert!(cache.lookup(&post).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_coalesce() {
+        let cache = ResponseCache::new(1024);
+        let owner = Arc::new(HttpOptions::default());
+        let req = request(&[]);
+        let mut leader = cache.lookup(&req).unwrap();
+        assert!(!cache.coalesce(&mut leader).await);
+
+        let follower = tokio::spawn({
+            let cache = cache.clone();
+            let owner = Arc::clone(&owner);
+            async move {
+                let mut lookup = cache.lookup(&request(&[])).unwrap();
+                assert!(cache.coalesce(&mut lookup).await);
+                cache.get(&lookup, &owner).map(|(_, body)| body)
+            }
+        });
+        tokio::task::yield_now().await;
+        assert!(!follower.is_finished());
+        let mut pending = cache
+            .store(
+                leader,
+                &owner,
+                &response(b"body", &[("cache-control", "max-age=60")]),
+            )
+            .unwrap();
+        pending.push(b"bo");
+        assert!(!follower.is_finished());
+        pending.push(b"dy");
+        assert_eq!(follower.await.unwrap(), Some(Bytes::from_static(b"body")));
+
+        // the followers are released if the response isn't cacheable
+        let page = || {
+            Request::get("/index.html")
+                .header("host", "foo.example.com")
+                .body(())
+                .unwrap()
+        };
+        let mut leader = cache.lookup(&page()).unwrap();
+        assert!(!cache.coalesce(&mut leader).await);
+        let mut follower = cache.lookup(&page()).unwrap();
+        let store = async {
+            tokio::task::yield_now().await;
+            cache.store(leader, &owner, &response(b"body", &[]))
+        };
+        let (coalesced, pending) = tokio::join!(cache.coalesce(&mut follower), store);
+        assert!(coalesced && pending.is_none());
+        assert!(cache.get(&follower, &owner).is_none());
+
+        // the request requiring the response from the backend is never coalesced
+        let mut no_cache = cache
+            .lookup(&request(&[("cache-control", "no-cache")]))
+            .unwrap();
+        assert!(!cache.coalesce(&mut no_cache).await);
+        assert!(no_cache.flight.is_none());
+    }
+
+    #[test]
+    fn test_response_cache_eviction() {
+        let body = [b'x'; 400];
+        let cache = ResponseCache::new(1024);
+        let owner = Arc::new(HttpOptions::default());
+        let requests: Vec<_> = ["/a", "/b", "/c"]
+            .iter()
+            .map(|path| {
+                Request::get(*path)
+                    .header("host", "foo.example.com")
+                    .body(())
+                    .unwrap()
+            })
+            .collect();
+        let headers = [("cache-control", "max-age=60")];
+        assert!(cache_response(
+            &cache,
+            &owner,
+            &requests[0],
+            &body,
+            &headers
+        ));
+        assert!(cache_response(
+            &cache,
+            &owner,
+            &requests[1],
+            &body,
+            &headers
+        ));
+        // /a is used recently, /b is evicted
+        assert!(cache
+            .get(&cache.lookup(&requests[0]).unwrap(), &owner)
+            .is_some());
+        assert!(cache_response(
+            &cache,
+            &owner,
+            &requests[2],
+            &body,
+            &headers
+        ));
+        assert!(cache.bytes() <= 1024);
+        assert!(cache
+            .get(&cache.lookup(&requests[0]).unwrap(), &owner)
+            .is_some());
+        assert!(cache
+            .get(&cache.lookup(&requests[1]).unwrap(), &owner)
+            .is_none());
+        assert!(cache
+            .get(&cache.lookup(&requests[2]).unwrap(), &owner)
+            .is_some());
+    }
+}
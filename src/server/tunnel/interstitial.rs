@@ -0,0 +1,233 @@
+//! The interstitial page of the http tunnels, it warns the first-time visitors
+//! of a tunnel before they reach the backend, e.g. to deter the phishing pages.
+//!
+//! Only the browser navigations (GETs accepting `text/html`) without the acknowledgment cookie
+//! are intercepted, the "continue" link of the page sets the cookie and redirects back,
+//! the rest of the requests always pass through, e.g. the webhooks and the apis.
+//!
+//! Behind the external base path of the vhttp server, the requests are already stripped of it,
+//! the link, the redirect back and the cookie are put under it.
+use bytes::Bytes;
+use http::{header, HeaderValue, Method, StatusCode};
+use http_body_util::{combinators::BoxBody, Full};
+use hyper::{Request, Response};
+use std::{convert::Infallible, sync::Arc};
+
+/// the cookie set by the "continue" link.
+const COOKIE: &str = "castle_interstitial";
+/// the path reserved for the "continue" link, it's never forwarded to the backend.
+const CONTINUE_PATH: &str = "/.castle/continue";
+const COOKIE_MAX_AGE: u64 = 7 * 24 * 3600;
+
+/// the page served by default, see [`Interstitial::new`] for the placeholders.
+const DEFAULT_PAGE: &str = "<!DOCTYPE html>\n<html>\n<head><title>castle</title></head>\n<body>\n\
+    <h1>You are about to visit {{host}}</h1>\n\
+    <p>This site is served through a castle tunnel, the content is not provided by the castle server.</p>\n\
+    <p>Only continue if you trust the person who sent you the link.</p>\n\
+    <p><a href=\"{{continue_url}}\">Continue</a></p>\n</body>\n</html>\n";
+
+#[derive(Clone)]
+pub(crate) struct Interstitial {
+    page: Arc<str>,
+}
+
+impl Default for Interstitial {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+impl Interstitial {
+    /// `{{host}}` and `{{continue_url}}` of the page are replaced by the host of the tunnel
+    /// and the link setting the cookie, None means the default page.
+    pub(crate) fn new(page: Option<String>) -> Self {
+        Self {
+            page: Arc::from(page.as_deref().unwrap_or(DEFAULT_PAGE)),
+        }
+    }
+
+    /// the response of the interstitial, None if the request passes through to the backend,
+    /// the base path is the external base path of the vhttp server, empty if none.
+    pub(crate) fn intercept<B>(
+        &self,
+        req: &Request<B>,
+        base_path: &str,
+    ) -> Option<Response<BoxBody<Bytes, Infallible>>> {
+        if req.uri().path() == CONTINUE_PATH {
+            return Some(acknowledge(req, base_path));
+        }
+        let headers = req.headers();
+        let acknowledged = headers
+            .get_all(header::COOKIE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(';'))
+            .any(|cookie| {
+                cookie
+                    .trim()
+                    .split_once('=')
+                    .is_some_and(|(name, _)| name == COOKIE)
+            });
+        let navigation = req.method() == Method::GET
+            && headers
+                .get(header::ACCEPT)
+                .and_then(|accept| accept.to_str().ok())
+                .is_some_and(|accept| accept.contains("text/html"));
+        if acknowledged || !navigation {
+            return None;
+        }
+
+        let to = req.uri().path_and_query().map_or("/", |path| path.as_str());
+        let continue_url = format!(
+            "{}{}?{}",
+            base_path,
+            CONTINUE_PATH,
+            form_urlencoded::Serializer::new(String::new())
+                .append_pair("to", to)
+                .finish()
+        );
+        let host = headers
+            .get(header::HOST)
+            .and_then(|host| host.to_str().ok())
+            .unwrap_or_default();
+        let html = self
+            .page
+            .replace("{{host}}", &escape(host))
+            .replace("{{continue_url}}", &escape(&continue_url));
+        Some(
+            Response::builder()
+                .status(StatusCode::OK)
+                .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+                .header(header::CACHE_CONTROL, "no-store")
+                .body(BoxBody::new(Full::new(Bytes::from(html))))
+                .unwrap(),
+        )
+    }
+}
+
+/// set the cookie and redirect back to the page the visitor asked for.
+fn acknowledge<B>(req: &Request<B>, base_path: &str) -> Response<BoxBody<Bytes, Infallible>> {
+    let to = form_urlencoded::parse(req.uri().query().unwrap_or_default().as_bytes())
+        .find(|(key, _)| key == "to")
+        .map(|(_, to)| to.into_owned())
+        // only the paths of the tunnel, not an open redirect
+        .filter(|to| to.starts_with('/') && !to.starts_with("//") && !to.starts_with("/\\"))
+        // e.g. a newline, the visitor is sent to the root of the tunnel instead
+        .filter(|to| HeaderValue::from_str(to).is_ok())
+        .unwrap_or_else(|| "/".to_string());
+    // the base path has no control characters, it's checked by `castled --external-base-path`
+    let to = HeaderValue::try_from(format!("{}{}", base_path, to))
+        .unwrap_or(HeaderValue::from_static("/"));
+    let cookie_path = if base_path.is_empty() { "/" } else { base_path };
+    Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header(header::LOCATION, to)
+        .header(
+            header::SET_COOKIE,
+            format!(
+                "{}=1; Path={}; Max-Age={}; HttpOnly; SameSite=Lax",
+                COOKIE, cookie_path, COOKIE_MAX_AGE
+            ),
+        )
+        .header(header::CACHE_CONTROL, "no-store")
+        .body(BoxBody::new(Full::new(Bytes::new())))
+        .unwrap()
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod test {
+    use http_body_util::BodyExt as _;
+
+    use super::*;
+
+    fn request(uri: &str, headers: &[(&str, &str)]) -> Request<()> {
+        let mut builder = Request::get(uri).header("host", "foo.example.com");
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        builder.body(()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_interstitial() {
+        let interstitial =
+            Interstitial::new(Some("<a href=\"{{continue_url}}\">{{host}}</a>".into()));
+        let html = [("accept", "text/html,application/xhtml+xml")];
+
+        let page = interstitial
+            .intercept(&request("/login?next=a&b", &html), "")
+            .unwrap();
+        assert_eq!(page.status(), StatusCode::OK);
+        let body = page.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(
+            body,
+            "<a href=\"/.castle/continue?to=%2Flogin%3Fnext%3Da%26b\">foo.example.com</a>"
+        );
+
+        let redirect = interstitial
+            .intercept(
+                &request("/.castle/continue?to=%2Flogin%3Fnext%3Da%26b", &html),
+                "",
+            )
+            .unwrap();
+        assert_eq!(redirect.status(), StatusCode::SEE_OTHER);
+        assert_eq!(redirect.headers()["location"], "/login?next=a&b");
+        assert!(redirect.headers()["set-cookie"]
+            .to_str()
+            .unwrap()
+            .starts_with("castle_interstitial=1;"));
+
+        // acknowledged
+        let cookie = [html[0], ("cookie", "theme=dark; castle_interstitial=1")];
+        assert!(interstitial
+            .intercept(&request("/login", &cookie), "")
+            .is_none());
+        // not a browser navigation
+        assert!(interstitial
+            .intercept(&request("/api", &[("accept", "application/json")]), "")
+            .is_none());
+        // never redirect out of the tunnel
+        let redirect = interstitial
+            .intercept(&request("/.castle/continue?to=%2F%2Fevil.com", &[]), "")
+            .unwrap();
+        assert_eq!(redirect.headers()["location"], "/");
+
+        // under the external base path, the request is stripped of it
+        let page = interstitial
+            .intercept(&request("/login", &html), "/tunnels")
+            .unwrap();
+        let body = page.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(
+            body,
+            "<a href=\"/tunnels/.castle/continue?to=%2Flogin\">foo.example.com</a>"
+        );
+        let redirect = interstitial
+            .intercept(&request("/.castle/continue?to=%2Flogin", &html), "/tunnels")
+            .unwrap();
+        assert_eq!(redirect.headers()["location"], "/tunnels/login");
+        // the invalid header value falls back to the root under the base path
+        let redirect = interstitial
+            .intercept(&request("/.castle/continue?to=%2F%0A", &html), "/tunnels")
+            .unwrap();
+        assert_eq!(redirect.headers()["location"], "/tunnels/");
+        assert!(redirect.headers()["set-cookie"]
+            .to_str()
+            .unwrap()
+            .contains("; Path=/tunnels;"));
+    }
+}
@@ -1,24 +1,28 @@
 use crate::{
-    bridge::{self, DataSenderBridge, IdDataSenderBridge},
+    bridge::{self, DataReceiver, DataSenderBridge, IdDataSenderBridge, InFlight, MemoryBudget},
     event,
 };
-use anyhow::Context as _;
 use bytes::Bytes;
+use std::net::IpAddr;
 use tokio::sync::mpsc;
 use tokio_util::sync::CancellationToken;
-use tonic::Status;
-use tracing::error;
+use tonic::{Code, Status};
+use tracing::{debug, error, info, warn};
 use uuid::Uuid;
 
 use super::port::{Available, PortManager};
 
+pub(crate) mod headers;
 pub(crate) mod http;
+pub(crate) mod http_cache;
+pub(crate) mod interstitial;
+pub(crate) mod rewrite;
 pub(crate) mod tcp;
 pub(crate) mod udp;
 
 pub(crate) struct BridgeResult {
-    pub data_sender: mpsc::Sender<Vec<u8>>,
-    pub data_receiver: mpsc::Receiver<bridge::BridgeData>,
+    pub data_sender: mpsc::Sender<Bytes>,
+    pub data_receiver: bridge::DataReceiver,
     pub client_cancel_receiver: CancellationToken,
     /// the caller should cancel this token when it finishes the transfer.
     pub remove_bridge_sender: CancellationToken,
@@ -28,42 +32,72 @@ pub(crate) struct BridgeResult {
 ///
 /// In this function, it has been sent the bridge to the control server,
 /// and wait to receive the first message which is [`crate::bridge::BridgeData::Sender`] from the control server.
+///
+/// `max_in_flight_bytes` caps the bytes sent by the client but not received by the caller yet,
+/// they are taken from the `memory_budget` of the server as well.
+///
+/// `host` is told to the client with the connection, see [`crate::pb::WorkPayload::host`].
+///
+/// It fails if the control stream of the tunnel is gone, e.g. the client disconnected
+/// while the connection was accepted, `user_incoming_chan.is_closed()` is true then.
+/// It fails as well if the bridge is dropped before the sender arrives,
+/// e.g. the client cancelled the connection during the setup.
 pub(crate) async fn init_data_sender_bridge(
     user_incoming_chan: mpsc::Sender<event::UserIncoming>,
+    host: String,
+    max_in_flight_bytes: usize,
+    memory_budget: MemoryBudget,
 ) -> anyhow::Result<BridgeResult> {
     let bridge_id = Bytes::from(Uuid::new_v4().to_string());
-    let (bridge_chan, mut bridge_chan_receiver) = mpsc::channel(1024);
+    let (bridge_chan, bridge_chan_receiver) = mpsc::channel(1024);
+    let in_flight = InFlight::new(max_in_flight_bytes, memory_budget);
+    let mut bridge_chan_receiver = DataReceiver::new(bridge_chan_receiver, in_flight.clone());
 
     let client_cancel = CancellationToken::new();
     let client_cancel_receiver = client_cancel.clone();
 
     let event = IdDataSenderBridge {
         id: bridge_id.clone(),
-        inner: DataSenderBridge::new(bridge_chan.clone(), client_cancel),
+        host,
+        // the bridge holds the only sender, so dropping it closes the channel.
+        inner: DataSenderBridge::new(bridge_chan, client_cancel, in_flight),
     };
-    user_incoming_chan
+    if user_incoming_chan
         .send(event::UserIncoming::Add(event))
         .await
-        .context("send user incoming event, this operation should not fail")
-        .unwrap();
+        .is_err()
+    {
+        return Err(anyhow::anyhow!("the tunnel is closed"));
+    }
 
     let remove_bridge_sender = CancellationToken::new();
     let remove_bridge_receiver = remove_bridge_sender.clone();
     let bridge_id_clone = bridge_id.clone();
     tokio::spawn(async move {
         remove_bridge_receiver.cancelled().await;
-        user_incoming_chan
+        // the control stream may be closed already, e.g. the sessions drained on shutdown.
+        if user_incoming_chan
             .send(event::UserIncoming::Remove(bridge_id_clone))
             .await
-            .context("notify server to remove connection channel")
-            .unwrap();
+            .is_err()
+        {
+            debug!("the tunnel is closed, skip removing the connection");
+        }
     });
 
     let data_sender = tokio::select! {
         data_sender = bridge_chan_receiver.recv() => {
             match data_sender {
                 Some(bridge::BridgeData::Sender(sender)) => sender,
-                _ => panic!("we expect to receive DataSender from data_channel_rx at the first time."),
+                Some(bridge::BridgeData::Data(_)) => {
+                    // the control server only sends data after the handshake.
+                    remove_bridge_sender.cancel();
+                    return Err(anyhow::anyhow!("the bridge must start with a sender"));
+                }
+                None => {
+                    remove_bridge_sender.cancel();
+                    return Err(anyhow::anyhow!("bridge closed before receiving a sender"));
+                }
             }
         }
         _ = client_cancel_receiver.cancelled() => {
@@ -83,50 +117,214 @@ pub(crate) async fn init_data_sender_bridge(
 pub(crate) trait SocketCreator {
     type Output;
 
-    async fn create_socket(port: u16) -> anyhow::Result<Self::Output, Status>;
+    async fn create_socket(ip: IpAddr, port: u16) -> anyhow::Result<Self::Output, Status>;
+
+    /// the port the socket is bound to, e.g. the port picked by the os for port 0.
+    fn local_port(socket: &Self::Output) -> Result<u16, Status>;
 }
 
+/// the random ports tried before giving up, e.g. most of the pool is bound by other processes.
+const RANDOM_PORT_ATTEMPTS: usize = 150;
+
+/// create_socket binds the port of the tunnel, a random one if the port is 0.
+///
+/// The port is kept out of the pool only if it's taken by another process or not permitted,
+/// the other failures(e.g. out of file descriptors) are not of the port, they are returned
+/// to the client right away instead of trying the other random ports.
 pub(crate) async fn create_socket<T: SocketCreator>(
+    ip: IpAddr,
     port: u16,
     port_manager: &mut PortManager,
 ) -> anyhow::Result<(Available, T::Output), Status> {
     if port > 0 {
-        if !port_manager.allow(port) {
-            return Err(Status::invalid_argument(
-                "port is not in the allowed range or is excluded",
-            ));
-        }
+        port_manager.check(port)?;
 
         match port_manager.take(port) {
             None => Err(Status::already_exists("port is already in use")),
-            Some(mut available_port) => match T::create_socket(port).await {
-                Err(e) => {
-                    available_port.unavailable();
-                    Err(e)
+            Some(mut available_port) => match T::create_socket(ip, port).await {
+                Err(status) => {
+                    if port_unavailable(&status) {
+                        available_port.unavailable();
+                    }
+                    Err(status)
                 }
                 Ok(socket) => Ok((available_port, socket)),
             },
         }
+    } else if port_manager.ephemeral() {
+        let socket = T::create_socket(ip, 0).await?;
+        let port = T::local_port(&socket)?;
+        info!(port, "bound the ephemeral port picked by the os");
+        Ok((Available::ephemeral(port), socket))
     } else {
-        for _ in 0..150 {
+        for _ in 0..RANDOM_PORT_ATTEMPTS {
             let mut available_port: Available = match port_manager.get() {
                 None => {
-                    return Err(Status::resource_exhausted("no available port"));
+                    return Err(port_manager.exhausted());
                 }
                 Some(port) => port,
             };
             let port = *available_port;
-            match T::create_socket(port).await {
-                Err(err) => {
-                    error!(?err, port, "failed to create socket");
+            match T::create_socket(ip, port).await {
+                Err(status) if port_unavailable(&status) => {
+                    warn!(
+                        port,
+                        message = status.message(),
+                        "random port is unavailable, try another one"
+                    );
                     available_port.unavailable();
                     continue;
                 }
+                Err(status) => {
+                    // the port goes back to the pool
+                    error!(?status, port, "failed to create socket");
+                    return Err(status);
+                }
                 Ok(socket) => {
                     return Ok((available_port, socket));
                 }
             }
         }
-        Err(Status::resource_exhausted("no available port"))
+        Err(port_manager.exhausted())
+    }
+}
+
+/// the bind failed because of the port itself, see [`crate::socket::create_tcp_listener_on`].
+fn port_unavailable(status: &Status) -> bool {
+    matches!(status.code(), Code::AlreadyExists | Code::PermissionDenied)
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    /// the port is bound by another process.
+    struct InUse;
+
+    /// the binds fail regardless of the port, e.g. out of file descriptors.
+    struct Broken;
+
+    /// the binds always succeed.
+    struct Free;
+
+    static BROKEN_BINDS: AtomicUsize = AtomicUsize::new(0);
+
+    impl SocketCreator for Free {
+        type Output = ();
+
+        async fn create_socket(_: IpAddr, _: u16) -> anyhow::Result<(), Status> {
+            Ok(())
+        }
+
+        fn local_port(_: &()) -> Result<u16, Status> {
+            Ok(0)
+        }
+    }
+
+    impl SocketCreator for InUse {
+        type Output = ();
+
+        async fn create_socket(_: IpAddr, _: u16) -> anyhow::Result<(), Status> {
+            Err(Status::already_exists("port is already in use"))
+        }
+
+        fn local_port(_: &()) -> Result<u16, Status> {
+            Ok(0)
+        }
+    }
+
+    impl SocketCreator for Broken {
+        type Output = ();
+
+        async fn create_socket(_: IpAddr, _: u16) -> anyhow::Result<(), Status> {
+            BROKEN_BINDS.fetch_add(1, Ordering::Relaxed);
+            Err(Status::internal("failed to bind port"))
+        }
+
+        fn local_port(_: &()) -> Result<u16, Status> {
+            Ok(0)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_create_socket_failures() {
+        let ip = IpAddr::from([127, 0, 0, 1]);
+
+        // the fixed port
+        let mut port_manager = PortManager::new(4000..=4004, vec![]);
+        let status = create_socket::<InUse>(ip, 4000, &mut port_manager)
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::AlreadyExists);
+        assert_eq!(port_manager.remaining(), 4);
+        let status = create_socket::<Broken>(ip, 4001, &mut port_manager)
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::Internal);
+        assert_eq!(port_manager.remaining(), 4);
+
+        // the random ports are tried until the pool runs out
+        let status = create_socket::<InUse>(ip, 0, &mut port_manager)
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::ResourceExhausted);
+        assert_eq!(port_manager.remaining(), 0);
+
+        // the failure not of the port is returned at once, the pool is kept
+        let mut port_manager = PortManager::new(4000..=4004, vec![]);
+        let status = create_socket::<Broken>(ip, 0, &mut port_manager)
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::Internal);
+        assert_eq!(BROKEN_BINDS.load(Ordering::Relaxed), 2);
+        assert_eq!(port_manager.remaining(), 5);
+
+        // the attempts are bounded even if the pool is large
+        let mut port_manager = PortManager::new(4000..=4299, vec![]);
+        let status = create_socket::<InUse>(ip, 0, &mut port_manager)
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::ResourceExhausted);
+        assert_eq!(
+            port_manager.remaining(),
+            port_manager.total() - RANDOM_PORT_ATTEMPTS
+        );
+    }
+
+    #[tokio::test]
+    async fn test_create_socket_in_tiny_range() {
+        let ip = IpAddr::from([127, 0, 0, 1]);
+        let mut port_manager = PortManager::new(4000..=4001, vec![]);
+        let (first, _) = create_socket::<Free>(ip, 0, &mut port_manager)
+            .await
+            .unwrap();
+        let (second, _) = create_socket::<Free>(ip, 0, &mut port_manager)
+            .await
+            .unwrap();
+        let status = create_socket::<Free>(ip, 0, &mut port_manager)
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::ResourceExhausted);
+        assert!(
+            status.message().contains("4000-4001"),
+            "{}",
+            status.message()
+        );
+        // the random ports stay in the range
+        let status = create_socket::<Free>(ip, 4002, &mut port_manager)
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::PermissionDenied);
+
+        // the port of the closed tunnel is back to the pool
+        let freed = *first;
+        drop(first);
+        let (available, _) = create_socket::<Free>(ip, 0, &mut port_manager)
+            .await
+            .unwrap();
+        assert_eq!(*available, freed);
+        drop(second);
     }
 }
@@ -0,0 +1,161 @@
+//! The rewrite of the absolute urls of the local server in the responses of the http tunnels,
+//! e.g. the `http://localhost:3000/login` emitted by a dev server breaks behind the tunnel.
+//!
+//! The origins of the local server are replaced by the public origin of the tunnel
+//! in the `Location` header and the `text/html` bodies, the bodies are rewritten chunk by chunk
+//! as they stream, only the bytes which may be the start of an origin are held back.
+//! The compressed bodies are forwarded as they are.
+use http::{header, HeaderMap, HeaderValue};
+
+/// OriginRewriter rewrites a response, it's created for each response.
+pub(crate) struct OriginRewriter {
+    /// the origins of the local server, e.g. `http://localhost:3000`.
+    from: Vec<Vec<u8>>,
+    /// the public origin of the tunnel, e.g. `https://foo.example.com`.
+    to: Vec<u8>,
+    /// the bytes held back until the next chunk tells if they are an origin.
+    pending: Vec<u8>,
+}
+
+impl OriginRewriter {
+    pub(crate) fn new(from: &[String], to: &str) -> Self {
+        Self {
+            from: from
+                .iter()
+                .filter(|origin| !origin.is_empty())
+                .map(|origin| origin.trim_end_matches('/').as_bytes().to_vec())
+                .collect(),
+            to: to.as_bytes().to_vec(),
+            pending: Vec::new(),
+        }
+    }
+
+    /// rewrite the `Location` header, true if the body is rewritten as well,
+    /// its `Content-Length` is removed since the length changes.
+    pub(crate) fn rewrite_headers(&self, headers: &mut HeaderMap) -> bool {
+        if let Some(location) = headers.get(header::LOCATION) {
+            let location = self.rewrite_all(location.as_bytes());
+            if let Ok(location) = HeaderValue::from_bytes(&location) {
+                headers.insert(header::LOCATION, location);
+            }
+        }
+        let html = headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .is_some_and(|content_type| {
+                content_type
+                    .trim_start()
+                    .to_ascii_lowercase()
+                    .starts_with("text/html")
+            });
+        let encoded = headers
+            .get(header::CONTENT_ENCODING)
+            .is_some_and(|encoding| !encoding.as_bytes().eq_ignore_ascii_case(b"identity"));
+        if !html || encoded {
+            return false;
+        }
+        headers.remove(header::CONTENT_LENGTH);
+        true
+    }
+
+    /// the rewritten bytes of the chunk which are ready, the rest wait for the next chunk.
+    pub(crate) fn rewrite(&mut self, chunk: &[u8]) -> Vec<u8> {
+        self.pending.extend_from_slice(chunk);
+        let (out, consumed) = self.replace(&self.pending, false);
+        self.pending.drain(..consumed);
+        out
+    }
+
+    /// the rewritten bytes held back, at the end of the body.
+    pub(crate) fn finish(&mut self) -> Vec<u8> {
+        let pending = std::mem::take(&mut self.pending);
+        self.rewrite_all(&pending)
+    }
+
+    fn rewrite_all(&self, data: &[u8]) -> Vec<u8> {
+        self.replace(data, true).0
+    }
+
+    /// the rewritten bytes and the number of the bytes consumed,
+    /// the bytes not at the end may be the start of an origin unless it's the end.
+    fn replace(&self, data: &[u8], end: bool) -> (Vec<u8>, usize) {
+        // an origin is matched with the byte after it.
+        let lookahead = self.from.iter().map(Vec::len).max().unwrap_or_default() + 1;
+        let mut out = Vec::with_capacity(data.len());
+        let mut i = 0;
+        while i < data.len() {
+            if !end && data.len() - i < lookahead {
+                break;
+            }
+            let matched = self.from.iter().find(|origin| {
+                data[i..].starts_with(origin)
+                    && data.get(i + origin.len()).is_none_or(|&b| ends_origin(b))
+            });
+            match matched {
+                Some(origin) => {
+                    out.extend_from_slice(&self.to);
+                    i += origin.len();
+                }
+                None => {
+                    out.push(data[i]);
+                    i += 1;
+                }
+            }
+        }
+        (out, i)
+    }
+}
+
+/// false if the byte continues the host or the port, e.g. `http://localhost:30001`
+/// isn't `http://localhost:3000`.
+fn ends_origin(b: u8) -> bool {
+    !(b.is_ascii_alphanumeric() || matches!(b, b':' | b'.' | b'-' | b'_'))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_origin_rewriter() {
+        let from = [
+            "http://localhost:3000".to_string(),
+            "http://127.0.0.1:3000/".to_string(),
+        ];
+        let mut rewriter = OriginRewriter::new(&from, "https://foo.example.com");
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::LOCATION,
+            "http://localhost:3000/login".parse().unwrap(),
+        );
+        headers.insert(
+            header::CONTENT_TYPE,
+            "text/html; charset=utf-8".parse().unwrap(),
+        );
+        headers.insert(header::CONTENT_LENGTH, "42".parse().unwrap());
+        assert!(rewriter.rewrite_headers(&mut headers));
+        assert_eq!(headers[header::LOCATION], "https://foo.example.com/login");
+        assert!(!headers.contains_key(header::CONTENT_LENGTH));
+
+        // the origins split across the chunks
+        let html = "<a href=\"http://localhost:3000/a\">http://127.0.0.1:3000</a> http://localhost:30001/ http://localhost:3000";
+        let mut out = Vec::new();
+        for chunk in html.as_bytes().chunks(7) {
+            out.extend(rewriter.rewrite(chunk));
+        }
+        out.extend(rewriter.finish());
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "<a href=\"https://foo.example.com/a\">https://foo.example.com</a> http://localhost:30001/ https://foo.example.com"
+        );
+
+        // only the uncompressed html bodies
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_TYPE, "application/json".parse().unwrap());
+        assert!(!rewriter.rewrite_headers(&mut headers));
+        headers.insert(header::CONTENT_TYPE, "text/html".parse().unwrap());
+        headers.insert(header::CONTENT_ENCODING, "gzip".parse().unwrap());
+        assert!(!rewriter.rewrite_headers(&mut headers));
+    }
+}
@@ -1,48 +1,120 @@
 use crate::{
-    event,
-    io::{StreamingReader, StreamingWriter, VecWrapper},
+    bridge::MemoryBudget,
+    constant,
+    event::{self, FirstByteTimeout},
+    io::{shutdown_within, StreamingWriter, VecWrapper},
     server::tunnel::BridgeResult,
-    socket::create_tcp_listener,
+    socket::{create_tcp_listener_on, set_tcp_keepalive, AcceptBackoff, TcpKeepalive},
+    timing::{self, FirstRead, SetupTimer},
 };
 use anyhow::Context as _;
+use bytes::Bytes;
+use std::{
+    future::pending,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 use tokio::{
-    io::{self, AsyncWriteExt as _},
+    io,
     net::TcpListener,
     select,
-    sync::mpsc,
+    sync::{mpsc, Semaphore},
+    time::sleep,
 };
 use tokio_util::sync::CancellationToken;
 use tonic::Status;
-use tracing::{debug, error};
+use tracing::{debug, error, warn};
 
 use super::SocketCreator;
 
 pub struct Tcp {
     listener: TcpListener,
     user_incoming_sender: mpsc::Sender<event::UserIncoming>,
+    tcp_keepalive: Option<TcpKeepalive>,
+    connection_setups: Arc<Semaphore>,
+    max_in_flight_bytes: usize,
+    data_chunk_size: Option<usize>,
+    memory_budget: MemoryBudget,
+    first_byte_timeout: Option<FirstByteTimeout>,
+    close_timeout: Option<Duration>,
 }
 
 impl Tcp {
     pub fn new(
         listener: TcpListener,
         user_incoming_sender: mpsc::Sender<event::UserIncoming>,
+        connection_setups: Arc<Semaphore>,
     ) -> Self {
         Self {
             listener,
             user_incoming_sender,
+            tcp_keepalive: None,
+            connection_setups,
+            max_in_flight_bytes: constant::DEFAULT_MAX_IN_FLIGHT_BYTES,
+            data_chunk_size: None,
+            memory_budget: MemoryBudget::new(None),
+            first_byte_timeout: None,
+            close_timeout: None,
         }
     }
 
+    pub fn with_tcp_keepalive(mut self, keepalive: Option<TcpKeepalive>) -> Self {
+        self.tcp_keepalive = keepalive;
+        self
+    }
+
+    pub fn with_max_in_flight_bytes(mut self, max_in_flight_bytes: usize) -> Self {
+        self.max_in_flight_bytes = max_in_flight_bytes;
+        self
+    }
+
+    /// the max bytes of a message sent to the client, see [`StreamingWriter::with_chunk_size`].
+    pub fn with_data_chunk_size(mut self, data_chunk_size: Option<usize>) -> Self {
+        self.data_chunk_size = data_chunk_size;
+        self
+    }
+
+    /// the new connections wait while the budget is exhausted.
+    pub(crate) fn with_memory_budget(mut self, memory_budget: MemoryBudget) -> Self {
+        self.memory_budget = memory_budget;
+        self
+    }
+
+    /// close the connections nobody speaks on within the timeout after they are set up.
+    pub(crate) fn with_first_byte_timeout(
+        mut self,
+        first_byte_timeout: Option<FirstByteTimeout>,
+    ) -> Self {
+        self.first_byte_timeout = first_byte_timeout;
+        self
+    }
+
+    /// drop the closing connections whose sides aren't shut down within the timeout,
+    /// e.g. the client doesn't take the end of the stream, None waits for as long as it takes.
+    pub(crate) fn with_close_timeout(mut self, close_timeout: Option<Duration>) -> Self {
+        self.close_timeout = close_timeout;
+        self
+    }
+
     pub async fn serve(self, shutdown: CancellationToken) {
+        let mut backoff = AcceptBackoff::new();
         loop {
             select! {
                 _ = shutdown.cancelled() => {
                     return;
                 }
                 result = async {
-                    match self.listener.accept().await  {
+                    // stop accepting when too many connections are waiting for the client
+                    // or the memory budget is exhausted, the new connections wait in the backlog.
+                    self.memory_budget.wait_available().await;
+                    let permit = Arc::clone(&self.connection_setups).acquire_owned().await.unwrap();
+                    match backoff.accept(&self.listener).await  {
                         Ok(result) => {
-                            Some(result)
+                            Some((result, permit))
                         }
                         Err(err) => {
                             error!(err = ?err, "failed to accept connection");
@@ -50,10 +122,22 @@ impl Tcp {
                         }
                     }
                 } => {
-                    if result.is_none() {
+                    let Some(((stream, _addr), permit)) = result else {
+                        return;
+                    };
+                    // the client is gone, the accepted connection is closed by dropping it.
+                    if self.user_incoming_sender.is_closed() {
+                        debug!("the tunnel is closed, stop accepting connections");
                         return;
                     }
                     let user_incoming_sender = self.user_incoming_sender.clone();
+                    let tcp_keepalive = self.tcp_keepalive;
+                    let max_in_flight_bytes = self.max_in_flight_bytes;
+                    let data_chunk_size = self.data_chunk_size;
+                    let memory_budget = self.memory_budget.clone();
+                    let first_byte_timeout = self.first_byte_timeout;
+                    let close_timeout = self.close_timeout;
+                    let mut timer = SetupTimer::start();
 
                     tokio::spawn(async move {
                         let BridgeResult{
@@ -61,8 +145,12 @@ impl Tcp {
                             data_receiver,
                             client_cancel_receiver,
                             remove_bridge_sender
-                        } = match super::init_data_sender_bridge(user_incoming_sender.clone()).await {
+                        } = match super::init_data_sender_bridge(user_incoming_sender.clone(), String::new(), max_in_flight_bytes, memory_budget).await {
                             Ok(result) => result,
+                            Err(_) if user_incoming_sender.is_closed() => {
+                                debug!("the tunnel is closed, dropped the connection");
+                                return;
+                            }
                             Err(err) => {
                                 error!(err = ?err, "failed to init data sender bridge");
                                 // we should continue to accept next connection,
@@ -71,28 +159,63 @@ impl Tcp {
                             }
                         };
 
-                        let (stream, _addr) = result.unwrap();
-                        let (mut remote_reader, mut remote_writer) = stream.into_split();
-                        let wrapper = VecWrapper::<Vec<u8>>::new();
+                        drop(permit);
+                        let bridge = timer.lap();
+
+                        if let Some(keepalive) = &tcp_keepalive {
+                            if let Err(err) = set_tcp_keepalive(&stream, keepalive) {
+                                warn!(err = ?err, "failed to set tcp keepalive");
+                            }
+                        }
+                        // the bytes are copied as they are without sniffing or buffering the lines,
+                        // so the protocols upgrading in place work, e.g. STARTTLS of smtp and imap.
+                        let (remote_reader, mut remote_writer) = stream.into_split();
+                        let user_spoke = Arc::new(AtomicBool::new(false));
+                        let local_spoke = Arc::new(AtomicBool::new(false));
+                        let mut remote_reader = FirstRead::new(remote_reader, {
+                            let user_spoke = Arc::clone(&user_spoke);
+                            move || user_spoke.store(true, Ordering::Relaxed)
+                        });
+                        let wrapper = VecWrapper::<Bytes>::new();
                         // we expect to receive data from data_channel_rx after receive the first data_sender
-                        let mut tunnel_reader = StreamingReader::new(data_receiver);
-                        let mut tunnel_writer = StreamingWriter::new(data_sender, wrapper);
+                        let mut tunnel_reader = FirstRead::new(data_receiver, {
+                            let local_spoke = Arc::clone(&local_spoke);
+                            move || {
+                                local_spoke.store(true, Ordering::Relaxed);
+                                let first_byte = timer.lap();
+                                debug!(target: timing::TARGET, ?bridge, ?first_byte, total = ?timer.total(), "tcp connection set up");
+                            }
+                        });
+                        let mut tunnel_writer = StreamingWriter::new(data_sender, wrapper).with_chunk_size(data_chunk_size);
                         let remote_to_me_to_tunnel = async {
-                            io::copy(&mut remote_reader, &mut tunnel_writer).await.unwrap();
-                            tunnel_writer.shutdown().await.context("failed to shutdown tunnel writer").unwrap();
+                            // e.g. the user resets the connection, only this connection is dropped.
+                            io::copy(&mut remote_reader, &mut tunnel_writer).await.context("failed to copy from remote to tunnel")?;
+                            shutdown_within(&mut tunnel_writer, close_timeout).await.context("failed to shutdown tunnel writer")?;
                             debug!("finished the transfer between remote and tunnel");
+                            anyhow::Ok(())
                         };
                         let tunnel_to_me_to_remote = async {
-                            io::copy(&mut tunnel_reader, &mut remote_writer).await.unwrap();
-                            remote_writer.shutdown().await.context("failed to shutdown remote writer").unwrap();
+                            io::copy(&mut tunnel_reader, &mut remote_writer).await.context("failed to copy from tunnel to remote")?;
+                            shutdown_within(&mut remote_writer, close_timeout).await.context("failed to shutdown remote writer")?;
                             debug!("finished the transfer between tunnel and remote");
+                            anyhow::Ok(())
                         };
 
                         tokio::select! {
-                            _ = async { tokio::join!(remote_to_me_to_tunnel, tunnel_to_me_to_remote) } => {}
+                            result = async { tokio::try_join!(remote_to_me_to_tunnel, tunnel_to_me_to_remote) } => {
+                                // the connection is dropped without waiting for the other side
+                                if let Err(err) = result {
+                                    warn!(err = ?err, "dropped the connection not closing cleanly");
+                                }
+                            }
                             _ = client_cancel_receiver.cancelled() => {
-                                let _ = remote_writer.shutdown().await;
-                                let _ = tunnel_writer.shutdown().await;
+                                let _ = shutdown_within(&mut remote_writer, close_timeout).await;
+                                let _ = shutdown_within(&mut tunnel_writer, close_timeout).await;
+                            }
+                            _ = silent(first_byte_timeout, &user_spoke, &local_spoke) => {
+                                debug!("nobody spoke within the first byte timeout, close the connection");
+                                let _ = shutdown_within(&mut remote_writer, close_timeout).await;
+                                let _ = shutdown_within(&mut tunnel_writer, close_timeout).await;
                             }
                         }
                         remove_bridge_sender.cancel();
@@ -103,10 +226,233 @@ impl Tcp {
     }
 }
 
+/// resolves if the connection is silent once the first byte timeout passes,
+/// it never resolves without the timeout or after the bytes counted by it.
+async fn silent(
+    first_byte_timeout: Option<FirstByteTimeout>,
+    user_spoke: &AtomicBool,
+    local_spoke: &AtomicBool,
+) {
+    let Some(first_byte_timeout) = first_byte_timeout else {
+        return pending().await;
+    };
+    sleep(first_byte_timeout.timeout).await;
+    if user_spoke.load(Ordering::Relaxed)
+        || (!first_byte_timeout.from_user && local_spoke.load(Ordering::Relaxed))
+    {
+        pending().await
+    }
+}
+
 impl SocketCreator for Tcp {
     type Output = TcpListener;
 
-    async fn create_socket(port: u16) -> anyhow::Result<TcpListener, Status> {
-        create_tcp_listener(port).await
+    async fn create_socket(ip: IpAddr, port: u16) -> anyhow::Result<TcpListener, Status> {
+        create_tcp_listener_on(SocketAddr::new(ip, port)).await
+    }
+
+    fn local_port(listener: &TcpListener) -> Result<u16, Status> {
+        listener
+            .local_addr()
+            .map(|addr| addr.port())
+            .map_err(|err| Status::internal(format!("failed to get the local address: {}", err)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use tokio::{
+        io::{AsyncReadExt as _, AsyncWriteExt as _},
+        time::timeout,
+    };
+
+    use super::*;
+    use crate::socket::create_tcp_listener;
+
+    #[tokio::test]
+    async fn test_serve_after_tunnel_closed() {
+        let listener = create_tcp_listener(0).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (user_incoming_sender, user_incoming_receiver) = mpsc::channel(1);
+        // the control stream of the tunnel is gone
+        drop(user_incoming_receiver);
+        let serve = tokio::spawn(
+            Tcp::new(listener, user_incoming_sender, Arc::new(Semaphore::new(1)))
+                .serve(CancellationToken::new()),
+        );
+
+        let mut user = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let mut buf = [0; 1];
+        assert_eq!(user.read(&mut buf).await.unwrap(), 0);
+        // the listener returns instead of panicking
+        serve.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_bridge_dropped_during_setup() {
+        let listener = create_tcp_listener(0).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (user_incoming_sender, mut user_incoming_receiver) = mpsc::channel(1);
+        let shutdown = CancellationToken::new();
+        tokio::spawn(
+            Tcp::new(listener, user_incoming_sender, Arc::new(Semaphore::new(1)))
+                .serve(shutdown.clone()),
+        );
+
+        for _ in 0..2 {
+            let mut user = tokio::net::TcpStream::connect(addr).await.unwrap();
+            // the client goes away before sending the sender of the data stream
+            let event::UserIncoming::Add(bridge) = user_incoming_receiver.recv().await.unwrap()
+            else {
+                panic!("expected a new connection");
+            };
+            let id = bridge.id.clone();
+            drop(bridge);
+
+            let mut buf = [0; 1];
+            assert_eq!(user.read(&mut buf).await.unwrap(), 0);
+            assert!(matches!(
+                user_incoming_receiver.recv().await.unwrap(),
+                event::UserIncoming::Remove(removed) if removed == id
+            ));
+        }
+        shutdown.cancel();
+    }
+
+    /// connect to the tunnel and set up the bridge, the receiver gets the bytes of the user.
+    async fn connect(
+        addr: SocketAddr,
+        user_incoming_receiver: &mut mpsc::Receiver<event::UserIncoming>,
+    ) -> (
+        tokio::net::TcpStream,
+        crate::bridge::IdDataSenderBridge,
+        mpsc::Receiver<Bytes>,
+    ) {
+        let user = tokio::net::TcpStream::connect(addr).await.unwrap();
+        // skip the removals of the previous connections
+        let bridge = loop {
+            if let event::UserIncoming::Add(bridge) = user_incoming_receiver.recv().await.unwrap() {
+                break bridge;
+            }
+        };
+        let (sender, receiver) = mpsc::channel(8);
+        bridge.inner.send_sender(sender).await.unwrap();
+        (user, bridge, receiver)
+    }
+
+    #[tokio::test]
+    async fn test_first_byte_timeout() {
+        for from_user in [false, true] {
+            let listener = create_tcp_listener(0).await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let (user_incoming_sender, mut user_incoming_receiver) = mpsc::channel(8);
+            let shutdown = CancellationToken::new();
+            tokio::spawn(
+                Tcp::new(listener, user_incoming_sender, Arc::new(Semaphore::new(8)))
+                    .with_first_byte_timeout(Some(FirstByteTimeout {
+                        timeout: Duration::from_millis(50),
+                        from_user,
+                    }))
+                    .serve(shutdown.clone()),
+            );
+            let mut buf = [0; 16];
+
+            // nobody speaks
+            let (mut user, _bridge, _receiver) = connect(addr, &mut user_incoming_receiver).await;
+            assert_eq!(user.read(&mut buf).await.unwrap(), 0);
+
+            // the user speaks
+            let (mut user, _bridge, mut receiver) =
+                connect(addr, &mut user_incoming_receiver).await;
+            user.write_all(b"hello").await.unwrap();
+            assert_eq!(receiver.recv().await.unwrap(), &b"hello"[..]);
+            assert!(timeout(Duration::from_millis(150), user.read(&mut buf))
+                .await
+                .is_err());
+
+            // the local endpoint speaks first, e.g. the banner of ssh
+            let (mut user, bridge, _receiver) = connect(addr, &mut user_incoming_receiver).await;
+            bridge.inner.send_data(b"SSH-2.0".to_vec()).await.unwrap();
+            assert_eq!(user.read(&mut buf).await.unwrap(), 7);
+            let read = timeout(Duration::from_millis(150), user.read(&mut buf)).await;
+            if from_user {
+                assert_eq!(read.unwrap().unwrap(), 0);
+            } else {
+                assert!(read.is_err());
+            }
+            shutdown.cancel();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_serve_after_connection_reset() {
+        let listener = create_tcp_listener(0).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (user_incoming_sender, mut user_incoming_receiver) = mpsc::channel(8);
+        let shutdown = CancellationToken::new();
+        tokio::spawn(
+            Tcp::new(listener, user_incoming_sender, Arc::new(Semaphore::new(8)))
+                .serve(shutdown.clone()),
+        );
+
+        let (mut user, bridge, mut receiver) = connect(addr, &mut user_incoming_receiver).await;
+        user.write_all(b"hello").await.unwrap();
+        assert_eq!(receiver.recv().await.unwrap(), &b"hello"[..]);
+        // the user resets the connection in the middle of the transfer
+        user.set_linger(Some(Duration::ZERO)).unwrap();
+        drop(user);
+        let removed = timeout(Duration::from_secs(1), user_incoming_receiver.recv())
+            .await
+            .unwrap();
+        assert!(matches!(removed, Some(event::UserIncoming::Remove(id)) if id == bridge.id));
+        // the local endpoint keeps sending to the dropped connection
+        let _ = bridge.inner.send_data(b"late".to_vec()).await;
+
+        // the listener still serves the next connection
+        let (mut user, bridge, mut receiver) = connect(addr, &mut user_incoming_receiver).await;
+        user.write_all(b"again").await.unwrap();
+        assert_eq!(receiver.recv().await.unwrap(), &b"again"[..]);
+        bridge.inner.send_data(b"world".to_vec()).await.unwrap();
+        let mut buf = [0; 5];
+        user.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"world");
+        shutdown.cancel();
+    }
+
+    #[tokio::test]
+    async fn test_close_timeout() {
+        let listener = create_tcp_listener(0).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (user_incoming_sender, mut user_incoming_receiver) = mpsc::channel(8);
+        let shutdown = CancellationToken::new();
+        tokio::spawn(
+            Tcp::new(listener, user_incoming_sender, Arc::new(Semaphore::new(8)))
+                .with_close_timeout(Some(Duration::from_millis(50)))
+                .serve(shutdown.clone()),
+        );
+
+        let mut user = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let event::UserIncoming::Add(bridge) = user_incoming_receiver.recv().await.unwrap() else {
+            panic!("expected a new connection");
+        };
+        // the client stalls, it never takes the data nor the end of the stream
+        let (sender, _receiver) = mpsc::channel(1);
+        bridge.inner.send_sender(sender).await.unwrap();
+        user.write_all(b"hello").await.unwrap();
+        user.shutdown().await.unwrap();
+
+        // the connection is dropped instead of waiting for the client forever
+        let removed = timeout(Duration::from_secs(1), user_incoming_receiver.recv())
+            .await
+            .unwrap();
+        assert!(matches!(removed, Some(event::UserIncoming::Remove(id)) if id == bridge.id));
+        let mut buf = [0; 1];
+        assert!(timeout(Duration::from_secs(1), user.read(&mut buf))
+            .await
+            .unwrap()
+            .map_or(true, |n| n == 0));
+        shutdown.cancel();
     }
 }
@@ -1,19 +1,107 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
 
-use crate::{bridge::BridgeData, event, server::tunnel::BridgeResult, socket::create_udp_socket};
-use dashmap::DashMap;
-use tokio::{net::UdpSocket, select, sync::mpsc};
+use crate::{
+    bridge::{BridgeData, DataReceiver, MemoryBudget},
+    compress, constant, event,
+    server::tunnel::BridgeResult,
+    socket::{create_udp_socket_on, MAX_DATAGRAM_SIZE},
+    timing::{self, SetupTimer},
+};
+use bytes::{Bytes, BytesMut};
+use schemars::JsonSchema;
+use serde::Serialize;
+use tokio::{net::UdpSocket, select, sync::mpsc, time::Instant};
 use tokio_util::sync::CancellationToken;
 use tonic::Status;
-use tracing::{debug, error};
+use tracing::{debug, error, warn};
 
 use super::SocketCreator;
 
-const MAX_DATAGRAM_SIZE: usize = 65507;
+/// UdpSessions counts the sessions of all the udp tunnels, a session is the datagrams of a peer.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct UdpSessions {
+    open: Arc<AtomicUsize>,
+    evicted: Arc<AtomicU64>,
+}
+
+/// the usage of the udp sessions, it's reported by `GET /admin/stats`.
+#[derive(Debug, Serialize, PartialEq, JsonSchema)]
+pub(crate) struct UdpStats {
+    /// the sessions being served.
+    sessions: usize,
+    /// the sessions evicted for the new peers, see `max_udp_sessions`.
+    evicted: u64,
+}
+
+impl UdpSessions {
+    pub(crate) fn stats(&self) -> UdpStats {
+        UdpStats {
+            sessions: self.open.load(Ordering::Relaxed),
+            evicted: self.evicted.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// DatagramPool is the buffer the datagrams of the users are read into, they are split off it
+/// without copying, so a datagram costs no allocation until the buffer is used up.
+///
+/// The buffer can't be reused while any datagram split off it is in flight, a new one is
+/// allocated then, the old one is freed once all its datagrams are sent to the client.
+struct DatagramPool {
+    buf: BytesMut,
+    capacity: usize,
+}
+
+impl DatagramPool {
+    /// the capacity is at least a max datagram, 0 reads each datagram into its own buffer.
+    fn new(capacity: usize) -> Self {
+        let capacity = if capacity == 0 {
+            0
+        } else {
+            capacity.max(MAX_DATAGRAM_SIZE)
+        };
+        Self {
+            buf: BytesMut::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    async fn recv_from(&mut self, socket: &UdpSocket) -> io::Result<(Bytes, SocketAddr)> {
+        if self.capacity == 0 {
+            let mut buf = [0; MAX_DATAGRAM_SIZE];
+            let (n, addr) = socket.recv_from(&mut buf).await?;
+            return Ok((Bytes::copy_from_slice(&buf[..n]), addr));
+        }
+        if self.buf.capacity() < MAX_DATAGRAM_SIZE {
+            // the whole buffer is reclaimed if its datagrams are all sent, or a new one is allocated.
+            self.buf.reserve(self.capacity);
+        }
+        let (_, addr) = socket.recv_buf_from(&mut self.buf).await?;
+        Ok((self.buf.split().freeze(), addr))
+    }
+}
 
 pub(crate) struct Udp {
     socket: UdpSocket,
     user_incoming_sender: mpsc::Sender<event::UserIncoming>,
+    max_in_flight_bytes: usize,
+    memory_budget: MemoryBudget,
+    drain_timeout: Duration,
+    max_sessions: Option<usize>,
+    idle_timeout: Option<Duration>,
+    sessions: UdpSessions,
+    compressed: bool,
+    max_datagram_size: usize,
+    buffer_pool: usize,
 }
 
 impl Udp {
@@ -24,82 +112,328 @@ impl Udp {
         Self {
             socket,
             user_incoming_sender,
+            max_in_flight_bytes: constant::DEFAULT_MAX_IN_FLIGHT_BYTES,
+            memory_budget: MemoryBudget::new(None),
+            drain_timeout: constant::DEFAULT_UDP_DRAIN_TIMEOUT,
+            max_sessions: None,
+            idle_timeout: None,
+            sessions: UdpSessions::default(),
+            compressed: false,
+            max_datagram_size: MAX_DATAGRAM_SIZE,
+            buffer_pool: constant::DEFAULT_UDP_BUFFER_POOL,
         }
     }
 
+    pub(crate) fn with_max_in_flight_bytes(mut self, max_in_flight_bytes: usize) -> Self {
+        self.max_in_flight_bytes = max_in_flight_bytes;
+        self
+    }
+
+    /// the datagrams of the new peers are dropped while the budget is exhausted.
+    pub(crate) fn with_memory_budget(mut self, memory_budget: MemoryBudget) -> Self {
+        self.memory_budget = memory_budget;
+        self
+    }
+
+    /// cap the sessions of the tunnel, the least recently used one is evicted for a new peer,
+    /// so the spoofed peers can't pile up the sessions, it's at least 1.
+    pub(crate) fn with_max_sessions(mut self, max_sessions: Option<usize>) -> Self {
+        self.max_sessions = max_sessions.map(|max| max.max(1));
+        self
+    }
+
+    /// close the sessions without any datagram in either direction for the duration,
+    /// None keeps them until they are evicted or the tunnel closes.
+    pub(crate) fn with_idle_timeout(mut self, idle_timeout: Option<Duration>) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// count the sessions of the tunnel in the shared counters.
+    pub(crate) fn with_sessions(mut self, sessions: UdpSessions) -> Self {
+        self.sessions = sessions;
+        self
+    }
+
+    /// compress the datagrams to the client and decompress the ones from it, see [`compress`].
+    pub(crate) fn with_compression(mut self, compressed: bool) -> Self {
+        self.compressed = compressed;
+        self
+    }
+
+    /// drop the datagrams of the users larger than the client takes, None takes any datagram,
+    /// see [`crate::protocol`].
+    pub(crate) fn with_max_datagram_size(mut self, max_datagram_size: Option<usize>) -> Self {
+        self.max_datagram_size =
+            max_datagram_size.map_or(MAX_DATAGRAM_SIZE, |size| size.min(MAX_DATAGRAM_SIZE));
+        self
+    }
+
+    /// read the datagrams into the buffer of the bytes, see [`DatagramPool`].
+    pub(crate) fn with_buffer_pool(mut self, buffer_pool: usize) -> Self {
+        self.buffer_pool = buffer_pool;
+        self
+    }
+
+    /// Serve the datagrams until the shutdown is cancelled, then drain the sessions:
+    /// no datagrams are read anymore, the sessions waiting for the reply of the client
+    /// are given `drain_timeout` to send it back, the rest are closed at once.
     pub async fn serve(self, shutdown: CancellationToken) {
         let socket = Arc::new(self.socket);
         let socket2 = Arc::clone(&socket);
 
-        let shutdown_listener = shutdown.clone();
+        let draining = CancellationToken::new();
+        let closed = CancellationToken::new();
+        // every session holds a sender, so the receiver is closed once all of them finish.
+        let (alive, mut drained) = mpsc::channel::<()>(1);
         let (data_sender, data_receiver) = mpsc::channel(128);
-        let transfer_manager =
-            TransferManager::new(shutdown.clone(), self.user_incoming_sender, data_receiver);
+        let transfer_manager = TransferManager::new(
+            draining.clone(),
+            closed.clone(),
+            alive,
+            self.user_incoming_sender,
+            data_receiver,
+            self.max_in_flight_bytes,
+            self.memory_budget,
+            self.max_sessions,
+            self.idle_timeout,
+            self.sessions,
+            self.compressed,
+        );
         tokio::spawn(async move {
             transfer_manager.run(socket2).await;
         });
 
+        // the delay before reading again after the transient errors in a row.
+        let mut backoff = Duration::ZERO;
+        let mut pool = DatagramPool::new(self.buffer_pool);
         loop {
-            let mut buf = [0; MAX_DATAGRAM_SIZE];
-
             select! {
-                _ = shutdown_listener.cancelled() => {
-                    return;
+                _ = shutdown.cancelled() => {
+                    break;
                 }
-                result = socket.recv_from(&mut buf) => { // read from user
+                result = async {
+                    if !backoff.is_zero() {
+                        tokio::time::sleep(backoff).await;
+                    }
+                    pool.recv_from(&socket).await
+                } => { // read from user
                     match result {
                         Ok(data) => {
-                            // since udp is connectionless,
-                            // when receive a packet from addr, we treat it as a keepalive.
-                            // so there should be a auto release mechanism.
-                            // TODO(sword): add a keepalive mechanism for udp.
-
-                            let (n, addr) = data;
-                            data_sender.send((buf[..n].to_vec(), addr)).await.unwrap();
+                            backoff = Duration::ZERO;
+                            // since udp is connectionless, the datagrams of a peer keep its session,
+                            // it's released after `idle_timeout` without any.
+                            let (datagram, addr) = data;
+                            if datagram.len() > self.max_datagram_size {
+                                debug!(
+                                    size = datagram.len(),
+                                    max_datagram_size = self.max_datagram_size,
+                                    ?addr,
+                                    "dropped a datagram larger than the client takes",
+                                );
+                                continue;
+                            }
+                            if data_sender.send((datagram, addr)).await.is_err() {
+                                // the transfer manager quits once the tunnel is closed.
+                                debug!("the tunnel is closed, stop receiving datagrams");
+                                break;
+                            }
+                        },
+                        Err(err) if is_transient(&err) => {
+                            backoff = (backoff * 2)
+                                .clamp(constant::UDP_RECV_ERROR_BACKOFF, constant::UDP_RECV_ERROR_MAX_BACKOFF);
+                            warn!(err = ?err, ?backoff, "failed to receive data, retry later");
                         },
                         Err(err) => {
-                            error!(err = ?err, "failed to receive data");
+                            // the socket is broken, reading it again fails forever.
+                            error!(err = ?err, "failed to receive data, close the udp tunnel");
+                            break;
                         },
                     }
                 }
             }
         }
+
+        debug!("draining udp sessions");
+        draining.cancel();
+        if tokio::time::timeout(self.drain_timeout, drained.recv())
+            .await
+            .is_err()
+        {
+            warn!(
+                drain_timeout = ?self.drain_timeout,
+                "the udp sessions are not drained in time, close them"
+            );
+        }
+        closed.cancel();
     }
 }
 
+/// the errors of a datagram or a peer rather than of the socket,
+/// e.g. the icmp errors of the datagrams sent before are reported by the next read on some platforms.
+fn is_transient(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::HostUnreachable
+            | io::ErrorKind::NetworkUnreachable
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::OutOfMemory
+    )
+}
+
 impl SocketCreator for Udp {
     type Output = UdpSocket;
 
-    async fn create_socket(port: u16) -> anyhow::Result<UdpSocket, Status> {
-        create_udp_socket(port).await
+    async fn create_socket(ip: IpAddr, port: u16) -> anyhow::Result<UdpSocket, Status> {
+        create_udp_socket_on(SocketAddr::new(ip, port)).await
+    }
+
+    fn local_port(socket: &UdpSocket) -> Result<u16, Status> {
+        socket
+            .local_addr()
+            .map(|addr| addr.port())
+            .map_err(|err| Status::internal(format!("failed to get the local address: {}", err)))
+    }
+}
+
+/// Session is the transfer of a peer in the [`TransferManager`].
+struct Session {
+    /// tells the session from the later one of the same peer.
+    id: u64,
+    transfer_tx: mpsc::Sender<Bytes>,
+    /// the sequence number of the last datagram from the peer.
+    last_seen: u64,
+    /// the session is closed after it's cancelled, like `closed` of the manager.
+    evict: CancellationToken,
+}
+
+/// Lru is the sessions of the peers ordered by their last datagrams,
+/// the least recently used one is evicted first.
+#[derive(Default)]
+struct Lru {
+    sessions: HashMap<SocketAddr, Session>,
+    /// the peers by the sequence numbers of their last datagrams.
+    order: BTreeMap<u64, SocketAddr>,
+}
+
+impl Lru {
+    fn insert(&mut self, addr: SocketAddr, session: Session) {
+        self.order.insert(session.last_seen, addr);
+        if let Some(replaced) = self.sessions.insert(addr, session) {
+            self.order.remove(&replaced.last_seen);
+        }
+    }
+
+    /// move the session of the peer to the most recently used one,
+    /// the sender of the session is returned if the peer has one.
+    fn touch(&mut self, addr: &SocketAddr, seen: u64) -> Option<mpsc::Sender<Bytes>> {
+        let session = self.sessions.get_mut(addr)?;
+        self.order.remove(&session.last_seen);
+        self.order.insert(seen, *addr);
+        session.last_seen = seen;
+        Some(session.transfer_tx.clone())
+    }
+
+    /// remove the session of the peer if it's still the one of the id.
+    fn remove(&mut self, addr: &SocketAddr, id: u64) {
+        if self
+            .sessions
+            .get(addr)
+            .is_some_and(|session| session.id == id)
+        {
+            let session = self.sessions.remove(addr).unwrap();
+            self.order.remove(&session.last_seen);
+        }
+    }
+
+    fn pop_lru(&mut self) -> Option<(SocketAddr, Session)> {
+        let (_, addr) = self.order.pop_first()?;
+        let session = self.sessions.remove(&addr)?;
+        Some((addr, session))
     }
 }
 
 struct TransferManager {
-    shutdown: CancellationToken,
+    /// no new sessions are created after it's cancelled.
+    draining: CancellationToken,
+    /// the sessions are closed after it's cancelled.
+    closed: CancellationToken,
+    alive: mpsc::Sender<()>,
     user_incoming_sender: mpsc::Sender<event::UserIncoming>,
-    data_receiver: mpsc::Receiver<(Vec<u8>, SocketAddr)>,
+    data_receiver: mpsc::Receiver<(Bytes, SocketAddr)>,
+    max_in_flight_bytes: usize,
+    memory_budget: MemoryBudget,
+    max_sessions: Option<usize>,
+    idle_timeout: Option<Duration>,
+    sessions: UdpSessions,
+    compressed: bool,
 }
 
 impl TransferManager {
+    #[allow(clippy::too_many_arguments)]
     fn new(
-        shutdown: CancellationToken,
+        draining: CancellationToken,
+        closed: CancellationToken,
+        alive: mpsc::Sender<()>,
         user_incoming_sender: mpsc::Sender<event::UserIncoming>,
-        data_receiver: mpsc::Receiver<(Vec<u8>, SocketAddr)>,
+        data_receiver: mpsc::Receiver<(Bytes, SocketAddr)>,
+        max_in_flight_bytes: usize,
+        memory_budget: MemoryBudget,
+        max_sessions: Option<usize>,
+        idle_timeout: Option<Duration>,
+        sessions: UdpSessions,
+        compressed: bool,
     ) -> Self {
         Self {
-            shutdown,
+            draining,
+            closed,
+            alive,
             user_incoming_sender,
             data_receiver,
+            max_in_flight_bytes,
+            memory_budget,
+            max_sessions,
+            idle_timeout,
+            sessions,
+            compressed,
+        }
+    }
+
+    /// evict the least recently used session if the sessions are at the cap.
+    fn evict(&self, transferring: &Mutex<Lru>) {
+        let Some(max_sessions) = self.max_sessions else {
+            return;
+        };
+        let mut transferring = transferring.lock().unwrap();
+        if transferring.sessions.len() < max_sessions {
+            return;
+        }
+        if let Some((socket_addr, session)) = transferring.pop_lru() {
+            warn!(
+                ?socket_addr,
+                max_sessions, "too many udp sessions, evicted the least recently used one"
+            );
+            session.evict.cancel();
+            self.sessions.evicted.fetch_add(1, Ordering::Relaxed);
         }
     }
 
     async fn run(mut self, socket: Arc<UdpSocket>) {
-        let transferring = Arc::new(DashMap::new());
+        let transferring: Arc<Mutex<Lru>> = Arc::default();
+        // the sequence of the datagrams, it orders the sessions by their last datagrams.
+        let mut seen = 0;
 
         loop {
             let transferring = Arc::clone(&transferring);
             select! {
+                _ = self.draining.cancelled() => {
+                    return;
+                }
                 data = self.data_receiver.recv() => {
                     let socket = Arc::clone(&socket);
                     let (data, socket_addr) = match data {
@@ -109,62 +443,123 @@ impl TransferManager {
                         }
                     };
 
-                    if !transferring.contains_key(&socket_addr) {
+                    seen += 1;
+                    if !transferring.lock().unwrap().sessions.contains_key(&socket_addr) {
+                        if self.memory_budget.is_exhausted() {
+                            warn!(?socket_addr, "the memory budget is exhausted, dropped the datagram of a new peer");
+                            continue;
+                        }
+                        // the session is evicted before the bridge is set up, so the sessions
+                        // and their bridges stay within the cap.
+                        self.evict(&transferring);
+                        let mut timer = SetupTimer::start();
+                        // the bridges of the new peers are set up one by one,
+                        // so the udp tunnel doesn't need the connection setups limit.
                         let BridgeResult {
                             data_sender,
                             data_receiver,
                             client_cancel_receiver,
                             remove_bridge_sender,
-                        } = match super::init_data_sender_bridge(self.user_incoming_sender.clone()).await {
+                        } = match super::init_data_sender_bridge(self.user_incoming_sender.clone(), String::new(), self.max_in_flight_bytes, self.memory_budget.clone()).await {
                             Ok(result) => result,
-                            Err(err) => {
-                                error!(err = ?err, "failed to init data sender bridge");
+                            Err(_) if self.user_incoming_sender.is_closed() => {
+                                debug!("the tunnel is closed, stop receiving datagrams");
                                 return
                             }
+                            Err(err) => {
+                                // e.g. the client cancelled the connection during the setup,
+                                // the next datagram of the peer sets up the bridge again.
+                                warn!(err = ?err, ?socket_addr, "failed to init data sender bridge, dropped the datagram");
+                                continue;
+                            }
                         };
 
                         let (transfer_tx, transfer_rx) = mpsc::channel(128);
-                        transferring.insert(socket_addr, transfer_tx.clone());
+                        let evict = self.closed.child_token();
+                        transferring.lock().unwrap().insert(socket_addr, Session {
+                            id: seen,
+                            transfer_tx: transfer_tx.clone(),
+                            last_seen: seen,
+                            evict: evict.clone(),
+                        });
+                        self.sessions.open.fetch_add(1, Ordering::Relaxed);
                         debug!(?socket_addr, "new transfer");
+                        debug!(target: timing::TARGET, ?socket_addr, bridge = ?timer.lap(), "udp peer set up");
 
 
-                        let shutdown = self.shutdown.clone();
+                        let draining = self.draining.clone();
+                        let alive = self.alive.clone();
+                        let sessions = self.sessions.clone();
+                        let id = seen;
+                        let compressed = self.compressed;
+                        let idle_timeout = self.idle_timeout;
                         tokio::spawn(async move {
                             Self::transfer(
-                                shutdown,
+                                draining,
+                                evict,
                                 transfer_rx,
                                 client_cancel_receiver,
                                 data_sender,
                                 data_receiver,
                                 &socket,
                                 socket_addr,
+                                compressed,
+                                idle_timeout,
                             ).await;
-                            transferring.remove(&socket_addr);
+                            // the peer may have a new session after it's evicted
+                            transferring.lock().unwrap().remove(&socket_addr, id);
+                            sessions.open.fetch_sub(1, Ordering::Relaxed);
                             remove_bridge_sender.cancel();
+                            drop(alive);
                         });
-                        transfer_tx.send(data).await.unwrap();
+                        if transfer_tx.send(data).await.is_err() {
+                            debug!(?socket_addr, "the session finished, dropped the datagram");
+                        }
                     } else {
                         debug!(?socket_addr, "send data to transfer");
-                        transferring.get(&socket_addr).unwrap().send(data).await.unwrap();
+                        // the session may finish meanwhile, the datagram is dropped then.
+                        let transfer_tx = transferring.lock().unwrap().touch(&socket_addr, seen);
+                        if let Some(transfer_tx) = transfer_tx {
+                            if transfer_tx.send(data).await.is_err() {
+                                debug!(?socket_addr, "the session finished, dropped the datagram");
+                            }
+                        }
                     }
                 }
             }
         }
     }
 
+    /// transfer the datagrams of a peer, it's a session.
+    ///
+    /// A session waits for a reply from the time it forwards a datagram to the client
+    /// until it sends a datagram back to the peer. When draining, a session waiting
+    /// for a reply finishes the exchange before it quits. A session is closed after
+    /// `idle_timeout` without any datagram in either direction.
+    #[allow(clippy::too_many_arguments)]
     async fn transfer(
-        shutdown: CancellationToken,
-        mut transfer_rx: mpsc::Receiver<Vec<u8>>,
+        draining: CancellationToken,
+        closed: CancellationToken,
+        mut transfer_rx: mpsc::Receiver<Bytes>,
         client_cancel_receiver: CancellationToken,
-        data_sender: mpsc::Sender<Vec<u8>>,
-        mut data_receiver: mpsc::Receiver<BridgeData>,
+        data_sender: mpsc::Sender<Bytes>,
+        mut data_receiver: DataReceiver,
         remote_writer: &UdpSocket,
         remote_addr: SocketAddr,
+        compressed: bool,
+        idle_timeout: Option<Duration>,
     ) {
+        let done = CancellationToken::new();
+        let waiting_reply = AtomicBool::new(false);
+        let last_active = Mutex::new(Instant::now());
+
         let read_transfer_send_to_bridge = async {
             loop {
                 select! {
-                    _ = shutdown.cancelled() => {
+                    _ = closed.cancelled() => {
+                        return
+                    }
+                    _ = done.cancelled() => {
                         return
                     }
                     _ = client_cancel_receiver.cancelled() => {
@@ -174,6 +569,10 @@ impl TransferManager {
                         match data {
                             None => return,
                             Some(data) => {
+                                *last_active.lock().unwrap() = Instant::now();
+                                waiting_reply.store(true, Ordering::Release);
+                                // the datagram is sent as it is, it holds the buffer it's read into until it's sent.
+                                let data = if compressed { Bytes::from(compress::compress(&data)) } else { data };
                                 select! {
                                     _ = client_cancel_receiver.cancelled() => {}
                                     result = data_sender.send(data) => {
@@ -193,7 +592,10 @@ impl TransferManager {
         let read_bridge_send_to_user = async {
             loop {
                 select! {
-                    _ = shutdown.cancelled() => {
+                    _ = closed.cancelled() => {
+                        return
+                    }
+                    _ = done.cancelled() => {
                         return
                     }
                     _ = client_cancel_receiver.cancelled() => {
@@ -205,6 +607,27 @@ impl TransferManager {
                         }
                         match result.unwrap() {
                             BridgeData::Data(data) => {
+                                *last_active.lock().unwrap() = Instant::now();
+                                let data = if compressed {
+                                    match compress::decompress(&data) {
+                                        Ok(data) => data,
+                                        Err(status) => {
+                                            warn!(?status, ?remote_addr, "dropped an invalid compressed datagram");
+                                            continue;
+                                        }
+                                    }
+                                } else {
+                                    data
+                                };
+                                if data.len() > MAX_DATAGRAM_SIZE {
+                                    warn!(
+                                        size = data.len(),
+                                        max_datagram_size = MAX_DATAGRAM_SIZE,
+                                        ?remote_addr,
+                                        "dropped a datagram exceeding the max datagram size",
+                                    );
+                                    continue;
+                                }
                                 select! {
                                     _ = client_cancel_receiver.cancelled() => {
                                         return;
@@ -214,11 +637,18 @@ impl TransferManager {
                                             error!(err = ?err, "failed to send udp to client");
                                             return;
                                         }
+                                        waiting_reply.store(false, Ordering::Release);
+                                        if draining.is_cancelled() {
+                                            // the exchange is finished
+                                            done.cancel();
+                                            return;
+                                        }
                                     }
                                 }
                             },
                             BridgeData::Sender(_) => {
-                                panic!("only expect data");
+                                error!("unexpected sender after the bridge handshake");
+                                return;
                             },
                         };
                     }
@@ -226,6 +656,298 @@ impl TransferManager {
             }
         };
 
-        tokio::join!(read_transfer_send_to_bridge, read_bridge_send_to_user);
+        let drain = async {
+            select! {
+                _ = draining.cancelled() => {}
+                _ = done.cancelled() => return,
+            }
+            if !waiting_reply.load(Ordering::Acquire) {
+                done.cancel();
+            }
+        };
+
+        let idle = async {
+            let Some(idle_timeout) = idle_timeout else {
+                return;
+            };
+            loop {
+                let deadline = *last_active.lock().unwrap() + idle_timeout;
+                select! {
+                    _ = done.cancelled() => return,
+                    _ = tokio::time::sleep_until(deadline) => {}
+                }
+                // a datagram may arrive right before the deadline
+                if last_active.lock().unwrap().elapsed() >= idle_timeout {
+                    debug!(
+                        ?remote_addr,
+                        ?idle_timeout,
+                        "the udp session is idle, close it"
+                    );
+                    done.cancel();
+                    return;
+                }
+            }
+        };
+
+        tokio::join!(
+            async {
+                tokio::join!(read_transfer_send_to_bridge, read_bridge_send_to_user);
+                done.cancel();
+            },
+            drain,
+            idle,
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::socket::create_udp_socket;
+
+    #[test]
+    fn test_is_transient() {
+        // the icmp port unreachable of a datagram sent before
+        assert!(is_transient(&io::Error::from(
+            io::ErrorKind::ConnectionRefused
+        )));
+        assert!(is_transient(&io::Error::from(
+            io::ErrorKind::ConnectionReset
+        )));
+        assert!(!is_transient(&io::Error::other("bad file descriptor")));
+        assert!(!is_transient(&io::Error::from(io::ErrorKind::InvalidInput)));
+    }
+
+    #[tokio::test]
+    async fn test_datagram_pool() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        // the datagrams are split off the same buffer
+        let mut pool = DatagramPool::new(2 * MAX_DATAGRAM_SIZE);
+        peer.send_to(b"first", addr).await.unwrap();
+        peer.send_to(b"second", addr).await.unwrap();
+        let (first, from) = pool.recv_from(&socket).await.unwrap();
+        let (second, _) = pool.recv_from(&socket).await.unwrap();
+        assert_eq!(from, peer.local_addr().unwrap());
+        assert_eq!((&first[..], &second[..]), (&b"first"[..], &b"second"[..]));
+        assert_eq!(second.as_ptr(), first[first.len()..].as_ptr());
+
+        // the used up buffer is reclaimed once its datagrams are dropped
+        let mut pool = DatagramPool::new(1);
+        peer.send_to(b"first", addr).await.unwrap();
+        let (first, _) = pool.recv_from(&socket).await.unwrap();
+        let ptr = first.as_ptr();
+        drop(first);
+        peer.send_to(b"second", addr).await.unwrap();
+        let (second, _) = pool.recv_from(&socket).await.unwrap();
+        assert_eq!(&second[..], b"second");
+        assert_eq!(second.as_ptr(), ptr);
+
+        // no pool
+        let mut pool = DatagramPool::new(0);
+        peer.send_to(b"third", addr).await.unwrap();
+        let (third, _) = pool.recv_from(&socket).await.unwrap();
+        assert_eq!(&third[..], b"third");
+    }
+
+    #[tokio::test]
+    async fn test_serve_after_bridge_setup_failed() {
+        let socket = create_udp_socket(0).await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let (user_incoming_sender, mut user_incoming_receiver) = mpsc::channel(16);
+        let shutdown = CancellationToken::new();
+        let serve = tokio::spawn(Udp::new(socket, user_incoming_sender).serve(shutdown.clone()));
+
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        for _ in 0..2 {
+            peer.send_to(b"ping", addr).await.unwrap();
+            let incoming = tokio::time::timeout(Duration::from_secs(1), async {
+                loop {
+                    if let Some(event::UserIncoming::Add(bridge)) =
+                        user_incoming_receiver.recv().await
+                    {
+                        return bridge;
+                    }
+                }
+            })
+            .await
+            .expect("the datagram is served");
+            // the bridge is dropped before the sender arrives, so the setup fails
+            drop(incoming);
+        }
+
+        shutdown.cancel();
+        serve.await.unwrap();
+    }
+
+    #[test]
+    fn test_lru() {
+        let (transfer_tx, _transfer_rx) = mpsc::channel(1);
+        let session = |id| Session {
+            id,
+            transfer_tx: transfer_tx.clone(),
+            last_seen: id,
+            evict: CancellationToken::new(),
+        };
+        let peers: Vec<SocketAddr> = (1..=3)
+            .map(|port| SocketAddr::from(([127, 0, 0, 1], port)))
+            .collect();
+        let mut lru = Lru::default();
+        for (id, peer) in (1..).zip(&peers) {
+            lru.insert(*peer, session(id));
+        }
+
+        // the first peer sends another datagram
+        assert!(lru.touch(&peers[0], 4).is_some());
+        assert_eq!(lru.pop_lru().unwrap().0, peers[1]);
+        // the finished session of another id doesn't remove the new one
+        lru.remove(&peers[2], 1);
+        assert_eq!(lru.pop_lru().unwrap().0, peers[2]);
+        lru.remove(&peers[0], 1);
+        assert!(lru.pop_lru().is_none());
+        assert!(lru.touch(&peers[0], 5).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_evict_udp_sessions() {
+        let socket = create_udp_socket(0).await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let (user_incoming_sender, mut user_incoming_receiver) = mpsc::channel(16);
+        let sessions = UdpSessions::default();
+        let shutdown = CancellationToken::new();
+        let serve = tokio::spawn(
+            Udp::new(socket, user_incoming_sender)
+                .with_max_sessions(Some(1))
+                .with_sessions(sessions.clone())
+                .serve(shutdown.clone()),
+        );
+
+        // the bridges are kept, so the sessions are open until they are evicted
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let first = open_session(&mut user_incoming_receiver, &peer, addr).await;
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let second = open_session(&mut user_incoming_receiver, &peer, addr).await;
+
+        // the first one is evicted for the second one
+        let removed = tokio::time::timeout(Duration::from_secs(1), async {
+            loop {
+                if let Some(event::UserIncoming::Remove(id)) = user_incoming_receiver.recv().await {
+                    return id;
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(removed, first.0.id);
+        assert_eq!(
+            sessions.stats(),
+            UdpStats {
+                sessions: 1,
+                evicted: 1,
+            }
+        );
+
+        // the client closes the sessions
+        first.0.inner.close();
+        second.0.inner.close();
+        shutdown.cancel();
+        serve.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_udp_session_idle_timeout() {
+        let socket = create_udp_socket(0).await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let (user_incoming_sender, mut user_incoming_receiver) = mpsc::channel(16);
+        let sessions = UdpSessions::default();
+        let shutdown = CancellationToken::new();
+        let serve = tokio::spawn(
+            Udp::new(socket, user_incoming_sender)
+                .with_idle_timeout(Some(Duration::from_millis(300)))
+                .with_sessions(sessions.clone())
+                .serve(shutdown.clone()),
+        );
+
+        // the datagrams of the peer share the session
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (first, mut datagrams) = open_session(&mut user_incoming_receiver, &peer, addr).await;
+        for _ in 0..3 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            peer.send_to(b"again", addr).await.unwrap();
+            assert_eq!(datagrams.recv().await.unwrap(), &b"again"[..]);
+        }
+        assert!(user_incoming_receiver.try_recv().is_err());
+
+        // the silent session is closed with its bridge
+        let removed = tokio::time::timeout(Duration::from_secs(1), user_incoming_receiver.recv())
+            .await
+            .unwrap();
+        assert!(matches!(removed, Some(event::UserIncoming::Remove(id)) if id == first.id));
+        assert_eq!(sessions.stats().sessions, 0);
+
+        // the next datagram opens a new session
+        let (second, _datagrams) = open_session(&mut user_incoming_receiver, &peer, addr).await;
+        assert_ne!(second.id, first.id);
+
+        second.inner.close();
+        shutdown.cancel();
+        serve.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_udp_session_closed_by_client() {
+        let socket = create_udp_socket(0).await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let (user_incoming_sender, mut user_incoming_receiver) = mpsc::channel(16);
+        let sessions = UdpSessions::default();
+        let shutdown = CancellationToken::new();
+        let serve = tokio::spawn(
+            Udp::new(socket, user_incoming_sender)
+                .with_idle_timeout(Some(Duration::from_secs(60)))
+                .with_sessions(sessions.clone())
+                .serve(shutdown.clone()),
+        );
+
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (first, _datagrams) = open_session(&mut user_incoming_receiver, &peer, addr).await;
+
+        // the client closes the session while the peer is still in the map
+        first.inner.close();
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while sessions.stats().sessions > 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the session is removed");
+
+        // the peer isn't stuck with the closed session
+        let (second, _datagrams) = open_session(&mut user_incoming_receiver, &peer, addr).await;
+        assert_ne!(second.id, first.id);
+        assert_eq!(sessions.stats().sessions, 1);
+
+        second.inner.close();
+        shutdown.cancel();
+        serve.await.unwrap();
+    }
+
+    /// send a datagram from the peer and answer the bridge of its session like the client.
+    async fn open_session(
+        user_incoming_receiver: &mut mpsc::Receiver<event::UserIncoming>,
+        peer: &UdpSocket,
+        addr: SocketAddr,
+    ) -> (crate::bridge::IdDataSenderBridge, mpsc::Receiver<Bytes>) {
+        peer.send_to(b"ping", addr).await.unwrap();
+        let bridge = loop {
+            if let event::UserIncoming::Add(bridge) = user_incoming_receiver.recv().await.unwrap() {
+                break bridge;
+            }
+        };
+        let (sender, mut datagrams) = mpsc::channel(1);
+        bridge.inner.send_sender(sender).await.unwrap();
+        assert_eq!(datagrams.recv().await.unwrap(), &b"ping"[..]);
+        (bridge, datagrams)
     }
 }
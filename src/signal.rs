@@ -0,0 +1,50 @@
+//! The signals stopping the binaries gracefully, ctrl-c on all the platforms,
+//! and `SIGTERM` on unix, e.g. sent by `docker stop`, kubernetes and systemd.
+use std::future::Future;
+
+/// wait for a signal asking the process to shut down, the name of the signal is returned.
+pub async fn shutdown_signal() -> std::io::Result<&'static str> {
+    listen()?.await
+}
+
+/// install the handlers of the signals right away, the future waits for the first one,
+/// so a signal arriving before the future is polled isn't missed.
+fn listen() -> std::io::Result<impl Future<Output = std::io::Result<&'static str>>> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut interrupt = signal(SignalKind::interrupt())?;
+        let mut terminate = signal(SignalKind::terminate())?;
+        Ok(async move {
+            tokio::select! {
+                _ = interrupt.recv() => Ok("ctrl-c"),
+                _ = terminate.recv() => Ok("SIGTERM"),
+            }
+        })
+    }
+    #[cfg(not(unix))]
+    {
+        Ok(async { tokio::signal::ctrl_c().await.map(|_| "ctrl-c") })
+    }
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_shutdown_signal() {
+        // the handlers are installed before the signal is sent
+        let signal = listen().unwrap();
+        unsafe {
+            libc::kill(libc::getpid(), libc::SIGTERM);
+        }
+        let signal = tokio::time::timeout(Duration::from_secs(1), signal)
+            .await
+            .unwrap();
+        assert_eq!(signal.unwrap(), "SIGTERM");
+    }
+}
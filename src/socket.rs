@@ -1,8 +1,10 @@
 //! Socket utilities for creating listeners, async readers, writers, and dialers.
 use std::{
-    net::SocketAddr,
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     pin::Pin,
     task::{Context, Poll},
+    time::{Duration, Instant},
 };
 
 use tokio::{
@@ -10,20 +12,36 @@ use tokio::{
     net::{TcpListener, TcpStream, UdpSocket},
 };
 use tonic::Status;
-use tracing::error;
+use tracing::{debug, error, warn};
 
-/// create a tcp listener.
+use crate::{client::tls::LocalConnector, constant};
+
+/// the max payload of a udp datagram over ipv4.
+pub(crate) const MAX_DATAGRAM_SIZE: usize = 65507;
+
+/// create a tcp listener on all the interfaces.
+#[cfg(test)]
 pub(crate) async fn create_tcp_listener(port: u16) -> Result<TcpListener, Status> {
-    TcpListener::bind(("0.0.0.0", port))
-        .await
-        .map_err(map_bind_error)
+    create_tcp_listener_on(SocketAddr::from(([0, 0, 0, 0], port))).await
 }
 
-/// create a udp socket.
+/// create a tcp listener on the address, e.g. only on the loopback interface.
+///
+/// `SO_REUSEADDR` is set except on windows, so the port of a closed tunnel can be bound again
+/// right away even if its connections linger in `TIME_WAIT`.
+pub(crate) async fn create_tcp_listener_on(addr: SocketAddr) -> Result<TcpListener, Status> {
+    TcpListener::bind(addr).await.map_err(map_bind_error)
+}
+
+/// create a udp socket on all the interfaces.
+#[cfg(test)]
 pub(crate) async fn create_udp_socket(port: u16) -> Result<UdpSocket, Status> {
-    UdpSocket::bind(("0.0.0.0", port))
-        .await
-        .map_err(map_bind_error)
+    create_udp_socket_on(SocketAddr::from(([0, 0, 0, 0], port))).await
+}
+
+/// create a udp socket on the address, e.g. only on the loopback interface.
+pub(crate) async fn create_udp_socket_on(addr: SocketAddr) -> Result<UdpSocket, Status> {
+    UdpSocket::bind(addr).await.map_err(map_bind_error)
 }
 
 fn map_bind_error(err: std::io::Error) -> Status {
@@ -37,14 +55,171 @@ fn map_bind_error(err: std::io::Error) -> Status {
     }
 }
 
+/// AcceptBackoff paces an accept loop when the process runs out of the file descriptors,
+/// accept fails right away until some are freed, retrying it at once spins the cpu.
+#[derive(Debug, Default)]
+pub(crate) struct AcceptBackoff {
+    delay: Duration,
+    /// the errors since the last warning.
+    errors: u64,
+    warned: Option<Instant>,
+}
+
+impl AcceptBackoff {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// accept a connection, it backs off and retries while the resources are exhausted,
+    /// the errors of the connections being accepted are skipped, the rest are returned.
+    pub(crate) async fn accept(
+        &mut self,
+        listener: &TcpListener,
+    ) -> io::Result<(TcpStream, SocketAddr)> {
+        loop {
+            match listener.accept().await {
+                Ok(accepted) => {
+                    self.delay = Duration::ZERO;
+                    return Ok(accepted);
+                }
+                Err(err) if is_resource_exhausted(&err) => {
+                    let delay = self.back_off();
+                    if self.should_warn(Instant::now()) {
+                        warn!(
+                            ?err,
+                            errors = self.errors,
+                            ?delay,
+                            "running out of the file descriptors, back off accepting the connections"
+                        );
+                        self.errors = 0;
+                    }
+                    tokio::time::sleep(delay).await;
+                }
+                Err(err) if is_connection_error(&err) => {
+                    debug!(?err, "failed to accept a connection, skipped");
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// the delay of the error, it doubles after each error in a row.
+    fn back_off(&mut self) -> Duration {
+        self.errors += 1;
+        self.delay = (self.delay * 2).clamp(
+            constant::ACCEPT_ERROR_BACKOFF,
+            constant::ACCEPT_ERROR_MAX_BACKOFF,
+        );
+        self.delay
+    }
+
+    fn should_warn(&mut self, now: Instant) -> bool {
+        if self.warned.is_some_and(|warned| {
+            now.saturating_duration_since(warned) < constant::ACCEPT_ERROR_WARN_INTERVAL
+        }) {
+            return false;
+        }
+        self.warned = Some(now);
+        true
+    }
+}
+
+/// EMFILE, ENFILE, ENOBUFS or ENOMEM, they're freed by the other connections sooner or later.
+fn is_resource_exhausted(err: &io::Error) -> bool {
+    #[cfg(unix)]
+    const CODES: &[i32] = &[
+        23, // ENFILE
+        24, // EMFILE
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        105, // ENOBUFS
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        55, // ENOBUFS
+    ];
+    #[cfg(windows)]
+    const CODES: &[i32] = &[
+        10024, // WSAEMFILE
+        10055, // WSAENOBUFS
+    ];
+    #[cfg(not(any(unix, windows)))]
+    const CODES: &[i32] = &[];
+
+    err.kind() == io::ErrorKind::OutOfMemory
+        || err.raw_os_error().is_some_and(|code| CODES.contains(&code))
+}
+
+/// the connection is gone before it's accepted.
+fn is_connection_error(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::TimedOut
+    )
+}
+
+/// OS-level keepalive of tcp connections.
+///
+/// It's separated from the application heartbeats, it helps to detect the dead peers
+/// and keeps the NAT mappings alive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TcpKeepalive {
+    /// the idle time before the first keepalive probe is sent.
+    pub idle: Duration,
+    /// the interval between the keepalive probes, the OS default is used if None.
+    pub interval: Option<Duration>,
+    /// the number of unacknowledged probes before the connection is dropped,
+    /// the OS default is used if None.
+    pub retries: Option<u32>,
+}
+
+impl TcpKeepalive {
+    /// Create a keepalive config with the idle time, the rest options use the OS defaults.
+    pub fn new(idle: Duration) -> Self {
+        Self {
+            idle,
+            interval: None,
+            retries: None,
+        }
+    }
+}
+
+/// enable SO_KEEPALIVE on the tcp stream.
+pub(crate) fn set_tcp_keepalive(stream: &TcpStream, keepalive: &TcpKeepalive) -> io::Result<()> {
+    #[allow(unused_mut)]
+    let mut params = socket2::TcpKeepalive::new().with_time(keepalive.idle);
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "macos"))]
+    {
+        if let Some(interval) = keepalive.interval {
+            params = params.with_interval(interval);
+        }
+        if let Some(retries) = keepalive.retries {
+            params = params.with_retries(retries);
+        }
+    }
+    socket2::SockRef::from(stream).set_tcp_keepalive(&params)
+}
+
 /// Async reader for a udp connection.
+///
+/// Each read returns one datagram, the datagrams larger than `max_datagram_size`
+/// are dropped with a warning instead of being truncated silently.
 pub(crate) struct AsyncUdpSocket<'a> {
     socket: &'a UdpSocket,
+    max_datagram_size: usize,
+    /// receives a whole datagram, allocated on the first read.
+    buf: Vec<u8>,
 }
 
 impl<'a> AsyncUdpSocket<'a> {
-    pub(crate) fn new(socket: &'a UdpSocket) -> Self {
-        Self { socket }
+    pub(crate) fn new(socket: &'a UdpSocket, max_datagram_size: usize) -> Self {
+        Self {
+            socket,
+            max_datagram_size,
+            buf: Vec::new(),
+        }
     }
 }
 
@@ -54,10 +229,38 @@ impl<'a> AsyncRead for AsyncUdpSocket<'a> {
         cx: &mut Context<'_>,
         buf: &mut io::ReadBuf<'_>,
     ) -> Poll<io::Result<()>> {
-        match self.get_mut().socket.poll_recv_from(cx, buf) {
-            Poll::Ready(Ok(_addr)) => Poll::Ready(Ok(())),
-            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
-            Poll::Pending => Poll::Pending,
+        let this = self.get_mut();
+        if this.buf.is_empty() {
+            // one more byte to tell the oversized datagrams from the max sized ones.
+            this.buf = vec![0; this.max_datagram_size + 1];
+        }
+
+        loop {
+            let mut datagram = io::ReadBuf::new(&mut this.buf);
+            match this.socket.poll_recv_from(cx, &mut datagram) {
+                Poll::Ready(Ok(_addr)) => {}
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+
+            let datagram = datagram.filled();
+            if datagram.len() > this.max_datagram_size {
+                warn!(
+                    max_datagram_size = this.max_datagram_size,
+                    "dropped a udp datagram exceeding the max datagram size"
+                );
+                continue;
+            }
+            if datagram.len() > buf.remaining() {
+                warn!(
+                    size = datagram.len(),
+                    buf_size = buf.remaining(),
+                    "truncated a udp datagram larger than the read buffer"
+                );
+            }
+            let n = datagram.len().min(buf.remaining());
+            buf.put_slice(&datagram[..n]);
+            return Poll::Ready(Ok(()));
         }
     }
 }
@@ -91,12 +294,61 @@ impl<'a> AsyncWrite for AsyncUdpSocket<'a> {
 pub(crate) struct Dialer {
     dial: DialFn,
     addr: SocketAddr,
+    options: DialOptions,
+    /// the endpoint receiving a copy of the traffic sent to `addr`, see [`Dialer::dial_shadow`].
+    shadow: Option<SocketAddr>,
+    /// the endpoints dialed instead of `addr` for the http requests of the hosts,
+    /// see [`Dialer::dial_host`].
+    upstreams: HashMap<String, SocketAddr>,
+    /// the other addresses of `addr` tried in order when it fails, e.g. the local endpoint
+    /// is a hostname resolved to several addresses.
+    fallbacks: Vec<SocketAddr>,
+}
+
+/// Options applied to the connection when dialing a endpoint.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct DialOptions {
+    /// only used by the tcp dialer.
+    pub tcp_keepalive: Option<TcpKeepalive>,
+    /// only used by the udp dialer, the larger datagrams from the endpoint are dropped.
+    pub max_datagram_size: Option<usize>,
+    /// only used by the udp dialer, the socket is bound to it instead of the unspecified address,
+    /// so the replies of the endpoint are received on the interface of the address.
+    pub bind_addr: Option<IpAddr>,
+    /// only used by the named pipe dialer on windows, e.g. `\\.\pipe\name`.
+    pub local_pipe: Option<String>,
+    /// only used by the tcp dialer, the connections run the tls handshake with it.
+    pub local_tls: Option<LocalConnector>,
 }
 
 impl Dialer {
     /// Create a new dialer.
     pub(crate) fn new(dial: DialFn, addr: SocketAddr) -> Self {
-        Self { dial, addr }
+        Self {
+            dial,
+            addr,
+            options: DialOptions::default(),
+            shadow: None,
+            upstreams: HashMap::new(),
+            fallbacks: Vec::new(),
+        }
+    }
+
+    /// the buffer size for reading from the dialed endpoint,
+    /// a datagram must be read as a whole.
+    pub(crate) fn read_buf_size(&self) -> usize {
+        self.options
+            .max_datagram_size
+            .unwrap_or(constant::DEFAULT_BUF_SIZE)
+    }
+
+    pub(crate) fn options(&self) -> &DialOptions {
+        &self.options
+    }
+
+    /// Expose the options of the dialer for updating.
+    pub(crate) fn options_mut(&mut self) -> &mut DialOptions {
+        &mut self.options
     }
 
     /// Dial the endpoint.
@@ -105,13 +357,104 @@ impl Dialer {
     ///
     /// A future that resolves to a async reader and a async writer.
     pub(crate) fn dial(&self) -> Pin<Box<dyn std::future::Future<Output = DialResult> + Send>> {
-        (self.dial)(self.addr)
+        self.dial_addr(self.addr)
+    }
+
+    /// Dial the address, or each of the addresses of the endpoint in order
+    /// up to [`constant::DIAL_ATTEMPT_TIMEOUT`] if it has the fallbacks,
+    /// the first connected one is taken.
+    fn dial_addr(
+        &self,
+        addr: SocketAddr,
+    ) -> Pin<Box<dyn std::future::Future<Output = DialResult> + Send>> {
+        if addr != self.addr || self.fallbacks.is_empty() {
+            return (self.dial)(addr, self.options.clone());
+        }
+        let dial = self.dial;
+        let options = self.options.clone();
+        let addrs: Vec<SocketAddr> = std::iter::once(addr)
+            .chain(self.fallbacks.iter().copied())
+            .collect();
+        Box::pin(async move {
+            let mut last_err = None;
+            for addr in addrs {
+                match tokio::time::timeout(
+                    constant::DIAL_ATTEMPT_TIMEOUT,
+                    dial(addr, options.clone()),
+                )
+                .await
+                {
+                    Ok(Ok(conn)) => return Ok(conn),
+                    Ok(Err(err)) => {
+                        debug!(?addr, ?err, "failed to dial the address, try the next one");
+                        last_err = Some(err);
+                    }
+                    Err(_) => {
+                        debug!(?addr, "dialing the address timed out, try the next one");
+                        last_err = Some(
+                            io::Error::new(io::ErrorKind::TimedOut, "dialing timed out").into(),
+                        );
+                    }
+                }
+            }
+            Err(last_err.unwrap())
+        })
     }
 
     /// Expose the address of the dialer.
     pub(crate) fn addr(&self) -> SocketAddr {
         self.addr
     }
+
+    pub(crate) fn set_shadow(&mut self, shadow: SocketAddr) {
+        self.shadow = Some(shadow);
+    }
+
+    pub(crate) fn shadow(&self) -> Option<SocketAddr> {
+        self.shadow
+    }
+
+    /// the host is matched case-insensitively without the port.
+    pub(crate) fn set_upstream(&mut self, host: &str, upstream: SocketAddr) {
+        self.upstreams.insert(host.to_ascii_lowercase(), upstream);
+    }
+
+    pub(crate) fn upstreams(&self) -> &HashMap<String, SocketAddr> {
+        &self.upstreams
+    }
+
+    /// the addresses are tried after `addr`, in order, see [`Dialer::dial`].
+    pub(crate) fn set_fallbacks(&mut self, fallbacks: Vec<SocketAddr>) {
+        self.fallbacks = fallbacks;
+    }
+
+    /// the endpoint of the host, `addr` if the host has no upstream.
+    pub(crate) fn upstream(&self, host: &str) -> SocketAddr {
+        self.upstreams.get(host).copied().unwrap_or(self.addr)
+    }
+
+    /// Dial the upstream of the host of the http request, the host is lowercase without the port,
+    /// see [`crate::pb::WorkPayload::host`].
+    pub(crate) fn dial_host(
+        &self,
+        host: &str,
+    ) -> Pin<Box<dyn std::future::Future<Output = DialResult> + Send>> {
+        self.dial_addr(self.upstream(host))
+    }
+
+    /// Dial the shadow endpoint the same way as the endpoint, if any.
+    pub(crate) fn dial_shadow(
+        &self,
+    ) -> Option<Pin<Box<dyn std::future::Future<Output = DialResult> + Send>>> {
+        self.shadow.map(|shadow| {
+            // the shadow is always a socket address, even if the endpoint is a named pipe.
+            let options = DialOptions {
+                local_pipe: None,
+                ..self.options.clone()
+            };
+            (self.dial)(shadow, options)
+        })
+    }
 }
 
 /// Result of dialing a endpoint.
@@ -125,29 +468,83 @@ pub(crate) type DialResult = Result<
 
 /// Function for dialing a endpoint to get a async reader and a async writer.
 pub(crate) type DialFn =
-    fn(SocketAddr) -> Pin<Box<dyn std::future::Future<Output = DialResult> + Send>>;
+    fn(SocketAddr, DialOptions) -> Pin<Box<dyn std::future::Future<Output = DialResult> + Send>>;
 
 /// Dial a tcp endpoint.
-pub(crate) async fn dial_tcp(local_endpoint: SocketAddr) -> DialResult {
+pub(crate) async fn dial_tcp(local_endpoint: SocketAddr, options: DialOptions) -> DialResult {
     let local_conn = TcpStream::connect(local_endpoint).await?;
+    if let Some(keepalive) = &options.tcp_keepalive {
+        set_tcp_keepalive(&local_conn, keepalive)?;
+    }
+    if let Some(tls) = &options.local_tls {
+        let (r, w) = io::split(tls.connect(local_endpoint, local_conn).await?);
+        return Ok((Box::new(r), Box::new(w)));
+    }
     let (r, w) = local_conn.into_split();
     Ok((Box::new(r), Box::new(w)))
 }
 
+/// Dial the named pipe of the options, it dials the tcp endpoint without one.
+///
+/// The pipe is retried while all of its instances are busy, up to [`NAMED_PIPE_BUSY_TIMEOUT`].
+#[cfg(windows)]
+pub(crate) async fn dial_named_pipe(endpoint: SocketAddr, options: DialOptions) -> DialResult {
+    use tokio::net::windows::named_pipe::ClientOptions;
+
+    // the error code of windows when all the instances of the pipe are serving other clients.
+    const ERROR_PIPE_BUSY: i32 = 231;
+
+    let Some(name) = options.local_pipe.as_deref() else {
+        return dial_tcp(endpoint, options).await;
+    };
+    let deadline = tokio::time::Instant::now() + NAMED_PIPE_BUSY_TIMEOUT;
+    let client = loop {
+        match ClientOptions::new().open(name) {
+            Ok(client) => break client,
+            Err(err)
+                if err.raw_os_error() == Some(ERROR_PIPE_BUSY)
+                    && tokio::time::Instant::now() < deadline => {}
+            Err(err) => return Err(err.into()),
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    };
+    let (r, w) = io::split(client);
+    Ok((Box::new(r), Box::new(w)))
+}
+
+/// the max time to wait for a busy named pipe.
+#[cfg(windows)]
+const NAMED_PIPE_BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Dial the built-in echo endpoint, the bytes written are read back, the endpoint is ignored.
+pub(crate) async fn dial_echo(_: SocketAddr, _: DialOptions) -> DialResult {
+    use tokio::io::AsyncWriteExt as _;
+
+    let (local, echo) = io::duplex(constant::DEFAULT_BUF_SIZE);
+    tokio::spawn(async move {
+        let (mut reader, mut writer) = io::split(echo);
+        if io::copy(&mut reader, &mut writer).await.is_ok() {
+            let _ = writer.shutdown().await;
+        }
+    });
+    let (r, w) = io::split(local);
+    Ok((Box::new(r), Box::new(w)))
+}
+
 /// Dial a udp endpoint.
-pub(crate) async fn dial_udp(local_endpoint: SocketAddr) -> DialResult {
-    let local_addr: SocketAddr = if local_endpoint.is_ipv4() {
-        "0.0.0.0:0"
+pub(crate) async fn dial_udp(local_endpoint: SocketAddr, options: DialOptions) -> DialResult {
+    let max_datagram_size = options.max_datagram_size.unwrap_or(MAX_DATAGRAM_SIZE);
+    let bind_addr = options.bind_addr.unwrap_or(if local_endpoint.is_ipv4() {
+        IpAddr::V4(Ipv4Addr::UNSPECIFIED)
     } else {
-        "[::]:0"
-    }
-    .parse()?;
-    let socket = UdpSocket::bind(local_addr).await?;
+        IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+    });
+    let socket = UdpSocket::bind(SocketAddr::new(bind_addr, 0)).await?;
     socket.connect(local_endpoint).await?;
     let socket = Box::leak(Box::new(socket));
     Ok((
-        Box::new(AsyncUdpSocket::new(socket)),
-        Box::new(AsyncUdpSocket::new(socket)),
+        Box::new(AsyncUdpSocket::new(socket, max_datagram_size)),
+        Box::new(AsyncUdpSocket::new(socket, max_datagram_size)),
     ))
 }
 
@@ -162,24 +559,201 @@ mod test {
         let listener = create_tcp_listener(port).await.unwrap();
 
         let dialer = Dialer::new(
-            |addr| Box::pin(dial_tcp(addr)),
+            |addr, options| Box::pin(dial_tcp(addr, options)),
             listener.local_addr().unwrap(),
         );
         dialer.dial().await.unwrap();
     }
 
+    #[tokio::test]
+    async fn test_dialer_with_fallbacks() {
+        use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+        // nothing listens on the first address
+        let dead = SocketAddr::from(([127, 0, 0, 1], free_port().unwrap()));
+        let listener = create_tcp_listener(0).await.unwrap();
+        let live = SocketAddr::from(([127, 0, 0, 1], listener.local_addr().unwrap().port()));
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream.write_all(b"live").await.unwrap();
+        });
+
+        let mut dialer = Dialer::new(|addr, options| Box::pin(dial_tcp(addr, options)), dead);
+        assert!(dialer.dial().await.is_err());
+        dialer.set_fallbacks(vec![dead, live]);
+        let (mut reader, _) = dialer.dial().await.unwrap();
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"live");
+
+        // the upstreams of the hosts don't fall back
+        dialer.set_upstream("a.example.com", dead);
+        assert!(dialer.dial_host("a.example.com").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_echo_dialer() {
+        use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+        let dialer = Dialer::new(
+            |addr, options| Box::pin(dial_echo(addr, options)),
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+        );
+        let (mut reader, mut writer) = dialer.dial().await.unwrap();
+        writer.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 5];
+        reader.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+        // the echo closes after the writer
+        writer.shutdown().await.unwrap();
+        assert_eq!(reader.read(&mut buf).await.unwrap(), 0);
+    }
+
     #[tokio::test]
     async fn test_udp_socket_and_dialer() {
         let port = free_port().unwrap();
         let socket = create_udp_socket(port).await.unwrap();
 
         let dialer = Dialer::new(
-            |addr| Box::pin(dial_udp(addr)),
+            |addr, options| Box::pin(dial_udp(addr, options)),
             socket.local_addr().unwrap(),
         );
         dialer.dial().await.unwrap();
     }
 
+    #[cfg(windows)]
+    #[tokio::test]
+    async fn test_named_pipe_dialer() {
+        use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+        use tokio::net::windows::named_pipe::ServerOptions;
+
+        let name = format!(r"\\.\pipe\castle-test-{}", std::process::id());
+        let mut server = ServerOptions::new()
+            .first_pipe_instance(true)
+            .create(&name)
+            .unwrap();
+        let mut dialer = Dialer::new(
+            |addr, options| Box::pin(dial_named_pipe(addr, options)),
+            "127.0.0.1:1".parse().unwrap(),
+        );
+        dialer.options_mut().local_pipe = Some(name);
+
+        let (_, mut writer) = dialer.dial().await.unwrap();
+        server.connect().await.unwrap();
+        writer.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+
+    #[tokio::test]
+    async fn test_udp_dialer_drops_oversized_datagram() {
+        use tokio::io::AsyncReadExt as _;
+
+        let backend = create_udp_socket(0).await.unwrap();
+        let backend_addr = SocketAddr::from(([127, 0, 0, 1], backend.local_addr().unwrap().port()));
+        let (mut reader, mut writer) = dial_udp(
+            backend_addr,
+            DialOptions {
+                max_datagram_size: Some(1024),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+
+        // let the backend know the address of the dialer
+        tokio::io::AsyncWriteExt::write_all(&mut writer, b"ping")
+            .await
+            .unwrap();
+        let mut buf = [0; 16];
+        let (_, dialer_addr) = backend.recv_from(&mut buf).await.unwrap();
+
+        backend.send_to(&[1; 2048], dialer_addr).await.unwrap();
+        backend.send_to(&[2; 1024], dialer_addr).await.unwrap();
+
+        let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+        let n = reader.read(&mut buf).await.unwrap();
+        assert_eq!(n, 1024);
+        assert!(buf[..n].iter().all(|b| *b == 2));
+    }
+
+    // the whole 127.0.0.0/8 is on the loopback interface of linux
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_udp_dialer_with_bind_addr() {
+        use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+        let backend = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let bind_addr = IpAddr::from([127, 0, 0, 2]);
+        let (mut reader, mut writer) = dial_udp(
+            backend.local_addr().unwrap(),
+            DialOptions {
+                bind_addr: Some(bind_addr),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+
+        writer.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 16];
+        let (n, dialer_addr) = backend.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"ping");
+        assert_eq!(dialer_addr.ip(), bind_addr);
+
+        backend.send_to(b"pong", dialer_addr).await.unwrap();
+        let n = reader.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"pong");
+    }
+
+    #[test]
+    fn test_accept_backoff() {
+        #[cfg(unix)]
+        assert!(is_resource_exhausted(&io::Error::from_raw_os_error(24)));
+        assert!(is_resource_exhausted(&io::Error::from(
+            io::ErrorKind::OutOfMemory
+        )));
+        assert!(!is_resource_exhausted(&io::Error::from(
+            io::ErrorKind::ConnectionAborted
+        )));
+        assert!(is_connection_error(&io::Error::from(
+            io::ErrorKind::ConnectionAborted
+        )));
+        assert!(!is_connection_error(&io::Error::from(
+            io::ErrorKind::InvalidInput
+        )));
+
+        let mut backoff = AcceptBackoff::new();
+        assert_eq!(backoff.back_off(), constant::ACCEPT_ERROR_BACKOFF);
+        assert_eq!(backoff.back_off(), constant::ACCEPT_ERROR_BACKOFF * 2);
+        for _ in 0..16 {
+            backoff.back_off();
+        }
+        assert_eq!(backoff.back_off(), constant::ACCEPT_ERROR_MAX_BACKOFF);
+
+        // the warnings are rate limited
+        let now = Instant::now();
+        assert!(backoff.should_warn(now));
+        assert!(!backoff.should_warn(now + Duration::from_secs(1)));
+        assert!(backoff.should_warn(now + constant::ACCEPT_ERROR_WARN_INTERVAL));
+    }
+
+    #[tokio::test]
+    async fn test_set_tcp_keepalive() {
+        let listener = create_tcp_listener(0).await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let keepalive = TcpKeepalive {
+            idle: Duration::from_secs(30),
+            interval: Some(Duration::from_secs(5)),
+            retries: Some(3),
+        };
+        set_tcp_keepalive(&stream, &keepalive).unwrap();
+        assert!(socket2::SockRef::from(&stream).keepalive().unwrap());
+    }
+
     /// free_port returns a free port number for testing.
     fn free_port() -> std::io::Result<u16> {
         let listener = StdTcpListener::bind("127.0.0.1:0")?;
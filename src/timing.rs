@@ -0,0 +1,135 @@
+//! The timing of the connection setups, it's logged at debug with the target [`TARGET`],
+//! e.g. `RUST_LOG=info,castled::setup=debug` to tell where the slow connections spend their time.
+//!
+//! The server logs the phases of a user connection:
+//! - `admission`: the wait for the limits of the server, http only.
+//! - `bridge`: the control round-trip asking the client for the connection,
+//!   it includes the data stream of the client and the dial of the local endpoint.
+//! - `first_byte`: from the bridge to the first byte from the client, i.e. the data path.
+//!
+//! The client logs the `data_stream` opened for the connection, the `dial` of the local endpoint
+//! and the `first_byte` read from it.
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, ReadBuf},
+    time::Instant,
+};
+
+/// the target of the timing logs, they are filtered by it.
+pub(crate) const TARGET: &str = "castled::setup";
+
+/// SetupTimer measures the phases of a connection setup one after another.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct SetupTimer {
+    start: Instant,
+    last: Instant,
+}
+
+impl SetupTimer {
+    pub(crate) fn start() -> Self {
+        let now = Instant::now();
+        Self {
+            start: now,
+            last: now,
+        }
+    }
+
+    /// the time of the phase ended now, the next phase starts.
+    pub(crate) fn lap(&mut self) -> Duration {
+        let now = Instant::now();
+        let elapsed = now - self.last;
+        self.last = now;
+        elapsed
+    }
+
+    /// the time since the start.
+    pub(crate) fn total(&self) -> Duration {
+        self.last - self.start
+    }
+}
+
+/// FirstRead calls the callback once the first byte is read from the reader,
+/// it's not called if the reader ends without any.
+pub(crate) struct FirstRead<R, F> {
+    inner: R,
+    on_first: Option<F>,
+}
+
+impl<R, F> FirstRead<R, F> {
+    pub(crate) fn new(inner: R, on_first: F) -> Self {
+        Self {
+            inner,
+            on_first: Some(on_first),
+        }
+    }
+}
+
+impl<R, F> AsyncRead for FirstRead<R, F>
+where
+    R: AsyncRead + Unpin,
+    F: FnOnce() + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if buf.filled().len() > filled {
+            if let Some(on_first) = this.on_first.take() {
+                on_first();
+            }
+        }
+        poll
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use tokio::io::AsyncReadExt as _;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_setup_timer() {
+        let mut timer = SetupTimer::start();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let first = timer.lap();
+        assert!(first >= Duration::from_millis(10));
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        let second = timer.lap();
+        assert!(second >= Duration::from_millis(5));
+        assert_eq!(timer.total(), first + second);
+    }
+
+    #[tokio::test]
+    async fn test_first_read() {
+        let called = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&called);
+        let mut reader = FirstRead::new(&b"hello"[..], move || {
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+        let mut buf = [0; 2];
+        assert_eq!(reader.read(&mut buf).await.unwrap(), 2);
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(rest, b"llo");
+        assert_eq!(called.load(Ordering::Relaxed), 1);
+
+        // nothing is read
+        let mut reader = FirstRead::new(&b""[..], || panic!("nothing is read"));
+        assert_eq!(reader.read(&mut buf).await.unwrap(), 0);
+    }
+}
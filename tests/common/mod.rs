@@ -10,3 +10,5 @@ pub fn free_port() -> std::io::Result<u16> {
 pub fn is_port_listening(port: u16) -> bool {
     TcpStream::connect(("127.0.0.1", port)).is_ok()
 }
+
+pub mod websocket;
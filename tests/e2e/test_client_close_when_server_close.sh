@@ -6,7 +6,7 @@ cur_dir=$root_dir/tests/e2e
 source $cur_dir/util.sh
 
 # Start the tunnel server
-exec $root_dir/target/debug/castled &
+exec $root_dir/target/debug/castled --ip 127.0.0.1 &
 server_pid=$!
 sleep 1
 
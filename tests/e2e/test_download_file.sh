@@ -17,7 +17,7 @@ cleanup() {
 
 trap cleanup EXIT
 
-exec $root_dir/target/debug/castled &
+exec $root_dir/target/debug/castled --ip 127.0.0.1 &
 server_pid=$!
 wait_port 6610
 
@@ -19,7 +19,7 @@ cleanup() {
 trap cleanup EXIT
 
 # Start the tunnel server
-exec $root_dir/target/debug/castled &
+exec $root_dir/target/debug/castled --ip 127.0.0.1 &
 server_pid=$!
 wait_port 6610
 
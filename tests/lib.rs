@@ -1,23 +1,27 @@
+//! Integration tests for the castle client and server.
 #![warn(missing_docs)]
 
 mod common;
 
 use crate::common::free_port;
 use crate::common::is_port_listening;
+use crate::common::websocket;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
 
 use async_shutdown::ShutdownManager;
-use castled::client::tunnel::HttpRemoteConfig;
 use castled::client::tunnel::RemoteConfig;
+use castled::client::tunnel::{HeaderRules, HttpRemoteConfig};
 use castled::{
-    client::{tunnel::Tunnel, Client},
-    server::{Config, EntrypointConfig, Server},
+    client::{config::TunnelsFile, tunnel::Tunnel, Client, ClientTls, LocalTls, TunnelState},
+    server::{Config, ControlTls, EntrypointConfig, Server, SubdomainWords},
 };
 use http::HeaderValue;
+use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
 use tokio::sync::oneshot;
 use tokio::time::sleep;
 use wiremock::{
-    matchers::{method, path},
+    matchers::{header, method, path},
     Mock, MockServer, ResponseTemplate,
 };
 
@@ -38,7 +42,7 @@ async fn client_register_tcp() {
     .await;
     let close_client = server.cancel.clone();
     let remote_port = free_port().unwrap();
-    let control_addr = server.control_addr().clone();
+    let control_addr = server.control_addr();
 
     let client_handler = tokio::spawn(async move {
         let client = Client::new(control_addr).await.unwrap();
@@ -103,7 +107,7 @@ async fn client_register_and_close_then_register_again() {
 
     tokio::spawn(async move {
         sleep(tokio::time::Duration::from_millis(300)).await;
-        shutdown.trigger_shutdown(0);
+        let _ = shutdown.trigger_shutdown(0);
     });
 
     let client_exit = tokio::join!(client_handler);
@@ -112,7 +116,7 @@ async fn client_register_and_close_then_register_again() {
     // register again with the same port
     let shutdown = ShutdownManager::new();
     let close_client = shutdown.clone();
-    let control_addr = server.control_addr().clone();
+    let control_addr = server.control_addr();
     let client_handler = tokio::spawn(async move {
         let client = Client::new(control_addr).await.unwrap();
         let _ = client
@@ -129,7 +133,7 @@ async fn client_register_and_close_then_register_again() {
 
     tokio::spawn(async move {
         sleep(tokio::time::Duration::from_millis(300)).await;
-        shutdown.trigger_shutdown(0);
+        let _ = shutdown.trigger_shutdown(0);
     });
 
     let client_exit = tokio::join!(client_handler);
@@ -153,9 +157,13 @@ async fn register_http_tunnel_with_subdomain() {
         .await;
 
     init();
-    let server = start_server(Default::default()).await;
+    let server = start_server(EntrypointConfig {
+        domain: vec!["example.com".to_string()],
+        ..Default::default()
+    })
+    .await;
     let close_client = server.cancel.clone();
-    let control_addr = server.control_addr().clone();
+    let control_addr = server.control_addr();
 
     let (wait_client_register, wait_client_register_rx) = oneshot::channel();
     let client_handler = tokio::spawn(async move {
@@ -199,263 +207,4088 @@ async fn register_http_tunnel_with_subdomain() {
 }
 
 #[tokio::test]
-async fn test_assigned_entrypoint() {
-    struct TestTunnel {
-        tunnel: Tunnel<'static>,
-        expected: Vec<&'static str>,
-    }
-
-    struct Case {
-        entrypoint: EntrypointConfig,
-        tunnels: Vec<TestTunnel>,
-    }
-
-    let cases = vec![
-        Case {
-            entrypoint: Default::default(),
-            tunnels: vec![
-                TestTunnel {
-                    tunnel: Tunnel::new(
-                        "test",
-                        SocketAddr::from(([127, 0, 0, 1], 8971)),
-                        RemoteConfig::Tcp(8080),
-                    ),
-                    expected: vec![],
-                },
-                TestTunnel {
-                    tunnel: Tunnel::new(
-                        "test",
-                        SocketAddr::from(([127, 0, 0, 1], 8971)),
-                        RemoteConfig::Udp(8080),
-                    ),
-                    expected: vec![], // TODO(Sword): assert random port
-                },
-                TestTunnel {
-                    tunnel: Tunnel::new(
-                        "test",
-                        SocketAddr::from(([127, 0, 0, 1], 8080)),
-                        RemoteConfig::Http(HttpRemoteConfig::RandomPort),
-                    ),
-                    expected: vec![],
-                },
-            ],
-        },
-        Case {
-            entrypoint: EntrypointConfig {
-                domain: vec!["example.com".to_string()],
-                ..Default::default()
-            },
-            tunnels: vec![
-                TestTunnel {
-                    tunnel: Tunnel::new(
-                        "test",
-                        SocketAddr::from(([127, 0, 0, 1], 8971)),
-                        RemoteConfig::Tcp(8080),
-                    ),
-                    expected: vec!["tcp://example.com:8080"],
-                },
-                TestTunnel {
-                    tunnel: Tunnel::new(
-                        "test",
-                        SocketAddr::from(([127, 0, 0, 1], 8971)),
-                        RemoteConfig::Udp(8080),
-                    ),
-                    expected: vec!["udp://example.com:8080"],
-                },
-                TestTunnel {
-                    tunnel: Tunnel::new(
-                        "test",
-                        SocketAddr::from(([127, 0, 0, 1], 8080)),
-                        RemoteConfig::Http(HttpRemoteConfig::Port(9999)),
-                    ),
-                    expected: vec!["http://example.com:9999"],
-                },
-                TestTunnel {
-                    tunnel: Tunnel::new(
-                        "test",
-                        SocketAddr::from(([127, 0, 0, 1], 8080)),
-                        RemoteConfig::Http(HttpRemoteConfig::Domain("mydomain.com")),
-                    ),
-                    expected: vec!["http://mydomain.com"],
-                },
-                TestTunnel {
-                    tunnel: Tunnel::new(
-                        "test",
-                        SocketAddr::from(([127, 0, 0, 1], 8080)),
-                        RemoteConfig::Http(HttpRemoteConfig::Subdomain("foo")),
-                    ),
-                    expected: vec!["http://foo.example.com"],
-                },
-            ],
-        },
-        Case {
-            entrypoint: EntrypointConfig {
-                ip: vec![IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))],
-                ..Default::default()
-            },
-            tunnels: vec![
-                TestTunnel {
-                    tunnel: Tunnel::new(
-                        "test",
-                        SocketAddr::from(([127, 0, 0, 1], 8971)),
-                        RemoteConfig::Tcp(8080),
-                    ),
-                    expected: vec!["tcp://127.0.0.1:8080"],
-                },
-                TestTunnel {
-                    tunnel: Tunnel::new(
-                        "test",
-                        SocketAddr::from(([127, 0, 0, 1], 8971)),
-                        RemoteConfig::Udp(8080),
-                    ),
-                    expected: vec!["udp://127.0.0.1:8080"],
-                },
-                TestTunnel {
-                    tunnel: Tunnel::new(
-                        "test",
-                        SocketAddr::from(([127, 0, 0, 1], 8080)),
-                        RemoteConfig::Http(HttpRemoteConfig::Port(9999)),
-                    ),
-                    expected: vec!["http://127.0.0.1:9999"],
-                },
-                TestTunnel {
-                    tunnel: Tunnel::new(
-                        "test",
-                        SocketAddr::from(([127, 0, 0, 1], 8080)),
-                        RemoteConfig::Http(HttpRemoteConfig::Domain("mydomain.com")),
-                    ),
-                    expected: vec!["http://mydomain.com"],
-                },
-                TestTunnel {
-                    tunnel: Tunnel::new(
-                        "test",
-                        SocketAddr::from(([127, 0, 0, 1], 8080)),
-                        RemoteConfig::Http(HttpRemoteConfig::Subdomain("foo")),
-                    ),
-                    expected: vec![/* no assigned entrypoint, because server doesn't has a domain */],
-                },
-            ],
-        },
-        Case {
-            entrypoint: EntrypointConfig {
-                domain: vec!["example.com".to_string(), "foo.example.com".to_string()],
-                ip: vec![IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))],
-                vhttp_behind_proxy_tls: true,
-                ..Default::default()
-            },
-            tunnels: vec![
-                TestTunnel {
-                    tunnel: Tunnel::new(
-                        "test",
-                        SocketAddr::from(([127, 0, 0, 1], 8971)),
-                        RemoteConfig::Tcp(8080),
-                    ),
-                    expected: vec![
-                        "tcp://127.0.0.1:8080",
-                        "tcp://example.com:8080",
-                        "tcp://foo.example.com:8080",
-                    ],
-                },
-                TestTunnel {
-                    tunnel: Tunnel::new(
-                        "test",
-                        SocketAddr::from(([127, 0, 0, 1], 8971)),
-                        RemoteConfig::Udp(8080),
-                    ),
-                    expected: vec![
-                        "udp://127.0.0.1:8080",
-                        "udp://example.com:8080",
-                        "udp://foo.example.com:8080",
-                    ],
-                },
-                TestTunnel {
-                    tunnel: Tunnel::new(
-                        "test",
-                        SocketAddr::from(([127, 0, 0, 1], 8080)),
-                        RemoteConfig::Http(HttpRemoteConfig::Port(9999)),
-                    ),
-                    expected: vec![
-                        "http://127.0.0.1:9999",
-                        "http://example.com:9999",
-                        "http://foo.example.com:9999",
-                    ],
-                },
-                TestTunnel {
-                    tunnel: Tunnel::new(
-                        "test",
-                        SocketAddr::from(([127, 0, 0, 1], 8080)),
-                        RemoteConfig::Http(HttpRemoteConfig::Domain("mydomain.com")),
-                    ),
-                    expected: vec!["https://mydomain.com"],
-                },
-                TestTunnel {
-                    tunnel: Tunnel::new(
-                        "test",
-                        SocketAddr::from(([127, 0, 0, 1], 8080)),
-                        RemoteConfig::Http(HttpRemoteConfig::Subdomain("bar")),
-                    ),
-                    expected: vec!["https://bar.example.com", "https://bar.foo.example.com"],
-                },
-            ],
-        },
-    ];
-
-    for case in cases {
-        let server = start_server(case.entrypoint).await;
-
-        for tunnel in case.tunnels {
-            let shutdown = ShutdownManager::new();
-            let close_client = shutdown.clone();
-            let control_addr = server.control_addr();
-
-            let client_handler = tokio::spawn(async move {
-                let client = Client::new(control_addr).await.unwrap();
-                let entrypoint = client.start_tunnel(tunnel.tunnel, close_client).await;
-                assert!(entrypoint.is_ok());
-                let entrypoint = entrypoint.unwrap();
-                assert_eq!(entrypoint, tunnel.expected);
-            });
-
+async fn http_tunnel_request_timeout() {
+    // a local server accepting the requests but never responding
+    let local_server = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let local_port = local_server.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = local_server.accept().await {
             tokio::spawn(async move {
-                sleep(tokio::time::Duration::from_millis(100)).await;
-                shutdown.trigger_shutdown(0).unwrap();
+                sleep(Duration::from_secs(5)).await;
+                drop(stream);
             });
-
-            let _ = tokio::join!(client_handler);
         }
-    }
-}
+    });
 
-#[tokio::test]
-async fn test_client_auto_close_when_server_crash() {
     init();
-    let server = start_server(Default::default()).await;
-    let control_addr = server.control_addr().clone();
+    let server = start_server(EntrypointConfig {
+        domain: vec!["example.com".to_string()],
+        ..Default::default()
+    })
+    .await;
+    let close_client = server.cancel.clone();
+    let control_addr = server.control_addr();
 
+    let (wait_client_register, wait_client_register_rx) = oneshot::channel();
     let client_handler = tokio::spawn(async move {
-        sleep(tokio::time::Duration::from_millis(50)).await; // wait for server to start
-        let shutdown = ShutdownManager::new();
-
         let client = Client::new(control_addr).await.unwrap();
         let _ = client
             .start_tunnel(
                 Tunnel::new(
                     "test",
-                    SocketAddr::from(([127, 0, 0, 1], 8971)),
-                    RemoteConfig::Tcp(free_port().unwrap()),
-                ),
-                shutdown.clone(),
+                    SocketAddr::from(([127, 0, 0, 1], local_port)),
+                    RemoteConfig::Http(HttpRemoteConfig::Subdomain("slow")),
+                )
+                .with_http_timeout(Duration::from_millis(200)),
+                close_client,
             )
             .await;
-
-        shutdown.wait_shutdown_complete().await;
+        wait_client_register.send(()).unwrap();
     });
 
-    sleep(tokio::time::Duration::from_millis(200)).await;
-    server.cancel.trigger_shutdown(0).unwrap();
+    wait_client_register_rx.await.unwrap();
+
+    let http_client = reqwest::Client::new();
+    let request = http_client
+        .request(
+            http::method::Method::GET,
+            format!("http://localhost:{}/slow", server.vhttp_port),
+        )
+        .header("Host", "slow.example.com")
+        .build()
+        .unwrap();
+    let response = http_client.execute(request).await.unwrap();
+    assert_eq!(response.status(), 504);
+
+    // a local server responding the headers but never finishing the body
+    let stalled_server = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let stalled_addr = stalled_server.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = stalled_server.accept().await {
+            tokio::spawn(async move {
+                let mut buf = [0u8; 1024];
+                let _ = stream.read(&mut buf).await;
+                let _ = stream
+                    .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 10\r\n\r\nhello")
+                    .await;
+                sleep(Duration::from_secs(5)).await;
+                drop(stream);
+            });
+        }
+    });
+    let client = Client::new(control_addr).await.unwrap();
+    client
+        .start_tunnel(
+            Tunnel::new(
+                "stalled",
+                stalled_addr,
+                RemoteConfig::Http(HttpRemoteConfig::Subdomain("stalled")),
+            )
+            .with_http_timeout(Duration::from_millis(200)),
+            ShutdownManager::new(),
+        )
+        .await
+        .unwrap();
+
+    let start = std::time::Instant::now();
+    let response = http_client
+        .get(format!("http://localhost:{}/stalled", server.vhttp_port))
+        .header("Host", "stalled.example.com")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    // the unfinished body is aborted at the timeout
+    assert!(response.bytes().await.is_err());
+    assert!(start.elapsed() < Duration::from_secs(3));
+
+    server.cancel.trigger_shutdown(0).unwrap();
+
+    let client_exit = tokio::join!(client_handler);
+    assert!(client_exit.0.is_ok());
+}
+
+#[tokio::test]
+async fn http_tunnel_with_request_deadline() {
+    // a local server accepting the requests but never responding
+    let local_server = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let local_addr = local_server.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = local_server.accept().await {
+            tokio::spawn(async move {
+                sleep(Duration::from_secs(5)).await;
+                drop(stream);
+            });
+        }
+    });
+
+    init();
+    let server = start_server_with_config(Config {
+        entrypoint: EntrypointConfig {
+            domain: vec!["example.com".to_string()],
+            ..Default::default()
+        },
+        max_request_deadline: Some(Duration::from_secs(10)),
+        ..Default::default()
+    })
+    .await;
+    let client = Client::new(server.control_addr()).await.unwrap();
+    client
+        .start_tunnel(
+            Tunnel::new(
+                "test",
+                local_addr,
+                RemoteConfig::Http(HttpRemoteConfig::Subdomain("deadline")),
+            ),
+            ShutdownManager::new(),
+        )
+        .await
+        .unwrap();
+
+    let start = std::time::Instant::now();
+    let response = reqwest::Client::new()
+        .get(format!("http://localhost:{}/slow", server.vhttp_port))
+        .header("Host", "deadline.example.com")
+        .header("X-Tunnel-Deadline", "300m")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 504);
+    assert!(start.elapsed() < Duration::from_secs(3));
+
+    server.cancel.trigger_shutdown(0).unwrap();
+}
+
+#[tokio::test]
+async fn http_tunnel_with_body_limits() {
+    let mock_local_server = MockServer::start().await;
+    let local_port = mock_local_server.address().port();
+    Mock::given(method("POST"))
+        .and(path("/upload"))
+        .respond_with(ResponseTemplate::new(200))
+        // only the body within the limit reaches the local server
+        .expect(1)
+        .mount(&mock_local_server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/small"))
+        .respond_with(ResponseTemplate::new(200).set_body_string("12345678"))
+        .mount(&mock_local_server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/large"))
+        .respond_with(ResponseTemplate::new(200).set_body_string("123456789"))
+        .mount(&mock_local_server)
+        .await;
+
+    init();
+    let server = start_server_with_config(Config {
+        entrypoint: EntrypointConfig {
+            domain: vec!["example.com".to_string()],
+            ..Default::default()
+        },
+        // the smaller limit of the tunnel applies
+        max_request_body: Some(1024),
+        max_response_body: Some(8),
+        ..Default::default()
+    })
+    .await;
+    let client = Client::new(server.control_addr()).await.unwrap();
+    client
+        .start_tunnel(
+            Tunnel::new(
+                "test",
+                SocketAddr::from(([127, 0, 0, 1], local_port)),
+                RemoteConfig::Http(HttpRemoteConfig::Subdomain("limits")),
+            )
+            .with_max_request_body(16),
+            ShutdownManager::new(),
+        )
+        .await
+        .unwrap();
+
+    let http_client = reqwest::Client::new();
+    let upload = |body: &'static [u8]| {
+        http_client
+            .post(format!("http://localhost:{}/upload", server.vhttp_port))
+            .header("Host", "limits.example.com")
+            .body(body)
+            .send()
+    };
+    assert_eq!(upload(&[b'a'; 16]).await.unwrap().status(), 200);
+    assert_eq!(upload(&[b'a'; 17]).await.unwrap().status(), 413);
+
+    // the chunked body has no Content-Length, it's counted as it flows
+    let mut stream = tokio::net::TcpStream::connect(("127.0.0.1", server.vhttp_port))
+        .await
+        .unwrap();
+    stream
+        .write_all(
+            b"POST /upload HTTP/1.1\r\nHost: limits.example.com\r\nTransfer-Encoding: chunked\r\n\r\n\
+            8\r\naaaaaaaa\r\n9\r\naaaaaaaaa\r\n",
+        )
+        .await
+        .unwrap();
+    let mut response = vec![0; 1024];
+    let n = tokio::time::timeout(Duration::from_secs(3), stream.read(&mut response))
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(
+        response[..n].starts_with(b"HTTP/1.1 413"),
+        "{}",
+        String::from_utf8_lossy(&response[..n])
+    );
+
+    let get = |path: &str| {
+        http_client
+            .get(format!("http://localhost:{}{}", server.vhttp_port, path))
+            .header("Host", "limits.example.com")
+            .send()
+    };
+    let response = get("/small").await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await.unwrap(), "12345678");
+    assert_eq!(get("/large").await.unwrap().status(), 502);
+
+    server.cancel.trigger_shutdown(0).unwrap();
+}
+
+#[tokio::test]
+async fn http_tunnel_with_cache() {
+    let mock_local_server = MockServer::start().await;
+    let local_port = mock_local_server.address().port();
+    Mock::given(method("GET"))
+        .and(path("/app.js"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_string("console.log(1)")
+                .insert_header("Cache-Control", "public, max-age=60"),
+        )
+        // the repeated requests are served by the cache
+        .expect(1)
+        .mount(&mock_local_server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/private"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_string("mine")
+                .insert_header("Cache-Control", "private, max-age=60"),
+        )
+        .expect(2)
+        .mount(&mock_local_server)
+        .await;
+
+    init();
+    let server = start_server_with_config(Config {
+        entrypoint: EntrypointConfig {
+            domain: vec!["example.com".to_string()],
+            ..Default::default()
+        },
+        http_cache: Some(1024 * 1024),
+        ..Default::default()
+    })
+    .await;
+    let close_client = server.cancel.clone();
+    let control_addr = server.control_addr();
+
+    let (wait_client_register, wait_client_register_rx) = oneshot::channel();
+    let client_handler = tokio::spawn(async move {
+        let client = Client::new(control_addr).await.unwrap();
+        let _ = client
+            .start_tunnel(
+                Tunnel::new(
+                    "test",
+                    SocketAddr::from(([127, 0, 0, 1], local_port)),
+                    RemoteConfig::Http(HttpRemoteConfig::Subdomain("cached")),
+                ),
+                close_client,
+            )
+            .await;
+        wait_client_register.send(()).unwrap();
+    });
+
+    wait_client_register_rx.await.unwrap();
+
+    let http_client = reqwest::Client::new();
+    for (path, body) in [
+        ("/app.js", "console.log(1)"),
+        ("/app.js", "console.log(1)"),
+        ("/private", "mine"),
+        ("/private", "mine"),
+    ] {
+        let response = http_client
+            .get(format!("http://localhost:{}{}", server.vhttp_port, path))
+            .header("Host", "cached.example.com")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.text().await.unwrap(), body);
+    }
+
+    server.cancel.trigger_shutdown(0).unwrap();
+
+    let client_exit = tokio::join!(client_handler);
+    assert!(client_exit.0.is_ok());
+    mock_local_server.verify().await;
+}
+
+#[tokio::test]
+async fn http_tunnel_with_coalesce() {
+    let mock_local_server = MockServer::start().await;
+    let local_port = mock_local_server.address().port();
+    Mock::given(method("GET"))
+        .and(path("/cold"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_string("warmed up")
+                .insert_header("Cache-Control", "max-age=60")
+                .set_delay(Duration::from_millis(300)),
+        )
+        // the concurrent requests share the response of the first one
+        .expect(1)
+        .mount(&mock_local_server)
+        .await;
+
+    init();
+    let server = start_server_with_config(Config {
+        entrypoint: EntrypointConfig {
+            domain: vec!["example.com".to_string()],
+            ..Default::default()
+        },
+        http_cache: Some(1024 * 1024),
+        ..Default::default()
+    })
+    .await;
+    let close_client = server.cancel.clone();
+    let control_addr = server.control_addr();
+
+    let (wait_client_register, wait_client_register_rx) = oneshot::channel();
+    let client_handler = tokio::spawn(async move {
+        let client = Client::new(control_addr).await.unwrap();
+        let _ = client
+            .start_tunnel(
+                Tunnel::new(
+                    "test",
+                    SocketAddr::from(([127, 0, 0, 1], local_port)),
+                    RemoteConfig::Http(HttpRemoteConfig::Subdomain("coalesced")),
+                )
+                .with_coalesce(),
+                close_client,
+            )
+            .await;
+        wait_client_register.send(()).unwrap();
+    });
+
+    wait_client_register_rx.await.unwrap();
+
+    let http_client = reqwest::Client::new();
+    let responses = futures::future::join_all((0..5).map(|_| {
+        http_client
+            .get(format!("http://localhost:{}/cold", server.vhttp_port))
+            .header("Host", "coalesced.example.com")
+            .send()
+    }))
+    .await;
+    for response in responses {
+        let response = response.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.text().await.unwrap(), "warmed up");
+    }
+
+    server.cancel.trigger_shutdown(0).unwrap();
+
+    let client_exit = tokio::join!(client_handler);
+    assert!(client_exit.0.is_ok());
+    mock_local_server.verify().await;
+}
+
+#[tokio::test]
+async fn http_tunnel_with_upstreams() {
+    let default_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .respond_with(ResponseTemplate::new(200).set_body_string("default"))
+        .mount(&default_server)
+        .await;
+    let admin_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .respond_with(ResponseTemplate::new(200).set_body_string("admin"))
+        .mount(&admin_server)
+        .await;
+
+    init();
+    let server = start_server(EntrypointConfig {
+        ip: vec![IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))],
+        ..Default::default()
+    })
+    .await;
+    let client = Client::new(server.control_addr()).await.unwrap();
+    // a tunnel on its own port serves all the hosts
+    let entrypoint = client
+        .start_tunnel(
+            Tunnel::new(
+                "test",
+                SocketAddr::from(([127, 0, 0, 1], default_server.address().port())),
+                RemoteConfig::Http(HttpRemoteConfig::RandomPort),
+            )
+            .with_upstream("admin.example.com", *admin_server.address()),
+            ShutdownManager::new(),
+        )
+        .await
+        .unwrap();
+
+    let http_client = reqwest::Client::new();
+    for (host, expected) in [
+        ("admin.example.com", "admin"),
+        ("Admin.Example.com:8080", "admin"),
+        ("www.example.com", "default"),
+    ] {
+        let body = http_client
+            .get(&entrypoint[0])
+            .header("Host", host)
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        assert_eq!(body, expected, "{}", host);
+    }
+
+    server.cancel.trigger_shutdown(0).unwrap();
+}
+
+#[tokio::test]
+async fn http_tunnel_with_header_rules() {
+    let mock_local_server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/hook"))
+        .and(header("x-forwarded-host", "hook.example.com"))
+        .and(header("host", "localhost:3000"))
+        .and(|req: &wiremock::Request| {
+            // the host isn't duplicated, the removed header doesn't reach the local server
+            req.headers.get_all(http::header::HOST).iter().count() == 1
+                && !req.headers.contains_key("x-debug")
+                && req.body == b"chunked body"
+        })
+        .respond_with(
+            ResponseTemplate::new(200)
+                .insert_header("server", "mock/1.0")
+                .set_body_string("ok"),
+        )
+        .expect(2)
+        .mount(&mock_local_server)
+        .await;
+
+    init();
+    let server = start_server(EntrypointConfig {
+        domain: vec!["example.com".to_string()],
+        ..Default::default()
+    })
+    .await;
+    let client = Client::new(server.control_addr()).await.unwrap();
+    client
+        .clone()
+        .start_tunnel(
+            Tunnel::new(
+                "test",
+                *mock_local_server.address(),
+                RemoteConfig::Http(HttpRemoteConfig::Subdomain("hook")),
+            )
+            .with_request_headers(HeaderRules {
+                add: [
+                    ("X-Forwarded-Host", "hook.example.com"),
+                    ("HOST", "localhost:3000"),
+                ]
+                .into_iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+                remove: vec!["X-DEBUG".to_string()],
+                ..Default::default()
+            })
+            .with_response_headers(HeaderRules {
+                remove: vec!["Server".to_string()],
+                set: [("x-tunnel".to_string(), "castle".to_string())].into(),
+                ..Default::default()
+            }),
+            ShutdownManager::new(),
+        )
+        .await
+        .unwrap();
+
+    let http_client = reqwest::Client::new();
+    let response = http_client
+        .post(format!("http://localhost:{}/hook", server.vhttp_port))
+        .header("Host", "hook.example.com")
+        .header("X-Debug", "1")
+        .body("chunked body")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    assert!(response.headers().get("server").is_none());
+    assert_eq!(response.headers()["x-tunnel"], "castle");
+    assert_eq!(response.text().await.unwrap(), "ok");
+
+    // the chunked body is forwarded with its Transfer-Encoding
+    let mut stream = tokio::net::TcpStream::connect(("127.0.0.1", server.vhttp_port))
+        .await
+        .unwrap();
+    stream
+        .write_all(
+            b"POST /hook HTTP/1.1\r\nHost: hook.example.com\r\nTransfer-Encoding: chunked\r\n\r\n\
+            7\r\nchunked\r\n5\r\n body\r\n0\r\n\r\n",
+        )
+        .await
+        .unwrap();
+    let mut response = vec![0; 1024];
+    let n = tokio::time::timeout(Duration::from_secs(3), stream.read(&mut response))
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(
+        response[..n].starts_with(b"HTTP/1.1 200"),
+        "{}",
+        String::from_utf8_lossy(&response[..n])
+    );
+
+    // the framing headers can't be rewritten
+    let result = client
+        .start_tunnel(
+            Tunnel::new(
+                "invalid",
+                *mock_local_server.address(),
+                RemoteConfig::Http(HttpRemoteConfig::Subdomain("invalid")),
+            )
+            .with_request_headers(HeaderRules {
+                remove: vec!["Transfer-Encoding".to_string()],
+                ..Default::default()
+            }),
+            ShutdownManager::new(),
+        )
+        .await;
+    assert!(result.is_err());
+
+    server.cancel.trigger_shutdown(0).unwrap();
+}
+
+#[tokio::test]
+async fn http_tunnel_with_websocket() {
+    init();
+    let local = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let local_addr = local.local_addr().unwrap();
+    tokio::spawn(websocket::serve_echo(local));
+    let server = start_server(EntrypointConfig {
+        domain: vec!["example.com".to_string()],
+        ..Default::default()
+    })
+    .await;
+    let client = Client::new(server.control_addr()).await.unwrap();
+    client
+        .start_tunnel(
+            Tunnel::new(
+                "ws",
+                local_addr,
+                RemoteConfig::Http(HttpRemoteConfig::Subdomain("ws")),
+            ),
+            ShutdownManager::new(),
+        )
+        .await
+        .unwrap();
+
+    let mut stream = tokio::net::TcpStream::connect(("127.0.0.1", server.vhttp_port))
+        .await
+        .unwrap();
+    stream
+        .write_all(
+            format!(
+                "GET /hot-reload HTTP/1.1\r\nHost: ws.example.com\r\nUpgrade: websocket\r\n\
+                Connection: Upgrade\r\nSec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n\r\n",
+                websocket::SAMPLE_KEY
+            )
+            .as_bytes(),
+        )
+        .await
+        .unwrap();
+    let head = tokio::time::timeout(Duration::from_secs(3), websocket::read_head(&mut stream))
+        .await
+        .unwrap()
+        .unwrap()
+        .to_ascii_lowercase();
+    assert!(head.starts_with("http/1.1 101"), "{}", head);
+    assert!(head.contains("connection: upgrade"), "{}", head);
+    assert!(head.contains("upgrade: websocket"), "{}", head);
+    assert!(
+        head.contains(&format!(
+            "sec-websocket-accept: {}",
+            websocket::SAMPLE_ACCEPT.to_ascii_lowercase()
+        )),
+        "{}",
+        head
+    );
+
+    websocket::write_frame(&mut stream, websocket::TEXT, b"hello", true)
+        .await
+        .unwrap();
+    let frame = websocket::read_frame(&mut stream).await.unwrap();
+    assert_eq!(frame, (websocket::TEXT, b"hello".to_vec()));
+    websocket::write_frame(&mut stream, websocket::PING, b"are you there", true)
+        .await
+        .unwrap();
+    let frame = websocket::read_frame(&mut stream).await.unwrap();
+    assert_eq!(frame, (websocket::PONG, b"are you there".to_vec()));
+    // larger than the buffers and the data messages of the bridge
+    let large = (0..1024 * 1024)
+        .map(|i| (i % 251) as u8)
+        .collect::<Vec<_>>();
+    websocket::write_frame(&mut stream, websocket::BINARY, &large, true)
+        .await
+        .unwrap();
+    let frame = tokio::time::timeout(Duration::from_secs(5), websocket::read_frame(&mut stream))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(frame, (websocket::BINARY, large));
+    websocket::write_frame(&mut stream, websocket::CLOSE, &1000u16.to_be_bytes(), true)
+        .await
+        .unwrap();
+    let frame = websocket::read_frame(&mut stream).await.unwrap();
+    assert_eq!(frame, (websocket::CLOSE, 1000u16.to_be_bytes().to_vec()));
+    // the local server closed the connection
+    let mut rest = Vec::new();
+    tokio::time::timeout(Duration::from_secs(3), stream.read_to_end(&mut rest))
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(rest.is_empty());
+
+    server.cancel.trigger_shutdown(0).unwrap();
+}
+
+#[tokio::test]
+async fn http_tunnel_with_allowed_methods() {
+    let mock_local_server = MockServer::start().await;
+    let local_port = mock_local_server.address().port();
+    Mock::given(method("POST"))
+        .and(path("/webhook"))
+        .respond_with(ResponseTemplate::new(204))
+        .expect(1)
+        .mount(&mock_local_server)
+        .await;
+
+    init();
+    let server = start_server(EntrypointConfig {
+        domain: vec!["example.com".to_string()],
+        ..Default::default()
+    })
+    .await;
+    let close_client = server.cancel.clone();
+    let control_addr = server.control_addr();
+
+    let (wait_client_register, wait_client_register_rx) = oneshot::channel();
+    let client_handler = tokio::spawn(async move {
+        let client = Client::new(control_addr).await.unwrap();
+        let _ = client
+            .start_tunnel(
+                Tunnel::new(
+                    "test",
+                    SocketAddr::from(([127, 0, 0, 1], local_port)),
+                    RemoteConfig::Http(HttpRemoteConfig::Subdomain("webhook")),
+                )
+                .with_allowed_methods(["POST", "PUT"]),
+                close_client,
+            )
+            .await;
+        wait_client_register.send(()).unwrap();
+    });
+
+    wait_client_register_rx.await.unwrap();
+
+    let http_client = reqwest::Client::new();
+    let url = format!("http://localhost:{}/webhook", server.vhttp_port);
+    let response = http_client
+        .post(&url)
+        .header("Host", "webhook.example.com")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 204);
+    let response = http_client
+        .get(&url)
+        .header("Host", "webhook.example.com")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 405);
+    assert_eq!(
+        response.headers().get("Allow"),
+        Some(&HeaderValue::from_static("POST, PUT")),
+    );
+
+    server.cancel.trigger_shutdown(0).unwrap();
+
+    let client_exit = tokio::join!(client_handler);
+    assert!(client_exit.0.is_ok());
+    mock_local_server.verify().await;
+}
+
+#[tokio::test]
+async fn http_tunnel_with_dedicated_port() {
+    let mock_local_server = MockServer::start().await;
+    let local_port = mock_local_server.address().port();
+    Mock::given(method("POST"))
+        .and(path("/webhook"))
+        .respond_with(ResponseTemplate::new(204))
+        .expect(1)
+        .mount(&mock_local_server)
+        .await;
+
+    init();
+    let server = start_server(EntrypointConfig {
+        domain: vec!["example.com".to_string()],
+        ..Default::default()
+    })
+    .await;
+    let close_client = server.cancel.clone();
+    let control_addr = server.control_addr();
+    let remote_port = free_port().unwrap();
+
+    let (wait_client_register, wait_client_register_rx) = oneshot::channel();
+    let client_handler = tokio::spawn(async move {
+        let client = Client::new(control_addr).await.unwrap();
+        let entrypoint = client
+            .start_tunnel(
+                Tunnel::new(
+                    "test",
+                    SocketAddr::from(([127, 0, 0, 1], local_port)),
+                    RemoteConfig::Http(HttpRemoteConfig::Port(remote_port)),
+                )
+                .with_allowed_methods(["POST"]),
+                close_client,
+            )
+            .await
+            .unwrap();
+        wait_client_register.send(entrypoint).unwrap();
+    });
+
+    let entrypoint = wait_client_register_rx.await.unwrap();
+    assert_eq!(
+        entrypoint,
+        vec![format!("http://example.com:{}", remote_port)]
+    );
+
+    // the http processing of the vhttp server applies to the dedicated port, regardless of the host
+    let http_client = reqwest::Client::new();
+    let url = format!("http://localhost:{}/webhook", remote_port);
+    let response = http_client.post(&url).send().await.unwrap();
+    assert_eq!(response.status(), 204);
+    let response = http_client.get(&url).send().await.unwrap();
+    assert_eq!(response.status(), 405);
+    // the vhttp port doesn't route to the tunnel
+    let response = http_client
+        .post(format!("http://localhost:{}/webhook", server.vhttp_port))
+        .header("Host", "example.com")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 404);
+
+    server.cancel.trigger_shutdown(0).unwrap();
+
+    let client_exit = tokio::join!(client_handler);
+    assert!(client_exit.0.is_ok());
+    mock_local_server.verify().await;
+}
+
+#[tokio::test]
+async fn http_tunnels_with_path_prefixes() {
+    let api_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/api/users"))
+        .respond_with(ResponseTemplate::new(200).set_body_string("api"))
+        .expect(1)
+        .mount(&api_server)
+        .await;
+    let web_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/web/index.html"))
+        .respond_with(ResponseTemplate::new(200).set_body_string("web"))
+        .expect(1)
+        .mount(&web_server)
+        .await;
+
+    init();
+    let server = start_server(EntrypointConfig {
+        domain: vec!["preview.example.com".to_string()],
+        ..Default::default()
+    })
+    .await;
+    let close_client = server.cancel.clone();
+    let control_addr = server.control_addr();
+    let api_addr = *api_server.address();
+    let web_addr = *web_server.address();
+
+    let (wait_client_register, wait_client_register_rx) = oneshot::channel();
+    let client_handler = tokio::spawn(async move {
+        let client = Client::new(control_addr).await.unwrap();
+        let mut entrypoints = Vec::new();
+        for (name, addr, path_prefix) in [("api", api_addr, "/api"), ("web", web_addr, "/web")] {
+            let entrypoint = client
+                .clone()
+                .start_tunnel(
+                    Tunnel::new(
+                        name,
+                        addr,
+                        RemoteConfig::Http(HttpRemoteConfig::Subdomain("pr-123")),
+                    )
+                    .with_path_prefix(path_prefix),
+                    close_client.clone(),
+                )
+                .await
+                .unwrap();
+            entrypoints.push(entrypoint);
+        }
+        // the same host and prefix is taken
+        let taken = client
+            .start_tunnel(
+                Tunnel::new(
+                    "api2",
+                    api_addr,
+                    RemoteConfig::Http(HttpRemoteConfig::Subdomain("pr-123")),
+                )
+                .with_path_prefix("/api/"),
+                ShutdownManager::new(),
+            )
+            .await;
+        assert!(taken.is_err());
+        wait_client_register.send(entrypoints).unwrap();
+    });
+
+    let entrypoints = wait_client_register_rx.await.unwrap();
+    assert_eq!(
+        entrypoints,
+        vec![
+            vec!["http://pr-123.preview.example.com/api".to_string()],
+            vec!["http://pr-123.preview.example.com/web".to_string()],
+        ]
+    );
+
+    let http_client = reqwest::Client::new();
+    let get = |path: &str| {
+        http_client
+            .get(format!("http://localhost:{}{}", server.vhttp_port, path))
+            .header("Host", "pr-123.preview.example.com")
+            .send()
+    };
+    let response = get("/api/users").await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await.unwrap(), "api");
+    let response = get("/web/index.html").await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await.unwrap(), "web");
+    // no tunnel serves the path
+    let response = get("/other").await.unwrap();
+    assert_eq!(response.status(), 404);
+
+    server.cancel.trigger_shutdown(0).unwrap();
+
+    let client_exit = tokio::join!(client_handler);
+    assert!(client_exit.0.is_ok());
+    api_server.verify().await;
+    web_server.verify().await;
+}
+
+#[tokio::test]
+async fn http_tunnel_under_external_base_path() {
+    let local_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/api/users"))
+        .respond_with(ResponseTemplate::new(200).set_body_string("users"))
+        .expect(1)
+        .mount(&local_server)
+        .await;
+
+    init();
+    let server = start_server(EntrypointConfig {
+        domain: vec!["site.com".to_string()],
+        external_base_path: Some("/tunnels".to_string()),
+        ..Default::default()
+    })
+    .await;
+    let close_client = server.cancel.clone();
+    let control_addr = server.control_addr();
+    let local_addr = *local_server.address();
+
+    let (wait_client_register, wait_client_register_rx) = oneshot::channel();
+    let client_handler = tokio::spawn(async move {
+        let client = Client::new(control_addr).await.unwrap();
+        let entrypoint = client
+            .start_tunnel(
+                Tunnel::new(
+                    "web",
+                    local_addr,
+                    RemoteConfig::Http(HttpRemoteConfig::Subdomain("web")),
+                ),
+                close_client,
+            )
+            .await
+            .unwrap();
+        wait_client_register.send(entrypoint).unwrap();
+    });
+
+    let entrypoint = wait_client_register_rx.await.unwrap();
+    assert_eq!(entrypoint, vec!["http://web.site.com/tunnels".to_string()]);
+
+    let http_client = reqwest::Client::new();
+    let get = |path: &str| {
+        http_client
+            .get(format!("http://localhost:{}{}", server.vhttp_port, path))
+            .header("Host", "web.site.com")
+            .send()
+    };
+    // the base path is stripped before the request reaches the local server
+    let response = get("/tunnels/api/users").await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await.unwrap(), "users");
+    // not under the base path
+    let response = get("/api/users").await.unwrap();
+    assert_eq!(response.status(), 404);
+
+    server.cancel.trigger_shutdown(0).unwrap();
+
+    let client_exit = tokio::join!(client_handler);
+    assert!(client_exit.0.is_ok());
+    local_server.verify().await;
+}
+
+#[tokio::test]
+async fn http_tunnel_with_rewrite_local_origin() {
+    let mock_local_server = MockServer::start().await;
+    let local_port = mock_local_server.address().port();
+    let local_origin = format!("http://localhost:{}", local_port);
+    Mock::given(method("GET"))
+        .and(path("/"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(
+            format!("<a href=\"{}/login\">login</a>", local_origin),
+            "text/html",
+        ))
+        .mount(&mock_local_server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/api"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(
+            format!("{{\"url\":\"{}\"}}", local_origin),
+            "application/json",
+        ))
+        .mount(&mock_local_server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/old"))
+        .respond_with(
+            ResponseTemplate::new(302).insert_header("location", format!("{}/new", local_origin)),
+        )
+        .mount(&mock_local_server)
+        .await;
+
+    init();
+    let server = start_server(EntrypointConfig {
+        domain: vec!["example.com".to_string()],
+        vhttp_behind_proxy_tls: true,
+        ..Default::default()
+    })
+    .await;
+    let close_client = server.cancel.clone();
+    let control_addr = server.control_addr();
+
+    let (wait_client_register, wait_client_register_rx) = oneshot::channel();
+    let client_handler = tokio::spawn(async move {
+        let client = Client::new(control_addr).await.unwrap();
+        let _ = client
+            .start_tunnel(
+                Tunnel::new(
+                    "test",
+                    SocketAddr::from(([127, 0, 0, 1], local_port)),
+                    RemoteConfig::Http(HttpRemoteConfig::Subdomain("rewritten")),
+                )
+                .with_rewrite_local_origin(),
+                close_client,
+            )
+            .await;
+        wait_client_register.send(()).unwrap();
+    });
+
+    wait_client_register_rx.await.unwrap();
+
+    let http_client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap();
+    let get = |path: &str| {
+        http_client
+            .get(format!("http://localhost:{}{}", server.vhttp_port, path))
+            .header("Host", "rewritten.example.com")
+            .send()
+    };
+    let page = get("/").await.unwrap().text().await.unwrap();
+    assert_eq!(
+        page,
+        "<a href=\"https://rewritten.example.com/login\">login</a>"
+    );
+    let redirect = get("/old").await.unwrap();
+    assert_eq!(
+        redirect.headers()["location"],
+        "https://rewritten.example.com/new"
+    );
+    // only the html bodies are rewritten
+    let api = get("/api").await.unwrap().text().await.unwrap();
+    assert_eq!(api, format!("{{\"url\":\"{}\"}}", local_origin));
+
+    server.cancel.trigger_shutdown(0).unwrap();
+
+    let client_exit = tokio::join!(client_handler);
+    assert!(client_exit.0.is_ok());
+}
+
+#[tokio::test]
+async fn http_tunnel_with_interstitial() {
+    let mock_local_server = MockServer::start().await;
+    let local_port = mock_local_server.address().port();
+    Mock::given(method("GET"))
+        .and(path("/"))
+        .respond_with(ResponseTemplate::new(200).set_body_string("backend"))
+        .mount(&mock_local_server)
+        .await;
+
+    init();
+    let server = start_server(EntrypointConfig {
+        domain: vec!["example.com".to_string()],
+        ..Default::default()
+    })
+    .await;
+    let close_client = server.cancel.clone();
+    let control_addr = server.control_addr();
+
+    let (wait_client_register, wait_client_register_rx) = oneshot::channel();
+    let client_handler = tokio::spawn(async move {
+        let client = Client::new(control_addr).await.unwrap();
+        let _ = client
+            .start_tunnel(
+                Tunnel::new(
+                    "test",
+                    SocketAddr::from(([127, 0, 0, 1], local_port)),
+                    RemoteConfig::Http(HttpRemoteConfig::Subdomain("warned")),
+                )
+                .with_interstitial(),
+                close_client,
+            )
+            .await;
+        wait_client_register.send(()).unwrap();
+    });
+
+    wait_client_register_rx.await.unwrap();
+
+    let http_client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap();
+    let get = |path: &str, cookie: Option<&str>| {
+        let mut request = http_client
+            .get(format!("http://localhost:{}{}", server.vhttp_port, path))
+            .header("Host", "warned.example.com")
+            .header("Accept", "text/html");
+        if let Some(cookie) = cookie {
+            request = request.header("Cookie", cookie);
+        }
+        request.send()
+    };
+    let page = get("/", None).await.unwrap().text().await.unwrap();
+    assert!(page.contains("You are about to visit warned.example.com"));
+    let acknowledged = get("/.castle/continue?to=%2F", None).await.unwrap();
+    assert_eq!(acknowledged.status(), 303);
+    let cookie = acknowledged.headers()["set-cookie"].to_str().unwrap();
+    let cookie = cookie.split(';').next().unwrap().to_string();
+    let response = get("/", Some(&cookie)).await.unwrap();
+    assert_eq!(response.text().await.unwrap(), "backend");
+
+    server.cancel.trigger_shutdown(0).unwrap();
+
+    let client_exit = tokio::join!(client_handler);
+    assert!(client_exit.0.is_ok());
+}
+
+#[tokio::test]
+async fn tcp_tunnel_with_slow_reader() {
+    init();
+    let server = start_server_with_config(Config {
+        max_in_flight_bytes: 16 * 1024,
+        ..Default::default()
+    })
+    .await;
+
+    // the local server writes a large response then closes the connection
+    let payload: Vec<u8> = (0..256 * 1024).map(|i| (i % 251) as u8).collect();
+    let local_server = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let local_port = local_server.local_addr().unwrap().port();
+    let local_payload = payload.clone();
+    tokio::spawn(async move {
+        let (mut stream, _) = local_server.accept().await.unwrap();
+        stream.write_all(&local_payload).await.unwrap();
+        stream.shutdown().await.unwrap();
+    });
+
+    let remote_port = free_port().unwrap();
+    let client = Client::new(server.control_addr()).await.unwrap();
+    client
+        .start_tunnel(
+            Tunnel::new(
+                "test",
+                SocketAddr::from(([127, 0, 0, 1], local_port)),
+                RemoteConfig::Tcp(remote_port),
+            ),
+            ShutdownManager::new(),
+        )
+        .await
+        .unwrap();
+
+    // the user reads slowly, the data is paused instead of piling up
+    let mut user = tokio::net::TcpStream::connect(("127.0.0.1", remote_port))
+        .await
+        .unwrap();
+    let mut received = Vec::new();
+    let mut buf = [0; 4096];
+    loop {
+        let n = user.read(&mut buf).await.unwrap();
+        if n == 0 {
+            break;
+        }
+        received.extend_from_slice(&buf[..n]);
+        sleep(Duration::from_millis(2)).await;
+    }
+    assert_eq!(received.len(), payload.len());
+    assert!(received == payload);
+
+    server.cancel.trigger_shutdown(0).unwrap();
+}
+
+#[tokio::test]
+async fn tcp_tunnel_with_slow_local() {
+    init();
+    let server = start_server(EntrypointConfig::default()).await;
+
+    // the local server reads the upload slowly, then answers the received length
+    let payload: Vec<u8> = (0..256 * 1024).map(|i| (i % 251) as u8).collect();
+    let local_server = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let local_port = local_server.local_addr().unwrap().port();
+    let (uploaded_tx, uploaded_rx) = oneshot::channel();
+    tokio::spawn(async move {
+        let (mut stream, _) = local_server.accept().await.unwrap();
+        let mut received = Vec::new();
+        let mut buf = [0; 4096];
+        loop {
+            let n = stream.read(&mut buf).await.unwrap();
+            if n == 0 {
+                break;
+            }
+            received.extend_from_slice(&buf[..n]);
+            sleep(Duration::from_millis(2)).await;
+        }
+        stream
+            .write_all(&(received.len() as u64).to_be_bytes())
+            .await
+            .unwrap();
+        stream.shutdown().await.unwrap();
+        uploaded_tx.send(received).unwrap();
+    });
+
+    let remote_port = free_port().unwrap();
+    let client = Client::new(server.control_addr()).await.unwrap();
+    client
+        .start_tunnel(
+            Tunnel::new(
+                "test",
+                SocketAddr::from(([127, 0, 0, 1], local_port)),
+                RemoteConfig::Tcp(remote_port),
+            ),
+            ShutdownManager::new(),
+        )
+        .await
+        .unwrap();
+
+    // the server waits for the credits of the client instead of piling up the upload
+    let mut user = tokio::net::TcpStream::connect(("127.0.0.1", remote_port))
+        .await
+        .unwrap();
+    user.write_all(&payload).await.unwrap();
+    user.shutdown().await.unwrap();
+    let mut len = [0; 8];
+    user.read_exact(&mut len).await.unwrap();
+    assert_eq!(u64::from_be_bytes(len), payload.len() as u64);
+    assert!(uploaded_rx.await.unwrap() == payload);
+
+    server.cancel.trigger_shutdown(0).unwrap();
+}
+
+#[tokio::test]
+async fn tcp_tunnel_with_shadow() {
+    init();
+    let server = start_server(EntrypointConfig::default()).await;
+
+    // the primary echoes the request, the shadow answers something else
+    let primary = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let primary_addr = primary.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = primary.accept().await.unwrap();
+        let mut buf = [0; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        stream.write_all(&buf).await.unwrap();
+    });
+    let shadow = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let shadow_addr = shadow.local_addr().unwrap();
+    let (mirrored_tx, mirrored_rx) = oneshot::channel();
+    tokio::spawn(async move {
+        let (mut stream, _) = shadow.accept().await.unwrap();
+        let mut buf = [0; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        stream.write_all(b"shadow").await.unwrap();
+        mirrored_tx.send(buf).unwrap();
+    });
+
+    let remote_port = free_port().unwrap();
+    let client = Client::new(server.control_addr()).await.unwrap();
+    client
+        .start_tunnel(
+            Tunnel::new("test", primary_addr, RemoteConfig::Tcp(remote_port))
+                .with_shadow(shadow_addr),
+            ShutdownManager::new(),
+        )
+        .await
+        .unwrap();
+
+    let mut user = tokio::net::TcpStream::connect(("127.0.0.1", remote_port))
+        .await
+        .unwrap();
+    user.write_all(b"hello").await.unwrap();
+    let mut response = [0; 5];
+    user.read_exact(&mut response).await.unwrap();
+    assert_eq!(&response, b"hello");
+    assert_eq!(&mirrored_rx.await.unwrap(), b"hello");
+
+    server.cancel.trigger_shutdown(0).unwrap();
+}
+
+#[tokio::test]
+async fn tcp_tunnel_with_fallback_addrs() {
+    init();
+    let server = start_server(EntrypointConfig::default()).await;
+
+    // the local endpoint resolved to a dead address and a live one
+    let dead = SocketAddr::from(([127, 0, 0, 1], free_port().unwrap()));
+    let local_server = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let live = local_server.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = local_server.accept().await.unwrap();
+        stream.write_all(b"live").await.unwrap();
+        stream.shutdown().await.unwrap();
+    });
+
+    let remote_port = free_port().unwrap();
+    let client = Client::new(server.control_addr()).await.unwrap();
+    client
+        .start_tunnel(
+            Tunnel::new("test", dead, RemoteConfig::Tcp(remote_port))
+                .with_fallback_addrs(vec![live]),
+            ShutdownManager::new(),
+        )
+        .await
+        .unwrap();
+
+    let mut user = tokio::net::TcpStream::connect(("127.0.0.1", remote_port))
+        .await
+        .unwrap();
+    let mut received = Vec::new();
+    tokio::time::timeout(Duration::from_secs(5), user.read_to_end(&mut received))
+        .await
+        .expect("the live address is dialed")
+        .unwrap();
+    assert_eq!(received, b"live");
+
+    server.cancel.trigger_shutdown(0).unwrap();
+}
+
+#[tokio::test]
+async fn tcp_tunnel_with_echo() {
+    init();
+    let server = start_server(EntrypointConfig::default()).await;
+
+    let remote_port = free_port().unwrap();
+    let client = Client::new(server.control_addr()).await.unwrap();
+    client
+        .start_tunnel(
+            // nothing listens on the local endpoint
+            Tunnel::new(
+                "echo",
+                SocketAddr::from(([127, 0, 0, 1], 0)),
+                RemoteConfig::Tcp(remote_port),
+            )
+            .with_echo(),
+            ShutdownManager::new(),
+        )
+        .await
+        .unwrap();
+
+    for _ in 0..2 {
+        let mut user = tokio::net::TcpStream::connect(("127.0.0.1", remote_port))
+            .await
+            .unwrap();
+        user.write_all(b"hello").await.unwrap();
+        let mut response = [0; 5];
+        user.read_exact(&mut response).await.unwrap();
+        assert_eq!(&response, b"hello");
+        user.shutdown().await.unwrap();
+        assert_eq!(user.read(&mut response).await.unwrap(), 0);
+    }
+
+    server.cancel.trigger_shutdown(0).unwrap();
+}
+
+// the flow controlled data stream ends with its connection,
+// the shutdown doesn't wait for it to drain.
+#[tokio::test]
+async fn tcp_data_stream_ends_with_connection() {
+    init();
+    let server = start_server(EntrypointConfig::default()).await;
+
+    let remote_port = free_port().unwrap();
+    let client_shutdown = ShutdownManager::new();
+    let client = Client::new(server.control_addr()).await.unwrap();
+    client
+        .start_tunnel(
+            Tunnel::new(
+                "echo",
+                SocketAddr::from(([127, 0, 0, 1], 0)),
+                RemoteConfig::Tcp(remote_port),
+            )
+            .with_echo(),
+            client_shutdown.clone(),
+        )
+        .await
+        .unwrap();
+
+    let mut user = tokio::net::TcpStream::connect(("127.0.0.1", remote_port))
+        .await
+        .unwrap();
+    user.write_all(b"hello").await.unwrap();
+    let mut response = [0; 5];
+    user.read_exact(&mut response).await.unwrap();
+    assert_eq!(&response, b"hello");
+    user.shutdown().await.unwrap();
+    assert_eq!(user.read(&mut response).await.unwrap(), 0);
+    drop(user);
+    sleep(Duration::from_millis(300)).await;
+
+    // a data stream still open would wait out the drain of 2 seconds
+    server.cancel.trigger_shutdown(0).unwrap();
+    let code = tokio::time::timeout(
+        Duration::from_secs(1),
+        server.cancel.wait_shutdown_complete(),
+    )
+    .await
+    .expect("the data stream is still open");
+    assert_eq!(code, 0);
+    client_shutdown.trigger_shutdown(0).unwrap();
+    tokio::time::timeout(
+        Duration::from_secs(1),
+        client_shutdown.wait_shutdown_complete(),
+    )
+    .await
+    .expect("the data stream tasks of the client are still running");
+}
+
+#[tokio::test]
+async fn tcp_tunnel_with_bandwidth_limit() {
+    init();
+    let server = start_server_with_config(Config {
+        bandwidth_limit: Some(64 * 1024),
+        tunnel_weights: vec!["tier=paid:4".parse().unwrap()],
+        ..Default::default()
+    })
+    .await;
+
+    let remote_port = free_port().unwrap();
+    let client = Client::new(server.control_addr()).await.unwrap();
+    client
+        .start_tunnel(
+            Tunnel::new(
+                "echo",
+                SocketAddr::from(([127, 0, 0, 1], 0)),
+                RemoteConfig::Tcp(remote_port),
+            )
+            .with_label("tier", "paid")
+            .with_echo(),
+            ShutdownManager::new(),
+        )
+        .await
+        .unwrap();
+
+    let mut user = tokio::net::TcpStream::connect(("127.0.0.1", remote_port))
+        .await
+        .unwrap();
+    let data: Vec<u8> = (0..64 * 1024).map(|i| i as u8).collect();
+    let started = std::time::Instant::now();
+    let (mut reader, mut writer) = user.split();
+    let (_, response) = tokio::join!(writer.write_all(&data), async {
+        let mut response = vec![0; data.len()];
+        reader.read_exact(&mut response).await.unwrap();
+        response
+    });
+    assert_eq!(response, data);
+    // the tunnel alone gets the whole limit, 64KiB take about a second
+    let elapsed = started.elapsed();
+    assert!(elapsed >= Duration::from_millis(700), "{:?}", elapsed);
+
+    server.cancel.trigger_shutdown(0).unwrap();
+}
+
+#[tokio::test]
+async fn tcp_tunnel_with_rate_limit() {
+    init();
+    let server = start_server_with_config(Config {
+        tunnel_rate_limit: Some(64 * 1024),
+        ..Default::default()
+    })
+    .await;
+
+    let remote_port = free_port().unwrap();
+    let client = Client::new(server.control_addr()).await.unwrap();
+    client
+        .start_tunnel(
+            Tunnel::new(
+                "echo",
+                SocketAddr::from(([127, 0, 0, 1], 0)),
+                RemoteConfig::Tcp(remote_port),
+            )
+            // the smaller limit of the tunnel wins
+            .with_rate_limit(16 * 1024)
+            .with_echo(),
+            ShutdownManager::new(),
+        )
+        .await
+        .unwrap();
+
+    let mut user = tokio::net::TcpStream::connect(("127.0.0.1", remote_port))
+        .await
+        .unwrap();
+    let data: Vec<u8> = (0..48 * 1024).map(|i| i as u8).collect();
+    let started = std::time::Instant::now();
+    let (mut reader, mut writer) = user.split();
+    let (_, response) = tokio::join!(writer.write_all(&data), async {
+        let mut response = vec![0; data.len()];
+        reader.read_exact(&mut response).await.unwrap();
+        response
+    });
+    assert_eq!(response, data);
+    // a second of the burst, the rest 32KiB take about 2 seconds
+    let elapsed = started.elapsed();
+    assert!(elapsed >= Duration::from_millis(1500), "{:?}", elapsed);
+
+    server.cancel.trigger_shutdown(0).unwrap();
+}
+
+#[tokio::test]
+async fn tcp_tunnel_with_wait_for_local() {
+    init();
+    let server = start_server(EntrypointConfig::default()).await;
+    let local_addr = SocketAddr::from(([127, 0, 0, 1], free_port().unwrap()));
+
+    // the local server isn't ready before the timeout
+    let client = Client::new(server.control_addr()).await.unwrap();
+    let err = client
+        .start_tunnel(
+            Tunnel::new("test", local_addr, RemoteConfig::Tcp(0))
+                .with_wait_for_local(Duration::from_millis(300)),
+            ShutdownManager::new(),
+        )
+        .await
+        .unwrap_err();
+    assert!(format!("{:?}", err).contains("isn't ready"), "{:?}", err);
+
+    // the local server starts after the client
+    tokio::spawn(async move {
+        sleep(Duration::from_millis(500)).await;
+        let local_server = tokio::net::TcpListener::bind(local_addr).await.unwrap();
+        loop {
+            let (mut stream, _) = local_server.accept().await.unwrap();
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = stream.split();
+                let _ = tokio::io::copy(&mut reader, &mut writer).await;
+            });
+        }
+    });
+    let remote_port = free_port().unwrap();
+    let client = Client::new(server.control_addr()).await.unwrap();
+    let started = std::time::Instant::now();
+    client
+        .start_tunnel(
+            Tunnel::new("test", local_addr, RemoteConfig::Tcp(remote_port))
+                .with_wait_for_local(Duration::from_secs(5)),
+            ShutdownManager::new(),
+        )
+        .await
+        .unwrap();
+    assert!(started.elapsed() >= Duration::from_millis(400));
+
+    let mut user = tokio::net::TcpStream::connect(("127.0.0.1", remote_port))
+        .await
+        .unwrap();
+    user.write_all(b"hello").await.unwrap();
+    let mut response = [0; 5];
+    user.read_exact(&mut response).await.unwrap();
+    assert_eq!(&response, b"hello");
+
+    server.cancel.trigger_shutdown(0).unwrap();
+}
+
+#[tokio::test]
+async fn tcp_tunnel_with_starttls() {
+    init();
+    let server = start_server(EntrypointConfig::default()).await;
+
+    // a tls record after the plaintext negotiation, with the bytes of the line endings
+    let client_hello: Vec<u8> = [0x16, 0x03, 0x01, 0x01, 0x03]
+        .into_iter()
+        .chain((0..=255).chain(b"\r\n\0".iter().copied()))
+        .collect();
+    let server_hello: Vec<u8> = [0x16, 0x03, 0x03, 0x01, 0x00]
+        .into_iter()
+        .chain((0..=255).rev())
+        .collect();
+
+    // the local server speaks first, like smtp
+    let local_server = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let local_addr = local_server.local_addr().unwrap();
+    let (expected_hello, local_hello) = (client_hello.clone(), server_hello.clone());
+    tokio::spawn(async move {
+        let (mut stream, _) = local_server.accept().await.unwrap();
+        stream.write_all(b"220 ready\r\n").await.unwrap();
+        let mut command = [0; 10];
+        stream.read_exact(&mut command).await.unwrap();
+        assert_eq!(&command, b"STARTTLS\r\n");
+        stream.write_all(b"220 go ahead\r\n").await.unwrap();
+        let mut hello = vec![0; expected_hello.len()];
+        stream.read_exact(&mut hello).await.unwrap();
+        assert_eq!(hello, expected_hello);
+        stream.write_all(&local_hello).await.unwrap();
+    });
+
+    let remote_port = free_port().unwrap();
+    let client = Client::new(server.control_addr()).await.unwrap();
+    client
+        .start_tunnel(
+            Tunnel::new("test", local_addr, RemoteConfig::Tcp(remote_port)),
+            ShutdownManager::new(),
+        )
+        .await
+        .unwrap();
+
+    let mut user = tokio::net::TcpStream::connect(("127.0.0.1", remote_port))
+        .await
+        .unwrap();
+    // the greeting arrives before the user sends anything
+    let mut greeting = [0; 11];
+    user.read_exact(&mut greeting).await.unwrap();
+    assert_eq!(&greeting, b"220 ready\r\n");
+    user.write_all(b"STARTTLS\r\n").await.unwrap();
+    let mut reply = [0; 14];
+    user.read_exact(&mut reply).await.unwrap();
+    assert_eq!(&reply, b"220 go ahead\r\n");
+    user.write_all(&client_hello).await.unwrap();
+    let mut hello = vec![0; server_hello.len()];
+    user.read_exact(&mut hello).await.unwrap();
+    assert_eq!(hello, server_hello);
+
+    server.cancel.trigger_shutdown(0).unwrap();
+}
+
+#[tokio::test]
+async fn tcp_tunnel_with_payload_key() {
+    init();
+    let key: castled::PayloadKey =
+        "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f"
+            .parse()
+            .unwrap();
+    let server = start_server_with_config(Config {
+        payload_key: Some(key.clone()),
+        ..Default::default()
+    })
+    .await;
+
+    let local_server = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let local_addr = local_server.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = local_server.accept().await.unwrap();
+        let mut buf = [0; 5];
+        for _ in 0..2 {
+            stream.read_exact(&mut buf).await.unwrap();
+            stream.write_all(&buf).await.unwrap();
+        }
+    });
+
+    let remote_port = free_port().unwrap();
+    let client = Client::new(server.control_addr()).await.unwrap();
+    client
+        .start_tunnel(
+            Tunnel::new("test", local_addr, RemoteConfig::Tcp(remote_port))
+                .with_payload_key(key.clone()),
+            ShutdownManager::new(),
+        )
+        .await
+        .unwrap();
+
+    let mut user = tokio::net::TcpStream::connect(("127.0.0.1", remote_port))
+        .await
+        .unwrap();
+    let mut response = [0; 5];
+    for message in [b"hello", b"world"] {
+        user.write_all(message).await.unwrap();
+        user.read_exact(&mut response).await.unwrap();
+        assert_eq!(&response, message);
+    }
+
+    // never fall back to plaintext
+    let plain_server = start_server(EntrypointConfig::default()).await;
+    let client = Client::new(plain_server.control_addr()).await.unwrap();
+    let err = client
+        .start_tunnel(
+            Tunnel::new("test", local_addr, RemoteConfig::Tcp(0)).with_payload_key(key),
+            ShutdownManager::new(),
+        )
+        .await
+        .unwrap_err();
+    let status = err
+        .downcast_ref::<tonic::Status>()
+        .unwrap_or_else(|| panic!("unexpected error: {:?}", err));
+    assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+
+    plain_server.cancel.trigger_shutdown(0).unwrap();
+    server.cancel.trigger_shutdown(0).unwrap();
+}
+
+#[tokio::test]
+async fn udp_tunnel_drains_sessions_on_shutdown() {
+    init();
+    let server = start_server(EntrypointConfig::default()).await;
+
+    // the local server replies slowly, the server is shutting down meanwhile
+    let local = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let local_addr = local.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buf = [0; 64];
+        let (n, peer) = local.recv_from(&mut buf).await.unwrap();
+        sleep(Duration::from_millis(300)).await;
+        local.send_to(&buf[..n], peer).await.unwrap();
+    });
+
+    let remote_port = free_port().unwrap();
+    let client = Client::new(server.control_addr()).await.unwrap();
+    client
+        .start_tunnel(
+            Tunnel::new("test", local_addr, RemoteConfig::Udp(remote_port)),
+            ShutdownManager::new(),
+        )
+        .await
+        .unwrap();
+
+    let user = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    user.send_to(b"ping", ("127.0.0.1", remote_port))
+        .await
+        .unwrap();
+    sleep(Duration::from_millis(100)).await;
+    server.cancel.trigger_shutdown(0).unwrap();
+
+    let mut buf = [0; 64];
+    let (n, _) = tokio::time::timeout(Duration::from_secs(2), user.recv_from(&mut buf))
+        .await
+        .expect("the reply is lost by the shutdown")
+        .unwrap();
+    assert_eq!(&buf[..n], b"ping");
+}
+
+#[tokio::test]
+async fn udp_tunnel_with_compression() {
+    init();
+    let server = start_server(EntrypointConfig::default()).await;
+
+    let local = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let local_addr = local.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buf = [0; 65536];
+        loop {
+            let (n, peer) = local.recv_from(&mut buf).await.unwrap();
+            local.send_to(&buf[..n], peer).await.unwrap();
+        }
+    });
+
+    let remote_port = free_port().unwrap();
+    let client = Client::new(server.control_addr()).await.unwrap();
+    client
+        .start_tunnel(
+            Tunnel::new("test", local_addr, RemoteConfig::Udp(remote_port)).with_compression(),
+            ShutdownManager::new(),
+        )
+        .await
+        .unwrap();
+
+    let user = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let compressible = b"castle ".repeat(1000);
+    let incompressible: Vec<u8> = (0..1000).map(|_| rand::random()).collect();
+    // larger than the 8KiB messages the tcp data is split into
+    let large: Vec<u8> = (0..12 * 1024).map(|_| rand::random()).collect();
+    // the datagrams keep their boundaries whether they are compressed or not
+    for datagram in [compressible, incompressible, large, b"a".to_vec()] {
+        user.send_to(&datagram, ("127.0.0.1", remote_port))
+            .await
+            .unwrap();
+        let mut buf = [0; 65536];
+        let (n, _) = tokio::time::timeout(Duration::from_secs(2), user.recv_from(&mut buf))
+            .await
+            .expect("the datagram is echoed")
+            .unwrap();
+        assert_eq!(&buf[..n], &datagram[..]);
+    }
+
+    server.cancel.trigger_shutdown(0).unwrap();
+}
+
+#[tokio::test]
+async fn tcp_tunnel_with_max_chunk_size() {
+    init();
+    // the chunks larger than 8KiB aren't split again by the server
+    for (data_chunk_size, max_chunk_size) in [(4096, Some(1400)), (64 * 1024, None)] {
+        let server = start_server_with_config(Config {
+            data_chunk_size: Some(data_chunk_size),
+            ..Default::default()
+        })
+        .await;
+
+        // the local server echoes the bytes
+        let local_server = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local_port = local_server.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (stream, _) = local_server.accept().await.unwrap();
+            let (mut r, mut w) = stream.into_split();
+            tokio::io::copy(&mut r, &mut w).await.unwrap();
+            w.shutdown().await.unwrap();
+        });
+
+        let remote_port = free_port().unwrap();
+        let mut tunnel = Tunnel::new(
+            "test",
+            SocketAddr::from(([127, 0, 0, 1], local_port)),
+            RemoteConfig::Tcp(remote_port),
+        );
+        if let Some(max_chunk_size) = max_chunk_size {
+            tunnel = tunnel.with_max_chunk_size(max_chunk_size);
+        }
+        let client = Client::new(server.control_addr()).await.unwrap();
+        client
+            .start_tunnel(tunnel, ShutdownManager::new())
+            .await
+            .unwrap();
+
+        // both sides split the large writes by the negotiated chunk size
+        let payload: Vec<u8> = (0..256 * 1024).map(|i| (i % 251) as u8).collect();
+        let user = tokio::net::TcpStream::connect(("127.0.0.1", remote_port))
+            .await
+            .unwrap();
+        let (mut r, mut w) = user.into_split();
+        let sent = payload.clone();
+        tokio::spawn(async move {
+            w.write_all(&sent).await.unwrap();
+            w.shutdown().await.unwrap();
+        });
+        let mut received = Vec::new();
+        tokio::time::timeout(Duration::from_secs(5), r.read_to_end(&mut received))
+            .await
+            .expect("the bytes are echoed")
+            .unwrap();
+        assert!(received == payload, "{}", data_chunk_size);
+
+        server.cancel.trigger_shutdown(0).unwrap();
+    }
+}
+
+// the server tells the negotiated chunk size, the data sent to the client is never larger
+#[tokio::test]
+async fn tcp_tunnel_with_max_chunk_size_negotiated() {
+    use castled::pb::{
+        control_command::Payload, traffic_to_server::Action, tunnel,
+        tunnel_service_client::TunnelServiceClient, RegisterReq, TcpConfig, TrafficToServer,
+    };
+    use tokio_stream::StreamExt as _;
+
+    init();
+    for (data_chunk_size, max_chunk_size, negotiated) in [
+        (4096, 1400, 1400),
+        (1024, 4096, 1024),
+        (64 * 1024, 0, 64 * 1024),
+    ] {
+        let server = start_server_with_config(Config {
+            data_chunk_size: Some(data_chunk_size),
+            ..Default::default()
+        })
+        .await;
+        let mut client = TunnelServiceClient::connect(format!("http://{}", server.control_addr()))
+            .await
+            .unwrap();
+        let remote_port = free_port().unwrap();
+        let mut control_stream = client
+            .register(RegisterReq {
+                tunnel: Some(castled::pb::Tunnel {
+                    name: "test".to_string(),
+                    config: Some(tunnel::Config::Tcp(TcpConfig {
+                        remote_port: remote_port as i32,
+                        max_chunk_size,
+                        ..Default::default()
+                    })),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        let command = control_stream.message().await.unwrap().unwrap();
+        let Some(Payload::Init(init)) = command.payload else {
+            panic!("unexpected command {:?}", command);
+        };
+        assert_eq!(init.max_chunk_size, negotiated, "{}", data_chunk_size);
+
+        let payload: Vec<u8> = (0..256 * 1024).map(|i| (i % 251) as u8).collect();
+        let mut user = tokio::net::TcpStream::connect(("127.0.0.1", remote_port))
+            .await
+            .unwrap();
+        user.write_all(&payload).await.unwrap();
+        let command = control_stream.message().await.unwrap().unwrap();
+        let Some(Payload::Work(work)) = command.payload else {
+            panic!("unexpected command {:?}", command);
+        };
+        let start = TrafficToServer {
+            connection_id: work.connection_id,
+            action: Action::Start as i32,
+            ..Default::default()
+        };
+        let mut data_stream = client
+            .data(tokio_stream::iter([start]).chain(tokio_stream::pending()))
+            .await
+            .unwrap()
+            .into_inner();
+        let mut received = Vec::new();
+        while received.len() < payload.len() {
+            let traffic = tokio::time::timeout(Duration::from_secs(5), data_stream.message())
+                .await
+                .expect("the bytes of the user are sent")
+                .unwrap()
+                .unwrap();
+            assert!(
+                traffic.data.len() <= negotiated as usize,
+                "{} > {}",
+                traffic.data.len(),
+                negotiated
+            );
+            received.extend_from_slice(&traffic.data);
+        }
+        assert!(received == payload, "{}", data_chunk_size);
+
+        server.cancel.trigger_shutdown(0).unwrap();
+    }
+}
+
+#[tokio::test]
+async fn udp_tunnel_with_max_datagram_size() {
+    init();
+    let server = start_server(EntrypointConfig::default()).await;
+
+    let local = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let local_addr = local.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buf = [0; 65536];
+        loop {
+            let (n, peer) = local.recv_from(&mut buf).await.unwrap();
+            local.send_to(&buf[..n], peer).await.unwrap();
+        }
+    });
+
+    let remote_port = free_port().unwrap();
+    let client = Client::new(server.control_addr()).await.unwrap();
+    client
+        .start_tunnel(
+            Tunnel::new("test", local_addr, RemoteConfig::Udp(remote_port))
+                .with_max_datagram_size(512),
+            ShutdownManager::new(),
+        )
+        .await
+        .unwrap();
+
+    // the server drops the datagrams larger than the client takes
+    let user = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    user.send_to(&[1; 1000], ("127.0.0.1", remote_port))
+        .await
+        .unwrap();
+    let mut buf = [0; 65536];
+    assert!(
+        tokio::time::timeout(Duration::from_millis(300), user.recv_from(&mut buf))
+            .await
+            .is_err()
+    );
+
+    user.send_to(&[2; 512], ("127.0.0.1", remote_port))
+        .await
+        .unwrap();
+    let (n, _) = tokio::time::timeout(Duration::from_secs(2), user.recv_from(&mut buf))
+        .await
+        .expect("the datagram is echoed")
+        .unwrap();
+    assert_eq!(&buf[..n], &[2; 512]);
+
+    server.cancel.trigger_shutdown(0).unwrap();
+}
+
+#[tokio::test]
+async fn test_assigned_entrypoint() {
+    struct TestTunnel {
+        tunnel: Tunnel<'static>,
+        expected: Vec<&'static str>,
+    }
+
+    struct Case {
+        entrypoint: EntrypointConfig,
+        tunnels: Vec<TestTunnel>,
+    }
+
+    let cases = vec![
+        Case {
+            entrypoint: Default::default(),
+            tunnels: vec![
+                TestTunnel {
+                    tunnel: Tunnel::new(
+                        "test",
+                        SocketAddr::from(([127, 0, 0, 1], 8971)),
+                        RemoteConfig::Tcp(8080),
+                    ),
+                    expected: vec![],
+                },
+                TestTunnel {
+                    tunnel: Tunnel::new(
+                        "test",
+                        SocketAddr::from(([127, 0, 0, 1], 8971)),
+                        RemoteConfig::Udp(8080),
+                    ),
+                    expected: vec![], // TODO(Sword): assert random port
+                },
+                TestTunnel {
+                    tunnel: Tunnel::new(
+                        "test",
+                        SocketAddr::from(([127, 0, 0, 1], 8080)),
+                        RemoteConfig::Http(HttpRemoteConfig::RandomPort),
+                    ),
+                    expected: vec![],
+                },
+            ],
+        },
+        Case {
+            entrypoint: EntrypointConfig {
+                domain: vec!["example.com".to_string()],
+                ..Default::default()
+            },
+            tunnels: vec![
+                TestTunnel {
+                    tunnel: Tunnel::new(
+                        "test",
+                        SocketAddr::from(([127, 0, 0, 1], 8971)),
+                        RemoteConfig::Tcp(8080),
+                    ),
+                    expected: vec!["tcp://example.com:8080"],
+                },
+                TestTunnel {
+                    tunnel: Tunnel::new(
+                        "test",
+                        SocketAddr::from(([127, 0, 0, 1], 8971)),
+                        RemoteConfig::Udp(8080),
+                    ),
+                    expected: vec!["udp://example.com:8080"],
+                },
+                TestTunnel {
+                    tunnel: Tunnel::new(
+                        "test",
+                        SocketAddr::from(([127, 0, 0, 1], 8080)),
+                        RemoteConfig::Http(HttpRemoteConfig::Port(9999)),
+                    ),
+                    expected: vec!["http://example.com:9999"],
+                },
+                TestTunnel {
+                    tunnel: Tunnel::new(
+                        "test",
+                        SocketAddr::from(([127, 0, 0, 1], 8080)),
+                        RemoteConfig::Http(HttpRemoteConfig::Domain("mydomain.com")),
+                    ),
+                    expected: vec!["http://mydomain.com"],
+                },
+                TestTunnel {
+                    tunnel: Tunnel::new(
+                        "test",
+                        SocketAddr::from(([127, 0, 0, 1], 8080)),
+                        RemoteConfig::Http(HttpRemoteConfig::Subdomain("foo")),
+                    ),
+                    expected: vec!["http://foo.example.com"],
+                },
+            ],
+        },
+        Case {
+            entrypoint: EntrypointConfig {
+                ip: vec![IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))],
+                ..Default::default()
+            },
+            tunnels: vec![
+                TestTunnel {
+                    tunnel: Tunnel::new(
+                        "test",
+                        SocketAddr::from(([127, 0, 0, 1], 8971)),
+                        RemoteConfig::Tcp(8080),
+                    ),
+                    expected: vec!["tcp://127.0.0.1:8080"],
+                },
+                TestTunnel {
+                    tunnel: Tunnel::new(
+                        "test",
+                        SocketAddr::from(([127, 0, 0, 1], 8971)),
+                        RemoteConfig::Udp(8080),
+                    ),
+                    expected: vec!["udp://127.0.0.1:8080"],
+                },
+                TestTunnel {
+                    tunnel: Tunnel::new(
+                        "test",
+                        SocketAddr::from(([127, 0, 0, 1], 8080)),
+                        RemoteConfig::Http(HttpRemoteConfig::Port(9999)),
+                    ),
+                    expected: vec!["http://127.0.0.1:9999"],
+                },
+                TestTunnel {
+                    tunnel: Tunnel::new(
+                        "test",
+                        SocketAddr::from(([127, 0, 0, 1], 8080)),
+                        RemoteConfig::Http(HttpRemoteConfig::Domain("mydomain.com")),
+                    ),
+                    expected: vec!["http://mydomain.com"],
+                },
+            ],
+        },
+        Case {
+            entrypoint: EntrypointConfig {
+                domain: vec!["example.com".to_string(), "foo.example.com".to_string()],
+                ip: vec![IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))],
+                vhttp_behind_proxy_tls: true,
+                ..Default::default()
+            },
+            tunnels: vec![
+                TestTunnel {
+                    tunnel: Tunnel::new(
+                        "test",
+                        SocketAddr::from(([127, 0, 0, 1], 8971)),
+                        RemoteConfig::Tcp(8080),
+                    ),
+                    expected: vec![
+                        "tcp://127.0.0.1:8080",
+                        "tcp://example.com:8080",
+                        "tcp://foo.example.com:8080",
+                    ],
+                },
+                TestTunnel {
+                    tunnel: Tunnel::new(
+                        "test",
+                        SocketAddr::from(([127, 0, 0, 1], 8971)),
+                        RemoteConfig::Udp(8080),
+                    ),
+                    expected: vec![
+                        "udp://127.0.0.1:8080",
+                        "udp://example.com:8080",
+                        "udp://foo.example.com:8080",
+                    ],
+                },
+                TestTunnel {
+                    tunnel: Tunnel::new(
+                        "test",
+                        SocketAddr::from(([127, 0, 0, 1], 8080)),
+                        RemoteConfig::Http(HttpRemoteConfig::Port(9999)),
+                    ),
+                    expected: vec![
+                        "http://127.0.0.1:9999",
+                        "http://example.com:9999",
+                        "http://foo.example.com:9999",
+                    ],
+                },
+                TestTunnel {
+                    tunnel: Tunnel::new(
+                        "test",
+                        SocketAddr::from(([127, 0, 0, 1], 8080)),
+                        RemoteConfig::Http(HttpRemoteConfig::Domain("mydomain.com")),
+                    ),
+                    expected: vec!["https://mydomain.com"],
+                },
+                TestTunnel {
+                    tunnel: Tunnel::new(
+                        "test",
+                        SocketAddr::from(([127, 0, 0, 1], 8080)),
+                        RemoteConfig::Http(HttpRemoteConfig::Subdomain("bar")),
+                    ),
+                    expected: vec!["https://bar.example.com", "https://bar.foo.example.com"],
+                },
+            ],
+        },
+    ];
+
+    for case in cases {
+        let server = start_server(case.entrypoint).await;
+
+        for tunnel in case.tunnels {
+            let shutdown = ShutdownManager::new();
+            let close_client = shutdown.clone();
+            let control_addr = server.control_addr();
+
+            let client_handler = tokio::spawn(async move {
+                let client = Client::new(control_addr).await.unwrap();
+                let entrypoint = client.start_tunnel(tunnel.tunnel, close_client).await;
+                assert!(entrypoint.is_ok());
+                let entrypoint = entrypoint.unwrap();
+                assert_eq!(entrypoint, tunnel.expected);
+            });
+
+            tokio::spawn(async move {
+                sleep(tokio::time::Duration::from_millis(100)).await;
+                shutdown.trigger_shutdown(0).unwrap();
+            });
+
+            let _ = tokio::join!(client_handler);
+        }
+    }
+}
+
+#[tokio::test]
+async fn register_port_outside_allowed_range() {
+    init();
+    let port = free_port().unwrap();
+    let server = start_server(EntrypointConfig {
+        port_range: port..=port,
+        ..Default::default()
+    })
+    .await;
+
+    let client = Client::new(server.control_addr()).await.unwrap();
+    let err = client
+        .start_tunnel(
+            Tunnel::new(
+                "test",
+                SocketAddr::from(([127, 0, 0, 1], 8080)),
+                RemoteConfig::Tcp(port - 1),
+            ),
+            ShutdownManager::new(),
+        )
+        .await
+        .unwrap_err();
+    let status = err
+        .downcast_ref::<tonic::Status>()
+        .unwrap_or_else(|| panic!("unexpected error: {:?}", err));
+    assert_eq!(status.code(), tonic::Code::PermissionDenied);
+    assert!(status
+        .message()
+        .contains(&format!("the allowed range {}-{}", port, port)));
+
+    // in range
+    let client = Client::new(server.control_addr()).await.unwrap();
+    client
+        .start_tunnel(
+            Tunnel::new(
+                "test",
+                SocketAddr::from(([127, 0, 0, 1], 8080)),
+                RemoteConfig::Tcp(port),
+            ),
+            ShutdownManager::new(),
+        )
+        .await
+        .unwrap();
+
+    server.cancel.trigger_shutdown(0).unwrap();
+}
+
+#[tokio::test]
+async fn register_with_auth_token() {
+    init();
+    let server = start_server_with_config(Config {
+        auth_token: Some("secret".to_string()),
+        ..Default::default()
+    })
+    .await;
+
+    let remote_port = free_port().unwrap();
+    let tunnel = || {
+        Tunnel::new(
+            "test",
+            SocketAddr::from(([127, 0, 0, 1], 8971)),
+            RemoteConfig::Tcp(remote_port),
+        )
+    };
+    // neither a missing token nor a wrong one binds the port
+    for token in [None, Some("wrong")] {
+        let client = Client::new(server.control_addr()).await.unwrap();
+        let client = match token {
+            Some(token) => client.with_auth_token(token).unwrap(),
+            None => client,
+        };
+        let err = client
+            .start_tunnel(tunnel(), ShutdownManager::new())
+            .await
+            .unwrap_err();
+        let status = err.root_cause().downcast_ref::<tonic::Status>().unwrap();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated, "{:?}", token);
+        assert!(!is_port_listening(remote_port));
+    }
+
+    let client = Client::new(server.control_addr())
+        .await
+        .unwrap()
+        .with_auth_token("secret")
+        .unwrap();
+    client
+        .start_tunnel(tunnel(), ShutdownManager::new())
+        .await
+        .unwrap();
+    assert!(is_port_listening(remote_port));
+
+    server.cancel.trigger_shutdown(0).unwrap();
+}
+
+#[tokio::test]
+async fn register_with_registration_rate_limit() {
+    init();
+    let server = start_server_with_config(Config {
+        registration_rate_limit: Some(1),
+        ..Default::default()
+    })
+    .await;
+
+    let register = |token: &str| {
+        let token = token.to_string();
+        let addr = server.control_addr();
+        async move {
+            Client::new(addr)
+                .await
+                .unwrap()
+                .with_auth_token(&token)
+                .unwrap()
+                .start_tunnel(
+                    Tunnel::new(
+                        "test",
+                        SocketAddr::from(([127, 0, 0, 1], 8971)),
+                        RemoteConfig::Tcp(0),
+                    ),
+                    ShutdownManager::new(),
+                )
+                .await
+        }
+    };
+    register("a").await.unwrap();
+    // the second registration of the token within a minute, from another client
+    let err = register("a").await.unwrap_err();
+    let status = err.root_cause().downcast_ref::<tonic::Status>().unwrap();
+    assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+    assert!(
+        status.message().contains("retry after"),
+        "{}",
+        status.message()
+    );
+    assert!(status.metadata().get("retry-after").is_some());
+    // another token has its own limit
+    register("b").await.unwrap();
+
+    server.cancel.trigger_shutdown(0).unwrap();
+}
+
+// the admin api and the reflection require the auth token as the tunnels do.
+#[tokio::test]
+async fn admin_api_and_reflection_with_auth_token() {
+    use tonic_reflection::pb::{
+        server_reflection_client::ServerReflectionClient,
+        server_reflection_request::MessageRequest, ServerReflectionRequest,
+    };
+
+    init();
+    let server = start_server_with_config(Config {
+        auth_token: Some("secret".to_string()),
+        admin_api: true,
+        grpc_reflection: true,
+        ..Default::default()
+    })
+    .await;
+    let client = Client::new(server.control_addr())
+        .await
+        .unwrap()
+        .with_auth_token("secret")
+        .unwrap();
+    client
+        .start_tunnel(
+            Tunnel::new(
+                "test",
+                SocketAddr::from(([127, 0, 0, 1], 8971)),
+                RemoteConfig::Tcp(free_port().unwrap()),
+            ),
+            ShutdownManager::new(),
+        )
+        .await
+        .unwrap();
+
+    let http = reqwest::Client::new();
+    let list = |token: Option<&str>| {
+        let mut request = http.get(format!("http://{}/admin/tunnels", server.control_addr()));
+        if let Some(token) = token {
+            request = request.bearer_auth(token);
+        }
+        async move { request.send().await.unwrap() }
+    };
+    for token in [None, Some("wrong")] {
+        let response = list(token).await;
+        assert_eq!(
+            response.status(),
+            reqwest::StatusCode::UNAUTHORIZED,
+            "{:?}",
+            token
+        );
+        assert_eq!(response.headers()["www-authenticate"], "Bearer");
+    }
+    let tunnels: Vec<serde_json::Value> = list(Some("secret")).await.json().await.unwrap();
+    assert_eq!(tunnels.len(), 1);
+    let tunnel_id = tunnels[0]["id"].as_str().unwrap();
+
+    // neither the tunnel is closed nor probed without the token
+    let url = format!(
+        "http://{}/admin/tunnels/{}",
+        server.control_addr(),
+        tunnel_id
+    );
+    let response = http.delete(&url).send().await.unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::UNAUTHORIZED);
+    let response = http
+        .post(format!("{}/probe?target=127.0.0.1:8971", url))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::UNAUTHORIZED);
+    let tunnels: Vec<serde_json::Value> = list(Some("secret")).await.json().await.unwrap();
+    assert_eq!(tunnels.len(), 1);
+
+    let list_services = |token: Option<&'static str>| {
+        let control_addr = server.control_addr();
+        async move {
+            let channel =
+                tonic::transport::Endpoint::from_shared(format!("http://{}", control_addr))
+                    .unwrap()
+                    .connect()
+                    .await
+                    .unwrap();
+            let mut client = ServerReflectionClient::new(channel);
+            let mut request = tonic::Request::new(tokio_stream::once(ServerReflectionRequest {
+                host: String::new(),
+                message_request: Some(MessageRequest::ListServices(String::new())),
+            }));
+            if let Some(token) = token {
+                let value = format!("Bearer {}", token).parse().unwrap();
+                request.metadata_mut().insert("authorization", value);
+            }
+            let mut responses = client.server_reflection_info(request).await?.into_inner();
+            responses.message().await.map(|response| response.is_some())
+        }
+    };
+    for token in [None, Some("wrong")] {
+        let err = list_services(token).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::Unauthenticated, "{:?}", token);
+    }
+    assert!(list_services(Some("secret")).await.unwrap());
+
+    server.cancel.trigger_shutdown(0).unwrap();
+}
+
+#[tokio::test]
+async fn tcp_tunnel_over_control_tls() {
+    init();
+    let fixture = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/tls");
+    let server = start_server_with_config(Config {
+        control_tls: Some(ControlTls {
+            cert: fixture.join("cert.pem"),
+            key: fixture.join("key.pem"),
+        }),
+        ..Default::default()
+    })
+    .await;
+
+    // the plaintext client can't register
+    let remote_port = free_port().unwrap();
+    let tunnel = |local| Tunnel::new("test", local, RemoteConfig::Tcp(remote_port));
+    let plaintext = SocketAddr::from(([127, 0, 0, 1], 8971));
+    if let Ok(client) = Client::new(server.control_addr()).await {
+        let registered = tokio::time::timeout(
+            Duration::from_secs(5),
+            client.start_tunnel(tunnel(plaintext), ShutdownManager::new()),
+        )
+        .await;
+        assert!(!matches!(registered, Ok(Ok(_))));
+    }
+    assert!(!is_port_listening(remote_port));
+
+    let local_server = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let local = local_server.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = local_server.accept().await.unwrap();
+        let mut buf = [0; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        stream.write_all(&buf).await.unwrap();
+    });
+
+    let tls = ClientTls::from_ca_file(fixture.join("cert.pem")).unwrap();
+    let client = Client::with_tls(vec![server.control_addr()], tls)
+        .await
+        .unwrap();
+    client
+        .start_tunnel(tunnel(local), ShutdownManager::new())
+        .await
+        .unwrap();
+
+    let mut user = tokio::net::TcpStream::connect(("127.0.0.1", remote_port))
+        .await
+        .unwrap();
+    user.write_all(b"ping").await.unwrap();
+    let mut received = [0; 4];
+    tokio::time::timeout(Duration::from_secs(5), user.read_exact(&mut received))
+        .await
+        .expect("the data streams run over the tls")
+        .unwrap();
+    assert_eq!(&received, b"ping");
+
+    // the certificate doesn't name the host
+    let tls = ClientTls::from_ca_file(fixture.join("cert.pem"))
+        .unwrap()
+        .with_server_name("example.com")
+        .unwrap();
+    assert!(Client::with_tls(vec![server.control_addr()], tls)
+        .await
+        .is_err());
+
+    server.cancel.trigger_shutdown(0).unwrap();
+}
+
+// the local server requires the client certificate of the tunnel
+#[tokio::test]
+async fn http_tunnel_with_local_mtls() {
+    init();
+    let fixture = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/tls");
+    let pem =
+        |name: &str| std::io::BufReader::new(std::fs::File::open(fixture.join(name)).unwrap());
+    let certs = |name: &str| {
+        rustls_pemfile::certs(&mut pem(name))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap()
+    };
+    let provider = std::sync::Arc::new(rustls::crypto::ring::default_provider());
+    let mut client_roots = rustls::RootCertStore::empty();
+    client_roots.add_parsable_certificates(certs("client-cert.pem"));
+    let verifier = rustls::server::WebPkiClientVerifier::builder_with_provider(
+        std::sync::Arc::new(client_roots),
+        provider.clone(),
+    )
+    .build()
+    .unwrap();
+    let config = rustls::ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(
+            certs("cert.pem"),
+            rustls_pemfile::private_key(&mut pem("key.pem"))
+                .unwrap()
+                .unwrap(),
+        )
+        .unwrap();
+    let acceptor = tokio_rustls::TlsAcceptor::from(std::sync::Arc::new(config));
+    let local_server = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let local = local_server.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = local_server.accept().await.unwrap();
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                // the handshake fails without the client certificate
+                let Ok(mut stream) = acceptor.accept(stream).await else {
+                    return;
+                };
+                let mut buf = [0; 1024];
+                let _ = stream.read(&mut buf).await;
+                let _ = stream
+                    .write_all(
+                        b"HTTP/1.1 200 OK\r\ncontent-length: 5\r\nconnection: close\r\n\r\nhello",
+                    )
+                    .await;
+                let _ = stream.shutdown().await;
+            });
+        }
+    });
+
+    let server = start_server(EntrypointConfig::default()).await;
+    let client = Client::new(server.control_addr()).await.unwrap();
+    let mut results = Vec::new();
+    for identity in [true, false] {
+        let remote_port = free_port().unwrap();
+        let tls = LocalTls {
+            ca: fixture.join("cert.pem"),
+            identity: identity.then(|| {
+                (
+                    fixture.join("client-cert.pem"),
+                    fixture.join("client-key.pem"),
+                )
+            }),
+        };
+        client
+            .clone()
+            .start_tunnel(
+                Tunnel::new(
+                    if identity { "mtls" } else { "tls" },
+                    local,
+                    RemoteConfig::Http(HttpRemoteConfig::Port(remote_port)),
+                )
+                .with_local_tls(tls)
+                .unwrap(),
+                ShutdownManager::new(),
+            )
+            .await
+            .unwrap();
+        let response = reqwest::get(format!("http://localhost:{}/", remote_port))
+            .await
+            .unwrap();
+        results.push((response.status(), response.text().await.unwrap()));
+    }
+    assert_eq!(results[0], (reqwest::StatusCode::OK, "hello".to_string()));
+    assert_eq!(results[1].0, reqwest::StatusCode::BAD_GATEWAY);
+
+    // the files are loaded up front
+    let missing = LocalTls {
+        ca: fixture.join("cert.pem"),
+        identity: Some((fixture.join("client-cert.pem"), fixture.join("missing.pem"))),
+    };
+    assert!(Tunnel::new("test", local, RemoteConfig::Tcp(0))
+        .with_local_tls(missing)
+        .is_err());
+
+    server.cancel.trigger_shutdown(0).unwrap();
+}
+
+#[tokio::test]
+async fn register_disabled_tunnels() {
+    init();
+    let kinds = ["tcp", "udp", "http"];
+    for disabled in kinds {
+        let server = start_server_with_config(Config {
+            disable_tcp: disabled == "tcp",
+            disable_udp: disabled == "udp",
+            disable_http: disabled == "http",
+            ..Default::default()
+        })
+        .await;
+
+        for kind in kinds {
+            let remote_config = match kind {
+                "tcp" => RemoteConfig::Tcp(0),
+                "udp" => RemoteConfig::Udp(0),
+                _ => RemoteConfig::Http(HttpRemoteConfig::RandomPort),
+            };
+            let client = Client::new(server.control_addr()).await.unwrap();
+            let result = client
+                .start_tunnel(
+                    Tunnel::new(
+                        "test",
+                        SocketAddr::from(([127, 0, 0, 1], 8080)),
+                        remote_config,
+                    ),
+                    ShutdownManager::new(),
+                )
+                .await;
+            if kind != disabled {
+                assert!(result.is_ok(), "{}: {:?}", kind, result);
+                continue;
+            }
+            let err = result.unwrap_err();
+            let status = err
+                .downcast_ref::<tonic::Status>()
+                .unwrap_or_else(|| panic!("unexpected error: {:?}", err));
+            assert_eq!(status.code(), tonic::Code::PermissionDenied);
+            assert!(status
+                .message()
+                .contains(&format!("the {} tunnels are disabled", kind)));
+        }
+
+        server.cancel.trigger_shutdown(0).unwrap();
+    }
+}
+
+#[tokio::test]
+async fn register_loopback_tunnel() {
+    init();
+    let server = start_server_with_config(Config {
+        entrypoint: EntrypointConfig {
+            domain: vec!["example.com".to_string()],
+            ..Default::default()
+        },
+        ..Default::default()
+    })
+    .await;
+    let client = Client::new(server.control_addr()).await.unwrap();
+    let port = free_port().unwrap();
+    let entrypoint = client
+        .start_tunnel(
+            Tunnel::new(
+                "test",
+                SocketAddr::from(([127, 0, 0, 1], 8080)),
+                RemoteConfig::Tcp(port),
+            )
+            .with_loopback(),
+            ShutdownManager::new(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(entrypoint, vec![format!("tcp://127.0.0.1:{}", port)]);
+    assert!(tokio::net::TcpStream::connect(("127.0.0.1", port))
+        .await
+        .is_ok());
+    // the port of the other interfaces is still free
+    assert!(tokio::net::TcpListener::bind(("127.0.0.2", port))
+        .await
+        .is_ok());
+
+    // the vhttp port is shared by the tunnels
+    let client = Client::new(server.control_addr()).await.unwrap();
+    let err = client
+        .start_tunnel(
+            Tunnel::new(
+                "test",
+                SocketAddr::from(([127, 0, 0, 1], 8080)),
+                RemoteConfig::Http(HttpRemoteConfig::Subdomain("loopback")),
+            )
+            .with_loopback(),
+            ShutdownManager::new(),
+        )
+        .await
+        .unwrap_err();
+    let status = err.downcast_ref::<tonic::Status>().unwrap();
+    assert_eq!(status.code(), tonic::Code::InvalidArgument);
+
+    server.cancel.trigger_shutdown(0).unwrap();
+}
+
+#[tokio::test]
+async fn register_subdomain_without_server_domain() {
+    init();
+    let server = start_server(EntrypointConfig {
+        ip: vec![IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))],
+        ..Default::default()
+    })
+    .await;
+
+    for config in [
+        HttpRemoteConfig::Subdomain("foo"),
+        HttpRemoteConfig::RandomSubdomain,
+    ] {
+        let client = Client::new(server.control_addr()).await.unwrap();
+        let err = client
+            .start_tunnel(
+                Tunnel::new(
+                    "test",
+                    SocketAddr::from(([127, 0, 0, 1], 8080)),
+                    RemoteConfig::Http(config),
+                ),
+                ShutdownManager::new(),
+            )
+            .await
+            .unwrap_err();
+        let status = err
+            .downcast_ref::<tonic::Status>()
+            .unwrap_or_else(|| panic!("unexpected error: {:?}", err));
+        assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+        assert!(status
+            .message()
+            .contains("the server has no domains configured"));
+    }
+
+    server.cancel.trigger_shutdown(0).unwrap();
+}
+
+#[tokio::test]
+async fn register_random_subdomain_from_words() {
+    init();
+    let server = start_server(EntrypointConfig {
+        domain: vec!["example.com".to_string()],
+        subdomain_words: Some(SubdomainWords {
+            adjectives: vec!["brave".to_string()],
+            nouns: vec!["otter".to_string()],
+        }),
+        ..Default::default()
+    })
+    .await;
+
+    let client = Client::new(server.control_addr()).await.unwrap();
+    let entrypoint = client
+        .start_tunnel(
+            Tunnel::new(
+                "test",
+                SocketAddr::from(([127, 0, 0, 1], 8080)),
+                RemoteConfig::Http(HttpRemoteConfig::RandomSubdomain),
+            ),
+            ShutdownManager::new(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(entrypoint.len(), 1);
+    let subdomain = entrypoint[0]
+        .strip_prefix("http://brave-otter-")
+        .and_then(|rest| rest.strip_suffix(".example.com"))
+        .unwrap_or_else(|| panic!("unexpected entrypoint: {}", entrypoint[0]));
+    assert!(subdomain.len() == 4 && subdomain.bytes().all(|b| b.is_ascii_digit()));
+
+    server.cancel.trigger_shutdown(0).unwrap();
+}
+
+#[tokio::test]
+async fn test_tunnel_handle() {
+    init();
+    let server = start_server(EntrypointConfig {
+        domain: vec!["example.com".to_string()],
+        ..Default::default()
+    })
+    .await;
+
+    let client = Client::new(server.control_addr()).await.unwrap();
+    let mut handle = client.clone().spawn_tunnel(
+        Tunnel::new(
+            "echo",
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            RemoteConfig::Tcp(0),
+        )
+        .with_echo(),
+        ShutdownManager::new(),
+    );
+    let registration = handle.registered().await.unwrap();
+    let remote_port = registration.remote_port.unwrap();
+    assert_eq!(
+        registration.entrypoint,
+        vec![format!("tcp://example.com:{}", remote_port)]
+    );
+    assert_eq!(handle.registration(), Some(registration));
+    let mut user = tokio::net::TcpStream::connect(("127.0.0.1", remote_port))
+        .await
+        .unwrap();
+    user.write_all(b"ping").await.unwrap();
+    let mut response = [0; 4];
+    user.read_exact(&mut response).await.unwrap();
+    assert_eq!(&response, b"ping");
+
+    let mut handle = client.clone().spawn_tunnel(
+        Tunnel::new(
+            "web",
+            SocketAddr::from(([127, 0, 0, 1], 8080)),
+            RemoteConfig::Http(HttpRemoteConfig::RandomSubdomain),
+        ),
+        ShutdownManager::new(),
+    );
+    let registration = handle.registered().await.unwrap();
+    let subdomain = registration.subdomain.unwrap();
+    assert_eq!(registration.remote_port, None);
+    assert_eq!(
+        registration.entrypoint,
+        vec![format!("http://{}.example.com", subdomain)]
+    );
+
+    // the port is taken
+    let mut handle = client.spawn_tunnel(
+        Tunnel::new(
+            "taken",
+            SocketAddr::from(([127, 0, 0, 1], 8971)),
+            RemoteConfig::Tcp(remote_port),
+        ),
+        ShutdownManager::new(),
+    );
+    assert!(handle.registered().await.is_err());
+    assert_eq!(handle.registration(), None);
+
+    server.cancel.trigger_shutdown(0).unwrap();
+}
+
+#[tokio::test]
+async fn test_tunnels_file() {
+    init();
+    let server = start_server(EntrypointConfig {
+        domain: vec!["example.com".to_string()],
+        ..Default::default()
+    })
+    .await;
+    let taken = std::net::TcpListener::bind("0.0.0.0:0").unwrap();
+    let taken_port = taken.local_addr().unwrap().port();
+    let file = TunnelsFile::parse(&format!(
+        r#"[[tunnel]]
+name = "echo"
+type = "tcp"
+local_addr = "127.0.0.1:0"
+echo = true
+
+[[tunnel]]
+name = "taken"
+type = "tcp"
+local_addr = "127.0.0.1:8971"
+remote_port = {}
+
+[[tunnel]]
+name = "web"
+type = "http"
+local_addr = "127.0.0.1:8080"
+random_subdomain = true
+"#,
+        taken_port
+    ))
+    .unwrap();
+
+    // each of its own shutdown, the failing one doesn't stop the rest
+    let client = Client::new(server.control_addr()).await.unwrap();
+    let mut started = Vec::new();
+    for config in &file.tunnels {
+        let shutdown = ShutdownManager::new();
+        let handle = client
+            .clone()
+            .spawn_tunnel(config.to_tunnel().unwrap(), shutdown.clone());
+        started.push((handle, shutdown));
+    }
+    let echo = started[0].0.registered().await.unwrap();
+    assert!(started[1].0.registered().await.is_err());
+    let web = started[2].0.registered().await.unwrap();
+    assert_eq!(
+        web.entrypoint,
+        vec![format!("http://{}.example.com", web.subdomain.unwrap())]
+    );
+
+    let mut user = tokio::net::TcpStream::connect(("127.0.0.1", echo.remote_port.unwrap()))
+        .await
+        .unwrap();
+    user.write_all(b"ping").await.unwrap();
+    let mut response = [0; 4];
+    user.read_exact(&mut response).await.unwrap();
+    assert_eq!(&response, b"ping");
+    assert_eq!(*started[0].0.state().borrow(), TunnelState::Connected);
+    assert_eq!(*started[2].0.state().borrow(), TunnelState::Connected);
+
+    for (_, shutdown) in &started {
+        shutdown.trigger_shutdown(0).ok();
+    }
+    server.cancel.trigger_shutdown(0).unwrap();
+}
+
+#[tokio::test]
+async fn test_control_rate_limit() {
+    init();
+    let server = start_server_with_config(Config {
+        control_rate_limit: Some(1),
+        admin_api: true,
+        ..Default::default()
+    })
+    .await;
+
+    let mut results = Vec::new();
+    for _ in 0..2 {
+        let client = Client::new(server.control_addr()).await.unwrap();
+        results.push(
+            client
+                .start_tunnel(
+                    Tunnel::new(
+                        "test",
+                        SocketAddr::from(([127, 0, 0, 1], 8080)),
+                        RemoteConfig::Tcp(0),
+                    ),
+                    ShutdownManager::new(),
+                )
+                .await,
+        );
+    }
+    assert!(results[0].is_ok());
+    // the second registration of the client ip within a second
+    let err = results[1].as_ref().unwrap_err();
+    let status = err
+        .downcast_ref::<tonic::Status>()
+        .unwrap_or_else(|| panic!("unexpected error: {:?}", err));
+    assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+
+    let stats: serde_json::Value =
+        reqwest::get(format!("http://{}/admin/stats", server.control_addr()))
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+    assert_eq!(stats["rate_limit"]["rate"], 1);
+    assert_eq!(stats["rate_limit"]["rejected"], 1);
+
+    server.cancel.trigger_shutdown(0).unwrap();
+}
+
+// the throttled data streams don't hold up the shutdown
+#[tokio::test]
+async fn test_control_rate_limit_on_shutdown() {
+    init();
+    let server = start_server_with_config(Config {
+        control_rate_limit: Some(1),
+        ..Default::default()
+    })
+    .await;
+
+    let remote_port = free_port().unwrap();
+    let client = Client::new(server.control_addr()).await.unwrap();
+    client
+        .start_tunnel(
+            Tunnel::new(
+                "echo",
+                SocketAddr::from(([127, 0, 0, 1], 0)),
+                RemoteConfig::Tcp(remote_port),
+            )
+            .with_echo(),
+            ShutdownManager::new(),
+        )
+        .await
+        .unwrap();
+    // the data streams of the client wait for the tokens one after another, up to 8s
+    let mut users = Vec::new();
+    for _ in 0..8 {
+        let mut user = tokio::net::TcpStream::connect(("127.0.0.1", remote_port))
+            .await
+            .unwrap();
+        user.write_all(b"hello").await.unwrap();
+        users.push(user);
+    }
+    sleep(Duration::from_millis(500)).await;
+
+    let start = tokio::time::Instant::now();
+    server.cancel.trigger_shutdown(0).unwrap();
+    tokio::time::timeout(
+        Duration::from_secs(4),
+        server.cancel.wait_shutdown_complete(),
+    )
+    .await
+    .expect("the shutdown waits for the throttled data streams");
+    assert!(start.elapsed() < Duration::from_secs(4));
+}
+
+#[tokio::test]
+async fn test_ephemeral_ports() {
+    init();
+    // the pool is empty, the ports come from the os
+    let server = start_server(EntrypointConfig {
+        ip: vec![IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))],
+        port_range: 2000..=2000,
+        exclude_ports: vec![2000],
+        ephemeral_ports: true,
+        ..Default::default()
+    })
+    .await;
+
+    for remote in [RemoteConfig::Tcp(0), RemoteConfig::Udp(0)] {
+        let client = Client::new(server.control_addr()).await.unwrap();
+        let entrypoint = client
+            .start_tunnel(
+                Tunnel::new("test", SocketAddr::from(([127, 0, 0, 1], 8971)), remote),
+                ShutdownManager::new(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(entrypoint.len(), 1);
+        let port: u16 = entrypoint[0].rsplit_once(':').unwrap().1.parse().unwrap();
+        assert_ne!(port, 0);
+        if entrypoint[0].starts_with("tcp://") {
+            tokio::net::TcpStream::connect(("127.0.0.1", port))
+                .await
+                .unwrap();
+        }
+    }
+
+    // the explicit ports are still checked against the range
+    let client = Client::new(server.control_addr()).await.unwrap();
+    assert!(client
+        .start_tunnel(
+            Tunnel::new(
+                "test",
+                SocketAddr::from(([127, 0, 0, 1], 8971)),
+                RemoteConfig::Tcp(free_port().unwrap()),
+            ),
+            ShutdownManager::new(),
+        )
+        .await
+        .is_err());
+
+    server.cancel.trigger_shutdown(0).unwrap();
+}
+
+#[tokio::test]
+async fn test_client_auto_close_when_server_crash() {
+    init();
+    let server = start_server(Default::default()).await;
+    let control_addr = server.control_addr();
+
+    let client_handler = tokio::spawn(async move {
+        sleep(tokio::time::Duration::from_millis(50)).await; // wait for server to start
+        let shutdown = ShutdownManager::new();
+
+        let client = Client::new(control_addr).await.unwrap();
+        let _ = client
+            .start_tunnel(
+                Tunnel::new(
+                    "test",
+                    SocketAddr::from(([127, 0, 0, 1], 8971)),
+                    RemoteConfig::Tcp(free_port().unwrap()),
+                ),
+                shutdown.clone(),
+            )
+            .await;
+
+        shutdown.wait_shutdown_complete().await;
+    });
+
+    sleep(tokio::time::Duration::from_millis(200)).await;
+    server.cancel.trigger_shutdown(0).unwrap();
+
+    let client_exit = tokio::join!(client_handler);
+    assert!(client_exit.0.is_ok());
+}
+
+#[tokio::test]
+async fn test_landing_page_on_control_port() {
+    init();
+    let server = start_server(Default::default()).await;
+
+    let response = reqwest::get(format!("http://{}/", server.control_addr()))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    assert!(response
+        .text()
+        .await
+        .unwrap()
+        .contains("This is a castle control endpoint"));
+
+    // grpc clients are still served on the same port
+    let client = Client::new(server.control_addr()).await.unwrap();
+    let entrypoint = client
+        .start_tunnel(
+            Tunnel::new(
+                "test",
+                SocketAddr::from(([127, 0, 0, 1], 8971)),
+                RemoteConfig::Tcp(free_port().unwrap()),
+            ),
+            ShutdownManager::new(),
+        )
+        .await;
+    assert!(entrypoint.is_ok());
+
+    server.cancel.trigger_shutdown(0).unwrap();
+}
+
+#[tokio::test]
+async fn test_server_version_on_register() {
+    use castled::pb::{
+        control_command::Payload, tunnel, tunnel_service_client::TunnelServiceClient, RegisterReq,
+        TcpConfig,
+    };
+
+    init();
+    let before = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let server = start_server(Default::default()).await;
+    let mut client = TunnelServiceClient::connect(format!("http://{}", server.control_addr()))
+        .await
+        .unwrap();
+    let mut control_stream = client
+        .register(RegisterReq {
+            tunnel: Some(castled::pb::Tunnel {
+                name: "test".to_string(),
+                config: Some(tunnel::Config::Tcp(TcpConfig {
+                    remote_port: free_port().unwrap() as i32,
+                    ..Default::default()
+                })),
+                ..Default::default()
+            }),
+            data_stream_version: 2,
+            ..Default::default()
+        })
+        .await
+        .unwrap()
+        .into_inner();
+    let command = control_stream.message().await.unwrap().unwrap();
+    let Some(Payload::Init(init)) = command.payload else {
+        panic!("unexpected command {:?}", command);
+    };
+    assert_eq!(init.server_version, env!("CARGO_PKG_VERSION"));
+    // the server started in the test
+    assert!(
+        init.server_started_at >= before,
+        "{}",
+        init.server_started_at
+    );
+
+    server.cancel.trigger_shutdown(0).unwrap();
+}
+
+#[tokio::test]
+async fn test_grpc_reflection() {
+    use tonic_reflection::pb::{
+        server_reflection_client::ServerReflectionClient,
+        server_reflection_request::MessageRequest, server_reflection_response::MessageResponse,
+        ServerReflectionRequest,
+    };
+
+    init();
+    let list_services = |control_addr: SocketAddr| async move {
+        let channel = tonic::transport::Endpoint::from_shared(format!("http://{}", control_addr))
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+        let mut client = ServerReflectionClient::new(channel);
+        let request = ServerReflectionRequest {
+            host: String::new(),
+            message_request: Some(MessageRequest::ListServices(String::new())),
+        };
+        let mut responses = client
+            .server_reflection_info(tokio_stream::once(request))
+            .await?
+            .into_inner();
+        let response = responses.message().await?.unwrap();
+        let Some(MessageResponse::ListServicesResponse(services)) = response.message_response
+        else {
+            panic!("unexpected response {:?}", response);
+        };
+        Ok::<_, tonic::Status>(
+            services
+                .service
+                .into_iter()
+                .map(|service| service.name)
+                .collect::<Vec<_>>(),
+        )
+    };
+
+    let server = start_server_with_config(Config {
+        grpc_reflection: true,
+        ..Default::default()
+    })
+    .await;
+    let services = list_services(server.control_addr()).await.unwrap();
+    assert!(services.contains(&"message.TunnelService".to_string()));
+    server.cancel.trigger_shutdown(0).unwrap();
+
+    // off by default
+    let server = start_server(Default::default()).await;
+    let err = list_services(server.control_addr()).await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::Unimplemented);
+    server.cancel.trigger_shutdown(0).unwrap();
+}
+
+#[tokio::test]
+async fn test_list_tunnels_by_labels() {
+    init();
+    let server = start_server_with_config(Config {
+        admin_api: true,
+        ..Default::default()
+    })
+    .await;
+
+    let mut entrypoints = Vec::new();
+    for (name, env) in [("api", "prod"), ("web", "dev")] {
+        let client = Client::new(server.control_addr()).await.unwrap();
+        let entrypoint = client
+            .start_tunnel(
+                Tunnel::new(
+                    name,
+                    SocketAddr::from(([127, 0, 0, 1], 8971)),
+                    RemoteConfig::Tcp(free_port().unwrap()),
+                )
+                .with_label("team", "infra")
+                .with_label("env", env),
+                ShutdownManager::new(),
+            )
+            .await
+            .unwrap();
+        entrypoints.push(entrypoint);
+    }
+
+    #[derive(serde::Deserialize)]
+    struct TunnelInfo {
+        name: String,
+        labels: std::collections::HashMap<String, String>,
+    }
+    let list = |query: &'static str| {
+        let url = format!("http://{}/admin/tunnels{}", server.control_addr(), query);
+        async move {
+            reqwest::get(url)
+                .await
+                .unwrap()
+                .json::<Vec<TunnelInfo>>()
+                .await
+                .unwrap()
+        }
+    };
+
+    let tunnels = list("?label=team=infra").await;
+    assert_eq!(tunnels.len(), 2);
+    assert_eq!(tunnels[0].name, "api");
+    assert_eq!(tunnels[0].labels["env"], "prod");
+    let tunnels = list("?label=team=infra&label=env=dev").await;
+    assert_eq!(tunnels.len(), 1);
+    assert_eq!(tunnels[0].name, "web");
+    assert!(list("?label=team=web").await.is_empty());
+
+    // the ports of the tcp tunnels are taken from the pool
+    let stats: serde_json::Value =
+        reqwest::get(format!("http://{}/admin/stats", server.control_addr()))
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+    assert_eq!(stats["ports"]["total"], 64512);
+    assert_eq!(stats["ports"]["remaining"], 64510);
+    assert_eq!(stats["connections"], 0);
+    assert_eq!(stats["tunnels"][0]["name"], "api");
+    assert_eq!(stats["tunnels"][1]["name"], "web");
+    assert_eq!(stats["tunnels"][1]["connections"], 0);
+    assert_eq!(
+        stats["tunnels"][1]["entrypoint"],
+        serde_json::json!(entrypoints[1])
+    );
+    assert_eq!(stats["memory"]["budget"], serde_json::Value::Null);
+    assert_eq!(stats["rate_limit"]["rate"], serde_json::Value::Null);
+    assert_eq!(stats["memory"]["used"], 0);
+
+    // invalid labels are rejected at the registration
+    let client = Client::new(server.control_addr()).await.unwrap();
+    let result = client
+        .start_tunnel(
+            Tunnel::new(
+                "invalid",
+                SocketAddr::from(([127, 0, 0, 1], 8971)),
+                RemoteConfig::Tcp(free_port().unwrap()),
+            )
+            .with_label("team name", "infra"),
+            ShutdownManager::new(),
+        )
+        .await;
+    assert!(result.is_err());
+
+    server.cancel.trigger_shutdown(0).unwrap();
+}
+
+#[tokio::test]
+async fn test_probe_through_tunnel() {
+    init();
+    let server = start_server_with_config(Config {
+        admin_api: true,
+        ..Default::default()
+    })
+    .await;
+
+    let local = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let database = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let client = Client::new(server.control_addr()).await.unwrap();
+    client
+        .start_tunnel(
+            Tunnel::new(
+                "probe",
+                local.local_addr().unwrap(),
+                RemoteConfig::Tcp(free_port().unwrap()),
+            )
+            .with_probe_target(database.local_addr().unwrap()),
+            ShutdownManager::new(),
+        )
+        .await
+        .unwrap();
 
-    let client_exit = tokio::join!(client_handler);
-    assert!(client_exit.0.is_ok());
+    let tunnels: serde_json::Value =
+        reqwest::get(format!("http://{}/admin/tunnels", server.control_addr()))
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+    let tunnel_id = tunnels[0]["id"].as_str().unwrap().to_string();
+    let probe = |tunnel_id: &str, target: SocketAddr| {
+        let url = format!(
+            "http://{}/admin/tunnels/{}/probe?target={}",
+            server.control_addr(),
+            tunnel_id,
+            target
+        );
+        async move { reqwest::Client::new().post(url).send().await.unwrap() }
+    };
+
+    for target in [local.local_addr().unwrap(), database.local_addr().unwrap()] {
+        let result: serde_json::Value = probe(&tunnel_id, target).await.json().await.unwrap();
+        assert_eq!(result["reachable"], true, "{}", result);
+    }
+
+    // the targets out of the allowlist are refused by the client
+    let other = SocketAddr::from(([127, 0, 0, 1], free_port().unwrap()));
+    let result: serde_json::Value = probe(&tunnel_id, other).await.json().await.unwrap();
+    assert_eq!(result["reachable"], false);
+    assert!(result["error"].as_str().unwrap().contains("not allowed"));
+
+    let response = probe("unknown", other).await;
+    assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
+
+    server.cancel.trigger_shutdown(0).unwrap();
+}
+
+#[tokio::test]
+async fn test_failover_to_backup_server() {
+    init();
+    let primary = start_server(Default::default()).await;
+    let backup = start_server(Default::default()).await;
+    let unreachable = SocketAddr::from(([127, 0, 0, 1], free_port().unwrap()));
+
+    // the unreachable server is skipped when connecting
+    let client = Client::with_servers(vec![unreachable, backup.control_addr()])
+        .await
+        .unwrap();
+    assert_eq!(client.active_server(), backup.control_addr());
+
+    // the client fails over when the active server goes away
+    let client = Client::with_servers(vec![primary.control_addr(), backup.control_addr()])
+        .await
+        .unwrap();
+    assert_eq!(client.active_server(), primary.control_addr());
+    primary.cancel.trigger_shutdown(0).unwrap();
+    primary.cancel.wait_shutdown_complete().await;
+    sleep(tokio::time::Duration::from_millis(50)).await;
+
+    let entrypoint = client
+        .start_tunnel(
+            Tunnel::new(
+                "test",
+                SocketAddr::from(([127, 0, 0, 1], 8971)),
+                RemoteConfig::Tcp(free_port().unwrap()),
+            ),
+            ShutdownManager::new(),
+        )
+        .await;
+    assert!(entrypoint.is_ok());
+
+    // the running tunnel is registered again on the backup server
+    let primary = start_server(Default::default()).await;
+    let client = Client::with_servers(vec![primary.control_addr(), backup.control_addr()])
+        .await
+        .unwrap();
+    let mut handle = client.spawn_tunnel(
+        Tunnel::new(
+            "test",
+            SocketAddr::from(([127, 0, 0, 1], 8971)),
+            RemoteConfig::Tcp(0),
+        ),
+        ShutdownManager::new(),
+    );
+    assert_eq!(
+        handle.registered().await.unwrap().server,
+        primary.control_addr()
+    );
+    let mut registrations = handle.subscribe();
+    registrations.mark_unchanged();
+    primary.cancel.trigger_shutdown(0).unwrap();
+    let registration = tokio::time::timeout(
+        Duration::from_secs(5),
+        registrations.wait_for(|registration| {
+            registration
+                .as_ref()
+                .is_some_and(|registration| registration.server != primary.control_addr())
+        }),
+    )
+    .await
+    .unwrap()
+    .unwrap()
+    .clone()
+    .unwrap();
+    assert_eq!(registration.server, backup.control_addr());
+    assert_eq!(*handle.state().borrow(), TunnelState::Connected);
+
+    backup.cancel.trigger_shutdown(0).unwrap();
+}
+
+#[tokio::test]
+async fn test_reregister_after_server_restart() {
+    init();
+    let server = start_server_with_config(Config {
+        admin_api: true,
+        ..Default::default()
+    })
+    .await;
+    let control_addr = server.control_addr();
+
+    let client = Client::new(control_addr).await.unwrap();
+    let shutdown = ShutdownManager::new();
+    client
+        .start_tunnel(
+            Tunnel::new(
+                "test",
+                SocketAddr::from(([127, 0, 0, 1], 8971)),
+                RemoteConfig::Tcp(free_port().unwrap()),
+            ),
+            shutdown.clone(),
+        )
+        .await
+        .unwrap();
+
+    // restart the server on the same ports
+    server.cancel.trigger_shutdown(0).unwrap();
+    server.cancel.wait_shutdown_complete().await;
+    let restarted = ShutdownManager::new();
+    let new_server = Server::new(
+        Config {
+            control_port: server.control_port,
+            vhttp_port: server.vhttp_port,
+            admin_api: true,
+            ..Default::default()
+        },
+        restarted.clone(),
+    );
+    tokio::spawn(async move {
+        let _ = new_server.run().await;
+    });
+
+    // the client registers the tunnel again after the backoff
+    let mut names = Vec::new();
+    for _ in 0..50 {
+        sleep(Duration::from_millis(100)).await;
+        let Ok(response) = reqwest::get(format!("http://{}/admin/tunnels", control_addr)).await
+        else {
+            continue;
+        };
+        let tunnels: Vec<serde_json::Value> = response.json().await.unwrap();
+        names = tunnels
+            .iter()
+            .map(|tunnel| tunnel["name"].as_str().unwrap().to_string())
+            .collect();
+        if !names.is_empty() {
+            break;
+        }
+    }
+    assert_eq!(names, ["test"]);
+    assert!(!shutdown.is_shutdown_triggered());
+
+    shutdown.trigger_shutdown(0).unwrap();
+    restarted.trigger_shutdown(0).unwrap();
+}
+
+#[tokio::test]
+async fn test_reconnect_after_server_outage() {
+    init();
+    let server = start_server_with_config(Config {
+        admin_api: true,
+        ..Default::default()
+    })
+    .await;
+    let control_addr = server.control_addr();
+
+    let client = Client::new(control_addr)
+        .await
+        .unwrap()
+        .with_reconnect(Duration::from_millis(600));
+    let shutdown = ShutdownManager::new();
+    let remote_port = free_port().unwrap();
+    let mut handle = client.spawn_tunnel(
+        Tunnel::new(
+            "test",
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            RemoteConfig::Tcp(remote_port),
+        )
+        .with_echo(),
+        shutdown.clone(),
+    );
+    handle.registered().await.unwrap();
+    let mut state = handle.state();
+    assert_eq!(*state.borrow_and_update(), TunnelState::Connected);
+
+    // the outage outlasts the retries without reconnecting
+    server.cancel.trigger_shutdown(0).unwrap();
+    server.cancel.wait_shutdown_complete().await;
+    let reconnecting = tokio::time::timeout(
+        Duration::from_secs(3),
+        state.wait_for(|state| matches!(state, TunnelState::Reconnecting { .. })),
+    )
+    .await
+    .unwrap()
+    .map(|state| *state)
+    .unwrap();
+    assert!(
+        matches!(reconnecting, TunnelState::Reconnecting { backoff, .. } if backoff <= Duration::from_millis(600)),
+        "{:?}",
+        reconnecting
+    );
+    sleep(Duration::from_secs(5)).await;
+    match *state.borrow() {
+        TunnelState::Reconnecting { retries, .. } => assert!(retries > 3, "{}", retries),
+        state => panic!("unexpected state: {:?}", state),
+    }
+
+    let restarted = ShutdownManager::new();
+    let new_server = Server::new(
+        Config {
+            control_port: server.control_port,
+            vhttp_port: server.vhttp_port,
+            admin_api: true,
+            ..Default::default()
+        },
+        restarted.clone(),
+    );
+    tokio::spawn(async move {
+        let _ = new_server.run().await;
+    });
+    tokio::time::timeout(
+        Duration::from_secs(3),
+        state.wait_for(|state| *state == TunnelState::Connected),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+
+    // the tunnel is back on the same port
+    let mut user = tokio::net::TcpStream::connect(("127.0.0.1", remote_port))
+        .await
+        .unwrap();
+    user.write_all(b"ping").await.unwrap();
+    let mut response = [0; 4];
+    user.read_exact(&mut response).await.unwrap();
+    assert_eq!(&response, b"ping");
+    assert!(!shutdown.is_shutdown_triggered());
+
+    shutdown.trigger_shutdown(0).unwrap();
+    restarted.trigger_shutdown(0).unwrap();
+}
+
+#[tokio::test]
+async fn test_close_tunnel_through_admin_api() {
+    init();
+    let server = start_server_with_config(Config {
+        admin_api: true,
+        ..Default::default()
+    })
+    .await;
+
+    let client = Client::new(server.control_addr()).await.unwrap();
+    let shutdown = ShutdownManager::new();
+    client
+        .start_tunnel(
+            Tunnel::new(
+                "test",
+                SocketAddr::from(([127, 0, 0, 1], 8971)),
+                RemoteConfig::Tcp(free_port().unwrap()),
+            ),
+            shutdown.clone(),
+        )
+        .await
+        .unwrap();
+    let tunnels: serde_json::Value =
+        reqwest::get(format!("http://{}/admin/tunnels", server.control_addr()))
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+    let tunnel_id = tunnels[0]["id"].as_str().unwrap().to_string();
+
+    let close = |tunnel_id: &str| {
+        let url = format!(
+            "http://{}/admin/tunnels/{}?reason=revoked&message=bye",
+            server.control_addr(),
+            tunnel_id
+        );
+        async move { reqwest::Client::new().delete(url).send().await.unwrap() }
+    };
+    assert_eq!(
+        close("unknown").await.status(),
+        reqwest::StatusCode::NOT_FOUND
+    );
+    assert_eq!(close(&tunnel_id).await.status(), reqwest::StatusCode::OK);
+
+    // the revoked tunnel isn't registered again, the client gives up
+    let code = tokio::time::timeout(Duration::from_secs(3), shutdown.wait_shutdown_complete())
+        .await
+        .unwrap();
+    assert_eq!(code, 1);
+    let tunnels: Vec<serde_json::Value> =
+        reqwest::get(format!("http://{}/admin/tunnels", server.control_addr()))
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+    assert!(tunnels.is_empty());
+
+    server.cancel.trigger_shutdown(0).unwrap();
+}
+
+#[tokio::test]
+async fn test_register_the_same_tunnel_twice() {
+    init();
+    let server = start_server_with_config(Config {
+        admin_api: true,
+        ..Default::default()
+    })
+    .await;
+    let port = free_port().unwrap();
+    let tunnel = || {
+        Tunnel::new(
+            "test",
+            SocketAddr::from(([127, 0, 0, 1], 8971)),
+            RemoteConfig::Tcp(port),
+        )
+    };
+
+    let first = ShutdownManager::new();
+    let client = Client::new(server.control_addr()).await.unwrap();
+    client
+        .clone()
+        .start_tunnel(tunnel(), first.clone())
+        .await
+        .unwrap();
+    // registered again right away by the same client, e.g. it reconnected
+    let second = ShutdownManager::new();
+    // it takes the same port once the previous one is released
+    client.start_tunnel(tunnel(), second.clone()).await.unwrap();
+
+    // the replaced one doesn't register it again
+    let code = tokio::time::timeout(Duration::from_secs(3), first.wait_shutdown_complete())
+        .await
+        .unwrap();
+    assert_eq!(code, 1);
+    assert!(!second.is_shutdown_triggered());
+
+    let tunnels: Vec<serde_json::Value> =
+        reqwest::get(format!("http://{}/admin/tunnels", server.control_addr()))
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+    assert_eq!(tunnels.len(), 1);
+    // the user connections go to the new one
+    tokio::net::TcpStream::connect(("127.0.0.1", port))
+        .await
+        .unwrap();
+
+    second.trigger_shutdown(0).unwrap();
+    server.cancel.trigger_shutdown(0).unwrap();
+}
+
+// the clients sharing the ip, e.g. behind a nat, run the same tunnel side by side.
+#[tokio::test]
+async fn test_register_the_same_tunnel_from_two_clients() {
+    init();
+    let server = start_server_with_config(Config {
+        admin_api: true,
+        ..Default::default()
+    })
+    .await;
+    let tunnel = |port| {
+        Tunnel::new(
+            "tcp-127.0.0.1:8971",
+            SocketAddr::from(([127, 0, 0, 1], 8971)),
+            RemoteConfig::Tcp(port),
+        )
+    };
+
+    let mut shutdowns = Vec::new();
+    let mut ports = Vec::new();
+    for _ in 0..2 {
+        let shutdown = ShutdownManager::new();
+        let port = free_port().unwrap();
+        Client::new(server.control_addr())
+            .await
+            .unwrap()
+            .start_tunnel(tunnel(port), shutdown.clone())
+            .await
+            .unwrap();
+        shutdowns.push(shutdown);
+        ports.push(port);
+    }
+    sleep(Duration::from_millis(200)).await;
+
+    // neither evicts the other
+    for (shutdown, port) in shutdowns.iter().zip(&ports) {
+        assert!(!shutdown.is_shutdown_triggered());
+        assert!(is_port_listening(*port), "{}", port);
+    }
+    let tunnels: Vec<serde_json::Value> =
+        reqwest::get(format!("http://{}/admin/tunnels", server.control_addr()))
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+    assert_eq!(tunnels.len(), 2);
+
+    for shutdown in shutdowns {
+        shutdown.trigger_shutdown(0).unwrap();
+    }
+    server.cancel.trigger_shutdown(0).unwrap();
+}
+
+#[tokio::test]
+async fn test_register_the_port_of_a_closed_tunnel_again() {
+    init();
+    let server = start_server_with_config(Config {
+        admin_api: true,
+        ..Default::default()
+    })
+    .await;
+    let port = free_port().unwrap();
+
+    for _ in 0..5 {
+        let client = Client::new(server.control_addr()).await.unwrap();
+        let shutdown = ShutdownManager::new();
+        client
+            .start_tunnel(
+                Tunnel::new(
+                    "test",
+                    SocketAddr::from(([127, 0, 0, 1], 8971)),
+                    RemoteConfig::Tcp(port),
+                ),
+                shutdown.clone(),
+            )
+            .await
+            .unwrap();
+        // a user connection lingers in TIME_WAIT after the tunnel is closed
+        let _ = tokio::net::TcpStream::connect(("127.0.0.1", port))
+            .await
+            .unwrap();
+
+        let tunnels: serde_json::Value =
+            reqwest::get(format!("http://{}/admin/tunnels", server.control_addr()))
+                .await
+                .unwrap()
+                .json()
+                .await
+                .unwrap();
+        let url = format!(
+            "http://{}/admin/tunnels/{}",
+            server.control_addr(),
+            tunnels[0]["id"].as_str().unwrap()
+        );
+        let response = reqwest::Client::new().delete(url).send().await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        // the client is told after the port is released, so it's registered again right away
+        tokio::time::timeout(Duration::from_secs(3), shutdown.wait_shutdown_complete())
+            .await
+            .unwrap();
+    }
+
+    server.cancel.trigger_shutdown(0).unwrap();
+}
+
+#[tokio::test]
+async fn test_server_ready() {
+    init();
+    let server = start_server(Default::default()).await;
+    // the ports are bound once it's ready
+    tokio::net::TcpStream::connect(server.control_addr())
+        .await
+        .unwrap();
+    tokio::net::TcpStream::connect(("127.0.0.1", server.vhttp_port))
+        .await
+        .unwrap();
+
+    // the ports are taken, the error tells which one
+    for (control_port, vhttp_port, vhttp_optional, taken_port) in [
+        (
+            server.control_port,
+            free_port().unwrap(),
+            false,
+            "control port",
+        ),
+        (free_port().unwrap(), server.vhttp_port, false, "vhttp port"),
+        // the control port is never optional
+        (
+            server.control_port,
+            free_port().unwrap(),
+            true,
+            "control port",
+        ),
+    ] {
+        let shutdown = ShutdownManager::new();
+        let taken = Server::new(
+            Config {
+                control_port,
+                vhttp_port,
+                vhttp_optional,
+                ..Default::default()
+            },
+            shutdown.clone(),
+        );
+        let ready = taken.ready();
+        let err = taken.run().await.unwrap_err();
+        assert!(format!("{:#}", err).contains(taken_port), "{:#}", err);
+        assert!(!ready.await);
+        assert_eq!(shutdown.wait_shutdown_complete().await, 1);
+    }
+    // dropped before running
+    let never = Server::new(Default::default(), ShutdownManager::new()).ready();
+    assert!(!never.await);
+
+    server.cancel.trigger_shutdown(0).unwrap();
+}
+
+#[tokio::test]
+async fn test_server_without_vhttp() {
+    init();
+    let taken = std::net::TcpListener::bind("0.0.0.0:0").unwrap();
+    let shutdown = ShutdownManager::new();
+    let control_port = free_port().unwrap();
+    let server = Server::new(
+        Config {
+            control_port,
+            vhttp_port: taken.local_addr().unwrap().port(),
+            vhttp_optional: true,
+            entrypoint: EntrypointConfig {
+                domain: vec!["example.com".to_string()],
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+        shutdown.clone(),
+    );
+    let ready = server.ready();
+    tokio::spawn(async move {
+        let _ = server.run().await;
+    });
+    assert!(ready.await);
+
+    let local = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let local_addr = local.local_addr().unwrap();
+    let client = Client::new(SocketAddr::from(([127, 0, 0, 1], control_port)))
+        .await
+        .unwrap();
+    // the tunnels of their own ports work
+    client
+        .clone()
+        .start_tunnel(
+            Tunnel::new("tcp", local_addr, RemoteConfig::Tcp(0)),
+            ShutdownManager::new(),
+        )
+        .await
+        .unwrap();
+    let err = client
+        .start_tunnel(
+            Tunnel::new(
+                "http",
+                local_addr,
+                RemoteConfig::Http(HttpRemoteConfig::Subdomain("foo")),
+            ),
+            ShutdownManager::new(),
+        )
+        .await
+        .unwrap_err();
+    assert!(
+        format!("{:#}", err).contains("the vhttp server isn't running"),
+        "{:#}",
+        err
+    );
+
+    shutdown.trigger_shutdown(0).unwrap();
+}
+
+// the client closes the tunnel and registers its port again right away,
+// the new registration waits for the entrypoint of the closed one to be released.
+#[tokio::test]
+async fn test_register_the_port_of_a_tunnel_closed_by_client_again() {
+    init();
+    let server = start_server(EntrypointConfig::default()).await;
+    let port = free_port().unwrap();
+    let client = Client::new(server.control_addr()).await.unwrap();
+
+    for _ in 0..5 {
+        let shutdown = ShutdownManager::new();
+        client
+            .clone()
+            .start_tunnel(
+                Tunnel::new(
+                    "test",
+                    SocketAddr::from(([127, 0, 0, 1], 8971)),
+                    RemoteConfig::Tcp(port),
+                ),
+                shutdown.clone(),
+            )
+            .await
+            .unwrap();
+        // a user connection lingers in TIME_WAIT after the tunnel is closed
+        let _ = tokio::net::TcpStream::connect(("127.0.0.1", port))
+            .await
+            .unwrap();
+
+        // the server may not notice the client is gone before the next registration
+        shutdown.trigger_shutdown(0).unwrap();
+        tokio::time::timeout(Duration::from_secs(3), shutdown.wait_shutdown_complete())
+            .await
+            .unwrap();
+    }
+
+    server.cancel.trigger_shutdown(0).unwrap();
+}
+
+#[tokio::test]
+async fn test_tunnel_hooks() {
+    init();
+    let events = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let shutdown = ShutdownManager::new();
+    let control_port = free_port().unwrap();
+    let mut server = Server::new(
+        Config {
+            control_port,
+            vhttp_port: free_port().unwrap(),
+            ..Default::default()
+        },
+        shutdown.clone(),
+    );
+    let open_events = events.clone();
+    server.on_tunnel_open(move |tunnel| {
+        let events = open_events.clone();
+        async move {
+            anyhow::ensure!(tunnel.name != "vetoed", "{} is not allowed", tunnel.name);
+            events.lock().unwrap().push(format!("open {}", tunnel.name));
+            Ok(())
+        }
+    });
+    let close_events = events.clone();
+    let port = free_port().unwrap();
+    server.on_tunnel_close(move |tunnel| {
+        let events = close_events.clone();
+        async move {
+            // the hook runs before the entrypoint is closed
+            let serving = tokio::net::TcpStream::connect(("127.0.0.1", port))
+                .await
+                .is_ok();
+            events
+                .lock()
+                .unwrap()
+                .push(format!("close {} serving={}", tunnel.name, serving));
+            Ok(())
+        }
+    });
+    let ready = server.ready();
+    tokio::spawn(async move {
+        let _ = server.run().await;
+    });
+    assert!(ready.await);
+    let control_addr = SocketAddr::from(([127, 0, 0, 1], control_port));
+
+    let tunnel = |name| {
+        Tunnel::new(
+            name,
+            SocketAddr::from(([127, 0, 0, 1], 8971)),
+            RemoteConfig::Tcp(port),
+        )
+    };
+    let client = Client::new(control_addr).await.unwrap();
+    let vetoed = client
+        .clone()
+        .start_tunnel(tunnel("vetoed"), ShutdownManager::new())
+        .await
+        .unwrap_err();
+    assert!(format!("{:#}", vetoed).contains("vetoed is not allowed"));
+
+    let client_shutdown = ShutdownManager::new();
+    client
+        .start_tunnel(tunnel("test"), client_shutdown.clone())
+        .await
+        .unwrap();
+    assert_eq!(*events.lock().unwrap(), ["open test"]);
+
+    client_shutdown.trigger_shutdown(0).unwrap();
+    client_shutdown.wait_shutdown_complete().await;
+    for _ in 0..50 {
+        if events.lock().unwrap().len() == 2 {
+            break;
+        }
+        sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(
+        *events.lock().unwrap(),
+        ["open test", "close test serving=true"]
+    );
+
+    shutdown.trigger_shutdown(0).unwrap();
+}
+
+#[tokio::test]
+async fn test_shed_pending_registrations() {
+    init();
+    let shutdown = ShutdownManager::new();
+    let control_port = free_port().unwrap();
+    let mut server = Server::new(
+        Config {
+            control_port,
+            vhttp_port: free_port().unwrap(),
+            max_pending_registrations: 1,
+            admin_api: true,
+            ..Default::default()
+        },
+        shutdown.clone(),
+    );
+    // a slow open hook keeps the registration pending
+    server.on_tunnel_open(|_| async {
+        sleep(Duration::from_millis(300)).await;
+        Ok(())
+    });
+    let ready = server.ready();
+    tokio::spawn(async move {
+        let _ = server.run().await;
+    });
+    assert!(ready.await);
+    let control_addr = SocketAddr::from(([127, 0, 0, 1], control_port));
+
+    let client = Client::new(control_addr).await.unwrap();
+    let start = |name| {
+        client.clone().start_tunnel(
+            Tunnel::new(
+                name,
+                SocketAddr::from(([127, 0, 0, 1], 8971)),
+                RemoteConfig::Tcp(free_port().unwrap()),
+            ),
+            ShutdownManager::new(),
+        )
+    };
+    // the shed registration is retried after the backoff
+    let (first, second) = tokio::join!(start("first"), start("second"));
+    assert!(first.is_ok());
+    assert!(second.is_ok());
+
+    let stats: serde_json::Value = reqwest::get(format!("http://{}/admin/stats", control_addr))
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(stats["registrations"]["max_pending"], 1);
+    assert_eq!(stats["registrations"]["pending"], 0);
+    assert_eq!(stats["registrations"]["shed"], 1);
+    assert_eq!(stats["registrations"]["queued"], 0);
+
+    shutdown.trigger_shutdown(0).unwrap();
 }
 
 struct TestServer {
@@ -472,6 +4305,15 @@ impl TestServer {
 }
 
 async fn start_server(entrypoint_config: EntrypointConfig) -> TestServer {
+    start_server_with_config(Config {
+        entrypoint: entrypoint_config,
+        ..Default::default()
+    })
+    .await
+}
+
+/// start a server with the config, the ports are overridden by free ports.
+async fn start_server_with_config(config: Config) -> TestServer {
     let shutdown = ShutdownManager::new();
     let control_port = free_port().unwrap();
     let vhttp_port = free_port().unwrap();
@@ -479,14 +4321,15 @@ async fn start_server(entrypoint_config: EntrypointConfig) -> TestServer {
         Config {
             vhttp_port,
             control_port,
-            entrypoint: entrypoint_config,
+            ..config
         },
         shutdown.clone(),
     );
+    let ready = server.ready();
     tokio::spawn(async move {
-        server.run().await;
+        let _ = server.run().await;
     });
-    sleep(tokio::time::Duration::from_millis(20)).await;
+    assert!(ready.await);
 
     TestServer {
         control_port,